@@ -0,0 +1,123 @@
+//! Parse time and allocations of config.json, owned and borrowed from the
+//! mapped file, run with `cargo bench --bench spec`.
+//! A plain timing loop, so the crate doesn't need a benchmark framework.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    convert::TryFrom,
+    hint::black_box,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use pura::oci::spec::{Mount, Spec, SpecFile, Str};
+
+const ITERATIONS: u32 = 20_000;
+
+/// System allocator counting the allocations
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Spec the size of the ones generated by container engines
+fn engine_spec() -> Spec {
+    let mut spec = Spec::new()
+        .with_rootfs("rootfs", false)
+        .with_hostname("bench")
+        .with_args(&["/usr/bin/server", "--config", "/etc/server.toml"])
+        .add_mount(Mount::new(
+            "/proc",
+            "proc",
+            "proc",
+            &["nosuid", "noexec", "nodev"],
+        ))
+        .add_mount(Mount::new(
+            "/dev",
+            "tmpfs",
+            "tmpfs",
+            &["nosuid", "mode=755"],
+        ))
+        .add_mount(Mount::new(
+            "/sys",
+            "sysfs",
+            "sysfs",
+            &["nosuid", "noexec", "ro"],
+        ));
+    for i in 0..16 {
+        spec = spec
+            .add_env(
+                &format!("VARIABLE_{}", i),
+                "a reasonably long value of the variable",
+            )
+            .add_mount(Mount::new(
+                &format!("/run/secrets/volume-{}", i),
+                "bind",
+                &format!("/var/lib/engine/volumes/{}/_data", i),
+                &["rbind", "rprivate"],
+            ))
+            .add_annotation(&format!("org.example.label.{}", i), "value");
+    }
+    ["pid", "network", "ipc", "uts", "mount"]
+        .iter()
+        .fold(spec, |spec, namespace| spec.add_namespace(namespace, None))
+}
+
+fn bench(name: &str, mut parse: impl FnMut()) {
+    // Warm up the page cache and the allocator
+    for _ in 0..100 {
+        parse();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        parse();
+    }
+    let per_parse = start.elapsed().as_secs_f64() * 1e6 / ITERATIONS as f64;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ITERATIONS as u64;
+    println!("{:<24} {:>8.2} us {:>6} allocations", name, per_parse, allocations);
+}
+
+fn main() {
+    let bundle = std::env::temp_dir().join(format!("pura-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&bundle).unwrap();
+    engine_spec().save(&bundle).unwrap();
+    let config = bundle.join("config.json");
+    let json = std::fs::read_to_string(&config).unwrap();
+    println!("config.json of {} bytes", json.len());
+
+    bench("Spec::try_from", || {
+        black_box(Spec::try_from(config.as_path()).unwrap());
+    });
+    bench("SpecFile::spec", || {
+        let file = SpecFile::open(&config).unwrap();
+        black_box(file.spec().unwrap());
+    });
+    // Deserialization alone, the rest is reading or mapping the file
+    bench("in-memory owned", || {
+        black_box(serde_json::from_str::<Spec>(&json).unwrap());
+    });
+    bench("in-memory borrowed", || {
+        black_box(serde_json::from_slice::<Spec<Str>>(json.as_bytes()).unwrap());
+    });
+
+    std::fs::remove_dir_all(&bundle).unwrap();
+}
@@ -0,0 +1,119 @@
+//! Start latency of a container, run as root with `cargo bench --bench startup`.
+//! Times `pura create` and `pura start` of a bundle running `true`, from
+//! busybox when it's on the PATH (or `PURA_BENCH_BUSYBOX`), from the bind
+//! mounted binaries of the host otherwise.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+use nix::unistd::geteuid;
+use pura::oci::spec::{Mount, Spec};
+
+const PURA: &str = env!("CARGO_BIN_EXE_pura");
+const ITERATIONS: usize = 100;
+
+fn pura(args: &[&str]) {
+    let status = Command::new(PURA)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "pura {} failed: {}", args.join(" "), status);
+}
+
+fn busybox() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PURA_BENCH_BUSYBOX") {
+        return Some(PathBuf::from(path));
+    }
+    std::env::var_os("PATH")?
+        .to_str()?
+        .split(':')
+        .map(|dir| Path::new(dir).join("busybox"))
+        .find(|path| path.is_file())
+}
+
+/// Spec of a container doing nothing, with the usual namespaces and mounts
+fn bundle(path: &Path) -> &'static str {
+    let rootfs = path.join("rootfs");
+    std::fs::create_dir_all(rootfs.join("bin")).unwrap();
+    let mut spec = Spec::new()
+        .with_rootfs("rootfs", false)
+        .with_args(&["/bin/true"])
+        .add_env("PATH", "/usr/bin:/bin")
+        .add_mount(Mount::new("/dev", "tmpfs", "tmpfs", &["nosuid"]))
+        .add_mount(Mount::new("/proc", "proc", "proc", &[]));
+    let source = match busybox() {
+        Some(busybox) => {
+            std::fs::copy(busybox, rootfs.join("bin/busybox")).unwrap();
+            std::os::unix::fs::symlink("busybox", rootfs.join("bin/true")).unwrap();
+            "busybox"
+        }
+        None => {
+            for dir in ["/bin", "/lib", "/lib64", "/usr"] {
+                if Path::new(dir).exists() {
+                    spec = spec.add_mount(Mount::new(dir, "bind", dir, &["bind"]));
+                }
+            }
+            "host binaries"
+        }
+    };
+    ["pid", "mount", "uts", "ipc", "network"]
+        .iter()
+        .fold(spec, |spec, namespace| spec.add_namespace(namespace, None))
+        .save(path)
+        .unwrap();
+    source
+}
+
+/// Median and 95th percentile in ms
+fn report(name: &str, mut samples: Vec<f64>) {
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    println!("{:<16} {:>8.2} ms {:>8.2} ms", name, percentile(50), percentile(95));
+}
+
+fn main() {
+    if !geteuid().is_root() {
+        eprintln!("skipping the startup benchmark: needs root");
+        return;
+    }
+    let path = std::env::temp_dir().join(format!("pura-bench-{}", std::process::id()));
+    let root = path.join("root");
+    let bundle_path = path.join("bundle");
+    let source = bundle(&bundle_path);
+    let root = root.to_str().unwrap();
+    let bundle_path = bundle_path.to_str().unwrap();
+    println!("{} runs of true from {}, median and p95", ITERATIONS, source);
+
+    let (mut create, mut start, mut total, mut delete) = (vec![], vec![], vec![], vec![]);
+    for i in 0..ITERATIONS + 5 {
+        let id = format!("bench-{}", i);
+        let begin = Instant::now();
+        pura(&["--root", root, "create", "--bundle", bundle_path, &id]);
+        let created = Instant::now();
+        pura(&["--root", root, "start", &id]);
+        let started = Instant::now();
+        pura(&["--root", root, "wait", &id]);
+        let waited = Instant::now();
+        pura(&["--root", root, "delete", &id]);
+        let deleted = Instant::now();
+        // Warming up the page cache and the dentries
+        if i >= 5 {
+            create.push((created - begin).as_secs_f64() * 1e3);
+            start.push((started - created).as_secs_f64() * 1e3);
+            total.push((started - begin).as_secs_f64() * 1e3);
+            delete.push((deleted - waited).as_secs_f64() * 1e3);
+        }
+    }
+    report("create", create);
+    report("start", start);
+    report("create+start", total);
+    report("delete", delete);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
@@ -0,0 +1,58 @@
+#ifndef PURA_H
+#define PURA_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes, matching the exit codes of the pura binary */
+#define PURA_OK 0
+#define PURA_ERR_RUNTIME 1
+#define PURA_ERR_INVALID_BUNDLE 2
+#define PURA_ERR_KERNEL_FEATURE 3
+#define PURA_ERR_CONTAINER 4
+#define PURA_ERR_INVALID_STATE 5
+/* A required argument is NULL or isn't valid UTF-8 */
+#define PURA_ERR_INVALID_ARGUMENT 64
+
+#define PURA_STATUS_CREATING 0
+#define PURA_STATUS_CREATED 1
+#define PURA_STATUS_RUNNING 2
+#define PURA_STATUS_STOPPED 3
+
+/* Options of pura_create, optional fields can be NULL */
+typedef struct PuraCreateOptions {
+    const char *id;
+    const char *bundle;
+    const char *root;
+    const char *console_socket;
+    const char *pid_file;
+} PuraCreateOptions;
+
+/* State of a container, released with pura_state_free */
+typedef struct PuraState {
+    char *oci_version;
+    char *id;
+    int status;
+    int64_t pid;
+    char *bundle;
+} PuraState;
+
+int pura_create(const PuraCreateOptions *opts);
+int pura_start(const char *root, const char *id);
+int pura_kill(const char *root, const char *id, int signal);
+int pura_delete(const char *root, const char *id);
+int pura_state(const char *root, const char *id, PuraState *out);
+void pura_state_free(PuraState *state);
+
+/* Message of the last error on the calling thread, or NULL.
+ * Valid until the next call on the same thread. */
+const char *pura_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PURA_H */
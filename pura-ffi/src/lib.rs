@@ -0,0 +1,272 @@
+//! C bindings for the pura library API.
+//!
+//! Every function returns `PURA_OK` (0) on success or one of the `PURA_ERR_*`
+//! codes, which match the exit codes of the `pura` binary. The message of the
+//! last error of the calling thread is available through `pura_last_error`.
+//! See `include/pura.h` for the C declarations.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    ptr,
+};
+
+use pura::{
+    core::{common::Error, restart::RestartPolicy},
+    oci::ops::Create,
+    Container, Status,
+};
+
+pub const PURA_OK: c_int = 0;
+/// A required argument is NULL or isn't valid UTF-8
+pub const PURA_ERR_INVALID_ARGUMENT: c_int = 64;
+
+pub const PURA_STATUS_CREATING: c_int = 0;
+pub const PURA_STATUS_CREATED: c_int = 1;
+pub const PURA_STATUS_RUNNING: c_int = 2;
+pub const PURA_STATUS_STOPPED: c_int = 3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Options of `pura_create`, optional fields can be NULL
+#[repr(C)]
+pub struct PuraCreateOptions {
+    pub id: *const c_char,
+    pub bundle: *const c_char,
+    pub root: *const c_char,
+    pub console_socket: *const c_char,
+    pub pid_file: *const c_char,
+}
+
+/// State of a container, filled by `pura_state` and released with `pura_state_free`
+#[repr(C)]
+pub struct PuraState {
+    pub oci_version: *mut c_char,
+    pub id: *mut c_char,
+    pub status: c_int,
+    pub pid: i64,
+    pub bundle: *mut c_char,
+}
+
+enum FfiError {
+    InvalidArgument(String),
+    Pura(Error),
+}
+
+impl From<Error> for FfiError {
+    fn from(err: Error) -> Self {
+        FfiError::Pura(err)
+    }
+}
+
+fn set_last_error(msg: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).ok());
+}
+
+fn handle(result: std::result::Result<(), FfiError>) -> c_int {
+    match result {
+        Ok(()) => PURA_OK,
+        Err(FfiError::InvalidArgument(msg)) => {
+            set_last_error(msg);
+            PURA_ERR_INVALID_ARGUMENT
+        }
+        Err(FfiError::Pura(err)) => {
+            let code = err.exit_code();
+            set_last_error(err.to_string());
+            code
+        }
+    }
+}
+
+unsafe fn required(arg: *const c_char, name: &str) -> std::result::Result<String, FfiError> {
+    optional(arg, name)?.ok_or_else(|| FfiError::InvalidArgument(format!("{} is NULL", name)))
+}
+
+unsafe fn optional(
+    arg: *const c_char,
+    name: &str,
+) -> std::result::Result<Option<String>, FfiError> {
+    if arg.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(arg)
+        .to_str()
+        .map(|s| Some(s.to_string()))
+        .map_err(|_| FfiError::InvalidArgument(format!("{} isn't valid UTF-8", name)))
+}
+
+unsafe fn load(root: *const c_char, id: *const c_char) -> std::result::Result<Container, FfiError> {
+    let root = required(root, "root")?;
+    let id = required(id, "id")?;
+    Ok(Container::load(&root, &id)?)
+}
+
+fn to_c_string(s: &str) -> *mut c_char {
+    CString::new(s)
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Creates a container from a bundle.
+///
+/// # Safety
+///
+/// `opts` must point to a valid `PuraCreateOptions` whose non-NULL fields are
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pura_create(opts: *const PuraCreateOptions) -> c_int {
+    handle((|| {
+        let opts = opts
+            .as_ref()
+            .ok_or_else(|| FfiError::InvalidArgument("opts is NULL".to_string()))?;
+        let create = Create {
+            id: required(opts.id, "id")?,
+            bundle: required(opts.bundle, "bundle")?,
+            root: required(opts.root, "root")?,
+            console_socket: optional(opts.console_socket, "console_socket")?,
+            pid_file: optional(opts.pid_file, "pid_file")?,
+            network: None,
+            detach: false,
+            init: false,
+            devices: Vec::new(),
+            gpus: None,
+            image: None,
+            rootfs_tar: None,
+            overlay: false,
+            volumes: Vec::new(),
+            record: false,
+            log_rotation: None,
+            console_buffer: None,
+            pod: None,
+            restart: RestartPolicy::No,
+        };
+        Container::create(&create)?;
+        Ok(())
+    })())
+}
+
+/// Starts a created container.
+///
+/// # Safety
+///
+/// `root` and `id` must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pura_start(root: *const c_char, id: *const c_char) -> c_int {
+    handle((|| Ok(load(root, id)?.start()?))())
+}
+
+/// Sends a signal to the container process.
+///
+/// # Safety
+///
+/// `root` and `id` must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pura_kill(root: *const c_char, id: *const c_char, signal: c_int) -> c_int {
+    handle((|| Ok(load(root, id)?.kill(signal)?))())
+}
+
+/// Deletes a container.
+///
+/// # Safety
+///
+/// `root` and `id` must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pura_delete(root: *const c_char, id: *const c_char) -> c_int {
+    handle((|| Ok(load(root, id)?.delete()?))())
+}
+
+/// Reads the state of a container into `out`.
+///
+/// # Safety
+///
+/// `root` and `id` must be NUL-terminated strings and `out` must point to a
+/// writable `PuraState`, released afterwards with `pura_state_free`.
+#[no_mangle]
+pub unsafe extern "C" fn pura_state(
+    root: *const c_char,
+    id: *const c_char,
+    out: *mut PuraState,
+) -> c_int {
+    handle((|| {
+        let out = out
+            .as_mut()
+            .ok_or_else(|| FfiError::InvalidArgument("out is NULL".to_string()))?;
+        let state = load(root, id)?.state()?;
+
+        *out = PuraState {
+            oci_version: to_c_string(&state.oci_version),
+            id: to_c_string(&state.id),
+            status: match state.status {
+                Status::Creating => PURA_STATUS_CREATING,
+                Status::Created => PURA_STATUS_CREATED,
+                Status::Running => PURA_STATUS_RUNNING,
+                Status::Stopped => PURA_STATUS_STOPPED,
+            },
+            pid: state.pid as i64,
+            bundle: to_c_string(&state.bundle.to_string_lossy()),
+        };
+        Ok(())
+    })())
+}
+
+/// Releases the strings of a state filled by `pura_state`.
+///
+/// # Safety
+///
+/// `state` must be NULL or point to a `PuraState` filled by `pura_state`.
+#[no_mangle]
+pub unsafe extern "C" fn pura_state_free(state: *mut PuraState) {
+    if let Some(state) = state.as_mut() {
+        for field in [&mut state.oci_version, &mut state.id, &mut state.bundle] {
+            if !field.is_null() {
+                drop(CString::from_raw(*field));
+                *field = ptr::null_mut();
+            }
+        }
+    }
+}
+
+/// Message of the last error on the calling thread, or NULL.
+/// The pointer is valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn pura_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CStr, ptr};
+
+    use super::*;
+
+    #[test]
+    fn errors() {
+        let root = CString::new("./ffi_root").unwrap();
+        let id = CString::new("missing").unwrap();
+
+        unsafe {
+            assert_eq!(
+                pura_start(ptr::null(), id.as_ptr()),
+                PURA_ERR_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                CStr::from_ptr(pura_last_error()).to_str().unwrap(),
+                "root is NULL"
+            );
+
+            let mut state = std::mem::zeroed::<PuraState>();
+            assert_eq!(
+                pura_state(root.as_ptr(), id.as_ptr(), &mut state),
+                pura::core::common::exit_code::INVALID_STATE
+            );
+            assert!(!pura_last_error().is_null());
+            pura_state_free(&mut state);
+        }
+    }
+}
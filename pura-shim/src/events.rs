@@ -0,0 +1,96 @@
+//! Task events of the shim, forwarded to the events service of containerd
+
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use prost::Message;
+use prost_types::Timestamp;
+
+use crate::{
+    proto::{any, Envelope, ForwardRequest, KeyValue},
+    ttrpc::Client,
+};
+
+const EVENTS_SERVICE: &str = "containerd.services.events.ttrpc.v1.Events";
+/// Metadata of the namespace of a ttrpc request
+const NAMESPACE_HEADER: &str = "containerd-namespace-ttrpc";
+
+pub const TASK_CREATE: (&str, &str) = ("/tasks/create", "containerd.events.TaskCreate");
+pub const TASK_START: (&str, &str) = ("/tasks/start", "containerd.events.TaskStart");
+pub const TASK_DELETE: (&str, &str) = ("/tasks/delete", "containerd.events.TaskDelete");
+pub const TASK_EXIT: (&str, &str) = ("/tasks/exit", "containerd.events.TaskExit");
+pub const TASK_EXEC_ADDED: (&str, &str) = ("/tasks/exec-added", "containerd.events.TaskExecAdded");
+pub const TASK_EXEC_STARTED: (&str, &str) =
+    ("/tasks/exec-started", "containerd.events.TaskExecStarted");
+
+/// Connection to the ttrpc socket of containerd, opened again when
+/// containerd restarted
+pub struct Publisher {
+    address: String,
+    namespace: String,
+    client: Mutex<Option<Client>>,
+}
+
+impl Publisher {
+    /// Publisher of the events of the namespace, none are sent without an address
+    pub fn new(address: &str, namespace: &str) -> Publisher {
+        Publisher {
+            address: address.to_string(),
+            namespace: namespace.to_string(),
+            client: Mutex::new(None),
+        }
+    }
+
+    /// Forwards the event of the topic and type
+    pub fn publish(&self, (topic, name): (&str, &str), event: &impl Message) {
+        if self.address.is_empty() {
+            return;
+        }
+        let request = ForwardRequest {
+            envelope: Some(Envelope {
+                timestamp: Some(now()),
+                namespace: self.namespace.clone(),
+                topic: topic.to_string(),
+                event: Some(any(name, event)),
+            }),
+        };
+        let metadata = vec![KeyValue {
+            key: NAMESPACE_HEADER.to_string(),
+            value: self.namespace.clone(),
+        }];
+        let mut client = self.client.lock().unwrap();
+        let mut result = Ok(());
+        // Once more on a new connection when the previous one is broken
+        for _ in 0..2 {
+            let connected = match client.take() {
+                Some(connected) => Ok(connected),
+                None => Client::connect(&self.address),
+            };
+            result = connected.and_then(|mut connected| {
+                let payload = request.encode_to_vec();
+                connected.call(EVENTS_SERVICE, "Forward", payload, metadata.clone())?;
+                *client = Some(connected);
+                Ok(())
+            });
+            if result.is_ok() {
+                return;
+            }
+        }
+        if let Err(err) = result {
+            warn!("unable to publish {}: {}", topic, err);
+        }
+    }
+}
+
+pub fn now() -> Timestamp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Timestamp {
+        seconds: now.as_secs() as i64,
+        nanos: now.subsec_nanos() as i32,
+    }
+}
@@ -0,0 +1,302 @@
+//! Stdio of the processes of the tasks: the fifos containerd names are copied
+//! from and to pipes, or the pty master of a terminal, by threads of the shim
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixListener,
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use log::warn;
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::{
+        socket::{recvmsg, ControlMessageOwned, MsgFlags},
+        uio::IoVec,
+    },
+    unistd::{dup2, pipe2},
+};
+use pura::core::{
+    common::{Error, Result},
+    terminal,
+};
+
+/// Prefix of the paths of plain files, the other paths are fifos
+const FILE_SCHEME: &str = "file://";
+
+/// The processes fork with the stdio of the shim, swapped one at a time
+static INHERITED: Mutex<()> = Mutex::new(());
+
+/// Stdio of a process as given by containerd, empty paths are /dev/null
+#[derive(Debug, Clone, Default)]
+pub struct Stdio {
+    pub stdin: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub terminal: bool,
+}
+
+/// Fds given to a new process as its stdin, stdout and stderr
+pub struct Io {
+    fds: [File; 3],
+    /// Where the stdin is copied to, closed by CloseIO
+    pub stdin: Input,
+}
+
+/// Write end of the stdin of a process, None once closed
+pub type Input = Arc<Mutex<Option<File>>>;
+
+impl Stdio {
+    /// Pipes of the process without a terminal, copied from and to the stdio
+    pub fn pipes(&self) -> Result<Io> {
+        let input: Input = Arc::new(Mutex::new(None));
+        let stdin = match self.stdin.is_empty() {
+            true => null()?,
+            false => {
+                let (read, write) = pipe()?;
+                *input.lock().unwrap() = Some(write);
+                copy_input(self.stdin.clone(), input.clone());
+                read
+            }
+        };
+        Ok(Io {
+            fds: [stdin, output(&self.stdout)?, output(&self.stderr)?],
+            stdin: input,
+        })
+    }
+
+    /// The container process of a terminal gets its pty slave during create,
+    /// its stdio is /dev/null until then
+    pub fn null() -> Result<Io> {
+        Ok(Io {
+            fds: [null()?, null()?, null()?],
+            stdin: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Copies the stdin to the pty master and the master to the stdout
+    pub fn console(&self, master: &File) -> Result<Input> {
+        let input: Input = Arc::new(Mutex::new(None));
+        if !self.stdin.is_empty() {
+            *input.lock().unwrap() = Some(dup(master)?);
+            copy_input(self.stdin.clone(), input.clone());
+        }
+        if !self.stdout.is_empty() {
+            copy_output(dup(master)?, self.stdout.clone());
+        }
+        Ok(input)
+    }
+}
+
+impl Io {
+    /// Runs the function with the stdio of the shim swapped for the fds of the
+    /// process, so the processes it forks inherit them
+    pub fn inherit<T>(&self, fun: impl FnOnce() -> Result<T>) -> Result<T> {
+        let _lock = INHERITED.lock().unwrap();
+        let mut saved = Vec::new();
+        for fd in 0..3 {
+            saved.push(
+                fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(3))
+                    .map_err(Error::sys("unable to save the stdio"))?,
+            );
+        }
+        let saved: Vec<File> = saved
+            .into_iter()
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .collect();
+        let swapped = self
+            .fds
+            .iter()
+            .enumerate()
+            .try_for_each(|(fd, file)| dup2(file.as_raw_fd(), fd as RawFd).map(drop))
+            .map_err(Error::sys("unable to set the stdio of the process"));
+        let result = swapped.and_then(|_| fun());
+        for (fd, file) in saved.iter().enumerate() {
+            dup2(file.as_raw_fd(), fd as RawFd)
+                .map_err(Error::sys("unable to restore the stdio"))?;
+        }
+        result
+    }
+}
+
+/// Unix socket the container process sends the master of its pty to, like
+/// the --console-socket of runc
+pub struct ConsoleSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ConsoleSocket {
+    pub fn bind(path: &Path) -> Result<ConsoleSocket> {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .map_err(Error::io(format!("unable to bind {}", path.display())))?;
+        Ok(ConsoleSocket {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+
+    /// Receives the pty master, sent by the container process before create
+    /// returns
+    pub fn receive(&self) -> Result<File> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .map_err(Error::io("unable to accept the console socket connection"))?;
+        let mut buf = [0u8; 64];
+        let iov = [IoVec::from_mut_slice(&mut buf)];
+        let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+        let msg = recvmsg(
+            stream.as_raw_fd(),
+            &iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(Error::sys("unable to receive the pty master"))?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                if let Some(fd) = fds.first() {
+                    return Ok(unsafe { File::from_raw_fd(*fd) });
+                }
+            }
+        }
+        Err(Error::Runtime("no pty master received".to_string()))
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn null() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(Error::io("unable to open /dev/null"))
+}
+
+fn pipe() -> Result<(File, File)> {
+    let (read, write) = pipe2(OFlag::O_CLOEXEC).map_err(Error::sys("unable to create a pipe"))?;
+    Ok(unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) })
+}
+
+fn dup(file: &File) -> Result<File> {
+    let fd = fcntl(file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(3))
+        .map_err(Error::sys("unable to dup the pty master"))?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Write end of a pipe copied to the output, read by the process
+fn output(path: &str) -> Result<File> {
+    if path.is_empty() {
+        return null();
+    }
+    let (read, write) = pipe()?;
+    copy_output(read, path.to_string());
+    Ok(write)
+}
+
+/// Copies the input to the stdin of the process until its end or CloseIO
+fn copy_input(path: String, input: Input) {
+    thread::spawn(move || {
+        // Blocks until containerd opens the fifo for writing
+        let mut from = match open_input(&path) {
+            Ok(from) => from,
+            Err(err) => return warn!("{}", err),
+        };
+        let mut buf = [0u8; 32 * 1024];
+        loop {
+            let num = match from.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(num) => num,
+            };
+            let written = match input.lock().unwrap().as_mut() {
+                Some(to) => to.write_all(&buf[..num]).is_ok(),
+                None => false,
+            };
+            if !written {
+                break;
+            }
+        }
+        // The process reads the end of its stdin
+        input.lock().unwrap().take();
+    });
+}
+
+/// Copies the output of the process to the fifo or file until the process
+/// and its children closed it
+fn copy_output(from: File, path: String) {
+    thread::spawn(move || {
+        let result =
+            open_output(&path).and_then(|to| terminal::proxy(from.as_raw_fd(), to.as_raw_fd()));
+        if let Err(err) = result {
+            warn!("unable to copy the output to {}: {}", path, err);
+        }
+    });
+}
+
+fn open_input(path: &str) -> Result<File> {
+    let path = path.strip_prefix(FILE_SCHEME).unwrap_or(path);
+    File::open(path).map_err(Error::io(format!("unable to open {}", path)))
+}
+
+fn open_output(path: &str) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    let path = match path.strip_prefix(FILE_SCHEME) {
+        Some(path) => {
+            options.append(true).create(true);
+            path
+        }
+        // Blocks until containerd opens the fifo for reading
+        None => path,
+    };
+    options
+        .open(path)
+        .map_err(Error::io(format!("unable to open {}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command, thread, time::Duration};
+
+    use super::Stdio;
+
+    #[test]
+    fn inherit() {
+        let dir = tempfile::tempdir().unwrap();
+        let stdout = dir.path().join("stdout");
+        let stdio = Stdio {
+            stdout: format!("file://{}", stdout.display()),
+            ..Default::default()
+        };
+        let io = stdio.pipes().unwrap();
+        let status = io
+            .inherit(|| Ok(Command::new("echo").arg("hello").status().unwrap()))
+            .unwrap();
+        assert!(status.success());
+        // The copy ends once the shim closed its end too
+        drop(io);
+        for _ in 0..50 {
+            if fs::read_to_string(&stdout).unwrap_or_default() == "hello\n" {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("stdout not copied");
+    }
+}
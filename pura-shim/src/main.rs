@@ -0,0 +1,337 @@
+//! containerd-shim-pura-v2, the shim v2 of containerd for pura.
+//!
+//! containerd runs `start` in the bundle of a new task: it spawns the shim
+//! serving the task service on a socket of its own, the one of the pod for
+//! the containers of a pod, and prints its address. `delete` cleans up a task
+//! whose shim is gone.
+
+mod events;
+mod io;
+mod mount;
+mod proto;
+mod task;
+mod ttrpc;
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::{
+        fs::{DirBuilderExt, OpenOptionsExt},
+        io::{AsRawFd, FromRawFd, IntoRawFd},
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    sync::Arc,
+};
+
+use log::Level;
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    unistd::{dup2, setsid},
+};
+use prost::Message;
+use pura::{
+    core::{
+        common::{Error, Result},
+        logger::{ContainerLogger, LogFormat},
+    },
+    oci::spec::SpecFile,
+    Container,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{events::Publisher, proto::DeleteResponse, task::TaskService};
+
+/// Runtime root of the containers of a namespace, under the one of containerd
+const ROOT: &str = "/run/containerd/pura";
+/// Directory of the sockets of the shims, like the other shims of containerd
+const SOCKET_DIR: &str = "/run/containerd/s";
+/// Annotation of the containers of a pod naming its sandbox, they share a shim
+const SANDBOX_ANNOTATION: &str = "io.kubernetes.cri.sandbox-id";
+/// Fd of the listening socket in the spawned shim
+const LISTENER_FD: i32 = 3;
+/// Exit status of a task deleted by `delete`, killed by SIGKILL
+const KILLED_EXIT_STATUS: u32 = 128 + 9;
+
+/// Flags of the shim, named like the Go flags of the other shims
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    namespace: String,
+    address: String,
+    publish_binary: String,
+    id: String,
+    bundle: Option<String>,
+    debug: bool,
+    /// Socket of the spawned shim, for its Shutdown to remove
+    socket: Option<String>,
+    /// `start` or `delete`, the spawned shim has none
+    action: Option<String>,
+}
+
+fn parse(args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut options = Options::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+            Some(flag) => flag,
+            None if options.action.is_none() => {
+                options.action = Some(arg);
+                continue;
+            }
+            None => return Err(Error::Runtime(format!("unexpected argument {}", arg))),
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+        if name == "debug" {
+            options.debug = value.is_none_or(|value| value != "false");
+            continue;
+        }
+        let value = match value {
+            Some(value) => value,
+            None => args
+                .next()
+                .ok_or_else(|| Error::Runtime(format!("flag -{} needs a value", name)))?,
+        };
+        match name {
+            "namespace" => options.namespace = value,
+            "address" => options.address = value,
+            "publish-binary" => options.publish_binary = value,
+            "id" => options.id = value,
+            "bundle" => options.bundle = Some(value),
+            "socket" => options.socket = Some(value),
+            _ => return Err(Error::Runtime(format!("unknown flag -{}", name))),
+        }
+    }
+    if options.namespace.is_empty() {
+        return Err(Error::Runtime(
+            "the -namespace flag is required".to_string(),
+        ));
+    }
+    if options.id.is_empty() && options.action.is_some() {
+        return Err(Error::Runtime("the -id flag is required".to_string()));
+    }
+    Ok(options)
+}
+
+/// Socket of the shim of the group, named after the address of containerd and
+/// the namespace too so several containerd daemons never share it
+fn socket_path(address: &str, namespace: &str, group: &str) -> PathBuf {
+    let name = Path::new(address).join(namespace).join(group);
+    let digest = Sha256::digest(name.to_string_lossy().as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Path::new(SOCKET_DIR).join(hex)
+}
+
+fn bundle(options: &Options) -> Result<PathBuf> {
+    match &options.bundle {
+        Some(bundle) => Ok(PathBuf::from(bundle)),
+        None => env::current_dir().map_err(Error::io("unable to get the working directory")),
+    }
+}
+
+/// Spawns the shim of the task, unless the one of its pod is already running,
+/// and prints its address
+fn start(options: &Options) -> Result<()> {
+    let bundle = bundle(options)?;
+    // Only the annotations are read, borrowed from the mapped file
+    let file = SpecFile::open(&bundle.join("config.json"))?;
+    let spec = file.spec()?;
+    let group = spec
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(SANDBOX_ANNOTATION))
+        .map_or(options.id.as_str(), |group| group);
+    let socket = socket_path(&options.address, &options.namespace, group);
+    let address = format!("unix://{}", socket.display());
+
+    if UnixStream::connect(&socket).is_err() {
+        let _ = fs::remove_file(&socket);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o711)
+            .create(SOCKET_DIR)
+            .map_err(Error::io(format!("unable to create {}", SOCKET_DIR)))?;
+        let listener = UnixListener::bind(&socket)
+            .map_err(Error::io(format!("unable to bind {}", socket.display())))?;
+        if let Err(err) = spawn(options, &bundle, &socket, &listener) {
+            let _ = fs::remove_file(&socket);
+            return Err(err);
+        }
+    }
+
+    fs::write(bundle.join("address"), &address)
+        .map_err(Error::io("unable to write the address of the shim"))?;
+    print!("{}", address);
+    Ok(())
+}
+
+/// Runs the shim in a session of its own with the listener as its fd 3
+fn spawn(options: &Options, bundle: &Path, socket: &Path, listener: &UnixListener) -> Result<()> {
+    let exe = env::current_exe().map_err(Error::io("unable to find the shim executable"))?;
+    let mut command = Command::new(exe);
+    command
+        .args(["-namespace", &options.namespace])
+        .args(["-id", &options.id])
+        .args(["-address", &options.address])
+        .args(["-publish-binary", &options.publish_binary])
+        .arg("-socket")
+        .arg(socket)
+        .current_dir(bundle)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if options.debug {
+        command.arg("-debug");
+    }
+    let listener = listener.as_raw_fd();
+    unsafe {
+        command.pre_exec(move || {
+            let errno = |err: nix::Error| err.as_errno().unwrap_or(Errno::EINVAL);
+            setsid().map_err(errno)?;
+            match listener == LISTENER_FD {
+                true => fcntl(listener, FcntlArg::F_SETFD(FdFlag::empty())).map_err(errno)?,
+                false => dup2(listener, LISTENER_FD).map_err(errno)?,
+            };
+            Ok(())
+        });
+    }
+    command
+        .spawn()
+        .map(drop)
+        .map_err(Error::io("unable to spawn the shim"))
+}
+
+/// Serves the task service on the listener of `start`
+fn serve(options: &Options) -> Result<()> {
+    init_logger(options)?;
+    let listener = unsafe { UnixListener::from_raw_fd(LISTENER_FD) };
+    let root = Path::new(ROOT).join(&options.namespace);
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o711)
+        .create(&root)
+        .map_err(Error::io(format!("unable to create {}", root.display())))?;
+    // The address of the ttrpc socket of containerd is given to its shims
+    let events_address =
+        env::var("TTRPC_ADDRESS").unwrap_or_else(|_| format!("{}.ttrpc", options.address));
+    let service = TaskService::new(
+        &root.to_string_lossy(),
+        options.socket.as_ref().map(PathBuf::from),
+        Publisher::new(&events_address, &options.namespace),
+    );
+    ttrpc::serve(listener, Arc::new(task::Shim(Arc::new(service))))
+}
+
+/// Logs to the `log` fifo containerd reads in the bundle, to stderr without it
+fn init_logger(options: &Options) -> Result<()> {
+    let level = match options.debug {
+        true => Level::Debug,
+        false => Level::Info,
+    };
+    // Held open for reading too, so opening it never blocks and the writes
+    // never fail while containerd restarts
+    let fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open("log");
+    match fifo {
+        Ok(fifo) => {
+            let path = format!("/proc/self/fd/{}", fifo.into_raw_fd());
+            ContainerLogger::init(Some(&path), LogFormat::Text, level)
+        }
+        Err(_) => ContainerLogger::init(None, LogFormat::Text, level),
+    }
+}
+
+/// Deletes the container of a task whose shim is gone, and prints the
+/// response of its Delete
+fn delete(options: &Options) -> Result<()> {
+    let root = Path::new(ROOT).join(&options.namespace);
+    if let Ok(container) = Container::load(&root.to_string_lossy(), &options.id) {
+        container.force_delete()?;
+    }
+    mount::unmount(&bundle(options)?.join("rootfs"))?;
+    let response = DeleteResponse {
+        pid: 0,
+        exit_status: KILLED_EXIT_STATUS,
+        exited_at: Some(events::now()),
+    };
+    std::io::stdout()
+        .write_all(&response.encode_to_vec())
+        .map_err(Error::io("unable to write the delete response"))
+}
+
+fn main() {
+    let result = parse(env::args().skip(1)).and_then(|options| match options.action.as_deref() {
+        Some("start") => start(&options),
+        Some("delete") => delete(&options),
+        Some(action) => Err(Error::Runtime(format!("unknown action {}", action))),
+        None => serve(&options),
+    });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, socket_path, Options};
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn flags() {
+        let options = parse(args(&[
+            "-namespace",
+            "k8s.io",
+            "-address",
+            "/run/containerd/containerd.sock",
+            "-publish-binary=/usr/bin/containerd",
+            "-id",
+            "abc",
+            "-debug",
+            "start",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                namespace: "k8s.io".to_string(),
+                address: "/run/containerd/containerd.sock".to_string(),
+                publish_binary: "/usr/bin/containerd".to_string(),
+                id: "abc".to_string(),
+                debug: true,
+                action: Some("start".to_string()),
+                ..Default::default()
+            }
+        );
+        assert!(parse(args(&["-namespace", "default", "-id"])).is_err());
+        assert!(parse(args(&["-namespace", "default", "delete"])).is_err());
+        assert!(parse(args(&["-namespace", "default", "-unknown", "x"])).is_err());
+        assert!(parse(args(&["-namespace", "default", "start", "delete"])).is_err());
+    }
+
+    #[test]
+    fn sockets() {
+        let socket = socket_path("/run/containerd/containerd.sock", "default", "abc");
+        assert!(socket.starts_with("/run/containerd/s"));
+        assert_eq!(socket.file_name().unwrap().len(), 64);
+        assert_ne!(
+            socket,
+            socket_path("/run/containerd/containerd.sock", "k8s.io", "abc")
+        );
+    }
+}
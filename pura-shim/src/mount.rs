@@ -0,0 +1,114 @@
+//! Mounts of the rootfs containerd prepares for a task, usually the overlay
+//! of the snapshots of its image, mounted on the rootfs of the bundle
+
+use std::path::Path;
+
+use nix::{
+    errno::Errno,
+    mount::{mount, umount2, MntFlags, MsFlags},
+};
+use pura::core::common::{Error, Result};
+
+use crate::proto::Mount;
+
+/// Mounts the rootfs on the target in order, unmounted again when one of
+/// them fails
+pub fn mount_rootfs(mounts: &[Mount], target: &Path) -> Result<()> {
+    for (i, rootfs) in mounts.iter().enumerate() {
+        let (flags, data) = options(&rootfs.options);
+        let fstype = match rootfs.r#type.as_str() {
+            "bind" => None,
+            fstype => Some(fstype),
+        };
+        let mounted = mount(
+            Some(rootfs.source.as_str()),
+            target,
+            fstype,
+            flags,
+            Some(data.as_str()).filter(|data| !data.is_empty()),
+        );
+        if let Err(err) = mounted {
+            if i > 0 {
+                unmount(target)?;
+            }
+            return Err(Error::sys(format!(
+                "unable to mount the {} rootfs {} on {}",
+                rootfs.r#type,
+                rootfs.source,
+                target.display()
+            ))(err));
+        }
+    }
+    Ok(())
+}
+
+/// Unmounts the rootfs, if mounted
+pub fn unmount(target: &Path) -> Result<()> {
+    loop {
+        match umount2(target, MntFlags::MNT_DETACH) {
+            Ok(()) => continue,
+            Err(nix::Error::Sys(Errno::EINVAL)) | Err(nix::Error::Sys(Errno::ENOENT)) => {
+                return Ok(())
+            }
+            Err(err) => {
+                return Err(Error::sys(format!(
+                    "unable to unmount {}",
+                    target.display()
+                ))(err))
+            }
+        }
+    }
+}
+
+/// Flags of the options of the mount, the others are its data
+fn options(options: &[String]) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options {
+        let (flag, set) = match option.as_str() {
+            "ro" => (MsFlags::MS_RDONLY, true),
+            "rw" => (MsFlags::MS_RDONLY, false),
+            "bind" => (MsFlags::MS_BIND, true),
+            "rbind" => (MsFlags::MS_BIND | MsFlags::MS_REC, true),
+            "nosuid" => (MsFlags::MS_NOSUID, true),
+            "suid" => (MsFlags::MS_NOSUID, false),
+            "nodev" => (MsFlags::MS_NODEV, true),
+            "dev" => (MsFlags::MS_NODEV, false),
+            "noexec" => (MsFlags::MS_NOEXEC, true),
+            "exec" => (MsFlags::MS_NOEXEC, false),
+            "noatime" => (MsFlags::MS_NOATIME, true),
+            "relatime" => (MsFlags::MS_RELATIME, true),
+            "sync" => (MsFlags::MS_SYNCHRONOUS, true),
+            "async" => (MsFlags::MS_SYNCHRONOUS, false),
+            "defaults" => (MsFlags::empty(), true),
+            _ => {
+                data.push(option.as_str());
+                continue;
+            }
+        };
+        flags.set(flag, set);
+    }
+    (flags, data.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::mount::MsFlags;
+
+    use super::options;
+
+    #[test]
+    fn overlay_options() {
+        let (flags, data) = options(&[
+            "ro".to_string(),
+            "nosuid".to_string(),
+            "lowerdir=/a:/b".to_string(),
+            "index=off".to_string(),
+        ]);
+        assert_eq!(flags, MsFlags::MS_RDONLY | MsFlags::MS_NOSUID);
+        assert_eq!(data, "lowerdir=/a:/b,index=off");
+        let (flags, data) = options(&["rbind".to_string(), "rw".to_string()]);
+        assert_eq!(flags, MsFlags::MS_BIND | MsFlags::MS_REC);
+        assert!(data.is_empty());
+    }
+}
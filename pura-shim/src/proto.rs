@@ -0,0 +1,440 @@
+//! Protobuf messages of ttrpc, of the task service of the shim API
+//! (containerd's runtime/task/v2/shim.proto), of the task events and of the
+//! cgroup v2 metrics, with the field numbers of their .proto files
+
+use prost::Message;
+use prost_types::{Any, Timestamp};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Request {
+    #[prost(string, tag = "1")]
+    pub service: String,
+    #[prost(string, tag = "2")]
+    pub method: String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub payload: Vec<u8>,
+    #[prost(int64, tag = "4")]
+    pub timeout_nano: i64,
+    #[prost(message, repeated, tag = "5")]
+    pub metadata: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Response {
+    #[prost(message, optional, tag = "1")]
+    pub status: Option<Status>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+}
+
+/// google.rpc.Status of a failed request
+#[derive(Clone, PartialEq, Message)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(message, repeated, tag = "3")]
+    pub details: Vec<Any>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Mount {
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(string, tag = "2")]
+    pub source: String,
+    #[prost(string, tag = "3")]
+    pub target: String,
+    #[prost(string, repeated, tag = "4")]
+    pub options: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CreateTaskRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub bundle: String,
+    #[prost(message, repeated, tag = "3")]
+    pub rootfs: Vec<Mount>,
+    #[prost(bool, tag = "4")]
+    pub terminal: bool,
+    #[prost(string, tag = "5")]
+    pub stdin: String,
+    #[prost(string, tag = "6")]
+    pub stdout: String,
+    #[prost(string, tag = "7")]
+    pub stderr: String,
+    #[prost(string, tag = "8")]
+    pub checkpoint: String,
+    #[prost(string, tag = "9")]
+    pub parent_checkpoint: String,
+    #[prost(message, optional, tag = "10")]
+    pub options: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CreateTaskResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+}
+
+/// Request of the methods naming a process of a task: Start, Delete, State
+/// and Wait
+#[derive(Clone, PartialEq, Message)]
+pub struct ProcessRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StartResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DeleteResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(uint32, tag = "2")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "3")]
+    pub exited_at: Option<Timestamp>,
+}
+
+/// Request of the methods naming a task only: Pids, Stats, Connect, Pause
+/// and Resume
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PidsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub processes: Vec<ProcessInfo>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProcessInfo {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(message, optional, tag = "2")]
+    pub info: Option<Any>,
+}
+
+/// Info of an exec'd process in the Pids of its task
+#[derive(Clone, PartialEq, Message)]
+pub struct ProcessDetails {
+    #[prost(string, tag = "1")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KillRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(uint32, tag = "3")]
+    pub signal: u32,
+    #[prost(bool, tag = "4")]
+    pub all: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExecProcessRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(bool, tag = "3")]
+    pub terminal: bool,
+    #[prost(string, tag = "4")]
+    pub stdin: String,
+    #[prost(string, tag = "5")]
+    pub stdout: String,
+    #[prost(string, tag = "6")]
+    pub stderr: String,
+    /// JSON of the process of the runtime spec
+    #[prost(message, optional, tag = "7")]
+    pub spec: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResizePtyRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(uint32, tag = "3")]
+    pub width: u32,
+    #[prost(uint32, tag = "4")]
+    pub height: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CloseIoRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(bool, tag = "3")]
+    pub stdin: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct UpdateTaskRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// JSON of the linux resources of the runtime spec
+    #[prost(message, optional, tag = "2")]
+    pub resources: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WaitResponse {
+    #[prost(uint32, tag = "1")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "2")]
+    pub exited_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StatsResponse {
+    #[prost(message, optional, tag = "1")]
+    pub stats: Option<Any>,
+}
+
+/// containerd.v1.types.Status of a process
+pub const STATUS_CREATED: i32 = 1;
+pub const STATUS_RUNNING: i32 = 2;
+pub const STATUS_STOPPED: i32 = 3;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StateResponse {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub bundle: String,
+    #[prost(uint32, tag = "3")]
+    pub pid: u32,
+    #[prost(int32, tag = "4")]
+    pub status: i32,
+    #[prost(string, tag = "5")]
+    pub stdin: String,
+    #[prost(string, tag = "6")]
+    pub stdout: String,
+    #[prost(string, tag = "7")]
+    pub stderr: String,
+    #[prost(bool, tag = "8")]
+    pub terminal: bool,
+    #[prost(uint32, tag = "9")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "10")]
+    pub exited_at: Option<Timestamp>,
+    #[prost(string, tag = "11")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ConnectResponse {
+    #[prost(uint32, tag = "1")]
+    pub shim_pid: u32,
+    #[prost(uint32, tag = "2")]
+    pub task_pid: u32,
+    #[prost(string, tag = "3")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ShutdownRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(bool, tag = "2")]
+    pub now: bool,
+}
+
+/// Event forwarded to the events service of containerd
+#[derive(Clone, PartialEq, Message)]
+pub struct ForwardRequest {
+    #[prost(message, optional, tag = "1")]
+    pub envelope: Option<Envelope>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Envelope {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub namespace: String,
+    #[prost(string, tag = "3")]
+    pub topic: String,
+    #[prost(message, optional, tag = "4")]
+    pub event: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskCreate {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub bundle: String,
+    #[prost(message, repeated, tag = "3")]
+    pub rootfs: Vec<Mount>,
+    #[prost(message, optional, tag = "4")]
+    pub io: Option<TaskIo>,
+    #[prost(string, tag = "5")]
+    pub checkpoint: String,
+    #[prost(uint32, tag = "6")]
+    pub pid: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskIo {
+    #[prost(string, tag = "1")]
+    pub stdin: String,
+    #[prost(string, tag = "2")]
+    pub stdout: String,
+    #[prost(string, tag = "3")]
+    pub stderr: String,
+    #[prost(bool, tag = "4")]
+    pub terminal: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskStart {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(uint32, tag = "2")]
+    pub pid: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskDelete {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(uint32, tag = "2")]
+    pub pid: u32,
+    #[prost(uint32, tag = "3")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "4")]
+    pub exited_at: Option<Timestamp>,
+    #[prost(string, tag = "5")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskExit {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub id: String,
+    #[prost(uint32, tag = "3")]
+    pub pid: u32,
+    #[prost(uint32, tag = "4")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "5")]
+    pub exited_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskExecAdded {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskExecStarted {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(uint32, tag = "3")]
+    pub pid: u32,
+}
+
+/// io.containerd.cgroups.v2.Metrics of the Stats of a task
+#[derive(Clone, PartialEq, Message)]
+pub struct Metrics {
+    #[prost(message, optional, tag = "1")]
+    pub pids: Option<PidsStat>,
+    #[prost(message, optional, tag = "2")]
+    pub cpu: Option<CpuStat>,
+    #[prost(message, optional, tag = "4")]
+    pub memory: Option<MemoryStat>,
+    #[prost(message, optional, tag = "6")]
+    pub io: Option<IoStat>,
+    #[prost(message, optional, tag = "8")]
+    pub memory_events: Option<MemoryEvents>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PidsStat {
+    #[prost(uint64, tag = "1")]
+    pub current: u64,
+    #[prost(uint64, tag = "2")]
+    pub limit: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CpuStat {
+    #[prost(uint64, tag = "1")]
+    pub usage_usec: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MemoryStat {
+    #[prost(uint64, tag = "32")]
+    pub usage: u64,
+    #[prost(uint64, tag = "33")]
+    pub usage_limit: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct IoStat {
+    #[prost(message, repeated, tag = "1")]
+    pub usage: Vec<IoEntry>,
+}
+
+/// I/O of a device, the totals of the cgroup go to device 0:0
+#[derive(Clone, PartialEq, Message)]
+pub struct IoEntry {
+    #[prost(uint64, tag = "1")]
+    pub major: u64,
+    #[prost(uint64, tag = "2")]
+    pub minor: u64,
+    #[prost(uint64, tag = "3")]
+    pub rbytes: u64,
+    #[prost(uint64, tag = "4")]
+    pub wbytes: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MemoryEvents {
+    #[prost(uint64, tag = "5")]
+    pub oom_kill: u64,
+}
+
+/// Any of the message, under its full protobuf name
+pub fn any(type_url: &str, message: &impl Message) -> Any {
+    Any {
+        type_url: type_url.to_string(),
+        value: message.encode_to_vec(),
+    }
+}
@@ -0,0 +1,904 @@
+//! Task service of the shim API over the library API: a container for each
+//! task, the processes exec'd in it, and their events
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use log::{debug, warn};
+use nix::unistd::{getpid, Pid};
+use prost_types::Timestamp;
+use pura::{
+    core::{
+        cgroups::{self, Cgroup},
+        common::{Error, Result},
+        fork,
+        restart::RestartPolicy,
+        terminal,
+    },
+    metrics::Stats,
+    oci::{
+        ops::{Create, Exec},
+        spec::{self, Resources},
+    },
+    Container, Status,
+};
+
+use crate::{
+    events::{self, now, Publisher},
+    io::{ConsoleSocket, Input, Stdio},
+    mount,
+    proto::{
+        self, any, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteResponse,
+        ExecProcessRequest, KillRequest, Metrics, PidsResponse, ProcessDetails, ProcessInfo,
+        ProcessRequest, ResizePtyRequest, ShutdownRequest, StartResponse, StateResponse,
+        StatsResponse, TaskRequest, UpdateTaskRequest, WaitResponse,
+    },
+    ttrpc::{self, unary, ALREADY_EXISTS, FAILED_PRECONDITION, NOT_FOUND, UNIMPLEMENTED},
+};
+
+pub const SERVICE: &str = "containerd.task.v2.Task";
+
+/// Exit status of a process whose exit code is unknown
+const UNKNOWN_EXIT_STATUS: u32 = 255;
+const METRICS_TYPE: &str = "io.containerd.cgroups.v2.Metrics";
+const PROCESS_DETAILS_TYPE: &str = "containerd.runc.v1.ProcessDetails";
+
+/// Container of a task
+struct Task {
+    container: Container,
+    bundle: String,
+    /// Whether the shim mounted the rootfs of the bundle
+    mounted: bool,
+    init: Arc<Process>,
+    execs: Mutex<HashMap<String, Arc<Process>>>,
+}
+
+/// Init process of a task or process exec'd in it
+struct Process {
+    stdio: Stdio,
+    /// Process of the spec of an exec'd process, run by its Start
+    spec: Mutex<Option<spec::Process>>,
+    state: Mutex<ProcessState>,
+    exited: Condvar,
+}
+
+#[derive(Default)]
+struct ProcessState {
+    pid: u32,
+    exit: Option<(u32, Timestamp)>,
+    stdin: Option<Input>,
+    /// pty master of a process with a terminal
+    console: Option<File>,
+}
+
+impl Process {
+    fn new(stdio: Stdio, spec: Option<spec::Process>) -> Process {
+        Process {
+            stdio,
+            spec: Mutex::new(spec),
+            state: Mutex::new(ProcessState::default()),
+            exited: Condvar::new(),
+        }
+    }
+
+    fn pid(&self) -> u32 {
+        self.state.lock().unwrap().pid
+    }
+
+    fn exit(&self) -> Option<(u32, Timestamp)> {
+        self.state.lock().unwrap().exit.clone()
+    }
+
+    /// Records the exit status and wakes up the waits
+    fn set_exit(&self, status: u32) -> Timestamp {
+        let exited_at = now();
+        self.state.lock().unwrap().exit = Some((status, exited_at.clone()));
+        self.exited.notify_all();
+        exited_at
+    }
+
+    fn wait(&self) -> (u32, Timestamp) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(exit) = &state.exit {
+                return exit.clone();
+            }
+            state = self.exited.wait(state).unwrap();
+        }
+    }
+}
+
+/// Tasks of the shim, the containers of a pod share one shim
+pub struct TaskService {
+    /// Runtime root of the containers
+    root: String,
+    /// Socket of the shim, removed by Shutdown
+    socket: Option<PathBuf>,
+    events: Publisher,
+    tasks: Mutex<HashMap<String, Arc<Task>>>,
+    /// Names the console sockets and the pid files of the execs
+    next: AtomicU32,
+    shutdown: AtomicBool,
+}
+
+impl TaskService {
+    pub fn new(root: &str, socket: Option<PathBuf>, events: Publisher) -> TaskService {
+        TaskService {
+            root: root.to_string(),
+            socket,
+            events,
+            tasks: Mutex::new(HashMap::new()),
+            next: AtomicU32::new(0),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    fn task(&self, id: &str) -> std::result::Result<Arc<Task>, ttrpc::Status> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ttrpc::Status::new(NOT_FOUND, format!("task {} not found", id)))
+    }
+
+    /// The init process of the task without an exec id
+    fn process(
+        &self,
+        id: &str,
+        exec_id: &str,
+    ) -> std::result::Result<(Arc<Task>, Arc<Process>), ttrpc::Status> {
+        let task = self.task(id)?;
+        if exec_id.is_empty() {
+            let init = task.init.clone();
+            return Ok((task, init));
+        }
+        let exec = task.execs.lock().unwrap().get(exec_id).cloned();
+        match exec {
+            Some(exec) => Ok((task, exec)),
+            None => Err(ttrpc::Status::new(
+                NOT_FOUND,
+                format!("process {} of task {} not found", exec_id, id),
+            )),
+        }
+    }
+
+    fn create(
+        self: &Arc<Self>,
+        request: CreateTaskRequest,
+    ) -> std::result::Result<CreateTaskResponse, ttrpc::Status> {
+        if !request.checkpoint.is_empty() {
+            return Err(ttrpc::Status::new(UNIMPLEMENTED, "restoring a checkpoint"));
+        }
+        if self.tasks.lock().unwrap().contains_key(&request.id) {
+            return Err(ttrpc::Status::new(
+                ALREADY_EXISTS,
+                format!("task {} already exists", request.id),
+            ));
+        }
+        let rootfs = Path::new(&request.bundle).join("rootfs");
+        mount::mount_rootfs(&request.rootfs, &rootfs)?;
+        let stdio = Stdio {
+            stdin: request.stdin.clone(),
+            stdout: request.stdout.clone(),
+            stderr: request.stderr.clone(),
+            terminal: request.terminal,
+        };
+        let init = Arc::new(Process::new(stdio, None));
+        let container = match self.create_container(&request, &init) {
+            Ok(container) => container,
+            Err(err) => {
+                if !request.rootfs.is_empty() {
+                    let _ = mount::unmount(&rootfs);
+                }
+                return Err(err.into());
+            }
+        };
+        let pid = init.pid();
+        let task = Arc::new(Task {
+            container,
+            bundle: request.bundle.clone(),
+            mounted: !request.rootfs.is_empty(),
+            init,
+            execs: Mutex::new(HashMap::new()),
+        });
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert(request.id.clone(), task.clone());
+
+        self.events.publish(
+            events::TASK_CREATE,
+            &proto::TaskCreate {
+                container_id: request.id.clone(),
+                bundle: request.bundle.clone(),
+                rootfs: request.rootfs.clone(),
+                io: Some(proto::TaskIo {
+                    stdin: request.stdin,
+                    stdout: request.stdout,
+                    stderr: request.stderr,
+                    terminal: request.terminal,
+                }),
+                checkpoint: String::new(),
+                pid,
+            },
+        );
+        // Waited for from its creation, it may never be started
+        let service = self.clone();
+        thread::spawn(move || {
+            let exit_code = task.container.wait().unwrap_or_else(|err| {
+                warn!("unable to wait for task {}: {}", task.container.id(), err);
+                None
+            });
+            service.exited(&task, "", &task.init, exit_code);
+        });
+        Ok(CreateTaskResponse { pid })
+    }
+
+    /// Creates the container with the stdio of its init process
+    fn create_container(&self, request: &CreateTaskRequest, init: &Process) -> Result<Container> {
+        let console = match request.terminal {
+            true => Some(ConsoleSocket::bind(&self.console_socket())?),
+            false => None,
+        };
+        let io = match &console {
+            Some(_) => Stdio::null()?,
+            None => init.stdio.pipes()?,
+        };
+        let create = Create {
+            id: request.id.clone(),
+            bundle: request.bundle.clone(),
+            console_socket: console.as_ref().map(ConsoleSocket::path),
+            pid_file: None,
+            root: self.root.clone(),
+            network: None,
+            // The shim is the parent of the container process, it waits for it
+            detach: false,
+            init: false,
+            devices: Vec::new(),
+            gpus: None,
+            image: None,
+            rootfs_tar: None,
+            overlay: false,
+            volumes: Vec::new(),
+            record: false,
+            log_rotation: None,
+            console_buffer: None,
+            pod: None,
+            restart: RestartPolicy::No,
+        };
+        let container = io.inherit(|| Container::create(&create))?;
+
+        let mut state = init.state.lock().unwrap();
+        state.stdin = Some(io.stdin.clone());
+        let console = console
+            .map(|console| console.receive())
+            .transpose()
+            .and_then(|master| {
+                if let Some(master) = &master {
+                    state.stdin = Some(init.stdio.console(master)?);
+                }
+                Ok(master)
+            })
+            .and_then(|master| Ok((master, container.state()?.pid as u32)));
+        match console {
+            Ok((master, pid)) => {
+                state.console = master;
+                state.pid = pid;
+                Ok(container)
+            }
+            Err(err) => {
+                let _ = container.force_delete();
+                Err(err)
+            }
+        }
+    }
+
+    fn console_socket(&self) -> PathBuf {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Path::new(&self.root).join(format!("console-{}-{}.sock", getpid(), next))
+    }
+
+    /// Records the exit of the process and publishes it
+    fn exited(&self, task: &Task, exec_id: &str, process: &Process, exit_code: Option<i32>) {
+        let status = match exit_code {
+            Some(exit_code) => exit_code as u32,
+            None => {
+                debug!("unknown exit code of task {}", task.container.id());
+                UNKNOWN_EXIT_STATUS
+            }
+        };
+        let exited_at = process.set_exit(status);
+        let id = match exec_id.is_empty() {
+            true => task.container.id(),
+            false => exec_id,
+        };
+        self.events.publish(
+            events::TASK_EXIT,
+            &proto::TaskExit {
+                container_id: task.container.id().to_string(),
+                id: id.to_string(),
+                pid: process.pid(),
+                exit_status: status,
+                exited_at: Some(exited_at),
+            },
+        );
+    }
+
+    fn start(
+        self: &Arc<Self>,
+        request: ProcessRequest,
+    ) -> std::result::Result<StartResponse, ttrpc::Status> {
+        let (task, process) = self.process(&request.id, &request.exec_id)?;
+        if request.exec_id.is_empty() {
+            task.container.start()?;
+            let pid = process.pid();
+            self.events.publish(
+                events::TASK_START,
+                &proto::TaskStart {
+                    container_id: request.id,
+                    pid,
+                },
+            );
+            return Ok(StartResponse { pid });
+        }
+
+        let spec = process.spec.lock().unwrap().take().ok_or_else(|| {
+            ttrpc::Status::new(
+                FAILED_PRECONDITION,
+                format!("process {} is already started", request.exec_id),
+            )
+        })?;
+        let pid = self.exec(&task, &process, spec)?;
+        self.events.publish(
+            events::TASK_EXEC_STARTED,
+            &proto::TaskExecStarted {
+                container_id: request.id.clone(),
+                exec_id: request.exec_id.clone(),
+                pid,
+            },
+        );
+        let service = self.clone();
+        thread::spawn(move || {
+            let exit_code = fork::Process::open(Pid::from_raw(pid as i32), None)
+                .and_then(|exec| {
+                    exec.wait_exit()?;
+                    exec.exit_code()
+                })
+                .unwrap_or_else(|err| {
+                    warn!("unable to wait for process {}: {}", request.exec_id, err);
+                    None
+                });
+            service.exited(&task, &request.exec_id, &process, exit_code);
+        });
+        Ok(StartResponse { pid })
+    }
+
+    /// Executes the process of the spec in the container, returning its pid
+    fn exec(&self, task: &Task, process: &Process, spec: spec::Process) -> Result<u32> {
+        let pid_file = task.container.path().join(format!(
+            "exec-{}.pid",
+            self.next.fetch_add(1, Ordering::Relaxed)
+        ));
+        let exec = Exec {
+            id: task.container.id().to_string(),
+            root: self.root.clone(),
+            args: spec.args.unwrap_or_default(),
+            detach: true,
+            pid_file: Some(pid_file.to_string_lossy().to_string()),
+            env: spec.env.unwrap_or_default(),
+            user: spec.user.map(|user| format!("{}:{}", user.uid, user.gid)),
+            cwd: Some(spec.cwd).filter(|cwd| !cwd.is_empty()),
+            cap_add: Vec::new(),
+            cap_drop: Vec::new(),
+            cgroup: None,
+            resources: None,
+        };
+        if exec.args.is_empty() {
+            return Err(Error::Bundle("the process has no args".to_string()));
+        }
+        let io = process.stdio.pipes()?;
+        io.inherit(|| task.container.exec(&exec))?;
+        let pid = std::fs::read_to_string(&pid_file)
+            .map_err(Error::io("unable to read the pid of the process"))?;
+        let _ = std::fs::remove_file(&pid_file);
+        let pid = pid
+            .trim()
+            .parse()
+            .map_err(|_| Error::Runtime(format!("invalid pid {:?}", pid)))?;
+        let mut state = process.state.lock().unwrap();
+        state.pid = pid;
+        state.stdin = Some(io.stdin.clone());
+        Ok(pid)
+    }
+
+    fn delete(
+        &self,
+        request: ProcessRequest,
+    ) -> std::result::Result<DeleteResponse, ttrpc::Status> {
+        let (task, process) = self.process(&request.id, &request.exec_id)?;
+        if !request.exec_id.is_empty() {
+            let started = process.spec.lock().unwrap().is_none();
+            let exit = process.exit();
+            if started && exit.is_none() {
+                return Err(ttrpc::Status::new(
+                    FAILED_PRECONDITION,
+                    format!("process {} is running", request.exec_id),
+                ));
+            }
+            task.execs.lock().unwrap().remove(&request.exec_id);
+            let (exit_status, exited_at) = exit.unwrap_or((0, now()));
+            return Ok(DeleteResponse {
+                pid: process.pid(),
+                exit_status,
+                exited_at: Some(exited_at),
+            });
+        }
+
+        if process.exit().is_none() {
+            // Like runc, the process of a created task is killed
+            match task.container.state()?.status {
+                Status::Created => task.container.kill(nix::libc::SIGKILL)?,
+                _ => {
+                    return Err(ttrpc::Status::new(
+                        FAILED_PRECONDITION,
+                        format!("task {} is running", request.id),
+                    ))
+                }
+            }
+        }
+        let (exit_status, exited_at) = process.wait();
+        task.container.clone().delete()?;
+        if task.mounted {
+            mount::unmount(&Path::new(&task.bundle).join("rootfs"))?;
+        }
+        self.tasks.lock().unwrap().remove(&request.id);
+        let pid = process.pid();
+        self.events.publish(
+            events::TASK_DELETE,
+            &proto::TaskDelete {
+                container_id: request.id.clone(),
+                pid,
+                exit_status,
+                exited_at: Some(exited_at.clone()),
+                id: request.id,
+            },
+        );
+        Ok(DeleteResponse {
+            pid,
+            exit_status,
+            exited_at: Some(exited_at),
+        })
+    }
+
+    fn exec_process(&self, request: ExecProcessRequest) -> std::result::Result<(), ttrpc::Status> {
+        let task = self.task(&request.id)?;
+        if request.terminal {
+            return Err(ttrpc::Status::new(UNIMPLEMENTED, "exec with a terminal"));
+        }
+        let spec: spec::Process = match &request.spec {
+            Some(spec) => serde_json::from_slice(&spec.value)
+                .map_err(|err| Error::Bundle(format!("invalid process: {}", err)))?,
+            None => return Err(Error::Bundle("no process to exec".to_string()).into()),
+        };
+        let stdio = Stdio {
+            stdin: request.stdin,
+            stdout: request.stdout,
+            stderr: request.stderr,
+            terminal: false,
+        };
+        {
+            let mut execs = task.execs.lock().unwrap();
+            if execs.contains_key(&request.exec_id) {
+                return Err(ttrpc::Status::new(
+                    ALREADY_EXISTS,
+                    format!("process {} already exists", request.exec_id),
+                ));
+            }
+            let process = Arc::new(Process::new(stdio, Some(spec)));
+            execs.insert(request.exec_id.clone(), process);
+        }
+        self.events.publish(
+            events::TASK_EXEC_ADDED,
+            &proto::TaskExecAdded {
+                container_id: request.id,
+                exec_id: request.exec_id,
+            },
+        );
+        Ok(())
+    }
+
+    fn kill(&self, request: KillRequest) -> std::result::Result<(), ttrpc::Status> {
+        let (task, process) = self.process(&request.id, &request.exec_id)?;
+        if process.exit().is_some() {
+            return Err(ttrpc::Status::new(NOT_FOUND, "process already finished"));
+        }
+        let signal = request.signal as i32;
+        if !request.exec_id.is_empty() {
+            let pid = process.pid();
+            if pid == 0 {
+                return Err(ttrpc::Status::new(
+                    FAILED_PRECONDITION,
+                    "process isn't started",
+                ));
+            }
+            fork::Process::open(Pid::from_raw(pid as i32), None)?.signal(signal)?;
+            return Ok(());
+        }
+        match request.all {
+            true => task.container.kill_all(signal)?,
+            false => task.container.kill(signal)?,
+        }
+        Ok(())
+    }
+
+    fn pids(&self, request: TaskRequest) -> std::result::Result<PidsResponse, ttrpc::Status> {
+        let task = self.task(&request.id)?;
+        let spec = task.container.spec()?;
+        let cgroup = Cgroup::new(task.container.id(), spec.linux.as_ref());
+        let execs: HashMap<u32, String> = task
+            .execs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(exec_id, process)| (process.pid(), exec_id.clone()))
+            .collect();
+        let processes = cgroups::procs(cgroup.path())
+            .into_iter()
+            .map(|pid| {
+                let pid = pid.as_raw() as u32;
+                let info = execs.get(&pid).map(|exec_id| {
+                    any(
+                        PROCESS_DETAILS_TYPE,
+                        &ProcessDetails {
+                            exec_id: exec_id.clone(),
+                        },
+                    )
+                });
+                ProcessInfo { pid, info }
+            })
+            .collect();
+        Ok(PidsResponse { processes })
+    }
+
+    fn stats(&self, request: TaskRequest) -> std::result::Result<StatsResponse, ttrpc::Status> {
+        let task = self.task(&request.id)?;
+        let stats = Stats::of(&task.container.state()?).ok_or_else(|| {
+            ttrpc::Status::new(NOT_FOUND, format!("task {} has no cgroup", request.id))
+        })?;
+        Ok(StatsResponse {
+            stats: Some(any(METRICS_TYPE, &metrics(&stats))),
+        })
+    }
+
+    fn state(&self, request: ProcessRequest) -> std::result::Result<StateResponse, ttrpc::Status> {
+        let (task, process) = self.process(&request.id, &request.exec_id)?;
+        let exit = process.exit();
+        let status = match (&exit, request.exec_id.is_empty()) {
+            (Some(_), _) => proto::STATUS_STOPPED,
+            (None, true) => match task.container.state()?.status {
+                Status::Creating => 0,
+                Status::Created => proto::STATUS_CREATED,
+                Status::Running => proto::STATUS_RUNNING,
+                Status::Stopped => proto::STATUS_STOPPED,
+            },
+            (None, false) if process.pid() == 0 => proto::STATUS_CREATED,
+            (None, false) => proto::STATUS_RUNNING,
+        };
+        let (exit_status, exited_at) = match exit {
+            Some((exit_status, exited_at)) => (exit_status, Some(exited_at)),
+            None => (0, None),
+        };
+        Ok(StateResponse {
+            id: request.id,
+            bundle: task.bundle.clone(),
+            pid: process.pid(),
+            status,
+            stdin: process.stdio.stdin.clone(),
+            stdout: process.stdio.stdout.clone(),
+            stderr: process.stdio.stderr.clone(),
+            terminal: process.stdio.terminal,
+            exit_status,
+            exited_at,
+            exec_id: request.exec_id,
+        })
+    }
+
+    fn wait(&self, request: ProcessRequest) -> std::result::Result<WaitResponse, ttrpc::Status> {
+        let (_, process) = self.process(&request.id, &request.exec_id)?;
+        let (exit_status, exited_at) = process.wait();
+        Ok(WaitResponse {
+            exit_status,
+            exited_at: Some(exited_at),
+        })
+    }
+
+    fn close_io(&self, request: proto::CloseIoRequest) -> std::result::Result<(), ttrpc::Status> {
+        let (_, process) = self.process(&request.id, &request.exec_id)?;
+        if request.stdin {
+            let stdin = process.state.lock().unwrap().stdin.clone();
+            if let Some(stdin) = stdin {
+                stdin.lock().unwrap().take();
+            }
+        }
+        Ok(())
+    }
+
+    fn resize_pty(&self, request: ResizePtyRequest) -> std::result::Result<(), ttrpc::Status> {
+        let (_, process) = self.process(&request.id, &request.exec_id)?;
+        let state = process.state.lock().unwrap();
+        let console = state.console.as_ref().ok_or_else(|| {
+            ttrpc::Status::new(FAILED_PRECONDITION, "the process has no terminal")
+        })?;
+        terminal::set_window_size(
+            console.as_raw_fd(),
+            request.height as u16,
+            request.width as u16,
+        )?;
+        Ok(())
+    }
+
+    fn update(&self, request: UpdateTaskRequest) -> std::result::Result<(), ttrpc::Status> {
+        let task = self.task(&request.id)?;
+        let resources: Resources = match &request.resources {
+            Some(resources) => serde_json::from_slice(&resources.value)
+                .map_err(|err| Error::Bundle(format!("invalid resources: {}", err)))?,
+            None => return Ok(()),
+        };
+        task.container.update(&resources)?;
+        Ok(())
+    }
+
+    fn connect(&self, request: TaskRequest) -> std::result::Result<ConnectResponse, ttrpc::Status> {
+        let task_pid = match self.task(&request.id) {
+            Ok(task) => task.init.pid(),
+            Err(_) => 0,
+        };
+        Ok(ConnectResponse {
+            shim_pid: getpid().as_raw() as u32,
+            task_pid,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        })
+    }
+
+    /// Exits once the last task is deleted, or right away with `now`
+    fn shutdown_tasks(&self, request: ShutdownRequest) -> std::result::Result<(), ttrpc::Status> {
+        if !request.now && !self.tasks.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+        self.shutdown.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Service of the shim, shared by the threads of the requests
+pub struct Shim(pub Arc<TaskService>);
+
+impl ttrpc::Service for Shim {
+    fn name(&self) -> &str {
+        SERVICE
+    }
+
+    fn handle(&self, method: &str, payload: &[u8]) -> std::result::Result<Vec<u8>, ttrpc::Status> {
+        let service = &self.0;
+        match method {
+            "Create" => unary(payload, |request| service.create(request)),
+            "Start" => unary(payload, |request| service.start(request)),
+            "Delete" => unary(payload, |request| service.delete(request)),
+            "Exec" => unary(payload, |request| service.exec_process(request)),
+            "Kill" => unary(payload, |request| service.kill(request)),
+            "Pids" => unary(payload, |request| service.pids(request)),
+            "Stats" => unary(payload, |request| service.stats(request)),
+            "State" => unary(payload, |request| service.state(request)),
+            "Wait" => unary(payload, |request| service.wait(request)),
+            "CloseIO" => unary(payload, |request| service.close_io(request)),
+            "ResizePty" => unary(payload, |request| service.resize_pty(request)),
+            "Update" => unary(payload, |request| service.update(request)),
+            "Connect" => unary(payload, |request| service.connect(request)),
+            "Shutdown" => unary(payload, |request| service.shutdown_tasks(request)),
+            _ => Err(ttrpc::Status::new(
+                UNIMPLEMENTED,
+                format!("{} isn't supported", method),
+            )),
+        }
+    }
+
+    fn shutdown(&self) -> bool {
+        self.0.shutdown.load(Ordering::SeqCst)
+    }
+}
+
+/// cgroup v2 metrics of the stats of the container
+fn metrics(stats: &Stats) -> Metrics {
+    let count = |value: Option<f64>| value.unwrap_or_default() as u64;
+    Metrics {
+        pids: stats.pids.map(|pids| proto::PidsStat {
+            current: pids as u64,
+            limit: 0,
+        }),
+        cpu: stats.cpu_seconds.map(|seconds| proto::CpuStat {
+            usage_usec: (seconds * 1e6) as u64,
+        }),
+        memory: stats.memory_usage.map(|usage| proto::MemoryStat {
+            usage: usage as u64,
+            usage_limit: count(stats.memory_limit),
+        }),
+        io: match (stats.io_read, stats.io_write) {
+            (None, None) => None,
+            (read, write) => Some(proto::IoStat {
+                usage: vec![proto::IoEntry {
+                    major: 0,
+                    minor: 0,
+                    rbytes: count(read),
+                    wbytes: count(write),
+                }],
+            }),
+        },
+        memory_events: stats.oom_kills.map(|oom_kills| proto::MemoryEvents {
+            oom_kill: oom_kills as u64,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::Arc, thread, time::Duration};
+
+    use nix::unistd::geteuid;
+    use prost_types::Any;
+    use pura::{
+        metrics::Stats,
+        oci::spec::{Mount, Spec},
+    };
+
+    use super::{metrics, TaskService};
+    use crate::{
+        events::Publisher,
+        proto::{self, CreateTaskRequest, ExecProcessRequest, KillRequest, ProcessRequest},
+    };
+
+    #[test]
+    fn stats_metrics() {
+        let stats = Stats {
+            memory_usage: Some(4096.0),
+            cpu_seconds: Some(1.5),
+            pids: Some(3.0),
+            io_read: Some(10.0),
+            ..Default::default()
+        };
+        let metrics = metrics(&stats);
+        assert_eq!(metrics.pids.unwrap().current, 3);
+        assert_eq!(metrics.cpu.unwrap().usage_usec, 1_500_000);
+        let memory = metrics.memory.unwrap();
+        assert_eq!((memory.usage, memory.usage_limit), (4096, 0));
+        assert_eq!(metrics.io.unwrap().usage[0].rbytes, 10);
+        assert!(metrics.memory_events.is_none());
+    }
+
+    fn read(path: &Path, expected: &str) -> bool {
+        (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(50));
+            fs::read_to_string(path).unwrap_or_default() == expected
+        })
+    }
+
+    /// Create, start, exec, kill, wait and delete of a task, with the binaries
+    /// of the host bind mounted
+    #[test]
+    fn lifecycle() {
+        if !geteuid().is_root() || !Path::new("/bin/sh").exists() {
+            eprintln!("skipping lifecycle: needs root and /bin/sh");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("bundle");
+        fs::create_dir_all(bundle.join("rootfs")).unwrap();
+        let mut spec = Spec::new()
+            .with_rootfs("rootfs", false)
+            .with_args(&["/bin/sh", "-c", "echo hello; exec sleep 100"])
+            .add_env("PATH", "/usr/local/bin:/usr/bin:/bin")
+            .add_mount(Mount::new("/dev", "tmpfs", "tmpfs", &["nosuid"]))
+            .add_mount(Mount::new("/proc", "proc", "proc", &[]));
+        for dir in ["/bin", "/sbin", "/lib", "/lib32", "/lib64", "/usr"] {
+            if Path::new(dir).exists() {
+                spec = spec.add_mount(Mount::new(dir, "bind", dir, &["bind"]));
+            }
+        }
+        let spec = ["pid", "mount", "uts", "ipc"]
+            .iter()
+            .fold(spec, |spec, namespace| spec.add_namespace(namespace, None));
+        spec.save(&bundle).unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let service = Arc::new(TaskService::new(
+            root.to_str().unwrap(),
+            None,
+            Publisher::new("", "default"),
+        ));
+        let id = format!("shim-{}", std::process::id());
+        let process = |exec_id: &str| ProcessRequest {
+            id: id.clone(),
+            exec_id: exec_id.to_string(),
+        };
+
+        let stdout = dir.path().join("stdout");
+        let pid = service
+            .create(CreateTaskRequest {
+                id: id.clone(),
+                bundle: bundle.to_string_lossy().to_string(),
+                stdout: format!("file://{}", stdout.display()),
+                ..Default::default()
+            })
+            .unwrap()
+            .pid;
+        assert_eq!(
+            service.state(process("")).unwrap().status,
+            proto::STATUS_CREATED
+        );
+        assert_eq!(service.start(process("")).unwrap().pid, pid);
+        assert!(read(&stdout, "hello\n"));
+        // The cgroup of the container only exists on the unified hierarchy
+        let pids = service.pids(proto::TaskRequest { id: id.clone() }).unwrap();
+        if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+            assert!(pids.processes.iter().any(|process| process.pid == pid));
+        }
+
+        let exec_stdout = dir.path().join("exec");
+        let exec = pura::oci::spec::Process {
+            args: Some(vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "echo exec; exit 3".to_string(),
+            ]),
+            cwd: "/".to_string(),
+            ..Default::default()
+        };
+        service
+            .exec_process(ExecProcessRequest {
+                id: id.clone(),
+                exec_id: "exec".to_string(),
+                stdout: format!("file://{}", exec_stdout.display()),
+                spec: Some(Any {
+                    type_url: "types.containerd.io/opencontainers/runtime-spec/1/Process"
+                        .to_string(),
+                    value: serde_json::to_vec(&exec).unwrap(),
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_ne!(service.start(process("exec")).unwrap().pid, 0);
+        assert_eq!(service.wait(process("exec")).unwrap().exit_status, 3);
+        assert!(read(&exec_stdout, "exec\n"));
+        assert_eq!(service.delete(process("exec")).unwrap().exit_status, 3);
+
+        // Running, it can't be deleted
+        assert!(service.delete(process("")).is_err());
+        service
+            .kill(KillRequest {
+                id: id.clone(),
+                signal: 9,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(service.wait(process("")).unwrap().exit_status, 137);
+        assert_eq!(
+            service.state(process("")).unwrap().status,
+            proto::STATUS_STOPPED
+        );
+        assert_eq!(service.delete(process("")).unwrap().exit_status, 137);
+        assert!(service.state(process("")).is_err());
+        assert!(!root.join(&id).exists());
+    }
+}
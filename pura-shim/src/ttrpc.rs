@@ -0,0 +1,354 @@
+//! ttrpc, the protocol of the shim API: protobuf requests and responses
+//! framed over a unix socket, like gRPC without HTTP/2
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use log::{debug, warn};
+use prost::Message;
+use pura::core::common::{Error, Result};
+
+pub use crate::proto::Status;
+use crate::proto::{KeyValue, Request, Response};
+
+/// Length, stream id, type and flags of a message
+const HEADER_LEN: usize = 10;
+/// Largest message ttrpc accepts
+const MAX_DATA_LEN: usize = 4 << 20;
+const MESSAGE_TYPE_REQUEST: u8 = 1;
+const MESSAGE_TYPE_RESPONSE: u8 = 2;
+
+/// Codes of google.rpc.Status
+pub const INVALID_ARGUMENT: i32 = 3;
+pub const NOT_FOUND: i32 = 5;
+pub const ALREADY_EXISTS: i32 = 6;
+pub const FAILED_PRECONDITION: i32 = 9;
+pub const UNIMPLEMENTED: i32 = 12;
+pub const INTERNAL: i32 = 13;
+
+/// Message of a stream of a connection
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub stream: u32,
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+/// Reads the next message, None once the peer closed the connection
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let stream = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if len > MAX_DATA_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "message of {} bytes over the limit of {}",
+                len, MAX_DATA_LEN
+            ),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+    Ok(Some(Frame {
+        stream,
+        kind: header[8],
+        data,
+    }))
+}
+
+pub fn write_frame(writer: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    if frame.data.len() > MAX_DATA_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "message of {} bytes over the limit of {}",
+                frame.data.len(),
+                MAX_DATA_LEN
+            ),
+        ));
+    }
+    let mut message = Vec::with_capacity(HEADER_LEN + frame.data.len());
+    message.extend_from_slice(&(frame.data.len() as u32).to_be_bytes());
+    message.extend_from_slice(&frame.stream.to_be_bytes());
+    message.extend_from_slice(&[frame.kind, 0]);
+    message.extend_from_slice(&frame.data);
+    writer.write_all(&message)
+}
+
+impl Status {
+    pub fn new(code: i32, message: impl Into<String>) -> Status {
+        Status {
+            code,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Status {
+        let code = match &err {
+            Error::NotFound(_) => NOT_FOUND,
+            Error::State(_) => FAILED_PRECONDITION,
+            Error::Bundle(_) | Error::Spec(_) => INVALID_ARGUMENT,
+            _ => INTERNAL,
+        };
+        Status::new(code, err.to_string())
+    }
+}
+
+/// Handles the requests of the methods of a service
+pub trait Service: Send + Sync + 'static {
+    /// Full name of the service, `package.Service`
+    fn name(&self) -> &str;
+
+    /// Decodes the payload of the request of the method and encodes the response
+    fn handle(&self, method: &str, payload: &[u8]) -> std::result::Result<Vec<u8>, Status>;
+
+    /// Whether the server exits once the response of the last request is sent
+    fn shutdown(&self) -> bool {
+        false
+    }
+}
+
+/// Decodes the request, calls the handler and encodes its response
+pub fn unary<Req: Message + Default, Resp: Message>(
+    payload: &[u8],
+    handler: impl FnOnce(Req) -> std::result::Result<Resp, Status>,
+) -> std::result::Result<Vec<u8>, Status> {
+    let request = Req::decode(payload)
+        .map_err(|err| Status::new(INVALID_ARGUMENT, format!("invalid request: {}", err)))?;
+    Ok(handler(request)?.encode_to_vec())
+}
+
+/// Serves the connections of the listener, each request in a thread of its
+/// own since Wait blocks until the process exits
+pub fn serve(listener: UnixListener, service: Arc<dyn Service>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream.map_err(Error::io("unable to accept a ttrpc connection"))?;
+        let service = service.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_connection(stream, service) {
+                warn!("ttrpc connection failed: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream, service: Arc<dyn Service>) -> io::Result<()> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = stream;
+    while let Some(frame) = read_frame(&mut reader)? {
+        if frame.kind != MESSAGE_TYPE_REQUEST {
+            debug!("ignoring ttrpc message of type {}", frame.kind);
+            continue;
+        }
+        let service = service.clone();
+        let writer = writer.clone();
+        thread::spawn(move || {
+            let response = respond(service.as_ref(), &frame.data);
+            let frame = Frame {
+                stream: frame.stream,
+                kind: MESSAGE_TYPE_RESPONSE,
+                data: response.encode_to_vec(),
+            };
+            let sent = match writer.lock() {
+                Ok(mut writer) => write_frame(&mut *writer, &frame),
+                Err(_) => return,
+            };
+            if let Err(err) = sent {
+                warn!("unable to send the ttrpc response: {}", err);
+            }
+            if service.shutdown() {
+                std::process::exit(0);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(service: &dyn Service, data: &[u8]) -> Response {
+    let result = match Request::decode(data) {
+        Ok(request) if request.service == service.name() => {
+            debug!("ttrpc request {}", request.method);
+            service.handle(&request.method, &request.payload)
+        }
+        Ok(request) => Err(Status::new(
+            UNIMPLEMENTED,
+            format!("unknown service {}", request.service),
+        )),
+        Err(err) => Err(Status::new(
+            INVALID_ARGUMENT,
+            format!("invalid request: {}", err),
+        )),
+    };
+    match result {
+        Ok(payload) => Response {
+            status: None,
+            payload,
+        },
+        Err(status) => Response {
+            status: Some(status),
+            payload: Vec::new(),
+        },
+    }
+}
+
+/// Connection to a ttrpc server, one request at a time
+pub struct Client {
+    stream: UnixStream,
+    /// Client streams are odd
+    next_stream: u32,
+}
+
+impl Client {
+    pub fn connect(path: &str) -> Result<Client> {
+        let stream = UnixStream::connect(path)
+            .map_err(Error::io(format!("unable to connect to {}", path)))?;
+        Ok(Client {
+            stream,
+            next_stream: 1,
+        })
+    }
+
+    /// Sends the request and returns the payload of its response
+    pub fn call(
+        &mut self,
+        service: &str,
+        method: &str,
+        payload: Vec<u8>,
+        metadata: Vec<KeyValue>,
+    ) -> Result<Vec<u8>> {
+        let stream = self.next_stream;
+        self.next_stream += 2;
+        let request = Request {
+            service: service.to_string(),
+            method: method.to_string(),
+            payload,
+            timeout_nano: 0,
+            metadata,
+        };
+        let frame = Frame {
+            stream,
+            kind: MESSAGE_TYPE_REQUEST,
+            data: request.encode_to_vec(),
+        };
+        write_frame(&mut self.stream, &frame)
+            .map_err(Error::io(format!("unable to send {}/{}", service, method)))?;
+        loop {
+            let frame = read_frame(&mut self.stream)
+                .map_err(Error::io(format!(
+                    "unable to receive {}/{}",
+                    service, method
+                )))?
+                .ok_or_else(|| Error::Runtime("ttrpc connection closed".to_string()))?;
+            if frame.kind != MESSAGE_TYPE_RESPONSE || frame.stream != stream {
+                continue;
+            }
+            let response = Response::decode(frame.data.as_slice()).map_err(|err| {
+                Error::Runtime(format!(
+                    "invalid response of {}/{}: {}",
+                    service, method, err
+                ))
+            })?;
+            return match response.status {
+                Some(status) if status.code != 0 => Err(Error::Runtime(format!(
+                    "{}/{} failed: {}",
+                    service, method, status.message
+                ))),
+                _ => Ok(response.payload),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, sync::Arc, thread};
+
+    use prost::Message;
+    use pura::core::common::Error;
+
+    use super::{read_frame, serve, unary, write_frame, Client, Frame, Service, Status};
+    use crate::proto::{ProcessRequest, StartResponse, TaskRequest};
+
+    struct Echo;
+
+    impl Service for Echo {
+        fn name(&self) -> &str {
+            "test.Echo"
+        }
+
+        fn handle(&self, method: &str, payload: &[u8]) -> Result<Vec<u8>, Status> {
+            match method {
+                "Start" => unary(payload, |request: ProcessRequest| {
+                    Ok(StartResponse {
+                        pid: request.id.len() as u32,
+                    })
+                }),
+                "Pids" => unary(payload, |request: TaskRequest| -> Result<(), Status> {
+                    Err(Error::NotFound(request.id).into())
+                }),
+                _ => Err(Status::new(super::UNIMPLEMENTED, method)),
+            }
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let frame = Frame {
+            stream: 3,
+            kind: 1,
+            data: b"payload".to_vec(),
+        };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &frame).unwrap();
+        assert_eq!(&buf[..10], &[0, 0, 0, 7, 0, 0, 0, 3, 1, 0]);
+        let mut reader = buf.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(frame));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+        // Over the 4MB limit
+        let mut reader: &[u8] = &[0x01, 0, 0, 0, 0, 0, 0, 1, 1, 0];
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ttrpc.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || serve(listener, Arc::new(Echo)));
+
+        let mut client = Client::connect(path.to_str().unwrap()).unwrap();
+        let request = ProcessRequest {
+            id: "abc".to_string(),
+            exec_id: String::new(),
+        };
+        let response = client
+            .call("test.Echo", "Start", request.encode_to_vec(), Vec::new())
+            .unwrap();
+        assert_eq!(StartResponse::decode(response.as_slice()).unwrap().pid, 3);
+
+        let err = client
+            .call("test.Echo", "Pids", Vec::new(), Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+        assert!(client
+            .call("test.Echo", "Stats", Vec::new(), Vec::new())
+            .is_err());
+        assert!(client
+            .call("test.Other", "Start", Vec::new(), Vec::new())
+            .is_err());
+    }
+}
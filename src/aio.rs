@@ -0,0 +1,608 @@
+//! Async (tokio) variant of the library API.
+//!
+//! Waiting on the container IPC sockets, receiving and proxying the console and
+//! waiting for the container process to exit are driven by the tokio reactor, so
+//! daemons embedding pura don't need a blocking thread per container.
+//! Operations running hooks are moved to the blocking thread pool.
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{self, fcntl, FcntlArg, OFlag, SpliceFFlags},
+    libc,
+    sys::{
+        socket::{accept, recvmsg, ControlMessageOwned, MsgFlags},
+        uio::IoVec,
+    },
+    unistd::{pipe2, read, write},
+};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, Interest, ReadBuf},
+    net::UnixListener,
+};
+
+use crate::container::Spawned;
+use crate::core::{
+    common::{Error, Result},
+    ipc::IpcChannel,
+    state::State,
+};
+use crate::oci::ops::Create;
+
+/// Async handle to a container living under a runtime root
+#[derive(Debug, Clone)]
+pub struct Container {
+    inner: crate::Container,
+}
+
+impl Container {
+    /// Loads an existing container from the runtime root
+    pub fn load(root: &str, id: &str) -> Result<Container> {
+        Ok(Container {
+            inner: crate::Container::load(root, id)?,
+        })
+    }
+
+    /// The blocking handle of the container
+    pub fn inner(&self) -> &crate::Container {
+        &self.inner
+    }
+
+    /// Creates the container from the bundle and waits until it's ready to be started.
+    /// createRuntime hooks run on the calling task.
+    pub async fn create(create: &Create) -> Result<Container> {
+        let (container, _lock, spawned) = crate::Container::spawn(create)?;
+        if let Err(err) = created(&container, create, spawned).await {
+            container.discard();
+            return Err(err);
+        }
+        Ok(Container { inner: container })
+    }
+
+    /// Runs the user-defined process of a created container
+    pub async fn start(&self) -> Result<()> {
+        let inner = self.inner.clone();
+        blocking(move || inner.start()).await
+    }
+
+    /// Sends the signal to the container process
+    pub async fn kill(&self, sig: i32) -> Result<()> {
+        self.inner.kill(sig)
+    }
+
+    /// Runs the poststop hooks and removes the container state
+    pub async fn delete(self) -> Result<()> {
+        let inner = self.inner;
+        blocking(move || inner.delete()).await
+    }
+
+    /// Reads the state of the container
+    pub async fn state(&self) -> Result<State> {
+        let inner = self.inner.clone();
+        blocking(move || inner.state()).await
+    }
+
+    /// Waits until the container process exits and marks the container as stopped.
+    ///
+    /// Returns the exit code (128 + signal number when killed by a signal) if
+    /// it's known, see `Process::exit_code`.
+    pub async fn wait(&self) -> Result<Option<i32>> {
+        let state = self.state().await?;
+        let exit_code = match self.inner.process(&state) {
+            Ok(process) => {
+                let pidfd = process.pidfd().ok_or_else(|| {
+                    Error::sys("pidfd_open failed")(nix::Error::Sys(Errno::ENOSYS))
+                })?;
+                // The pidfd becomes readable once the process exits
+                let pidfd = AsyncFd::with_interest(pidfd, Interest::READABLE)
+                    .map_err(Error::io("unable to register pidfd"))?;
+                let _ = pidfd
+                    .readable()
+                    .await
+                    .map_err(Error::io("error waiting on pidfd"))?;
+                drop(pidfd);
+                // Waits for the parent to reap a process that isn't a child
+                blocking(move || process.exit_code()).await?
+            }
+            // The process is already gone
+            Err(Error::Sys { source, .. }) if source.as_errno() == Some(Errno::ESRCH) => None,
+            Err(err) => return Err(err),
+        };
+
+        let inner = self.inner.clone();
+        blocking(move || inner.stopped(exit_code)).await
+    }
+}
+
+/// Console socket receiving the pty master of containers created with `terminal: true`.
+/// Its path is passed as `console_socket` of the create operation.
+pub struct ConsoleSocket {
+    listener: UnixListener,
+}
+
+impl ConsoleSocket {
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<ConsoleSocket> {
+        let listener = UnixListener::bind(path.as_ref()).map_err(Error::io(format!(
+            "unable to bind console socket {:?}",
+            path.as_ref()
+        )))?;
+        Ok(ConsoleSocket { listener })
+    }
+
+    /// Waits for the container to send its pty master
+    pub async fn recv(&self) -> Result<Console> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .await
+            .map_err(Error::io("unable to accept console connection"))?;
+
+        loop {
+            stream
+                .readable()
+                .await
+                .map_err(Error::io("error waiting on console socket"))?;
+
+            match stream.try_io(Interest::READABLE, || recv_fd(stream.as_raw_fd())) {
+                Ok(fd) => return Console::new(fd),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(Error::io("unable to receive pty master")(err)),
+            }
+        }
+    }
+}
+
+/// Pty master of a container, proxied through async reads and writes
+pub struct Console {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Console {
+    fn new(fd: OwnedFd) -> Result<Console> {
+        nonblocking(fd.as_raw_fd())?;
+        Ok(Console {
+            fd: AsyncFd::new(fd).map_err(Error::io("unable to register pty master"))?,
+        })
+    }
+}
+
+impl AsRawFd for Console {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsyncRead for Console {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| read(fd.as_raw_fd(), unfilled).map_err(to_io)) {
+                Ok(Ok(num)) => {
+                    buf.advance(num);
+                    return Poll::Ready(Ok(()));
+                }
+                // A hangup of the pty slave reads as EIO, treat it as EOF
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Console {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| write(fd.as_raw_fd(), buf).map_err(to_io)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Bytes moved by a single splice(2) call
+const SPLICE_LEN: usize = 64 * 1024;
+
+impl Console {
+    /// Copies the output of the container to `out` until the pty hangs up,
+    /// returning the number of bytes copied.
+    ///
+    /// The data moves through a pipe with splice(2), it's never copied into
+    /// userspace buffers. Kernels that can't splice from a tty fall back to reads
+    /// and writes.
+    pub async fn splice_to(&self, out: RawFd) -> Result<u64> {
+        let out = Endpoint::new(out);
+        splice(&Endpoint::Pty(&self.fd), &out).await
+    }
+
+    /// Copies `input` to the container until the end of the input,
+    /// returning the number of bytes copied. See `splice_to`.
+    pub async fn splice_from(&self, input: RawFd) -> Result<u64> {
+        let input = Endpoint::new(input);
+        splice(&input, &Endpoint::Pty(&self.fd)).await
+    }
+}
+
+/// One side of a console copy. Regular files can't be polled and don't
+/// block, they're used as they are.
+enum Endpoint<'a> {
+    Pty(&'a AsyncFd<OwnedFd>),
+    Polled(AsyncFd<RawFd>),
+    Unpolled(RawFd),
+}
+
+impl<'a> Endpoint<'a> {
+    fn new(fd: RawFd) -> Endpoint<'a> {
+        match AsyncFd::new(fd) {
+            Ok(fd) => Endpoint::Polled(fd),
+            Err(_) => Endpoint::Unpolled(fd),
+        }
+    }
+
+    /// Runs the operation once the fd is ready for it
+    async fn io<F>(&self, interest: Interest, mut f: F) -> io::Result<usize>
+    where
+        F: FnMut(RawFd) -> io::Result<usize>,
+    {
+        match self {
+            Endpoint::Pty(fd) => ready_io(fd, interest, f).await,
+            Endpoint::Polled(fd) => ready_io(fd, interest, f).await,
+            Endpoint::Unpolled(fd) => f(*fd),
+        }
+    }
+}
+
+async fn ready_io<T, F>(fd: &AsyncFd<T>, interest: Interest, mut f: F) -> io::Result<usize>
+where
+    T: AsRawFd,
+    F: FnMut(RawFd) -> io::Result<usize>,
+{
+    loop {
+        let mut guard = fd.ready(interest).await?;
+        match guard.try_io(|fd| f(fd.as_raw_fd())) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Reads of the pty after the hangup of the slave fail with EIO, it's the end
+/// of the output
+fn eof(result: io::Result<usize>) -> io::Result<usize> {
+    match result {
+        Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(0),
+        result => result,
+    }
+}
+
+async fn splice(from: &Endpoint<'_>, to: &Endpoint<'_>) -> Result<u64> {
+    let (pipe_out, pipe_in) =
+        pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK).map_err(Error::sys("unable to create pipe"))?;
+    let pipe = unsafe { (OwnedFd::from_raw_fd(pipe_out), OwnedFd::from_raw_fd(pipe_in)) };
+    let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+    let mut buf = vec![0u8; SPLICE_LEN];
+    let mut splice_out = true;
+
+    let mut total = 0;
+    loop {
+        let filled = from
+            .io(Interest::READABLE, |fd| {
+                fcntl::splice(fd, None, pipe.1.as_raw_fd(), None, SPLICE_LEN, flags).map_err(to_io)
+            })
+            .await;
+        let filled = match eof(filled) {
+            Ok(0) => return Ok(total),
+            Ok(num) => num,
+            Err(err) if total == 0 && err.raw_os_error() == Some(libc::EINVAL) => {
+                return copy(from, to).await
+            }
+            Err(err) => return Err(Error::io("unable to splice console data")(err)),
+        };
+
+        let mut left = filled;
+        while left > 0 {
+            let moved = if splice_out {
+                to.io(Interest::WRITABLE, |fd| {
+                    fcntl::splice(pipe.0.as_raw_fd(), None, fd, None, left, flags).map_err(to_io)
+                })
+                .await
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            };
+            left -= match moved {
+                Ok(num) => num,
+                // Some fds, like O_APPEND files, can't be spliced to
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                    splice_out = false;
+                    let num = read(pipe.0.as_raw_fd(), &mut buf[..left])
+                        .map_err(Error::sys("unable to read console data"))?;
+                    write_all(to, &buf[..num]).await?;
+                    num
+                }
+                Err(err) => return Err(Error::io("unable to splice console data")(err)),
+            };
+        }
+        total += filled as u64;
+    }
+}
+
+/// Copy through a userspace buffer, for the kernels that can't splice ttys
+async fn copy(from: &Endpoint<'_>, to: &Endpoint<'_>) -> Result<u64> {
+    let mut buf = vec![0u8; SPLICE_LEN];
+    let mut total = 0;
+    loop {
+        let num = from
+            .io(Interest::READABLE, |fd| read(fd, &mut buf).map_err(to_io))
+            .await;
+        let num = eof(num).map_err(Error::io("unable to read console data"))?;
+        if num == 0 {
+            return Ok(total);
+        }
+        write_all(to, &buf[..num]).await?;
+        total += num as u64;
+    }
+}
+
+async fn write_all(to: &Endpoint<'_>, buf: &[u8]) -> Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        written += to
+            .io(Interest::WRITABLE, |fd| write(fd, &buf[written..]).map_err(to_io))
+            .await
+            .map_err(Error::io("unable to write console data"))?;
+    }
+    Ok(())
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Error::Runtime(format!("blocking task failed: {}", err)))?
+}
+
+fn to_io(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    }
+}
+
+fn nonblocking(fd: RawFd) -> Result<RawFd> {
+    let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(Error::sys("fcntl F_GETFL failed"))?;
+    let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+    fcntl(fd, FcntlArg::F_SETFL(flags)).map_err(Error::sys("fcntl F_SETFL failed"))?;
+    Ok(fd)
+}
+
+fn recv_fd(socket: RawFd) -> io::Result<OwnedFd> {
+    let mut buf = [0u8; 64];
+    let iov = [IoVec::from_mut_slice(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+
+    let msg = recvmsg(socket, &iov, Some(&mut cmsg), MsgFlags::empty()).map_err(to_io)?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no file descriptor received",
+    ))
+}
+
+/// Sets up the forked container process until it's ready to be started
+async fn created(container: &crate::Container, create: &Create, spawned: Spawned) -> Result<()> {
+    // Wait until child sets up IPC channel
+    let init_msg = accept_msg(spawned.init_lock.as_raw_fd()).await;
+    spawned.init_lock.close()?;
+    crate::Container::check_init(init_msg)?;
+
+    let ipc_channel = IpcChannel::connect(&spawned.sock_path)?;
+    let result = async {
+        let channel = AsyncFd::new(nonblocking(ipc_channel.as_raw_fd())?)
+            .map_err(Error::io("unable to register IPC socket"))?;
+        loop {
+            let msg = recv_msg(&channel).await?;
+            if container.handle_msg(&spawned, &msg)? {
+                return Ok(());
+            }
+            if msg.eq("before_pivot") {
+                send_msg(&channel, "ok").await?;
+            }
+        }
+    }
+    .await;
+    ipc_channel.disconnect()?;
+
+    if let Err(err) = result {
+        let _ = spawned.process.signal(9);
+        return Err(err);
+    }
+
+    container.finish_create(create, spawned)
+}
+
+// Accepts a connection on the listening socket and reads a single message
+async fn accept_msg(listener: RawFd) -> Result<String> {
+    let listener =
+        AsyncFd::new(nonblocking(listener)?).map_err(Error::io("unable to register IPC socket"))?;
+
+    let conn = loop {
+        let mut guard = listener
+            .readable()
+            .await
+            .map_err(Error::io("error waiting on IPC socket"))?;
+        match accept(listener.as_raw_fd()) {
+            Ok(fd) => break unsafe { OwnedFd::from_raw_fd(fd) },
+            Err(err) if err.as_errno() == Some(Errno::EAGAIN) => guard.clear_ready(),
+            Err(err) => return Err(Error::sys("unable to accept incoming socket")(err)),
+        }
+    };
+
+    let conn = AsyncFd::new(conn).map_err(Error::io("unable to register IPC socket"))?;
+    nonblocking(conn.as_raw_fd())?;
+    recv_msg(&conn).await
+}
+
+async fn recv_msg<T: AsRawFd>(fd: &AsyncFd<T>) -> Result<String> {
+    let mut buf = [0; 1024];
+    let num = loop {
+        let mut guard = fd
+            .readable()
+            .await
+            .map_err(Error::io("error waiting on IPC socket"))?;
+        match guard.try_io(|fd| read(fd.as_raw_fd(), &mut buf).map_err(to_io)) {
+            Ok(num) => break num.map_err(Error::io("unable to read from IPC socket"))?,
+            Err(_would_block) => continue,
+        }
+    };
+
+    match std::str::from_utf8(&buf[0..num]) {
+        Ok(str) => Ok(str.trim().to_string()),
+        Err(err) => Err(Error::Runtime(format!(
+            "error while converting byte to string {}",
+            err
+        ))),
+    }
+}
+
+async fn send_msg<T: AsRawFd>(fd: &AsyncFd<T>, msg: &str) -> Result<()> {
+    loop {
+        let mut guard = fd
+            .writable()
+            .await
+            .map_err(Error::io("error waiting on IPC socket"))?;
+        match guard.try_io(|fd| write(fd.as_raw_fd(), msg.as_bytes()).map_err(to_io)) {
+            Ok(result) => {
+                result.map_err(Error::io("unable to write to IPC socket"))?;
+                return Ok(());
+            }
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        fcntl::{open, OFlag},
+        pty::{grantpt, posix_openpt, ptsname_r, unlockpt},
+        sys::stat::Mode,
+        unistd::{close, isatty, write},
+    };
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+
+    use crate::core::{
+        ipc::{IpcChild, IpcParent},
+        terminal::{Pty, PtySocket},
+    };
+
+    use super::{accept_msg, Console, ConsoleSocket};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn async_accept() {
+        let path = String::from("./aio_tmp.sock");
+        let parent = IpcParent::new(&path).unwrap();
+
+        let child_path = path.clone();
+        let _ = std::thread::spawn(move || {
+            let child = IpcChild::new(&child_path).unwrap();
+            child.notify(&String::from("ok")).unwrap();
+            child.close().unwrap();
+        });
+
+        let msg = runtime().block_on(accept_msg(parent.as_raw_fd())).unwrap();
+        parent.close().unwrap();
+        assert_eq!(msg, "ok");
+    }
+
+    #[test]
+    fn console_socket() {
+        let path = "./aio_console.sock";
+        let _ = std::fs::remove_file(path);
+
+        let console = runtime().block_on(async {
+            let socket = ConsoleSocket::bind(path).unwrap();
+            let _ = std::thread::spawn(move || {
+                let pty = Pty::new().unwrap();
+                let pty_socket = PtySocket::new(path).unwrap();
+                pty_socket.send_pty(&pty).unwrap();
+                pty_socket.close().unwrap();
+            });
+            socket.recv().await.unwrap()
+        });
+
+        assert!(isatty(console.as_raw_fd()).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn splice_console_output() {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let slave = open(
+            ptsname_r(&master).unwrap().as_str(),
+            OFlag::O_RDWR | OFlag::O_NOCTTY,
+            Mode::empty(),
+        )
+        .unwrap();
+        write(slave, b"hello\n").unwrap();
+        close(slave).unwrap();
+
+        let path = "./aio_splice.log";
+        let log = std::fs::File::create(path).unwrap();
+        let copied = runtime().block_on(async {
+            let console =
+                Console::new(unsafe { OwnedFd::from_raw_fd(master.into_raw_fd()) }).unwrap();
+            console.splice_to(log.as_raw_fd()).await.unwrap()
+        });
+
+        // The pty translates the newline
+        assert_eq!(copied, 7);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hello\r\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
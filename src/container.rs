@@ -0,0 +1,1506 @@
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+use nix::{
+    errno::Errno,
+    libc,
+    unistd::{isatty, Pid},
+};
+
+use crate::core::{
+    cdi,
+    cgroups::{self, Cgroup, Driver, CGROUP_ROOT},
+    common::{Error, Result},
+    container::fork_container,
+    criu,
+    events::{self, Event, EventKind, Events},
+    exec, filesystem,
+    fork::{self, Process},
+    hooks::exec_hook,
+    ipc::{IpcChannel, IpcParent},
+    jsonfile::{self, Entries},
+    lock::Lock,
+    monitor::{self, Monitor},
+    network::{self, Network},
+    notify::{NotifySocket, NOTIFY_DIR, NOTIFY_SOCKET_ENV},
+    nvidia::{self, Gpus},
+    otlp::{self, info_span},
+    overlay::Overlay,
+    plugins::{self, Phase},
+    pod::{self, Pod},
+    restart::{RestartPolicy, RESTART_COUNT_ANNOTATION},
+    seccomp::{self, Filter},
+    signals::Forwarder,
+    state::{self, validate_id, State, Status, Store},
+    terminal::{PtySocket, RawMode},
+    trace::Tracer,
+    volume::{self, VolumeMount},
+    wasm,
+};
+#[cfg(feature = "image")]
+use crate::image::{
+    self,
+    unpack::{self, IdShift},
+};
+use crate::oci::{
+    ops::{Checkpoint, Create, Exec, Logs, Restore, Run},
+    spec::{Device, DeviceResource, Mount, Namespace, Resources, Spec},
+    validate::{validate, Severity},
+};
+
+/// Handle to a container living under a runtime root.
+///
+/// ```no_run
+/// use pura::{oci::ops::Create, Container};
+///
+/// let container = Container::create(&Create {
+///     id: "example".to_string(),
+///     bundle: "/path/to/bundle".to_string(),
+///     console_socket: None,
+///     pid_file: None,
+///     root: "/run/pura".to_string(),
+///     network: None,
+///     detach: false,
+///     init: false,
+///     devices: Vec::new(),
+///     gpus: None,
+///     image: None,
+///     rootfs_tar: None,
+///     overlay: false,
+///     volumes: Vec::new(),
+///     record: false,
+///     log_rotation: None,
+///     console_buffer: None,
+///     pod: None,
+///     restart: Default::default(),
+/// })?;
+/// container.start()?;
+/// println!("{:?}", container.state()?.status);
+/// # Ok::<(), pura::core::common::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Container {
+    id: String,
+    path: PathBuf,
+}
+
+/// What the monitor of a detached container creates it again with, see
+/// `Container::restart`
+struct Restart {
+    create: Create,
+    /// Of the monitor, with a terminal
+    console_socket: Option<String>,
+    overlay: Option<Overlay>,
+    network: Option<Network>,
+    before_pivot: bool,
+}
+
+/// Container process forked by create, not yet ready to be started
+pub(crate) struct Spawned {
+    spec: Spec,
+    state: State,
+    pub(crate) process: Process,
+    pub(crate) init_lock: IpcParent,
+    pub(crate) sock_path: String,
+    pty_socket: Option<PtySocket>,
+    notify_socket: Option<NotifySocket>,
+    network: Option<Network>,
+}
+
+/// Signals of kill stopping the container, its monitor doesn't restart it
+const STOPPING: [i32; 4] = [libc::SIGINT, libc::SIGQUIT, libc::SIGKILL, libc::SIGTERM];
+/// Start time of the container process, see `fork::start_time`
+const START_TIME_FILE: &str = "process.start";
+/// Time given to the processes of a failed create to exit once killed
+const DISCARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bundle synthesized from the image of `create --image`, in the state dir
+#[cfg(feature = "image")]
+const IMAGE_BUNDLE: &str = "bundle";
+/// Rootfs extracted from the tarball of `create --rootfs-tar`, in the state dir
+#[cfg(feature = "image")]
+const TAR_ROOTFS: &str = "rootfs";
+
+fn load_spec(bundle: &Path) -> Result<Spec> {
+    Spec::try_from(bundle.join("config.json").as_path())
+}
+
+/// Validates the spec before anything is set up, reporting every error at
+/// once rather than failing midway through the setup on the first one
+fn check_spec(spec: &Spec, bundle: &Path) -> Result<()> {
+    let mut errors = Vec::new();
+    for diagnostic in validate(spec, bundle) {
+        match diagnostic.severity {
+            Severity::Error => errors.push(format!("{}: {}", diagnostic.field, diagnostic.message)),
+            Severity::Warning => warn!("{}: {}", diagnostic.field, diagnostic.message),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Bundle(errors.join("; "))),
+    }
+}
+
+impl Container {
+    /// Loads an existing container from the runtime root
+    pub fn load(root: &str, id: &str) -> Result<Container> {
+        validate_id(id)?;
+        let container = Container {
+            id: id.to_string(),
+            path: Path::new(root).join(id),
+        };
+        // Make sure the container exists
+        container.saved_state()?;
+        Ok(container)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Directory holding the state and the sockets of the container
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates the container from the bundle and waits until it's ready to be started
+    pub fn create(create: &Create) -> Result<Container> {
+        let _span = info_span!("create", id = %create.id).entered();
+        let (container, _lock, spawned) = Container::spawn(create)?;
+        if let Err(err) = container.created(create, spawned) {
+            container.discard();
+            return Err(err);
+        }
+        Ok(container)
+    }
+
+    /// Sets up the forked container process until it's ready to be started
+    fn created(&self, create: &Create, spawned: Spawned) -> Result<()> {
+        // Wait until child sets up IPC channel
+        let init_msg = spawned.init_lock.wait();
+        spawned.init_lock.close()?;
+        Container::check_init(init_msg)?;
+
+        let ipc_channel = IpcChannel::connect(&spawned.sock_path)?;
+
+        loop {
+            let msg = match ipc_channel.recv() {
+                Ok(msg) => msg,
+                Err(err) => {
+                    let _ = spawned.process.signal(9);
+                    return Err(err);
+                }
+            };
+
+            if self.handle_msg(&spawned, &msg)? {
+                break;
+            }
+            if msg.eq("before_pivot") {
+                ipc_channel.send("ok")?;
+            }
+        }
+        ipc_channel.disconnect()?;
+
+        self.finish_create(create, spawned)
+    }
+
+    /// Creates the stopped container of the monitor again from the spec
+    /// cached in its state dir, not from its bundle, and starts it. Runs in the
+    /// monitor, the parent of the new container process. The restarts are
+    /// counted in the annotations of the state.
+    fn restart(&self, restart: &Restart, restarts: u32) -> Result<Process> {
+        let _span = info_span!("restart", id = %self.id).entered();
+        let (lock, mut state) = self.lock_state()?;
+        if state.status != Status::Stopped {
+            return Err(Error::State(format!("container {} isn't stopped", self.id)));
+        }
+        let spec = self.spec()?;
+        self.release(&state, &spec)?;
+
+        state.status = Status::Creating;
+        state.pid = 0;
+        state
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(RESTART_COUNT_ANNOTATION.to_string(), restarts.to_string());
+        self.save_state(&state)?;
+
+        let pty_socket = match &restart.console_socket {
+            Some(console_socket) => Some(PtySocket::new(console_socket)?),
+            None => None,
+        };
+        let init_lock_path = format!("{}/init.sock", self.path.display());
+        let init_lock = IpcParent::new(&init_lock_path)?;
+        let sock_path = format!("{}/container.sock", self.path.display());
+        let namespaces = match &spec.linux {
+            Some(linux) => linux.namespaces.clone().unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let process = fork_container(
+            &spec,
+            &state,
+            &namespaces,
+            &init_lock_path,
+            &sock_path,
+            &pty_socket,
+            restart.create.init,
+            restart.overlay.as_ref(),
+            restart.before_pivot,
+            self.seccomp_filter(&spec)?.as_ref(),
+        )?;
+        let spawned = Spawned {
+            spec,
+            state,
+            process,
+            init_lock,
+            sock_path,
+            pty_socket,
+            notify_socket: None,
+            network: restart.network.clone(),
+        };
+        self.created(&restart.create, spawned)?;
+        drop(lock);
+        self.start()?;
+        self.process(&self.saved_state()?)
+    }
+
+    /// Prepares the state and forks the container process. The container
+    /// stays locked until it's created.
+    pub(crate) fn spawn(create: &Create) -> Result<(Container, Lock, Spawned)> {
+        validate_id(&create.id)?;
+        let container = Container {
+            id: create.id.clone(),
+            path: Path::new(&create.root).join(&create.id),
+        };
+        // Claimed first, the bundle of an image goes to the state dir
+        let lock = container.claim()?;
+        match container.spawn_claimed(create) {
+            Ok(spawned) => Ok((container, lock, spawned)),
+            Err(err) => {
+                container.discard();
+                Err(err)
+            }
+        }
+    }
+
+    fn spawn_claimed(&self, create: &Create) -> Result<Spawned> {
+        let bundle = match &create.image {
+            Some(image) => info_span!("image").in_scope(|| self.image_bundle(image))?,
+            None => PathBuf::from(&create.bundle),
+        };
+
+        let spec = info_span!("spec").in_scope(|| load_spec(&bundle))?;
+        // Injected before the checks, the devices and mounts of CDI included
+        let mut devices = cdi::annotated_devices(spec.annotations.as_ref());
+        devices.extend(create.devices.iter().cloned());
+        let mut spec = info_span!("cdi").in_scope(|| cdi::inject(spec, &devices))?;
+        // The flag of create takes precedence over the annotation
+        let gpus = match &create.gpus {
+            Some(gpus) => Some(Gpus::parse(gpus)?),
+            None => nvidia::annotated_gpus(&spec)?,
+        };
+        if let Some(gpus) = gpus {
+            spec = info_span!("gpus").in_scope(|| nvidia::inject(spec, &gpus))?;
+        }
+        let volumes = create
+            .volumes
+            .iter()
+            .map(|volume| VolumeMount::parse(volume))
+            .collect::<Result<Vec<_>>>()?;
+        let (mut spec, volume_names) = info_span!("volumes")
+            .in_scope(|| volume::inject(spec, Path::new(&create.root), &volumes))?;
+        let pod = match &create.pod {
+            Some(name) => Some(
+                Pod::load(Path::new(&create.root), name)?
+                    .ok_or_else(|| Error::State(format!("pod {} doesn't exist", name)))?,
+            ),
+            None => None,
+        };
+        if let Some(pod) = &pod {
+            spec = pod.join(spec)?;
+        }
+        if let Some(tarball) = &create.rootfs_tar {
+            let rootfs = info_span!("rootfs").in_scope(|| self.tar_rootfs(tarball, &spec))?;
+            spec.root.path = rootfs.to_string_lossy().to_string();
+        }
+        seccomp::apply_default_profile(&mut spec)?;
+        wasm::check(&spec)?;
+        check_spec(&spec, &bundle)?;
+
+        let has_terminal = if let Some(process) = &spec.process {
+            process.terminal.unwrap_or_default()
+        } else {
+            false
+        };
+
+        // The flag of create takes precedence over the annotations
+        let network = match &create.network {
+            Some(network) => Some(Network::parse(network)?),
+            None => Network::from_annotations(&spec.annotations.clone().unwrap_or_default())?,
+        };
+        let new_netns = spec
+            .linux
+            .as_ref()
+            .and_then(|linux| linux.namespaces.as_ref())
+            .is_some_and(|namespaces| {
+                namespaces
+                    .iter()
+                    .any(|ns| ns.namespace == "network" && ns.path.is_none())
+            });
+        let new_mountns = spec
+            .linux
+            .as_ref()
+            .and_then(|linux| linux.namespaces.as_ref())
+            .is_some_and(|namespaces| {
+                namespaces
+                    .iter()
+                    .any(|ns| ns.namespace == "mount" && ns.path.is_none())
+            });
+        if network.is_some() && !new_netns {
+            return Err(Error::Bundle(
+                "network requires a new network namespace".to_string(),
+            ));
+        }
+
+        let container_path = self.path.as_path();
+        let mut state = State::new(&create.id, 0, &bundle.to_string_lossy());
+        volume::annotate(&mut state, &volume_names);
+        if let Some(pod) = &pod {
+            pod::annotate(&mut state, pod);
+        }
+        self.save_state(&state)?;
+
+        // root.path is relative to the bundle
+        if Path::new(&spec.root.path).is_relative() {
+            spec.root.path = state.bundle.join(&spec.root.path).to_string_lossy().to_string();
+        }
+
+        // The rootfs becomes the lower layer, the writes go to the state dir
+        let overlay = if create.overlay || Overlay::annotated(spec.annotations.as_ref()) {
+            if !new_mountns {
+                return Err(Error::Bundle(
+                    "overlay requires a new mount namespace".to_string(),
+                ));
+            }
+            let overlay = Overlay::prepare(container_path, Path::new(&spec.root.path))?;
+            spec.root.path = overlay.merged.to_string_lossy().to_string();
+            Some(overlay)
+        } else {
+            None
+        };
+
+        let monitor = if create.detach {
+            if create.console_socket.is_some() {
+                return Err(Error::Runtime(
+                    "the monitor of a detached container holds its console".to_string(),
+                ));
+            }
+            Some(Monitor::new(
+                container_path,
+                has_terminal,
+                create.record,
+                create.log_rotation,
+                create.console_buffer.unwrap_or_default(),
+                create.restart,
+            )?)
+        } else if create.record {
+            return Err(Error::Runtime(
+                "only the monitor of a detached container records its console".to_string(),
+            ));
+        } else if create.log_rotation.is_some() || create.console_buffer.is_some() {
+            return Err(Error::Runtime(
+                "only the monitor of a detached container logs its output".to_string(),
+            ));
+        } else if create.restart != RestartPolicy::No {
+            return Err(Error::Runtime(
+                "only the monitor of a detached container restarts it".to_string(),
+            ));
+        } else {
+            None
+        };
+
+        let pty_socket = if has_terminal {
+            let console_socket = match monitor.as_ref().and_then(Monitor::console_socket) {
+                Some(console_socket) => console_socket,
+                None => create.console_socket.clone().ok_or_else(|| {
+                    Error::Bundle("terminal requires the console-socket argument".to_string())
+                })?,
+            };
+            Some(PtySocket::new(&console_socket)?)
+        } else {
+            None
+        };
+
+        // IPC lock that waits the setup of the container IPC channel
+        let init_lock_path = format!("{}/init.sock", container_path.display());
+        let init_lock = IpcParent::new(&init_lock_path)?;
+
+        let sock_path = format!("{}/container.sock", container_path.display());
+
+        // Proxy sd_notify messages of the container to the socket of the host
+        let notify_socket = NotifySocket::from_env(container_path)?;
+        if let Some(notify_socket) = &notify_socket {
+            let source = notify_socket.dir().to_string_lossy();
+            spec = spec
+                .add_mount(Mount::new(NOTIFY_DIR, "bind", &source, &["bind"]))
+                .add_env(NOTIFY_SOCKET_ENV, &NotifySocket::container_path());
+        }
+
+        let namespaces: Vec<Namespace> = match &spec.linux {
+            Some(linux) => linux.namespaces.clone().unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        // Later operations use this copy, the bundle may change or go away after create
+        spec.save(container_path)?;
+
+        // A round trip with the container process before pivot_root only
+        // when the runtime has something to set up then
+        let before_pivot = network.is_some()
+            || spec
+                .linux
+                .as_ref()
+                .and_then(|linux| linux.net_devices.as_ref())
+                .is_some_and(|devices| !devices.is_empty())
+            || spec
+                .hooks
+                .as_ref()
+                .and_then(|hooks| hooks.create_runtime.as_ref())
+                .is_some_and(|hooks| !hooks.is_empty())
+            || plugins::registered(Phase::CreateRuntime);
+
+        let filter = self.seccomp_filter(&spec)?;
+        debug!("forking container {} from bundle {:?}", create.id, bundle);
+        let fork = || {
+            fork_container(
+                &spec,
+                &state,
+                &namespaces,
+                &init_lock_path,
+                &sock_path,
+                &pty_socket,
+                create.init,
+                overlay.as_ref(),
+                before_pivot,
+                filter.as_ref(),
+            )
+        };
+        let process = match monitor {
+            Some(monitor) => {
+                let restart = Restart {
+                    create: create.clone(),
+                    console_socket: monitor.console_socket(),
+                    overlay: overlay.clone(),
+                    network: network.clone(),
+                    before_pivot,
+                };
+                let monitored = self.clone();
+                let restarted = self.clone();
+                monitor.spawn(
+                    fork,
+                    move |exit_code| {
+                        if let Err(err) = monitored.stopped(exit_code) {
+                            warn!("{}", err);
+                        }
+                    },
+                    move |restarts| restarted.restart(&restart, restarts),
+                )?
+            }
+            None => fork()?,
+        };
+
+        Ok(Spawned {
+            spec,
+            state,
+            process,
+            init_lock,
+            sock_path,
+            pty_socket,
+            notify_socket,
+            network,
+        })
+    }
+
+    /// Checks the first message the container process sends on the init lock
+    pub(crate) fn check_init(init_msg: Result<String>) -> Result<()> {
+        match init_msg {
+            Ok(msg) if msg.eq("ok") => Ok(()),
+            Ok(msg) => Err(Error::Container(msg)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Handles a message of the container process during create.
+    /// Returns true once the container is ready to be started.
+    pub(crate) fn handle_msg(&self, spawned: &Spawned, msg: &str) -> Result<bool> {
+        if msg.starts_with("error") {
+            return Err(Error::Container(msg.to_string()));
+        } else if msg.eq("ready") {
+            return Ok(true);
+        } else if let Some(span) = msg.strip_prefix("span:") {
+            if let Err(err) = otlp::import(span) {
+                warn!("{}", err);
+            }
+        } else if msg.eq("before_pivot") {
+            // The network namespace exists by now, set up like a createRuntime hook would
+            if let Some(network) = &spawned.network {
+                let setup = info_span!("network")
+                    .in_scope(|| network.setup(&self.id, spawned.process.pid(), &self.path));
+                if let Err(err) = setup {
+                    let _ = spawned.process.signal(9);
+                    return Err(err);
+                }
+            }
+            let net_devices = spawned
+                .spec
+                .linux
+                .as_ref()
+                .and_then(|linux| linux.net_devices.as_ref());
+            if let Some(devices) = net_devices {
+                let moved = info_span!("netDevices").in_scope(|| {
+                    network::move_net_devices(devices, spawned.process.pid(), &self.path)
+                });
+                if let Err(err) = moved {
+                    let _ = spawned.process.signal(9);
+                    let _ = network::return_net_devices(&self.path);
+                    return Err(err);
+                }
+            }
+            if let Err(err) = plugins::run(Phase::CreateRuntime, &spawned.state) {
+                error!("{}", err);
+                spawned.process.signal(9)?;
+            }
+            if let Some(hooks) = &spawned.spec.hooks {
+                if let Some(create_runtime) = &hooks.create_runtime {
+                    let _span = info_span!("hooks.createRuntime").entered();
+                    for cr_hook in create_runtime {
+                        debug!("running createRuntime hook {}", cr_hook.path);
+                        if exec_hook(cr_hook, &spawned.state).is_err() {
+                            error!("createRuntime hook failed");
+                            spawned.process.signal(9)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Writes the pid file and marks the container as created
+    pub(crate) fn finish_create(&self, create: &Create, spawned: Spawned) -> Result<()> {
+        let pid = spawned.process.pid();
+
+        // The process execs the user program only on start, it's limited from then on
+        let linux = spawned.spec.linux.as_ref();
+        let cgroup = info_span!("cgroups").in_scope(|| {
+            let cgroup = cgroups::setup(&self.id, linux, pid)?;
+            match cgroups::delegated_controllers(spawned.spec.annotations.as_ref())? {
+                Some(controllers) => cgroup.delegate(&controllers, linux),
+                None => Ok(()),
+            }
+        });
+        if let Err(err) = cgroup {
+            let _ = spawned.process.signal(9);
+            return Err(err);
+        }
+
+        // Later runtime invocations reopen the process by pid, the start time
+        // tells it apart from another process reusing the pid
+        if let Some(start_time) = fork::start_time(pid) {
+            std::fs::write(self.path.join(START_TIME_FILE), start_time.to_string())
+                .map_err(Error::io("unable to save the process start time"))?;
+        }
+
+        if let Some(pid_file_path) = &create.pid_file {
+            write_pid_file(pid_file_path, pid)?;
+        }
+
+        // Update state, still the one of spawn as the container is claimed
+        let mut state = spawned.state.clone();
+        state.status = Status::Created;
+        state.pid = i32::from(pid) as u64;
+        self.save_state(&state)?;
+        events::emit(&self.path, &self.id, EventKind::Created { pid: state.pid });
+
+        if let Some(notify_socket) = spawned.notify_socket {
+            notify_socket.spawn_proxy(pid)?;
+        }
+
+        // Parent cleanup
+        if let Some(pty_socket) = spawned.pty_socket {
+            if let Err(err) = pty_socket.close() {
+                error!("error closing console-socket: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the user-defined process of a created container
+    pub fn start(&self) -> Result<()> {
+        let _span = info_span!("start", id = %self.id).entered();
+        let (_lock, state) = self.lock_state()?;
+        let mut state = state.refreshed();
+        let spec = self.spec()?;
+
+        let process = self.process(&state)?;
+
+        if state.status != Status::Created {
+            process.signal(9)?;
+            return Err(Error::State("container isn't created".to_string()));
+        }
+
+        for phase in [Phase::Prestart, Phase::StartContainer] {
+            if let Err(err) = plugins::run(phase, &state) {
+                process.signal(9)?;
+                return Err(err);
+            }
+        }
+        if let Some(hooks) = &spec.hooks {
+            if let Some(prestart) = &hooks.prestart {
+                let _span = info_span!("hooks.prestart").entered();
+                for pre_hook in prestart {
+                    if exec_hook(pre_hook, &state).is_err() {
+                        process.signal(9)?;
+                        return Err(Error::Runtime("prestart hook failed".to_string()));
+                    }
+                }
+            }
+
+            if let Some(start_container) = &hooks.start_container {
+                let _span = info_span!("hooks.startContainer").entered();
+                for hook in start_container {
+                    if exec_hook(hook, &state).is_err() {
+                        process.signal(9)?;
+                        return Err(Error::Runtime("startContainer hook failed".to_string()));
+                    }
+                }
+            }
+        }
+
+        let sock_path = format!("{}/container.sock", self.path.display());
+        let ipc_channel = IpcChannel::connect(&sock_path)?;
+        ipc_channel.send("start")?;
+        ipc_channel.close()?;
+
+        state.status = Status::Running;
+        self.save_state(&state)?;
+        events::emit(&self.path, &self.id, EventKind::Started);
+
+        if let Err(err) = plugins::run(Phase::Poststart, &state) {
+            warn!("poststart plugin error: {}", err);
+        }
+        if let Some(hooks) = &spec.hooks {
+            if let Some(poststart) = &hooks.poststart {
+                let _span = info_span!("hooks.poststart").entered();
+                for hook in poststart {
+                    if let Err(err) = exec_hook(hook, &state) {
+                        warn!("poststart hook error: {}", err);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates and starts the container in the foreground, forwarding the
+    /// signals of the runtime to it until it exits, then deletes it.
+    /// Returns the exit code like `wait`.
+    ///
+    /// Detached, returns None once the container is started, leaving it to
+    /// its monitor and its restart policy.
+    pub fn run(run: &Run) -> Result<Option<i32>> {
+        let mut forwarder = match run.create.detach {
+            true => None,
+            false => Some(Forwarder::new()?),
+        };
+        let trace = match &run.trace_syscalls {
+            Some(path) => Some(
+                File::create(path).map_err(Error::io(format!("unable to create {}", path)))?,
+            ),
+            None => None,
+        };
+        let container = Container::create(&run.create)?;
+
+        // Traced from before its start, when it's still the runtime
+        let mut tracer = None;
+        let exit_code = match &trace {
+            Some(trace) => container.saved_state().and_then(|state| {
+                tracer = Some(Tracer::attach(Pid::from_raw(state.pid as i32), trace)?);
+                Ok(())
+            }),
+            None => Ok(()),
+        }
+        .and_then(|_| match &run.resources {
+            Some(resources) => container.update(resources),
+            None => Ok(()),
+        })
+        .and_then(|_| container.start())
+        .and_then(|_| match &mut forwarder {
+            Some(forwarder) => container.forward_signals(forwarder, run.all),
+            None => Ok(None),
+        });
+        if exit_code.is_err() {
+            // The container doesn't outlive the runtime in the foreground
+            if let Ok(state) = container.state() {
+                if state.status != Status::Stopped {
+                    let _ = container.kill(9);
+                    let _ = container.wait();
+                }
+            }
+        }
+        if let Some(Err(err)) = tracer.map(Tracer::finish) {
+            warn!("{}", err);
+        }
+
+        if !run.rm {
+            return exit_code;
+        }
+        match (container.delete(), exit_code) {
+            (Ok(()), exit_code) => exit_code,
+            (Err(err), Ok(_)) => Err(err),
+            (Err(err), Err(run_err)) => {
+                warn!("{}", err);
+                Err(run_err)
+            }
+        }
+    }
+
+    fn forward_signals(&self, forwarder: &mut Forwarder, all: bool) -> Result<Option<i32>> {
+        let state = self.saved_state()?;
+        let process = self.process(&state)?;
+        let cgroup = if all {
+            Some(Cgroup::new(&self.id, self.spec()?.linux.as_ref()))
+        } else {
+            None
+        };
+        forwarder.forward(&process, cgroup.as_ref().map(|cgroup| cgroup.path()))?;
+        self.stopped(process.exit_code()?)
+    }
+
+    /// Executes the args in the container, like its process with the
+    /// overrides of the exec.
+    ///
+    /// Detached, returns once the args are executed. Otherwise the signals of
+    /// the runtime are forwarded to the new process until it exits, and its
+    /// exit code is returned.
+    pub fn exec(&self, exec: &Exec) -> Result<Option<i32>> {
+        let state = self.state()?;
+        if state.status != Status::Created && state.status != Status::Running {
+            return Err(Error::State(format!("container {} isn't running", self.id)));
+        }
+        let spec = self.spec()?;
+        let spec_process = spec
+            .process
+            .as_ref()
+            .ok_or_else(|| Error::Bundle("spec has no process".to_string()))?;
+        // The rootfs as the container process sees it, mounts included
+        let rootfs = PathBuf::from(format!("/proc/{}/root", state.pid));
+        let exec_process = exec::process(spec_process, exec, &rootfs)?;
+
+        // Signals received meanwhile are forwarded once the process runs
+        let mut forwarder = match exec.detach {
+            true => None,
+            false => Some(Forwarder::new()?),
+        };
+        // A child cgroup of the container, with limits of its own
+        let cgroup = match &exec.cgroup {
+            Some(name) => {
+                let cgroup = Cgroup::new(&self.id, spec.linux.as_ref()).child(name)?;
+                cgroup.create(exec.resources.as_ref())?;
+                exec::register_cgroup(&self.path, cgroup.path())?;
+                Some(cgroup)
+            }
+            None if exec.resources.is_some() => {
+                return Err(Error::Runtime(
+                    "the resources of an exec need its own cgroup".to_string(),
+                ))
+            }
+            None => None,
+        };
+        let filter = self.seccomp_filter(&spec)?;
+        let process = exec::exec(
+            Pid::from_raw(state.pid as i32),
+            &exec_process,
+            &exec.args,
+            cgroup.as_ref().map(Cgroup::path),
+            filter.as_ref(),
+        )?;
+        exec::register(&self.path, &process)?;
+        if let Some(pid_file_path) = &exec.pid_file {
+            write_pid_file(pid_file_path, process.pid())?;
+        }
+
+        match &mut forwarder {
+            Some(forwarder) => {
+                forwarder.forward(&process, None)?;
+                process.exit_code()
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sends the signal to the container process, the processes of its cgroup
+    /// and its exec'd processes
+    pub fn kill_all(&self, sig: i32) -> Result<()> {
+        let state = self.saved_state()?;
+        let cgroup = Cgroup::new(&self.id, self.spec()?.linux.as_ref());
+        // At once on the unified hierarchy, no process forked meanwhile escapes,
+        // and first, so the ones left behind by an exited container process too
+        let killed = sig == libc::SIGKILL && cgroup.kill()?;
+
+        match self.kill(sig) {
+            Err(err) if is_gone(&err) => (),
+            result => result?,
+        }
+        let exec_processes = exec::processes(&self.path);
+        // Processes exiting meanwhile are fine
+        for process in exec_processes.iter() {
+            let _ = process.signal(sig);
+        }
+        if killed {
+            return Ok(());
+        }
+        for pid in cgroups::procs(cgroup.path()) {
+            let signaled = pid.as_raw() as u64 == state.pid
+                || exec_processes.iter().any(|process| process.pid() == pid);
+            if !signaled {
+                let _ = fork::signal(pid, sig);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the signal to the container process
+    pub fn kill(&self, sig: i32) -> Result<()> {
+        let mut state = self.state()?;
+
+        if state.status != Status::Created && state.status != Status::Running {
+            error!(
+                "[KILL] error can't kill container that isn't created or running: {:?}",
+                &state
+            );
+        }
+
+        let process = self.process(&state)?;
+        if STOPPING.contains(&sig) {
+            monitor::stop_restarts(&self.path)?;
+        }
+        process.signal(sig)?;
+
+        // Only reaped when the container process is a child of the current process
+        if let Some(exit_code) = process.try_wait()? {
+            state.status = Status::Stopped;
+            self.save_state(&state)?;
+            let exit_code = Some(exit_code);
+            events::emit(&self.path, &self.id, EventKind::Stopped { exit_code });
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the container process exits and marks the container as stopped.
+    ///
+    /// Returns the exit code (128 + signal number when killed by a signal) if
+    /// it's known, see `Process::exit_code`.
+    pub fn wait(&self) -> Result<Option<i32>> {
+        // The monitor of a detached container reaps the process
+        if let Some(status) = monitor::wait(&self.path)? {
+            return self.stopped(status.exit_code);
+        }
+        let state = self.saved_state()?;
+        let exit_code = match self.process(&state) {
+            Ok(process) => {
+                process.wait_exit()?;
+                process.exit_code()?
+            }
+            // The process is already gone
+            Err(Error::Sys { source, .. }) if source.as_errno() == Some(Errno::ESRCH) => None,
+            Err(err) => return Err(err),
+        };
+        self.stopped(exit_code)
+    }
+
+    /// Marks the container as stopped once its process exited, unless another
+    /// runtime process already did. An unknown exit code is taken from the
+    /// stopped event of the runtime process that reaped the container process.
+    pub(crate) fn stopped(&self, exit_code: Option<i32>) -> Result<Option<i32>> {
+        let mut state = self.saved_state()?;
+        if state.status == Status::Stopped {
+            return Ok(exit_code.or_else(|| self.recorded_exit_code()));
+        }
+        state.status = Status::Stopped;
+        self.save_state(&state)?;
+        events::emit(&self.path, &self.id, EventKind::Stopped { exit_code });
+        Ok(exit_code)
+    }
+
+    fn recorded_exit_code(&self) -> Option<i32> {
+        self.events(false)
+            .filter_map(|event| match event.ok()?.kind {
+                EventKind::Stopped { exit_code } => exit_code,
+                _ => None,
+            })
+            .last()
+    }
+
+    /// Updates the resources of the cgroup of a created or running container.
+    /// The resources left unset keep their value, the spec of the container
+    /// records the new ones.
+    pub fn update(&self, resources: &Resources) -> Result<()> {
+        let _lock = self.lock()?;
+        let state = self.state()?;
+        if state.status != Status::Created && state.status != Status::Running {
+            return Err(Error::State("container isn't created or running".to_string()));
+        }
+
+        let mut spec = self.spec()?;
+        let linux = spec.linux.get_or_insert_with(Default::default);
+        cgroups::merge(
+            linux.resources.get_or_insert_with(Default::default),
+            resources,
+        );
+        let cgroup = Cgroup::new(&self.id, Some(linux));
+        if let Some(merged) = &linux.resources {
+            info_span!("cgroups").in_scope(|| cgroup.apply(merged))?;
+        }
+        spec.save(&self.path)
+    }
+
+    /// Adds the devices, `HOST[:CONTAINER[:PERMISSIONS]]` like docker, to the
+    /// created or running container: their nodes are created in its mount
+    /// namespace, allowed in its cgroup and recorded in its spec
+    pub fn add_devices(&self, devices: &[String]) -> Result<()> {
+        let _lock = self.lock()?;
+        let state = self.state()?;
+        if state.status != Status::Created && state.status != Status::Running {
+            return Err(Error::State("container isn't created or running".to_string()));
+        }
+        let devices = devices
+            .iter()
+            .map(|device| filesystem::parse_device(device))
+            .collect::<Result<Vec<_>>>()?;
+        let nodes: Vec<Device> = devices.iter().map(|(device, _)| device.clone()).collect();
+        info_span!("devices").in_scope(|| {
+            filesystem::add_devices(Pid::from_raw(state.pid as i32), &nodes)
+        })?;
+
+        let mut spec = self.spec()?;
+        let linux = spec.linux.get_or_insert_with(Default::default);
+        let cgroup = Cgroup::new(&self.id, Some(linux));
+        for (device, access) in devices {
+            let rule = DeviceResource {
+                allow: Some(true),
+                access: Some(access),
+                device_type: Some(device.device_type.clone()),
+                major: Some(device.major as i64),
+                minor: Some(device.minor as i64),
+                ..Default::default()
+            };
+            if !cgroup.allow_device(&rule)? {
+                debug!("no devices controller for {}, {} is allowed", self.id, device.path);
+            }
+            let resources = linux.resources.get_or_insert_with(Default::default);
+            resources.devices.get_or_insert_with(Vec::new).push(rule);
+            linux.devices.get_or_insert_with(Vec::new).push(device);
+        }
+        spec.save(&self.path)
+    }
+
+    /// Checkpoints the container process with criu.
+    /// Unless it's a pre-dump, the container is stopped afterwards.
+    pub fn checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut state = self.state()?;
+        if state.status != Status::Running {
+            return Err(Error::State("container isn't running".to_string()));
+        }
+
+        criu::dump(Pid::from_raw(state.pid as i32), checkpoint)?;
+
+        if !checkpoint.pre_dump && !checkpoint.leave_running {
+            state.status = Status::Stopped;
+            self.save_state(&state)?;
+            events::emit(&self.path, &self.id, EventKind::Stopped { exit_code: None });
+        }
+        Ok(())
+    }
+
+    /// Restores a checkpoint as a new running container, with its own state,
+    /// cgroup and network, so a checkpoint can be cloned under several ids.
+    /// Like a detached container, it outlives the runtime.
+    pub fn restore(restore: &Restore) -> Result<Container> {
+        let _span = info_span!("restore", id = %restore.id).entered();
+        validate_id(&restore.id)?;
+        let container = Container {
+            id: restore.id.clone(),
+            path: Path::new(&restore.root).join(&restore.id),
+        };
+        let mut spec = load_spec(Path::new(&restore.bundle))?;
+        if spec
+            .process
+            .as_ref()
+            .and_then(|process| process.terminal)
+            .unwrap_or_default()
+        {
+            return Err(Error::Bundle(
+                "a container with a terminal can't be restored".to_string(),
+            ));
+        }
+        let network = match &restore.network {
+            Some(network) => Some(Network::parse(network)?),
+            None => Network::from_annotations(&spec.annotations.clone().unwrap_or_default())?,
+        };
+
+        let _lock = container.claim()?;
+        let state = State::new(&restore.id, 0, &restore.bundle);
+        container.save_state(&state)?;
+        if Path::new(&spec.root.path).is_relative() {
+            spec.root.path = state.bundle.join(&spec.root.path).to_string_lossy().to_string();
+        }
+        spec.save(&container.path)?;
+
+        let result = container.restore_process(restore, &spec, network.as_ref());
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&container.path);
+        }
+        result.map(|_| container)
+    }
+
+    fn restore_process(
+        &self,
+        restore: &Restore,
+        spec: &Spec,
+        network: Option<&Network>,
+    ) -> Result<()> {
+        let linux = spec.linux.as_ref();
+        // criu recreates the cgroup of the checkpoint below this one
+        let cgroup = Cgroup::new(&self.id, linux);
+        let cgroup_root = match cgroup.driver() {
+            Driver::Fs => cgroup
+                .path()
+                .strip_prefix(CGROUP_ROOT)
+                .ok()
+                .map(|path| format!("/{}", path.display())),
+            Driver::SystemdUser => None,
+        };
+
+        // criu resolves a relative pid file against its work dir
+        let path = std::fs::canonicalize(&self.path)
+            .map_err(Error::io(format!("unable to resolve {:?}", self.path)))?;
+        let pid = criu::restore(
+            restore,
+            &spec.root.path,
+            &path.join("restore.pid"),
+            cgroup_root.as_deref(),
+        )?;
+        let process = Process::open(pid, None)?;
+
+        let setup = info_span!("cgroups")
+            .in_scope(|| cgroups::setup(&self.id, linux, pid))
+            .and_then(|_| match network {
+                Some(network) => info_span!("network")
+                    .in_scope(|| network.setup(&self.id, pid, &self.path)),
+                None => Ok(()),
+            });
+        if let Err(err) = setup {
+            let _ = process.signal(9);
+            return Err(err);
+        }
+
+        if let Some(start_time) = fork::start_time(pid) {
+            std::fs::write(self.path.join(START_TIME_FILE), start_time.to_string())
+                .map_err(Error::io("unable to save the process start time"))?;
+        }
+        if let Some(pid_file_path) = &restore.pid_file {
+            write_pid_file(pid_file_path, pid)?;
+        }
+
+        let mut state = self.saved_state()?;
+        state.status = Status::Running;
+        state.pid = i32::from(pid) as u64;
+        self.save_state(&state)?;
+        events::emit(&self.path, &self.id, EventKind::Created { pid: state.pid });
+        events::emit(&self.path, &self.id, EventKind::Started);
+        Ok(())
+    }
+
+    /// Runs the poststop hooks and removes the container state
+    pub fn delete(self) -> Result<()> {
+        let _lock = self.lock()?;
+        self.remove()
+    }
+
+    fn remove(self) -> Result<()> {
+        let _span = info_span!("delete", id = %self.id).entered();
+        let state = self.state()?;
+        let spec = self.spec()?;
+        monitor::stop_restarts(&self.path)?;
+
+        match state.status {
+            Status::Stopped => (),
+            // Like runc, the process of a created container is killed
+            Status::Created => match self.process(&state).and_then(|process| process.signal(9)) {
+                // Already killed with its cgroup by delete --force
+                Err(err) if is_gone(&err) => (),
+                Err(err) => warn!("{}", err),
+                Ok(()) => (),
+            },
+            // Just log the error
+            _ => error!("[DELETE] container {} isn't stopped", self.id),
+        }
+        // The monitor records the exit of the killed process before the state
+        // goes away. The one of a stopped container may wait for the lock to
+        // restart it, it gives up once the state is gone.
+        if state.status == Status::Created {
+            if let Err(err) = monitor::wait(&self.path) {
+                warn!("{}", err);
+            }
+        }
+
+        self.release(&state, &spec)?;
+
+        if let Err(err) = self.store().and_then(|store| store.remove(&self.id)) {
+            warn!("{}", err);
+        }
+        if std::fs::remove_dir_all(&self.path).is_err() {
+            warn!("failed to delete container root");
+        }
+
+        Ok(())
+    }
+
+    /// Removes what a create that failed once the container was claimed left:
+    /// the processes of its cgroup are killed, then the cgroup, the network
+    /// and the state go away
+    pub(crate) fn discard(&self) {
+        let _ = monitor::stop_restarts(&self.path);
+        // The copy of the spec is saved before the process is forked
+        if let Ok(spec) = self.spec() {
+            let cgroup = Cgroup::new(&self.id, spec.linux.as_ref());
+            if !cgroup.kill().unwrap_or_default() {
+                for pid in cgroups::procs(cgroup.path()) {
+                    let _ = fork::signal(pid, libc::SIGKILL);
+                }
+            }
+            // Removable once the killed processes are gone
+            let deadline = Instant::now() + DISCARD_TIMEOUT;
+            while !cgroups::procs(cgroup.path()).is_empty() && Instant::now() < deadline {
+                thread::sleep(fork::EXIT_POLL_INTERVAL);
+            }
+            if let Err(err) = cgroup.remove() {
+                warn!("{}", err);
+            }
+        }
+        if let Err(err) = Network::teardown(&self.path) {
+            warn!("{}", err);
+        }
+        let _ = self.store().and_then(|store| store.remove(&self.id));
+        if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            warn!("unable to remove state dir {:?}: {}", self.path, err);
+        }
+    }
+
+    /// Runs the poststop hooks and releases what the stopped container holds
+    /// on the host, its processes, cgroups and network
+    fn release(&self, state: &State, spec: &Spec) -> Result<()> {
+        plugins::run(Phase::Poststop, state)?;
+        if let Some(hooks) = &spec.hooks {
+            if let Some(poststop) = &hooks.poststop {
+                let _span = info_span!("hooks.poststop").entered();
+                for hook in poststop {
+                    exec_hook(hook, state)?;
+                }
+            }
+        }
+
+        // Without a PID namespace, exec'd processes outlive the container process
+        for process in exec::processes(&self.path) {
+            if process.signal(9).is_ok() {
+                let _ = process.wait_exit();
+            }
+        }
+
+        // Children first, the cgroup of the container can't be removed before
+        for cgroup in exec::cgroups(&self.path).iter().rev() {
+            match std::fs::remove_dir(cgroup) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("unable to remove exec cgroup {:?}: {}", cgroup, err)
+                }
+                _ => (),
+            }
+        }
+
+        if let Err(err) = Network::teardown(&self.path) {
+            warn!("{}", err);
+        }
+
+        let cgroup = info_span!("cgroups")
+            .in_scope(|| Cgroup::new(&self.id, spec.linux.as_ref()).remove());
+        if let Err(err) = cgroup {
+            warn!("{}", err);
+        }
+        Ok(())
+    }
+
+    /// Deletes the container even if it's running: its process is killed
+    /// through its pidfd, which never hits another process reusing the pid,
+    /// the rest of its cgroup through cgroup.kill, and waited for first
+    pub fn force_delete(self) -> Result<()> {
+        let _lock = self.lock()?;
+        monitor::stop_restarts(&self.path)?;
+        let state = self.state()?;
+        // Whatever the state, the processes left in the cgroup by an exited
+        // container process or forked meanwhile included
+        let cgroup = Cgroup::new(&self.id, self.spec()?.linux.as_ref());
+        let killed = match cgroup.kill() {
+            Ok(killed) => killed,
+            Err(err) => {
+                warn!("{}", err);
+                false
+            }
+        };
+        if !killed {
+            for pid in cgroups::procs(cgroup.path()) {
+                let _ = fork::signal(pid, libc::SIGKILL);
+            }
+        }
+        if state.status == Status::Running {
+            match self.process(&state) {
+                Ok(process) => {
+                    if let Err(err) = process.signal(9) {
+                        if !is_gone(&err) {
+                            return Err(err);
+                        }
+                    }
+                    self.wait()?;
+                }
+                // The process is already gone
+                Err(err) if is_gone(&err) => {
+                    self.stopped(None)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.remove()
+    }
+
+    /// Copies the stdin of the runtime to the container process of a detached
+    /// container and its output to the stdout, until the process exits.
+    /// A terminal is passed through in raw mode, its size follows the one of
+    /// the terminal of the runtime.
+    pub fn attach(&self) -> Result<()> {
+        let _raw_mode = if isatty(0).unwrap_or(false) {
+            monitor::follow_window_size(&self.path, 0)?;
+            Some(RawMode::enable(0)?)
+        } else {
+            None
+        };
+        monitor::attach(&self.path, 0, 1)
+    }
+
+    /// Entries of the json-file log of a detached container
+    pub fn logs(&self, logs: &Logs) -> Result<Entries> {
+        let path = self.path.join(monitor::LOG_FILE);
+        if !path.exists() {
+            return Err(Error::State(format!(
+                "container {} has no log, it wasn't created detached",
+                self.id
+            )));
+        }
+        let since = match &logs.since {
+            Some(since) => Some(
+                jsonfile::parse_since(since)
+                    .ok_or_else(|| Error::Runtime(format!("invalid since {:?}", since)))?,
+            ),
+            None => None,
+        };
+        // The monitor logs the output until the container process exits
+        let monitor_file = self.path.join(monitor::MONITOR_FILE);
+        let alive = match logs.follow {
+            true => Some(Box::new(move || monitor_file.exists()) as Box<dyn FnMut() -> bool>),
+            false => None,
+        };
+        Entries::open(&path, logs.tail, since, alive)
+    }
+
+    /// Reads the state of the container.
+    /// A container whose process is gone is reported as stopped.
+    pub fn state(&self) -> Result<State> {
+        Ok(self.saved_state()?.refreshed())
+    }
+
+    /// The state with the number of exec'd processes still running in its
+    /// `pura.exec.count` annotation, while the container is alive
+    pub fn exec_state(&self, mut state: State) -> State {
+        if state.status == Status::Created || state.status == Status::Running {
+            state.annotations.get_or_insert_with(Default::default).insert(
+                exec::EXEC_COUNT_ANNOTATION.to_string(),
+                exec::processes(&self.path).len().to_string(),
+            );
+        }
+        state
+    }
+
+    /// Serializes the runtime invocations changing the container
+    fn lock(&self) -> Result<Lock> {
+        self.lock_state().map(|(lock, _)| lock)
+    }
+
+    /// Locks the container, with its state as saved once locked
+    fn lock_state(&self) -> Result<(Lock, State)> {
+        let lock = Lock::acquire(self.root(), &self.id)?;
+        // Deleted while waiting for the lock
+        let state = self.saved_state()?;
+        Ok((lock, state))
+    }
+
+    /// Locks the directory of a new container, an existing one is refused
+    fn claim(&self) -> Result<Lock> {
+        let lock = Lock::acquire(self.root(), &self.id)?;
+        if self.store()?.load(&self.id)?.is_some() {
+            return Err(Error::State(format!("container {} already exists", self.id)));
+        }
+        std::fs::create_dir_all(&self.path)
+            .map_err(Error::io(format!("unable to create state dir {:?}", self.path)))?;
+        Ok(lock)
+    }
+
+    /// Bundle synthesized from the image in the state dir, see `image::create_bundle`
+    #[cfg(feature = "image")]
+    fn image_bundle(&self, image: &str) -> Result<PathBuf> {
+        let bundle = self.path.join(IMAGE_BUNDLE);
+        // Left by a create that failed, the container being claimed
+        if bundle.exists() {
+            std::fs::remove_dir_all(&bundle)
+                .map_err(Error::io(format!("unable to remove {:?}", bundle)))?;
+        }
+        image::create_bundle(image, &bundle)?;
+        Ok(bundle)
+    }
+
+    #[cfg(not(feature = "image"))]
+    fn image_bundle(&self, _image: &str) -> Result<PathBuf> {
+        Err(crate::core::common::disabled("image"))
+    }
+
+    /// Rootfs extracted from the tarball in the state dir. With a user
+    /// namespace, the owners are shifted by its id mappings.
+    #[cfg(feature = "image")]
+    fn tar_rootfs(&self, tarball: &str, spec: &Spec) -> Result<PathBuf> {
+        let rootfs = self.path.join(TAR_ROOTFS);
+        if rootfs.exists() {
+            std::fs::remove_dir_all(&rootfs)
+                .map_err(Error::io(format!("unable to remove {:?}", rootfs)))?;
+        }
+        let linux = spec.linux.clone().unwrap_or_default();
+        let user_ns = linux
+            .namespaces
+            .unwrap_or_default()
+            .iter()
+            .any(|ns| ns.namespace == "user" && ns.path.is_none());
+        let (uids, gids) = (
+            linux.uid_mappings.unwrap_or_default(),
+            linux.gid_mappings.unwrap_or_default(),
+        );
+        let shift = IdShift {
+            uids: &uids,
+            gids: &gids,
+        };
+        unpack::extract(Path::new(tarball), &rootfs, user_ns.then_some(&shift))?;
+        Ok(rootfs)
+    }
+
+    #[cfg(not(feature = "image"))]
+    fn tar_rootfs(&self, _tarball: &str, _spec: &Spec) -> Result<PathBuf> {
+        Err(crate::core::common::disabled("image"))
+    }
+
+    /// Runtime root of the container, the parent of its state dir
+    fn root(&self) -> &Path {
+        self.path.parent().unwrap_or(&self.path)
+    }
+
+    /// Store of the states of the runtime root of the container
+    fn store(&self) -> Result<Box<dyn Store>> {
+        state::open(self.root())
+    }
+
+    /// The state as saved, the process isn't checked to still be alive
+    fn saved_state(&self) -> Result<State> {
+        self.store()?
+            .load(&self.id)?
+            .ok_or_else(|| Error::NotFound(self.id.clone()))
+    }
+
+    fn save_state(&self, state: &State) -> Result<()> {
+        self.store()?.save(state)
+    }
+
+    /// The spec the container was created with, as resolved at create
+    pub fn spec(&self) -> Result<Spec> {
+        load_spec(&self.path)
+    }
+
+    /// Opens the container process, failing if it's gone or its pid got reused
+    pub(crate) fn process(&self, state: &State) -> Result<Process> {
+        let start_time = std::fs::read_to_string(self.path.join(START_TIME_FILE))
+            .ok()
+            .and_then(|start_time| start_time.trim().parse().ok());
+        Process::open(Pid::from_raw(state.pid as i32), start_time)
+    }
+
+    /// Filter of `linux.seccomp`, from the cache of the runtime root
+    fn seccomp_filter(&self, spec: &Spec) -> Result<Option<Filter>> {
+        let seccomp = match spec.linux.as_ref().and_then(|linux| linux.seccomp.as_ref()) {
+            Some(seccomp) => seccomp,
+            None => return Ok(None),
+        };
+        Filter::cached(seccomp, &self.root().join(seccomp::CACHE_DIR)).map(Some)
+    }
+
+    /// Lifecycle events of the container recorded so far or, when following,
+    /// blocking for new ones until the container stops
+    pub fn events(&self, follow: bool) -> Events {
+        Events::new(&self.path, &self.id, follow)
+    }
+
+    /// Follows the events of the container from a background thread
+    pub fn subscribe(&self) -> Receiver<Result<Event>> {
+        let (sender, receiver) = channel();
+        let events = self.events(true);
+        thread::spawn(move || {
+            for event in events {
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// Whether the error is the one of a process that already exited
+fn is_gone(err: &Error) -> bool {
+    matches!(err, Error::Sys { source, .. } if source.as_errno() == Some(Errno::ESRCH))
+}
+
+fn write_pid_file(pid_file_path: &str, pid: Pid) -> Result<()> {
+    let mut pid_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(pid_file_path)
+        .map_err(Error::io(format!("unable to open pid file {}", pid_file_path)))?;
+    pid_file
+        .write_all(format!("{}", pid).as_bytes())
+        .map_err(Error::io(format!("unable to write pid file {}", pid_file_path)))
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use crate::core::common::{disabled, Error, Result};
+use crate::oci::spec::Process;
+
+/// `Y` when the kernel enables AppArmor
+const ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+/// Profile of the next exec of the process, the attribute of AppArmor first
+/// and the one shared by the security modules before Linux 5.8
+const EXEC_ATTRS: [&str; 2] = ["/proc/self/attr/apparmor/exec", "/proc/self/attr/exec"];
+/// Profile of the process itself, for the wasm modules that run without an exec
+const CURRENT_ATTRS: [&str; 2] = ["/proc/self/attr/apparmor/current", "/proc/self/attr/current"];
+
+pub fn enabled() -> bool {
+    std::fs::read_to_string(ENABLED)
+        .map(|enabled| enabled.trim() == "Y")
+        .unwrap_or(false)
+}
+
+/// Profile of `process.apparmorProfile`, None when the host doesn't enable
+/// AppArmor. Checked by the runtime, the container doesn't see /sys.
+pub fn profile(process: &Process) -> Result<Option<String>> {
+    let profile = match &process.apparmor_profile {
+        Some(profile) if enabled() => profile.clone(),
+        _ => return Ok(None),
+    };
+    // Left out, the process would run unconfined
+    if !cfg!(feature = "apparmor") {
+        return Err(disabled("apparmor"));
+    }
+    Ok(Some(profile))
+}
+
+/// Has the next exec of the current process switch to the profile
+pub fn change_onexec(profile: &str) -> Result<()> {
+    write_attr(&EXEC_ATTRS, "exec", profile)
+}
+
+/// Switches the current process to the profile right away
+pub fn change_profile(profile: &str) -> Result<()> {
+    write_attr(&CURRENT_ATTRS, "changeprofile", profile)
+}
+
+fn write_attr(attrs: &[&str; 2], command: &str, profile: &str) -> Result<()> {
+    let attr = attrs
+        .iter()
+        .find(|attr| Path::new(attr).exists())
+        .unwrap_or(&attrs[1]);
+    std::fs::write(attr, format!("{} {}", command, profile)).map_err(Error::io(format!(
+        "unable to set the apparmor profile {}",
+        profile
+    )))
+}
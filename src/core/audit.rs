@@ -0,0 +1,157 @@
+use chrono::Local;
+use lazy_static::lazy_static;
+use nix::unistd::{getpid, getuid, Pid};
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::core::common::{Error, Result};
+
+pub const AUDIT_FILE: &str = "audit.log";
+
+lazy_static! {
+    static ref PENDING: Mutex<Option<Operation>> = Mutex::new(None);
+}
+
+/// State-changing operation waiting for its result to be audited
+struct Operation {
+    root: PathBuf,
+    subcommand: String,
+    id: String,
+    // Only the runtime process records the result, not the cloned container child
+    pid: Pid,
+    start: Instant,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    uid: u32,
+    subcommand: &'a str,
+    id: &'a str,
+    result: &'a str,
+    code: i32,
+    /// Exit code of the container process of `run`, apart from the result of
+    /// the runtime
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_status: Option<i32>,
+    /// Seconds the operation took
+    duration: f64,
+}
+
+/// Marks the start of a state-changing operation on a container.
+/// The record is appended to `<root>/audit.log` once the process exits.
+pub fn begin(root: &str, subcommand: &str, id: &str) {
+    *PENDING.lock().unwrap() = Some(Operation {
+        root: PathBuf::from(root),
+        subcommand: subcommand.to_string(),
+        id: id.to_string(),
+        pid: getpid(),
+        start: Instant::now(),
+    });
+}
+
+/// Records the result of the pending operation, if any
+pub fn finish(code: i32) {
+    record(code, None)
+}
+
+/// Records the pending operation as a success of the runtime whose
+/// container process exited with the code
+pub fn finish_process(exit_status: i32) {
+    record(0, Some(exit_status))
+}
+
+fn record(code: i32, exit_status: Option<i32>) {
+    let operation = match PENDING.lock() {
+        Ok(mut pending) => pending.take(),
+        Err(_) => return,
+    };
+
+    if let Some(operation) = operation {
+        if operation.pid != getpid() {
+            return;
+        }
+
+        let result = if code == 0 { "success" } else { "failure" };
+        if let Err(err) = append(&operation, result, code, exit_status) {
+            log::warn!("{}", err);
+        }
+    }
+}
+
+fn append(operation: &Operation, result: &str, code: i32, exit_status: Option<i32>) -> Result<()> {
+    let record = AuditRecord {
+        time: Local::now().to_rfc3339(),
+        uid: getuid().as_raw(),
+        subcommand: &operation.subcommand,
+        id: &operation.id,
+        result,
+        code,
+        exit_status,
+        duration: operation.start.elapsed().as_secs_f64(),
+    };
+    let root: &Path = &operation.root;
+
+    std::fs::create_dir_all(root)
+        .map_err(Error::io(format!("unable to create runtime root {:?}", root)))?;
+
+    let mut audit_file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(root.join(AUDIT_FILE))
+        .map_err(Error::io("unable to open audit file"))?;
+
+    // A single write of an O_APPEND file keeps concurrent records from interleaving
+    let line = format!("{}\n", serde_json::to_string(&record).unwrap());
+    audit_file
+        .write_all(line.as_bytes())
+        .map_err(Error::io("unable to write audit record"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{begin, finish, finish_process};
+
+    #[test]
+    fn audit_record() {
+        let root = "./audit_test";
+        begin(root, "kill", "abc");
+        finish(1);
+        // Finishing again without a pending operation doesn't record anything
+        finish(0);
+        // The runtime succeeded, whatever the exit code of the process
+        begin(root, "run", "abc");
+        finish_process(3);
+
+        let audit = std::fs::read_to_string(Path::new(root).join("audit.log")).unwrap();
+        let lines: Vec<&str> = audit.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["subcommand"], "kill");
+        assert_eq!(record["id"], "abc");
+        assert_eq!(record["result"], "failure");
+        assert_eq!(record["code"], 1);
+        assert!(record["uid"].is_number());
+        assert!(record["time"].is_string());
+        assert!(record["duration"].as_f64().unwrap() >= 0.0);
+        assert!(record.get("exit_status").is_none());
+
+        let record: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(record["subcommand"], "run");
+        assert_eq!(record["result"], "success");
+        assert_eq!(record["code"], 0);
+        assert_eq!(record["exit_status"], 3);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
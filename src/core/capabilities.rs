@@ -0,0 +1,319 @@
+use nix::{errno::Errno, libc};
+
+use crate::core::common::{Error, Result};
+use crate::oci::spec::Capabilities;
+
+/// Capabilities by number, up to Linux 5.9
+const CAPABILITIES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+/// _LINUX_CAPABILITY_VERSION_3 of capset(2), 64-bit sets
+const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Canonical name of the capability, like `CAP_SYS_PTRACE` for `sys_ptrace`
+pub fn canonical(name: &str) -> Result<String> {
+    let name = name.to_uppercase();
+    let name = match name.starts_with("CAP_") {
+        true => name,
+        false => format!("CAP_{}", name),
+    };
+    match CAPABILITIES.contains(&name.as_str()) {
+        true => Ok(name),
+        false => Err(Error::Runtime(format!("unknown capability {}", name))),
+    }
+}
+
+/// Names of the capabilities of a mask like the ones of /proc/<pid>/status
+pub fn names(mask: u64) -> Vec<&'static str> {
+    CAPABILITIES
+        .iter()
+        .enumerate()
+        .filter(|(number, _)| mask & (1 << number) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn number(name: &str) -> Result<u32> {
+    let name = canonical(name)?;
+    Ok(CAPABILITIES.iter().position(|cap| *cap == name).unwrap() as u32)
+}
+
+/// Drops and adds capabilities, `ALL` standing for every capability, so
+/// dropping ALL and adding some keeps only those. Added capabilities go to
+/// every set, ambient included so they survive the exec of a process not
+/// running as root. Without capabilities, the process keeps every capability
+/// of the runtime, which the drops apply to.
+pub fn adjust(
+    capabilities: Option<&Capabilities>,
+    add: &[String],
+    drop: &[String],
+) -> Result<Option<Capabilities>> {
+    if add.is_empty() && drop.is_empty() {
+        return Ok(capabilities.cloned());
+    }
+    let expand = |names: &[String]| -> Result<Vec<String>> {
+        let mut expanded = Vec::new();
+        for name in names {
+            if name.eq_ignore_ascii_case("ALL") {
+                expanded.extend(CAPABILITIES.iter().map(|cap| cap.to_string()));
+            } else {
+                expanded.push(canonical(name)?);
+            }
+        }
+        Ok(expanded)
+    };
+    let (add, drop) = (expand(add)?, expand(drop)?);
+
+    let every = || Some(CAPABILITIES.iter().map(|cap| cap.to_string()).collect());
+    let mut capabilities = capabilities.cloned().unwrap_or(Capabilities {
+        bounding: every(),
+        permitted: every(),
+        inheritable: every(),
+        effective: every(),
+        ambient: None,
+        ..Default::default()
+    });
+    for set in [
+        &mut capabilities.bounding,
+        &mut capabilities.permitted,
+        &mut capabilities.inheritable,
+        &mut capabilities.effective,
+        &mut capabilities.ambient,
+    ] {
+        let set = set.get_or_insert_with(Vec::new);
+        set.retain(|name| {
+            !drop
+                .iter()
+                .any(|cap| canonical(name).ok().as_ref() == Some(cap))
+        });
+        for cap in add.iter() {
+            if !set
+                .iter()
+                .any(|name| canonical(name).ok().as_ref() == Some(cap))
+            {
+                set.push(cap.clone());
+            }
+        }
+    }
+    Ok(Some(capabilities))
+}
+
+/// Drops the capabilities out of the bounding set. Needs CAP_SETPCAP, so it
+/// comes before the change of user.
+pub fn drop_bounding(capabilities: &Capabilities) -> Result<()> {
+    let bounding = mask(capabilities.bounding.as_deref())?;
+    for cap in 0..=last_capability() {
+        if bounding & (1 << cap) == 0 {
+            let res = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+            // Capabilities unknown to the kernel
+            match Errno::result(res) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINVAL)) => (),
+                Err(err) => return Err(Error::sys(format!("unable to drop {}", name(cap)))(err)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sets the effective, permitted, inheritable and ambient sets of the current
+/// process. After a change of user, the capabilities must have been kept with
+/// PR_SET_KEEPCAPS. The capabilities the process doesn't have are left out,
+/// it can't gain them.
+pub fn set(capabilities: &Capabilities) -> Result<()> {
+    let held = permitted()?;
+    let effective = mask(capabilities.effective.as_deref())? & held;
+    let permitted = mask(capabilities.permitted.as_deref())? & held;
+    let inheritable = mask(capabilities.inheritable.as_deref())? & held;
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: effective as u32,
+            permitted: permitted as u32,
+            inheritable: inheritable as u32,
+        },
+        CapData {
+            effective: (effective >> 32) as u32,
+            permitted: (permitted >> 32) as u32,
+            inheritable: (inheritable >> 32) as u32,
+        },
+    ];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapHeader,
+            data.as_ptr(),
+        )
+    };
+    Errno::result(res).map_err(Error::sys("capset failed"))?;
+
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    Errno::result(res).map_err(Error::sys("unable to clear the ambient capabilities"))?;
+    for name in capabilities.ambient.iter().flatten() {
+        // Ambient capabilities must be permitted and inheritable
+        let cap = number(name)?;
+        if permitted & inheritable & (1 << cap) == 0 {
+            continue;
+        }
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                cap as libc::c_ulong,
+                0,
+                0,
+            )
+        };
+        Errno::result(res).map_err(Error::sys(format!("unable to raise ambient {}", name)))?;
+    }
+    Ok(())
+}
+
+/// Permitted set of the current process
+fn permitted() -> Result<u64> {
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_capget,
+            &mut header as *mut CapHeader,
+            data.as_mut_ptr(),
+        )
+    };
+    Errno::result(res).map_err(Error::sys("capget failed"))?;
+    Ok(data[0].permitted as u64 | (data[1].permitted as u64) << 32)
+}
+
+fn mask(names: Option<&[String]>) -> Result<u64> {
+    let mut mask = 0;
+    for name in names.unwrap_or_default() {
+        mask |= 1 << number(name)?;
+    }
+    Ok(mask)
+}
+
+fn name(cap: u32) -> &'static str {
+    CAPABILITIES
+        .get(cap as usize)
+        .copied()
+        .unwrap_or("capability")
+}
+
+/// Highest capability of the running kernel
+fn last_capability() -> u32 {
+    std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(CAPABILITIES.len() as u32 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adjust, canonical, mask};
+    use crate::oci::spec::Capabilities;
+
+    #[test]
+    fn adjust_capabilities() {
+        assert_eq!(canonical("sys_ptrace").unwrap(), "CAP_SYS_PTRACE");
+        assert_eq!(canonical("CAP_KILL").unwrap(), "CAP_KILL");
+        assert!(canonical("CAP_FLY").is_err());
+
+        let caps = |names: &[&str]| Some(names.iter().map(|name| name.to_string()).collect());
+        let spec = Capabilities {
+            bounding: caps(&["CAP_KILL", "CAP_CHOWN"]),
+            permitted: caps(&["CAP_KILL", "CAP_CHOWN"]),
+            effective: caps(&["CAP_KILL"]),
+            ..Default::default()
+        };
+        assert_eq!(adjust(Some(&spec), &[], &[]).unwrap(), Some(spec.clone()));
+
+        let adjusted = adjust(
+            Some(&spec),
+            &["SYS_PTRACE".to_string()],
+            &["cap_chown".to_string()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(adjusted.bounding, caps(&["CAP_KILL", "CAP_SYS_PTRACE"]));
+        assert_eq!(adjusted.effective, caps(&["CAP_KILL", "CAP_SYS_PTRACE"]));
+        assert_eq!(adjusted.ambient, caps(&["CAP_SYS_PTRACE"]));
+
+        // Every capability of the runtime but the dropped ones
+        let adjusted = adjust(None, &[], &["NET_RAW".to_string()])
+            .unwrap()
+            .unwrap();
+        let effective = mask(adjusted.effective.as_deref()).unwrap();
+        assert_eq!(effective, (1 << 41) - 1 - (1 << 13));
+        let adjusted = adjust(None, &["KILL".to_string()], &["ALL".to_string()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(adjusted.bounding, caps(&["CAP_KILL"]));
+    }
+}
@@ -0,0 +1,423 @@
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::core::{
+    common::{Error, Result},
+    filesystem::host_device,
+};
+use crate::oci::spec::{Device, Hook, Hooks, Mount, Process, Spec};
+
+/// Directories of the CDI specs, a spec of a later one taking precedence
+pub const SPEC_DIRS: [&str; 2] = ["/etc/cdi", "/var/run/cdi"];
+/// Colon separated directories searched instead of `SPEC_DIRS`
+pub const SPEC_DIRS_ENV: &str = "PURA_CDI_SPEC_DIRS";
+/// Prefix of the annotations requesting devices, as set by the kubelet
+pub const ANNOTATION_PREFIX: &str = "cdi.k8s.io/";
+
+/// Container Device Interface spec of a kind of devices, like `nvidia.com/gpu`
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CdiSpec {
+    pub cdi_version: String,
+    pub kind: String,
+    pub devices: Vec<CdiDevice>,
+    /// Edits shared by every device of the spec
+    pub container_edits: ContainerEdits,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CdiDevice {
+    pub name: String,
+    pub container_edits: ContainerEdits,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ContainerEdits {
+    pub env: Vec<String>,
+    pub device_nodes: Vec<DeviceNode>,
+    pub mounts: Vec<CdiMount>,
+    pub hooks: Vec<CdiHook>,
+    #[serde(rename = "additionalGIDs")]
+    pub additional_gids: Vec<u32>,
+}
+
+/// Device node of the container, the type and numbers read from the host
+/// path when the spec doesn't have them
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeviceNode {
+    pub path: String,
+    pub host_path: Option<String>,
+    #[serde(rename = "type")]
+    pub device_type: Option<String>,
+    pub major: Option<u64>,
+    pub minor: Option<u64>,
+    pub file_mode: Option<i64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CdiMount {
+    pub host_path: String,
+    pub container_path: String,
+    #[serde(rename = "type")]
+    pub mount_type: Option<String>,
+    pub options: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CdiHook {
+    /// Hook of the OCI spec, like `createContainer`
+    pub hook_name: String,
+    pub path: String,
+    pub args: Option<Vec<String>>,
+    pub env: Option<Vec<String>>,
+    pub timeout: Option<i64>,
+}
+
+/// Specs of the CDI directories
+#[derive(Default, Debug)]
+pub struct Registry {
+    specs: Vec<CdiSpec>,
+}
+
+impl Registry {
+    /// Loads the specs of `SPEC_DIRS_ENV`, or of `SPEC_DIRS` without it
+    pub fn load() -> Result<Registry> {
+        let dirs: Vec<PathBuf> = match std::env::var_os(SPEC_DIRS_ENV) {
+            Some(dirs) => std::env::split_paths(&dirs).collect(),
+            None => SPEC_DIRS.iter().map(PathBuf::from).collect(),
+        };
+        Registry::load_dirs(&dirs)
+    }
+
+    /// Loads the JSON specs of the directories, missing ones skipped
+    pub fn load_dirs(dirs: &[PathBuf]) -> Result<Registry> {
+        let mut specs = Vec::new();
+        for dir in dirs {
+            let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir) {
+                Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+                Err(_) => continue,
+            };
+            paths.sort();
+            for path in paths {
+                match path.extension().and_then(|extension| extension.to_str()) {
+                    Some("json") => specs.push(load_spec(&path)?),
+                    Some("yaml") | Some("yml") => {
+                        warn!(
+                            "skipping CDI spec {:?}, only JSON specs are supported",
+                            path
+                        )
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(Registry { specs })
+    }
+
+    /// Whether a spec has devices of the kind
+    pub fn has_kind(&self, kind: &str) -> bool {
+        self.specs.iter().any(|spec| spec.kind == kind)
+    }
+
+    /// Edits of the device: those shared by its spec, then its own
+    pub fn resolve(&self, qualified: &str) -> Result<[&ContainerEdits; 2]> {
+        let (kind, name) = parse_name(qualified)
+            .ok_or_else(|| Error::Bundle(format!("invalid CDI device name {:?}", qualified)))?;
+        self.specs
+            .iter()
+            .rev()
+            .filter(|spec| spec.kind == kind)
+            .find_map(|spec| {
+                let device = spec.devices.iter().find(|device| device.name == name)?;
+                Some([&spec.container_edits, &device.container_edits])
+            })
+            .ok_or_else(|| Error::Bundle(format!("unresolvable CDI device {}", qualified)))
+    }
+}
+
+fn load_spec(path: &Path) -> Result<CdiSpec> {
+    let content = std::fs::read_to_string(path)
+        .map_err(Error::io(format!("unable to read CDI spec {:?}", path)))?;
+    serde_json::from_str(&content).map_err(Error::json(format!("invalid CDI spec {:?}", path)))
+}
+
+/// Splits a fully qualified device name, `vendor.com/class=name`, into its
+/// kind and name
+pub fn parse_name(qualified: &str) -> Option<(&str, &str)> {
+    let (kind, name) = qualified.split_once('=')?;
+    let (vendor, class) = kind.split_once('/')?;
+    let valid = |part: &str, extra: &[char]| {
+        part.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || extra.contains(&c))
+    };
+    let qualified = valid(vendor, &['.', '-', '_'])
+        && valid(class, &['-', '_'])
+        && valid(name, &['.', '-', '_', ':']);
+    qualified.then_some((kind, name))
+}
+
+/// Devices the `cdi.k8s.io/` annotations request, comma separated
+pub fn annotated_devices(annotations: Option<&HashMap<String, String>>) -> Vec<String> {
+    let mut devices: Vec<String> = annotations
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| key.starts_with(ANNOTATION_PREFIX))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|device| device.trim().to_string())
+        .filter(|device| !device.is_empty())
+        .collect();
+    devices.sort();
+    devices
+}
+
+/// Injects the device nodes, mounts, env, hooks and groups of the CDI devices
+/// into the spec, the edits shared by devices of the same spec once
+pub fn inject(mut spec: Spec, devices: &[String]) -> Result<Spec> {
+    if devices.is_empty() {
+        return Ok(spec);
+    }
+    let registry = Registry::load()?;
+    let mut edits: Vec<&ContainerEdits> = Vec::new();
+    for device in devices {
+        debug!("injecting CDI device {}", device);
+        for device_edits in registry.resolve(device)? {
+            if !edits.iter().any(|edit| std::ptr::eq(*edit, device_edits)) {
+                edits.push(device_edits);
+            }
+        }
+    }
+    for device_edits in edits {
+        spec = apply(spec, device_edits)?;
+    }
+    Ok(spec)
+}
+
+/// Applies the edits to the spec, a device node replacing one of the same path
+pub fn apply(mut spec: Spec, edits: &ContainerEdits) -> Result<Spec> {
+    for entry in &edits.env {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| Error::Bundle(format!("invalid CDI env {:?}", entry)))?;
+        spec = spec.add_env(key, value);
+    }
+
+    for node in &edits.device_nodes {
+        let device = device(node)?;
+        let devices = spec
+            .linux
+            .get_or_insert_with(Default::default)
+            .devices
+            .get_or_insert_with(Vec::new);
+        devices.retain(|existing| existing.path != device.path);
+        devices.push(device);
+    }
+
+    for mount in &edits.mounts {
+        spec = spec.add_mount(Mount {
+            destination: mount.container_path.clone(),
+            mount_type: Some(
+                mount
+                    .mount_type
+                    .clone()
+                    .unwrap_or_else(|| "bind".to_string()),
+            ),
+            source: Some(mount.host_path.clone()),
+            options: Some(mount.options.clone()),
+            ..Default::default()
+        });
+    }
+
+    for cdi_hook in &edits.hooks {
+        let hooks = spec.hooks.get_or_insert_with(Hooks::default);
+        let hooks = match cdi_hook.hook_name.as_str() {
+            "prestart" => &mut hooks.prestart,
+            "createRuntime" => &mut hooks.create_runtime,
+            "createContainer" => &mut hooks.create_container,
+            "startContainer" => &mut hooks.start_container,
+            "poststart" => &mut hooks.poststart,
+            "poststop" => &mut hooks.poststop,
+            name => return Err(Error::Bundle(format!("unknown CDI hook {}", name))),
+        };
+        hooks.get_or_insert_with(Vec::new).push(Hook {
+            path: cdi_hook.path.clone(),
+            args: cdi_hook.args.clone(),
+            env: cdi_hook.env.clone(),
+            timeout: cdi_hook.timeout,
+            ..Default::default()
+        });
+    }
+
+    if !edits.additional_gids.is_empty() {
+        let process = spec.process.get_or_insert_with(|| Process {
+            cwd: String::from("/"),
+            ..Default::default()
+        });
+        let gids = process
+            .user
+            .get_or_insert_with(Default::default)
+            .additional_gids
+            .get_or_insert_with(Vec::new);
+        for gid in &edits.additional_gids {
+            if !gids.contains(&(*gid as i64)) {
+                gids.push(*gid as i64);
+            }
+        }
+    }
+    Ok(spec)
+}
+
+/// Device of the spec for the node, the missing type, numbers and mode taken
+/// from the device at the host path
+fn device(node: &DeviceNode) -> Result<Device> {
+    if let (Some(device_type), Some(major), Some(minor)) =
+        (&node.device_type, node.major, node.minor)
+    {
+        return Ok(Device {
+            path: node.path.clone(),
+            device_type: device_type.clone(),
+            major,
+            minor,
+            file_mode: node.file_mode,
+            uid: node.uid,
+            gid: node.gid,
+            ..Default::default()
+        });
+    }
+    let host_path = node.host_path.as_deref().unwrap_or(&node.path);
+    let host = host_device(host_path, &node.path)?;
+    Ok(Device {
+        device_type: node.device_type.clone().unwrap_or(host.device_type),
+        major: node.major.unwrap_or(host.major),
+        minor: node.minor.unwrap_or(host.minor),
+        file_mode: node.file_mode.or(host.file_mode),
+        uid: node.uid,
+        gid: node.gid,
+        ..host
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::{annotated_devices, apply, device, parse_name, DeviceNode, Registry};
+    use crate::oci::spec::Spec;
+
+    #[test]
+    fn device_names() {
+        assert_eq!(
+            parse_name("nvidia.com/gpu=0"),
+            Some(("nvidia.com/gpu", "0"))
+        );
+        assert_eq!(
+            parse_name("vendor.com/class=mig-1:0"),
+            Some(("vendor.com/class", "mig-1:0"))
+        );
+        for invalid in [
+            "nvidia.com/gpu",
+            "gpu=0",
+            "nvidia.com/gpu=",
+            "vendor.com/a.b=0",
+        ] {
+            assert_eq!(parse_name(invalid), None, "{}", invalid);
+        }
+
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            "cdi.k8s.io/gpus".to_string(),
+            "nvidia.com/gpu=1, nvidia.com/gpu=0".to_string(),
+        );
+        annotations.insert("other".to_string(), "vendor.com/x=0".to_string());
+        assert_eq!(
+            annotated_devices(Some(&annotations)),
+            ["nvidia.com/gpu=0", "nvidia.com/gpu=1"]
+        );
+        assert!(annotated_devices(None).is_empty());
+    }
+
+    #[test]
+    fn resolve_and_apply() {
+        let dirs = [
+            PathBuf::from("./cdi_test/etc"),
+            PathBuf::from("./cdi_test/run"),
+        ];
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(
+            dirs[0].join("vendor.json"),
+            r#"{"cdiVersion": "0.6.0", "kind": "vendor.com/device",
+                "devices": [{"name": "null", "containerEdits": {"env": ["OLD=1"]}}]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dirs[1].join("vendor.json"),
+            r#"{
+                "cdiVersion": "0.6.0",
+                "kind": "vendor.com/device",
+                "containerEdits": {"env": ["VENDOR=1"], "additionalGIDs": [44]},
+                "devices": [{
+                    "name": "null",
+                    "containerEdits": {
+                        "deviceNodes": [{"path": "/dev/vendor0", "hostPath": "/dev/null"}],
+                        "mounts": [{"hostPath": "/lib", "containerPath": "/vendor", "options": ["ro", "bind"]}],
+                        "hooks": [{"hookName": "createContainer", "path": "/bin/true"}]
+                    }
+                }]
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(dirs[1].join("other.yaml"), "kind: vendor.com/other").unwrap();
+
+        let registry = Registry::load_dirs(&dirs).unwrap();
+        assert!(registry.resolve("vendor.com/device=missing").is_err());
+        assert!(registry.resolve("vendor.com/other=0").is_err());
+        let mut spec = Spec::new();
+        for edits in registry.resolve("vendor.com/device=null").unwrap() {
+            spec = apply(spec, edits).unwrap();
+        }
+
+        let process = spec.process.unwrap();
+        assert_eq!(process.env.unwrap(), ["VENDOR=1"]);
+        assert_eq!(process.user.unwrap().additional_gids, Some(vec![44]));
+        let devices = spec.linux.unwrap().devices.unwrap();
+        assert_eq!(devices[0].path, "/dev/vendor0");
+        assert_eq!(
+            (
+                devices[0].device_type.as_str(),
+                devices[0].major,
+                devices[0].minor
+            ),
+            ("c", 1, 3)
+        );
+        let mounts = spec.mounts.unwrap();
+        assert_eq!(mounts[0].destination, "/vendor");
+        assert_eq!(mounts[0].mount_type.as_deref(), Some("bind"));
+        assert_eq!(
+            spec.hooks.unwrap().create_container.unwrap()[0].path,
+            "/bin/true"
+        );
+
+        assert!(device(&DeviceNode {
+            path: String::from("/dev/vendor0"),
+            host_path: Some(String::from("/etc/hostname")),
+            ..Default::default()
+        })
+        .is_err());
+
+        std::fs::remove_dir_all("./cdi_test").unwrap();
+    }
+}
@@ -0,0 +1,927 @@
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use nix::unistd::{chown, geteuid, Gid, Pid, Uid};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::core::common::{Error, Result};
+use crate::oci::spec::{DeviceResource, Linux, Resources, UidMapping};
+
+/// Mount point of the unified (v2) hierarchy
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Annotation delegating the cgroup of the container to it, for systemd or a
+/// container runtime inside: `true` for every controller available, or a
+/// comma separated list like `cpu,memory,pids`
+pub const DELEGATE_ANNOTATION: &str = "pura.cgroup.delegate";
+/// Controllers of the unified hierarchy
+const CONTROLLERS: [&str; 8] = [
+    "cpu", "cpuset", "io", "memory", "pids", "hugetlb", "rdma", "misc",
+];
+/// Files of a cgroup its delegatee writes to, along with the directory
+/// (Documentation/admin-guide/cgroup-v2.rst)
+const DELEGATED_FILES: [&str; 3] = ["cgroup.procs", "cgroup.threads", "cgroup.subtree_control"];
+/// Leaf cgroup of the container in its systemd user scope. systemd only
+/// hands the scope directory and its `DELEGATED_FILES` to the user, the
+/// limits and cgroup.kill go to a cgroup the user creates below.
+const SCOPE_LEAF: &str = "container";
+
+/// Resources with pressure stall information (PSI) in a cgroup
+pub const PRESSURE_RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+/// Share of the time tasks stalled on a resource, from a line of a
+/// `*.pressure` file: over the last 10, 60 and 300 seconds, in percent, and
+/// the total stall time in microseconds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: u64,
+}
+
+/// Pressure of a resource: `some` task stalled, or all of them (`full`)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pressure {
+    pub some: PressureLine,
+    pub full: Option<PressureLine>,
+}
+
+/// How the cgroup of the container is created
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    /// Directory created by the runtime under the cgroup root
+    Fs,
+    /// Transient scope of the systemd user manager, delegated to the user
+    #[serde(rename = "systemd")]
+    SystemdUser,
+}
+
+lazy_static! {
+    /// Driver of the host configuration, instead of the one of the user
+    static ref DRIVER: Mutex<Option<Driver>> = Mutex::new(None);
+}
+
+/// Creates the cgroups of the containers with the driver, whatever the user
+pub fn set_driver(driver: Driver) {
+    *DRIVER.lock().unwrap() = Some(driver);
+}
+
+/// cgroup (v2) of a container
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    id: String,
+    path: PathBuf,
+    driver: Driver,
+}
+
+impl Cgroup {
+    /// Cgroup of the container: linux.cgroupsPath (or pura/<id>) as root,
+    /// the leaf of a `pura-<id>.scope` of the systemd user manager when
+    /// rootless, unless the host configuration sets the driver
+    pub fn new(id: &str, linux: Option<&Linux>) -> Cgroup {
+        let driver = DRIVER.lock().unwrap().clone().unwrap_or(if geteuid().is_root() {
+            Driver::Fs
+        } else {
+            Driver::SystemdUser
+        });
+        if driver == Driver::Fs {
+            let cgroups_path = linux
+                .and_then(|linux| linux.cgroups_path.clone())
+                .unwrap_or_else(|| format!("pura/{}", id));
+            Cgroup {
+                id: id.to_string(),
+                path: Path::new(CGROUP_ROOT).join(cgroups_path.trim_start_matches('/')),
+                driver: Driver::Fs,
+            }
+        } else {
+            let uid = geteuid();
+            Cgroup {
+                id: id.to_string(),
+                path: Path::new(CGROUP_ROOT)
+                    .join(format!(
+                        "user.slice/user-{uid}.slice/user@{uid}.service/user.slice/{}",
+                        scope_name(id),
+                        uid = uid
+                    ))
+                    .join(SCOPE_LEAF),
+                driver: Driver::SystemdUser,
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn driver(&self) -> &Driver {
+        &self.driver
+    }
+
+    /// Creates the cgroup, moves the process into it and applies the resources
+    pub fn setup(&mut self, pid: Pid, resources: Option<&Resources>) -> Result<()> {
+        if !is_unified() {
+            return Err(Error::Runtime(format!(
+                "cgroup v2 isn't mounted on {}",
+                CGROUP_ROOT
+            )));
+        }
+
+        match self.driver {
+            Driver::Fs => {
+                debug!("creating cgroup {:?}", self.path);
+                std::fs::create_dir_all(&self.path).map_err(Error::io(format!(
+                    "unable to create cgroup {:?}",
+                    self.path
+                )))?;
+                self.write("cgroup.procs", &pid.to_string())?;
+            }
+            Driver::SystemdUser => {
+                debug!("starting systemd user scope {}", scope_name(&self.id));
+                busctl(&start_scope_args(&self.id, pid))?;
+                self.path = wait_scope(&self.id, pid)?.join(SCOPE_LEAF);
+                // Out of the scope, its subtree_control can't enable controllers otherwise
+                std::fs::create_dir_all(&self.path).map_err(Error::io(format!(
+                    "unable to create cgroup {:?}",
+                    self.path
+                )))?;
+                self.write("cgroup.procs", &pid.to_string())?;
+            }
+        }
+
+        if let Some(resources) = resources {
+            self.apply(resources)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the cgroup files of the resources
+    pub fn apply(&self, resources: &Resources) -> Result<()> {
+        for (file, value) in resource_files(resources) {
+            // Up to the scope rootless, the controllers above are the ones
+            // systemd delegates to the user
+            enable_controller(&self.path, controller(file));
+            self.write(file, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the cgroup once the container processes are gone
+    pub fn remove(&self) -> Result<()> {
+        match self.driver {
+            Driver::Fs => match std::fs::remove_dir(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(Error::io(format!(
+                        "unable to remove cgroup {:?}",
+                        self.path
+                    ))(err))
+                }
+                _ => Ok(()),
+            },
+            // The scope may already be collected by systemd once empty
+            Driver::SystemdUser => {
+                let _ = std::fs::remove_dir(&self.path);
+                let _ = busctl(&stop_scope_args(&self.id));
+                Ok(())
+            }
+        }
+    }
+
+    /// Child cgroup of this one, like a sub-cgroup for exec'd processes
+    pub fn child(&self, name: &str) -> Result<Cgroup> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(Error::Runtime(format!("invalid cgroup name {:?}", name)));
+        }
+        Ok(Cgroup {
+            id: self.id.clone(),
+            path: self.path.join(name),
+            driver: Driver::Fs,
+        })
+    }
+
+    /// Creates the cgroup if missing and applies the resources, for processes
+    /// moved into it afterwards. A cgroup created here is removed again when
+    /// the resources fail.
+    pub fn create(&self, resources: Option<&Resources>) -> Result<()> {
+        let created = !self.path.exists();
+        std::fs::create_dir_all(&self.path).map_err(Error::io(format!(
+            "unable to create cgroup {:?}",
+            self.path
+        )))?;
+        if let Some(resources) = resources {
+            if let Err(err) = self.apply(resources) {
+                if created {
+                    let _ = std::fs::remove_dir(&self.path);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Delegates the cgroup to the container: the controllers are made
+    /// available to it, so it can enable them for the sub-tree it manages, and
+    /// the cgroup belongs to the root of its user namespace. Its processes have
+    /// to move into child cgroups before it enables any controller.
+    /// No controller means every one available to the parent cgroup.
+    pub fn delegate(&self, controllers: &[String], linux: Option<&Linux>) -> Result<()> {
+        let controllers = match controllers.is_empty() {
+            true => self
+                .path
+                .parent()
+                .and_then(|parent| std::fs::read_to_string(parent.join("cgroup.controllers")).ok())
+                .map(|available| available.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+            false => controllers.to_vec(),
+        };
+        for controller in &controllers {
+            enable_controller(&self.path, controller);
+        }
+        let available = std::fs::read_to_string(self.path.join("cgroup.controllers")).map_err(
+            Error::io(format!("unable to read the controllers of {:?}", self.path)),
+        )?;
+        let missing: Vec<&str> = controllers
+            .iter()
+            .map(String::as_str)
+            .filter(|controller| !available.split_whitespace().any(|c| c == *controller))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::Runtime(format!(
+                "unable to delegate the {} controller(s), not available in {:?}",
+                missing.join(", "),
+                self.path
+            )));
+        }
+
+        // Without a user namespace, the root of the container owns it already
+        let owner = linux.and_then(|linux| {
+            let uid = mapped_root(linux.uid_mappings.as_deref())?;
+            let gid = mapped_root(linux.gid_mappings.as_deref())?;
+            Some((Uid::from_raw(uid), Gid::from_raw(gid)))
+        });
+        if let Some((uid, gid)) = owner {
+            debug!("delegating cgroup {:?} to {}:{}", self.path, uid, gid);
+            let paths = std::iter::once(self.path.clone())
+                .chain(DELEGATED_FILES.iter().map(|file| self.path.join(file)));
+            for path in paths {
+                chown(&path, Some(uid), Some(gid))
+                    .map_err(Error::sys(format!("unable to delegate {:?}", path)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allows the device in the devices controller of a v1 hierarchy where the
+    /// container has a cgroup. False without one: pura sets no device rules on
+    /// the unified hierarchy, every device is allowed there.
+    pub fn allow_device(&self, device: &DeviceResource) -> Result<bool> {
+        let relative = self.path.strip_prefix(CGROUP_ROOT).unwrap_or(&self.path);
+        let allow = Path::new(CGROUP_ROOT)
+            .join("devices")
+            .join(relative)
+            .join("devices.allow");
+        if !allow.exists() {
+            return Ok(false);
+        }
+        let rule = device_rule(device);
+        debug!("allowing device {} in {:?}", rule, allow);
+        std::fs::write(&allow, &rule).map_err(Error::io(format!(
+            "unable to write {} to {:?}",
+            rule, allow
+        )))?;
+        Ok(true)
+    }
+
+    /// Kills every process of the cgroup at once through cgroup.kill, those
+    /// forked meanwhile included. False on kernels without it (before 5.14).
+    pub fn kill(&self) -> Result<bool> {
+        if !self.path.join("cgroup.kill").exists() {
+            return Ok(false);
+        }
+        self.write("cgroup.kill", "1")?;
+        Ok(true)
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        std::fs::write(self.path.join(file), value).map_err(Error::io(format!(
+            "unable to write {} to {:?}",
+            value,
+            self.path.join(file)
+        )))
+    }
+}
+
+/// Sets up the cgroup of the container process. When the resources don't
+/// need it, a missing cgroup v2 or systemd user session only warns.
+pub fn setup(id: &str, linux: Option<&Linux>, pid: Pid) -> Result<Cgroup> {
+    let mut cgroup = Cgroup::new(id, linux);
+    let resources = linux.and_then(|linux| linux.resources.as_ref());
+
+    match cgroup.setup(pid, resources) {
+        Err(err) if resources.map(resource_files).unwrap_or_default().is_empty() => {
+            warn!("container {} runs without its own cgroup: {}", id, err);
+            Ok(cgroup)
+        }
+        result => result.map(|_| cgroup),
+    }
+}
+
+/// The cgroup of the process, relative to the cgroup root
+pub fn cgroup_of(pid: Pid) -> Option<String> {
+    let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.to_string())
+}
+
+/// Processes of the cgroup
+pub fn procs(cgroup: &Path) -> Vec<Pid> {
+    std::fs::read_to_string(cgroup.join("cgroup.procs"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|pid| pid.parse().ok())
+        .map(Pid::from_raw)
+        .collect()
+}
+
+/// Controllers the annotations delegate to the container, if any, none for
+/// every available one
+pub fn delegated_controllers(
+    annotations: Option<&HashMap<String, String>>,
+) -> Result<Option<Vec<String>>> {
+    let value = match annotations.and_then(|annotations| annotations.get(DELEGATE_ANNOTATION)) {
+        Some(value) => value.trim(),
+        None => return Ok(None),
+    };
+    match value {
+        "false" | "" => Ok(None),
+        // Every controller available, see Cgroup::delegate
+        "true" => Ok(Some(Vec::new())),
+        _ => {
+            let controllers: Vec<String> = value.split(',').map(|c| c.trim().to_string()).collect();
+            match controllers
+                .iter()
+                .find(|c| !CONTROLLERS.contains(&c.as_str()))
+            {
+                Some(unknown) => Err(Error::Bundle(format!(
+                    "unknown controller {:?} in {}",
+                    unknown, DELEGATE_ANNOTATION
+                ))),
+                None => Ok(Some(controllers)),
+            }
+        }
+    }
+}
+
+/// Rule of the devices controller, like `c 1:3 rwm`
+fn device_rule(device: &DeviceResource) -> String {
+    let number = |number: Option<i64>| number.map_or(String::from("*"), |n| n.to_string());
+    format!(
+        "{} {}:{} {}",
+        device.device_type.as_deref().unwrap_or("a"),
+        number(device.major),
+        number(device.minor),
+        device.access.as_deref().unwrap_or("rwm")
+    )
+}
+
+/// Host id mapped to the root of the user namespace
+fn mapped_root(mappings: Option<&[UidMapping]>) -> Option<u32> {
+    mappings?
+        .iter()
+        .find(|mapping| mapping.container_id == 0 && mapping.size > 0)
+        .map(|mapping| mapping.host_id as u32)
+}
+
+/// Pressure of the resource in the cgroup directory, None without PSI
+pub fn pressure(cgroup: &Path, resource: &str) -> Option<Pressure> {
+    let content = std::fs::read_to_string(cgroup.join(format!("{}.pressure", resource))).ok()?;
+    parse_pressure(&content)
+}
+
+/// Parses the content of a `*.pressure` file, like
+/// `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`
+pub fn parse_pressure(content: &str) -> Option<Pressure> {
+    let mut some = None;
+    let mut full = None;
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next()?;
+        let mut parsed = PressureLine::default();
+        for field in fields {
+            match field.split_once('=')? {
+                ("avg10", value) => parsed.avg10 = value.parse().ok()?,
+                ("avg60", value) => parsed.avg60 = value.parse().ok()?,
+                ("avg300", value) => parsed.avg300 = value.parse().ok()?,
+                ("total", value) => parsed.total = value.parse().ok()?,
+                _ => (),
+            }
+        }
+        match kind {
+            "some" => some = Some(parsed),
+            "full" => full = Some(parsed),
+            _ => (),
+        }
+    }
+    Some(Pressure { some: some?, full })
+}
+
+fn is_unified() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+fn scope_name(id: &str) -> String {
+    format!("pura-{}.scope", id)
+}
+
+fn controller(file: &str) -> &str {
+    file.split('.').next().unwrap_or(file)
+}
+
+/// Enables the controller in the subtree_control of every ancestor of the cgroup
+fn enable_controller(path: &Path, controller: &str) {
+    let ancestors: Vec<&Path> = path
+        .ancestors()
+        .skip(1)
+        .take_while(|ancestor| ancestor.starts_with(CGROUP_ROOT))
+        .collect();
+    for ancestor in ancestors.iter().rev() {
+        // Already enabled controllers are fine, missing ones fail on the resource write
+        let _ = std::fs::write(
+            ancestor.join("cgroup.subtree_control"),
+            format!("+{}", controller),
+        );
+    }
+}
+
+fn limit(value: i64) -> String {
+    if value < 0 {
+        String::from("max")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Files and values of the cgroup v2 interface for the OCI resources
+pub fn resource_files(resources: &Resources) -> Vec<(&'static str, String)> {
+    let mut files = Vec::new();
+
+    if let Some(memory) = &resources.memory {
+        if let Some(limit_bytes) = memory.limit {
+            files.push(("memory.max", limit(limit_bytes)));
+        }
+        if let Some(reservation) = memory.reservation {
+            files.push(("memory.low", limit(reservation)));
+        }
+        // OCI swap is the memory+swap limit, v2 limits the swap alone
+        match (memory.swap, memory.limit) {
+            (Some(swap), _) if swap < 0 => files.push(("memory.swap.max", limit(swap))),
+            (Some(swap), Some(limit_bytes)) if limit_bytes >= 0 && swap >= limit_bytes => {
+                files.push(("memory.swap.max", (swap - limit_bytes).to_string()))
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(pids) = &resources.pids {
+        if let Some(pids_limit) = pids.limit {
+            let pids_limit = if pids_limit <= 0 { -1 } else { pids_limit };
+            files.push(("pids.max", limit(pids_limit)));
+        }
+    }
+
+    if let Some(cpu) = &resources.cpu {
+        if let Some(shares) = cpu.shares {
+            // Same conversion of the 2-262144 shares to the 1-10000 weight as runc
+            let weight = 1 + ((shares.clamp(2, 262144) - 2) * 9999) / 262142;
+            files.push(("cpu.weight", weight.to_string()));
+        }
+        if cpu.quota.is_some() || cpu.period.is_some() {
+            let quota = cpu.quota.map(limit).unwrap_or_else(|| String::from("max"));
+            files.push((
+                "cpu.max",
+                format!("{} {}", quota, cpu.period.unwrap_or(100000)),
+            ));
+        }
+        // After cpu.max, the burst can't exceed the quota
+        if let Some(burst) = cpu.burst {
+            files.push(("cpu.max.burst", burst.to_string()));
+        }
+        if let Some(idle) = cpu.idle {
+            files.push(("cpu.idle", idle.to_string()));
+        }
+        if let Some(cpus) = &cpu.cpus {
+            files.push(("cpuset.cpus", cpus.clone()));
+        }
+        if let Some(mems) = &cpu.mems {
+            files.push(("cpuset.mems", mems.clone()));
+        }
+    }
+
+    if let Some(block_io) = &resources.block_io {
+        if let Some(weight) = block_io.weight {
+            // blkio weight is 10-1000, io weight 1-10000
+            let weight = 1 + ((weight.clamp(10, 1000) - 10) * 9999) / 990;
+            files.push(("io.weight", format!("default {}", weight)));
+        }
+    }
+
+    files
+}
+
+/// Bytes of a size like `512m`, `1.5g` or `1024` (k, m, g and t are powers
+/// of 1024), -1 for `max` or `-1`
+pub fn parse_bytes(size: &str) -> Result<i64> {
+    let invalid = || Error::Runtime(format!("invalid size {:?}, expected like 512m", size));
+    let lower = size.trim().to_lowercase();
+    if lower == "max" || lower == "-1" {
+        return Ok(-1);
+    }
+    let number = lower.strip_suffix('b').unwrap_or(&lower);
+    let (number, unit) = match number.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&number[..index], unit),
+        _ => (number, ' '),
+    };
+    let shift = match unit {
+        ' ' => 0,
+        'k' => 10,
+        'm' => 20,
+        'g' => 30,
+        't' => 40,
+        _ => return Err(invalid()),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    if number < 0.0 || !number.is_finite() {
+        return Err(invalid());
+    }
+    Ok((number * (1u64 << shift) as f64) as i64)
+}
+
+/// Quota and period of cpu.max for a number of CPUs like `1.5`
+pub fn parse_cpus(cpus: &str) -> Result<(i64, i64)> {
+    const PERIOD: i64 = 100000;
+    match cpus.trim().parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => {
+            Ok(((cpus * PERIOD as f64).round() as i64, PERIOD))
+        }
+        _ => Err(Error::Runtime(format!(
+            "invalid cpus {:?}, expected a positive number like 1.5",
+            cpus
+        ))),
+    }
+}
+
+/// Merges the set resources of the update into the resources
+pub fn merge(resources: &mut Resources, update: &Resources) {
+    fn set<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+        if value.is_some() {
+            *field = value.clone();
+        }
+    }
+
+    if let Some(memory) = &update.memory {
+        let merged = resources.memory.get_or_insert_with(Default::default);
+        set(&mut merged.limit, &memory.limit);
+        set(&mut merged.reservation, &memory.reservation);
+        set(&mut merged.swap, &memory.swap);
+    }
+    if let Some(cpu) = &update.cpu {
+        let merged = resources.cpu.get_or_insert_with(Default::default);
+        set(&mut merged.shares, &cpu.shares);
+        set(&mut merged.quota, &cpu.quota);
+        set(&mut merged.period, &cpu.period);
+        set(&mut merged.cpus, &cpu.cpus);
+        set(&mut merged.mems, &cpu.mems);
+        set(&mut merged.burst, &cpu.burst);
+        set(&mut merged.idle, &cpu.idle);
+    }
+    if let Some(pids) = &update.pids {
+        let merged = resources.pids.get_or_insert_with(Default::default);
+        set(&mut merged.limit, &pids.limit);
+    }
+    if let Some(block_io) = &update.block_io {
+        let merged = resources.block_io.get_or_insert_with(Default::default);
+        set(&mut merged.weight, &block_io.weight);
+    }
+}
+
+fn start_scope_args(id: &str, pid: Pid) -> Vec<String> {
+    [
+        "StartTransientUnit",
+        "ssa(sv)a(sa(sv))",
+        &scope_name(id),
+        "fail",
+        "3",
+        "PIDs",
+        "au",
+        "1",
+        &pid.to_string(),
+        "Delegate",
+        "b",
+        "true",
+        "Slice",
+        "s",
+        "user.slice",
+        "0",
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+fn stop_scope_args(id: &str) -> Vec<String> {
+    vec![
+        String::from("StopUnit"),
+        String::from("ss"),
+        scope_name(id),
+        String::from("replace"),
+    ]
+}
+
+/// Calls a method of the systemd manager of the user session
+fn busctl(args: &[String]) -> Result<()> {
+    let output = Command::new("busctl")
+        .args([
+            "--user",
+            "call",
+            "org.freedesktop.systemd1",
+            "/org/freedesktop/systemd1",
+            "org.freedesktop.systemd1.Manager",
+        ])
+        .args(args)
+        .output()
+        .map_err(Error::io("unable to run busctl"))?;
+    if !output.status.success() {
+        return Err(Error::Runtime(format!(
+            "systemd {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Waits for systemd to move the process into the scope
+fn wait_scope(id: &str, pid: Pid) -> Result<PathBuf> {
+    let scope = scope_name(id);
+    for _ in 0..100 {
+        if let Some(cgroup) = cgroup_of(pid) {
+            if cgroup.ends_with(&scope) {
+                return Ok(Path::new(CGROUP_ROOT).join(cgroup.trim_start_matches('/')));
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err(Error::Runtime(format!(
+        "process {} wasn't moved into {}",
+        pid, scope
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::Pid;
+    use std::{collections::HashMap, os::unix::fs::MetadataExt};
+
+    use super::{
+        delegated_controllers, device_rule, merge, parse_bytes, parse_cpus, parse_pressure,
+        resource_files, start_scope_args, Cgroup, Driver, PressureLine, DELEGATED_FILES,
+        DELEGATE_ANNOTATION,
+    };
+    use crate::oci::spec::{
+        BlockIo, Cpu, DeviceResource, Linux, Memory, Pids, Resources, UidMapping,
+    };
+
+    #[test]
+    fn v2_files() {
+        let resources = Resources {
+            memory: Some(Memory {
+                limit: Some(512 << 20),
+                swap: Some(768 << 20),
+                ..Default::default()
+            }),
+            pids: Some(Pids {
+                limit: Some(0),
+                ..Default::default()
+            }),
+            cpu: Some(Cpu {
+                shares: Some(1024),
+                quota: Some(50000),
+                cpus: Some(String::from("0-1")),
+                burst: Some(10000),
+                idle: Some(1),
+                ..Default::default()
+            }),
+            block_io: Some(BlockIo {
+                weight: Some(500),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let files = resource_files(&resources);
+        let value = |file: &str| {
+            files
+                .iter()
+                .find(|(name, _)| *name == file)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(value("memory.max"), Some("536870912"));
+        assert_eq!(value("memory.swap.max"), Some("268435456"));
+        assert_eq!(value("pids.max"), Some("max"));
+        assert_eq!(value("cpu.weight"), Some("39"));
+        assert_eq!(value("cpu.max"), Some("50000 100000"));
+        assert_eq!(value("cpu.max.burst"), Some("10000"));
+        assert_eq!(value("cpu.idle"), Some("1"));
+        assert_eq!(value("cpuset.cpus"), Some("0-1"));
+        assert_eq!(value("io.weight"), Some("default 4950"));
+
+        assert!(resource_files(&Resources::default()).is_empty());
+    }
+
+    #[test]
+    fn human_resources() {
+        assert_eq!(parse_bytes("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_bytes("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_bytes("64kb").unwrap(), 64 << 10);
+        assert_eq!(parse_bytes("1024").unwrap(), 1024);
+        assert_eq!(parse_bytes("max").unwrap(), -1);
+        assert!(parse_bytes("12x").is_err());
+        assert!(parse_bytes("m").is_err());
+        assert_eq!(parse_cpus("1.5").unwrap(), (150000, 100000));
+        assert!(parse_cpus("0").is_err());
+
+        let mut resources = Resources {
+            memory: Some(Memory {
+                limit: Some(1 << 30),
+                swap: Some(2 << 30),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        merge(
+            &mut resources,
+            &Resources {
+                memory: Some(Memory {
+                    limit: Some(512 << 20),
+                    ..Default::default()
+                }),
+                pids: Some(Pids {
+                    limit: Some(200),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let memory = resources.memory.unwrap();
+        assert_eq!(memory.limit, Some(512 << 20));
+        assert_eq!(memory.swap, Some(2 << 30));
+        assert_eq!(resources.pids.unwrap().limit, Some(200));
+    }
+
+    #[test]
+    fn device_rules() {
+        let rule = DeviceResource {
+            allow: Some(true),
+            access: Some(String::from("rw")),
+            device_type: Some(String::from("c")),
+            major: Some(189),
+            minor: None,
+            ..Default::default()
+        };
+        assert_eq!(device_rule(&rule), "c 189:* rw");
+        assert_eq!(device_rule(&DeviceResource::default()), "a *:* rwm");
+    }
+
+    #[test]
+    fn scope_args() {
+        let args = start_scope_args("abc", Pid::from_raw(42));
+        assert_eq!(args[2], "pura-abc.scope");
+        assert_eq!(args[5..9], ["PIDs", "au", "1", "42"]);
+        assert_eq!(args.last().unwrap(), "0");
+    }
+
+    #[test]
+    fn pressure_files() {
+        let pressure = parse_pressure(concat!(
+            "some avg10=1.50 avg60=0.25 avg300=0.00 total=123456\n",
+            "full avg10=0.50 avg60=0.00 avg300=0.00 total=42\n"
+        ))
+        .unwrap();
+        assert_eq!(
+            pressure.some,
+            PressureLine {
+                avg10: 1.5,
+                avg60: 0.25,
+                avg300: 0.0,
+                total: 123456,
+            }
+        );
+        assert_eq!(pressure.full.unwrap().total, 42);
+
+        let some_only = parse_pressure("some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n");
+        assert_eq!(some_only.unwrap().full, None);
+        assert_eq!(parse_pressure(""), None);
+        assert_eq!(parse_pressure("some avg10=x\n"), None);
+    }
+
+    #[test]
+    fn kill_cgroup() {
+        let path = std::path::Path::new("./cgroup_kill_test");
+        std::fs::create_dir_all(path).unwrap();
+        let cgroup = Cgroup {
+            id: String::from("abc"),
+            path: path.to_path_buf(),
+            driver: Driver::Fs,
+        };
+        assert!(!cgroup.kill().unwrap());
+
+        std::fs::write(path.join("cgroup.kill"), "").unwrap();
+        assert!(cgroup.kill().unwrap());
+        assert_eq!(
+            std::fs::read_to_string(path.join("cgroup.kill")).unwrap(),
+            "1"
+        );
+
+        let child = cgroup.child("debug").unwrap();
+        assert_eq!(child.path(), path.join("debug"));
+        child.create(None).unwrap();
+        assert!(path.join("debug").is_dir());
+        for name in ["", "..", "a/b"] {
+            assert!(cgroup.child(name).is_err());
+        }
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn delegation() {
+        let annotations = |value: &str| {
+            let mut annotations = HashMap::new();
+            annotations.insert(DELEGATE_ANNOTATION.to_string(), value.to_string());
+            annotations
+        };
+        assert_eq!(delegated_controllers(None).unwrap(), None);
+        assert_eq!(
+            delegated_controllers(Some(&annotations("false"))).unwrap(),
+            None
+        );
+        assert_eq!(
+            delegated_controllers(Some(&annotations("true"))).unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(
+            delegated_controllers(Some(&annotations("cpu, memory"))).unwrap(),
+            Some(vec![String::from("cpu"), String::from("memory")])
+        );
+        assert!(delegated_controllers(Some(&annotations("cpu,blkio"))).is_err());
+
+        let parent = std::path::Path::new("./cgroup_delegate_test");
+        let path = parent.join("abc");
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(parent.join("cgroup.controllers"), "cpu memory pids\n").unwrap();
+        std::fs::write(path.join("cgroup.controllers"), "cpu memory\n").unwrap();
+        for file in DELEGATED_FILES.iter() {
+            std::fs::write(path.join(file), "").unwrap();
+        }
+        let cgroup = Cgroup {
+            id: String::from("abc"),
+            path: path.clone(),
+            driver: Driver::Fs,
+        };
+        let mapping = vec![UidMapping {
+            container_id: 0,
+            host_id: 1000,
+            size: 65536,
+            ..Default::default()
+        }];
+        let linux = Linux {
+            uid_mappings: Some(mapping.clone()),
+            gid_mappings: Some(mapping),
+            ..Default::default()
+        };
+
+        // pids is available to the parent only
+        assert!(cgroup.delegate(&[], Some(&linux)).is_err());
+        cgroup
+            .delegate(&[String::from("cpu")], Some(&linux))
+            .unwrap();
+        for file in
+            std::iter::once(String::new()).chain(DELEGATED_FILES.iter().map(|f| f.to_string()))
+        {
+            let metadata = std::fs::metadata(path.join(file)).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+        }
+
+        std::fs::remove_dir_all(parent).unwrap();
+    }
+}
@@ -1,43 +1,341 @@
-use std::{fmt::Display, io::{Write, stdout}};
+use std::{
+    fmt::Display,
+    io::{stderr, stdout, Write},
+    str::FromStr,
+    sync::OnceLock,
+};
 
-#[derive(Debug, Clone)]
-pub enum ErrorType {
-    Runtime,
-    Container,
+use nix::errno::Errno;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::core::{audit, otlp, terminal};
+
+/// Process exit codes, one per error category
+pub mod exit_code {
+    /// Runtime failure or bug
+    pub const RUNTIME: i32 = 1;
+    /// The bundle or its config.json is invalid
+    pub const INVALID_BUNDLE: i32 = 2;
+    /// The kernel doesn't support a requested feature
+    pub const KERNEL_FEATURE: i32 = 3;
+    /// The container process failed during setup
+    pub const CONTAINER: i32 = 4;
+    /// The container doesn't exist or the operation isn't allowed in its state
+    pub const INVALID_STATE: i32 = 5;
+    /// `state --quiet`: the container is created but not started yet
+    pub const CREATED: i32 = 10;
+    /// `state --quiet`: the container process has exited
+    pub const STOPPED: i32 = 11;
+    /// `state --quiet`: the container doesn't exist
+    pub const NOT_FOUND: i32 = 12;
+    /// `run`, `wait` and `exec`, which exit with the exit code of the process:
+    /// the runtime failed. Like with docker run, the failures get codes from
+    /// 125 up instead of the ones of their category, which the process could
+    /// exit with too.
+    pub const RUN_RUNTIME: i32 = 125;
+    /// `run`: the container process failed during setup
+    pub const RUN_CONTAINER: i32 = 126;
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The bundle or the spec doesn't describe a valid container
+    #[error("invalid bundle: {0}")]
+    Bundle(String),
+    /// config.json isn't a valid OCI spec
+    #[error("invalid spec: {0}")]
+    Spec(#[source] serde_json::Error),
+    /// A syscall failed during the given stage
+    #[error("{stage}: {source}")]
+    Sys {
+        stage: String,
+        #[source]
+        source: nix::Error,
+    },
+    /// An I/O operation failed
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Serialization of the runtime files failed
+    #[error("{context}: {source}")]
+    Json {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// The container process reported an error while setting up
+    #[error("container setup failed: {0}")]
+    Container(String),
+    /// The operation isn't allowed in the current state of the container
+    #[error("{0}")]
+    State(String),
+    /// No container of the id in the root
+    #[error("container {0} does not exist")]
+    NotFound(String),
+    /// Any other runtime failure
+    #[error("{0}")]
+    Runtime(String),
 }
 
-impl std::fmt::Display for ErrorType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Error {
+    /// Maps a failed syscall to an error tagged with the setup stage
+    pub fn sys<S: Into<String>>(stage: S) -> impl FnOnce(nix::Error) -> Error {
+        let stage = stage.into();
+        move |source| Error::Sys { stage, source }
+    }
+
+    /// Maps a failed I/O operation to an error with context
+    pub fn io<S: Into<String>>(context: S) -> impl FnOnce(std::io::Error) -> Error {
+        let context = context.into();
+        move |source| Error::Io { context, source }
+    }
+
+    /// Maps a failed (de)serialization to an error with context
+    pub fn json<S: Into<String>>(context: S) -> impl FnOnce(serde_json::Error) -> Error {
+        let context = context.into();
+        move |source| Error::Json { context, source }
+    }
+
+    /// Process exit code of the error category
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Bundle(_) | Error::Spec(_) => exit_code::INVALID_BUNDLE,
+            Error::Sys { source, .. } if is_unsupported(source) => exit_code::KERNEL_FEATURE,
+            Error::Container(_) => exit_code::CONTAINER,
+            Error::State(_) | Error::NotFound(_) => exit_code::INVALID_STATE,
+            _ => exit_code::RUNTIME,
+        }
+    }
+
+    /// Process exit code of the error of `run`, `wait` and `exec`, out of the
+    /// range of the categories
+    pub fn run_exit_code(&self) -> i32 {
         match self {
-            &ErrorType::Container => write!(f, "container"),
-            &ErrorType::Runtime => write!(f, "runtime"),
+            Error::Container(_) => exit_code::RUN_CONTAINER,
+            _ => exit_code::RUN_RUNTIME,
         }
     }
+
+    /// Stage of the failure: the one of a failed syscall, the setup step the
+    /// container process reported (`error:STEP:...`), or the error category
+    pub fn stage(&self) -> &str {
+        match self {
+            Error::Bundle(_) => "bundle",
+            Error::Spec(_) => "spec",
+            Error::Sys { stage, .. } => stage,
+            Error::Io { .. } => "io",
+            Error::Json { .. } => "json",
+            Error::Container(msg) => msg
+                .strip_prefix("error:")
+                .and_then(|msg| msg.split(':').next())
+                .filter(|step| !step.is_empty())
+                .unwrap_or("container"),
+            Error::State(_) => "state",
+            Error::NotFound(_) => "not_found",
+            Error::Runtime(_) => "runtime",
+        }
+    }
+
+    /// errno of a failed syscall or I/O operation
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Sys { source, .. } => source.as_errno().map(|errno| errno as i32),
+            Error::Io { source, .. } => source.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Single-line JSON report of the error, see `ErrorFormat::Json`
+    pub fn to_json(&self) -> String {
+        report(self.exit_code(), self.stage(), &self.to_string(), self.errno())
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Error {
-    pub msg: String,
-    pub err_type: ErrorType,
+/// How the CLI reports the error it fails on
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorFormat {
+    /// Logged like any other record
+    #[default]
+    Text,
+    /// One JSON object on stderr: `{"code":2,"stage":"bundle","message":"...","errno":null}`
+    Json,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "ERROR: type {} - {}", self.err_type, self.msg)
+impl FromStr for ErrorFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(Error::Runtime(format!("unknown error format {}", format))),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+/// JSON report of a failure with the exit code, see `ErrorFormat::Json`
+pub fn report(code: i32, stage: &str, message: &str, errno: Option<i32>) -> String {
+    json!({
+        "code": code,
+        "stage": stage,
+        "message": message,
+        "errno": errno,
+    })
+    .to_string()
+}
+
+static ERROR_FORMAT: OnceLock<ErrorFormat> = OnceLock::new();
+
+/// Sets how `fail` and `exit_msg` report errors, once at startup
+pub fn set_error_format(format: ErrorFormat) {
+    let _ = ERROR_FORMAT.set(format);
+}
+
+fn error_format() -> ErrorFormat {
+    ERROR_FORMAT.get().copied().unwrap_or_default()
+}
+
+fn is_unsupported(err: &nix::Error) -> bool {
+    matches!(
+        err.as_errno(),
+        Some(Errno::ENOSYS) | Some(Errno::EOPNOTSUPP) | Some(Errno::EPROTONOSUPPORT)
+    )
+}
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 // helper methods
 
+/// Error of an operation needing a cargo feature pura is built without
+pub fn disabled(feature: &str) -> Error {
+    Error::Runtime(format!("pura is built without the {} feature", feature))
+}
+
 pub fn exit(code: i32) -> ! {
+    terminal::restore();
+    audit::finish(code);
+    otlp::export();
+    std::process::exit(code);
+}
+
+/// Exits with the exit code of the container process once the runtime
+/// succeeded, audited as the exit status of the process rather than as the
+/// result of the runtime
+pub fn exit_process(code: i32) -> ! {
+    terminal::restore();
+    audit::finish_process(code);
+    otlp::export();
     std::process::exit(code);
 }
 
 pub fn exit_msg<T: Display>(code: i32, msg: T) -> ! {
-    let _ = stdout().write_all(msg.to_string().as_bytes());
+    terminal::restore();
+    match error_format() {
+        ErrorFormat::Text => {
+            let _ = stdout().write_all(msg.to_string().as_bytes());
+        }
+        ErrorFormat::Json => {
+            let _ = writeln!(stderr(), "{}", report(code, "cli", &msg.to_string(), None));
+        }
+    }
+    audit::finish(code);
+    otlp::export();
     std::process::exit(code);
-}
\ No newline at end of file
+}
+
+/// Reports the error in the configured format, see `set_error_format`
+pub fn report_error(err: &Error) {
+    match error_format() {
+        ErrorFormat::Text => log::error!("{}", err),
+        ErrorFormat::Json => {
+            let _ = writeln!(stderr(), "{}", err.to_json());
+        }
+    }
+}
+
+/// Reports the error the command failed on and exits with the code of its
+/// category
+pub fn fail(err: &Error) -> ! {
+    report_error(err);
+    exit(err.exit_code());
+}
+
+/// Like `fail`, for the commands exiting with the exit code of the process.
+/// The audit record keeps the code of the category.
+pub fn fail_run(err: &Error) -> ! {
+    report_error(err);
+    terminal::restore();
+    audit::finish(err.exit_code());
+    otlp::export();
+    std::process::exit(err.run_exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::errno::Errno;
+
+    use super::{exit_code, Error};
+    use serde_json::Value;
+
+    #[test]
+    fn exit_codes() {
+        let spec_err = serde_json::from_str::<u32>("{").unwrap_err();
+        assert_eq!(Error::Spec(spec_err).exit_code(), exit_code::INVALID_BUNDLE);
+        assert_eq!(
+            Error::sys("clone")(nix::Error::Sys(Errno::ENOSYS)).exit_code(),
+            exit_code::KERNEL_FEATURE
+        );
+        assert_eq!(
+            Error::sys("mount")(nix::Error::Sys(Errno::EPERM)).exit_code(),
+            exit_code::RUNTIME
+        );
+        assert_eq!(
+            Error::Container("error:rootfs".to_string()).exit_code(),
+            exit_code::CONTAINER
+        );
+        assert_eq!(
+            Error::State("container isn't created".to_string()).exit_code(),
+            exit_code::INVALID_STATE
+        );
+        assert_eq!(
+            Error::NotFound("missing".to_string()).exit_code(),
+            exit_code::INVALID_STATE
+        );
+    }
+
+    #[test]
+    fn run_exit_codes() {
+        assert_eq!(
+            Error::Bundle("no process".to_string()).run_exit_code(),
+            exit_code::RUN_RUNTIME
+        );
+        assert_eq!(
+            Error::NotFound("missing".to_string()).run_exit_code(),
+            exit_code::RUN_RUNTIME
+        );
+        assert_eq!(
+            Error::Container("error:exec".to_string()).run_exit_code(),
+            exit_code::RUN_CONTAINER
+        );
+    }
+
+    #[test]
+    fn json_report() {
+        let err = Error::sys("pivot_root")(nix::Error::Sys(Errno::EINVAL));
+        let report: Value = serde_json::from_str(&err.to_json()).unwrap();
+        assert_eq!(report["code"], exit_code::RUNTIME);
+        assert_eq!(report["stage"], "pivot_root");
+        assert_eq!(report["errno"], Errno::EINVAL as i32);
+        assert_eq!(report["message"], err.to_string());
+        assert!(!err.to_json().contains('\n'));
+
+        let err = Error::Container("error:rootfs:no such directory".to_string());
+        assert_eq!(err.stage(), "rootfs");
+        assert_eq!(err.errno(), None);
+        assert_eq!(Error::State("missing".to_string()).stage(), "state");
+    }
+}
@@ -0,0 +1,178 @@
+use nix::unistd::geteuid;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::core::{
+    cgroups::{self, Driver},
+    common::{Error, Result},
+    hooks, plugins, seccomp,
+    state::STATE_BACKEND_ENV,
+};
+
+/// Host configuration file, read unless `--config` names another one
+pub const CONFIG_PATH: &str = "/etc/pura/config.toml";
+
+/// Defaults of the host for every invocation, so fleets don't need a wrapper
+/// around the binary. The flags of the command line take precedence.
+///
+/// ```toml
+/// root = "/run/pura"
+/// log_format = "json"
+/// cgroup_driver = "fs"
+///
+/// [hooks]
+/// allowed = ["/usr/libexec/oci/hooks.d/nvidia"]
+/// plugins = ["/usr/lib/pura/plugins/libnetinject.so"]
+///
+/// [features]
+/// state_backend = "sqlite"
+/// audit = false
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Runtime root, instead of the default one
+    pub root: Option<String>,
+    /// Log file
+    pub log: Option<String>,
+    /// `text`, `json` or `journald`
+    pub log_format: Option<String>,
+    pub log_level: Option<String>,
+    /// `fs` or `systemd`, by default `fs` as root and `systemd` rootless
+    pub cgroup_driver: Option<Driver>,
+    /// Seccomp profile of the containers whose spec has none
+    pub seccomp_profile: Option<String>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub features: Features,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Paths of the only hooks the bundles may run, any hook without it
+    pub allowed: Option<Vec<String>>,
+    /// Shared libraries called in-process for their phases, see `plugins::load`
+    #[serde(default)]
+    pub plugins: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Features {
+    /// Store of the states of a new root, `json` or `sqlite`, see `state::open`
+    pub state_backend: Option<String>,
+    /// Records the operations in the audit file of the root, on by default
+    pub audit: Option<bool>,
+}
+
+impl Config {
+    /// Reads the configuration file. Without one given, the host file is
+    /// optional and an empty configuration is used when it's missing.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (Path::new(path), true),
+            None => (Path::new(CONFIG_PATH), false),
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config::default())
+            }
+            Err(err) => return Err(Error::io(format!("unable to read {:?}", path))(err)),
+        };
+        Config::parse_file(path, &content)
+    }
+
+    #[cfg(feature = "config")]
+    fn parse_file(path: &Path, content: &str) -> Result<Config> {
+        Config::parse(content)
+            .map_err(|err| Error::Runtime(format!("invalid configuration {:?}: {}", path, err)))
+    }
+
+    /// Without the TOML parser, an existing file is refused rather than ignored
+    #[cfg(not(feature = "config"))]
+    fn parse_file(_path: &Path, _content: &str) -> Result<Config> {
+        Err(crate::core::common::disabled("config"))
+    }
+
+    #[cfg(feature = "config")]
+    pub fn parse(content: &str) -> std::result::Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    /// Sets the defaults of the runtime the flags don't cover. A state
+    /// backend set in the environment takes precedence over the file.
+    pub fn apply(&self) -> Result<()> {
+        if let Some(driver) = &self.cgroup_driver {
+            if *driver == Driver::SystemdUser && geteuid().is_root() {
+                return Err(Error::Runtime(
+                    "the systemd cgroup driver is only supported rootless".to_string(),
+                ));
+            }
+            cgroups::set_driver(driver.clone());
+        }
+        if let Some(allowed) = &self.hooks.allowed {
+            hooks::allow_only(allowed);
+        }
+        for plugin in &self.hooks.plugins {
+            plugins::load(plugin)?;
+        }
+        if let Some(backend) = &self.features.state_backend {
+            if std::env::var_os(STATE_BACKEND_ENV).is_none() {
+                std::env::set_var(STATE_BACKEND_ENV, backend);
+            }
+        }
+        if let Some(profile) = &self.seccomp_profile {
+            seccomp::set_default_profile(profile);
+        }
+        Ok(())
+    }
+
+    pub fn audit(&self) -> bool {
+        self.features.audit.unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    #[cfg(feature = "config")]
+    use crate::core::cgroups::Driver;
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn parse() {
+        let config = Config::parse(
+            r#"
+root = "/run/pura"
+log_format = "json"
+cgroup_driver = "systemd"
+
+[hooks]
+allowed = ["/usr/bin/hook"]
+
+[features]
+audit = false
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.root.as_deref(), Some("/run/pura"));
+        assert_eq!(config.log_format.as_deref(), Some("json"));
+        assert_eq!(config.cgroup_driver, Some(Driver::SystemdUser));
+        assert_eq!(config.hooks.allowed, Some(vec!["/usr/bin/hook".to_string()]));
+        assert_eq!(config.features.state_backend, None);
+        assert!(!config.audit());
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::default().audit());
+        assert!(Config::parse("roots = \"/run/pura\"").is_err());
+        assert!(Config::parse("cgroup_driver = \"cgroupfs\"").is_err());
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(Config::load(Some("./config_missing_test.toml")).is_err());
+    }
+}
@@ -1,27 +1,44 @@
-use std::{ffi::CString, os::unix::prelude::AsRawFd, path::Path};
+use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt, prelude::AsRawFd, io::RawFd},
+    path::Path,
+};
 
+use log::{debug, trace};
 use nix::{
     fcntl::{open, OFlag},
     sched::{setns, CloneFlags},
     sys::stat::Mode,
-    unistd::{chdir, execvp, setgid, sethostname, setuid, Gid, Pid, Uid},
+    errno::Errno,
+    libc,
+    unistd::{chdir, close, execvp, setgid, sethostname, setuid, Gid, Uid},
 };
 
 use crate::{
-    core::common::{exit, exit_msg, Result},
-    oci::spec::{Namespace, Spec},
+    core::common::{exit, exit_msg, Error, Result},
+    oci::spec::{self, Namespace, Spec},
 };
 
 use super::{
+    apparmor, env,
     filesystem::{
-        create_default_devices, create_devices, mount_devices, mount_rootfs, pivot_rootfs,
-        symlinks_defaults,
+        create_default_devices, create_devices, find_executable, mask_paths, mount_devices,
+        mount_rootfs, pivot_rootfs, symlinks_defaults,
     },
-    fork::clone_child,
+    fork::{clone_child, close_fds, close_fds_on_exec, set_dumpable, Process},
     hooks::exec_hook,
+    init,
     ipc::{IpcChannel, IpcChild},
+    numa,
+    otlp::{self, info_span},
+    overlay::Overlay,
+    plugins::{self, Phase},
+    seccomp::{self, Filter},
+    selinux::MountLabels,
     state::State,
-    terminal::{Pty, PtySocket},
+    terminal::{chown_stdio, Pty, PtySocket},
+    users::{self, set_groups},
+    wasm::{self, Module},
 };
 
 /// Fork a child container process and initializes the container.
@@ -35,24 +52,37 @@ use super::{
 /// * `init_lock_path` - String path to the initial lock Unix domain socket (used to inform the parent when the child finishes container preparation)
 /// * `sock_path` - Container's main Unix domain socket (used for the start command)
 /// * `pty_socket` - Optional: if the user specified a terminal
+/// * `init` - Runs the user-defined process under the init shim, see `init::run`
+/// * `overlay` - Optional: overlay mounted as the rootfs, see `overlay::Overlay`
+/// * `filter` - Optional: seccomp filter of `linux.seccomp`, compiled by the runtime so an invalid profile fails the create
 ///
 /// # Returns
 ///
-/// Pid of the running container process inside the root PID namespace
+/// The running container process inside the root PID namespace, with its pidfd
 /// 
+#[allow(clippy::too_many_arguments)]
 pub fn fork_container(
     spec: &Spec,
     state: &State,
-    namespaces: &Vec<Namespace>,
+    namespaces: &[Namespace],
     init_lock_path: &String,
     sock_path: &String,
     pty_socket: &Option<PtySocket>,
-) -> Result<Pid> {
-    let pid = clone_child(
+    init: bool,
+    overlay: Option<&Overlay>,
+    before_pivot: bool,
+    filter: Option<&Filter>,
+) -> Result<Process> {
+    let apparmor_profile = match &spec.process {
+        Some(process) => apparmor::profile(process)?,
+        None => None,
+    };
+    let own_pid_namespace = enter_pid_namespace(spec)?;
+    let process = clone_child(
         || {
-            let init_lock_child = IpcChild::new(&init_lock_path).unwrap();
+            let init_lock_child = IpcChild::new(init_lock_path).unwrap();
 
-            let mut ipc_channel = match IpcChannel::new(&sock_path) {
+            let mut ipc_channel = match IpcChannel::new(sock_path) {
                 Ok(ch) => ch,
                 Err(err) => {
                     init_lock_child
@@ -69,8 +99,17 @@ pub fn fork_container(
             // Bind to namespaces paths
             if let Some(linux) = &spec.linux {
                 if let Some(namespaces) = &linux.namespaces {
+                    let _span = info_span!("namespaces").entered();
+                    // The processes already in the joined namespaces can't reach the runtime
+                    if namespaces.iter().any(|ns| ns.path.is_some()) {
+                        if let Err(err) = set_dumpable(false) {
+                            ipc_channel.send(&format!("error:ns:{}", err)).unwrap();
+                            exit_msg(1, format!("error:ns:{}", err));
+                        }
+                    }
                     for ns in namespaces {
                         if let Some(path) = &ns.path {
+                            debug!("joining {} namespace {}", ns.namespace, path);
                             let fd = match open(path.as_str(), OFlag::empty(), Mode::empty()) {
                                 Ok(fd) => fd,
                                 Err(err) => {
@@ -90,15 +129,18 @@ pub fn fork_container(
 
             // Accept the create process
             ipc_channel.accept().unwrap();
+            trace!("create process connected, preparing rootfs");
 
             let rootfs = Path::new(&spec.root.path);
 
-            let _: Option<Pty> = if let Some(pty_sock) = &pty_socket {
+            // The slave of the pty becomes /dev/console
+            let console: Option<String> = if let Some(pty_sock) = &pty_socket {
                 match Pty::new() {
                     Ok(pty) => {
+                        trace!("sending pty master to console socket");
                         pty.connect().unwrap();
                         pty_sock.send_pty(&pty).unwrap();
-                        Some(pty)
+                        Some(pty.slave_name().to_string())
                     }
                     Err(err) => {
                         ipc_channel
@@ -112,36 +154,53 @@ pub fn fork_container(
             };
 
             // Mounts the rootfs folder with bind option
-            if let Err(err) = mount_rootfs(&rootfs) {
+            if let Err(err) = info_span!("rootfs").in_scope(|| mount_rootfs(rootfs, overlay)) {
                 ipc_channel.send(&format!("error:rootfs:{}", err)).unwrap();
                 exit_msg(1, format!("error:rootfs:{}", err));
             }
 
             if let Some(mounts) = &spec.mounts {
-                if let Err(err) = mount_devices(&mounts, rootfs) {
+                let mounted = info_span!("mounts").in_scope(|| {
+                    let labels = MountLabels::from_spec(spec)?;
+                    mount_devices(mounts, rootfs, labels.as_ref())
+                });
+                if let Err(err) = mounted {
                     ipc_channel.send(&format!("error:devices:{}", err)).unwrap();
                     exit_msg(1, format!("error:devices:{}", err));
                 }
             }
 
+            let devices_span = info_span!("devices").entered();
             if let Some(linux) = &spec.linux {
                 if let Some(devices) = &linux.devices {
-                    if let Err(err) = create_devices(&devices, rootfs) {
+                    if let Err(err) = create_devices(devices, rootfs) {
                         ipc_channel.send(&format!("error:devices:{}", err)).unwrap();
                         exit_msg(1, format!("error:devices:{}", err));
                     }
                 }
             }
-            // Create default devices and mounts
-            create_default_devices(&rootfs);
-
-            // Symlinks the file descriptors of the process
-            symlinks_defaults(&rootfs);
+            // Create default devices and symlinks, like the file descriptors of the process
+            trace!("creating default devices");
+            let defaults = create_default_devices(rootfs, console.as_deref())
+                .and_then(|_| symlinks_defaults(rootfs));
+            if let Err(err) = defaults {
+                ipc_channel.send(&format!("error:devices:{}", err)).unwrap();
+                exit_msg(1, format!("error:devices:{}", err));
+            }
+            drop(devices_span);
 
+            if let Err(err) = plugins::run(Phase::CreateContainer, state) {
+                ipc_channel
+                    .send(&format!("error:hook:createContainer:{}", err))
+                    .unwrap();
+                exit_msg(1, format!("error:hook:createContainer:{}", err));
+            }
             if let Some(hooks) = &spec.hooks {
                 if let Some(create) = &hooks.create_container {
+                    let _span = info_span!("hooks.createContainer").entered();
                     for create_hook in create {
-                        if let Err(err) = exec_hook(create_hook, &state) {
+                        debug!("running createContainer hook {}", create_hook.path);
+                        if let Err(err) = exec_hook(create_hook, state) {
                             ipc_channel
                                 .send(&format!("error:hook:createContainer:{}", err))
                                 .unwrap();
@@ -152,24 +211,41 @@ pub fn fork_container(
             }
 
             // Wait for the hook to finish and the parent to confirm
-            ipc_channel.send("before_pivot").unwrap();
-            if let Ok(msg) = ipc_channel.recv() {
-                if !msg.eq("ok") {
-                    exit_msg(1, format!("error:hook:createRuntime:{}", msg));
+            if before_pivot {
+                ipc_channel.send("before_pivot").unwrap();
+                if let Ok(msg) = ipc_channel.recv() {
+                    if !msg.eq("ok") {
+                        exit_msg(1, format!("error:hook:createRuntime:{}", msg));
+                    }
                 }
             }
 
-            if let Err(err) = pivot_rootfs(&rootfs) {
+            if let Err(err) = info_span!("pivot_root").in_scope(|| pivot_rootfs(rootfs)) {
                 ipc_channel
                     .send(&format!("error:pivot_root:{}", err))
                     .unwrap();
                 exit_msg(1, format!("error:pivot_root:{}", err));
             }
 
-            ipc_channel.send("after_pivot").unwrap();
+            if let Some(linux) = &spec.linux {
+                if let Err(err) = info_span!("mask_paths").in_scope(|| mask_paths(linux)) {
+                    ipc_channel.send(&format!("error:mask:{}", err)).unwrap();
+                    exit_msg(1, format!("error:mask:{}", err));
+                }
+            }
+
+            if let Err(err) = set_uts_names(spec) {
+                ipc_channel.send(&format!("error:uts:{}", err)).unwrap();
+                exit_msg(1, format!("error:uts:{}", err));
+            }
 
-            if let Some(hostname) = &spec.hostname {
-                sethostname(hostname).unwrap();
+            // Inherited by the process of the spec, like the rest of the setup
+            let policy = spec.linux.as_ref().and_then(|linux| linux.memory_policy.as_ref());
+            if let Some(policy) = policy {
+                if let Err(err) = numa::set_memory_policy(policy) {
+                    ipc_channel.send(&format!("error:mempolicy:{}", err)).unwrap();
+                    exit_msg(1, format!("error:mempolicy:{}", err));
+                }
             }
 
             // Here gets the process executed
@@ -186,21 +262,55 @@ pub fn fork_container(
                     .map(|a| CString::new(a.to_string()).unwrap_or_default())
                     .collect();
 
-                let exec = CString::new(cmd.as_bytes()).unwrap();
+                // The user names are those of the container, now the root
+                let user = match resolve_user(process) {
+                    Ok(user) => user,
+                    Err(err) => {
+                        ipc_channel.send(&format!("error:user:{}", err)).unwrap();
+                        exit_msg(1, format!("error:user:{}", err));
+                    }
+                };
 
-                if let Some(envs) = &process.env {
-                    for (key, _) in std::env::vars() {
-                        std::env::remove_var(key);
+                // Nothing of the runtime environment leaks into the container, its
+                // own NOTIFY_SOCKET is only reachable through the proxy
+                for (key, _) in std::env::vars_os() {
+                    std::env::remove_var(key);
+                }
+                for env in process.env.iter().flatten() {
+                    if let Some((key, value)) = env.split_once("=") {
+                        std::env::set_var(key, value);
                     }
+                }
+                let uid = user.as_ref().map(|user| user.uid as u32).unwrap_or(0);
+                env::set_defaults(uid, process.terminal.unwrap_or_default());
 
-                    for env in envs {
-                        if let Some((key, value)) = env.split_once("=") {
-                            std::env::set_var(key, value);
-                        }
+                // Resolved or compiled now, so create fails on a missing
+                // executable or an invalid module
+                let path = std::env::var("PATH").ok();
+                let resolved = match wasm::is_module(spec) {
+                    true => {
+                        Module::load(process).map(|module| (CString::default(), Some(module)))
+                    }
+                    false => find_executable(cmd, path.as_deref(), Path::new(&process.cwd))
+                        .map(|executable| {
+                            (CString::new(executable.as_os_str().as_bytes()).unwrap(), None)
+                        }),
+                };
+                let (exec, module) = match resolved {
+                    Ok(resolved) => resolved,
+                    Err(err) => {
+                        ipc_channel.send(&format!("error:exec:{}", err)).unwrap();
+                        exit_msg(1, format!("error:exec:{}", err));
                     }
+                };
+
+                // The runtime process exports the setup spans, this one execs
+                for span in otlp::take_finished() {
+                    ipc_channel.send(&format!("span:{}", span)).unwrap();
                 }
 
                 // Finish the create command
+                debug!("container ready, waiting for start");
                 ipc_channel.send("ready").unwrap();
 
                 // Wait for the start command to fire start
@@ -218,17 +328,66 @@ pub fn fork_container(
                     }
                 }
 
-                if let Some(user) = &process.user {
-                    setuid(Uid::from_raw(user.uid as u32)).unwrap();
-                    setgid(Gid::from_raw(user.gid as u32)).unwrap();
+                // Only the stdio is passed to the user process
+                if let Err(err) = close_fds_on_exec(3) {
+                    println!("[ERROR]: {}", err);
+                    exit(1);
+                }
+
+                let no_new_privileges = process.no_new_privileges.unwrap_or_default();
+                // A module runs without an exec to switch the profile on
+                let confined = match (&apparmor_profile, &module) {
+                    (Some(profile), Some(_)) => apparmor::change_profile(profile),
+                    (Some(profile), None) => apparmor::change_onexec(profile),
+                    (None, _) => Ok(()),
+                };
+                let confined =
+                    confined.and_then(|_| seccomp::load_first(filter, no_new_privileges));
+                if let Err(err) = confined {
+                    println!("[ERROR]: {}", err);
+                    exit(1);
+                }
+
+                if let Some(user) = &user {
+                    let (uid, gid) = (Uid::from_raw(user.uid as u32), Gid::from_raw(user.gid as u32));
+                    let terminal = pty_socket.is_some();
+                    let stdio = chown_stdio(uid, gid, terminal);
+                    if let Err(err) = stdio.and_then(|_| set_groups(user)) {
+                        println!("[ERROR]: {}", err);
+                        exit(1);
+                    }
+                    // The gid can't be changed anymore once the uid is
+                    setgid(gid).unwrap();
+                    setuid(uid).unwrap();
                 }
 
                 chdir(Path::new(&process.cwd)).unwrap();
+                if let Err(err) = seccomp::load_last(filter, no_new_privileges) {
+                    println!("[ERROR]: {}", err);
+                    exit(1);
+                }
+                // In place of the exec, which would close the fds and make the
+                // process dumpable. A module can't fork, it needs no init.
+                if let Some(module) = &module {
+                    let ran = close_fds(3)
+                        .and_then(|_| set_dumpable(true))
+                        .and_then(|_| module.run(process));
+                    match ran {
+                        Ok(code) => exit(code),
+                        Err(err) => {
+                            println!("[ERROR]: {}", err);
+                            exit(1);
+                        }
+                    }
+                }
+                if init {
+                    init::run(&exec, &args);
+                }
                 match execvp(&exec, &args) {
                     Ok(_) => (),
                     Err(err) => {
                         // We can't log this error because it doesn't see the log file
-                        println!("[ERROR]: {}", err.to_string());
+                        println!("[ERROR]: {}", err);
                         exit(1);
                     }
                 }
@@ -236,8 +395,81 @@ pub fn fork_container(
 
             0
         },
-        &namespaces,
+        namespaces,
     );
+    if let Some(own) = own_pid_namespace {
+        let restored = setns(own, CloneFlags::CLONE_NEWPID)
+            .map_err(Error::sys("unable to return to the pid namespace"));
+        let _ = close(own);
+        restored?;
+    }
+
+    process
+}
 
-    pid
+/// Has the next child created in the pid namespace of the spec with a path,
+/// as joining it from the child would only move the children of the child.
+/// Returns the pid namespace of the runtime to return to after the clone.
+fn enter_pid_namespace(spec: &Spec) -> Result<Option<RawFd>> {
+    let path = spec
+        .linux
+        .as_ref()
+        .and_then(|linux| linux.namespaces.as_ref())
+        .and_then(|namespaces| namespaces.iter().find(|ns| ns.namespace == "pid"))
+        .and_then(|ns| ns.path.as_ref());
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let own = open("/proc/self/ns/pid", OFlag::O_CLOEXEC, Mode::empty())
+        .map_err(Error::sys("unable to open the pid namespace"))?;
+    let entered = open(path.as_str(), OFlag::O_CLOEXEC, Mode::empty())
+        .and_then(|target| {
+            let joined = setns(target, CloneFlags::CLONE_NEWPID);
+            let _ = close(target);
+            joined
+        })
+        .map_err(Error::sys(format!("unable to join the pid namespace {}", path)));
+    if let Err(err) = entered {
+        let _ = close(own);
+        return Err(err);
+    }
+    Ok(Some(own))
+}
+
+/// Sets the hostname and the domainname of the spec in the new UTS namespace
+fn set_uts_names(spec: &Spec) -> nix::Result<()> {
+    if let Some(hostname) = &spec.hostname {
+        sethostname(hostname)?;
+    }
+    if let Some(domainname) = &spec.domainname {
+        let res = unsafe {
+            libc::setdomainname(domainname.as_ptr() as *const libc::c_char, domainname.len())
+        };
+        Errno::result(res)?;
+    }
+    Ok(())
+}
+
+/// User of the process, its username resolved with the supplementary groups
+/// of the spec kept
+fn resolve_user(process: &spec::Process) -> Result<Option<spec::User>> {
+    let user = match &process.user {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let username = match &user.username {
+        Some(username) => username,
+        None => return Ok(Some(user.clone())),
+    };
+    let mut resolved = users::lookup(Path::new("/"), username)?;
+    if let Some(additional_gids) = &user.additional_gids {
+        let gids = resolved.additional_gids.get_or_insert_with(Vec::new);
+        for gid in additional_gids {
+            if !gids.contains(gid) {
+                gids.push(*gid);
+            }
+        }
+    }
+    Ok(Some(resolved))
 }
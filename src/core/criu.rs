@@ -0,0 +1,350 @@
+use log::debug;
+use nix::unistd::Pid;
+use std::{path::Path, process::Command};
+
+use crate::core::common::{disabled, Error, Result};
+use crate::oci::ops::{Checkpoint, Restore};
+
+const CRIU_BINARY: &str = "criu";
+const RESTORE_LOG_FILE: &str = "restore.log";
+
+fn log_file(checkpoint: &Checkpoint) -> &'static str {
+    if checkpoint.pre_dump {
+        "pre-dump.log"
+    } else {
+        "dump.log"
+    }
+}
+
+/// Arguments of the criu dump (or pre-dump) of the process tree
+fn dump_args(pid: Pid, checkpoint: &Checkpoint) -> Result<Vec<String>> {
+    let image_path = Path::new(&checkpoint.image_path);
+    let mut args = vec![
+        String::from(if checkpoint.pre_dump {
+            "pre-dump"
+        } else {
+            "dump"
+        }),
+        String::from("--tree"),
+        pid.to_string(),
+        String::from("--images-dir"),
+        checkpoint.image_path.clone(),
+        String::from("--log-file"),
+        String::from(log_file(checkpoint)),
+        String::from("--ext-mount-map"),
+        String::from("auto"),
+    ];
+
+    if let Some(work_path) = &checkpoint.work_path {
+        args.push(String::from("--work-dir"));
+        args.push(work_path.clone());
+    }
+    // A pre-dump always leaves the process tree running
+    if checkpoint.leave_running && !checkpoint.pre_dump {
+        args.push(String::from("--leave-running"));
+    }
+    if checkpoint.tcp_established {
+        args.push(String::from("--tcp-established"));
+    }
+
+    // Memory changes are tracked between the pre-dumps and the final dump
+    if checkpoint.pre_dump || checkpoint.parent_path.is_some() {
+        args.push(String::from("--track-mem"));
+    }
+    if let Some(parent_path) = &checkpoint.parent_path {
+        // criu resolves the parent images relative to the images dir
+        if !image_path.join(parent_path).is_dir() {
+            return Err(Error::Runtime(format!(
+                "parent path {} isn't a directory relative to {}",
+                parent_path, checkpoint.image_path
+            )));
+        }
+        args.push(String::from("--prev-images-dir"));
+        args.push(parent_path.clone());
+    }
+
+    if checkpoint.lazy_pages {
+        if checkpoint.pre_dump {
+            return Err(Error::Runtime(
+                "lazy pages can't be used with a pre-dump".to_string(),
+            ));
+        }
+        args.push(String::from("--lazy-pages"));
+    }
+    if let Some(page_server) = &checkpoint.page_server {
+        let (address, port) = page_server.rsplit_once(':').ok_or_else(|| {
+            Error::Runtime(format!("page server {} isn't ADDRESS:PORT", page_server))
+        })?;
+        // With lazy pages criu serves the pages itself on the address,
+        // otherwise it sends them to the page server there
+        if !checkpoint.lazy_pages {
+            args.push(String::from("--page-server"));
+        }
+        args.extend([
+            String::from("--address"),
+            address.to_string(),
+            String::from("--port"),
+            port.to_string(),
+        ]);
+    }
+
+    Ok(args)
+}
+
+/// Dumps the process tree of the container into the image path
+pub fn dump(pid: Pid, checkpoint: &Checkpoint) -> Result<()> {
+    if !cfg!(feature = "criu") {
+        return Err(disabled("criu"));
+    }
+    std::fs::create_dir_all(&checkpoint.image_path).map_err(Error::io(format!(
+        "unable to create image path {}",
+        checkpoint.image_path
+    )))?;
+    if let Some(work_path) = &checkpoint.work_path {
+        std::fs::create_dir_all(work_path).map_err(Error::io(format!(
+            "unable to create work path {}",
+            work_path
+        )))?;
+    }
+
+    let args = dump_args(pid, checkpoint)?;
+    debug!("running {} {}", CRIU_BINARY, args.join(" "));
+
+    let status = Command::new(CRIU_BINARY)
+        .args(&args)
+        .status()
+        .map_err(Error::io(format!("unable to run {}", CRIU_BINARY)))?;
+    if !status.success() {
+        return Err(Error::Runtime(format!(
+            "{} {} failed ({}), see {}/{}",
+            CRIU_BINARY,
+            args[0],
+            status,
+            checkpoint
+                .work_path
+                .as_ref()
+                .unwrap_or(&checkpoint.image_path),
+            log_file(checkpoint)
+        )));
+    }
+    Ok(())
+}
+
+/// Arguments of the criu restore of the process tree, detached from criu
+///
+/// * `rootfs` - Root filesystem of the new container
+/// * `pid_file` - Absolute path receiving the pid of the restored process
+/// * `cgroup` - Cgroup of the new container relative to the cgroup root,
+///   the tree is restored there instead of the cgroup of the checkpoint
+fn restore_args(
+    restore: &Restore,
+    rootfs: &str,
+    pid_file: &Path,
+    cgroup: Option<&str>,
+) -> Vec<String> {
+    let mut args = vec![
+        String::from("restore"),
+        String::from("--restore-detached"),
+        String::from("--images-dir"),
+        restore.image_path.clone(),
+        String::from("--log-file"),
+        String::from(RESTORE_LOG_FILE),
+        String::from("--pidfile"),
+        pid_file.to_string_lossy().to_string(),
+        String::from("--root"),
+        rootfs.to_string(),
+        String::from("--ext-mount-map"),
+        String::from("auto"),
+    ];
+    if let Some(work_path) = &restore.work_path {
+        args.push(String::from("--work-dir"));
+        args.push(work_path.clone());
+    }
+    if let Some(cgroup) = cgroup {
+        args.push(String::from("--cgroup-root"));
+        args.push(cgroup.to_string());
+    }
+    if restore.tcp_established {
+        args.push(String::from("--tcp-established"));
+    }
+    args
+}
+
+/// Restores the process tree of the checkpoint in the image path.
+/// Returns the pid of the restored container process.
+pub fn restore(
+    restore: &Restore,
+    rootfs: &str,
+    pid_file: &Path,
+    cgroup: Option<&str>,
+) -> Result<Pid> {
+    if !cfg!(feature = "criu") {
+        return Err(disabled("criu"));
+    }
+    if !Path::new(&restore.image_path).is_dir() {
+        return Err(Error::Runtime(format!(
+            "image path {} isn't a directory",
+            restore.image_path
+        )));
+    }
+    if let Some(work_path) = &restore.work_path {
+        std::fs::create_dir_all(work_path).map_err(Error::io(format!(
+            "unable to create work path {}",
+            work_path
+        )))?;
+    }
+
+    let args = restore_args(restore, rootfs, pid_file, cgroup);
+    debug!("running {} {}", CRIU_BINARY, args.join(" "));
+
+    let status = Command::new(CRIU_BINARY)
+        .args(&args)
+        .status()
+        .map_err(Error::io(format!("unable to run {}", CRIU_BINARY)))?;
+    if !status.success() {
+        return Err(Error::Runtime(format!(
+            "{} restore failed ({}), see {}/{}",
+            CRIU_BINARY,
+            status,
+            restore.work_path.as_ref().unwrap_or(&restore.image_path),
+            RESTORE_LOG_FILE
+        )));
+    }
+
+    let pid = std::fs::read_to_string(pid_file)
+        .map_err(Error::io(format!("unable to read {}", pid_file.display())))?;
+    pid.trim()
+        .parse()
+        .map(Pid::from_raw)
+        .map_err(|_| Error::Runtime(format!("invalid pid {:?} restored by criu", pid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::Pid;
+    use std::path::Path;
+
+    use super::{dump_args, restore_args};
+    use crate::oci::ops::{Checkpoint, Restore};
+
+    fn gen_checkpoint() -> Checkpoint {
+        Checkpoint {
+            id: String::from("abc"),
+            root: String::from("/tmp/pura"),
+            image_path: String::from("./criu_test"),
+            parent_path: None,
+            work_path: None,
+            pre_dump: false,
+            leave_running: false,
+            tcp_established: false,
+            lazy_pages: false,
+            page_server: None,
+        }
+    }
+
+    #[test]
+    fn criu_args() {
+        let pid = Pid::from_raw(42);
+
+        let args = dump_args(pid, &gen_checkpoint()).unwrap();
+        assert_eq!(args[..3], ["dump", "--tree", "42"]);
+        assert!(!args.contains(&String::from("--track-mem")));
+        assert!(!args.contains(&String::from("--leave-running")));
+
+        let running = Checkpoint {
+            leave_running: true,
+            tcp_established: true,
+            work_path: Some(String::from("/tmp/criu_work")),
+            ..gen_checkpoint()
+        };
+        let args = dump_args(pid, &running).unwrap();
+        assert!(args.ends_with(&[
+            String::from("--work-dir"),
+            String::from("/tmp/criu_work"),
+            String::from("--leave-running"),
+            String::from("--tcp-established"),
+        ]));
+
+        let pre_dump = Checkpoint {
+            pre_dump: true,
+            ..gen_checkpoint()
+        };
+        let args = dump_args(pid, &pre_dump).unwrap();
+        assert_eq!(args[0], "pre-dump");
+        assert!(args.contains(&String::from("--track-mem")));
+
+        let lazy = Checkpoint {
+            lazy_pages: true,
+            page_server: Some(String::from("10.0.0.2:27")),
+            ..gen_checkpoint()
+        };
+        let args = dump_args(pid, &lazy).unwrap();
+        assert!(args.ends_with(&[
+            String::from("--lazy-pages"),
+            String::from("--address"),
+            String::from("10.0.0.2"),
+            String::from("--port"),
+            String::from("27"),
+        ]));
+
+        let page_server = Checkpoint {
+            lazy_pages: false,
+            ..lazy.clone()
+        };
+        let args = dump_args(pid, &page_server).unwrap();
+        assert!(args.contains(&String::from("--page-server")));
+
+        // The parent images must exist
+        let chained = Checkpoint {
+            parent_path: Some(String::from("../missing")),
+            ..gen_checkpoint()
+        };
+        assert!(dump_args(pid, &chained).is_err());
+
+        let lazy_pre_dump = Checkpoint {
+            pre_dump: true,
+            ..lazy
+        };
+        assert!(dump_args(pid, &lazy_pre_dump).is_err());
+    }
+
+    #[test]
+    fn restore_criu_args() {
+        let restore = Restore {
+            id: String::from("clone"),
+            bundle: String::from("/bundle"),
+            root: String::from("/tmp/pura"),
+            image_path: String::from("./criu_test"),
+            work_path: None,
+            pid_file: None,
+            network: None,
+            tcp_established: false,
+        };
+        let pid_file = Path::new("/tmp/pura/clone/restore.pid");
+        let args = restore_args(&restore, "/bundle/rootfs", pid_file, None);
+        assert_eq!(args[..2], ["restore", "--restore-detached"]);
+        assert!(args.ends_with(&[
+            String::from("--pidfile"),
+            String::from("/tmp/pura/clone/restore.pid"),
+            String::from("--root"),
+            String::from("/bundle/rootfs"),
+            String::from("--ext-mount-map"),
+            String::from("auto"),
+        ]));
+
+        let restore = Restore {
+            tcp_established: true,
+            work_path: Some(String::from("/tmp/criu_work")),
+            ..restore
+        };
+        let cgroup = Some("/pura/clone");
+        let args = restore_args(&restore, "/bundle/rootfs", pid_file, cgroup);
+        assert!(args.ends_with(&[
+            String::from("--work-dir"),
+            String::from("/tmp/criu_work"),
+            String::from("--cgroup-root"),
+            String::from("/pura/clone"),
+            String::from("--tcp-established"),
+        ]));
+    }
+}
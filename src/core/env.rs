@@ -0,0 +1,77 @@
+use nix::unistd::gethostname;
+use std::path::Path;
+
+use crate::core::users;
+
+/// PATH of a process without one, like runc
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// TERM of a process with a terminal and without one
+const DEFAULT_TERM: &str = "xterm";
+
+/// Sets the variables most entrypoints expect but the spec left out, in the
+/// environment of the current process once it's in the container: PATH,
+/// HOME of the user in the /etc/passwd of the rootfs, HOSTNAME of the UTS
+/// namespace and, with a terminal, TERM.
+pub fn set_defaults(uid: u32, terminal: bool) {
+    let set_default = |key: &str, value: &dyn Fn() -> Option<String>| {
+        if std::env::var_os(key).is_none() {
+            if let Some(value) = value() {
+                std::env::set_var(key, value);
+            }
+        }
+    };
+    set_default("PATH", &|| Some(DEFAULT_PATH.to_string()));
+    set_default("HOME", &|| {
+        Some(users::home(Path::new("/"), uid).unwrap_or_else(|| "/".to_string()))
+    });
+    set_default("HOSTNAME", &|| {
+        let mut buf = [0u8; 256];
+        gethostname(&mut buf)
+            .ok()
+            .map(|hostname| hostname.to_string_lossy().into_owned())
+    });
+    if terminal {
+        set_default("TERM", &|| Some(DEFAULT_TERM.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        libc,
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
+    };
+
+    use super::{set_defaults, DEFAULT_PATH};
+
+    #[test]
+    fn default_env() {
+        // The environment of the test process is shared, check it in a child
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                for (key, _) in std::env::vars() {
+                    std::env::remove_var(key);
+                }
+                std::env::set_var("PATH", "/bin");
+                set_defaults(0, true);
+                let ok = std::env::var("PATH").as_deref() == Ok("/bin")
+                    && std::env::var("HOME").is_ok()
+                    && std::env::var("HOSTNAME").is_ok_and(|hostname| !hostname.is_empty())
+                    && std::env::var("TERM").as_deref() == Ok("xterm");
+
+                std::env::remove_var("PATH");
+                std::env::remove_var("TERM");
+                set_defaults(0, false);
+                let ok = ok
+                    && std::env::var("PATH").as_deref() == Ok(DEFAULT_PATH)
+                    && std::env::var_os("TERM").is_none();
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => {
+                let status = waitpid(child, None).unwrap();
+                assert_eq!(status, WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
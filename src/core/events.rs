@@ -0,0 +1,371 @@
+use chrono::Local;
+use log::warn;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::core::{
+    cgroups::{cgroup_of, pressure, CGROUP_ROOT, PRESSURE_RESOURCES},
+    common::{Error, Result},
+    fork::exited,
+    state::{self, Status},
+};
+
+const EVENTS_FILE: &str = "events.log";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lifecycle transition of a container
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum EventKind {
+    Created {
+        pid: u64,
+    },
+    Started,
+    /// The exit code is only known when the runtime reaped the process
+    #[serde(rename_all = "camelCase")]
+    Stopped {
+        exit_code: Option<i32>,
+    },
+    /// The OOM killer killed a process in the cgroup of the container
+    Oom,
+    /// Tasks of the container stalled on the resource (cpu, memory or io)
+    /// over the threshold share of the last 10 seconds, once per rise above it
+    Pressure {
+        resource: String,
+        avg10: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub time: String,
+    pub id: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Appends the event to `<container>/events.log`.
+/// Failures are only logged, they never fail the operation emitting the event.
+pub fn emit(container_path: &Path, id: &str, kind: EventKind) {
+    let event = Event {
+        time: Local::now().to_rfc3339(),
+        id: id.to_string(),
+        kind,
+    };
+    if let Err(err) = append(container_path, &event) {
+        warn!("{}", err);
+    }
+}
+
+fn append(container_path: &Path, event: &Event) -> Result<()> {
+    let mut events_file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(container_path.join(EVENTS_FILE))
+        .map_err(Error::io("unable to open events file"))?;
+
+    // Single write of an O_APPEND file, readers never see interleaved events
+    let line = format!(
+        "{}\n",
+        serde_json::to_string(event).map_err(Error::json("unable to serialize event"))?
+    );
+    events_file
+        .write_all(line.as_bytes())
+        .map_err(Error::io("unable to write event"))?;
+    Ok(())
+}
+
+/// Events of a container, oldest first.
+///
+/// When following, the iterator blocks waiting for new events and ends after
+/// the container stopped or got deleted. A container process that exits
+/// without the runtime noticing is reported as stopped with an unknown exit code.
+pub struct Events {
+    path: PathBuf,
+    id: String,
+    reader: Option<BufReader<File>>,
+    line: String,
+    follow: bool,
+    oom_kills: Option<u64>,
+    pressure_threshold: Option<f64>,
+    /// Whether each of the PRESSURE_RESOURCES is above the threshold
+    stalled: [bool; PRESSURE_RESOURCES.len()],
+    done: bool,
+}
+
+impl Events {
+    pub fn new(container_path: &Path, id: &str, follow: bool) -> Events {
+        Events {
+            path: container_path.to_path_buf(),
+            id: id.to_string(),
+            reader: None,
+            line: String::new(),
+            follow,
+            oom_kills: None,
+            pressure_threshold: None,
+            stalled: [false; PRESSURE_RESOURCES.len()],
+            done: false,
+        }
+    }
+
+    /// When following, reports a pressure event when the tasks stalled on a
+    /// resource over `threshold` percent of the last 10 seconds (PSI `some avg10`)
+    pub fn with_pressure_threshold(mut self, threshold: f64) -> Events {
+        self.pressure_threshold = Some(threshold);
+        self
+    }
+
+    /// Reads the next complete line of the events file, if any
+    fn read_event(&mut self) -> Result<Option<Event>> {
+        if self.reader.is_none() {
+            match File::open(self.path.join(EVENTS_FILE)) {
+                Ok(file) => self.reader = Some(BufReader::new(file)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(Error::io("unable to open events file")(err)),
+            }
+        }
+
+        let reader = self.reader.as_mut().unwrap();
+        reader
+            .read_line(&mut self.line)
+            .map_err(Error::io("unable to read events file"))?;
+        if !self.line.ends_with('\n') {
+            // Nothing new, or the rest of the line isn't written yet
+            return Ok(None);
+        }
+
+        let event = serde_json::from_str(&self.line).map_err(Error::json("invalid event"));
+        self.line.clear();
+        event.map(Some)
+    }
+
+    /// Event for changes of the container process not recorded by the runtime
+    fn poll_process(&mut self) -> Option<EventKind> {
+        let root = self.path.parent().unwrap_or(&self.path);
+        let state = match state::open(root).and_then(|store| store.load(&self.id)) {
+            Ok(Some(state)) => state,
+            // Deleted container
+            _ => {
+                self.done = true;
+                return None;
+            }
+        };
+
+        match state.status {
+            Status::Created | Status::Running => (),
+            Status::Creating => return None,
+            Status::Stopped => {
+                self.done = true;
+                return None;
+            }
+        }
+
+        let pid = Pid::from_raw(state.pid as i32);
+        if exited(pid) {
+            return Some(EventKind::Stopped { exit_code: None });
+        }
+
+        if let Some(oom_kills) = oom_kills(pid) {
+            match self.oom_kills.replace(oom_kills) {
+                Some(previous) if oom_kills > previous => return Some(EventKind::Oom),
+                _ => (),
+            }
+        }
+        self.poll_pressure(pid)
+    }
+
+    /// Pressure event for the first resource rising above the threshold
+    fn poll_pressure(&mut self, pid: Pid) -> Option<EventKind> {
+        let threshold = self.pressure_threshold?;
+        let cgroup = Path::new(CGROUP_ROOT).join(cgroup_of(pid)?.trim_start_matches('/'));
+        for (resource, stalled) in PRESSURE_RESOURCES.iter().zip(self.stalled.iter_mut()) {
+            let avg10 = match pressure(&cgroup, resource) {
+                Some(pressure) => pressure.some.avg10,
+                None => continue,
+            };
+            if rises(stalled, avg10, threshold) {
+                return Some(EventKind::Pressure {
+                    resource: resource.to_string(),
+                    avg10,
+                });
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Events {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            match self.read_event() {
+                Ok(Some(event)) => {
+                    if let EventKind::Stopped { .. } = event.kind {
+                        self.done = self.follow;
+                    }
+                    return Some(Ok(event));
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+
+            if !self.follow {
+                return None;
+            }
+
+            if let Some(kind) = self.poll_process() {
+                if let EventKind::Stopped { .. } = kind {
+                    self.done = true;
+                }
+                return Some(Ok(Event {
+                    time: Local::now().to_rfc3339(),
+                    id: self.id.clone(),
+                    kind,
+                }));
+            }
+
+            if !self.done {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Parses an interval like `5s`, `500ms`, `1m` or `2.5` (seconds)
+pub fn parse_interval(interval: &str) -> Option<Duration> {
+    let (number, unit) = match interval.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => interval.split_at(index),
+        None => (interval, "s"),
+    };
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None,
+    };
+    if seconds > 0.0 && seconds.is_finite() {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+/// Whether the value just rose to the threshold, recording whether it's above
+fn rises(above: &mut bool, value: f64, threshold: f64) -> bool {
+    let was_above = std::mem::replace(above, value >= threshold);
+    *above && !was_above
+}
+
+/// oom_kill counter of the cgroup (v2) of the process
+fn oom_kills(pid: Pid) -> Option<u64> {
+    let cgroup = cgroup_of(pid)?;
+    let memory_events =
+        std::fs::read_to_string(format!("{}{}/memory.events", CGROUP_ROOT, cgroup)).ok()?;
+    memory_events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command, time::Duration};
+
+    use super::{emit, parse_interval, rises, EventKind, Events};
+    use crate::core::state::{State, Status};
+
+    #[test]
+    fn read_events() {
+        let path = Path::new("./events_test");
+        std::fs::create_dir_all(path).unwrap();
+
+        emit(path, "abc", EventKind::Created { pid: 42 });
+        emit(path, "abc", EventKind::Started);
+
+        let events: Vec<_> = Events::new(path, "abc", false)
+            .map(|event| event.unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].id, "abc");
+        assert_eq!(events[0].kind, EventKind::Created { pid: 42 });
+        assert_eq!(events[1].kind, EventKind::Started);
+
+        let line = std::fs::read_to_string(path.join("events.log")).unwrap();
+        let first: serde_json::Value = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+        assert_eq!(first["type"], "created");
+        assert_eq!(first["pid"], 42);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn follow_exited_process() {
+        let root = Path::new("./events_follow_test");
+        let path = root.join("abc");
+        let path = path.as_path();
+
+        // Running container whose process already exited
+        let mut child = Command::new("true").spawn().unwrap();
+        let mut state = State::new("abc", child.id() as u64, "/tmp");
+        child.wait().unwrap();
+        state.status = Status::Running;
+        state.save(path).unwrap();
+        emit(path, "abc", EventKind::Started);
+
+        let events: Vec<_> = Events::new(path, "abc", true)
+            .map(|event| event.unwrap().kind)
+            .collect();
+        assert_eq!(
+            events,
+            vec![EventKind::Started, EventKind::Stopped { exit_code: None }]
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn pressure_rises() {
+        let mut above = false;
+        let events: Vec<bool> = [1.0, 12.5, 30.0, 4.0, 10.0]
+            .iter()
+            .map(|avg10| rises(&mut above, *avg10, 10.0))
+            .collect();
+        assert_eq!(events, vec![false, true, false, false, true]);
+
+        let event = serde_json::to_value(EventKind::Pressure {
+            resource: String::from("memory"),
+            avg10: 12.5,
+        })
+        .unwrap();
+        assert_eq!(event["type"], "pressure");
+        assert_eq!(event["resource"], "memory");
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("5s"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_interval("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_interval("1m"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_interval("2.5"), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_interval("0s"), None);
+        assert_eq!(parse_interval("5d"), None);
+        assert_eq!(parse_interval("s"), None);
+    }
+}
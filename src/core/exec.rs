@@ -0,0 +1,470 @@
+use log::debug;
+use nix::{
+    fcntl::OFlag,
+    libc,
+    sched::{setns, CloneFlags},
+    sys::signal::Signal,
+    unistd::{
+        chdir, execvp, fork, getpid, isatty, pipe2, setgid, setuid, ForkResult, Gid, Pid, Uid,
+    },
+};
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::MetadataExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::core::{
+    apparmor, capabilities,
+    cgroups::{cgroup_of, CGROUP_ROOT},
+    common::{Error, Result},
+    env,
+    filesystem::find_executable,
+    fork::{self, set_dumpable, set_parent_death_signal, Process},
+    seccomp::{self, Filter},
+    terminal::chown_stdio,
+    users::{self, set_groups},
+};
+use crate::oci::{ops::Exec, spec};
+
+/// Processes exec'd in the container, one `pid start_time` line each
+pub const EXEC_FILE: &str = "exec.pids";
+/// Exec'd processes still running, in the state reported by `pura state`
+pub const EXEC_COUNT_ANNOTATION: &str = "pura.exec.count";
+/// Child cgroups of the container created for exec'd processes, a path each
+pub const EXEC_CGROUPS_FILE: &str = "exec.cgroups";
+/// Namespaces of the container process joined by exec. The user namespace
+/// comes first for the privileges over the others, the mount namespace last
+/// since it hides the /proc of the host.
+const NAMESPACES: [(&str, CloneFlags); 7] = [
+    ("user", CloneFlags::CLONE_NEWUSER),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+/// Exit code of a process that can't be executed, like a shell
+const EXEC_FAILED: i32 = 127;
+
+/// Process of the spec with the overrides of the exec command line, users
+/// are looked up in the rootfs
+pub fn process(spec_process: &spec::Process, exec: &Exec, rootfs: &Path) -> Result<spec::Process> {
+    let mut process = spec_process.clone();
+
+    let mut env = process.env.take().unwrap_or_default();
+    for var in exec.env.iter() {
+        // KEY alone takes the value of the runtime environment, like docker
+        let var = match var.split_once('=') {
+            Some(_) => var.clone(),
+            None => match std::env::var(var) {
+                Ok(value) => format!("{}={}", var, value),
+                Err(_) => continue,
+            },
+        };
+        let key = var.split('=').next().unwrap_or_default();
+        env.retain(|existing| existing.split('=').next() != Some(key));
+        env.push(var);
+    }
+    process.env = Some(env);
+
+    if let Some(user) = &exec.user {
+        let user = users::lookup(rootfs, user)?;
+        // HOME follows the user unless it's given too
+        if !exec
+            .env
+            .iter()
+            .any(|var| var.split('=').next() == Some("HOME"))
+        {
+            let home = users::home(rootfs, user.uid as u32).unwrap_or_else(|| "/".to_string());
+            let env = process.env.get_or_insert_with(Vec::new);
+            env.retain(|existing| existing.split('=').next() != Some("HOME"));
+            env.push(format!("HOME={}", home));
+        }
+        process.user = Some(user);
+    }
+    if let Some(cwd) = &exec.cwd {
+        process.cwd = cwd.clone();
+    }
+    process.capabilities =
+        capabilities::adjust(process.capabilities.as_ref(), &exec.cap_add, &exec.cap_drop)?;
+    Ok(process)
+}
+
+/// Executes the args in the namespaces and the cgroup of the container
+/// process (or the given cgroup directory), with the environment, user and
+/// working directory of the process of the spec, confined by the seccomp
+/// filter of the container. Returns once the new process executed the args.
+///
+/// An intermediate process joins the namespaces and forks the new process, so
+/// it's in the PID namespace of the container. The caller becomes a child
+/// subreaper to get the new process as its child when the intermediate exits.
+pub fn exec(
+    container: Pid,
+    process: &spec::Process,
+    args: &[String],
+    cgroup: Option<&Path>,
+    filter: Option<&Filter>,
+) -> Result<Process> {
+    let apparmor_profile = apparmor::profile(process)?;
+    let mut namespaces = Vec::new();
+    for (name, flags) in NAMESPACES.iter() {
+        let path = format!("/proc/{}/ns/{}", container, name);
+        let namespace = File::open(&path).map_err(Error::io(format!("unable to open {}", path)))?;
+        // Joining the namespace of the runtime itself fails for the user namespace
+        let own = std::fs::metadata(format!("/proc/self/ns/{}", name))
+            .map_err(Error::io(format!("unable to read the {} namespace", name)))?;
+        let theirs = namespace
+            .metadata()
+            .map_err(Error::io(format!("unable to read {}", path)))?;
+        if own.ino() != theirs.ino() {
+            namespaces.push((namespace, *flags));
+        }
+    }
+    let cgroup = cgroup.map(Path::to_path_buf).or_else(|| {
+        cgroup_of(container)
+            .map(|cgroup| Path::new(CGROUP_ROOT).join(cgroup.trim_start_matches('/')))
+    });
+    let cgroup_procs = cgroup
+        .map(|cgroup| cgroup.join("cgroup.procs"))
+        .filter(|procs| procs.exists());
+
+    let to_cstring = |arg: &String| CString::new(arg.as_bytes()).unwrap_or_default();
+    let args: Vec<CString> = args.iter().map(to_cstring).collect();
+    let env: Vec<(String, String)> = process
+        .env
+        .iter()
+        .flatten()
+        .filter_map(|env| env.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } < 0 {
+        return Err(Error::sys("unable to become a child subreaper")(
+            nix::Error::last(),
+        ));
+    }
+    let (result_read, result_write) =
+        pipe2(OFlag::O_CLOEXEC).map_err(Error::sys("unable to create exec result pipe"))?;
+    let (mut result_read, mut result_write) = unsafe {
+        (
+            File::from_raw_fd(result_read),
+            File::from_raw_fd(result_write),
+        )
+    };
+
+    let runtime = getpid();
+    let intermediate = match unsafe { fork() }.map_err(Error::sys("unable to fork exec process"))? {
+        ForkResult::Parent { child } => child,
+        ForkResult::Child => {
+            drop(result_read);
+            let result = set_parent_death_signal(Signal::SIGKILL, runtime)
+                .and_then(|_| set_dumpable(false))
+                .and_then(|_| {
+                    join(&namespaces, cgroup_procs.as_deref()).and_then(|_| {
+                        match unsafe { fork() }
+                            .map_err(Error::sys("unable to fork exec process"))?
+                        {
+                            ForkResult::Parent { child } => Ok(child),
+                            ForkResult::Child => {
+                                let err = execute(
+                                    process,
+                                    &args,
+                                    &env,
+                                    filter,
+                                    apparmor_profile.as_deref(),
+                                );
+                                // The write end is closed on exec, the runtime reads it until then
+                                let _ = writeln!(result_write, "error:{}", err);
+                                unsafe { libc::_exit(EXEC_FAILED) }
+                            }
+                        }
+                    })
+                });
+            let (line, code) = match result {
+                Ok(pid) => (format!("{}", pid), 0),
+                Err(err) => (format!("error:{}", err), 1),
+            };
+            let _ = writeln!(result_write, "{}", line);
+            unsafe { libc::_exit(code) }
+        }
+    };
+    drop(result_write);
+
+    // Until the intermediate process exited and the new process executed the args
+    let mut result = String::new();
+    let read = result_read.read_to_string(&mut result);
+    let _ = nix::sys::wait::waitpid(intermediate, None);
+    read.map_err(Error::io("unable to read exec result"))?;
+
+    let mut pid = None;
+    for line in result.lines() {
+        if let Some(err) = line.strip_prefix("error:") {
+            return Err(Error::Runtime(format!(
+                "unable to exec {:?}: {}",
+                args, err
+            )));
+        }
+        pid = line.parse().ok().map(Pid::from_raw);
+    }
+    let pid = pid.ok_or_else(|| Error::Runtime(format!("invalid exec result {:?}", result)))?;
+    debug!("exec'd {:?} as {}", args, pid);
+    Process::open(pid, fork::start_time(pid))
+}
+
+/// Moves the current process into the cgroup and the namespaces
+fn join(namespaces: &[(File, CloneFlags)], cgroup_procs: Option<&Path>) -> Result<()> {
+    // Before the mount namespace of the container hides the cgroup of the host
+    if let Some(cgroup_procs) = cgroup_procs {
+        std::fs::write(cgroup_procs, getpid().to_string()).map_err(Error::io(format!(
+            "unable to join cgroup {:?}",
+            cgroup_procs
+        )))?;
+    }
+    for (namespace, flags) in namespaces {
+        setns(namespace.as_raw_fd(), *flags)
+            .map_err(Error::sys(format!("unable to join namespace {:?}", flags)))?;
+    }
+    Ok(())
+}
+
+/// Executes the args like the process of the spec, returning only on errors
+fn execute(
+    process: &spec::Process,
+    args: &[CString],
+    env: &[(String, String)],
+    filter: Option<&Filter>,
+    apparmor_profile: Option<&str>,
+) -> Error {
+    for (key, _) in std::env::vars() {
+        std::env::remove_var(key);
+    }
+    for (key, value) in env {
+        std::env::set_var(key, value);
+    }
+    let uid = process
+        .user
+        .as_ref()
+        .map(|user| user.uid as u32)
+        .unwrap_or(0);
+    env::set_defaults(uid, isatty(0).unwrap_or(false));
+    // Only the stdio is passed to the process, the result pipe is already
+    if let Err(err) = fork::close_fds_on_exec(3) {
+        return err;
+    }
+    let no_new_privileges = process.no_new_privileges.unwrap_or_default();
+    if let Some(profile) = apparmor_profile {
+        if let Err(err) = apparmor::change_onexec(profile) {
+            return err;
+        }
+    }
+    if let Err(err) = seccomp::load_first(filter, no_new_privileges) {
+        return err;
+    }
+    if let Some(capabilities) = &process.capabilities {
+        if let Err(err) = capabilities::drop_bounding(capabilities) {
+            return err;
+        }
+        // The capabilities are set once the user changed
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } < 0 {
+            return Error::sys("unable to keep the capabilities")(nix::Error::last());
+        }
+    }
+    if let Some(user) = &process.user {
+        let (uid, gid) = (
+            Uid::from_raw(user.uid as u32),
+            Gid::from_raw(user.gid as u32),
+        );
+        if let Err(err) = chown_stdio(uid, gid, false).and_then(|_| set_groups(user)) {
+            return err;
+        }
+        if let Err(err) = setgid(gid) {
+            return Error::sys("unable to set the gid")(err);
+        }
+        if let Err(err) = setuid(uid) {
+            return Error::sys("unable to set the uid")(err);
+        }
+    }
+    if let Some(capabilities) = &process.capabilities {
+        if let Err(err) = capabilities::set(capabilities) {
+            return err;
+        }
+        // Kept across the change of user only
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) } < 0 {
+            return Error::sys("unable to clear the kept capabilities")(nix::Error::last());
+        }
+    }
+    if let Err(err) = chdir(Path::new(&process.cwd)) {
+        return Error::sys(format!("unable to change directory to {}", process.cwd))(err);
+    }
+    let name = match args.first() {
+        Some(name) => name.to_string_lossy(),
+        None => return Error::Runtime("no command to exec".to_string()),
+    };
+    let path = std::env::var("PATH").ok();
+    let exec = match find_executable(&name, path.as_deref(), Path::new(&process.cwd)) {
+        Ok(executable) => CString::new(executable.as_os_str().as_bytes()).unwrap_or_default(),
+        Err(err) => return err,
+    };
+    if let Err(err) = seccomp::load_last(filter, no_new_privileges) {
+        return err;
+    }
+    Error::sys("execvp failed")(execvp(&exec, args).unwrap_err())
+}
+
+/// Records the exec'd process, so kill and delete account for it
+pub fn register(container_path: &Path, process: &Process) -> Result<()> {
+    let start_time = fork::start_time(process.pid()).unwrap_or_default();
+    // Single write of an O_APPEND file, concurrent execs never interleave
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(container_path.join(EXEC_FILE))
+        .and_then(|mut file| {
+            file.write_all(format!("{} {}\n", process.pid(), start_time).as_bytes())
+        })
+        .map_err(Error::io(format!(
+            "unable to record exec'd process {}",
+            process.pid()
+        )))
+}
+
+/// Records the child cgroup created for exec'd processes, so delete removes it
+pub fn register_cgroup(container_path: &Path, cgroup: &Path) -> Result<()> {
+    if cgroups(container_path)
+        .iter()
+        .any(|registered| registered == cgroup)
+    {
+        return Ok(());
+    }
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(container_path.join(EXEC_CGROUPS_FILE))
+        .and_then(|mut file| file.write_all(format!("{}\n", cgroup.display()).as_bytes()))
+        .map_err(Error::io(format!(
+            "unable to record exec cgroup {:?}",
+            cgroup
+        )))
+}
+
+/// Child cgroups created for exec'd processes, in the order of creation
+pub fn cgroups(container_path: &Path) -> Vec<PathBuf> {
+    std::fs::read_to_string(container_path.join(EXEC_CGROUPS_FILE))
+        .unwrap_or_default()
+        .lines()
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Exec'd processes of the container still running. Recorded processes whose
+/// pid got reused are left out.
+pub fn processes(container_path: &Path) -> Vec<Process> {
+    std::fs::read_to_string(container_path.join(EXEC_FILE))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(parse_exec_line)
+        .filter_map(|(pid, start_time)| Process::open(pid, Some(start_time)).ok())
+        .filter(|process| !fork::exited(process.pid()))
+        .collect()
+}
+
+fn parse_exec_line(line: &str) -> Option<(Pid, u64)> {
+    let (pid, start_time) = line.trim().split_once(' ')?;
+    Some((Pid::from_raw(pid.parse().ok()?), start_time.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::{getpid, Pid};
+    use std::path::Path;
+
+    use super::{parse_exec_line, process, processes, register, EXEC_FILE};
+    use crate::core::fork::Process;
+    use crate::oci::{ops::Exec, spec};
+
+    #[test]
+    fn exec_overrides() {
+        let spec_process = spec::Process {
+            env: Some(vec!["PATH=/bin".to_string(), "HOME=/root".to_string()]),
+            cwd: "/".to_string(),
+            ..Default::default()
+        };
+        let mut exec = Exec {
+            id: "abc".to_string(),
+            root: "/tmp/pura".to_string(),
+            args: vec!["sh".to_string()],
+            detach: false,
+            pid_file: None,
+            env: vec!["HOME=/home/ci".to_string(), "TERM=xterm".to_string()],
+            user: Some("1000".to_string()),
+            cwd: Some("/tmp".to_string()),
+            cap_add: vec!["SYS_PTRACE".to_string()],
+            cap_drop: vec!["ALL".to_string()],
+            cgroup: None,
+            resources: None,
+        };
+
+        // Without /etc/passwd and /etc/group, only ids resolve
+        let rootfs = Path::new("./missing_rootfs");
+        let overridden = process(&spec_process, &exec, rootfs).unwrap();
+        assert_eq!(
+            overridden.env.unwrap(),
+            ["PATH=/bin", "HOME=/home/ci", "TERM=xterm"]
+        );
+        assert_eq!(
+            overridden.user.map(|user| (user.uid, user.gid)),
+            Some((1000, 0))
+        );
+        assert_eq!(overridden.cwd, "/tmp");
+        assert_eq!(
+            overridden.capabilities.unwrap().effective.unwrap(),
+            ["CAP_SYS_PTRACE"]
+        );
+
+        exec.user = Some("1000:wheel".to_string());
+        assert!(process(&spec_process, &exec, rootfs).is_err());
+        exec.user = Some("1000:10".to_string());
+        exec.env.clear();
+        let overridden = process(&spec_process, &exec, rootfs).unwrap();
+        assert_eq!(overridden.env.unwrap(), ["PATH=/bin", "HOME=/"]);
+    }
+
+    #[test]
+    fn exec_registry() {
+        assert_eq!(
+            parse_exec_line("42 1234\n"),
+            Some((Pid::from_raw(42), 1234))
+        );
+        assert_eq!(parse_exec_line("42"), None);
+
+        let path = Path::new("./exec_test");
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir(path).unwrap();
+        assert!(processes(path).is_empty());
+
+        register(path, &Process::open(getpid(), None).unwrap()).unwrap();
+        // Another process reusing a recorded pid isn't the exec'd process
+        std::fs::write(
+            path.join(EXEC_FILE),
+            format!(
+                "{}{} 1\n",
+                std::fs::read_to_string(path.join(EXEC_FILE)).unwrap(),
+                getpid()
+            ),
+        )
+        .unwrap();
+        let exec_processes = processes(path);
+        assert_eq!(exec_processes.len(), 1);
+        assert_eq!(exec_processes[0].pid(), getpid());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -1,100 +1,84 @@
+use log::{debug, trace, warn};
 use nix::{
     errno::Errno,
+    fcntl::{open, OFlag},
+    libc,
     mount::{mount, umount2, MntFlags, MsFlags},
-    sys::stat::{makedev, mknod, Mode, SFlag},
-    unistd::{chdir, chown, pivot_root, Gid, Uid},
+    sched::{setns, unshare, CloneFlags},
+    sys::{
+        stat::{major, makedev, minor, mknod, Mode, SFlag},
+        statfs::{fstatfs, FsType, PROC_SUPER_MAGIC},
+        statvfs::{fstatvfs, FsFlags},
+    },
+    unistd::{chdir, chown, chroot, close, fchdir, pivot_root, Gid, Pid, Uid},
 };
 
 use std::{
     convert::TryInto,
-    os::unix::fs::symlink,
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    os::unix::{
+        fs::{symlink, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    },
     path::{Path, PathBuf},
-    str::FromStr,
+    thread,
 };
 
-use crate::core::common::{exit_msg, Error, ErrorType, Result};
+use crate::core::{
+    common::{exit_msg, Error, Result},
+    env::DEFAULT_PATH,
+    overlay::Overlay,
+    selinux::MountLabels,
+};
 
-use crate::oci::spec::{Device, Mount};
+use crate::oci::spec::{Device, Linux, Mount};
 
-pub fn symlinks_defaults(rootfs: &Path) {
+/// Symlinks of /dev every container has, the fds of the process and the
+/// multiplexer of the devpts of the container
+pub fn symlinks_defaults(rootfs: &Path) -> Result<()> {
     let default_symlinks = [
         ("/proc/self/fd", "dev/fd"),
         ("/proc/self/fd/0", "dev/stdin"),
         ("/proc/self/fd/1", "dev/stdout"),
         ("/proc/self/fd/2", "dev/stderr"),
+        ("pts/ptmx", "dev/ptmx"),
     ];
 
     for (src, dest) in default_symlinks {
-        symlink(src, rootfs.join(dest)).unwrap();
+        let dest = rootfs.join(dest);
+        // Left alone when the spec or the rootfs has it already
+        if dest.symlink_metadata().is_ok() {
+            continue;
+        }
+        symlink(src, &dest).map_err(Error::io(format!("unable to symlink {:?}", dest)))?;
     }
+    Ok(())
 }
 
+/// Devices every container has, readable and writable by everyone. Their
+/// numbers are fixed by the kernel on every architecture.
 fn default_devices() -> Vec<Device> {
-    vec![
-        Device {
-            path: String::from("/dev/null"),
-            device_type: String::from("c"),
-            major: 1,
-            minor: 3,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
-        Device {
-            path: String::from("/dev/zero"),
-            device_type: String::from("c"),
-            major: 1,
-            minor: 5,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
-        Device {
-            path: String::from("/dev/full"),
-            device_type: String::from("c"),
-            major: 1,
-            minor: 7,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
-        Device {
-            path: String::from("/dev/random"),
-            device_type: String::from("c"),
-            major: 1,
-            minor: 8,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
-        Device {
-            path: String::from("/dev/urandom"),
-            device_type: String::from("c"),
-            major: 1,
-            minor: 9,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
-        Device {
-            path: String::from("/dev/tty"),
-            device_type: String::from("c"),
-            major: 5,
-            minor: 0,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
-        Device {
-            path: String::from("/dev/ptmx"),
-            device_type: String::from("c"),
-            major: 5,
-            minor: 2,
-            file_mode: Some(0o066),
-            uid: Some(0),
-            gid: Some(0),
-        },
+    [
+        ("/dev/null", 1, 3),
+        ("/dev/zero", 1, 5),
+        ("/dev/full", 1, 7),
+        ("/dev/random", 1, 8),
+        ("/dev/urandom", 1, 9),
+        ("/dev/tty", 5, 0),
     ]
+    .iter()
+    .map(|(path, major, minor)| Device {
+        path: path.to_string(),
+        device_type: String::from("c"),
+        major: *major,
+        minor: *minor,
+        file_mode: Some(0o666),
+        uid: Some(0),
+        gid: Some(0),
+        ..Default::default()
+    })
+    .collect()
 }
 
 fn to_sflag(flag: &str) -> SFlag {
@@ -106,68 +90,182 @@ fn to_sflag(flag: &str) -> SFlag {
     }
 }
 
-fn bind_dev(dev: &Device) {
-    let path = PathBuf::from_str(&dev.path).unwrap();
+/// Bind mounts the device of the host over an empty file, for a user
+/// namespace where mknod isn't permitted
+fn bind_dev(dev: &Device, rootfs: &Path) -> Result<()> {
+    let path = rootfs.join(dev.path.trim_start_matches('/'));
 
+    trace!("bind mounting device {} on {:?}", dev.path, path);
+    File::create(&path).map_err(Error::io(format!("failed to create dev at {}", dev.path)))?;
     mount(
-        Some(&path),
+        Some(Path::new(&dev.path)),
         &path,
         None::<&str>,
         MsFlags::MS_BIND,
         None::<&str>,
     )
-    .unwrap();
+    .map_err(Error::sys(format!("failed to bind dev at {}", dev.path)))
 }
 
-fn create_dev(dev: &Device, rootfs: &Path) -> Result<()> {
-    let path = rootfs.join(&dev.path.trim_start_matches("/"));
+/// Creates the device node under the rootfs. Where mknod isn't permitted,
+/// the device of the host is bind mounted instead when `bind` allows it.
+fn create_dev(dev: &Device, rootfs: &Path, bind: bool) -> Result<()> {
+    let path = rootfs.join(dev.path.trim_start_matches("/"));
 
-    mknod(
+    trace!("mknod {:?} type {} {}:{}", path, dev.device_type, dev.major, dev.minor);
+    let mode = Mode::from_bits_truncate(dev.file_mode.unwrap_or(0o666).try_into().unwrap());
+    match mknod(
         path.as_path(),
         to_sflag(dev.device_type.as_str()),
-        Mode::from_bits_truncate(dev.file_mode.unwrap_or(0o066).try_into().unwrap()),
+        mode,
         makedev(dev.major, dev.minor),
-    )
-    .map_err(|err| Error {
-        msg: format!("failed to create dev at {}: {}", dev.path, err.to_string()),
-        err_type: ErrorType::Container,
-    })?;
-
-    if let Some(uid) = dev.uid {
-        chown(path.as_path(), Some(Uid::from_raw(uid)), None).unwrap();
+    ) {
+        Err(nix::Error::Sys(Errno::EPERM)) if bind => return bind_dev(dev, rootfs),
+        result => result.map_err(Error::sys(format!("failed to create dev at {}", dev.path)))?,
     }
+    // The mode of mknod is masked by the umask
+    std::fs::set_permissions(&path, PermissionsExt::from_mode(mode.bits()))
+        .map_err(Error::io(format!("failed to set the mode of {}", dev.path)))?;
 
-    if let Some(gid) = dev.gid {
-        chown(path.as_path(), None, Some(Gid::from_raw(gid))).unwrap();
-    }
+    chown(
+        path.as_path(),
+        dev.uid.map(Uid::from_raw),
+        dev.gid.map(Gid::from_raw),
+    )
+    .map_err(Error::sys(format!("failed to chown dev at {}", dev.path)))?;
 
     Ok(())
 }
 
-pub fn create_default_devices(rootfs: &Path) {
-    let devices = default_devices();
-    let bind = false;
-
-    for dev in devices.iter() {
-        if bind {
-            bind_dev(dev);
-        } else {
-            create_dev(dev, rootfs).unwrap();
+/// Creates the default devices the spec doesn't have, and with a terminal,
+/// /dev/console on the pty of the process
+pub fn create_default_devices(rootfs: &Path, console: Option<&str>) -> Result<()> {
+    for dev in default_devices() {
+        if rootfs.join(dev.path.trim_start_matches('/')).symlink_metadata().is_err() {
+            create_dev(&dev, rootfs, true)?;
         }
     }
+
+    if let Some(console) = console {
+        let path = rootfs.join("dev/console");
+        trace!("bind mounting console {} on {:?}", console, path);
+        File::create(&path).map_err(Error::io("failed to create /dev/console"))?;
+        mount(
+            Some(console),
+            &path,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(Error::sys("failed to bind /dev/console"))?;
+    }
+    Ok(())
 }
 
 pub fn create_devices(devices: &Vec<Device>, rootfs: &Path) -> Result<()> {
     for d in devices {
-        create_dev(d, rootfs).map_err(|err| Error {
-            msg: format!("failed to create device: {}", err.to_string()),
-            err_type: ErrorType::Container,
-        })?;
+        create_dev(d, rootfs, true)?;
     }
     Ok(())
 }
 
-pub fn mount_rootfs(rootfs: &Path) -> Result<()> {
+/// Device at the path of the container for the device at the host path,
+/// with its type, numbers and mode
+pub fn host_device(host_path: &str, path: &str) -> Result<Device> {
+    let metadata = std::fs::metadata(host_path)
+        .map_err(Error::io(format!("unable to stat device {}", host_path)))?;
+    let file_type = metadata.file_type();
+    let device_type = if file_type.is_char_device() {
+        "c"
+    } else if file_type.is_block_device() {
+        "b"
+    } else if file_type.is_fifo() {
+        "p"
+    } else {
+        return Err(Error::Bundle(format!("{} is not a device", host_path)));
+    };
+    Ok(Device {
+        path: path.to_string(),
+        device_type: device_type.to_string(),
+        major: major(metadata.rdev()),
+        minor: minor(metadata.rdev()),
+        file_mode: Some((metadata.mode() & 0o777) as i64),
+        uid: None,
+        gid: None,
+        ..Default::default()
+    })
+}
+
+/// Parses `HOST[:CONTAINER[:PERMISSIONS]]`, like `--device` of docker, into
+/// the device at the container path and its cgroup permissions, `rwm` by default
+pub fn parse_device(device: &str) -> Result<(Device, String)> {
+    let mut parts = device.splitn(3, ':');
+    let host_path = parts.next().unwrap_or_default();
+    let path = parts.next().filter(|path| !path.is_empty()).unwrap_or(host_path);
+    let access = parts.next().unwrap_or("rwm");
+    if !Path::new(path).is_absolute()
+        || access.is_empty()
+        || !access.chars().all(|c| "rwm".contains(c))
+    {
+        return Err(Error::Bundle(format!(
+            "invalid device {:?}, HOST[:CONTAINER[:PERMISSIONS]]",
+            device
+        )));
+    }
+    Ok((host_device(host_path, path)?, access.to_string()))
+}
+
+/// Creates the devices in the running container: a thread with a
+/// filesystem context of its own joins the mount namespace of the process
+/// and chroots into its root, so the runtime is left as it was
+pub fn add_devices(pid: Pid, devices: &[Device]) -> Result<()> {
+    let open_proc = |file: &str, flags: OFlag| {
+        let path = format!("/proc/{}/{}", pid, file);
+        open(path.as_str(), flags | OFlag::O_CLOEXEC, Mode::empty())
+            .map_err(Error::sys(format!("unable to open {}", path)))
+    };
+    let mnt = open_proc("ns/mnt", OFlag::O_RDONLY)?;
+    let root = match open_proc("root", OFlag::O_RDONLY | OFlag::O_DIRECTORY) {
+        Ok(root) => root,
+        Err(err) => {
+            let _ = close(mnt);
+            return Err(err);
+        }
+    };
+
+    let devices = devices.to_vec();
+    let added = thread::spawn(move || -> Result<()> {
+        unshare(CloneFlags::CLONE_FS).map_err(Error::sys("unable to unshare the fs context"))?;
+        setns(mnt, CloneFlags::CLONE_NEWNS)
+            .map_err(Error::sys("unable to join the mount namespace"))?;
+        fchdir(root).map_err(Error::sys("unable to chdir into the container root"))?;
+        chroot(".").map_err(Error::sys("unable to chroot into the container root"))?;
+        for device in &devices {
+            let path = Path::new(&device.path);
+            if path.symlink_metadata().is_ok() {
+                return Err(Error::Runtime(format!(
+                    "{} already exists in the container",
+                    device.path
+                )));
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(Error::io(format!("unable to create {:?}", parent)))?;
+            }
+            // The devices of the host aren't reachable from the container to bind them
+            create_dev(device, Path::new("/"), false)?;
+        }
+        Ok(())
+    })
+    .join()
+    .unwrap_or_else(|_| Err(Error::Runtime(String::from("adding the devices panicked"))));
+    let _ = close(mnt);
+    let _ = close(root);
+    added
+}
+
+/// Mounts the rootfs on itself, or the overlay of the container on it
+pub fn mount_rootfs(rootfs: &Path, overlay: Option<&Overlay>) -> Result<()> {
     // https://man7.org/linux/man-pages/man2/pivot_root.2.html
     mount(
         None::<&str>,
@@ -176,95 +274,625 @@ pub fn mount_rootfs(rootfs: &Path) -> Result<()> {
         MsFlags::MS_PRIVATE | MsFlags::MS_REC,
         None::<&str>,
     )
-    .map_err(|_| Error {
-        msg: "mount failed".to_string(),
-        err_type: ErrorType::Container,
-    })?;
+    .map_err(Error::sys("mount failed"))?;
+    if let Some(overlay) = overlay {
+        return overlay.mount();
+    }
+
+    debug!("bind mounting rootfs {:?}", rootfs);
 
     mount::<Path, Path, str, str>(
-        Some(&rootfs),
-        &rootfs,
+        Some(rootfs),
+        rootfs,
         None::<&str>,
         MsFlags::MS_BIND | MsFlags::MS_REC,
         None::<&str>,
     )
-    .map_err(|err| {
-        Error {
-            msg: format!("mount rootfs failed {} for {:?}", err, rootfs),
-            err_type: ErrorType::Container,
-        }
-    })?;
+    .map_err(Error::sys(format!("mount rootfs failed for {:?}", rootfs)))?;
 
     Ok(())
 }
 
 pub fn pivot_rootfs(rootfs: &Path) -> Result<()> {
-    chdir(rootfs).map_err(|_| Error {
-        msg: "unable to chdir into container".to_string(),
-        err_type: ErrorType::Container,
-    })?;
-
-    std::fs::create_dir_all(rootfs.join("oldroot")).map_err(|_| Error {
-        msg: "unable to create tmp root".to_string(),
-        err_type: ErrorType::Container,
-    })?;
-
-    pivot_root(rootfs.as_os_str(), rootfs.join("oldroot").as_os_str()).map_err(|err| Error {
-        msg: format!("pivot_root failed {}", err.to_string()).to_string(),
-        err_type: ErrorType::Container,
-    })?;
-
-    umount2("./oldroot", MntFlags::MNT_DETACH).map_err(|_| Error {
-        msg: "unmount old_dir failed".to_string(),
-        err_type: ErrorType::Container,
-    })?;
-
-    std::fs::remove_dir_all("./oldroot").map_err(|_| Error {
-        msg: "rm old_dir failed".to_string(),
-        err_type: ErrorType::Container,
-    })?;
-
-    chdir("/").map_err(|_| Error {
-        msg: "chdir on root(/) failed".to_string(),
-        err_type: ErrorType::Container,
-    })?;
+    debug!("pivot_root into {:?}", rootfs);
+    chdir(rootfs).map_err(Error::sys("unable to chdir into container"))?;
+
+    std::fs::create_dir_all(rootfs.join("oldroot"))
+        .map_err(Error::io("unable to create tmp root"))?;
+
+    match pivot_root(rootfs.as_os_str(), rootfs.join("oldroot").as_os_str()) {
+        Ok(_) => (),
+        // A rootfs on the initramfs, or a seccomp profile of the outer container
+        Err(err) if matches!(err.as_errno(), Some(Errno::EINVAL) | Some(Errno::EPERM)) => {
+            warn!("pivot_root refused ({}), moving the rootfs over / instead", err);
+            std::fs::remove_dir("./oldroot").map_err(Error::io("rm old_dir failed"))?;
+            return move_root();
+        }
+        Err(err) => return Err(Error::sys("pivot_root failed")(err)),
+    }
+
+    umount2("./oldroot", MntFlags::MNT_DETACH).map_err(Error::sys("unmount old_dir failed"))?;
+
+    std::fs::remove_dir_all("./oldroot").map_err(Error::io("rm old_dir failed"))?;
+
+    chdir("/").map_err(Error::sys("chdir on root(/) failed"))?;
+    Ok(())
+}
+
+/// Moves the rootfs, the current directory, over / and chroots into it, like
+/// `runc --no-pivot`. The old root stays in the mount namespace, under the
+/// rootfs, so a process with CAP_SYS_CHROOT can get back to it.
+fn move_root() -> Result<()> {
+    mount(
+        Some("."),
+        "/",
+        None::<&str>,
+        MsFlags::MS_MOVE,
+        None::<&str>,
+    )
+    .map_err(Error::sys("moving the rootfs over / failed"))?;
+    chroot(".").map_err(Error::sys("chroot into the rootfs failed"))?;
+    chdir("/").map_err(Error::sys("chdir on root(/) failed"))?;
+    Ok(())
+}
+
+/// Values of `hidepid=`, the names known since Linux 5.8
+const HIDEPID_VALUES: [&str; 8] = [
+    "0", "1", "2", "4", "off", "noaccess", "invisible", "ptraceable",
+];
+
+/// Mount of the runtime standing in for a fresh filesystem of the type that
+/// can't be mounted, when pura runs in a user-namespaced container: a sysfs
+/// without a network namespace of its own, a procfs with the masked paths of
+/// the outer runtime over it, or a cgroup hierarchy
+fn host_mount(mount_type: &str) -> Option<&'static str> {
+    match mount_type {
+        "proc" => Some("/proc"),
+        "sysfs" => Some("/sys"),
+        "cgroup" | "cgroup2" => Some("/sys/fs/cgroup"),
+        "mqueue" => Some("/dev/mqueue"),
+        _ => None,
+    }
+}
+
+/// Flag of a mount option, and whether the option clears it
+fn mount_flag(option: &str) -> Option<(MsFlags, bool)> {
+    let flag = match option {
+        "ro" => (MsFlags::MS_RDONLY, false),
+        "rw" => (MsFlags::MS_RDONLY, true),
+        "nosuid" => (MsFlags::MS_NOSUID, false),
+        "suid" => (MsFlags::MS_NOSUID, true),
+        "nodev" => (MsFlags::MS_NODEV, false),
+        "dev" => (MsFlags::MS_NODEV, true),
+        "noexec" => (MsFlags::MS_NOEXEC, false),
+        "exec" => (MsFlags::MS_NOEXEC, true),
+        "sync" => (MsFlags::MS_SYNCHRONOUS, false),
+        "async" => (MsFlags::MS_SYNCHRONOUS, true),
+        "dirsync" => (MsFlags::MS_DIRSYNC, false),
+        "remount" => (MsFlags::MS_REMOUNT, false),
+        "mand" => (MsFlags::MS_MANDLOCK, false),
+        "nomand" => (MsFlags::MS_MANDLOCK, true),
+        "noatime" => (MsFlags::MS_NOATIME, false),
+        "atime" => (MsFlags::MS_NOATIME, true),
+        "nodiratime" => (MsFlags::MS_NODIRATIME, false),
+        "diratime" => (MsFlags::MS_NODIRATIME, true),
+        "relatime" => (MsFlags::MS_RELATIME, false),
+        "norelatime" => (MsFlags::MS_RELATIME, true),
+        "strictatime" => (MsFlags::MS_STRICTATIME, false),
+        "nostrictatime" => (MsFlags::MS_STRICTATIME, true),
+        "bind" => (MsFlags::MS_BIND, false),
+        "rbind" => (MsFlags::MS_BIND | MsFlags::MS_REC, false),
+        _ => return None,
+    };
+    Some(flag)
+}
+
+/// Propagation type of a mount option
+fn mount_propagation(option: &str) -> Option<MsFlags> {
+    let propagation = match option {
+        "private" => MsFlags::MS_PRIVATE,
+        "rprivate" => MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+        "shared" => MsFlags::MS_SHARED,
+        "rshared" => MsFlags::MS_SHARED | MsFlags::MS_REC,
+        "slave" => MsFlags::MS_SLAVE,
+        "rslave" => MsFlags::MS_SLAVE | MsFlags::MS_REC,
+        "unbindable" => MsFlags::MS_UNBINDABLE,
+        "runbindable" => MsFlags::MS_UNBINDABLE | MsFlags::MS_REC,
+        _ => return None,
+    };
+    Some(propagation)
+}
+
+/// Flags, propagation and data of the options of a mount. The options that
+/// are neither are the filesystem's own, passed as the data of mount(2), like
+/// `mode=755` of a tmpfs or `addr=` of nfs.
+fn parse_options(options: &[String]) -> (MsFlags, MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut propagation = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options {
+        if let Some((flag, clear)) = mount_flag(option) {
+            flags.set(flag, !clear);
+        } else if let Some(flag) = mount_propagation(option) {
+            propagation |= flag;
+        } else {
+            data.push(option.as_str());
+        }
+    }
+    (flags, propagation, data.join(","))
+}
+
+/// Checks the values of the procfs options restricting what the container
+/// sees of the processes, `hidepid=`, `subset=pid` and `gid=`
+pub fn check_proc_options(options: &[String]) -> Result<()> {
+    let (_, _, data) = parse_options(options);
+    for option in data.split(',') {
+        let valid = match option.split_once('=') {
+            Some(("hidepid", value)) => HIDEPID_VALUES.contains(&value),
+            Some(("subset", value)) => value == "pid",
+            Some(("gid", value)) => value.parse::<u32>().is_ok(),
+            _ => continue,
+        };
+        if !valid {
+            return Err(Error::Bundle(format!("invalid proc option {:?}", option)));
+        }
+    }
     Ok(())
 }
 
-pub fn mount_devices(mounts: &Vec<Mount>, rootfs: &Path) -> Result<()> {
+/// Mount data without the `subset=` option, which kernels before 5.8 reject,
+/// None if it had none
+fn without_subset(data: &str) -> Option<String> {
+    let options: Vec<&str> = data.split(',').collect();
+    let kept: Vec<&str> = options
+        .iter()
+        .copied()
+        .filter(|option| !option.starts_with("subset="))
+        .collect();
+    (kept.len() < options.len()).then(|| kept.join(","))
+}
+
+/// Mounts the mounts of the spec under the rootfs, labeled with the labels
+/// when SELinux is enabled
+pub fn mount_devices(
+    mounts: &Vec<Mount>,
+    rootfs: &Path,
+    labels: Option<&MountLabels>,
+) -> Result<()> {
     for m in mounts {
-        let mut flags = MsFlags::empty();
+        let options = m.options.as_deref().unwrap_or_default();
+        let (mut flags, propagation, mut data) = parse_options(options);
+        if let Some(labels) = labels {
+            let mount_type = m.mount_type.as_deref().unwrap_or_default();
+            if let Some(context) = labels.context_option(mount_type, options) {
+                data = match data.is_empty() {
+                    true => context,
+                    false => format!("{},{}", data, context),
+                };
+            }
+            if mount_type == "bind" {
+                labels.relabel(m)?;
+            }
+        }
 
         let dest = rootfs.join(m.destination.trim_start_matches("/"));
 
         if !std::path::Path::new(&dest).exists() {
             std::fs::create_dir_all(&dest)
-                .map_err(|err| Error { msg: format!("{}", err), err_type: ErrorType::Container })?;
+                .map_err(Error::io(format!("unable to create mount point {:?}", dest)))?;
         }
 
         if m.mount_type.as_ref().unwrap() == "bind" {
             flags |= MsFlags::MS_BIND;
         }
 
+        trace!(
+            "mount {:?} on {:?} type {:?} flags {:?} data {:?}",
+            m.source, dest, m.mount_type, flags, data
+        );
         match mount::<str, PathBuf, str, str>(
             Some(m.source.as_ref().unwrap().as_str()),
             &dest,
             Some(m.mount_type.as_ref().unwrap().as_str()),
             flags,
-            None::<&str>,
-        ) {
+            Some(data.as_str()).filter(|data| !data.is_empty()),
+        )
+        .or_else(|err| match (err.as_errno(), without_subset(&data)) {
+            (Some(Errno::EINVAL), Some(data)) if m.mount_type.as_deref() == Some("proc") => {
+                warn!(
+                    "the kernel doesn't support subset=pid, mounting {} without it",
+                    m.destination
+                );
+                mount::<str, PathBuf, str, str>(
+                    Some(m.source.as_ref().unwrap().as_str()),
+                    &dest,
+                    Some("proc"),
+                    flags,
+                    Some(data.as_str()).filter(|data| !data.is_empty()),
+                )
+            }
+            _ => Err(err),
+        }) {
             Ok(_) => (),
             Err(err) => {
-                // Skip if the device is busy
-                // This happens with the cgroup mount
-                if err.as_errno() != Some(Errno::EBUSY) {
-                    return Err(Error {
-                        msg: format!("mount device failed {}", err),
-                        err_type: ErrorType::Runtime,
-                    });
+                let mount_type = m.mount_type.as_deref().unwrap_or_default();
+                match (err.as_errno(), host_mount(mount_type)) {
+                    // Skip if the device is busy
+                    // This happens with the cgroup mount
+                    (Some(Errno::EBUSY), _) => {
+                        debug!("skipping busy mount {:?}", dest);
+                        continue;
+                    }
+                    (Some(Errno::EPERM), Some(source)) => {
+                        warn!(
+                            "unable to mount a new {} on {}, binding {} instead",
+                            mount_type, m.destination, source
+                        );
+                        if !data.is_empty() {
+                            warn!("the options {} of {} are ignored", data, m.destination);
+                        }
+                        flags |= MsFlags::MS_BIND | MsFlags::MS_REC;
+                        mount::<str, PathBuf, str, str>(
+                            Some(source),
+                            &dest,
+                            None,
+                            MsFlags::MS_BIND | MsFlags::MS_REC,
+                            None,
+                        )
+                        .map_err(Error::sys(format!("bind of {} failed", source)))?;
+                    }
+                    _ => return Err(Error::sys(format!("mount {} failed", m.destination))(err)),
                 }
             }
         };
+
+        // A bind mount ignores the other flags until it's remounted
+        let bind_flags = flags - MsFlags::MS_BIND - MsFlags::MS_REC;
+        if flags.contains(MsFlags::MS_BIND) && !bind_flags.is_empty() {
+            mount::<str, PathBuf, str, str>(
+                None,
+                &dest,
+                None,
+                bind_flags | MsFlags::MS_BIND | MsFlags::MS_REMOUNT,
+                None,
+            )
+            .map_err(Error::sys(format!("remount {} failed", m.destination)))?;
+        }
+        if !propagation.is_empty() {
+            mount::<str, PathBuf, str, str>(None, &dest, None, propagation, None)
+                .map_err(Error::sys(format!("propagation of {} failed", m.destination)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Filesystem a path under /proc or /sys must be on
+fn expected_fs(path: &Path) -> Option<(FsType, &'static str)> {
+    if path.starts_with("/proc") {
+        Some((PROC_SUPER_MAGIC, "procfs"))
+    } else if path.starts_with("/sys") {
+        Some((FsType(libc::SYSFS_MAGIC as _), "sysfs"))
+    } else {
+        None
+    }
+}
+
+/// Path of the opened file, to mount on the file itself rather than on
+/// whatever the path resolves to by the time of the mount
+fn fd_path(file: &File) -> String {
+    format!("/proc/self/fd/{}", file.as_raw_fd())
+}
+
+/// Checks the opened file is the path itself and not something a symlink
+/// led to, and that a path under /proc or /sys is on the procfs or sysfs
+/// rather than on a filesystem of the rootfs faking it (CVE-2019-16884).
+fn verify(file: &File, path: &str) -> Result<()> {
+    let resolved = std::fs::read_link(fd_path(file))
+        .map_err(Error::io(format!("unable to resolve {}", path)))?;
+    if resolved != Path::new(path) {
+        return Err(Error::Runtime(format!(
+            "{} resolves to {:?}",
+            path, resolved
+        )));
+    }
+    if let Some((magic, name)) = expected_fs(Path::new(path)) {
+        let fs = fstatfs(file).map_err(Error::sys(format!("unable to statfs {}", path)))?;
+        if fs.filesystem_type() != magic {
+            return Err(Error::Runtime(format!(
+                "{} isn't on {} but on a {:#x} filesystem",
+                path,
+                name,
+                fs.filesystem_type().0
+            )));
+        }
     }
     Ok(())
 }
+
+/// Opens a path of the container without following a symlink, none when
+/// the path doesn't exist
+fn open_verified(path: &str) -> Result<Option<File>> {
+    let file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::io(format!("unable to open {}", path))(err)),
+    };
+    let metadata = file
+        .metadata()
+        .map_err(Error::io(format!("unable to stat {}", path)))?;
+    if metadata.file_type().is_symlink() {
+        return Err(Error::Runtime(format!("{} is a symlink", path)));
+    }
+    verify(&file, path)?;
+    Ok(Some(file))
+}
+
+/// Makes the readonly paths read-only and hides the masked paths of the
+/// spec, once in the rootfs. Paths the container doesn't have are skipped.
+pub fn mask_paths(linux: &Linux) -> Result<()> {
+    for path in linux.readonly_paths.iter().flatten() {
+        readonly_path(path)?;
+    }
+    for path in linux.masked_paths.iter().flatten() {
+        mask_path(path)?;
+    }
+    Ok(())
+}
+
+fn readonly_path(path: &str) -> Result<()> {
+    let file = match open_verified(path)? {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    trace!("bind mounting {} read-only", path);
+    mount(
+        Some(fd_path(&file).as_str()),
+        fd_path(&file).as_str(),
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .map_err(Error::sys(format!("unable to bind mount {}", path)))?;
+
+    // Remount the new bind mount, keeping the flags a user namespace locks
+    let file = open_verified(path)?
+        .ok_or_else(|| Error::Runtime(format!("{} vanished while mounting", path)))?;
+    let locked = fstatvfs(&file)
+        .map_err(Error::sys(format!("unable to statvfs {}", path)))?
+        .flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+    ] {
+        if locked.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(
+        None::<&str>,
+        fd_path(&file).as_str(),
+        None::<&str>,
+        flags,
+        None::<&str>,
+    )
+    .map_err(Error::sys(format!("unable to remount {} read-only", path)))
+}
+
+fn mask_path(path: &str) -> Result<()> {
+    let file = match open_verified(path)? {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let metadata = file
+        .metadata()
+        .map_err(Error::io(format!("unable to stat {}", path)))?;
+    trace!("masking {}", path);
+    if metadata.is_dir() {
+        mount(
+            Some("tmpfs"),
+            fd_path(&file).as_str(),
+            Some("tmpfs"),
+            MsFlags::MS_RDONLY,
+            None::<&str>,
+        )
+        .map_err(Error::sys(format!("unable to mask {}", path)))
+    } else {
+        // The null device of the rootfs could be anything
+        let null = open_verified("/dev/null")?
+            .ok_or_else(|| Error::Runtime("no /dev/null to mask with".to_string()))?;
+        let null_metadata = null
+            .metadata()
+            .map_err(Error::io("unable to stat /dev/null"))?;
+        if !null_metadata.file_type().is_char_device() || null_metadata.rdev() != makedev(1, 3) {
+            return Err(Error::Runtime("/dev/null isn't the null device".to_string()));
+        }
+        mount(
+            Some(fd_path(&null).as_str()),
+            fd_path(&file).as_str(),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(Error::sys(format!("unable to mask {}", path)))
+    }
+}
+
+/// Resolves the executable like execvp would: a name with a slash relative
+/// to the working directory, others against the PATH. Unlike execvp, the
+/// error tells whether the executable is missing or can't be executed.
+pub fn find_executable(name: &str, path: Option<&str>, cwd: &Path) -> Result<PathBuf> {
+    let executable = |candidate: &Path| {
+        std::fs::metadata(candidate).map(|metadata| {
+            (
+                metadata.is_file(),
+                metadata.permissions().mode() & 0o111 != 0,
+            )
+        })
+    };
+
+    if name.contains('/') {
+        let candidate = cwd.join(name);
+        return match executable(&candidate) {
+            Ok((true, true)) => Ok(candidate),
+            Ok((false, _)) => Err(Error::Runtime(format!("{}: is a directory", name))),
+            Ok((true, false)) => Err(Error::Runtime(format!("{}: permission denied", name))),
+            Err(_) => Err(Error::Runtime(format!("{}: no such file or directory", name))),
+        };
+    }
+
+    let mut denied = false;
+    for dir in path.unwrap_or(DEFAULT_PATH).split(':') {
+        // An empty entry is the working directory
+        let candidate = cwd.join(dir).join(name);
+        match executable(&candidate) {
+            Ok((true, true)) => return Ok(candidate),
+            Ok((true, false)) => denied = true,
+            _ => (),
+        }
+    }
+    match denied {
+        true => Err(Error::Runtime(format!("{}: permission denied", name))),
+        false => Err(Error::Runtime(format!(
+            "{}: executable file not found in $PATH",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path};
+
+    use nix::mount::MsFlags;
+
+    use super::{
+        check_proc_options, find_executable, host_mount, open_verified, parse_device,
+        parse_options, verify, without_subset,
+    };
+
+    #[test]
+    fn executable_in_path() {
+        let root = Path::new("./executable_test");
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::create_dir_all(root.join("sbin")).unwrap();
+        std::fs::write(root.join("sbin/app"), "").unwrap();
+        std::fs::set_permissions(root.join("sbin/app"), Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(root.join("bin/data"), "").unwrap();
+        std::fs::set_permissions(root.join("bin/data"), Permissions::from_mode(0o644)).unwrap();
+
+        let path = Some("bin:sbin");
+        assert_eq!(
+            find_executable("app", path, root).unwrap(),
+            root.join("sbin/app")
+        );
+        assert_eq!(
+            find_executable("./sbin/app", None, root).unwrap(),
+            root.join("./sbin/app")
+        );
+        let err = find_executable("data", path, root).unwrap_err().to_string();
+        assert!(err.contains("permission denied"), "{}", err);
+        let err = find_executable("missing", path, root).unwrap_err().to_string();
+        assert!(err.contains("not found in $PATH"), "{}", err);
+        let err = find_executable("./bin", path, root).unwrap_err().to_string();
+        assert!(err.contains("is a directory"), "{}", err);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn verified_paths() {
+        assert!(open_verified("/proc/version").unwrap().is_some());
+        assert!(open_verified("/proc/missing").unwrap().is_none());
+
+        // A file of another filesystem posing as one of /proc
+        let root = std::env::current_dir().unwrap().join("verify_test");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file"), "").unwrap();
+        let file = open_verified(root.join("dir/file").to_str().unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(verify(&file, root.join("dir/file").to_str().unwrap()).is_ok());
+        let err = verify(&file, "/proc/kcore").unwrap_err().to_string();
+        assert!(err.contains("resolves to"), "{}", err);
+
+        // Symlinks are never followed
+        std::os::unix::fs::symlink(root.join("dir"), root.join("link")).unwrap();
+        assert!(open_verified(root.join("link/file").to_str().unwrap()).is_err());
+        assert!(open_verified(root.join("link").to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn mount_options() {
+        let options: Vec<String> = [
+            "nosuid", "ro", "rw", "rbind", "rslave", "mode=755", "size=64k",
+        ]
+        .iter()
+        .map(|option| option.to_string())
+        .collect();
+        let (flags, propagation, data) = parse_options(&options);
+        assert_eq!(flags, MsFlags::MS_NOSUID | MsFlags::MS_BIND | MsFlags::MS_REC);
+        assert_eq!(propagation, MsFlags::MS_SLAVE | MsFlags::MS_REC);
+        assert_eq!(data, "mode=755,size=64k");
+
+        let (flags, propagation, data) = parse_options(&[]);
+        assert!(flags.is_empty() && propagation.is_empty() && data.is_empty());
+    }
+
+    #[test]
+    fn proc_options() {
+        let options = |options: &[&str]| -> Vec<String> {
+            options.iter().map(|option| option.to_string()).collect()
+        };
+        for valid in [
+            &["nosuid", "hidepid=2", "subset=pid"][..],
+            &["hidepid=invisible", "gid=10"],
+            &["noexec"],
+        ] {
+            assert!(check_proc_options(&options(valid)).is_ok(), "{:?}", valid);
+        }
+        for invalid in ["hidepid=3", "subset=net", "gid=wheel"] {
+            assert!(check_proc_options(&options(&[invalid])).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(
+            without_subset("hidepid=2,subset=pid").as_deref(),
+            Some("hidepid=2")
+        );
+        assert_eq!(without_subset("subset=pid").as_deref(), Some(""));
+        assert_eq!(without_subset("hidepid=2"), None);
+    }
+
+    #[test]
+    fn nested_fallbacks() {
+        assert_eq!(host_mount("proc"), Some("/proc"));
+        assert_eq!(host_mount("sysfs"), Some("/sys"));
+        assert_eq!(host_mount("cgroup2"), Some("/sys/fs/cgroup"));
+        assert_eq!(host_mount("tmpfs"), None);
+        assert_eq!(host_mount("bind"), None);
+    }
+
+    #[test]
+    fn device_args() {
+        let (device, access) = parse_device("/dev/null").unwrap();
+        assert_eq!(device.path, "/dev/null");
+        assert_eq!((device.device_type.as_str(), device.major, device.minor), ("c", 1, 3));
+        assert_eq!(access, "rwm");
+
+        let (device, access) = parse_device("/dev/zero:/dev/hot0:r").unwrap();
+        assert_eq!((device.path.as_str(), device.minor), ("/dev/hot0", 5));
+        assert_eq!(access, "r");
+
+        assert!(parse_device("/dev/null:dev/hot0").is_err());
+        assert!(parse_device("/dev/null:/dev/hot0:rx").is_err());
+        assert!(parse_device("/etc/hostname").is_err());
+        assert!(parse_device("/dev/missing").is_err());
+    }
+}
@@ -1,10 +1,258 @@
+use log::{debug, trace};
 use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    libc,
+    poll::{poll, PollFd, PollFlags},
     sched::{clone, CloneFlags},
     sys::signal::{kill, Signal},
-    unistd::Pid,
+    unistd::{close, getppid, Pid},
 };
+use std::{
+    convert::TryFrom,
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr, thread,
+    time::Duration,
+};
+
+use crate::{core::common::{Error, Result}, oci::spec::Namespace};
+
+const P_PIDFD: libc::idtype_t = 3;
+/// Flag of close_range(2) marking the fds close-on-exec instead (Linux 5.11)
+const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+/// _IOWR(PIDFS_IOCTL_MAGIC, 11, struct pidfd_info) of pidfs (Linux 6.13), 0xC040FF0B
+/// except on powerpc and mips, which lay out the direction bits differently
+const PIDFD_GET_INFO: libc::Ioctl =
+    nix::request_code_readwrite!(0xFF, 11, mem::size_of::<PidfdInfo>()) as libc::Ioctl;
+/// pidfd_info.mask flag of the exit status (Linux 6.15)
+const PIDFD_INFO_EXIT: u64 = 1 << 3;
+/// Polling of the exit of a process without pidfd (before Linux 5.3)
+pub(crate) const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Time given to the parent of an exited process to reap it
+const REAP_RETRIES: usize = 100;
+const REAP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// struct pidfd_info of pidfs, up to the exit code
+#[repr(C)]
+#[derive(Default)]
+struct PidfdInfo {
+    mask: u64,
+    cgroupid: u64,
+    pid: u32,
+    tgid: u32,
+    ppid: u32,
+    ruid: u32,
+    rgid: u32,
+    euid: u32,
+    egid: u32,
+    suid: u32,
+    sgid: u32,
+    fsuid: u32,
+    fsgid: u32,
+    exit_code: i32,
+}
+
+/// struct clone_args of clone3(2), up to the cgroup field
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
+
+/// Process signaled and reaped through its pidfd, so a reused pid is never hit.
+/// Kernels without pidfds (before 5.3) fall back to the pid.
+#[derive(Debug)]
+pub struct Process {
+    pid: Pid,
+    pidfd: Option<OwnedFd>,
+}
+
+impl Process {
+    /// Opens the process of the pid.
+    /// When the start time is given, a process started at another time is
+    /// another process reusing the pid and is reported as gone.
+    pub fn open(pid: Pid, start_time: Option<u64>) -> Result<Process> {
+        // kill(2) would signal the whole process group (0) or every process (-1)
+        if pid.as_raw() <= 0 {
+            return Err(Error::State(format!("container has no process (pid {})", pid)));
+        }
+        let pidfd = match pidfd_open(pid) {
+            Ok(pidfd) => Some(pidfd),
+            Err(Errno::ENOSYS) => None,
+            Err(errno) => return Err(Error::sys("pidfd_open failed")(nix::Error::Sys(errno))),
+        };
+        // Checked after opening the pidfd, it keeps referring to the same process
+        if let Some(start_time) = start_time {
+            if self::start_time(pid) != Some(start_time) {
+                return Err(Error::sys(format!("process {} is gone", pid))(
+                    nix::Error::Sys(Errno::ESRCH),
+                ));
+            }
+        }
+        Ok(Process { pid, pidfd })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn pidfd(&self) -> Option<RawFd> {
+        self.pidfd.as_ref().map(|pidfd| pidfd.as_raw_fd())
+    }
+
+    pub fn signal(&self, sig: i32) -> Result<()> {
+        debug!("sending signal {} to {}", sig, self.pid);
+        let signal = to_signal(sig)?;
+        match &self.pidfd {
+            Some(pidfd) => {
+                let res = unsafe {
+                    libc::syscall(
+                        libc::SYS_pidfd_send_signal,
+                        pidfd.as_raw_fd(),
+                        signal as libc::c_int,
+                        ptr::null::<libc::siginfo_t>(),
+                        0,
+                    )
+                };
+                Errno::result(res).map_err(Error::sys("error signal"))?;
+            }
+            None => kill(self.pid, signal).map_err(Error::sys("error signal"))?,
+        }
+        Ok(())
+    }
+
+    /// Reaps the process if it exited, returning its exit code
+    /// (128 + signal number when killed by a signal).
+    /// Processes that aren't children of the current process are never reaped.
+    pub fn try_wait(&self) -> Result<Option<i32>> {
+        let (idtype, id) = match &self.pidfd {
+            Some(pidfd) => (P_PIDFD, pidfd.as_raw_fd() as libc::id_t),
+            None => (libc::P_PID, self.pid.as_raw() as libc::id_t),
+        };
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let res = unsafe { libc::waitid(idtype, id, &mut info, libc::WEXITED | libc::WNOHANG) };
+        match Errno::result(res) {
+            Ok(_) => (),
+            Err(nix::Error::Sys(Errno::ECHILD)) => return Ok(None),
+            Err(err) => return Err(Error::sys("error polling pid status")(err)),
+        }
 
-use crate::{core::common::{Error, ErrorType, Result}, oci::spec::Namespace};
+        // Nothing to reap yet
+        let child_pid = unsafe { info.si_pid() };
+        if child_pid == 0 {
+            return Ok(None);
+        }
+        let status = unsafe { info.si_status() };
+        match info.si_code {
+            libc::CLD_EXITED => Ok(Some(status)),
+            libc::CLD_KILLED | libc::CLD_DUMPED => Ok(Some(128 + status)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Blocks until the process exits, without reaping it
+    pub fn wait_exit(&self) -> Result<()> {
+        let pidfd = match &self.pidfd {
+            Some(pidfd) => pidfd.as_raw_fd(),
+            None => {
+                while !exited(self.pid) {
+                    thread::sleep(EXIT_POLL_INTERVAL);
+                }
+                return Ok(());
+            }
+        };
+        // The pidfd becomes readable once the process exits
+        let mut fds = [PollFd::new(pidfd, PollFlags::POLLIN)];
+        loop {
+            match poll(&mut fds, -1) {
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                result => return result.map(drop).map_err(Error::sys("error polling pidfd")),
+            }
+        }
+    }
+
+    /// Exit code of the exited process (128 + signal number when killed by a
+    /// signal), reaping it if it's a child of the current process. The exit code
+    /// of another process comes from /proc while it's a zombie, then from pidfs
+    /// once its parent reaped it (Linux 6.15).
+    pub fn exit_code(&self) -> Result<Option<i32>> {
+        if let Some(exit_code) = self.try_wait()? {
+            return Ok(Some(exit_code));
+        }
+        for _ in 0..REAP_RETRIES {
+            let status = self
+                .pidfd()
+                .and_then(pidfd_exit_status)
+                .or_else(|| zombie_exit_status(self.pid));
+            if let Some(status) = status {
+                return Ok(exit_code(status));
+            }
+            thread::sleep(REAP_INTERVAL);
+        }
+        Ok(None)
+    }
+}
+
+/// Wait status of the reaped process of the pidfd
+fn pidfd_exit_status(pidfd: RawFd) -> Option<i32> {
+    let mut info = PidfdInfo {
+        mask: PIDFD_INFO_EXIT,
+        ..Default::default()
+    };
+    let res = unsafe { libc::ioctl(pidfd, PIDFD_GET_INFO, &mut info as *mut PidfdInfo) };
+    if res < 0 || info.mask & PIDFD_INFO_EXIT == 0 {
+        return None;
+    }
+    Some(info.exit_code)
+}
+
+/// Wait status of the process if it's a zombie, the 52nd field of /proc/<pid>/stat
+fn zombie_exit_status(pid: Pid) -> Option<i32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, rest) = stat.rsplit_once(')')?;
+    let mut fields = rest.split_whitespace();
+    if fields.next()? != "Z" {
+        return None;
+    }
+    fields.nth(48)?.parse().ok()
+}
+
+fn exit_code(status: i32) -> Option<i32> {
+    if libc::WIFEXITED(status) {
+        Some(libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        Some(128 + libc::WTERMSIG(status))
+    } else {
+        None
+    }
+}
+
+fn pidfd_open(pid: Pid) -> std::result::Result<OwnedFd, Errno> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        return Err(Errno::last());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Start time of the process, in clock ticks after boot
+pub fn start_time(pid: Pid) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // starttime is the 22nd field, the 20th after the command name
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
 
 fn to_flags(namespace: &Namespace) -> CloneFlags {
     match namespace.namespace.as_str() {
@@ -19,12 +267,13 @@ fn to_flags(namespace: &Namespace) -> CloneFlags {
     }
 }
 
-pub fn clone_child(child_fun: impl FnMut() -> isize, namespaces: &Vec<Namespace>) -> Result<nix::unistd::Pid> {
-    const STACK_SIZE: usize = 4 * 1024 * 1024; // 4 MB
-    let ref mut stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-    let spec_namespaces = namespaces.into_iter()
-        .map(|ns| to_flags(ns))
+pub fn clone_child(mut child_fun: impl FnMut() -> isize, namespaces: &[Namespace]) -> Result<Process> {
+    // Those with a path are joined by the child rather than created: a new
+    // user namespace would lose the rights to join the others, e.g. when
+    // pura itself runs in a user-namespaced container
+    let spec_namespaces = namespaces.iter()
+        .filter(|namespace| namespace.path.is_none())
+        .map(to_flags)
         .reduce(|a, b| a | b);
 
     let clone_flags = match spec_namespaces {
@@ -32,30 +281,285 @@ pub fn clone_child(child_fun: impl FnMut() -> isize, namespaces: &Vec<Namespace>
         None => CloneFlags::empty(),
     };
 
+    debug!("clone3 child with flags {:?}", clone_flags);
+    let mut pidfd: RawFd = -1;
+    let mut args = CloneArgs {
+        flags: clone_flags.bits() as u64 | libc::CLONE_PIDFD as u64,
+        pidfd: &mut pidfd as *mut RawFd as u64,
+        exit_signal: libc::SIGCHLD as u64,
+        ..Default::default()
+    };
+    // Without a stack the child runs on a copy of the stack of the parent, like fork(2)
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_clone3,
+            &mut args as *mut CloneArgs,
+            mem::size_of::<CloneArgs>(),
+        )
+    };
+    trace!("clone3 returned {}", res);
+    match Errno::result(res) {
+        Ok(0) => {
+            let code = child_fun();
+            unsafe { libc::_exit(code as i32) }
+        }
+        Ok(pid) => Ok(Process {
+            pid: Pid::from_raw(pid as i32),
+            pidfd: Some(unsafe { OwnedFd::from_raw_fd(pidfd) }),
+        }),
+        // Kernels before 5.3, or seccomp filters not knowing clone3
+        Err(nix::Error::Sys(Errno::ENOSYS)) | Err(nix::Error::Sys(Errno::EPERM)) => {
+            clone_legacy(child_fun, clone_flags)
+        }
+        Err(err) => Err(Error::sys("error clone3()")(err)),
+    }
+}
+
+fn clone_legacy(child_fun: impl FnMut() -> isize, clone_flags: CloneFlags) -> Result<Process> {
+    const STACK_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+    let stack: &mut [u8; STACK_SIZE] = &mut [0; STACK_SIZE];
+
+    debug!("clone child with flags {:?}", clone_flags);
     let child = clone(Box::new(child_fun), stack, clone_flags, None);
+    trace!("clone returned {:?}", child);
 
-    return child.map_err(|err| Error {
-        msg: format!("error clone(): {}", err.to_string()),
-        err_type: ErrorType::Runtime,
-    });
+    let pid = child.map_err(Error::sys("error clone()"))?;
+    Ok(Process {
+        pid,
+        pidfd: pidfd_open(pid).ok(),
+    })
 }
 
+/// Signals the process of the pid, through a pidfd when possible
 pub fn signal(pid: Pid, sig: i32) -> Result<()> {
-    kill(pid, to_signal(sig)).map_err(|err| Error {
-        msg: format!("error signal {}", err.to_string()),
-        err_type: ErrorType::Runtime,
-    })?;
+    Process::open(pid, None)?.signal(sig)
+}
+
+/// Whether the process is gone, or a zombie nobody reaped yet
+pub fn exited(pid: Pid) -> bool {
+    if let Err(nix::Error::Sys(Errno::ESRCH)) = kill(pid, None) {
+        return true;
+    }
+    // The state follows the parenthesized command name in /proc/<pid>/stat
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.trim_start().starts_with('Z'))
+            .unwrap_or(false),
+        Err(_) => true,
+    }
+}
+
+/// Marks every fd from the first one close-on-exec, so sockets, the pty
+/// master or log files of the runtime never leak into the container process.
+/// They're closed by the exec only, the errors of a failed exec can still be
+/// reported through them. Uses close_range, /proc/self/fd on older kernels.
+pub fn close_fds_on_exec(first: RawFd) -> Result<()> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            first as libc::c_uint,
+            libc::c_uint::MAX,
+            CLOSE_RANGE_CLOEXEC,
+        )
+    };
+    match Errno::result(res) {
+        Ok(_) => Ok(()),
+        // Before Linux 5.9, or 5.11 for the flag
+        Err(nix::Error::Sys(Errno::ENOSYS)) | Err(nix::Error::Sys(Errno::EINVAL)) => {
+            cloexec_proc_fds(first)
+        }
+        Err(err) => Err(Error::sys("close_range failed")(err)),
+    }
+}
+
+fn cloexec_proc_fds(first: RawFd) -> Result<()> {
+    // Collected first, the directory has an fd of its own
+    let fds: Vec<RawFd> = std::fs::read_dir("/proc/self/fd")
+        .map_err(Error::io("unable to list /proc/self/fd"))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|fd| *fd >= first)
+        .collect();
+    for fd in fds {
+        // The fd of the closed directory is gone
+        match fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            Ok(_) | Err(nix::Error::Sys(Errno::EBADF)) => (),
+            Err(err) => {
+                return Err(Error::sys(format!("unable to mark fd {} close-on-exec", fd))(err))
+            }
+        }
+    }
     Ok(())
 }
 
-fn to_signal(sig: i32) -> Signal {
-    match sig {
-        1 => Signal::SIGHUP,
-        2 => Signal::SIGINT,
-        6 => Signal::SIGABRT,
-        9 => Signal::SIGKILL,
-        15 => Signal::SIGTERM,
-        17 => Signal::SIGCHLD,
-        _ => panic!("unknown signal"),
+/// Sets whether the current process is dumpable. One that isn't can't be
+/// ptraced and its /proc files belong to root, so the processes of a
+/// container it joins can't reach into the runtime. The exec of the
+/// container process makes it dumpable again.
+pub fn set_dumpable(dumpable: bool) -> Result<()> {
+    let res = unsafe { libc::prctl(libc::PR_SET_DUMPABLE, dumpable as libc::c_ulong, 0, 0, 0) };
+    Errno::result(res)
+        .map(drop)
+        .map_err(Error::sys("unable to set the dumpable flag"))
+}
+
+/// Sets no_new_privs, so the exec of the process and of its children never
+/// grants privileges, like setuid binaries would. It can't be unset.
+pub fn set_no_new_privileges() -> Result<()> {
+    let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(res)
+        .map(drop)
+        .map_err(Error::sys("unable to set no_new_privs"))
+}
+
+/// Has the current process killed by the signal once its parent exits, so
+/// an intermediate process never outlives the runtime. Fails when the
+/// parent already exited before the signal was armed.
+pub fn set_parent_death_signal(signal: Signal, parent: Pid) -> Result<()> {
+    let res = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, signal as libc::c_ulong, 0, 0, 0) };
+    Errno::result(res).map_err(Error::sys("unable to set the parent death signal"))?;
+    if getppid() != parent {
+        return Err(Error::Runtime(format!("parent {} already exited", parent)));
+    }
+    Ok(())
+}
+
+fn to_signal(sig: i32) -> Result<Signal> {
+    Signal::try_from(sig).map_err(Error::sys(format!("unknown signal {}", sig)))
+}
+
+/// Closes every fd from the first one, for the container processes that
+/// don't exec
+pub fn close_fds(first: RawFd) -> Result<()> {
+    let res = unsafe {
+        libc::syscall(libc::SYS_close_range, first as libc::c_uint, libc::c_uint::MAX, 0)
+    };
+    match Errno::result(res) {
+        Ok(_) => Ok(()),
+        // Before Linux 5.9
+        Err(nix::Error::Sys(Errno::ENOSYS)) => {
+            let fds: Vec<RawFd> = std::fs::read_dir("/proc/self/fd")
+                .map_err(Error::io("unable to list /proc/self/fd"))?
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter(|fd| *fd >= first)
+                .collect();
+            for fd in fds {
+                let _ = close(fd);
+            }
+            Ok(())
+        }
+        Err(err) => Err(Error::sys("close_range failed")(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Command, thread, time::Duration};
+
+    use nix::unistd::Pid;
+
+    use nix::{
+        fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
+        libc,
+        sys::{
+            signal::Signal,
+            stat::Mode,
+            wait::{waitpid, WaitStatus},
+        },
+        unistd::{close, fork, getpid, ForkResult},
+    };
+
+    use super::{
+        cloexec_proc_fds, close_fds_on_exec, set_dumpable, set_parent_death_signal, start_time,
+        CloneArgs, PidfdInfo, Process, PIDFD_GET_INFO,
+    };
+
+    #[test]
+    fn signal_and_reap() {
+        // Reaped through the pidfd
+        let pid = Command::new("sleep").arg("60").spawn().unwrap().id();
+        let pid = Pid::from_raw(pid as i32);
+
+        let start = start_time(pid).unwrap();
+        // Another process reusing the pid starts at another time
+        assert!(Process::open(pid, Some(start + 1)).is_err());
+
+        let process = Process::open(pid, Some(start)).unwrap();
+        assert_eq!(process.try_wait().unwrap(), None);
+        process.signal(9).unwrap();
+
+        let mut exit_code = None;
+        for _ in 0..100 {
+            exit_code = process.try_wait().unwrap();
+            if exit_code.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(exit_code, Some(128 + 9));
+    }
+
+    #[test]
+    fn wait_other_process() {
+        // The subshell outlives its parent, it isn't a child of the test
+        let output = Command::new("sh")
+            .args(["-c", "(sleep 0.2; exit 7) & echo $!"])
+            .output()
+            .unwrap();
+        let pid: i32 = String::from_utf8_lossy(&output.stdout).trim().parse().unwrap();
+
+        let process = Process::open(Pid::from_raw(pid), None).unwrap();
+        process.wait_exit().unwrap();
+        assert_eq!(process.exit_code().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn fds_closed_on_exec() {
+        let cloexec = |fd| {
+            let flags = fcntl(fd, FcntlArg::F_GETFD).unwrap();
+            FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC)
+        };
+        let leaked = || open("/dev/null", OFlag::O_RDONLY, Mode::empty()).unwrap();
+
+        let fd = leaked();
+        assert!(!cloexec(fd));
+        close_fds_on_exec(fd).unwrap();
+        assert!(cloexec(fd));
+        close(fd).unwrap();
+
+        let fd = leaked();
+        cloexec_proc_fds(fd).unwrap();
+        assert!(cloexec(fd));
+        close(fd).unwrap();
+    }
+
+    #[test]
+    fn prctl_flags() {
+        // In a child, the flags of the test process stay as they are
+        let parent = getpid();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let dumpable = || unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) };
+                let ok = set_dumpable(false).is_ok()
+                    && dumpable() == 0
+                    && set_dumpable(true).is_ok()
+                    && dumpable() == 1
+                    && set_parent_death_signal(Signal::SIGKILL, parent).is_ok()
+                    && set_parent_death_signal(Signal::SIGKILL, Pid::from_raw(1)).is_err();
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn kernel_abi() {
+        // CLONE_ARGS_SIZE_VER2 and PIDFD_INFO_SIZE_VER0, the same on every architecture
+        assert_eq!(std::mem::size_of::<CloneArgs>(), 88);
+        assert_eq!(std::mem::size_of::<PidfdInfo>(), 64);
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64"))]
+        assert_eq!(PIDFD_GET_INFO as u32, 0xC040_FF0B);
     }
 }
@@ -1,13 +1,32 @@
-use std::{io::Write, os::unix::prelude::CommandExt, process::Command};
+use lazy_static::lazy_static;
+use std::{io::Write, os::unix::prelude::CommandExt, process::Command, sync::Mutex};
 
 use crate::core::{
-    common::{Error, ErrorType, Result},
+    common::{Error, Result},
     state::State,
 };
 
 use crate::oci::spec::Hook;
 
+lazy_static! {
+    /// Hooks allowed by the host configuration, any hook without one
+    static ref ALLOWED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+}
+
+/// Refuses to run the hooks whose path isn't one of the given paths
+pub fn allow_only(paths: &[String]) {
+    *ALLOWED.lock().unwrap() = Some(paths.to_vec());
+}
+
 pub fn exec_hook(hook: &Hook, state: &State) -> Result<i32> {
+    if let Some(allowed) = ALLOWED.lock().unwrap().as_ref() {
+        if !allowed.contains(&hook.path) {
+            return Err(Error::Runtime(format!(
+                "hook {} isn't allowed by the host configuration",
+                hook.path
+            )));
+        }
+    }
     let mut hook_command = Command::new(&hook.path);
 
     hook_command.env_clear();
@@ -26,37 +45,32 @@ pub fn exec_hook(hook: &Hook, state: &State) -> Result<i32> {
     let mut hook_process: std::process::Child = hook_command
         .stdin(std::process::Stdio::piped())
         .spawn()
-        .map_err(|err| Error {
-            msg: err.to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        .map_err(Error::io(format!("unable to spawn hook {}", hook.path)))?;
 
     // Provide container state to the hook
     // NOTE: the "pid" is the only important field for the libnetwork hook
     if let Some(mut stdin) = hook_process.stdin.as_ref() {
         let state_json = serde_json::to_string(state).unwrap();
-        stdin.write(state_json.as_bytes()).map_err(|_| Error {
-            msg: String::from("error writing to hook stdin"),
-            err_type: ErrorType::Runtime,
-        })?;
+        stdin
+            .write(state_json.as_bytes())
+            .map_err(Error::io("error writing to hook stdin"))?;
     }
 
-    let status = hook_process.wait().map_err(|_| Error {
-        msg: String::from("error hook execution"),
-        err_type: ErrorType::Runtime,
-    })?;
+    let status = hook_process
+        .wait()
+        .map_err(Error::io("error hook execution"))?;
     match status.code() {
         Some(c) => Ok(c),
-        None => Err(Error {
-            msg: String::from("hook interrupted by signal"),
-            err_type: ErrorType::Runtime,
-        }),
+        None => Err(Error::Runtime(format!(
+            "hook {} interrupted by signal",
+            hook.path
+        ))),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
     use crate::{core::{hooks::exec_hook, state::{State, Status}}, oci::spec::Hook};
 
@@ -74,7 +88,7 @@ mod tests {
             oci_version: String::from("1.0.2"),
             status: Status::Created,
             pid: 99999,
-            bundle: String::from("/path/to/bundle"),
+            bundle: PathBuf::from("/path/to/bundle"),
             annotations: None,
         }
     }
@@ -87,7 +101,7 @@ mod tests {
         // Don't unwrap because of state file going to stdin
         let _ = exec_hook(&hook, &state);
 
-        assert_eq!(Path::new("./test_dir").exists(), true);
+        assert!(Path::new("./test_dir").exists());
 
         let hook = gen_hook("rmdir", vec!["rmdir", "./test_dir"]);
         let state = gen_state();
@@ -95,6 +109,6 @@ mod tests {
         // Don't unwrap because of state file going to stdin
         let _ = exec_hook(&hook, &state);
 
-        assert_eq!(Path::new("./test_dir").exists(), false);
+        assert!(!Path::new("./test_dir").exists());
     }
 }
\ No newline at end of file
@@ -0,0 +1,128 @@
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{
+        signal::{kill, SigSet, SigmaskHow, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{execvp, fork, ForkResult, Pid},
+};
+use std::ffi::CString;
+
+/// Exit code of the init when the workload can't be executed, like a shell
+const EXEC_FAILED: i32 = 127;
+
+/// tini-like init of the container: runs the workload as its child, forwards
+/// it the signals, reaps the zombies reparented to PID 1 and exits with the
+/// exit code of the workload (128 + signal number when killed by a signal).
+///
+/// For images whose entrypoint doesn't handle the PID 1 duties. The kernel
+/// drops the signals with the default action sent to PID 1 of a PID namespace,
+/// unless they're blocked: every signal is blocked and received with sigwait.
+pub fn run(exec: &CString, args: &[CString]) -> ! {
+    let mut mask = SigSet::empty();
+    for signal in Signal::iterator().filter(|signal| forwarded(*signal)) {
+        mask.add(signal);
+    }
+    let old_mask = match mask.thread_swap_mask(SigmaskHow::SIG_BLOCK) {
+        Ok(old_mask) => old_mask,
+        Err(err) => exit(1, &format!("unable to block signals: {}", err)),
+    };
+
+    let workload = match unsafe { fork() } {
+        Ok(ForkResult::Parent { child }) => child,
+        Ok(ForkResult::Child) => {
+            // The workload starts with the signals of a plain exec
+            let _ = old_mask.thread_set_mask();
+            let err = execvp(exec, args).unwrap_err();
+            exit(EXEC_FAILED, &format!("{}", err));
+        }
+        Err(err) => exit(1, &format!("unable to fork the workload: {}", err)),
+    };
+
+    loop {
+        let signal = match mask.wait() {
+            Ok(signal) => signal,
+            Err(_) => continue,
+        };
+        if signal != Signal::SIGCHLD {
+            // The workload may be gone already, with the zombies left to reap
+            let _ = kill(workload, signal);
+            continue;
+        }
+        if let Some(exit_code) = reap(workload) {
+            unsafe { libc::_exit(exit_code) };
+        }
+    }
+}
+
+/// Signals forwarded to the workload, the others can't be blocked or are
+/// the faults of init itself
+fn forwarded(signal: Signal) -> bool {
+    !matches!(
+        signal,
+        Signal::SIGKILL
+            | Signal::SIGSTOP
+            | Signal::SIGSEGV
+            | Signal::SIGBUS
+            | Signal::SIGFPE
+            | Signal::SIGILL
+            | Signal::SIGABRT
+            | Signal::SIGTTIN
+            | Signal::SIGTTOU
+    )
+}
+
+/// Reaps every zombie, returning the exit code of the workload if it's among them
+fn reap(workload: Pid) -> Option<i32> {
+    let mut exit_code = None;
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(nix::Error::Sys(Errno::ECHILD)) => return exit_code,
+            Ok(status) if status.pid() == Some(workload) => {
+                exit_code = exit_code_of(status);
+            }
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(_) => return exit_code,
+        }
+    }
+}
+
+fn exit_code_of(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+        _ => None,
+    }
+}
+
+fn exit(code: i32, msg: &str) -> ! {
+    // Like the rest of the container process, the log file isn't visible here
+    println!("[ERROR]: init: {}", msg);
+    unsafe { libc::_exit(code) }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        sys::{signal::Signal, wait::WaitStatus},
+        unistd::Pid,
+    };
+
+    use super::{exit_code_of, forwarded};
+
+    #[test]
+    fn workload_exit_code() {
+        let pid = Pid::from_raw(42);
+        assert_eq!(exit_code_of(WaitStatus::Exited(pid, 6)), Some(6));
+        assert_eq!(
+            exit_code_of(WaitStatus::Signaled(pid, Signal::SIGTERM, false)),
+            Some(143)
+        );
+        assert_eq!(exit_code_of(WaitStatus::StillAlive), None);
+
+        assert!(forwarded(Signal::SIGTERM));
+        assert!(forwarded(Signal::SIGCHLD));
+        assert!(!forwarded(Signal::SIGKILL));
+    }
+}
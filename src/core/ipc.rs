@@ -1,11 +1,12 @@
-use std::path::Path;
+use std::{os::unix::io::{AsRawFd, RawFd}, path::Path};
 
+use log::trace;
 use nix::{
     sys::socket::{bind, connect, listen, socket, AddressFamily, SockAddr, SockFlag, SockType},
     unistd::{close, read, write},
 };
 
-use crate::core::common::{Error, ErrorType, Result};
+use crate::core::common::{Error, Result};
 
 pub struct IpcParent {
     fd: i32,
@@ -20,27 +21,15 @@ impl IpcParent {
             SockFlag::SOCK_CLOEXEC,
             None,
         )
-        .map_err(|_| Error {
-            msg: "unable to create IPC socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
-
-        let sockaddr = SockAddr::new_unix(Path::new(path)).map_err(|_| Error {
-            msg: "unable to create unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
-
-        bind(socket_raw_fd, &sockaddr).map_err(|err| {
-            Error {
-                msg: format!("unable to bind IPC parent socket {} for {}", err, path),
-                err_type: ErrorType::Runtime,
-            }
-        })?;
+        .map_err(Error::sys("unable to create IPC socket"))?;
+
+        let sockaddr = SockAddr::new_unix(Path::new(path))
+            .map_err(Error::sys("unable to create unix socket"))?;
+
+        bind(socket_raw_fd, &sockaddr)
+            .map_err(Error::sys(format!("unable to bind IPC parent socket for {}", path)))?;
 
-        listen(socket_raw_fd, 10).map_err(|err| Error {
-            msg: format!("unable to listen IPC socket {}", err),
-            err_type: ErrorType::Runtime,
-        })?;
+        listen(socket_raw_fd, 10).map_err(Error::sys("unable to listen IPC socket"))?;
         Ok(IpcParent {
             fd: socket_raw_fd,
             sock_path: path.clone(),
@@ -48,38 +37,41 @@ impl IpcParent {
     }
 
     pub fn wait(&self) -> Result<String> {
-        let child_socket = nix::sys::socket::accept(self.fd).map_err(|_| Error {
-            msg: "unable to accept incoming socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        let child_socket = nix::sys::socket::accept(self.fd)
+            .map_err(Error::sys("unable to accept incoming socket"))?;
 
         let mut buf = [0; 1024];
-        let num = read(child_socket, &mut buf).unwrap();
+        let num = read(child_socket, &mut buf);
+        let _ = close(child_socket);
+        let num = num.map_err(Error::sys("unable to read from unix socket"))?;
 
         match std::str::from_utf8(&buf[0..num]) {
-            Ok(str) => Ok(str.trim().to_string()),
-            Err(_) => Err(Error {
-                msg: "error while converting byte to string {}".to_string(),
-                err_type: ErrorType::Runtime,
-            }),
+            Ok(str) => {
+                trace!("ipc {}: received {:?}", self.sock_path, str);
+                Ok(str.trim().to_string())
+            }
+            Err(err) => Err(Error::Runtime(format!(
+                "error while converting byte to string {}",
+                err
+            ))),
         }
     }
 
     pub fn close(&self) -> Result<()> {
-        close(self.fd).map_err(|_| Error {
-            msg: "error closing socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        close(self.fd).map_err(Error::sys("error closing socket"))?;
 
-        std::fs::remove_file(&self.sock_path).map_err(|_| Error {
-            msg: "error removing socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        std::fs::remove_file(&self.sock_path).map_err(Error::io("error removing socket"))?;
 
         Ok(())
     }
 }
 
+impl AsRawFd for IpcParent {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 pub struct IpcChild {
     fd: i32,
 }
@@ -92,37 +84,24 @@ impl IpcChild {
             SockFlag::SOCK_CLOEXEC,
             None,
         )
-        .map_err(|_| Error {
-            msg: "unable to create IPC socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        .map_err(Error::sys("unable to create IPC socket"))?;
 
-        let sockaddr = SockAddr::new_unix(Path::new(path)).map_err(|_| Error {
-            msg: "unable to create unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        let sockaddr = SockAddr::new_unix(Path::new(path))
+            .map_err(Error::sys("unable to create unix socket"))?;
 
-        connect(socket_raw_fd, &sockaddr).map_err(|_| Error {
-            msg: "unable to connect to unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        connect(socket_raw_fd, &sockaddr).map_err(Error::sys("unable to connect to unix socket"))?;
 
         Ok(IpcChild { fd: socket_raw_fd })
     }
 
     pub fn notify(&self, msg: &String) -> Result<()> {
-        write(self.fd, msg.as_bytes()).map_err(|_| Error {
-            msg: "unable to write to unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        trace!("ipc: notify {:?}", msg);
+        write(self.fd, msg.as_bytes()).map_err(Error::sys("unable to write to unix socket"))?;
         Ok(())
     }
 
     pub fn close(&self) -> Result<()> {
-        close(self.fd).map_err(|_| Error {
-            msg: "error closing socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        close(self.fd).map_err(Error::sys("error closing socket"))?;
         Ok(())
     }
 }
@@ -141,27 +120,16 @@ impl IpcChannel {
             SockFlag::SOCK_CLOEXEC,
             None,
         )
-        .map_err(|_| Error {
-            msg: "unable to create IPC socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
-
-        let sockaddr = SockAddr::new_unix(Path::new(path)).map_err(|_| Error {
-            msg: "unable to create unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
-
-        bind(socket_raw_fd, &sockaddr).map_err(|err| {
-            Error {
-                msg: format!("unable to bind IPC channel socket {} for {}", err, path),
-                err_type: ErrorType::Runtime,
-            }
-        })?;
+        .map_err(Error::sys("unable to create IPC socket"))?;
+
+        let sockaddr = SockAddr::new_unix(Path::new(path))
+            .map_err(Error::sys("unable to create unix socket"))?;
+
+        bind(socket_raw_fd, &sockaddr)
+            .map_err(Error::sys(format!("unable to bind IPC channel socket for {}", path)))?;
 
-        listen(socket_raw_fd, 10).map_err(|_| Error {
-            msg: "unable to listen IPC socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        listen(socket_raw_fd, 10).map_err(Error::sys("unable to listen IPC socket"))?;
+        trace!("ipc {}: listening", path);
         Ok(IpcChannel {
             fd: socket_raw_fd,
             sock_path: path.clone(),
@@ -176,21 +144,14 @@ impl IpcChannel {
             SockFlag::SOCK_CLOEXEC,
             None,
         )
-        .map_err(|_| Error {
-            msg: "unable to create IPC socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        .map_err(Error::sys("unable to create IPC socket"))?;
 
-        let sockaddr = SockAddr::new_unix(Path::new(path)).map_err(|_| Error {
-            msg: "unable to create unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        let sockaddr = SockAddr::new_unix(Path::new(path))
+            .map_err(Error::sys("unable to create unix socket"))?;
 
-        connect(socket_raw_fd, &sockaddr).map_err(|_| Error {
-            msg: "unable to connect to unix socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        connect(socket_raw_fd, &sockaddr).map_err(Error::sys("unable to connect to unix socket"))?;
 
+        trace!("ipc {}: connected", path);
         Ok(IpcChannel {
             fd: socket_raw_fd,
             sock_path: path.clone(),
@@ -199,11 +160,10 @@ impl IpcChannel {
     }
 
     pub fn accept(&mut self) -> Result<()> {
-        let child_socket_fd = nix::sys::socket::accept(self.fd).map_err(|_| Error {
-            msg: "unable to accept incoming socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        let child_socket_fd = nix::sys::socket::accept(self.fd)
+            .map_err(Error::sys("unable to accept incoming socket"))?;
 
+        trace!("ipc {}: accepted connection", self.sock_path);
         self._client = Some(child_socket_fd);
         Ok(())
     }
@@ -214,10 +174,8 @@ impl IpcChannel {
             None => self.fd,
         };
 
-        write(fd, msg.as_bytes()).map_err(|err| Error {
-            msg: format!("unable to write to unix socket {}", err),
-            err_type: ErrorType::Runtime,
-        })?;
+        trace!("ipc {}: send {:?}", self.sock_path, msg);
+        write(fd, msg.as_bytes()).map_err(Error::sys("unable to write to unix socket"))?;
 
         Ok(())
     }
@@ -228,38 +186,53 @@ impl IpcChannel {
             None => self.fd,
         };
         let mut buf = [0; 1024];
-        let num = read(fd, &mut buf).unwrap();
+        let num = read(fd, &mut buf).map_err(Error::sys("error reading from socket"))?;
+        if num == 0 {
+            return Err(Error::Container(format!(
+                "{} closed by the other side",
+                self.sock_path
+            )));
+        }
 
         match std::str::from_utf8(&buf[0..num]) {
-            Ok(str) => Ok(str.trim().to_string()),
-            Err(_) => Err(Error {
-                msg: "error while converting byte to string {}".to_string(),
-                err_type: ErrorType::Runtime,
-            }),
+            Ok(str) => {
+                trace!("ipc {}: received {:?}", self.sock_path, str);
+                Ok(str.trim().to_string())
+            }
+            Err(err) => Err(Error::Runtime(format!(
+                "error while converting byte to string {}",
+                err
+            ))),
         }
     }
 
+    /// Closes the connection without removing the socket of the listening side
+    pub fn disconnect(&self) -> Result<()> {
+        close(self.as_raw_fd()).map_err(Error::sys("error closing socket"))?;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn send_recv(&self, msg: &str) -> Result<String> {
         self.send(msg)?;
-        Ok(self.recv()?)
+        self.recv()
     }
 
     pub fn close(&self) -> Result<()> {
-        close(self.fd).map_err(|_| Error {
-            msg: "error closing socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        close(self.fd).map_err(Error::sys("error closing socket"))?;
 
-        std::fs::remove_file(&self.sock_path).map_err(|_| Error {
-            msg: "error removing socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        std::fs::remove_file(&self.sock_path).map_err(Error::io("error removing socket"))?;
 
         Ok(())
     }
 }
 
+impl AsRawFd for IpcChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self._client.unwrap_or(self.fd)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
@@ -292,10 +265,7 @@ mod tests {
         let path = String::from("./tmp.sock");
 
         let parent = IpcParent::new(&path).unwrap();
-        match IpcParent::new(&path) {
-            Ok(_) => panic!(),
-            Err(_) => assert!(true),
-        }
+        assert!(IpcParent::new(&path).is_err());
 
         parent.close().unwrap();
     }
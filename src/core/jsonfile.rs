@@ -0,0 +1,586 @@
+use chrono::{DateTime, Duration as TimeDelta, FixedOffset, SecondsFormat, Utc};
+use nix::unistd::read;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+#[cfg(feature = "uring")]
+use crate::core::uring::{self, Ring};
+use crate::core::{
+    common::{Error, Result},
+    terminal::read_ready,
+};
+
+/// Longest log entry, longer lines are split like docker does
+const MAX_LINE: usize = 16 * 1024;
+const BUF_SIZE: usize = 64 * 1024;
+/// Polling of the new entries when following a log
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Output stream of the container a log entry comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    log: &'a str,
+    stream: Stream,
+    time: &'a str,
+}
+
+/// Log entry read back from a json-file log
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LogEntry {
+    pub log: String,
+    pub stream: Stream,
+    pub time: String,
+}
+
+/// Rotation of a json-file log growing past `max_size` bytes, like the
+/// `max-size` and `max-file` log options of docker. The log keeps `max_files`
+/// files, itself included: the rotated ones are `<log>.1` (the newest) to
+/// `<log>.<max_files - 1>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+enum Backend {
+    File,
+    #[cfg(feature = "uring")]
+    Uring(Box<Ring>),
+}
+
+/// Container output logged in the json-file format of docker, one
+/// `{"log":"...","stream":"stdout","time":"..."}` object per line.
+///
+/// Built with the `uring` feature, the reads and writes go through io_uring
+/// when the kernel supports it.
+pub struct JsonFileLog {
+    path: PathBuf,
+    file: File,
+    /// Size of the file, checked against the rotation
+    size: u64,
+    rotation: Option<Rotation>,
+    backend: Backend,
+    /// Incomplete last line of each stream
+    partial: [Vec<u8>; 2],
+}
+
+impl JsonFileLog {
+    pub fn open(path: &Path, rotation: Option<Rotation>) -> Result<JsonFileLog> {
+        let (file, size) = open_log(path)?;
+
+        #[cfg(feature = "uring")]
+        let backend = if uring::supported() {
+            Backend::Uring(Box::new(Ring::new()?))
+        } else {
+            Backend::File
+        };
+        #[cfg(not(feature = "uring"))]
+        let backend = Backend::File;
+
+        Ok(JsonFileLog {
+            path: path.to_path_buf(),
+            file,
+            size,
+            rotation,
+            backend,
+            partial: [Vec::new(), Vec::new()],
+        })
+    }
+
+    /// Logs the complete lines of the data, the rest waits for the next write
+    pub fn write(&mut self, stream: Stream, data: &[u8]) -> Result<()> {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let partial = &mut self.partial[stream as usize];
+        partial.extend_from_slice(data);
+
+        let mut entries = Vec::new();
+        let mut start = 0;
+        while let Some(end) = line_end(&partial[start..]) {
+            append_entry(&mut entries, stream, &partial[start..start + end], &time)?;
+            start += end;
+        }
+        partial.drain(..start);
+
+        self.write_entries(&entries)
+    }
+
+    /// Logs the incomplete lines
+    pub fn flush(&mut self) -> Result<()> {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut entries = Vec::new();
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            let partial = std::mem::take(&mut self.partial[stream as usize]);
+            if !partial.is_empty() {
+                append_entry(&mut entries, stream, &partial, &time)?;
+            }
+        }
+        self.write_entries(&entries)
+    }
+
+    /// Logs the output read from the fd until its end, or the hangup of a pty
+    /// slave, returning the number of bytes read
+    pub fn copy_from(&mut self, fd: RawFd, stream: Stream) -> Result<u64> {
+        let mut buf = vec![0; BUF_SIZE];
+        let mut total = 0;
+        loop {
+            let num = match &mut self.backend {
+                Backend::File => read_ready(fd, &mut buf)?,
+                #[cfg(feature = "uring")]
+                Backend::Uring(ring) => ring.read(fd, &mut buf)?,
+            };
+            if num == 0 {
+                self.flush()?;
+                return Ok(total);
+            }
+            self.write(stream, &buf[..num])?;
+            total += num as u64;
+        }
+    }
+
+    /// Reads the output available in the nonblocking fd, without waiting for
+    /// it: a fd without data fails with EAGAIN
+    pub fn read_available(&mut self, fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+        match &mut self.backend {
+            Backend::File => read(fd, buf).map_err(Error::sys("unable to read the output")),
+            #[cfg(feature = "uring")]
+            Backend::Uring(ring) => ring.try_read(fd, buf),
+        }
+    }
+
+    /// Opens the log at its path again, once an external rotator like
+    /// logrotate moved it away
+    pub fn reopen(&mut self) -> Result<()> {
+        let (file, size) = open_log(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Shifts the rotated files, the oldest one dropped, and starts a new log
+    fn rotate(&mut self, max_files: usize) -> Result<()> {
+        let rotated = |index: usize| rotated_path(&self.path, index);
+        let _ = std::fs::remove_file(rotated(max_files - 1));
+        for index in (1..max_files - 1).rev() {
+            match std::fs::rename(rotated(index), rotated(index + 1)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::io(format!("unable to rotate log file {:?}", rotated(index)))(err))
+                }
+                _ => (),
+            }
+        }
+        match max_files {
+            1 => std::fs::remove_file(&self.path),
+            _ => std::fs::rename(&self.path, rotated(1)),
+        }
+        .map_err(Error::io(format!("unable to rotate log file {:?}", self.path)))?;
+        self.reopen()
+    }
+
+    fn write_entries(&mut self, mut entries: &[u8]) -> Result<()> {
+        let rotation = match self.rotation {
+            Some(rotation) => rotation,
+            None => return self.append(entries),
+        };
+        // As many entries as fit before each rotation, one at least
+        while !entries.is_empty() {
+            let room = rotation.max_size.saturating_sub(self.size) as usize;
+            let fitting = entries[..room.min(entries.len())]
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map(|newline| newline + 1);
+            let end = match fitting {
+                Some(end) => end,
+                None if self.size > 0 => {
+                    self.rotate(rotation.max_files.max(1))?;
+                    continue;
+                }
+                // An entry larger than the log, alone in its file
+                None => entries
+                    .iter()
+                    .position(|byte| *byte == b'\n')
+                    .map_or(entries.len(), |newline| newline + 1),
+            };
+            self.append(&entries[..end])?;
+            entries = &entries[end..];
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, entries: &[u8]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        // A single write of the O_APPEND file, readers never see half an entry
+        match &mut self.backend {
+            Backend::File => self
+                .file
+                .write_all(entries)
+                .map_err(Error::io("unable to write log entries"))?,
+            #[cfg(feature = "uring")]
+            Backend::Uring(ring) => ring.write_all(self.file.as_raw_fd(), entries)?,
+        }
+        self.size += entries.len() as u64;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(Error::io(format!("unable to open log file {:?}", path)))?;
+    let size = file
+        .metadata()
+        .map_err(Error::io(format!("unable to stat log file {:?}", path)))?
+        .len();
+    Ok((file, size))
+}
+
+/// Path of the rotated log of the index, 1 being the newest
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+impl AsRawFd for JsonFileLog {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Entries of a json-file log, oldest first, from its rotated files on.
+///
+/// When following, the iterator blocks waiting for new entries while `alive`
+/// says the writer is still around, then ends with what the writer logged last.
+/// A log rotated meanwhile is read to its end before the new one.
+pub struct Entries {
+    path: PathBuf,
+    reader: BufReader<File>,
+    /// Files left to read after the current one, the log itself last
+    next: VecDeque<PathBuf>,
+    line: String,
+    since: Option<DateTime<FixedOffset>>,
+    /// Entries read ahead to keep only the last ones
+    tail: VecDeque<LogEntry>,
+    alive: Option<Box<dyn FnMut() -> bool>>,
+    done: bool,
+}
+
+impl Entries {
+    /// Reads the log at `path`, the last `tail` entries only when given, and
+    /// only the entries logged at or after `since`
+    pub fn open(
+        path: &Path,
+        tail: Option<usize>,
+        since: Option<DateTime<FixedOffset>>,
+        alive: Option<Box<dyn FnMut() -> bool>>,
+    ) -> Result<Entries> {
+        let mut next: VecDeque<PathBuf> = (1..)
+            .map(|index| rotated_path(path, index))
+            .take_while(|rotated| rotated.exists())
+            .collect();
+        next.make_contiguous().reverse();
+        next.push_back(path.to_path_buf());
+        let first = next.pop_front().expect("the log is read last");
+        let file = File::open(&first)
+            .map_err(Error::io(format!("unable to open log file {:?}", first)))?;
+        let mut entries = Entries {
+            path: path.to_path_buf(),
+            reader: BufReader::new(file),
+            next,
+            line: String::new(),
+            since,
+            tail: VecDeque::new(),
+            alive,
+            done: false,
+        };
+        if let Some(tail) = tail {
+            while let Some(entry) = entries.read_entry()? {
+                if entries.tail.len() == tail {
+                    entries.tail.pop_front();
+                }
+                if tail > 0 {
+                    entries.tail.push_back(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Reads the next complete entry logged since `since`, if any
+    fn read_entry(&mut self) -> Result<Option<LogEntry>> {
+        loop {
+            self.reader
+                .read_line(&mut self.line)
+                .map_err(Error::io("unable to read log file"))?;
+            if !self.line.ends_with('\n') {
+                let path = match self.next.pop_front().or_else(|| self.rotated()) {
+                    Some(path) => path,
+                    // Nothing new, or the rest of the line isn't written yet
+                    None => return Ok(None),
+                };
+                // A rotated file is complete, the rest of its last line included
+                self.reader
+                    .read_line(&mut self.line)
+                    .map_err(Error::io("unable to read log file"))?;
+                let file = File::open(&path)
+                    .map_err(Error::io(format!("unable to open log file {:?}", path)))?;
+                self.reader = BufReader::new(file);
+                if !self.line.ends_with('\n') {
+                    self.line.clear();
+                    continue;
+                }
+            }
+
+            let entry: Result<LogEntry> =
+                serde_json::from_str(&self.line).map_err(Error::json("invalid log entry"));
+            self.line.clear();
+            let entry = entry?;
+            let logged_since = match (&self.since, DateTime::parse_from_rfc3339(&entry.time)) {
+                (Some(since), Ok(time)) => time >= *since,
+                _ => true,
+            };
+            if logged_since {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    /// The log, if the file read to its end was rotated meanwhile
+    fn rotated(&self) -> Option<PathBuf> {
+        let current = self.reader.get_ref().metadata().ok()?;
+        let log = std::fs::metadata(&self.path).ok()?;
+        (current.ino() != log.ino() || current.dev() != log.dev()).then(|| self.path.clone())
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.tail.pop_front() {
+            return Some(Ok(entry));
+        }
+        loop {
+            if self.done {
+                return None;
+            }
+
+            match self.read_entry() {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => (),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+
+            match self.alive.as_mut().map(|alive| alive()) {
+                Some(true) => thread::sleep(POLL_INTERVAL),
+                // The writer is gone, one last read for its final entries
+                Some(false) => self.alive = None,
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+/// Parses the `--since` of logs: an RFC 3339 timestamp, or a duration ago
+/// like `30s`, `5m` or `2h`
+pub fn parse_since(since: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Some(time);
+    }
+    let unit = since.chars().last()?;
+    let num: i64 = since[..since.len() - unit.len_utf8()].parse().ok()?;
+    let ago = match unit {
+        's' => TimeDelta::seconds(num),
+        'm' => TimeDelta::minutes(num),
+        'h' => TimeDelta::hours(num),
+        _ => return None,
+    };
+    Some((Utc::now() - ago).into())
+}
+
+/// Length of the first line, up to MAX_LINE bytes, if it's complete
+fn line_end(data: &[u8]) -> Option<usize> {
+    match data.iter().take(MAX_LINE).position(|byte| *byte == b'\n') {
+        Some(newline) => Some(newline + 1),
+        None if data.len() >= MAX_LINE => Some(MAX_LINE),
+        None => None,
+    }
+}
+
+fn append_entry(entries: &mut Vec<u8>, stream: Stream, line: &[u8], time: &str) -> Result<()> {
+    let entry = Entry {
+        log: &String::from_utf8_lossy(line),
+        stream,
+        time,
+    };
+    serde_json::to_writer(&mut *entries, &entry).map_err(Error::json("unable to serialize log entry"))?;
+    entries.push(b'\n');
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::{close, pipe, write};
+    use serde_json::Value;
+    use std::path::Path;
+
+    use super::{parse_since, rotated_path, Entries, JsonFileLog, Rotation, Stream, MAX_LINE};
+
+    #[test]
+    fn json_file_entries() {
+        let path = Path::new("./jsonfile_test.log");
+        let _ = std::fs::remove_file(path);
+        let mut log = JsonFileLog::open(path, None).unwrap();
+
+        log.write(Stream::Stdout, b"hello\nwor").unwrap();
+        log.write(Stream::Stderr, b"oops\n").unwrap();
+        log.write(Stream::Stdout, b"ld\n").unwrap();
+
+        let (pipe_out, pipe_in) = pipe().unwrap();
+        write(pipe_in, &vec![b'x'; MAX_LINE + 2]).unwrap();
+        close(pipe_in).unwrap();
+        assert_eq!(
+            log.copy_from(pipe_out, Stream::Stdout).unwrap(),
+            MAX_LINE as u64 + 2
+        );
+        close(pipe_out).unwrap();
+
+        let entries: Vec<Value> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let logs: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry["stream"].as_str().unwrap(), entry["log"].as_str().unwrap()))
+            .collect();
+        assert_eq!(logs[..3], [("stdout", "hello\n"), ("stderr", "oops\n"), ("stdout", "world\n")]);
+        // Long lines are split, the incomplete end is flushed at the end of the input
+        assert_eq!(logs[3].1.len(), MAX_LINE);
+        assert_eq!(logs[4].1, "xx");
+        assert!(entries[0]["time"].as_str().unwrap().ends_with('Z'));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_entries() {
+        let path = Path::new("./jsonfile_read_test.log");
+        let _ = std::fs::remove_file(path);
+        let mut log = JsonFileLog::open(path, None).unwrap();
+        log.write(Stream::Stdout, b"one\ntwo\n").unwrap();
+        log.write(Stream::Stderr, b"three\n").unwrap();
+
+        let logs = |entries: Entries| -> Vec<String> {
+            entries.map(|entry| entry.unwrap().log).collect()
+        };
+        assert_eq!(
+            logs(Entries::open(path, None, None, None).unwrap()),
+            ["one\n", "two\n", "three\n"]
+        );
+        assert_eq!(
+            logs(Entries::open(path, Some(2), None, None).unwrap()),
+            ["two\n", "three\n"]
+        );
+        assert!(logs(Entries::open(path, Some(0), None, None).unwrap()).is_empty());
+        let since = parse_since("1h").unwrap();
+        assert_eq!(logs(Entries::open(path, None, Some(since), None).unwrap()).len(), 3);
+        let since = parse_since("2100-01-01T00:00:00Z").unwrap();
+        assert!(logs(Entries::open(path, None, Some(since), None).unwrap()).is_empty());
+        assert!(parse_since("5 minutes").is_none());
+
+        // Following ends with the last entries of the writer
+        let mut polls = 0;
+        let alive = Box::new(move || {
+            polls += 1;
+            polls < 3
+        });
+        let mut entries = Entries::open(path, Some(1), None, Some(alive)).unwrap();
+        assert_eq!(entries.next().unwrap().unwrap().stream, Stream::Stderr);
+        log.write(Stream::Stdout, b"four").unwrap();
+        log.flush().unwrap();
+        assert_eq!(logs(entries), ["four"]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rotation() {
+        let path = Path::new("./jsonfile_rotation_test.log");
+        let remove = || {
+            let _ = std::fs::remove_file(path);
+            for index in 1..4 {
+                let _ = std::fs::remove_file(rotated_path(path, index));
+            }
+        };
+        remove();
+        let rotation = Rotation {
+            max_size: 100,
+            max_files: 3,
+        };
+        let mut log = JsonFileLog::open(path, Some(rotation)).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            log.write(Stream::Stdout, line.as_bytes()).unwrap();
+        }
+        let logs = || -> Vec<String> {
+            Entries::open(path, None, None, None)
+                .unwrap()
+                .map(|entry| entry.unwrap().log)
+                .collect()
+        };
+
+        // An entry each, the oldest one dropped
+        assert!(std::fs::metadata(path).unwrap().len() <= rotation.max_size);
+        assert!(rotated_path(path, 2).exists());
+        assert!(!rotated_path(path, 3).exists());
+        assert_eq!(logs(), ["two\n", "three\n", "four\n"]);
+
+        // Reopened at its path once moved away
+        let moved = Path::new("./jsonfile_rotation_test.log.old");
+        std::fs::rename(path, moved).unwrap();
+        log.reopen().unwrap();
+        log.write(Stream::Stderr, b"five\n").unwrap();
+        assert_eq!(logs(), ["two\n", "three\n", "five\n"]);
+        std::fs::remove_file(moved).unwrap();
+
+        // Following reads the rest of the rotated log before the new one
+        let mut polls = 0;
+        let alive = Box::new(move || {
+            polls += 1;
+            polls < 3
+        });
+        let mut entries = Entries::open(path, Some(1), None, Some(alive)).unwrap();
+        assert_eq!(entries.next().unwrap().unwrap().log, "five\n");
+        log.write(Stream::Stdout, b"six\n").unwrap();
+        assert_eq!(
+            entries.map(|entry| entry.unwrap().log).collect::<Vec<_>>(),
+            ["six\n"]
+        );
+
+        remove();
+    }
+}
@@ -0,0 +1,117 @@
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg},
+    libc,
+};
+use std::{fs::File, os::unix::io::AsRawFd, path::Path};
+
+use crate::core::common::{Error, Result};
+
+/// Directory of the runtime root holding a lock file per container id. The
+/// lock files outlive the state dirs: a create waiting for a delete locks
+/// the same file as the invocations coming after the delete.
+pub const LOCKS_DIR: &str = "locks";
+
+/// Exclusive lock of a container, serializing the runtime invocations that
+/// change it, like a start racing a delete of the engine.
+/// It's a POSIX record lock: the container process forked while it's held
+/// doesn't inherit it, and it's released when the runtime exits or the lock
+/// is dropped. Locks of the same process don't exclude each other.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Waits for the invocation holding the lock of the container of the
+    /// root. The container may have been deleted meanwhile, its state is to
+    /// be checked once the lock is taken.
+    pub fn acquire(root: &Path, id: &str) -> Result<Lock> {
+        let dir = root.join(LOCKS_DIR);
+        std::fs::create_dir_all(&dir)
+            .map_err(Error::io(format!("unable to create the locks dir {:?}", dir)))?;
+        let path = dir.join(id);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(Error::io(format!("unable to open the lock {:?}", path)))?;
+
+        let lock = libc::flock {
+            l_type: libc::F_WRLCK as libc::c_short,
+            l_whence: libc::SEEK_SET as libc::c_short,
+            l_start: 0,
+            l_len: 0,
+            l_pid: 0,
+        };
+        loop {
+            match fcntl(file.as_raw_fd(), FcntlArg::F_SETLKW(&lock)) {
+                Ok(_) => return Ok(Lock { _file: file }),
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => return Err(Error::sys(format!("unable to lock {:?}", path))(err)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        libc,
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
+    };
+    use std::{path::Path, time::{Duration, Instant}};
+
+    use super::Lock;
+
+    #[test]
+    fn exclusive_between_processes() {
+        let path = Path::new("./lock_test");
+        std::fs::create_dir_all(path).unwrap();
+
+        let lock = Lock::acquire(path, "example").unwrap();
+        let started = Instant::now();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // Blocks until the parent releases the lock
+                let _lock = Lock::acquire(path, "example");
+                let code = if started.elapsed() >= Duration::from_millis(200) { 0 } else { 1 };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                std::thread::sleep(Duration::from_millis(300));
+                drop(lock);
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn outlives_state_dir() {
+        let root = Path::new("./lock_test_state_dir");
+        let state_dir = root.join("example");
+        std::fs::create_dir_all(&state_dir).unwrap();
+
+        // Held by a delete removing the state dir
+        let lock = Lock::acquire(root, "example").unwrap();
+        std::fs::remove_dir_all(&state_dir).unwrap();
+        let started = Instant::now();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let _lock = Lock::acquire(root, "example");
+                let code = if started.elapsed() >= Duration::from_millis(200) { 0 } else { 1 };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                std::thread::sleep(Duration::from_millis(300));
+                drop(lock);
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
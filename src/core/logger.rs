@@ -1,15 +1,17 @@
-
 use chrono::Local;
 use lazy_static::lazy_static;
 use std::{
     fs::OpenOptions,
-    io::Write,
+    io::{stderr, Write},
+    os::unix::net::UnixDatagram,
+    str::FromStr,
     sync::Mutex,
 };
 use log::{Level, Log, Metadata, Record};
+use nix::sys::stat::fstat;
 use serde::Serialize;
 
-use crate::core::common::Result;
+use crate::core::common::{Error, Result};
 
 lazy_static! {
     static ref LOGGER: ContainerLogger = ContainerLogger {
@@ -17,29 +19,103 @@ lazy_static! {
     };
 }
 
+/// Format of the records written by the runtime logger
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    /// logfmt-like lines: `time="..." level=info msg="..."`
+    #[default]
+    Text,
+    /// runc-compatible JSON lines: `{"level":"info","msg":"...","time":"..."}`
+    Json,
+    /// Structured records sent to systemd-journald
+    Journald,
+}
+
+impl LogFormat {
+    /// Picks journald when stderr is connected to the journal (the runtime
+    /// is run by a systemd unit) and no log file was requested.
+    pub fn detect(path: Option<&str>) -> LogFormat {
+        if path.is_none() && stderr_is_journal() {
+            LogFormat::Journald
+        } else {
+            LogFormat::default()
+        }
+    }
+}
+
+// systemd sets JOURNAL_STREAM to "<dev>:<inode>" of the stream connected to stderr
+fn stderr_is_journal() -> bool {
+    let journal_stream = match std::env::var("JOURNAL_STREAM") {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+
+    match fstat(2) {
+        Ok(stat) => journal_stream == format!("{}:{}", stat.st_dev, stat.st_ino),
+        Err(_) => false,
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" | "txt" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "journald" => Ok(LogFormat::Journald),
+            _ => Err(Error::Runtime(format!("unknown log format {}", format))),
+        }
+    }
+}
+
 pub struct ContainerLogger {
     logger: Mutex<Option<Logger>>,
 }
 
 impl ContainerLogger {
-    pub fn init(path: &String, max_level: Level) -> Result<()> {
-        // Make sure the file exists
-        let _ = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path)
-            .unwrap();
+    /// Installs the runtime logger.
+    ///
+    /// Records are appended to the file at `path` (created if missing) or written
+    /// to stderr when no path is given, like runc does. The journald format ignores
+    /// the path and sends the records to the journal socket.
+    pub fn init(path: Option<&str>, format: LogFormat, max_level: Level) -> Result<()> {
+        let output = match (format, path) {
+            (LogFormat::Journald, _) => Output::Journal(Journal::new()?),
+            (_, Some(path)) => Output::Writer(Box::new(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(Error::io(format!("unable to open log file {}", path)))?,
+            )),
+            (_, None) => Output::Writer(Box::new(stderr())),
+        };
 
         *LOGGER.logger.lock().unwrap() = Some(Logger {
-            max_level: max_level,
-            path: path.clone(),
+            max_level,
+            format,
+            output,
+            context: Vec::new(),
         });
 
-        log::set_logger(&*LOGGER).unwrap();
+        // The logger can only be set once, later calls just replace the inner one
+        let _ = log::set_logger(&*LOGGER);
         log::set_max_level(max_level.to_level_filter());
 
         Ok(())
     }
+
+    /// Attaches the container id and the subcommand to the records.
+    /// Only the journald output makes use of them, as structured fields.
+    pub fn set_context(subcommand: &str, container_id: Option<&str>) {
+        if let Some(ref mut inner) = *LOGGER.logger.lock().unwrap() {
+            inner.context = vec![("SUBCOMMAND", subcommand.to_string())];
+            if let Some(id) = container_id {
+                inner.context.push(("CONTAINER_ID", id.to_string()));
+            }
+        }
+    }
 }
 
 impl Log for ContainerLogger {
@@ -53,12 +129,72 @@ impl Log for ContainerLogger {
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Some(ref mut inner) = *self.logger.lock().unwrap() {
+            if let Output::Writer(writer) = &mut inner.output {
+                let _ = writer.flush();
+            }
+        }
+    }
+}
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Client for the journald native protocol
+/// (https://systemd.io/JOURNAL_NATIVE_PROTOCOL/)
+struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    fn new() -> Result<Journal> {
+        let socket =
+            UnixDatagram::unbound().map_err(Error::io("unable to create journald socket"))?;
+        Ok(Journal { socket })
+    }
+
+    fn send(&self, fields: &[(&str, &str)]) {
+        let _ = self.socket.send_to(&journal_payload(fields), JOURNAL_SOCKET);
+    }
+}
+
+fn journal_payload(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (key, value) in fields {
+        payload.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // Multi-line values are sent as a little-endian 64-bit size followed by the data
+            payload.push(b'\n');
+            payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            payload.push(b'=');
+        }
+        payload.extend_from_slice(value.as_bytes());
+        payload.push(b'\n');
+    }
+    payload
+}
+
+// Maps log levels to the syslog priorities used by the journal
+fn syslog_priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    Journal(Journal),
 }
 
 struct Logger {
-    path: String,
+    output: Output,
+    format: LogFormat,
     max_level: Level,
+    context: Vec<(&'static str, String)>,
 }
 
 #[derive(Serialize)]
@@ -68,41 +204,64 @@ struct LogEntry {
     time: String,
 }
 
-impl Log for Logger {
+impl LogEntry {
+    fn new(record: &Record) -> LogEntry {
+        LogEntry {
+            // logrus (used by containerd to parse the runtime logs) names the levels in lowercase
+            level: record.level().to_string().to_lowercase(),
+            msg: record.args().to_string(),
+            time: Local::now().to_rfc3339(),
+        }
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json | LogFormat::Journald => serde_json::to_string(self).unwrap(),
+            LogFormat::Text => format!(
+                "time={:?} level={} msg={:?}",
+                self.time, self.level, self.msg
+            ),
+        }
+    }
+}
+
+impl Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= self.max_level
     }
 
-    fn log(&self, record: &Record) {
-        println!("{}", record.args());
-        if self.enabled(record.metadata()) {
-            let log_entry = LogEntry {
-                level: record.level().to_string(),
-                msg: record.args().to_string(),
-                time: Local::now().to_rfc3339(),
-            };
-
-            let mut file = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&self.path)
-                .unwrap();
-
-            let log_json = serde_json::to_string(&log_entry).unwrap();
-            let _ = file.write(format!("{}\n", log_json).as_bytes());
+    fn log(&mut self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
-    }
 
-    fn flush(&self) {}
+        match &mut self.output {
+            Output::Writer(writer) => {
+                let line = LogEntry::new(record).format(self.format);
+                let _ = writer.write_all(format!("{}\n", line).as_bytes());
+            }
+            Output::Journal(journal) => {
+                let msg = record.args().to_string();
+                let mut fields = vec![
+                    ("MESSAGE", msg.as_str()),
+                    ("PRIORITY", syslog_priority(record.level())),
+                    ("SYSLOG_IDENTIFIER", "pura"),
+                ];
+                fields.extend(self.context.iter().map(|(key, value)| (*key, value.as_str())));
+                journal.send(&fields);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
+    use std::{io::Read, str::FromStr};
 
     use log::{debug, error, info, trace, warn, Level};
+    use serial_test::serial;
 
-    use super::ContainerLogger;
+    use super::{journal_payload, ContainerLogger, LogFormat};
 
     fn read_file(path: &str) -> String {
         let mut log_file = std::fs::OpenOptions::new()
@@ -116,8 +275,9 @@ mod tests {
     }
 
     #[test]
+    #[serial]
     fn log() {
-        let _ = ContainerLogger::init(&"log.txt".to_string(), Level::Info).unwrap();
+        ContainerLogger::init(Some("log.txt"), LogFormat::Json, Level::Info).unwrap();
         warn!("warn");
         error!("error");
         info!("info");
@@ -132,6 +292,47 @@ mod tests {
         assert!(!logs.contains("debug"));
         assert!(!logs.contains("trace"));
 
+        for line in logs.lines() {
+            let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(entry["level"].is_string());
+            assert!(entry["msg"].is_string());
+            assert!(entry["time"].is_string());
+        }
+
         std::fs::remove_file("log.txt").unwrap();
     }
+
+    #[test]
+    #[serial]
+    fn log_text() {
+        ContainerLogger::init(Some("log_text.txt"), LogFormat::Text, Level::Info).unwrap();
+        error!("container \"abc\" failed");
+
+        let logs = read_file("log_text.txt");
+
+        assert!(logs.starts_with("time=\""));
+        assert!(logs.contains("level=error msg=\"container \\\"abc\\\" failed\""));
+
+        std::fs::remove_file("log_text.txt").unwrap();
+    }
+
+    #[test]
+    fn log_format() {
+        assert_eq!(LogFormat::from_str("json").unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::from_str("text").unwrap(), LogFormat::Text);
+        assert_eq!(LogFormat::from_str("journald").unwrap(), LogFormat::Journald);
+        assert!(LogFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn journal_fields() {
+        let payload = journal_payload(&[("MESSAGE", "hello"), ("CONTAINER_ID", "abc")]);
+        assert_eq!(payload, b"MESSAGE=hello\nCONTAINER_ID=abc\n".to_vec());
+
+        let payload = journal_payload(&[("MESSAGE", "a\nb")]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(payload, expected);
+    }
 }
@@ -1,10 +1,54 @@
 pub mod ipc;
+pub mod apparmor;
+pub mod audit;
+pub mod capabilities;
+pub mod cdi;
+pub mod cgroups;
 pub mod common;
+pub mod config;
+pub mod criu;
+pub mod env;
+pub mod events;
+pub mod exec;
+pub mod jsonfile;
+pub mod lock;
 pub mod logger;
+pub mod monitor;
+pub mod network;
+pub mod numa;
+pub mod nvidia;
+pub mod notify;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+/// The API of `otlp` without the tracing crate, the spans aren't recorded
+#[cfg(not(feature = "otlp"))]
+#[path = "otlp_disabled.rs"]
+pub mod otlp;
+pub mod overlay;
+pub mod plugins;
+pub mod pod;
+pub mod recording;
+pub mod restart;
+pub mod root;
+pub mod sealed;
+pub mod seccomp;
+pub mod selinux;
+pub mod signals;
+pub mod slirp;
 pub mod state;
+mod syscalls;
+pub mod users;
+pub mod volume;
 pub mod fork;
 pub mod hooks;
+pub mod init;
 pub mod terminal;
+pub mod trace;
 pub mod filesystem;
 pub mod container;
+pub mod wasm;
+#[cfg(feature = "uring")]
+pub mod uring;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
@@ -0,0 +1,1020 @@
+use chrono::Local;
+use log::{debug, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, open, FcntlArg, OFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::{SigSet, Signal},
+    sys::{
+        signalfd::{SfdFlags, SignalFd},
+        socket::{recvmsg, ControlMessageOwned, MsgFlags},
+        stat::Mode,
+        uio::IoVec,
+    },
+    unistd::{close, dup2, fork, getpid, pipe2, read, setsid, write, ForkResult, Pid},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::{UnixDatagram, UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::core::{
+    common::{Error, Result},
+    fork::{self, Process, EXIT_POLL_INTERVAL},
+    jsonfile::{JsonFileLog, Rotation, Stream},
+    recording::{recording_path, Recorder},
+    restart::{self, RestartPolicy},
+    terminal::{self, Splice},
+};
+
+/// Pid and start time of the monitor of a detached container
+pub const MONITOR_FILE: &str = "monitor.pid";
+/// Socket `pura attach` connects to
+pub const ATTACH_SOCKET: &str = "attach.sock";
+/// Datagram socket of the `resize ROWS COLS` messages of the attached terminals
+pub const CONTROL_SOCKET: &str = "control.sock";
+/// Output of the container process, or its console with a terminal, in the
+/// json-file format
+pub const LOG_FILE: &str = "container-json.log";
+/// Exit status of the container process, recorded by the monitor
+pub const EXIT_FILE: &str = "exit.json";
+/// Console socket the container process sends its pty master to
+const CONSOLE_SOCKET: &str = "console.sock";
+/// Left by the runtime stopping the container, its monitor doesn't restart it
+const STOP_FILE: &str = "restart.stop";
+const BUF_SIZE: usize = 64 * 1024;
+/// Attached clients not taking the rest of their output for that long once
+/// the process exited are dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Output buffered for each attached client by default
+const CONSOLE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Exit status of a container process, as `exit.json` of the container
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExitStatus {
+    /// 128 + signal number when killed by a signal
+    pub exit_code: Option<i32>,
+    pub finished_at: String,
+}
+
+/// Output buffered by the monitor for each attached client not reading it as
+/// fast as the container writes it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConsoleBuffer {
+    /// Bytes buffered per client
+    pub size: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for ConsoleBuffer {
+    fn default() -> Self {
+        ConsoleBuffer {
+            size: CONSOLE_BUFFER_SIZE,
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+/// What the monitor does once the buffer of a client is full
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    /// Drops the oldest output of the client, the container never waits
+    #[default]
+    DropOldest,
+    /// Stops reading the output of the container until the client catches up,
+    /// the container then blocks on its full pipe or pty
+    Block,
+}
+
+impl FromStr for Overflow {
+    type Err = Error;
+
+    fn from_str(overflow: &str) -> Result<Self> {
+        match overflow {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "block" => Ok(Overflow::Block),
+            _ => Err(Error::Runtime(format!(
+                "unknown console overflow {}, expected drop-oldest or block",
+                overflow
+            ))),
+        }
+    }
+}
+
+/// conmon-like supervisor of a detached container.
+///
+/// Create double-forks it before the container process, so the process is its
+/// child and the runtime can exit. The monitor holds the stdio of the process
+/// (pipes, or the pty master with a terminal), logs the output to
+/// `container-json.log`, copies it to the attached clients, and records the
+/// exit status once it reaps the process. SIGHUP makes it reopen the log, for
+/// external rotators like logrotate.
+///
+/// With a restart policy, the monitor creates the container again once its
+/// process failed, until the runtime stops or deletes it.
+pub struct Monitor {
+    path: PathBuf,
+    /// Receives the pty master when the process has a terminal
+    console: Option<UnixListener>,
+    /// Records the session, see `recording::Recorder`
+    record: bool,
+    /// Rotation of the log, see `jsonfile::Rotation`
+    log_rotation: Option<Rotation>,
+    console_buffer: ConsoleBuffer,
+    restart: RestartPolicy,
+}
+
+impl Monitor {
+    pub fn new(
+        container_path: &Path,
+        terminal: bool,
+        record: bool,
+        log_rotation: Option<Rotation>,
+        console_buffer: ConsoleBuffer,
+        restart: RestartPolicy,
+    ) -> Result<Monitor> {
+        let console = if terminal {
+            let path = container_path.join(CONSOLE_SOCKET);
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).map_err(Error::io(format!(
+                "unable to bind console socket {:?}",
+                path
+            )))?;
+            Some(listener)
+        } else {
+            None
+        };
+        Ok(Monitor {
+            path: container_path.to_path_buf(),
+            console,
+            record,
+            log_rotation,
+            console_buffer,
+            restart,
+        })
+    }
+
+    /// Console socket to pass to the container process, with a terminal
+    pub fn console_socket(&self) -> Option<String> {
+        self.console
+            .as_ref()
+            .map(|_| self.path.join(CONSOLE_SOCKET).to_string_lossy().to_string())
+    }
+
+    /// Forks the monitor, which forks the container process with `spawn`.
+    /// Once the process exits, `stopped` gets its exit code in the monitor.
+    /// A restart forks the next process with `restart`, given the number of
+    /// restarts so far included.
+    pub fn spawn(
+        self,
+        spawn: impl FnOnce() -> Result<Process>,
+        stopped: impl Fn(Option<i32>),
+        restart: impl FnMut(u32) -> Result<Process>,
+    ) -> Result<Process> {
+        let (result_read, result_write) =
+            pipe2(OFlag::O_CLOEXEC).map_err(Error::sys("unable to create the monitor pipe"))?;
+        let (result_read, result_write) = unsafe {
+            (
+                File::from_raw_fd(result_read),
+                File::from_raw_fd(result_write),
+            )
+        };
+
+        match unsafe { fork() }.map_err(Error::sys("unable to fork the monitor"))? {
+            ForkResult::Parent { child } => {
+                drop(result_write);
+                // The intermediate process exits right after forking the monitor
+                let _ = nix::sys::wait::waitpid(child, None);
+                self.spawned(result_read)
+            }
+            ForkResult::Child => {
+                drop(result_read);
+                let _ = setsid();
+                match unsafe { fork() } {
+                    Ok(ForkResult::Child) => self.run(result_write, spawn, stopped, restart),
+                    Ok(ForkResult::Parent { .. }) => unsafe { nix::libc::_exit(0) },
+                    Err(err) => {
+                        let mut result_write = result_write;
+                        let _ = writeln!(result_write, "error:unable to fork the monitor: {}", err);
+                        unsafe { nix::libc::_exit(1) }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The container process, once the monitor forked it
+    fn spawned(&self, result_read: File) -> Result<Process> {
+        // A line, the container process holds the pipe until its exec
+        let mut result = String::new();
+        BufReader::new(result_read)
+            .read_line(&mut result)
+            .map_err(Error::io("unable to read the monitor pipe"))?;
+        let result = result.trim_end();
+        if let Some(err) = result.strip_prefix("error:") {
+            return Err(Error::Runtime(err.to_string()));
+        }
+        let pid = result
+            .parse()
+            .map_err(|_| Error::Runtime("the monitor exited".to_string()))?;
+        Process::open(Pid::from_raw(pid), None)
+    }
+
+    /// Body of the monitor process, never returning
+    fn run(
+        self,
+        mut result_write: File,
+        spawn: impl FnOnce() -> Result<Process>,
+        stopped: impl Fn(Option<i32>),
+        mut restart: impl FnMut(u32) -> Result<Process>,
+    ) -> ! {
+        let monitor_file = match fork::start_time(getpid()) {
+            Some(start_time) => std::fs::write(
+                self.path.join(MONITOR_FILE),
+                format!("{} {}", getpid(), start_time),
+            )
+            .map_err(Error::io("unable to save the monitor pid")),
+            None => Err(Error::Runtime(
+                "unable to read the monitor start time".to_string(),
+            )),
+        };
+
+        let spawned = monitor_file
+            .and_then(|_| self.stdio())
+            .and_then(|stdio| spawn_with_stdio(spawn, stdio));
+        let (mut process, mut stdio) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                let _ = writeln!(result_write, "error:{}", err);
+                let _ = std::fs::remove_file(self.path.join(MONITOR_FILE));
+                std::process::exit(1);
+            }
+        };
+        let _ = writeln!(result_write, "{}", process.pid());
+        drop(result_write);
+
+        let mut restarts = 0;
+        loop {
+            debug!("monitoring container process {}", process.pid());
+            let exit_code = match self.supervise(&process, stdio) {
+                Ok(()) => process.exit_code().unwrap_or(None),
+                Err(err) => {
+                    warn!(
+                        "monitor of container process {} failed: {}",
+                        process.pid(),
+                        err
+                    );
+                    let _ = process.wait_exit();
+                    process.exit_code().unwrap_or(None)
+                }
+            };
+            self.exited(exit_code, &stopped);
+            // Blocked by supervise, the next container process doesn't inherit it
+            let mut hangup = SigSet::empty();
+            hangup.add(Signal::SIGHUP);
+            let _ = hangup.thread_unblock();
+
+            if !self.restart.restarts(exit_code, restarts) {
+                break;
+            }
+            let backoff = restart::backoff(restarts);
+            warn!("container process exited with {:?}, restarting in {:?}", exit_code, backoff);
+            if !self.pause(backoff) {
+                debug!("the container was stopped, it isn't restarted");
+                break;
+            }
+            restarts += 1;
+            let restarted = self
+                .stdio()
+                .and_then(|stdio| spawn_with_stdio(|| restart(restarts), stdio));
+            match restarted {
+                Ok(restarted) => (process, stdio) = restarted,
+                Err(err) => {
+                    warn!("unable to restart the container: {}", err);
+                    break;
+                }
+            }
+        }
+        for file in [ATTACH_SOCKET, CONTROL_SOCKET, CONSOLE_SOCKET, MONITOR_FILE] {
+            let _ = std::fs::remove_file(self.path.join(file));
+        }
+        std::process::exit(0);
+    }
+
+    /// Waits for the duration before a restart, false once the runtime
+    /// stopped or deleted the container meanwhile
+    fn pause(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.path.join(STOP_FILE).exists() || !self.path.exists() {
+                return false;
+            }
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => std::thread::sleep(left.min(EXIT_POLL_INTERVAL)),
+                _ => return true,
+            }
+        }
+    }
+
+    /// Pipes of the stdio of the container process, unless it has a terminal
+    fn stdio(&self) -> Result<Option<Stdio>> {
+        if self.console.is_some() {
+            return Ok(None);
+        }
+        let pipe = || pipe2(OFlag::O_CLOEXEC).map_err(Error::sys("unable to create stdio pipe"));
+        let (stdin, stdin_write) = pipe()?;
+        let (stdout_read, stdout) = pipe()?;
+        let (stderr_read, stderr) = pipe()?;
+        Ok(Some(Stdio {
+            container: [stdin, stdout, stderr],
+            input: unsafe { OwnedFd::from_raw_fd(nonblocking(stdin_write)?) },
+            outputs: vec![
+                (unsafe { OwnedFd::from_raw_fd(nonblocking(stdout_read)?) }, Stream::Stdout),
+                (unsafe { OwnedFd::from_raw_fd(nonblocking(stderr_read)?) }, Stream::Stderr),
+            ],
+        }))
+    }
+
+    /// Copies the stdio of the process from and to the clients until it exits
+    fn supervise(&self, process: &Process, stdio: Option<Stdio>) -> Result<()> {
+        let attach_path = self.path.join(ATTACH_SOCKET);
+        let _ = std::fs::remove_file(&attach_path);
+        let attach = UnixListener::bind(&attach_path).map_err(Error::io(format!(
+            "unable to bind attach socket {:?}",
+            attach_path
+        )))?;
+        attach
+            .set_nonblocking(true)
+            .map_err(Error::io("unable to set attach socket nonblocking"))?;
+        let control_path = self.path.join(CONTROL_SOCKET);
+        let _ = std::fs::remove_file(&control_path);
+        let control = UnixDatagram::bind(&control_path).map_err(Error::io(format!(
+            "unable to bind control socket {:?}",
+            control_path
+        )))?;
+        let mut log = JsonFileLog::open(&self.path.join(LOG_FILE), self.log_rotation)?;
+        // Blocked once the container process is forked, it doesn't inherit the mask
+        let mut hangup = SigSet::empty();
+        hangup.add(Signal::SIGHUP);
+        hangup
+            .thread_block()
+            .map_err(Error::sys("unable to block SIGHUP"))?;
+        let mut reopen = SignalFd::with_flags(&hangup, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+            .map_err(Error::sys("unable to create the SIGHUP signalfd"))?;
+        let mut recorder = match self.record {
+            true => Recorder::create(&recording_path(&self.path), None)
+                .map_err(|err| warn!("unable to record the session: {}", err))
+                .ok(),
+            false => None,
+        };
+
+        let (mut input, mut outputs) = match stdio {
+            Some(stdio) => (Some(stdio.input), stdio.outputs),
+            None => (None, Vec::new()),
+        };
+        let mut clients: Vec<Client> = Vec::new();
+        let mut buf = vec![0u8; BUF_SIZE];
+        let splice = Splice::new()?;
+
+        loop {
+            let mut sources = Vec::new();
+            let mut fds = Vec::new();
+            let mut watch = |source: Source, fd: RawFd| {
+                let events = match source {
+                    Source::Drain(_) => PollFlags::POLLOUT,
+                    _ => PollFlags::POLLIN,
+                };
+                sources.push(source);
+                fds.push(PollFd::new(fd, events));
+            };
+            if let Some(pidfd) = process.pidfd() {
+                watch(Source::Exit, pidfd);
+            }
+            // Left unread while a client is behind, with the block overflow
+            let blocked = self.console_buffer.overflow == Overflow::Block
+                && clients.iter().any(|client| client.full(&self.console_buffer));
+            if !blocked {
+                for (index, (output, _)) in outputs.iter().enumerate() {
+                    watch(Source::Output(index), output.as_raw_fd());
+                }
+            }
+            watch(Source::Attach, attach.as_raw_fd());
+            watch(Source::Control, control.as_raw_fd());
+            watch(Source::Reopen, reopen.as_raw_fd());
+            if let (Some(console), None) = (&self.console, &input) {
+                watch(Source::Console, console.as_raw_fd());
+            }
+            for (index, client) in clients.iter().enumerate() {
+                if client.reading {
+                    watch(Source::Client(index), client.stream.as_raw_fd());
+                }
+                if !client.pending.is_empty() {
+                    watch(Source::Drain(index), client.stream.as_raw_fd());
+                }
+            }
+
+            let timeout = match process.pidfd() {
+                Some(_) => -1,
+                None => EXIT_POLL_INTERVAL.as_millis() as i32,
+            };
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(err) => return Err(Error::sys("error polling the container stdio")(err)),
+            }
+
+            let ready: Vec<Source> = sources
+                .into_iter()
+                .zip(fds.iter())
+                .filter(|(_, fd)| fd.revents().is_some_and(|revents| !revents.is_empty()))
+                .map(|(source, _)| source)
+                .collect();
+            let mut closed = Vec::new();
+            for source in ready {
+                match source {
+                    Source::Exit => (),
+                    Source::Output(index) => {
+                        let (output, stream) = &outputs[index];
+                        let fd = output.as_raw_fd();
+                        let copied = copy_output(
+                            fd,
+                            *stream,
+                            &mut buf,
+                            &mut log,
+                            &mut recorder,
+                            &mut clients,
+                            &self.console_buffer,
+                        );
+                        if copied.is_none() {
+                            closed.push(index);
+                        }
+                    }
+                    Source::Attach => {
+                        while let Ok((stream, _)) = attach.accept() {
+                            // Its output is buffered instead of blocking the monitor
+                            if stream.set_nonblocking(true).is_err() {
+                                continue;
+                            }
+                            clients.push(Client {
+                                stream,
+                                reading: true,
+                                connected: true,
+                                pending: VecDeque::new(),
+                            });
+                        }
+                    }
+                    Source::Control => {
+                        let num = control.recv(&mut buf).unwrap_or(0);
+                        let msg = String::from_utf8_lossy(&buf[..num]);
+                        match (parse_resize(&msg), &input) {
+                            // The input of a terminal is the pty master
+                            (Some((rows, cols)), Some(master)) if self.console.is_some() => {
+                                if let Err(err) =
+                                    terminal::set_window_size(master.as_raw_fd(), rows, cols)
+                                {
+                                    warn!("{}", err);
+                                }
+                                record(&mut recorder, |recorder| recorder.resize(rows, cols));
+                            }
+                            (Some(_), _) => (),
+                            (None, _) => debug!("invalid control message {:?}", msg),
+                        }
+                    }
+                    Source::Reopen => {
+                        while let Ok(Some(_)) = reopen.read_signal() {}
+                        debug!("reopening the log");
+                        if let Err(err) = log.reopen() {
+                            warn!("unable to reopen the log: {}", err);
+                        }
+                    }
+                    Source::Console => {
+                        let console = self.console.as_ref().expect("console is watched");
+                        if let Ok((stream, _)) = console.accept() {
+                            let master = recv_fd(stream.as_raw_fd())?;
+                            nonblocking(master.as_raw_fd())?;
+                            if let Some((rows, cols)) = terminal::window_size(master.as_raw_fd())
+                                .filter(|(rows, cols)| *rows > 0 && *cols > 0)
+                            {
+                                record(&mut recorder, |recorder| recorder.resize(rows, cols));
+                            }
+                            let output = dup(&master)?;
+                            input = Some(master);
+                            // The console mixes both streams
+                            outputs.push((output, Stream::Stdout));
+                        }
+                    }
+                    Source::Client(index) => {
+                        let client = &mut clients[index];
+                        let copied = copy_input(
+                            client.stream.as_raw_fd(),
+                            input.as_ref().map(AsRawFd::as_raw_fd),
+                            &splice,
+                            &mut buf,
+                            &mut recorder,
+                        );
+                        match copied {
+                            Err(nix::Error::Sys(Errno::EAGAIN))
+                            | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                            // The output still goes to the client after the end of its input
+                            Ok(0) | Err(_) => client.reading = false,
+                            Ok(_) => (),
+                        }
+                    }
+                    Source::Drain(index) => clients[index].flush(),
+                }
+            }
+            for index in closed.into_iter().rev() {
+                outputs.remove(index);
+            }
+            clients.retain(|client| client.connected);
+
+            if fork::exited(process.pid()) {
+                // What the process wrote before exiting
+                for (output, stream) in outputs.iter() {
+                    let fd = output.as_raw_fd();
+                    while copy_output(
+                        fd,
+                        *stream,
+                        &mut buf,
+                        &mut log,
+                        &mut recorder,
+                        &mut clients,
+                        &self.console_buffer,
+                    )
+                    .unwrap_or(0)
+                        > 0
+                    {}
+                }
+                if let Err(err) = log.flush() {
+                    warn!("unable to write the log: {}", err);
+                }
+                for client in clients.iter_mut() {
+                    client.finish();
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Records the exit of the container process
+    fn exited(&self, exit_code: Option<i32>, stopped: &impl Fn(Option<i32>)) {
+        debug!("container process exited with {:?}", exit_code);
+        let status = ExitStatus {
+            exit_code,
+            finished_at: Local::now().to_rfc3339(),
+        };
+        // The container may be deleted already
+        if self.path.exists() {
+            if let Err(err) = std::fs::write(
+                self.path.join(EXIT_FILE),
+                serde_json::to_string(&status).unwrap(),
+            ) {
+                warn!("unable to record the exit status: {}", err);
+            }
+            stopped(exit_code);
+        }
+    }
+}
+
+/// Stdio of the container process without a terminal
+struct Stdio {
+    /// Ends of the container process
+    container: [RawFd; 3],
+    /// Write end of stdin
+    input: OwnedFd,
+    /// Read ends of stdout and stderr
+    outputs: Vec<(OwnedFd, Stream)>,
+}
+
+struct Client {
+    stream: UnixStream,
+    /// Until the end of its input
+    reading: bool,
+    connected: bool,
+    /// Output the client didn't take yet, bounded by the console buffer
+    pending: VecDeque<u8>,
+}
+
+impl Client {
+    /// Writes the output, buffering what the client doesn't take yet
+    fn send(&mut self, data: &[u8], buffer: &ConsoleBuffer) {
+        self.pending.extend(data);
+        if buffer.overflow == Overflow::DropOldest && self.pending.len() > buffer.size {
+            let dropped = self.pending.len() - buffer.size;
+            debug!("dropping {} bytes of output of a slow client", dropped);
+            self.pending.drain(..dropped);
+        }
+        self.flush();
+    }
+
+    /// Writes the buffered output until the client stops taking it
+    fn flush(&mut self) {
+        while self.connected && !self.pending.is_empty() {
+            match (&self.stream).write(self.pending.as_slices().0) {
+                Ok(num) => drop(self.pending.drain(..num)),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(_) => {
+                    self.connected = false;
+                    self.pending.clear();
+                }
+            }
+        }
+    }
+
+    fn full(&self, buffer: &ConsoleBuffer) -> bool {
+        self.pending.len() >= buffer.size
+    }
+
+    /// Writes the rest of the output once the process exited, a client not
+    /// taking it within the timeout is dropped
+    fn finish(&mut self) {
+        if self.stream.set_nonblocking(false).is_err()
+            || self.stream.set_write_timeout(Some(CLIENT_TIMEOUT)).is_err()
+        {
+            return;
+        }
+        let (front, back) = self.pending.as_slices();
+        let _ = (&self.stream)
+            .write_all(front)
+            .and_then(|_| (&self.stream).write_all(back));
+        self.pending.clear();
+    }
+}
+
+/// What the monitor waits for
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Exit,
+    Output(usize),
+    Attach,
+    Control,
+    /// SIGHUP, to reopen the log
+    Reopen,
+    Console,
+    /// Input of a client
+    Client(usize),
+    /// A client able to take its buffered output
+    Drain(usize),
+}
+
+/// Forks the container process with the stdio as its fds 0, 1 and 2, leaving
+/// /dev/null as the ones of the monitor, detached from the terminal of create
+fn spawn_with_stdio(
+    spawn: impl FnOnce() -> Result<Process>,
+    stdio: Option<Stdio>,
+) -> Result<(Process, Option<Stdio>)> {
+    if let Some(stdio) = &stdio {
+        for (target, fd) in stdio.container.iter().enumerate() {
+            dup2(*fd, target as RawFd).map_err(Error::sys("unable to dup2 stdio"))?;
+        }
+    }
+    let process = spawn();
+
+    let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())
+        .map_err(Error::sys("unable to open /dev/null"))?;
+    for target in 0..3 {
+        let _ = dup2(null, target);
+    }
+    let _ = close(null);
+    if let Some(stdio) = &stdio {
+        for fd in stdio.container.iter() {
+            let _ = close(*fd);
+        }
+    }
+    Ok((process?, stdio))
+}
+
+/// Copies the available output to the log, the recording and the clients,
+/// returning the number of bytes copied or None at the end of the output.
+/// It's read through the backend of the log, io_uring when built with it.
+fn copy_output(
+    fd: RawFd,
+    stream: Stream,
+    buf: &mut [u8],
+    log: &mut JsonFileLog,
+    recorder: &mut Option<Recorder>,
+    clients: &mut [Client],
+    buffer: &ConsoleBuffer,
+) -> Option<usize> {
+    let num = match log.read_available(fd, buf) {
+        Ok(0) => return None,
+        Ok(num) => num,
+        Err(Error::Sys { source, .. })
+            if matches!(source.as_errno(), Some(Errno::EAGAIN) | Some(Errno::EINTR)) =>
+        {
+            return Some(0)
+        }
+        // The hangup of the pty slave reads as EIO
+        Err(_) => return None,
+    };
+    if let Err(err) = log.write(stream, &buf[..num]) {
+        warn!("unable to write the log: {}", err);
+    }
+    record(recorder, |recorder| recorder.output(&buf[..num]));
+    for client in clients.iter_mut() {
+        client.send(&buf[..num], buffer);
+    }
+    Some(num)
+}
+
+/// Moves the available input of a client to the container process, returning
+/// the number of bytes or 0 at the end of the input. The input is spliced to
+/// the process unless recorded, the recording needs the data.
+fn copy_input(
+    fd: RawFd,
+    input: Option<RawFd>,
+    splice: &Splice,
+    buf: &mut [u8],
+    recorder: &mut Option<Recorder>,
+) -> nix::Result<usize> {
+    if let (Some(input), None) = (input, &recorder) {
+        match splice.fill(fd, false) {
+            Ok(num) => {
+                // The process doesn't read its stdin, the input is dropped
+                if let Err(err) = splice.drain(input, num, false) {
+                    debug!("dropping input: {}", err);
+                    splice.discard();
+                }
+                return Ok(num);
+            }
+            Err(nix::Error::Sys(Errno::EINVAL)) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    let num = read(fd, buf)?;
+    if let Some(input) = input {
+        write_input(input, &buf[..num]);
+        record(recorder, |recorder| recorder.input(&buf[..num]));
+    }
+    Ok(num)
+}
+
+/// Records the event, the recording stops at its first error
+fn record(recorder: &mut Option<Recorder>, event: impl FnOnce(&mut Recorder) -> Result<()>) {
+    if let Some(err) = recorder.as_mut().and_then(|recorder| event(recorder).err()) {
+        warn!("unable to record the session, stopping: {}", err);
+        *recorder = None;
+    }
+}
+
+fn write_input(fd: RawFd, mut data: &[u8]) {
+    while !data.is_empty() {
+        match write(fd, data) {
+            Ok(num) => data = &data[num..],
+            Err(nix::Error::Sys(Errno::EINTR)) => (),
+            // The process doesn't read its stdin, the input is dropped
+            Err(err) => {
+                debug!("dropping {} bytes of input: {}", data.len(), err);
+                return;
+            }
+        }
+    }
+}
+
+fn nonblocking(fd: RawFd) -> Result<RawFd> {
+    let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(Error::sys("fcntl F_GETFL failed"))?;
+    let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+    fcntl(fd, FcntlArg::F_SETFL(flags)).map_err(Error::sys("fcntl F_SETFL failed"))?;
+    Ok(fd)
+}
+
+fn dup(fd: &OwnedFd) -> Result<OwnedFd> {
+    let fd = fcntl(fd.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(3))
+        .map_err(Error::sys("unable to dup the pty master"))?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Receives the pty master sent by `PtySocket::send_pty`
+fn recv_fd(socket: RawFd) -> Result<OwnedFd> {
+    let mut buf = [0u8; 64];
+    let iov = [IoVec::from_mut_slice(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+
+    let msg = recvmsg(socket, &iov, Some(&mut cmsg), MsgFlags::empty())
+        .map_err(Error::sys("unable to receive the pty master"))?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+            }
+        }
+    }
+    Err(Error::Runtime("no pty master received".to_string()))
+}
+
+/// Parses a `resize ROWS COLS` control message
+fn parse_resize(msg: &str) -> Option<(u16, u16)> {
+    let mut fields = msg.split_whitespace();
+    if fields.next()? != "resize" {
+        return None;
+    }
+    let rows = fields.next()?.parse().ok()?;
+    let cols = fields.next()?.parse().ok()?;
+    Some((rows, cols))
+}
+
+/// Resizes the terminal of the container process of the monitor
+pub fn resize(container_path: &Path, rows: u16, cols: u16) -> Result<()> {
+    let socket = UnixDatagram::unbound().map_err(Error::io("unable to create socket"))?;
+    socket
+        .send_to(
+            format!("resize {} {}", rows, cols).as_bytes(),
+            container_path.join(CONTROL_SOCKET),
+        )
+        .map(drop)
+        .map_err(Error::io("unable to send the window size to the monitor"))
+}
+
+/// Follows the window size of the terminal with the one of the container
+/// process from a background thread, until the process exits
+pub fn follow_window_size(container_path: &Path, tty: RawFd) -> Result<()> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGWINCH);
+    // Blocked before the thread starts, so SIGWINCH only reaches sigwait
+    mask.thread_block()
+        .map_err(Error::sys("unable to block SIGWINCH"))?;
+    let container_path = container_path.to_path_buf();
+    std::thread::spawn(move || loop {
+        if let Some((rows, cols)) = terminal::window_size(tty) {
+            if resize(&container_path, rows, cols).is_err() {
+                return;
+            }
+        }
+        if mask.wait().is_err() {
+            return;
+        }
+    });
+    Ok(())
+}
+
+fn parse_monitor_file(content: &str) -> Option<(Pid, u64)> {
+    let (pid, start_time) = content.trim().split_once(' ')?;
+    Some((Pid::from_raw(pid.parse().ok()?), start_time.parse().ok()?))
+}
+
+/// Keeps the monitor of the container from restarting it, once the runtime
+/// stops the container
+pub fn stop_restarts(container_path: &Path) -> Result<()> {
+    match std::fs::write(container_path.join(STOP_FILE), "") {
+        // A container without its state dir can't be restarted
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(Error::io(format!("unable to write {}", STOP_FILE))),
+    }
+}
+
+/// Waits for the monitor of the container to exit, if any, and returns the
+/// exit status it recorded. None for a container without a monitor.
+pub fn wait(container_path: &Path) -> Result<Option<ExitStatus>> {
+    if let Ok(content) = std::fs::read_to_string(container_path.join(MONITOR_FILE)) {
+        let (pid, start_time) = parse_monitor_file(&content)
+            .ok_or_else(|| Error::State(format!("invalid {}: {:?}", MONITOR_FILE, content)))?;
+        match Process::open(pid, Some(start_time)) {
+            Ok(monitor) => monitor.wait_exit()?,
+            Err(err) => debug!("monitor already gone: {}", err),
+        }
+    }
+    match std::fs::read_to_string(container_path.join(EXIT_FILE)) {
+        Ok(status) => serde_json::from_str(&status)
+            .map(Some)
+            .map_err(Error::json(format!("invalid {}", EXIT_FILE))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(Error::io(format!("unable to read {}", EXIT_FILE))(err)),
+    }
+}
+
+/// Copies the input to the container process of the monitor and its output to
+/// the output, until the container process exits
+pub fn attach(container_path: &Path, input: RawFd, output: RawFd) -> Result<()> {
+    let path = container_path.join(ATTACH_SOCKET);
+    let stream = UnixStream::connect(&path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused => {
+            Error::State("container has no monitor to attach to".to_string())
+        }
+        _ => Error::io(format!("unable to connect to {:?}", path))(err),
+    })?;
+
+    let writer = stream
+        .try_clone()
+        .map_err(Error::io("unable to clone attach socket"))?;
+    std::thread::spawn(move || {
+        let _ = terminal::proxy(input, writer.as_raw_fd());
+        // The output keeps coming after the end of the input
+        let _ = writer.shutdown(std::net::Shutdown::Write);
+    });
+
+    match terminal::proxy(stream.as_raw_fd(), output) {
+        Ok(_) => Ok(()),
+        // The terminal of the runtime hung up, the output has nowhere to go
+        Err(Error::Sys { source, .. })
+            if matches!(source.as_errno(), Some(Errno::EIO) | Some(Errno::EPIPE)) =>
+        {
+            debug!("output of the attached container closed: {}", source);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::socket::{setsockopt, sockopt::SndBuf};
+    use std::{
+        collections::VecDeque,
+        io::Read,
+        os::unix::{io::AsRawFd, net::UnixStream},
+        path::Path,
+    };
+
+    use super::{
+        parse_monitor_file, parse_resize, wait, Client, ConsoleBuffer, ExitStatus, Overflow,
+        EXIT_FILE,
+    };
+
+    #[test]
+    fn exit_status() {
+        let path = Path::new("./monitor_test");
+        std::fs::create_dir_all(path).unwrap();
+        // Neither a monitor nor a recorded exit
+        assert_eq!(wait(path).unwrap(), None);
+
+        let status = ExitStatus {
+            exit_code: Some(3),
+            finished_at: String::from("2021-06-01T00:00:00+00:00"),
+        };
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(
+            json,
+            r#"{"exitCode":3,"finishedAt":"2021-06-01T00:00:00+00:00"}"#
+        );
+        std::fs::write(path.join(EXIT_FILE), json).unwrap();
+        assert_eq!(wait(path).unwrap(), Some(status));
+
+        assert_eq!(
+            parse_monitor_file("42 1234\n").map(|(_, start)| start),
+            Some(1234)
+        );
+        assert_eq!(parse_monitor_file("42"), None);
+
+        assert_eq!(parse_resize("resize 24 80"), Some((24, 80)));
+        assert_eq!(parse_resize("resize 24"), None);
+        assert_eq!(parse_resize("close"), None);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn client_buffer() {
+        let client = |overflow| {
+            let (stream, peer) = UnixStream::pair().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            setsockopt(stream.as_raw_fd(), SndBuf, &4096).unwrap();
+            let client = Client {
+                stream,
+                reading: true,
+                connected: true,
+                pending: VecDeque::new(),
+            };
+            let buffer = ConsoleBuffer {
+                size: 1024,
+                overflow,
+            };
+            peer.set_nonblocking(true).unwrap();
+            (client, buffer, peer)
+        };
+        // More than the socket takes before the client reads it
+        let chunks: Vec<Vec<u8>> = (0..16u8).map(|byte| vec![byte; 64 * 1024]).collect();
+
+        let (mut slow, buffer, mut peer) = client(Overflow::DropOldest);
+        for chunk in chunks.iter() {
+            slow.send(chunk, &buffer);
+        }
+        assert_eq!(slow.pending.len(), buffer.size);
+        assert!(slow.pending.iter().all(|byte| *byte == 15));
+        // The rest once the client reads
+        let mut received = Vec::new();
+        let _ = peer.read_to_end(&mut received);
+        slow.flush();
+        assert!(slow.pending.is_empty());
+        let _ = peer.read_to_end(&mut received);
+        assert!(received.len() < 16 * 64 * 1024);
+        assert!(received.ends_with(&[15; 1024]));
+
+        let (mut slow, buffer, _peer) = client(Overflow::Block);
+        for chunk in chunks.iter() {
+            slow.send(chunk, &buffer);
+        }
+        assert!(slow.full(&buffer));
+        assert!(slow.pending.len() > buffer.size);
+        assert!(slow.connected);
+    }
+}
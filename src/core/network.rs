@@ -0,0 +1,456 @@
+use log::{debug, warn};
+use nix::{
+    fcntl::{open, OFlag},
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sched::{setns, CloneFlags},
+    sys::stat::Mode,
+    unistd::{close, getpid, Pid},
+};
+use std::{
+    collections::HashMap, fs::OpenOptions, io::Write, net::IpAddr, os::unix::process::CommandExt,
+    path::Path, process::Command,
+};
+
+use crate::core::{
+    common::{disabled, Error, Result},
+    slirp,
+};
+use crate::oci::spec::NetDevice;
+
+/// Annotation selecting the network mode: `bridge`, `slirp4netns` or `pasta`
+pub const MODE_ANNOTATION: &str = "pura.network.mode";
+/// Annotation naming the bridge, enabling the bridge mode
+pub const BRIDGE_ANNOTATION: &str = "pura.network.bridge";
+/// Annotation giving the address of the container, in CIDR notation
+pub const ADDRESS_ANNOTATION: &str = "pura.network.address";
+/// Annotation giving the default gateway of the container
+pub const GATEWAY_ANNOTATION: &str = "pura.network.gateway";
+
+/// Name of the interface inside the container
+pub const CONTAINER_INTERFACE: &str = "eth0";
+const IP_BINARY: &str = "ip";
+/// IFNAMSIZ without the trailing NUL
+const MAX_INTERFACE_NAME: usize = 15;
+/// Host interfaces moved into the container, a `NAME HOST_NAME` line each
+pub const NET_DEVICES_FILE: &str = "net.devices";
+/// Bind mount of the network namespace of the container, keeping its devices
+/// around after the container process until they're returned
+const NETNS_FILE: &str = "netns";
+
+/// Network set up in the new network namespace of the container, without a CNI plugin.
+///
+/// The mode comes from the `pura.network.*` annotations of the spec or the
+/// `--network` flag of create, `bridge=NAME,address=CIDR[,gateway=IP]`,
+/// `slirp4netns` or `pasta`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Network {
+    Bridge(Bridge),
+    /// User-mode networking for rootless containers, see `core::slirp`
+    Slirp4netns,
+    /// Same as slirp4netns, with pasta(1) of passt
+    Pasta,
+}
+
+impl Network {
+    /// Parses the value of the `--network` flag
+    pub fn parse(network: &str) -> Result<Network> {
+        match network {
+            "slirp4netns" => Ok(Network::Slirp4netns),
+            "pasta" => Ok(Network::Pasta),
+            _ => Ok(Network::Bridge(Bridge::parse(network)?)),
+        }
+    }
+
+    /// Reads the network of the annotations, if any
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Option<Network>> {
+        match annotations.get(MODE_ANNOTATION).map(|mode| mode.as_str()) {
+            Some("slirp4netns") => Ok(Some(Network::Slirp4netns)),
+            Some("pasta") => Ok(Some(Network::Pasta)),
+            Some("bridge") => match Bridge::from_annotations(annotations)? {
+                Some(bridge) => Ok(Some(Network::Bridge(bridge))),
+                None => Err(Error::Bundle("network requires a bridge".to_string())),
+            },
+            None => Ok(Bridge::from_annotations(annotations)?.map(Network::Bridge)),
+            Some(mode) => Err(Error::Bundle(format!("unknown network mode {}", mode))),
+        }
+    }
+
+    /// Sets up the network namespace of the container process
+    pub fn setup(&self, id: &str, pid: Pid, container_path: &Path) -> Result<()> {
+        if !cfg!(feature = "network") {
+            return Err(disabled("network"));
+        }
+        match self {
+            Network::Bridge(bridge) => bridge.setup(id, pid),
+            Network::Slirp4netns => slirp::start(slirp::Helper::Slirp4netns, pid, container_path),
+            Network::Pasta => slirp::start(slirp::Helper::Pasta, pid, container_path),
+        }
+    }
+
+    /// Stops what outlives the network namespace of the container
+    pub fn teardown(container_path: &Path) -> Result<()> {
+        let devices = return_net_devices(container_path);
+        slirp::stop(container_path).and(devices)
+    }
+}
+
+/// Whether the kernel accepts the name for a network interface
+pub fn valid_interface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_INTERFACE_NAME
+        && name != "."
+        && name != ".."
+        && !name
+            .chars()
+            .any(|c| c == '/' || c == ':' || c.is_whitespace())
+}
+
+/// Moves the host interfaces of `linux.netDevices` into the network namespace
+/// of the container process, renamed when the spec gives a name.
+///
+/// A physical interface would go back to the host on its own with the
+/// namespace, but under its name in the container, and a virtual one would be
+/// destroyed. The namespace is kept mounted in the container directory
+/// instead, for delete to return the interfaces as they were.
+pub fn move_net_devices(
+    devices: &HashMap<String, NetDevice>,
+    pid: Pid,
+    container_path: &Path,
+) -> Result<()> {
+    if devices.is_empty() {
+        return Ok(());
+    }
+    if !cfg!(feature = "network") {
+        return Err(disabled("network"));
+    }
+    let netns = container_path.join(NETNS_FILE);
+    std::fs::File::create(&netns).map_err(Error::io("unable to create the netns file"))?;
+    mount(
+        Some(format!("/proc/{}/ns/net", pid).as_str()),
+        &netns,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .map_err(Error::sys("unable to keep the network namespace"))?;
+
+    let mut record = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(container_path.join(NET_DEVICES_FILE))
+        .map_err(Error::io("unable to record the net devices"))?;
+    let mut devices: Vec<_> = devices.iter().collect();
+    devices.sort_by_key(|(host_name, _)| *host_name);
+    let pid = pid.to_string();
+    for (host_name, device) in devices {
+        let name = device.name.as_deref().unwrap_or(host_name);
+        debug!(
+            "moving net device {} into the container as {}",
+            host_name, name
+        );
+        ip(
+            &["link", "set", host_name, "netns", &pid, "name", name],
+            None,
+        )?;
+        // Recorded one at a time, a failed create returns what was moved
+        writeln!(record, "{} {}", name, host_name)
+            .map_err(Error::io("unable to record the net devices"))?;
+    }
+    Ok(())
+}
+
+/// Returns the interfaces moved into the container to the host, under their
+/// names on the host, and releases its network namespace
+pub fn return_net_devices(container_path: &Path) -> Result<()> {
+    let record = container_path.join(NET_DEVICES_FILE);
+    let content = match std::fs::read_to_string(&record) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(Error::io("unable to read the net devices")(err)),
+    };
+    let netns = container_path.join(NETNS_FILE);
+    let netns_path = netns.to_string_lossy();
+    let runtime = getpid().to_string();
+
+    // Every device gets its chance, the first error is reported
+    let mut result = Ok(());
+    for line in content.lines() {
+        let (name, host_name) = match line.split_once(' ') {
+            Some(names) => names,
+            None => {
+                warn!("invalid {} line: {:?}", NET_DEVICES_FILE, line);
+                continue;
+            }
+        };
+        debug!("returning net device {} to the host as {}", name, host_name);
+        let returned = ip(
+            &["link", "set", name, "netns", &runtime, "name", host_name],
+            Some(&netns_path),
+        );
+        if let Err(err) = returned {
+            warn!("{}", err);
+            result = result.and(Err(err));
+        }
+    }
+
+    if let Err(err) = umount2(&netns, MntFlags::MNT_DETACH) {
+        warn!("unable to release the network namespace: {}", err);
+    }
+    let _ = std::fs::remove_file(&netns);
+    std::fs::remove_file(&record).map_err(Error::io("unable to remove the net devices"))?;
+    result
+}
+
+/// Basic connectivity as root: a veth pair with one end attached to a bridge of
+/// the host and the other one as `eth0` of the container.
+///
+/// The bridge is created when missing, with the gateway address. The host end
+/// goes away with the network namespace of the container, the bridge stays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bridge {
+    pub bridge: String,
+    pub address: IpAddr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+}
+
+impl Bridge {
+    /// Parses the `bridge=NAME,address=CIDR[,gateway=IP]` form of the CLI flag
+    pub fn parse(network: &str) -> Result<Bridge> {
+        let mut options = HashMap::new();
+        for option in network.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| Error::Bundle(format!("invalid network option {}", option)))?;
+            let key = match key {
+                "bridge" => BRIDGE_ANNOTATION,
+                "address" => ADDRESS_ANNOTATION,
+                "gateway" => GATEWAY_ANNOTATION,
+                _ => return Err(Error::Bundle(format!("unknown network option {}", key))),
+            };
+            options.insert(key.to_string(), value.to_string());
+        }
+        Bridge::from_annotations(&options)?
+            .ok_or_else(|| Error::Bundle("network requires a bridge".to_string()))
+    }
+
+    /// Reads the bridge of the annotations, if set
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Option<Bridge>> {
+        let bridge = match annotations.get(BRIDGE_ANNOTATION) {
+            Some(bridge) => bridge,
+            None => return Ok(None),
+        };
+        if bridge.is_empty() || bridge.len() > MAX_INTERFACE_NAME {
+            return Err(Error::Bundle(format!("invalid bridge name {:?}", bridge)));
+        }
+
+        let address = annotations
+            .get(ADDRESS_ANNOTATION)
+            .ok_or_else(|| Error::Bundle("network requires an address".to_string()))?;
+        let (address, prefix_len) = address
+            .split_once('/')
+            .and_then(|(address, prefix_len)| {
+                Some((address.parse().ok()?, prefix_len.parse().ok()?))
+            })
+            .ok_or_else(|| Error::Bundle(format!("invalid network address {}", address)))?;
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(Error::Bundle(format!(
+                "invalid prefix length {}",
+                prefix_len
+            )));
+        }
+
+        let gateway = match annotations.get(GATEWAY_ANNOTATION) {
+            Some(gateway) => Some(
+                gateway
+                    .parse()
+                    .map_err(|_| Error::Bundle(format!("invalid gateway {}", gateway)))?,
+            ),
+            None => None,
+        };
+
+        Ok(Some(Bridge {
+            bridge: bridge.to_string(),
+            address,
+            prefix_len,
+            gateway,
+        }))
+    }
+
+    /// Name of the host end of the veth pair of the container
+    pub fn host_interface(id: &str) -> String {
+        let id: String = id.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        format!("veth{}", &id[..id.len().min(MAX_INTERFACE_NAME - 4)])
+    }
+
+    /// Connects the network namespace of the container process to the bridge
+    pub fn setup(&self, id: &str, pid: Pid) -> Result<()> {
+        let host = Bridge::host_interface(id);
+        debug!(
+            "connecting container {} to bridge {} through {}",
+            id, self.bridge, host
+        );
+
+        if !Path::new("/sys/class/net").join(&self.bridge).exists() {
+            ip(&["link", "add", &self.bridge, "type", "bridge"], None)?;
+            if let Some(gateway) = &self.gateway {
+                let gateway = format!("{}/{}", gateway, self.prefix_len);
+                ip(&["addr", "add", &gateway, "dev", &self.bridge], None)?;
+            }
+            ip(&["link", "set", &self.bridge, "up"], None)?;
+        }
+
+        let pid = pid.to_string();
+        ip(
+            &[
+                "link",
+                "add",
+                &host,
+                "type",
+                "veth",
+                "peer",
+                "name",
+                CONTAINER_INTERFACE,
+                "netns",
+                &pid,
+            ],
+            None,
+        )?;
+        ip(&["link", "set", &host, "master", &self.bridge, "up"], None)?;
+
+        let netns = format!("/proc/{}/ns/net", pid);
+        let address = format!("{}/{}", self.address, self.prefix_len);
+        ip(&["link", "set", "lo", "up"], Some(&netns))?;
+        ip(
+            &["addr", "add", &address, "dev", CONTAINER_INTERFACE],
+            Some(&netns),
+        )?;
+        ip(&["link", "set", CONTAINER_INTERFACE, "up"], Some(&netns))?;
+        if let Some(gateway) = &self.gateway {
+            ip(
+                &["route", "add", "default", "via", &gateway.to_string()],
+                Some(&netns),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs ip(8), in the network namespace at the path if any
+fn ip(args: &[&str], netns: Option<&str>) -> Result<()> {
+    let mut command = Command::new(IP_BINARY);
+    command.args(args);
+    if let Some(netns) = netns {
+        let fd = open(netns, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+            .map_err(Error::sys(format!("unable to open {}", netns)))?;
+        // Only setns(2) runs between the fork and the exec
+        unsafe {
+            command.pre_exec(move || {
+                setns(fd, CloneFlags::CLONE_NEWNET).map_err(|err| {
+                    std::io::Error::from_raw_os_error(
+                        err.as_errno().map_or(0, |errno| errno as i32),
+                    )
+                })
+            });
+        }
+        let output = command.output();
+        let _ = close(fd);
+        return check(args, output);
+    }
+    check(args, command.output())
+}
+
+fn check(args: &[&str], output: std::io::Result<std::process::Output>) -> Result<()> {
+    let output = output.map_err(Error::io(format!("unable to run {}", IP_BINARY)))?;
+    if !output.status.success() {
+        return Err(Error::Runtime(format!(
+            "{} {} failed: {}",
+            IP_BINARY,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{valid_interface_name, Bridge, Network, BRIDGE_ANNOTATION, MODE_ANNOTATION};
+
+    #[test]
+    fn network_options() {
+        let network = Bridge::parse("bridge=pura0,address=10.88.0.2/16,gateway=10.88.0.1").unwrap();
+        assert_eq!(network.bridge, "pura0");
+        assert_eq!(network.address.to_string(), "10.88.0.2");
+        assert_eq!(network.prefix_len, 16);
+        assert_eq!(network.gateway.unwrap().to_string(), "10.88.0.1");
+
+        let network = Bridge::parse("bridge=pura0,address=fd00::2/64").unwrap();
+        assert_eq!(network.gateway, None);
+
+        assert!(Bridge::parse("bridge=pura0").is_err());
+        assert!(Bridge::parse("address=10.88.0.2/16").is_err());
+        assert!(Bridge::parse("bridge=pura0,address=10.88.0.2").is_err());
+        assert!(Bridge::parse("bridge=pura0,address=10.88.0.2/33").is_err());
+        assert!(Bridge::parse("bridge=pura0,address=10.88.0.2/16,mtu=1500").is_err());
+
+        assert_eq!(Bridge::from_annotations(&HashMap::new()).unwrap(), None);
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            BRIDGE_ANNOTATION.to_string(),
+            "a-very-long-bridge".to_string(),
+        );
+        assert!(Bridge::from_annotations(&annotations).is_err());
+    }
+
+    #[test]
+    fn network_modes() {
+        assert_eq!(Network::parse("slirp4netns").unwrap(), Network::Slirp4netns);
+        assert_eq!(Network::parse("pasta").unwrap(), Network::Pasta);
+        assert!(matches!(
+            Network::parse("bridge=pura0,address=10.88.0.2/16").unwrap(),
+            Network::Bridge(_)
+        ));
+        assert!(Network::parse("tap").is_err());
+
+        let mut annotations = HashMap::new();
+        assert_eq!(Network::from_annotations(&annotations).unwrap(), None);
+        annotations.insert(MODE_ANNOTATION.to_string(), "pasta".to_string());
+        assert_eq!(
+            Network::from_annotations(&annotations).unwrap(),
+            Some(Network::Pasta)
+        );
+        annotations.insert(MODE_ANNOTATION.to_string(), "bridge".to_string());
+        assert!(Network::from_annotations(&annotations).is_err());
+        annotations.insert(MODE_ANNOTATION.to_string(), "host".to_string());
+        assert!(Network::from_annotations(&annotations).is_err());
+    }
+
+    #[test]
+    fn host_interface_name() {
+        assert_eq!(Bridge::host_interface("abc"), "vethabc");
+        assert_eq!(
+            Bridge::host_interface("0123456789abcdef0123"),
+            "veth0123456789a"
+        );
+        assert_eq!(Bridge::host_interface("my_container"), "vethmycontainer");
+    }
+
+    #[test]
+    fn interface_names() {
+        assert!(valid_interface_name("eth0"));
+        assert!(valid_interface_name("enp0s31f6.100"));
+        assert!(!valid_interface_name(""));
+        assert!(!valid_interface_name(".."));
+        assert!(!valid_interface_name("a-very-long-name0"));
+        assert!(!valid_interface_name("eth/0"));
+        assert!(!valid_interface_name("eth 0"));
+        assert!(!valid_interface_name("eth0:1"));
+    }
+}
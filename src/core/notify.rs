@@ -0,0 +1,163 @@
+use log::debug;
+use nix::unistd::{fork, setsid, ForkResult, Pid};
+use std::{
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            fs::PermissionsExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::core::{
+    common::{Error, Result},
+    fork::exited,
+};
+
+/// Environment variable of the sd_notify protocol
+pub const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+/// Directory holding the proxy socket inside the container
+pub const NOTIFY_DIR: &str = "/run/notify";
+const NOTIFY_SOCKET_NAME: &str = "notify.sock";
+
+/// Socket of the container side of the sd_notify proxy.
+/// It lives in `<container>/notify`, bind mounted on `/run/notify` of the container.
+pub struct NotifySocket {
+    socket: UnixDatagram,
+    dir: PathBuf,
+    host: String,
+}
+
+impl NotifySocket {
+    /// Binds the proxy socket if the runtime itself runs with NOTIFY_SOCKET set
+    pub fn from_env(container_path: &Path) -> Result<Option<NotifySocket>> {
+        match std::env::var(NOTIFY_SOCKET_ENV) {
+            Ok(host) if !host.is_empty() => NotifySocket::bind(container_path, &host).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn bind(container_path: &Path, host: &str) -> Result<NotifySocket> {
+        let dir = container_path.join("notify");
+        std::fs::create_dir_all(&dir)
+            .map_err(Error::io(format!("unable to create notify dir {:?}", dir)))?;
+
+        let path = dir.join(NOTIFY_SOCKET_NAME);
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).map_err(Error::io(format!(
+            "unable to bind notify socket {:?}",
+            path
+        )))?;
+        // Any user of the container may notify
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o777))
+            .map_err(Error::io("unable to chmod notify socket"))?;
+
+        Ok(NotifySocket {
+            socket,
+            dir,
+            host: host.to_string(),
+        })
+    }
+
+    /// Host directory to bind mount on NOTIFY_DIR
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Value of NOTIFY_SOCKET inside the container
+    pub fn container_path() -> String {
+        format!("{}/{}", NOTIFY_DIR, NOTIFY_SOCKET_NAME)
+    }
+
+    /// Forks a detached process forwarding the notifications until the container process exits
+    pub fn spawn_proxy(self, pid: Pid) -> Result<()> {
+        debug!("proxying {} of container process {}", self.host, pid);
+        match unsafe { fork() }.map_err(Error::sys("unable to fork notify proxy"))? {
+            ForkResult::Parent { .. } => Ok(()),
+            ForkResult::Child => {
+                let _ = setsid();
+                let code = match self.forward(pid) {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                };
+                std::process::exit(code);
+            }
+        }
+    }
+
+    /// Forwards every datagram (READY=1, WATCHDOG=1, STATUS=...) to the host socket
+    pub fn forward(&self, pid: Pid) -> Result<()> {
+        let host = UnixDatagram::unbound().map_err(Error::io("unable to create socket"))?;
+        let host_addr = host_address(&self.host)?;
+        self.socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .map_err(Error::io("unable to set notify socket timeout"))?;
+
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(len) => {
+                    host.send_to_addr(&buf[..len], &host_addr)
+                        .map_err(Error::io("unable to notify host"))?;
+                }
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(err) => return Err(Error::io("unable to read notify socket")(err)),
+            }
+
+            if exited(pid) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Address of NOTIFY_SOCKET, `@` prefixes an abstract socket
+fn host_address(host: &str) -> Result<SocketAddr> {
+    match host.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(host),
+    }
+    .map_err(Error::io(format!("invalid {} {}", NOTIFY_SOCKET_ENV, host)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixDatagram, path::Path, process::Command};
+
+    use nix::unistd::Pid;
+
+    use super::NotifySocket;
+
+    #[test]
+    fn forward() {
+        let path = Path::new("./notify_test");
+        std::fs::create_dir_all(path).unwrap();
+        let host_path = path.join("host.sock");
+        let host = UnixDatagram::bind(&host_path).unwrap();
+
+        let proxy = NotifySocket::bind(path, host_path.to_str().unwrap()).unwrap();
+        assert_eq!(NotifySocket::container_path(), "/run/notify/notify.sock");
+
+        let container = UnixDatagram::unbound().unwrap();
+        container
+            .send_to(b"READY=1", proxy.dir().join("notify.sock"))
+            .unwrap();
+
+        // The process already exited, the proxy stops after forwarding
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        child.wait().unwrap();
+        proxy.forward(pid).unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = host.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
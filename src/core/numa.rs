@@ -0,0 +1,143 @@
+use log::debug;
+use nix::libc;
+
+use crate::core::common::{Error, Result};
+use crate::oci::spec::MemoryPolicy;
+
+/// Modes of set_mempolicy(2), by their name in the spec
+const MODES: [(&str, i32); 7] = [
+    ("MPOL_DEFAULT", 0),
+    ("MPOL_PREFERRED", 1),
+    ("MPOL_BIND", 2),
+    ("MPOL_INTERLEAVE", 3),
+    ("MPOL_LOCAL", 4),
+    ("MPOL_PREFERRED_MANY", 5),
+    ("MPOL_WEIGHTED_INTERLEAVE", 6),
+];
+/// Mode flags of set_mempolicy(2), by their name in the spec
+const FLAGS: [(&str, i32); 3] = [
+    ("MPOL_F_NUMA_BALANCING", 1 << 13),
+    ("MPOL_F_RELATIVE_NODES", 1 << 14),
+    ("MPOL_F_STATIC_NODES", 1 << 15),
+];
+const BITS_PER_WORD: usize = 8 * std::mem::size_of::<libc::c_ulong>();
+
+pub fn parse_mode(mode: &str) -> Option<i32> {
+    MODES
+        .iter()
+        .find(|(name, _)| *name == mode)
+        .map(|(_, mode)| *mode)
+}
+
+pub fn parse_flag(flag: &str) -> Option<i32> {
+    FLAGS
+        .iter()
+        .find(|(name, _)| *name == flag)
+        .map(|(_, flag)| *flag)
+}
+
+/// Whether the mode takes nodes: never for the default and local policies,
+/// optionally for the preferred one (local without), always for the others.
+/// None when either goes, or for an unknown mode.
+pub fn needs_nodes(mode: &str) -> Option<bool> {
+    match mode {
+        "MPOL_DEFAULT" | "MPOL_LOCAL" => Some(false),
+        "MPOL_PREFERRED" => None,
+        _ => parse_mode(mode).map(|_| true),
+    }
+}
+
+/// Parses a node list like `0-3,5` into the node numbers
+pub fn parse_nodes(nodes: &str) -> Option<Vec<usize>> {
+    let mut parsed = Vec::new();
+    for item in nodes.split(',') {
+        let (start, end): (usize, usize) = match item.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let node = item.parse().ok()?;
+                (node, node)
+            }
+        };
+        if start > end {
+            return None;
+        }
+        parsed.extend(start..=end);
+    }
+    Some(parsed)
+}
+
+/// Bitmask of the nodes in the layout of set_mempolicy(2), with its number of bits
+fn node_mask(nodes: &[usize]) -> (Vec<libc::c_ulong>, usize) {
+    let max = nodes.iter().max().map_or(0, |max| max + 1);
+    let mut mask = vec![0; max.div_ceil(BITS_PER_WORD).max(1)];
+    for node in nodes {
+        mask[node / BITS_PER_WORD] |= 1 << (node % BITS_PER_WORD);
+    }
+    // The kernel ignores the last bit of maxnode
+    (mask, max + 1)
+}
+
+/// Sets the NUMA memory policy of `linux.memoryPolicy` for the current
+/// process, kept across fork and exec by the container process
+pub fn set_memory_policy(policy: &MemoryPolicy) -> Result<()> {
+    let mut mode = parse_mode(&policy.mode)
+        .ok_or_else(|| Error::Bundle(format!("unknown memory policy mode {}", policy.mode)))?;
+    for flag in policy.flags.iter().flatten() {
+        mode |= parse_flag(flag)
+            .ok_or_else(|| Error::Bundle(format!("unknown memory policy flag {}", flag)))?;
+    }
+    let nodes = match policy.nodes.as_deref().filter(|nodes| !nodes.is_empty()) {
+        Some(nodes) => parse_nodes(nodes)
+            .ok_or_else(|| Error::Bundle(format!("invalid memory policy nodes {}", nodes)))?,
+        None => Vec::new(),
+    };
+    let (mask, max_node) = node_mask(&nodes);
+    debug!("setting memory policy {} on nodes {:?}", policy.mode, nodes);
+
+    if unsafe { libc::syscall(libc::SYS_set_mempolicy, mode, mask.as_ptr(), max_node) } < 0 {
+        return Err(Error::sys("unable to set the memory policy")(
+            nix::Error::last(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{node_mask, parse_flag, parse_mode, parse_nodes, set_memory_policy};
+    use crate::oci::spec::MemoryPolicy;
+
+    #[test]
+    fn memory_policy() {
+        assert_eq!(parse_mode("MPOL_INTERLEAVE"), Some(3));
+        assert_eq!(parse_mode("MPOL_UNKNOWN"), None);
+        assert_eq!(parse_flag("MPOL_F_STATIC_NODES"), Some(1 << 15));
+
+        assert_eq!(parse_nodes("0-2,5"), Some(vec![0, 1, 2, 5]));
+        assert_eq!(parse_nodes("3-1"), None);
+        assert_eq!(parse_nodes("0,"), None);
+        assert_eq!(node_mask(&[0, 2]), (vec![0b101], 4));
+        assert_eq!(node_mask(&[64]).0, vec![0, 1]);
+
+        // Node 0 exists on every NUMA-capable machine
+        set_memory_policy(&MemoryPolicy {
+            mode: String::from("MPOL_PREFERRED"),
+            nodes: Some(String::from("0")),
+            flags: None,
+            ..Default::default()
+        })
+        .unwrap();
+        set_memory_policy(&MemoryPolicy {
+            mode: String::from("MPOL_DEFAULT"),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(set_memory_policy(&MemoryPolicy {
+            mode: String::from("MPOL_BIND"),
+            nodes: Some(String::from("x")),
+            flags: None,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use log::{debug, warn};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::core::{
+    cdi::{self, CdiHook, CdiMount, ContainerEdits, DeviceNode, Registry},
+    common::{Error, Result},
+    filesystem::find_executable,
+};
+use crate::oci::spec::Spec;
+
+/// Annotation of the GPUs given to the container, like `--gpus`
+pub const GPUS_ANNOTATION: &str = "pura.gpus";
+/// Kind of the CDI specs of nvidia-ctk, used when the host has them
+const CDI_KIND: &str = "nvidia.com/gpu";
+/// Devices every GPU container needs, those missing on the host skipped
+const CONTROL_DEVICES: [&str; 4] = [
+    "nvidiactl",
+    "nvidia-uvm",
+    "nvidia-uvm-tools",
+    "nvidia-modeset",
+];
+/// Driver libraries of the utility and compute capabilities, by soname prefix
+const DRIVER_LIBRARIES: [&str; 9] = [
+    "libcuda.so",
+    "libcudadebugger.so",
+    "libnvidia-allocator.so",
+    "libnvidia-cfg.so",
+    "libnvidia-gpucomp.so",
+    "libnvidia-ml.so",
+    "libnvidia-nvvm.so",
+    "libnvidia-opencl.so",
+    "libnvidia-ptxjitcompiler.so",
+];
+const DRIVER_BINARIES: [&str; 5] = [
+    "nvidia-smi",
+    "nvidia-debugdump",
+    "nvidia-persistenced",
+    "nvidia-cuda-mps-control",
+    "nvidia-cuda-mps-server",
+];
+/// Architecture of the libraries in the output of `ldconfig -p`
+#[cfg(target_arch = "x86_64")]
+const LDCONFIG_ARCH: &str = "x86-64";
+#[cfg(target_arch = "aarch64")]
+const LDCONFIG_ARCH: &str = "AArch64";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const LDCONFIG_ARCH: &str = "";
+
+/// GPUs given to the container
+#[derive(Debug, Clone, PartialEq)]
+pub enum Gpus {
+    All,
+    Indices(Vec<u32>),
+}
+
+impl Gpus {
+    /// Parses `all` or a comma separated list of indices, like `0,2`
+    pub fn parse(gpus: &str) -> Result<Gpus> {
+        if gpus.trim() == "all" {
+            return Ok(Gpus::All);
+        }
+        let indices = gpus
+            .split(',')
+            .map(|index| index.trim().parse())
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map_err(|_| Error::Bundle(format!("invalid gpus {:?}, all or indices", gpus)))?;
+        Ok(Gpus::Indices(indices))
+    }
+
+    /// Value of `NVIDIA_VISIBLE_DEVICES` and names of the CDI devices
+    fn names(&self) -> Vec<String> {
+        match self {
+            Gpus::All => vec![String::from("all")],
+            Gpus::Indices(indices) => indices.iter().map(u32::to_string).collect(),
+        }
+    }
+}
+
+/// GPUs of the `pura.gpus` annotation, if any
+pub fn annotated_gpus(spec: &Spec) -> Result<Option<Gpus>> {
+    let annotations = spec.annotations.as_ref();
+    match annotations.and_then(|annotations| annotations.get(GPUS_ANNOTATION)) {
+        Some(gpus) => Gpus::parse(gpus).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Gives the GPUs to the container: through the `nvidia.com/gpu` CDI specs
+/// when the host has them, otherwise with the device nodes of the driver, its
+/// libraries and tools bind mounted, and the ldcache of the container updated
+/// by the hook of nvidia-ctk when installed
+pub fn inject(spec: Spec, gpus: &Gpus) -> Result<Spec> {
+    if Registry::load()?.has_kind(CDI_KIND) {
+        debug!("injecting GPUs {:?} from the CDI specs", gpus);
+        let devices: Vec<String> = gpus
+            .names()
+            .iter()
+            .map(|name| format!("{}={}", CDI_KIND, name))
+            .collect();
+        return cdi::inject(spec, &devices);
+    }
+
+    debug!("injecting GPUs {:?} from the driver", gpus);
+    let mut edits = ContainerEdits {
+        env: vec![format!("NVIDIA_VISIBLE_DEVICES={}", gpus.names().join(","))],
+        ..Default::default()
+    };
+    for device in gpu_devices(Path::new("/dev"), gpus)? {
+        let path = device.to_string_lossy().to_string();
+        edits.device_nodes.push(DeviceNode {
+            path,
+            ..Default::default()
+        });
+    }
+
+    let ldconfig = Command::new("ldconfig")
+        .arg("-p")
+        .output()
+        .map_err(Error::io("unable to list the libraries with ldconfig"))?;
+    let libraries = driver_libraries(&String::from_utf8_lossy(&ldconfig.stdout));
+    let path = std::env::var("PATH").ok();
+    let binaries = DRIVER_BINARIES
+        .iter()
+        .filter_map(|binary| find_executable(binary, path.as_deref(), Path::new("/")).ok());
+    for host_path in libraries.iter().cloned().chain(binaries) {
+        edits.mounts.push(CdiMount {
+            container_path: host_path.to_string_lossy().to_string(),
+            host_path: host_path.to_string_lossy().to_string(),
+            mount_type: None,
+            options: ["ro", "nosuid", "nodev", "bind"]
+                .iter()
+                .map(|o| o.to_string())
+                .collect(),
+        });
+    }
+
+    match find_executable("nvidia-ctk", path.as_deref(), Path::new("/")) {
+        Ok(nvidia_ctk) => {
+            let folders: BTreeSet<&Path> =
+                libraries.iter().filter_map(|lib| lib.parent()).collect();
+            let mut args: Vec<String> = ["nvidia-ctk", "hook", "update-ldcache"]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect();
+            for folder in folders {
+                args.push(String::from("--folder"));
+                args.push(folder.to_string_lossy().to_string());
+            }
+            edits.hooks.push(CdiHook {
+                hook_name: String::from("createContainer"),
+                path: nvidia_ctk.to_string_lossy().to_string(),
+                args: Some(args),
+                ..Default::default()
+            });
+        }
+        Err(_) => warn!("nvidia-ctk not found, the ldcache of the container isn't updated"),
+    }
+    cdi::apply(spec, &edits)
+}
+
+/// Device nodes of the GPUs and the control devices in the dev directory
+fn gpu_devices(dev: &Path, gpus: &Gpus) -> Result<Vec<PathBuf>> {
+    if !dev.join("nvidiactl").exists() {
+        return Err(Error::Runtime(String::from(
+            "no NVIDIA driver on the host, /dev/nvidiactl is missing",
+        )));
+    }
+    let mut devices: Vec<PathBuf> = match gpus {
+        Gpus::All => {
+            let entries = std::fs::read_dir(dev).map_err(Error::io("unable to list /dev"))?;
+            let mut devices: Vec<PathBuf> = entries
+                .flatten()
+                .filter(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.strip_prefix("nvidia")
+                        .is_some_and(|index| index.parse::<u32>().is_ok())
+                })
+                .map(|entry| entry.path())
+                .collect();
+            devices.sort();
+            devices
+        }
+        Gpus::Indices(indices) => {
+            let mut devices = Vec::new();
+            for index in indices {
+                let device = dev.join(format!("nvidia{}", index));
+                if !device.exists() {
+                    return Err(Error::Runtime(format!("no GPU {} on the host", index)));
+                }
+                devices.push(device);
+            }
+            devices
+        }
+    };
+    devices.extend(
+        CONTROL_DEVICES
+            .iter()
+            .map(|device| dev.join(device))
+            .filter(|device| device.exists()),
+    );
+    Ok(devices)
+}
+
+/// Driver libraries in the output of `ldconfig -p`, of the architecture of pura
+fn driver_libraries(ldconfig: &str) -> Vec<PathBuf> {
+    let mut libraries: Vec<PathBuf> = ldconfig
+        .lines()
+        .filter_map(|line| {
+            let (name, path) = line.trim().split_once(" => ")?;
+            let (soname, arch) = name.split_once(' ')?;
+            let driver = DRIVER_LIBRARIES.iter().any(|lib| soname.starts_with(lib));
+            (driver && arch.contains(LDCONFIG_ARCH)).then(|| PathBuf::from(path))
+        })
+        .collect();
+    libraries.sort();
+    libraries.dedup();
+    libraries
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{driver_libraries, gpu_devices, Gpus, LDCONFIG_ARCH};
+
+    #[test]
+    fn gpus() {
+        assert_eq!(Gpus::parse("all").unwrap(), Gpus::All);
+        assert_eq!(Gpus::parse("0, 2").unwrap(), Gpus::Indices(vec![0, 2]));
+        assert!(Gpus::parse("gpu0").is_err());
+        assert!(Gpus::parse("").is_err());
+
+        let dev = Path::new("./nvidia_dev_test");
+        std::fs::create_dir_all(dev).unwrap();
+        assert!(gpu_devices(dev, &Gpus::All).is_err());
+        for device in [
+            "nvidiactl",
+            "nvidia-uvm",
+            "nvidia0",
+            "nvidia1",
+            "nvidia-caps",
+        ] {
+            std::fs::write(dev.join(device), "").unwrap();
+        }
+        assert_eq!(
+            gpu_devices(dev, &Gpus::All).unwrap(),
+            ["nvidia0", "nvidia1", "nvidiactl", "nvidia-uvm"].map(|d| dev.join(d))
+        );
+        assert_eq!(
+            gpu_devices(dev, &Gpus::Indices(vec![1])).unwrap(),
+            ["nvidia1", "nvidiactl", "nvidia-uvm"].map(|d| dev.join(d))
+        );
+        assert!(gpu_devices(dev, &Gpus::Indices(vec![2])).is_err());
+        std::fs::remove_dir_all(dev).unwrap();
+    }
+
+    #[test]
+    fn ldconfig_libraries() {
+        let ldconfig = format!(
+            concat!(
+                "4 libs found in cache `/etc/ld.so.cache'\n",
+                "\tlibcuda.so.1 (libc6,{arch}) => /usr/lib/x86_64-linux-gnu/libcuda.so.1\n",
+                "\tlibcuda.so.1 (libc6) => /usr/lib/i386-linux-gnu/libcuda.so.1\n",
+                "\tlibnvidia-ml.so.1 (libc6,{arch}) => /usr/lib/x86_64-linux-gnu/libnvidia-ml.so.1\n",
+                "\tlibz.so.1 (libc6,{arch}) => /lib/x86_64-linux-gnu/libz.so.1\n",
+            ),
+            arch = LDCONFIG_ARCH
+        );
+        let libraries = driver_libraries(&ldconfig);
+        assert!(libraries.contains(&PathBuf::from("/usr/lib/x86_64-linux-gnu/libcuda.so.1")));
+        assert!(libraries.contains(&PathBuf::from(
+            "/usr/lib/x86_64-linux-gnu/libnvidia-ml.so.1"
+        )));
+        assert!(!libraries.contains(&PathBuf::from("/lib/x86_64-linux-gnu/libz.so.1")));
+    }
+}
@@ -0,0 +1,402 @@
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use nix::{libc, unistd::getpid};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Debug,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+use crate::core::common::{Error, Result};
+
+pub use tracing::info_span;
+
+/// Base URL of the collector, the spans are posted to `<endpoint>/v1/traces`
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+/// Full URL of the collector traces endpoint, overriding `ENDPOINT_ENV`
+pub const TRACES_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+const SERVICE_NAME: &str = "pura";
+const TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RESPONSE: u64 = 64 * 1024;
+/// OTLP SPAN_KIND_INTERNAL
+const KIND_INTERNAL: u8 = 1;
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<Exporter>> = Mutex::new(None);
+    static ref OPEN: Mutex<HashMap<u64, OpenSpan>> = Mutex::new(HashMap::new());
+    static ref FINISHED: Mutex<Vec<SpanRecord>> = Mutex::new(Vec::new());
+    static ref TRACE_ID: String = hex_id(16);
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Spans entered by the thread, innermost last
+    static STACK: RefCell<Vec<span::Id>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Span in the OTLP/JSON encoding
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SpanRecord {
+    trace_id: String,
+    span_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    parent_span_id: String,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Attribute>,
+    // Process that recorded the span, the container child only hands its own ones over
+    #[serde(skip)]
+    pid: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Attribute {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+struct OpenSpan {
+    record: SpanRecord,
+    refs: usize,
+}
+
+/// Collector receiving the spans over OTLP/HTTP with the JSON encoding
+struct Exporter {
+    host: String,
+    path: String,
+    // Only the runtime process exports, not the cloned container child
+    pid: i32,
+}
+
+impl Exporter {
+    fn from_env() -> Option<Result<Exporter>> {
+        let url = match std::env::var(TRACES_ENDPOINT_ENV) {
+            Ok(url) => url,
+            Err(_) => format!(
+                "{}/v1/traces",
+                std::env::var(ENDPOINT_ENV).ok()?.trim_end_matches('/')
+            ),
+        };
+        Some(Exporter::new(&url))
+    }
+
+    fn new(url: &str) -> Result<Exporter> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            Error::Runtime(format!("unsupported OTLP endpoint {}, only http is", url))
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/v1/traces"),
+        };
+        // The default port of OTLP/HTTP
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:4318", host)
+        };
+        Ok(Exporter {
+            host,
+            path: path.to_string(),
+            pid: getpid().as_raw(),
+        })
+    }
+
+    fn export(&self, spans: Vec<SpanRecord>) -> Result<()> {
+        let body = serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", SERVICE_NAME)],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": SERVICE_NAME },
+                    "spans": spans,
+                }],
+            }],
+        })
+        .to_string();
+
+        let addr = self
+            .host
+            .to_socket_addrs()
+            .map_err(Error::io(format!("unable to resolve {}", self.host)))?
+            .next()
+            .ok_or_else(|| Error::Runtime(format!("unable to resolve {}", self.host)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+            .map_err(Error::io(format!("unable to connect to {}", self.host)))?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(Error::io("unable to set the OTLP read timeout"))?;
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(Error::io("unable to send the spans"))?;
+
+        // Read the whole response, closing earlier resets the connection
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE)
+            .read_to_end(&mut response)
+            .map_err(Error::io("unable to read the OTLP response"))?;
+        let response = String::from_utf8_lossy(&response);
+        match response.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(Error::Runtime(format!(
+                "collector rejected the spans: {}",
+                response.lines().next().unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Attribute {
+    Attribute {
+        key: key.to_string(),
+        value: AnyValue {
+            string_value: value.to_string(),
+        },
+    }
+}
+
+/// Random trace or span id, hex encoded as in OTLP/JSON
+fn hex_id(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    // getrandom(2) doesn't fail for requests this small
+    unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut libc::c_void, len, 0) };
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn now() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+struct Attributes<'a>(&'a mut Vec<Attribute>);
+
+impl Visit for Attributes<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push(attribute(field.name(), value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push(attribute(field.name(), &format!("{:?}", value)));
+    }
+}
+
+/// Subscriber recording the spans of the runtime for the exporter
+struct Tracer;
+
+impl Subscriber for Tracer {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // Events still go through the log crate
+        metadata.is_span()
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let mut open = OPEN.lock().unwrap();
+        let parent = if attrs.is_contextual() {
+            STACK.with(|stack| stack.borrow().last().cloned())
+        } else {
+            attrs.parent().cloned()
+        };
+        let parent_span_id = parent
+            .and_then(|parent| open.get(&parent.into_u64()))
+            .map(|parent| parent.record.span_id.clone())
+            .unwrap_or_default();
+
+        let mut attributes = Vec::new();
+        attrs.record(&mut Attributes(&mut attributes));
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        open.insert(
+            id,
+            OpenSpan {
+                record: SpanRecord {
+                    trace_id: TRACE_ID.clone(),
+                    span_id: hex_id(8),
+                    parent_span_id,
+                    name: attrs.metadata().name().to_string(),
+                    kind: KIND_INTERNAL,
+                    start_time_unix_nano: now(),
+                    end_time_unix_nano: String::new(),
+                    attributes,
+                    pid: getpid().as_raw(),
+                },
+                refs: 1,
+            },
+        );
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        if let Some(open) = OPEN.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut Attributes(&mut open.record.attributes));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &span::Id) {
+        STACK.with(|stack| stack.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(index) = stack.iter().rposition(|entered| entered == span) {
+                stack.remove(index);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(open) = OPEN.lock().unwrap().get_mut(&span.into_u64()) {
+            open.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut open = OPEN.lock().unwrap();
+        let id = span.into_u64();
+        match open.get_mut(&id) {
+            Some(span) if span.refs > 1 => {
+                span.refs -= 1;
+                false
+            }
+            Some(_) => {
+                let mut record = open.remove(&id).unwrap().record;
+                record.end_time_unix_nano = now();
+                FINISHED.lock().unwrap().push(record);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Records the spans of the runtime when an OTLP endpoint is configured
+/// through `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`
+pub fn init() -> Result<()> {
+    let exporter = match Exporter::from_env() {
+        Some(exporter) => exporter?,
+        None => return Ok(()),
+    };
+    debug!("exporting spans to http://{}{}", exporter.host, exporter.path);
+    tracing::dispatcher::set_global_default(tracing::Dispatch::new(Tracer))
+        .map_err(|err| Error::Runtime(format!("unable to record spans: {}", err)))?;
+    *EXPORTER.lock().unwrap() = Some(exporter);
+    Ok(())
+}
+
+/// Sends the finished spans to the collector, from the runtime process only
+pub fn export() {
+    let exporter = match EXPORTER.lock() {
+        Ok(exporter) => exporter,
+        Err(_) => return,
+    };
+    if let Some(exporter) = exporter.as_ref().filter(|e| e.pid == getpid().as_raw()) {
+        let spans = std::mem::take(&mut *FINISHED.lock().unwrap());
+        if spans.is_empty() {
+            return;
+        }
+        if let Err(err) = exporter.export(spans) {
+            warn!("unable to export spans: {}", err);
+        }
+    }
+}
+
+/// Takes the spans finished by this process, serialized for the runtime process
+/// to import. The container child hands its setup spans over this way before it
+/// execs the user program.
+pub(crate) fn take_finished() -> Vec<String> {
+    let pid = getpid().as_raw();
+    let mut finished = FINISHED.lock().unwrap();
+    let (own, others): (Vec<SpanRecord>, Vec<SpanRecord>) =
+        finished.drain(..).partition(|span| span.pid == pid);
+    *finished = others;
+    own.iter()
+        .filter_map(|span| serde_json::to_string(span).ok())
+        .collect()
+}
+
+/// Imports a span serialized by `take_finished` in another process
+pub(crate) fn import(span: &str) -> Result<()> {
+    let span: SpanRecord = serde_json::from_str(span).map_err(Error::json("invalid span"))?;
+    FINISHED.lock().unwrap().push(span);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, take_finished, Exporter, Tracer, FINISHED};
+    use tracing::info_span;
+
+    #[test]
+    fn nested_spans() {
+        tracing::subscriber::with_default(Tracer, || {
+            let create = info_span!("create", id = "abc").entered();
+            info_span!("mounts").in_scope(|| ());
+            drop(create);
+        });
+
+        let spans = take_finished();
+        assert_eq!(spans.len(), 2);
+        let mounts: serde_json::Value = serde_json::from_str(&spans[0]).unwrap();
+        let create: serde_json::Value = serde_json::from_str(&spans[1]).unwrap();
+        assert_eq!(mounts["name"], "mounts");
+        assert_eq!(mounts["parentSpanId"], create["spanId"]);
+        assert_eq!(mounts["traceId"], create["traceId"]);
+        assert_eq!(create["attributes"][0]["key"], "id");
+        assert_eq!(create["attributes"][0]["value"]["stringValue"], "abc");
+        assert!(create.get("parentSpanId").is_none());
+        assert_eq!(create["traceId"].as_str().unwrap().len(), 32);
+
+        import(&spans[0]).unwrap();
+        assert_eq!(FINISHED.lock().unwrap().drain(..).count(), 1);
+    }
+
+    #[test]
+    fn endpoints() {
+        let exporter = Exporter::new("http://collector:4318/v1/traces").unwrap();
+        assert_eq!(exporter.host, "collector:4318");
+        assert_eq!(exporter.path, "/v1/traces");
+        let exporter = Exporter::new("http://collector").unwrap();
+        assert_eq!(exporter.host, "collector:4318");
+        assert_eq!(exporter.path, "/v1/traces");
+        assert!(Exporter::new("https://collector").is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::core::common::{disabled, Result};
+
+/// Same variables as with the `otlp` feature, refused when set
+const ENDPOINT_ENVS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+
+/// Span of `info_span!` recording nothing
+pub struct Span;
+
+impl Span {
+    pub fn entered(self) -> Span {
+        self
+    }
+
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+}
+
+/// Dropped to close it, like the spans of tracing
+impl Drop for Span {
+    fn drop(&mut self) {}
+}
+
+macro_rules! info_span {
+    ($($args:tt)*) => {
+        $crate::core::otlp::Span
+    };
+}
+pub(crate) use info_span;
+
+/// Fails when an endpoint is configured, the spans can't be recorded
+pub fn init() -> Result<()> {
+    match ENDPOINT_ENVS.iter().any(|env| std::env::var_os(env).is_some()) {
+        true => Err(disabled("otlp")),
+        false => Ok(()),
+    }
+}
+
+pub fn export() {}
+
+pub(crate) fn take_finished() -> Vec<String> {
+    Vec::new()
+}
+
+pub(crate) fn import(_span: &str) -> Result<()> {
+    Ok(())
+}
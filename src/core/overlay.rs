@@ -0,0 +1,125 @@
+use log::debug;
+use nix::{
+    mount::{mount, MsFlags},
+    unistd::geteuid,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::core::common::{Error, Result};
+
+/// Directory of the upper, work and merged dirs in the state dir
+const OVERLAY_DIR: &str = "overlay";
+/// `true` shares the rootfs of the bundle through an overlay, like `--overlay`
+pub const OVERLAY_ANNOTATION: &str = "pura.rootfs.overlay";
+
+/// Overlay of a container over the rootfs of its bundle, the read-only lower
+/// layer many containers can share. The writes of the container go to the
+/// upper dir in its state dir and are gone with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    pub lower: PathBuf,
+    pub upper: PathBuf,
+    pub work: PathBuf,
+    /// Root of the container, the overlay is mounted on it
+    pub merged: PathBuf,
+    /// Rootless, the overlay keeps its metadata in `user.` xattrs (Linux 5.11)
+    userxattr: bool,
+}
+
+impl Overlay {
+    /// Creates the directories of the overlay of the rootfs in the state dir
+    pub fn prepare(container_path: &Path, rootfs: &Path) -> Result<Overlay> {
+        let lower = rootfs
+            .canonicalize()
+            .map_err(Error::io(format!("unable to resolve rootfs {:?}", rootfs)))?;
+        let dir = container_path.join(OVERLAY_DIR);
+        let overlay = Overlay {
+            lower,
+            upper: dir.join("upper"),
+            work: dir.join("work"),
+            merged: dir.join("merged"),
+            userxattr: !geteuid().is_root(),
+        };
+        // Separators of the mount options
+        for path in [&overlay.lower, &dir] {
+            if path.to_string_lossy().contains([',', ':']) {
+                return Err(Error::Bundle(format!(
+                    "{:?} can't be an overlay layer, it has a ',' or ':'",
+                    path
+                )));
+            }
+        }
+        for path in [&overlay.upper, &overlay.work, &overlay.merged] {
+            std::fs::create_dir_all(path)
+                .map_err(Error::io(format!("unable to create {:?}", path)))?;
+        }
+        Ok(overlay)
+    }
+
+    /// Whether the annotations ask for an overlay
+    pub fn annotated(annotations: Option<&HashMap<String, String>>) -> bool {
+        annotations
+            .and_then(|annotations| annotations.get(OVERLAY_ANNOTATION))
+            .is_some_and(|value| value == "true")
+    }
+
+    /// Mount options of the overlay
+    pub fn options(&self) -> String {
+        let mut options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            self.lower.display(),
+            self.upper.display(),
+            self.work.display()
+        );
+        if self.userxattr {
+            options.push_str(",userxattr");
+        }
+        options
+    }
+
+    /// Mounts the overlay on the merged dir, in the mount namespace of the
+    /// container
+    pub fn mount(&self) -> Result<()> {
+        debug!("mounting overlay {} on {:?}", self.options(), self.merged);
+        mount(
+            Some("overlay"),
+            &self.merged,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(self.options().as_str()),
+        )
+        .map_err(Error::sys(format!("unable to mount the overlay on {:?}", self.merged)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path};
+
+    use super::{Overlay, OVERLAY_ANNOTATION};
+
+    #[test]
+    fn prepare() {
+        let path = Path::new("./overlay_test");
+        std::fs::create_dir_all(path.join("rootfs")).unwrap();
+
+        let overlay = Overlay::prepare(&path.join("container"), &path.join("rootfs")).unwrap();
+        assert!(overlay.lower.is_absolute());
+        assert!(overlay.upper.is_dir() && overlay.work.is_dir() && overlay.merged.is_dir());
+        assert!(overlay.options().starts_with(&format!(
+            "lowerdir={},upperdir=./overlay_test/container/overlay/upper,workdir=",
+            overlay.lower.display()
+        )));
+        assert!(Overlay::prepare(&path.join("container"), &path.join("missing")).is_err());
+
+        let annotations: HashMap<String, String> =
+            [(OVERLAY_ANNOTATION.to_string(), "true".to_string())].into();
+        assert!(Overlay::annotated(Some(&annotations)));
+        assert!(!Overlay::annotated(None));
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
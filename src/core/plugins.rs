@@ -0,0 +1,196 @@
+use lazy_static::lazy_static;
+use log::debug;
+use nix::libc;
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    sync::Mutex,
+};
+
+use crate::core::{
+    common::{Error, Result},
+    state::State,
+};
+
+/// Symbol returning the comma separated phases the plugin registers for
+const PHASES_SYMBOL: &str = "pura_plugin_phases";
+/// Symbol called with the phase and the state of the container as JSON,
+/// a non-zero result fails the phase like a failing hook
+const HOOK_SYMBOL: &str = "pura_plugin_hook";
+
+type PhasesFn = unsafe extern "C" fn() -> *const c_char;
+type HookFn = unsafe extern "C" fn(phase: *const c_char, state: *const c_char) -> c_int;
+
+/// Lifecycle phases of the OCI hooks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    CreateRuntime,
+    CreateContainer,
+    StartContainer,
+    Prestart,
+    Poststart,
+    Poststop,
+}
+
+impl Phase {
+    /// Name of the hooks of the phase in the spec
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::CreateRuntime => "createRuntime",
+            Phase::CreateContainer => "createContainer",
+            Phase::StartContainer => "startContainer",
+            Phase::Prestart => "prestart",
+            Phase::Poststart => "poststart",
+            Phase::Poststop => "poststop",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Phase> {
+        [
+            Phase::CreateRuntime,
+            Phase::CreateContainer,
+            Phase::StartContainer,
+            Phase::Prestart,
+            Phase::Poststart,
+            Phase::Poststop,
+        ]
+        .iter()
+        .copied()
+        .find(|phase| phase.name() == name)
+    }
+}
+
+/// Shared library loaded into the runtime, called in-process for its phases
+/// instead of forking a hook. It stays loaded until the runtime exits.
+struct Plugin {
+    path: String,
+    phases: Vec<Phase>,
+    hook: HookFn,
+}
+
+lazy_static! {
+    static ref PLUGINS: Mutex<Vec<Plugin>> = Mutex::new(Vec::new());
+}
+
+/// Loads the plugin, a shared library exporting
+/// `const char *pura_plugin_phases(void)` and
+/// `int pura_plugin_hook(const char *phase, const char *state)`
+pub fn load(path: &str) -> Result<()> {
+    let plugin = open(path)?;
+    debug!("loaded plugin {} for {:?}", path, plugin.phases);
+    PLUGINS.lock().unwrap().push(plugin);
+    Ok(())
+}
+
+/// Whether a loaded plugin runs in the phase
+pub fn registered(phase: Phase) -> bool {
+    PLUGINS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|plugin| plugin.phases.contains(&phase))
+}
+
+fn open(path: &str) -> Result<Plugin> {
+    let c_path = CString::new(path)
+        .map_err(|_| Error::Runtime(format!("invalid plugin path {:?}", path)))?;
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(Error::Runtime(format!(
+            "unable to load plugin {}: {}",
+            path,
+            dl_error()
+        )));
+    }
+
+    let symbol = |name: &str| {
+        let c_name = CString::new(name).unwrap();
+        let symbol = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+        if symbol.is_null() {
+            unsafe { libc::dlclose(handle) };
+            return Err(Error::Runtime(format!("plugin {} has no {}", path, name)));
+        }
+        Ok(symbol)
+    };
+    let phases: PhasesFn = unsafe { std::mem::transmute(symbol(PHASES_SYMBOL)?) };
+    let hook: HookFn = unsafe { std::mem::transmute(symbol(HOOK_SYMBOL)?) };
+
+    let names = unsafe { phases() };
+    if names.is_null() {
+        return Err(Error::Runtime(format!("plugin {} registers no phase", path)));
+    }
+    let names = unsafe { CStr::from_ptr(names) }.to_string_lossy().to_string();
+    let phases = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            Phase::parse(name).ok_or_else(|| {
+                Error::Runtime(format!("plugin {} registers unknown phase {}", path, name))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Plugin {
+        path: path.to_string(),
+        phases,
+        hook,
+    })
+}
+
+fn dl_error() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        return "unknown error".to_string();
+    }
+    unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+}
+
+/// Calls the plugins registered for the phase, in the order they were loaded.
+/// They run before the hooks of the spec for the same phase.
+pub fn run(phase: Phase, state: &State) -> Result<()> {
+    let plugins = PLUGINS.lock().unwrap();
+    if plugins.is_empty() {
+        return Ok(());
+    }
+    let state_json = CString::new(
+        serde_json::to_string(state).map_err(Error::json("unable to serialize state"))?,
+    )
+    .map_err(|_| Error::Runtime("state with a NUL byte".to_string()))?;
+    let name = CString::new(phase.name()).unwrap();
+
+    for plugin in plugins.iter().filter(|plugin| plugin.phases.contains(&phase)) {
+        debug!("running {} plugin {}", phase.name(), plugin.path);
+        let code = unsafe { (plugin.hook)(name.as_ptr(), state_json.as_ptr()) };
+        if code != 0 {
+            return Err(Error::Runtime(format!(
+                "plugin {} failed the {} phase with {}",
+                plugin.path,
+                phase.name(),
+                code
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, Phase};
+
+    #[test]
+    fn phases() {
+        assert_eq!(Phase::parse("createRuntime"), Some(Phase::CreateRuntime));
+        assert_eq!(Phase::parse("poststop"), Some(Phase::Poststop));
+        assert_eq!(Phase::parse("poststopped"), None);
+    }
+
+    #[test]
+    fn invalid_plugins() {
+        let err = load("./plugin_missing_test.so").unwrap_err();
+        assert!(err.to_string().contains("unable to load plugin"));
+        // A library, but not a plugin
+        let err = load("libc.so.6").unwrap_err();
+        assert!(err.to_string().contains("has no pura_plugin_phases"));
+    }
+}
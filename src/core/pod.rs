@@ -0,0 +1,409 @@
+use chrono::Local;
+use log::{debug, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    libc,
+    sched::{unshare, CloneFlags},
+    sys::{
+        signal::{SigSet, Signal},
+        socket::{socket, AddressFamily, SockFlag, SockType},
+        stat::Mode,
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{chdir, close, dup2, fork, pipe2, sethostname, setsid, ForkResult, Pid},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::io::FromRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::core::{
+    common::{Error, Result},
+    fork::{self, Process},
+    network::Network,
+    state::{self, validate_id, State},
+};
+use crate::oci::spec::{Namespace, Spec};
+
+/// Directory of the pods in the runtime root
+pub const PODS_DIR: &str = "pods";
+/// Pod of a container in its state
+pub const POD_ANNOTATION: &str = "pura.pod";
+const POD_FILE: &str = "pod.json";
+/// Namespaces of a pod, as spec namespace types and files of /proc/PID/ns
+const SHARED_NAMESPACES: [(&str, &str); 3] = [("network", "net"), ("ipc", "ipc"), ("uts", "uts")];
+
+/// Sandbox of a group of containers, like a Kubernetes pod: a pause process
+/// holds the network, IPC and UTS namespaces (and the PID one if shared) the
+/// containers added to the pod join, so they reach each other on localhost.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pod {
+    pub name: String,
+    /// Pause process holding the namespaces
+    pub pid: i32,
+    pub start_time: u64,
+    /// The containers share a PID namespace, the pause process being its PID 1
+    pub share_pid: bool,
+    pub created: String,
+}
+
+impl Pod {
+    /// Directory of the pod in the root
+    pub fn path(root: &Path, name: &str) -> PathBuf {
+        root.join(PODS_DIR).join(name)
+    }
+
+    /// Starts the pause process of a new pod, connected to the network when
+    /// given
+    pub fn create(root: &Path, name: &str, share_pid: bool, network: Option<&Network>) -> Result<Pod> {
+        validate_id(name)?;
+        let path = Pod::path(root, name);
+        std::fs::create_dir_all(root.join(PODS_DIR))
+            .map_err(Error::io(format!("unable to create the pods of {:?}", root)))?;
+        match std::fs::create_dir(&path) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                return Err(Error::Runtime(format!("pod {} already exists", name)))
+            }
+            Err(err) => return Err(Error::io(format!("unable to create pod {}", name))(err)),
+            Ok(_) => (),
+        }
+
+        let started = spawn_pause(name, share_pid).and_then(|pid| {
+            let start_time = fork::start_time(pid).ok_or_else(|| {
+                Error::Runtime(format!("pause process {} of pod {} is gone", pid, name))
+            })?;
+            Ok(Pod {
+                name: name.to_string(),
+                pid: pid.as_raw(),
+                start_time,
+                share_pid,
+                created: Local::now().to_rfc3339(),
+            })
+        });
+        let pod = started.and_then(|pod| {
+            if let Some(network) = network {
+                network.setup(name, Pid::from_raw(pod.pid), &path)?;
+            }
+            pod.save(root)?;
+            Ok(pod)
+        });
+        if pod.is_err() {
+            if let Ok(pod) = &pod {
+                let _ = pod.stop();
+            }
+            let _ = std::fs::remove_dir_all(&path);
+        }
+        pod
+    }
+
+    fn save(&self, root: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).map_err(Error::json("unable to serialize pod"))?;
+        std::fs::write(Pod::path(root, &self.name).join(POD_FILE), json)
+            .map_err(Error::io(format!("unable to save pod {}", self.name)))
+    }
+
+    /// The pod of the root, None without a pod of the name
+    pub fn load(root: &Path, name: &str) -> Result<Option<Pod>> {
+        validate_id(name)?;
+        let path = Pod::path(root, name).join(POD_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map(Some)
+                .map_err(Error::json(format!("invalid {:?}", path))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::io(format!("unable to read {:?}", path))(err)),
+        }
+    }
+
+    /// Pods of the root, sorted by name
+    pub fn list(root: &Path) -> Result<Vec<Pod>> {
+        let dir = root.join(PODS_DIR);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // No pod created yet
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::io(format!("unable to list {:?}", dir))(err)),
+        };
+        let mut pods = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            match Pod::load(root, &entry.file_name().to_string_lossy()) {
+                Ok(Some(pod)) => pods.push(pod),
+                Ok(None) => (),
+                // Removed meanwhile
+                Err(err) => debug!("skipping pod {:?}: {}", entry.file_name(), err),
+            }
+        }
+        pods.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(pods)
+    }
+
+    /// Whether the pause process still holds the namespaces
+    pub fn running(&self) -> bool {
+        let pid = Pid::from_raw(self.pid);
+        Process::open(pid, Some(self.start_time)).is_ok() && !fork::exited(pid)
+    }
+
+    /// Namespaces of the pod, the paths of those of the pause process
+    pub fn namespaces(&self) -> Vec<Namespace> {
+        let pid = self.share_pid.then_some(("pid", "pid"));
+        SHARED_NAMESPACES
+            .iter()
+            .copied()
+            .chain(pid)
+            .map(|(namespace, file)| Namespace {
+                namespace: namespace.to_string(),
+                path: Some(format!("/proc/{}/ns/{}", self.pid, file)),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Has the spec join the namespaces of the pod, instead of creating its
+    /// own. The hostname is the one of the pod.
+    pub fn join(&self, mut spec: Spec) -> Result<Spec> {
+        if !self.running() {
+            return Err(Error::State(format!("pod {} isn't running", self.name)));
+        }
+        let namespaces = spec
+            .linux
+            .get_or_insert_with(Default::default)
+            .namespaces
+            .get_or_insert_with(Vec::new);
+        for shared in self.namespaces() {
+            namespaces.retain(|namespace| namespace.namespace != shared.namespace);
+            namespaces.push(shared);
+        }
+        spec.hostname = None;
+        spec.domainname = None;
+        Ok(spec)
+    }
+
+    /// Containers of the root in the pod, until they are deleted
+    pub fn members(&self, root: &Path) -> Result<Vec<String>> {
+        Ok(state::open(root)?
+            .list()?
+            .into_iter()
+            .filter(|state| pod_of(state) == Some(self.name.as_str()))
+            .map(|state| state.id)
+            .collect())
+    }
+
+    /// Stops the pause process and removes the pod, refusing while it has
+    /// containers unless forced
+    pub fn remove(&self, root: &Path, force: bool) -> Result<()> {
+        let members = self.members(root)?;
+        if !members.is_empty() && !force {
+            return Err(Error::Runtime(format!(
+                "pod {} has the containers {}",
+                self.name,
+                members.join(", ")
+            )));
+        }
+        self.stop()?;
+        let path = Pod::path(root, &self.name);
+        if let Err(err) = Network::teardown(&path) {
+            warn!("{}", err);
+        }
+        std::fs::remove_dir_all(&path)
+            .map_err(Error::io(format!("unable to remove pod {}", self.name)))
+    }
+
+    fn stop(&self) -> Result<()> {
+        let process = match Process::open(Pid::from_raw(self.pid), Some(self.start_time)) {
+            Ok(process) => process,
+            // Gone already
+            Err(_) => return Ok(()),
+        };
+        process.signal(libc::SIGTERM)?;
+        process.wait_exit()
+    }
+}
+
+/// Pod recorded in the state of a container
+fn pod_of(state: &State) -> Option<&str> {
+    state
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(POD_ANNOTATION))
+        .map(String::as_str)
+}
+
+/// Records the pod of the container in its state
+pub fn annotate(state: &mut State, pod: &Pod) {
+    state
+        .annotations
+        .get_or_insert_with(Default::default)
+        .insert(POD_ANNOTATION.to_string(), pod.name.clone());
+}
+
+/// Forks the pause process in new namespaces, detached from the runtime like
+/// the monitor, and returns its pid
+fn spawn_pause(name: &str, share_pid: bool) -> Result<Pid> {
+    let (result_read, result_write) =
+        pipe2(OFlag::O_CLOEXEC).map_err(Error::sys("unable to create the pod pipe"))?;
+    let (result_read, mut result_write) =
+        unsafe { (File::from_raw_fd(result_read), File::from_raw_fd(result_write)) };
+
+    match unsafe { fork() }.map_err(Error::sys("unable to fork the pod"))? {
+        ForkResult::Parent { child } => {
+            drop(result_write);
+            // The intermediate process exits right after forking the pause
+            let _ = waitpid(child, None);
+            let mut result = String::new();
+            BufReader::new(result_read)
+                .read_line(&mut result)
+                .map_err(Error::io("unable to read the pod pipe"))?;
+            let result = result.trim_end();
+            if let Some(err) = result.strip_prefix("error:") {
+                return Err(Error::Runtime(err.to_string()));
+            }
+            result
+                .parse()
+                .map(Pid::from_raw)
+                .map_err(|_| Error::Runtime("the pause process exited".to_string()))
+        }
+        ForkResult::Child => {
+            drop(result_read);
+            let _ = setsid();
+            let mut flags = CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWUTS;
+            if share_pid {
+                // The next child is PID 1 of the new namespace
+                flags |= CloneFlags::CLONE_NEWPID;
+            }
+            let setup = unshare(flags)
+                .map_err(|err| format!("unable to create the namespaces of the pod: {}", err))
+                .and_then(|_| {
+                    sethostname(name).map_err(|err| format!("unable to set the hostname: {}", err))
+                })
+                .and_then(|_| {
+                    loopback_up().map_err(|err| format!("unable to set the loopback up: {}", err))
+                });
+            if let Err(err) = setup {
+                let _ = writeln!(result_write, "error:{}", err);
+                unsafe { libc::_exit(1) };
+            }
+            // Blocked before the fork, the kernel would drop the signals
+            // sent to PID 1 until they are
+            let mask = pause_signals();
+            let _ = mask.thread_block();
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { child }) => {
+                    let _ = writeln!(result_write, "{}", child);
+                    unsafe { libc::_exit(0) }
+                }
+                Ok(ForkResult::Child) => {
+                    drop(result_write);
+                    pause(mask)
+                }
+                Err(err) => {
+                    let _ = writeln!(result_write, "error:unable to fork the pause process: {}", err);
+                    unsafe { libc::_exit(1) }
+                }
+            }
+        }
+    }
+}
+
+/// Sets the loopback interface of the new network namespace up
+fn loopback_up() -> nix::Result<()> {
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo\0") {
+        *dst = *src as libc::c_char;
+    }
+    let res = unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request) };
+    let res = Errno::result(res).and_then(|_| {
+        unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+        Errno::result(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS, &request) })
+    });
+    let _ = close(fd);
+    res.map(drop)
+}
+
+fn pause_signals() -> SigSet {
+    let mut mask = SigSet::empty();
+    for signal in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP, Signal::SIGCHLD] {
+        mask.add(signal);
+    }
+    mask
+}
+
+/// Body of the pause process: holds the namespaces, reaping the zombies
+/// reparented to it as PID 1, until SIGTERM or SIGINT of the blocked mask
+fn pause(mask: SigSet) -> ! {
+    let _ = chdir("/");
+    if let Ok(null) = open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+        for target in 0..3 {
+            let _ = dup2(null, target);
+        }
+        let _ = close(null);
+    }
+    loop {
+        match mask.wait() {
+            Ok(Signal::SIGCHLD) => {
+                while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                    if status == WaitStatus::StillAlive {
+                        break;
+                    }
+                }
+            }
+            Ok(Signal::SIGHUP) | Err(_) => (),
+            Ok(_) => unsafe { libc::_exit(0) },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::geteuid;
+    use std::path::Path;
+
+    use super::{annotate, Pod};
+    use crate::core::state::{JsonStore, State, Store};
+    use crate::oci::spec::Spec;
+
+    #[test]
+    fn lifecycle() {
+        if !geteuid().is_root() {
+            return;
+        }
+        let root = Path::new("./pod_test");
+        std::fs::create_dir_all(root).unwrap();
+
+        let pod = Pod::create(root, "web", true, None).unwrap();
+        assert!(pod.running());
+        assert!(Pod::create(root, "web", false, None).is_err());
+        assert_eq!(Pod::load(root, "web").unwrap(), Some(pod.clone()));
+        assert_eq!(Pod::list(root).unwrap(), vec![pod.clone()]);
+
+        let spec = pod
+            .join(Spec::new().with_hostname("app").add_namespace("network", None))
+            .unwrap();
+        assert!(spec.hostname.is_none());
+        let namespaces = spec.linux.unwrap().namespaces.unwrap();
+        assert_eq!(namespaces.len(), 4);
+        assert!(namespaces.iter().all(|namespace| namespace.path.is_some()));
+
+        // Kept while a container is in it
+        let mut state = State::new("app", 0, "/bundle");
+        annotate(&mut state, &pod);
+        JsonStore::new(root).save(&state).unwrap();
+        assert_eq!(pod.members(root).unwrap(), vec!["app"]);
+        assert!(pod.remove(root, false).is_err());
+        pod.remove(root, true).unwrap();
+        assert!(!pod.running());
+        assert!(Pod::load(root, "web").unwrap().is_none());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
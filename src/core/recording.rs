@@ -0,0 +1,197 @@
+use serde_json::{json, Value};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::core::common::{Error, Result};
+
+/// Extension of the recordings in the runtime root
+pub const RECORDING_EXTENSION: &str = "cast";
+/// Size of the terminal until the recording knows better
+const DEFAULT_SIZE: (u16, u16) = (24, 80);
+
+/// Recording of the container of the state dir, `<root>/<id>.cast`, next to
+/// the state dir so it outlives the container
+pub fn recording_path(container_path: &Path) -> PathBuf {
+    let id = container_path
+        .file_name()
+        .map(|id| id.to_string_lossy().to_string())
+        .unwrap_or_default();
+    container_path.with_file_name(format!("{}.{}", id, RECORDING_EXTENSION))
+}
+
+/// Writer of a console recording in the asciicast v2 format of asciinema.
+/// The events are written as they come, so a recording cut short is still
+/// readable.
+pub struct Recorder {
+    file: File,
+    start: Instant,
+    /// Start of a UTF-8 sequence split by the end of the data, per event type
+    output: Vec<u8>,
+    input: Vec<u8>,
+}
+
+impl Recorder {
+    /// Creates the recording, replacing the one of a previous container of
+    /// the same id
+    pub fn create(path: &Path, size: Option<(u16, u16)>) -> Result<Recorder> {
+        let (rows, cols) = size.unwrap_or(DEFAULT_SIZE);
+        let mut file =
+            File::create(path).map_err(Error::io(format!("unable to create {:?}", path)))?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+        });
+        writeln!(file, "{}", header).map_err(Error::io("unable to write the recording"))?;
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+            output: Vec::new(),
+            input: Vec::new(),
+        })
+    }
+
+    /// Records data written by the container
+    pub fn output(&mut self, data: &[u8]) -> Result<()> {
+        let text = utf8(&mut self.output, data);
+        self.event("o", &text)
+    }
+
+    /// Records data typed by the attached clients
+    pub fn input(&mut self, data: &[u8]) -> Result<()> {
+        let text = utf8(&mut self.input, data);
+        self.event("i", &text)
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) -> Result<()> {
+        self.event("r", &format!("{}x{}", cols, rows))
+    }
+
+    fn event(&mut self, kind: &str, data: &str) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.file, "[{:.6}, {}, {}]", time, json!(kind), json!(data))
+            .map_err(Error::io("unable to write the recording"))
+    }
+}
+
+/// Text of the data, the incomplete UTF-8 sequence at its end kept in
+/// `pending` for the next data
+fn utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let valid = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        // Invalid, not merely cut
+        Err(_) => pending.len(),
+    };
+    let rest = pending.split_off(valid);
+    let text = String::from_utf8_lossy(pending).to_string();
+    *pending = rest;
+    text
+}
+
+/// Writes the output of the recording to the output at its pace, sped up by
+/// the factor
+pub fn replay(path: &Path, output: &mut impl Write, speed: f64) -> Result<()> {
+    let file = File::open(path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => Error::State(format!("no recording at {:?}", path)),
+        _ => Error::io(format!("unable to open {:?}", path))(err),
+    })?;
+    let mut lines = BufReader::new(file).lines();
+    let header: Value = lines
+        .next()
+        .transpose()
+        .map_err(Error::io("unable to read the recording"))?
+        .and_then(|header| serde_json::from_str(&header).ok())
+        .ok_or_else(|| Error::Runtime(format!("{:?} isn't an asciicast recording", path)))?;
+    if header["version"] != 2 {
+        return Err(Error::Runtime(format!(
+            "{:?} isn't an asciicast v2 recording",
+            path
+        )));
+    }
+
+    let start = Instant::now();
+    for line in lines {
+        let line = line.map_err(Error::io("unable to read the recording"))?;
+        if line.is_empty() {
+            continue;
+        }
+        let (time, kind, data): (f64, String, String) = serde_json::from_str(&line)
+            .map_err(Error::json(format!("invalid event in {:?}", path)))?;
+        if kind != "o" {
+            continue;
+        }
+        let at = Duration::from_secs_f64((time / speed).max(0.0));
+        if let Some(wait) = at.checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        output
+            .write_all(data.as_bytes())
+            .and_then(|_| output.flush())
+            .map_err(Error::io("unable to write the recording"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{recording_path, replay, utf8, Recorder};
+
+    #[test]
+    fn record_and_replay() {
+        let path = Path::new("./recording_test.cast");
+        let mut recorder = Recorder::create(path, Some((40, 120))).unwrap();
+        recorder.output(b"$ ls\r\n").unwrap();
+        recorder.input(b"q").unwrap();
+        recorder.resize(50, 132).unwrap();
+        // A character split between two reads
+        recorder.output(&"é".as_bytes()[..1]).unwrap();
+        recorder.output(&"é".as_bytes()[1..]).unwrap();
+        drop(recorder);
+
+        let recording = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = recording.lines().collect();
+        assert!(lines[0].contains("\"version\":2") && lines[0].contains("\"width\":120"));
+        assert!(lines[1].ends_with(", \"o\", \"$ ls\\r\\n\"]"));
+        assert!(lines[2].ends_with(", \"i\", \"q\"]"));
+        assert!(lines[3].ends_with(", \"r\", \"132x50\"]"));
+        assert!(lines[4].ends_with(", \"o\", \"é\"]"));
+        assert_eq!(lines.len(), 5);
+
+        let mut output = Vec::new();
+        replay(path, &mut output, 100.0).unwrap();
+        assert_eq!(output, "$ ls\r\né".as_bytes());
+
+        std::fs::remove_file(path).unwrap();
+        assert!(replay(path, &mut output, 1.0).is_err());
+    }
+
+    #[test]
+    fn pending_utf8() {
+        let mut pending = Vec::new();
+        assert_eq!(utf8(&mut pending, &[b'a', 0xe2, 0x82]), "a");
+        assert_eq!(utf8(&mut pending, &[0xac]), "€");
+        assert_eq!(utf8(&mut pending, &[0xff, b'b']), "\u{fffd}b");
+        assert!(pending.is_empty());
+
+        assert_eq!(
+            recording_path(Path::new("/tmp/pura/web.1")),
+            Path::new("/tmp/pura/web.1.cast")
+        );
+    }
+}
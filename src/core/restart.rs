@@ -0,0 +1,90 @@
+use std::{str::FromStr, time::Duration};
+
+use crate::core::common::{Error, Result};
+
+/// Restarts of the container in its state, once it was restarted
+pub const RESTART_COUNT_ANNOTATION: &str = "pura.restart.count";
+/// Delay before the first restart, doubled by each restart
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Restart policy of `run --restart`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    /// Restarts the container exiting with a non-zero exit code, at most the
+    /// number of times when given
+    OnFailure(Option<u32>),
+}
+
+impl FromStr for RestartPolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        let invalid = || {
+            Error::Runtime(format!(
+                "invalid restart policy {:?}, expected no or on-failure[:MAX]",
+                policy
+            ))
+        };
+        match policy.split_once(':') {
+            None if policy == "no" => Ok(RestartPolicy::No),
+            None if policy == "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+            Some(("on-failure", max)) => max
+                .parse()
+                .map(|max| RestartPolicy::OnFailure(Some(max)))
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Whether the container exiting with the exit code is restarted, after
+    /// `restarts` restarts. An unknown exit code is a failure.
+    pub fn restarts(&self, exit_code: Option<i32>, restarts: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure(max) => {
+                exit_code != Some(0) && max.is_none_or(|max| restarts < max)
+            }
+        }
+    }
+}
+
+/// Delay before the restart following `restarts` restarts, so a container
+/// failing right away isn't restarted in a busy loop
+pub fn backoff(restarts: u32) -> Duration {
+    FIRST_BACKOFF
+        .saturating_mul(2u32.saturating_pow(restarts))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use super::{backoff, RestartPolicy};
+
+    #[test]
+    fn policies() {
+        assert_eq!(RestartPolicy::from_str("no").unwrap(), RestartPolicy::No);
+        let unlimited = RestartPolicy::from_str("on-failure").unwrap();
+        assert_eq!(unlimited, RestartPolicy::OnFailure(None));
+        let limited = RestartPolicy::from_str("on-failure:2").unwrap();
+        assert!(RestartPolicy::from_str("on-failure:").is_err());
+        assert!(RestartPolicy::from_str("always").is_err());
+
+        assert!(!RestartPolicy::No.restarts(Some(1), 0));
+        assert!(!unlimited.restarts(Some(0), 0));
+        assert!(unlimited.restarts(Some(137), 1000));
+        assert!(unlimited.restarts(None, 0));
+        assert!(limited.restarts(Some(1), 1));
+        assert!(!limited.restarts(Some(1), 2));
+
+        assert_eq!(backoff(0), Duration::from_millis(100));
+        assert_eq!(backoff(3), Duration::from_millis(800));
+        assert_eq!(backoff(100), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,114 @@
+use nix::unistd::{geteuid, Uid};
+use std::{
+    fs::DirBuilder,
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::core::common::{Error, Result};
+
+/// Runtime root of the containers of root
+pub const ROOT_PATH: &str = "/run/pura";
+
+/// Default runtime root: `/run/pura` as root, `$XDG_RUNTIME_DIR/pura`
+/// otherwise, so rootless users don't share a root with each other.
+/// Both the runtime dir and the root must be private to the user.
+pub fn default_root() -> Result<PathBuf> {
+    let uid = geteuid();
+    if uid.is_root() {
+        let root = PathBuf::from(ROOT_PATH);
+        open_root(&root)?;
+        return Ok(root);
+    }
+
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|runtime_dir| !runtime_dir.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            Error::Runtime("XDG_RUNTIME_DIR isn't set, rootless pura needs --root".to_string())
+        })?;
+    check_private(&runtime_dir, uid)?;
+
+    let root = runtime_dir.join("pura");
+    open_root(&root)?;
+    Ok(root)
+}
+
+/// Creates the root private to the user if missing, and checks an existing
+/// one is: the state dirs hold the specs whose hooks `start` and `delete`
+/// run, so no one else may be able to plant a container there
+pub fn open_root(root: &Path) -> Result<()> {
+    match DirBuilder::new().recursive(true).mode(0o700).create(root) {
+        Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+            return Err(Error::io(format!("unable to create root {:?}", root))(err));
+        }
+        _ => (),
+    }
+    check_private(root, geteuid())
+}
+
+/// Checks the directory is owned by the user and writable by no one else
+fn check_private(path: &Path, uid: Uid) -> Result<()> {
+    let metadata =
+        std::fs::metadata(path).map_err(Error::io(format!("unable to stat {:?}", path)))?;
+    if !metadata.is_dir() {
+        return Err(Error::Runtime(format!("{:?} isn't a directory", path)));
+    }
+    if metadata.uid() != uid.as_raw() {
+        return Err(Error::Runtime(format!(
+            "{:?} is owned by uid {}, not {}",
+            path,
+            metadata.uid(),
+            uid
+        )));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err(Error::Runtime(format!(
+            "{:?} is writable by other users (mode {:o})",
+            path,
+            metadata.mode() & 0o777
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::{geteuid, Uid};
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::{check_private, open_root};
+
+    #[test]
+    fn private_root() {
+        let path = std::env::temp_dir().join(format!("pura-root-test-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        std::fs::set_permissions(&path, Permissions::from_mode(0o700)).unwrap();
+        assert!(check_private(&path, geteuid()).is_ok());
+        assert!(check_private(&path, Uid::from_raw(geteuid().as_raw() + 1)).is_err());
+
+        std::fs::set_permissions(&path, Permissions::from_mode(0o777)).unwrap();
+        assert!(check_private(&path, geteuid()).is_err());
+        assert!(check_private(&path.join("missing"), geteuid()).is_err());
+
+        std::fs::remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn open_root_private() {
+        let path = std::env::temp_dir().join(format!("pura-open-root-test-{}", std::process::id()));
+        let root = path.join("root");
+        open_root(&root).unwrap();
+        let mode = std::fs::metadata(&root).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert!(open_root(&root).is_ok());
+
+        // Like a root planted in /tmp by another user
+        std::fs::set_permissions(&root, Permissions::from_mode(0o777)).unwrap();
+        assert!(open_root(&root).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
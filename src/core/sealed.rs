@@ -0,0 +1,119 @@
+use log::debug;
+use nix::{
+    fcntl::{fcntl, FcntlArg, SealFlag},
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        statvfs::{fstatvfs, FsFlags},
+    },
+    unistd::fexecve,
+};
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+    },
+};
+
+use crate::core::common::{Error, Result};
+
+/// Seals making the copy of the binary immutable for good
+fn seals() -> SealFlag {
+    SealFlag::F_SEAL_SEAL | SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_WRITE
+}
+
+/// Whether the file is a memfd with every seal
+fn is_sealed(file: &File) -> bool {
+    match fcntl(file.as_raw_fd(), FcntlArg::F_GET_SEALS) {
+        Ok(flags) => SealFlag::from_bits_truncate(flags).contains(seals()),
+        Err(_) => false,
+    }
+}
+
+/// Whether the binary is on a read-only mount, which the processes of the
+/// container can't write through either
+fn read_only(file: &File) -> bool {
+    fstatvfs(file).is_ok_and(|stat| stat.flags().contains(FsFlags::ST_RDONLY))
+}
+
+/// Copies the binary into a sealed memfd
+fn sealed_copy(binary: &mut File) -> Result<File> {
+    let name = CStr::from_bytes_with_nul(b"pura\0").unwrap();
+    let fd = memfd_create(
+        name,
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )
+    .map_err(Error::sys(
+        "unable to create a memfd for the runtime binary",
+    ))?;
+    let mut copy = unsafe { File::from_raw_fd(fd) };
+    std::io::copy(binary, &mut copy).map_err(Error::io("unable to copy the runtime binary"))?;
+    fcntl(copy.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals()))
+        .map_err(Error::sys("unable to seal the copy of the runtime binary"))?;
+    Ok(copy)
+}
+
+/// Re-executes the runtime from a sealed in-memory copy of its binary,
+/// unless it already runs from one. Processes of the container can reach
+/// the runtime through /proc/<pid>/exe while it joins their namespaces; the
+/// copy is all they'd reach, so they can't overwrite the binary of the host
+/// (CVE-2019-5736). A binary of a read-only filesystem is safe as it is,
+/// and spared the copy.
+pub fn reexec() -> Result<()> {
+    let mut binary =
+        File::open("/proc/self/exe").map_err(Error::io("unable to open the runtime binary"))?;
+    if is_sealed(&binary) || read_only(&binary) {
+        return Ok(());
+    }
+    let copy = sealed_copy(&mut binary)?;
+
+    let args: Vec<CString> = std::env::args_os()
+        .map(|arg| CString::new(arg.as_bytes()).unwrap_or_default())
+        .collect();
+    let env: Vec<CString> = std::env::vars_os()
+        .map(|(key, value)| {
+            let mut var = key.as_bytes().to_vec();
+            var.push(b'=');
+            var.extend_from_slice(value.as_bytes());
+            CString::new(var).unwrap_or_default()
+        })
+        .collect();
+    debug!("re-executing from a sealed copy of the runtime binary");
+    match fexecve(copy.as_raw_fd(), &args, &env) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Error::sys("unable to re-execute the runtime binary")(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{Read, Write},
+        os::unix::io::AsRawFd,
+    };
+
+    use super::{is_sealed, sealed_copy};
+
+    #[test]
+    fn sealed_binary() {
+        let mut binary = File::open("/proc/self/exe").unwrap();
+        assert!(!is_sealed(&binary));
+
+        let mut copy = sealed_copy(&mut binary).unwrap();
+        assert!(is_sealed(&copy));
+        let path = format!("/proc/self/fd/{}", copy.as_raw_fd());
+        let mut contents = Vec::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, std::fs::read("/proc/self/exe").unwrap());
+
+        assert!(copy.write_all(b"overwritten").is_err());
+        let mut reopened = OpenOptions::new().write(true).open(&path).unwrap();
+        assert!(reopened.write_all(b"overwritten").is_err());
+        assert!(copy.set_len(0).is_err());
+    }
+}
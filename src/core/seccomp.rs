@@ -0,0 +1,893 @@
+use lazy_static::lazy_static;
+use log::debug;
+#[cfg(feature = "seccomp")]
+use log::warn;
+#[cfg(feature = "seccomp")]
+use nix::sys::utsname::uname;
+use nix::{errno::Errno, libc};
+#[cfg(feature = "seccomp")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "seccomp")]
+use std::{fs::DirBuilder, os::unix::fs::DirBuilderExt, path::Path};
+use std::sync::Mutex;
+
+use crate::core::{
+    common::{disabled, Error, Result},
+    fork, syscalls,
+};
+use crate::oci::spec::{Seccomp, Spec, Syscall, SyscallArg};
+
+lazy_static! {
+    /// Profile of the containers whose spec has none, from the host configuration
+    static ref DEFAULT_PROFILE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Directory of the compiled filters in the runtime root
+pub const CACHE_DIR: &str = "seccomp";
+/// Bytes of each instruction in a cached program
+#[cfg(feature = "seccomp")]
+const CACHED_INSN_LEN: usize = 8;
+
+/// Offsets of the fields of struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+const MAX_ARGS: u32 = 6;
+/// Syscalls of the x32 ABI, with the audit arch of x86_64
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+/// AUDIT_ARCH_* of the architectures with a syscall table
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+const AUDIT_ARCH_AARCH64: u32 = 0xc000_00b7;
+const AUDIT_ARCH_ARM: u32 = 0x4000_0028;
+const AUDIT_ARCH_S390X: u32 = 0x8000_0016;
+const AUDIT_ARCH_PPC64: u32 = 0x8000_0015;
+const AUDIT_ARCH_PPC64LE: u32 = 0xc000_0015;
+const AUDIT_ARCH_RISCV64: u32 = 0xc000_00f3;
+/// Instructions of classic BPF used by the filters
+const LD_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+const AND_K: u16 = (libc::BPF_ALU | libc::BPF_AND | libc::BPF_K) as u16;
+const JA: u16 = (libc::BPF_JMP | libc::BPF_JA) as u16;
+const JEQ_K: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+const JGT_K: u16 = (libc::BPF_JMP | libc::BPF_JGT | libc::BPF_K) as u16;
+const JGE_K: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+const RET_K: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+const BPF_MAXINSNS: usize = libc::BPF_MAXINSNS as usize;
+/// Syscalls checked by a single jump to their action
+const CHAIN_LEN: usize = 200;
+
+/// Architecture of the syscalls a filter covers
+struct Arch {
+    /// SCMP_ARCH_* of `linux.seccomp.architectures`
+    name: &'static str,
+    /// AUDIT_ARCH_* of seccomp_data.arch
+    audit: u32,
+    /// Syscall numbers by name, sorted by name
+    table: &'static [(&'static str, u32)],
+    /// Added to the numbers of the table, for x32
+    nr_base: u32,
+}
+
+impl Arch {
+    /// Number of the syscall in seccomp_data.nr
+    fn syscall(&self, name: &str) -> Option<u32> {
+        self.table
+            .binary_search_by(|(entry, _)| entry.cmp(&name))
+            .ok()
+            .map(|index| self.table[index].1 | self.nr_base)
+    }
+}
+
+const fn arch(name: &'static str, audit: u32, table: &'static [(&'static str, u32)]) -> Arch {
+    Arch {
+        name,
+        audit,
+        table,
+        nr_base: 0,
+    }
+}
+
+/// Architectures the filters can check
+const ARCHES: &[Arch] = &[
+    arch("SCMP_ARCH_X86_64", AUDIT_ARCH_X86_64, syscalls::X86_64),
+    Arch {
+        nr_base: X32_SYSCALL_BIT,
+        ..arch("SCMP_ARCH_X32", AUDIT_ARCH_X86_64, syscalls::X32)
+    },
+    arch("SCMP_ARCH_X86", AUDIT_ARCH_I386, syscalls::X86),
+    arch("SCMP_ARCH_AARCH64", AUDIT_ARCH_AARCH64, syscalls::AARCH64),
+    arch("SCMP_ARCH_ARM", AUDIT_ARCH_ARM, syscalls::ARM),
+    arch("SCMP_ARCH_S390X", AUDIT_ARCH_S390X, syscalls::S390X),
+    arch("SCMP_ARCH_PPC64", AUDIT_ARCH_PPC64, syscalls::PPC64),
+    arch("SCMP_ARCH_PPC64LE", AUDIT_ARCH_PPC64LE, syscalls::PPC64),
+    arch("SCMP_ARCH_RISCV64", AUDIT_ARCH_RISCV64, syscalls::RISCV64),
+];
+
+/// Architecture of the host, always checked by the filters
+#[cfg(target_arch = "x86_64")]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_X86_64");
+#[cfg(target_arch = "x86")]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_X86");
+#[cfg(target_arch = "aarch64")]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_AARCH64");
+#[cfg(target_arch = "arm")]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_ARM");
+#[cfg(target_arch = "s390x")]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_S390X");
+#[cfg(all(target_arch = "powerpc64", target_endian = "big"))]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_PPC64");
+#[cfg(all(target_arch = "powerpc64", target_endian = "little"))]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_PPC64LE");
+#[cfg(target_arch = "riscv64")]
+const NATIVE: Option<&str> = Some("SCMP_ARCH_RISCV64");
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "s390x",
+    target_arch = "powerpc64",
+    target_arch = "riscv64"
+)))]
+const NATIVE: Option<&str> = None;
+
+fn find_arch(name: &str) -> Option<&'static Arch> {
+    ARCHES.iter().find(|arch| arch.name == name)
+}
+
+/// BPF program of `linux.seccomp`, loaded by the container process right
+/// before it executes the process of the spec
+pub struct Filter {
+    program: Vec<libc::sock_filter>,
+    flags: libc::c_ulong,
+}
+
+impl Filter {
+    /// Compiles the profile for the architecture of the host and those of
+    /// `architectures`, each resolving the syscall names in its own table.
+    /// Unknown syscall names are skipped, like a profile naming syscalls of
+    /// newer kernels, and so are unknown architectures.
+    pub fn compile(seccomp: &Seccomp) -> Result<Filter> {
+        if !cfg!(feature = "seccomp") {
+            return Err(disabled("seccomp"));
+        }
+        let native = NATIVE.and_then(find_arch).ok_or_else(|| {
+            Error::Runtime(format!(
+                "seccomp isn't supported on {}",
+                std::env::consts::ARCH
+            ))
+        })?;
+        // Handing the syscalls to a supervisor isn't supported
+        if seccomp.listener_path.is_some() {
+            return Err(Error::Bundle("seccomp listeners aren't supported".to_string()));
+        }
+        let flags = filter_flags(seccomp)?;
+        let default = action(&seccomp.default_action, seccomp.default_errno_ret)?;
+
+        let mut arches = vec![native];
+        for name in seccomp.architectures.iter().flatten() {
+            match find_arch(name) {
+                Some(arch) if !arches.iter().any(|other| other.name == arch.name) => {
+                    arches.push(arch)
+                }
+                Some(_) => {}
+                None => debug!("skipping unsupported seccomp architecture {}", name),
+            }
+        }
+        let mut audits: Vec<u32> = arches.iter().map(|arch| arch.audit).collect();
+        audits.sort_unstable();
+        audits.dedup();
+
+        // Each audit arch jumps to its section, the others kill the process
+        let mut asm = Assembler::default();
+        let sections: Vec<Label> = audits.iter().map(|_| asm.label()).collect();
+        asm.load(ARCH_OFFSET);
+        for (audit, section) in audits.iter().zip(&sections) {
+            let other = asm.label();
+            asm.jump(JEQ_K, *audit, Target::Next, other.into());
+            asm.jump(JA, 0, (*section).into(), Target::Next);
+            asm.bind(other);
+        }
+        asm.ret(libc::SECCOMP_RET_KILL_PROCESS);
+        for (audit, section) in audits.iter().zip(sections) {
+            let arch = |x32: bool| {
+                arches
+                    .iter()
+                    .find(|arch| arch.audit == *audit && (arch.nr_base != 0) == x32)
+                    .copied()
+            };
+            asm.bind(section);
+            asm.load(NR_OFFSET);
+            // x86_64 and x32 share the audit arch, the x32 syscalls having a bit set
+            if *audit == AUDIT_ARCH_X86_64 {
+                let x86_64 = asm.label();
+                asm.jump(JGE_K, X32_SYSCALL_BIT, Target::Next, x86_64.into());
+                rules(&mut asm, arch(true), seccomp, default)?;
+                asm.bind(x86_64);
+            }
+            rules(&mut asm, arch(false), seccomp, default)?;
+        }
+
+        let program = asm.assemble()?;
+        if program.len() > BPF_MAXINSNS {
+            return Err(Error::Bundle(format!(
+                "seccomp filter of {} instructions is too long",
+                program.len()
+            )));
+        }
+        debug!("compiled seccomp filter of {} instructions", program.len());
+        Ok(Filter { program, flags })
+    }
+
+    /// Filter of the profile from the cache dir, compiled and saved there on a
+    /// miss, so a large profile like the default one of docker compiles once
+    /// per host. The programs are keyed by a hash of the profile, the kernel
+    /// release, the architecture and the version of pura.
+    #[cfg(feature = "seccomp")]
+    pub fn cached(seccomp: &Seccomp, dir: &Path) -> Result<Filter> {
+        let path = dir.join(cache_key(seccomp)?);
+        if let Some(program) = std::fs::read(&path).ok().and_then(|cached| decode(&cached)) {
+            debug!("using the cached seccomp filter {:?}", path);
+            let flags = filter_flags(seccomp)?;
+            return Ok(Filter { program, flags });
+        }
+        let filter = Filter::compile(seccomp)?;
+        // The cache only saves time, the container doesn't need it
+        if let Err(err) = filter.save(dir, &path) {
+            warn!("unable to cache the seccomp filter: {}", err);
+        }
+        Ok(filter)
+    }
+
+    #[cfg(not(feature = "seccomp"))]
+    pub fn cached(seccomp: &Seccomp, _dir: &std::path::Path) -> Result<Filter> {
+        Filter::compile(seccomp)
+    }
+
+    /// Renamed into the cache, concurrent creations never read it half written
+    #[cfg(feature = "seccomp")]
+    fn save(&self, dir: &Path, path: &Path) -> Result<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(Error::io(format!("unable to create {:?}", dir)))?;
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&tmp, encode(&self.program))
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(Error::io(format!("unable to write {:?}", path)))
+    }
+
+    /// Installs the filter on the current process, needing no_new_privs or
+    /// CAP_SYS_ADMIN. Every syscall made from now on goes through it.
+    pub fn load(&self) -> Result<()> {
+        let program = libc::sock_fprog {
+            len: self.program.len() as libc::c_ushort,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                self.flags,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        Errno::result(res)
+            .map(drop)
+            .map_err(Error::sys("unable to load the seccomp filter"))
+    }
+}
+
+/// Instructions of a cached program, in the byte order of the host
+#[cfg(feature = "seccomp")]
+fn encode(program: &[libc::sock_filter]) -> Vec<u8> {
+    let mut content = Vec::with_capacity(program.len() * CACHED_INSN_LEN);
+    for insn in program {
+        content.extend_from_slice(&insn.code.to_ne_bytes());
+        content.extend_from_slice(&[insn.jt, insn.jf]);
+        content.extend_from_slice(&insn.k.to_ne_bytes());
+    }
+    content
+}
+
+/// Program of `encode`, none when the content is cut or too long
+#[cfg(feature = "seccomp")]
+fn decode(content: &[u8]) -> Option<Vec<libc::sock_filter>> {
+    if !content.len().is_multiple_of(CACHED_INSN_LEN) {
+        return None;
+    }
+    let program: Vec<libc::sock_filter> = content
+        .chunks_exact(CACHED_INSN_LEN)
+        .map(|insn| libc::sock_filter {
+            code: u16::from_ne_bytes([insn[0], insn[1]]),
+            jt: insn[2],
+            jf: insn[3],
+            k: u32::from_ne_bytes([insn[4], insn[5], insn[6], insn[7]]),
+        })
+        .collect();
+    match program.len() {
+        1..=BPF_MAXINSNS => Some(program),
+        _ => None,
+    }
+}
+
+/// Name of the cached filter of the profile
+#[cfg(feature = "seccomp")]
+fn cache_key(seccomp: &Seccomp) -> Result<String> {
+    let profile = serde_json::to_vec(seccomp)
+        .map_err(Error::json("unable to serialize the seccomp profile"))?;
+    let uname = uname();
+    let mut hasher = Sha256::new();
+    for part in [
+        &profile[..],
+        uname.release().as_bytes(),
+        std::env::consts::ARCH.as_bytes(),
+        env!("CARGO_PKG_VERSION").as_bytes(),
+    ] {
+        hasher.update(part);
+        hasher.update([0]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Gives the containers whose spec has no `linux.seccomp` the profile of the
+/// file, in the format of `linux.seccomp`
+pub fn set_default_profile(path: &str) {
+    *DEFAULT_PROFILE.lock().unwrap() = Some(path.to_string());
+}
+
+/// Sets the default profile on a spec without one, saved with the spec so
+/// the exec'd processes get it too
+pub fn apply_default_profile(spec: &mut Spec) -> Result<()> {
+    let path = match DEFAULT_PROFILE.lock().unwrap().clone() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let linux = spec.linux.get_or_insert_with(Default::default);
+    if linux.seccomp.is_some() {
+        return Ok(());
+    }
+    let content = std::fs::read(&path)
+        .map_err(Error::io(format!("unable to read the seccomp profile {}", path)))?;
+    let profile = serde_json::from_slice(&content)
+        .map_err(Error::json(format!("invalid seccomp profile {}", path)))?;
+    linux.seccomp = Some(profile);
+    Ok(())
+}
+
+/// Loads the filter of the process before its user changes, while it still
+/// has CAP_SYS_ADMIN. With `process.noNewPrivileges`, no_new_privs is set
+/// instead and the filter waits for `load_last`, so it doesn't see the syscalls
+/// of the setup.
+pub fn load_first(filter: Option<&Filter>, no_new_privileges: bool) -> Result<()> {
+    if no_new_privileges {
+        return fork::set_no_new_privileges();
+    }
+    filter.map_or(Ok(()), Filter::load)
+}
+
+/// Loads the filter right before the exec, once no_new_privs is set
+pub fn load_last(filter: Option<&Filter>, no_new_privileges: bool) -> Result<()> {
+    match no_new_privileges {
+        true => filter.map_or(Ok(()), Filter::load),
+        false => Ok(()),
+    }
+}
+
+/// SECCOMP_FILTER_FLAG_* of the `flags` of the profile
+fn filter_flags(seccomp: &Seccomp) -> Result<libc::c_ulong> {
+    seccomp
+        .flags
+        .iter()
+        .flatten()
+        .map(|flag| match flag.as_str() {
+            "SECCOMP_FILTER_FLAG_TSYNC" => Ok(libc::SECCOMP_FILTER_FLAG_TSYNC),
+            "SECCOMP_FILTER_FLAG_LOG" => Ok(libc::SECCOMP_FILTER_FLAG_LOG),
+            "SECCOMP_FILTER_FLAG_SPEC_ALLOW" => Ok(libc::SECCOMP_FILTER_FLAG_SPEC_ALLOW),
+            _ => Err(Error::Bundle(format!("unknown seccomp flag {}", flag))),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|flags| flags.into_iter().fold(0, |flags, flag| flags | flag))
+}
+
+/// Return value of the filter for the `SCMP_ACT_*` action
+fn action(action: &str, errno_ret: Option<u32>) -> Result<u32> {
+    let data = errno_ret.unwrap_or(libc::EPERM as u32) & libc::SECCOMP_RET_DATA;
+    Ok(match action {
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => libc::SECCOMP_RET_KILL_THREAD,
+        "SCMP_ACT_KILL_PROCESS" => libc::SECCOMP_RET_KILL_PROCESS,
+        "SCMP_ACT_TRAP" => libc::SECCOMP_RET_TRAP,
+        "SCMP_ACT_ERRNO" => libc::SECCOMP_RET_ERRNO | data,
+        "SCMP_ACT_TRACE" => libc::SECCOMP_RET_TRACE | data,
+        "SCMP_ACT_LOG" => libc::SECCOMP_RET_LOG,
+        "SCMP_ACT_ALLOW" => libc::SECCOMP_RET_ALLOW,
+        "SCMP_ACT_NOTIFY" => {
+            return Err(Error::Bundle(
+                "seccomp notifications aren't supported".to_string(),
+            ))
+        }
+        _ => return Err(Error::Bundle(format!("unknown seccomp action {}", action))),
+    })
+}
+
+/// Section of the architecture, killing the process when the filter doesn't
+/// cover it
+fn rules(asm: &mut Assembler, arch: Option<&Arch>, seccomp: &Seccomp, default: u32) -> Result<()> {
+    let arch = match arch {
+        Some(arch) => arch,
+        None => {
+            asm.ret(libc::SECCOMP_RET_KILL_PROCESS);
+            return Ok(());
+        }
+    };
+    for syscall in seccomp.syscalls.iter().flatten() {
+        rule(asm, arch, syscall, default)?;
+    }
+    asm.ret(default);
+    Ok(())
+}
+
+/// Checks of a syscall rule, the first rule matching a syscall deciding
+fn rule(asm: &mut Assembler, arch: &Arch, syscall: &Syscall, default: u32) -> Result<()> {
+    let ret = action(&syscall.action, syscall.errno_ret)?;
+    let args = syscall.args.as_deref().unwrap_or_default();
+    if ret == default && args.is_empty() {
+        return Ok(());
+    }
+    let mut numbers = Vec::new();
+    for name in &syscall.names {
+        match arch.syscall(name) {
+            Some(nr) => numbers.push(nr),
+            None => debug!("skipping unknown syscall {}", name),
+        }
+    }
+    if args.is_empty() {
+        // The syscall number stays loaded, the chains only jump on it
+        for chain in numbers.chunks(CHAIN_LEN) {
+            let (matched, next) = (asm.label(), asm.label());
+            for (i, nr) in chain.iter().enumerate() {
+                let mismatch = match i + 1 == chain.len() {
+                    true => next.into(),
+                    false => Target::Next,
+                };
+                asm.jump(JEQ_K, *nr, matched.into(), mismatch);
+            }
+            asm.bind(matched);
+            asm.ret(ret);
+            asm.bind(next);
+        }
+        return Ok(());
+    }
+    for nr in numbers {
+        let (mismatch, next) = (asm.label(), asm.label());
+        asm.jump(JEQ_K, nr, Target::Next, next.into());
+        for arg in args {
+            compare(asm, arg, mismatch)?;
+        }
+        asm.ret(ret);
+        // The comparisons loaded the arguments instead of the number
+        asm.bind(mismatch);
+        asm.load(NR_OFFSET);
+        asm.bind(next);
+    }
+    Ok(())
+}
+
+/// Jumps to the label unless the 64-bit argument passes the comparison,
+/// as two 32-bit ones: the high halves first, then the low ones
+fn compare(asm: &mut Assembler, arg: &SyscallArg, mismatch: Label) -> Result<()> {
+    if arg.index >= MAX_ARGS {
+        return Err(Error::Bundle(format!(
+            "invalid seccomp argument index {}",
+            arg.index
+        )));
+    }
+    let offset = ARGS_OFFSET + arg.index * 8;
+    let (high, low) = match cfg!(target_endian = "big") {
+        true => (offset, offset + 4),
+        false => (offset + 4, offset),
+    };
+    let split = |value: u64| ((value >> 32) as u32, value as u32);
+    let (value_high, value_low) = split(arg.value);
+    let matched = asm.label();
+    let fail = Target::from(mismatch);
+    match arg.op.as_str() {
+        "SCMP_CMP_EQ" => {
+            asm.load(high);
+            asm.jump(JEQ_K, value_high, Target::Next, fail);
+            asm.load(low);
+            asm.jump(JEQ_K, value_low, Target::Next, fail);
+        }
+        "SCMP_CMP_NE" => {
+            asm.load(high);
+            asm.jump(JEQ_K, value_high, Target::Next, matched.into());
+            asm.load(low);
+            asm.jump(JEQ_K, value_low, fail, Target::Next);
+        }
+        "SCMP_CMP_MASKED_EQ" => {
+            let (two_high, two_low) = split(arg.value_two.unwrap_or_default());
+            asm.load(high);
+            asm.push(AND_K, value_high, Target::Next, Target::Next);
+            asm.jump(JEQ_K, two_high, Target::Next, fail);
+            asm.load(low);
+            asm.push(AND_K, value_low, Target::Next, Target::Next);
+            asm.jump(JEQ_K, two_low, Target::Next, fail);
+        }
+        "SCMP_CMP_GT" | "SCMP_CMP_GE" | "SCMP_CMP_LT" | "SCMP_CMP_LE" => {
+            // LT and LE are the negations of GE and GT
+            let (op, greater) = match arg.op.as_str() {
+                "SCMP_CMP_GT" => (JGT_K, true),
+                "SCMP_CMP_GE" => (JGE_K, true),
+                "SCMP_CMP_LT" => (JGE_K, false),
+                _ => (JGT_K, false),
+            };
+            let (taken, not_taken) = match greater {
+                true => (Target::from(matched), fail),
+                false => (fail, Target::from(matched)),
+            };
+            asm.load(high);
+            asm.jump(JGT_K, value_high, taken, Target::Next);
+            asm.jump(JEQ_K, value_high, Target::Next, not_taken);
+            asm.load(low);
+            asm.jump(op, value_low, taken, not_taken);
+        }
+        op => return Err(Error::Bundle(format!("unknown seccomp operator {}", op))),
+    }
+    asm.bind(matched);
+    Ok(())
+}
+
+/// Label of an instruction of the program being assembled
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+/// Destination of a jump, the next instruction or a label
+#[derive(Clone, Copy)]
+enum Target {
+    Next,
+    Label(Label),
+}
+
+impl From<Label> for Target {
+    fn from(label: Label) -> Target {
+        Target::Label(label)
+    }
+}
+
+/// BPF program with jumps to labels, resolved to offsets once assembled
+#[derive(Default)]
+struct Assembler {
+    code: Vec<(u16, u32, Target, Target)>,
+    labels: Vec<Option<usize>>,
+}
+
+impl Assembler {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Places the label before the next instruction
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn push(&mut self, code: u16, k: u32, jt: Target, jf: Target) {
+        self.code.push((code, k, jt, jf));
+    }
+
+    fn load(&mut self, offset: u32) {
+        self.push(LD_ABS, offset, Target::Next, Target::Next);
+    }
+
+    fn jump(&mut self, code: u16, k: u32, jt: Target, jf: Target) {
+        self.push(code, k, jt, jf);
+    }
+
+    fn ret(&mut self, action: u32) {
+        self.push(RET_K, action, Target::Next, Target::Next);
+    }
+
+    fn assemble(self) -> Result<Vec<libc::sock_filter>> {
+        let labels = self.labels;
+        let offset = |from: usize, target: Target| -> Result<u32> {
+            let to = match target {
+                Target::Next => return Ok(0),
+                Target::Label(label) => labels[label.0].unwrap_or_default(),
+            };
+            to.checked_sub(from + 1)
+                .map(|offset| offset as u32)
+                .ok_or_else(|| Error::Runtime("seccomp filter jumps backwards".to_string()))
+        };
+        let mut program = Vec::with_capacity(self.code.len());
+        for (i, (code, k, jt, jf)) in self.code.into_iter().enumerate() {
+            // Unconditional jumps take their offset from k, so they go anywhere
+            if code == JA {
+                let k = offset(i, jt)?;
+                program.push(libc::sock_filter {
+                    code,
+                    jt: 0,
+                    jf: 0,
+                    k,
+                });
+                continue;
+            }
+            let (jt, jf) = (offset(i, jt)?, offset(i, jf)?);
+            if jt > u8::MAX as u32 || jf > u8::MAX as u32 {
+                return Err(Error::Runtime("seccomp filter jump too long".to_string()));
+            }
+            program.push(libc::sock_filter {
+                code,
+                jt: jt as u8,
+                jf: jf as u8,
+                k,
+            });
+        }
+        Ok(program)
+    }
+}
+
+#[cfg(all(test, feature = "seccomp"))]
+mod tests {
+    use nix::libc;
+    use std::convert::TryInto;
+
+    use super::{encode, find_arch, Arch, Filter, CACHE_DIR, NATIVE};
+    use super::{AND_K, JA, JEQ_K, JGE_K, JGT_K, LD_ABS, RET_K};
+    use crate::oci::spec::{Seccomp, Syscall, SyscallArg};
+
+    /// Runs the filter on the syscall like the kernel does, returning its action
+    fn run(filter: &Filter, arch: u32, nr: u32, args: [u64; 6]) -> u32 {
+        let mut data = Vec::new();
+        data.extend_from_slice(&nr.to_ne_bytes());
+        data.extend_from_slice(&arch.to_ne_bytes());
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        for arg in args {
+            data.extend_from_slice(&arg.to_ne_bytes());
+        }
+        let (mut a, mut pc) = (0u32, 0);
+        loop {
+            let insn = filter.program[pc];
+            pc += 1;
+            let taken = match insn.code {
+                LD_ABS => {
+                    let offset = insn.k as usize;
+                    a = u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+                    continue;
+                }
+                AND_K => {
+                    a &= insn.k;
+                    continue;
+                }
+                JA => {
+                    pc += insn.k as usize;
+                    continue;
+                }
+                RET_K => return insn.k,
+                JEQ_K => a == insn.k,
+                JGT_K => a > insn.k,
+                JGE_K => a >= insn.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            };
+            pc += match taken {
+                true => insn.jt as usize,
+                false => insn.jf as usize,
+            };
+        }
+    }
+
+    fn native() -> &'static Arch {
+        find_arch(NATIVE.unwrap()).unwrap()
+    }
+
+    fn syscall(names: &[&str], action: &str, args: Vec<SyscallArg>) -> Syscall {
+        Syscall {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            action: action.to_string(),
+            args: Some(args).filter(|args| !args.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn actions() {
+        let arch = native();
+        let nr = |name| arch.syscall(name).unwrap();
+        let filter = Filter::compile(&Seccomp {
+            default_action: "SCMP_ACT_ERRNO".to_string(),
+            default_errno_ret: Some(libc::ENOSYS as u32),
+            syscalls: Some(vec![
+                syscall(&["read", "write", "unknown_syscall"], "SCMP_ACT_ALLOW", vec![]),
+                syscall(&["getpid"], "SCMP_ACT_LOG", vec![]),
+                syscall(&["getpid", "gettid"], "SCMP_ACT_KILL_PROCESS", vec![]),
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let run = |arch, nr| run(&filter, arch, nr, [0; 6]);
+        assert_eq!(run(arch.audit, nr("read")), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(arch.audit, nr("write")), libc::SECCOMP_RET_ALLOW);
+        // The first rule of a syscall decides
+        assert_eq!(run(arch.audit, nr("getpid")), libc::SECCOMP_RET_LOG);
+        assert_eq!(run(arch.audit, nr("gettid")), libc::SECCOMP_RET_KILL_PROCESS);
+        let errno = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+        assert_eq!(run(arch.audit, nr("openat")), errno);
+        let audit_arch_mips = 0x0000_0008;
+        assert_eq!(run(audit_arch_mips, nr("read")), libc::SECCOMP_RET_KILL_PROCESS);
+        if cfg!(target_arch = "x86_64") {
+            let x32_read = 0x4000_0000;
+            assert_eq!(run(arch.audit, x32_read), libc::SECCOMP_RET_KILL_PROCESS);
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        let arch = native();
+        let kill = arch.syscall("kill").unwrap();
+        const HIGH: u64 = 1 << 32;
+        // Operator, value and valueTwo, with the arguments matching or not
+        type Case = (&'static str, u64, u64, &'static [(u64, bool)]);
+        let cases: [Case; 7] = [
+            ("SCMP_CMP_EQ", HIGH + 2, 0, &[(HIGH + 2, true), (2, false), (2 * HIGH + 2, false)]),
+            ("SCMP_CMP_NE", HIGH + 2, 0, &[(HIGH + 2, false), (2, true), (HIGH + 3, true)]),
+            (
+                "SCMP_CMP_MASKED_EQ",
+                0xff00_0000_00ff,
+                0x1200_0000_0034,
+                &[(0x12ab_cdef_9934, true), (0x1300_0000_0034, false), (0x1200_0000_0035, false)],
+            ),
+            ("SCMP_CMP_GT", HIGH, 0, &[(HIGH + 1, true), (HIGH, false), (HIGH - 1, false)]),
+            ("SCMP_CMP_GE", HIGH, 0, &[(HIGH, true), (HIGH - 1, false), (2 * HIGH, true)]),
+            ("SCMP_CMP_LT", HIGH, 0, &[(HIGH - 1, true), (HIGH, false), (HIGH + 1, false)]),
+            ("SCMP_CMP_LE", HIGH, 0, &[(HIGH, true), (HIGH + 1, false), (0, true)]),
+        ];
+        for (op, value, value_two, args) in cases {
+            let arg = SyscallArg {
+                index: 1,
+                value,
+                value_two: Some(value_two),
+                op: op.to_string(),
+                ..Default::default()
+            };
+            let filter = Filter::compile(&Seccomp {
+                default_action: "SCMP_ACT_ALLOW".to_string(),
+                syscalls: Some(vec![syscall(&["kill"], "SCMP_ACT_ERRNO", vec![arg])]),
+                ..Default::default()
+            })
+            .unwrap();
+            for (arg, matched) in args {
+                let action = run(&filter, arch.audit, kill, [0, *arg, 0, 0, 0, 0]);
+                let expected = match matched {
+                    true => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+                    false => libc::SECCOMP_RET_ALLOW,
+                };
+                assert_eq!(action, expected, "{} {:#x} of {:#x}", op, arg, value);
+            }
+        }
+    }
+
+    #[test]
+    fn long_profiles() {
+        // More syscalls than a jump of BPF reaches
+        let arch = native();
+        let names: Vec<&str> = arch.table.iter().map(|(name, _)| *name).collect();
+        let filter = Filter::compile(&Seccomp {
+            default_action: "SCMP_ACT_KILL".to_string(),
+            syscalls: Some(vec![syscall(&names, "SCMP_ACT_ALLOW", vec![])]),
+            ..Default::default()
+        })
+        .unwrap();
+        for (_, nr) in arch.table {
+            assert_eq!(run(&filter, arch.audit, *nr, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        }
+        assert_eq!(run(&filter, arch.audit, 4000, [0; 6]), libc::SECCOMP_RET_KILL_THREAD);
+    }
+
+    #[test]
+    fn architectures() {
+        let profile = |arches: &[&str]| Seccomp {
+            default_action: "SCMP_ACT_ALLOW".to_string(),
+            architectures: Some(arches.iter().map(|arch| arch.to_string()).collect()),
+            syscalls: Some(vec![syscall(&["getpid"], "SCMP_ACT_ERRNO", vec![])]),
+            ..Default::default()
+        };
+        let errno = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        let x86 = Filter::compile(&profile(&["SCMP_ARCH_X86_64", "SCMP_ARCH_X86", "SCMP_ARCH_X32"]))
+            .unwrap();
+        // getpid is 39 on x86_64 and x32, 20 on i386
+        let (audit_x86_64, audit_i386) = (0xc000_003e, 0x4000_0003);
+        assert_eq!(run(&x86, audit_x86_64, 39, [0; 6]), errno);
+        assert_eq!(run(&x86, audit_x86_64, 20, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&x86, audit_i386, 20, [0; 6]), errno);
+        assert_eq!(run(&x86, audit_i386, 39, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&x86, audit_x86_64, 0x4000_0027, [0; 6]), errno);
+        assert_eq!(run(&x86, audit_x86_64, 0x4000_0000, [0; 6]), libc::SECCOMP_RET_ALLOW);
+
+        // Without x32 in the list, its syscalls kill like those of unlisted arches
+        let arm = profile(&["SCMP_ARCH_AARCH64", "SCMP_ARCH_ARM", "SCMP_ARCH_MIPS"]);
+        let arm = Filter::compile(&arm).unwrap();
+        let (audit_aarch64, audit_arm) = (0xc000_00b7, 0x4000_0028);
+        assert_eq!(run(&arm, audit_aarch64, 172, [0; 6]), errno);
+        assert_eq!(run(&arm, audit_aarch64, 20, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run(&arm, audit_arm, 20, [0; 6]), errno);
+        assert_eq!(run(&arm, audit_arm, 172, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        match NATIVE {
+            Some("SCMP_ARCH_X86_64") => {
+                assert_eq!(run(&arm, audit_x86_64, 0x4000_0027, [0; 6]), kill)
+            }
+            _ => assert_eq!(run(&arm, audit_x86_64, 39, [0; 6]), kill),
+        }
+        let audit_arch_mips = 0x0000_0008;
+        assert_eq!(run(&arm, audit_arch_mips, 20, [0; 6]), kill);
+
+        // Every table resolves the names of the profile on its own
+        for arch in super::ARCHES {
+            let filter = Filter::compile(&profile(&[arch.name])).unwrap();
+            let getpid = arch.syscall("getpid").unwrap();
+            assert_eq!(run(&filter, arch.audit, getpid, [0; 6]), errno, "{}", arch.name);
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join(CACHE_DIR);
+        let profile = |action: &str| Seccomp {
+            default_action: "SCMP_ACT_ALLOW".to_string(),
+            flags: Some(vec!["SECCOMP_FILTER_FLAG_LOG".to_string()]),
+            syscalls: Some(vec![syscall(&["kill"], action, vec![])]),
+            ..Default::default()
+        };
+        let entries = || std::fs::read_dir(&cache).unwrap().map(|entry| entry.unwrap().path());
+        let compiled = Filter::cached(&profile("SCMP_ACT_ERRNO"), &cache).unwrap();
+        let path = entries().next().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), encode(&compiled.program));
+
+        // The next creations load the file instead of compiling
+        let other = Filter::compile(&profile("SCMP_ACT_LOG")).unwrap();
+        std::fs::write(&path, encode(&other.program)).unwrap();
+        let cached = Filter::cached(&profile("SCMP_ACT_ERRNO"), &cache).unwrap();
+        assert_eq!(encode(&cached.program), encode(&other.program));
+        assert_eq!(cached.flags, libc::SECCOMP_FILTER_FLAG_LOG);
+
+        // A cut file is compiled again
+        std::fs::write(&path, &encode(&compiled.program)[..12]).unwrap();
+        let cached = Filter::cached(&profile("SCMP_ACT_ERRNO"), &cache).unwrap();
+        assert_eq!(encode(&cached.program), encode(&compiled.program));
+        assert_eq!(std::fs::read(&path).unwrap(), encode(&compiled.program));
+
+        Filter::cached(&profile("SCMP_ACT_LOG"), &cache).unwrap();
+        assert_eq!(entries().count(), 2);
+        assert!(Filter::cached(&profile("SCMP_ACT_DENY"), &cache).is_err());
+        assert_eq!(entries().count(), 2);
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let profile = |action: &str, args: Vec<SyscallArg>| Seccomp {
+            default_action: "SCMP_ACT_ALLOW".to_string(),
+            syscalls: Some(vec![syscall(&["kill"], action, args)]),
+            ..Default::default()
+        };
+        let arg = |index, op: &str| SyscallArg {
+            index,
+            op: op.to_string(),
+            ..Default::default()
+        };
+        assert!(Filter::compile(&profile("SCMP_ACT_DENY", vec![])).is_err());
+        assert!(Filter::compile(&profile("SCMP_ACT_NOTIFY", vec![])).is_err());
+        assert!(Filter::compile(&profile("SCMP_ACT_ERRNO", vec![arg(6, "SCMP_CMP_EQ")])).is_err());
+        assert!(Filter::compile(&profile("SCMP_ACT_ERRNO", vec![arg(0, "SCMP_CMP_IN")])).is_err());
+        let mut flagged = profile("SCMP_ACT_ERRNO", vec![]);
+        flagged.flags = Some(vec!["SECCOMP_FILTER_FLAG_LOG".to_string()]);
+        assert_eq!(Filter::compile(&flagged).unwrap().flags, libc::SECCOMP_FILTER_FLAG_LOG);
+        flagged.flags = Some(vec!["SECCOMP_FILTER_FLAG_NEW".to_string()]);
+        assert!(Filter::compile(&flagged).is_err());
+        let mut listener = profile("SCMP_ACT_ERRNO", vec![]);
+        listener.listener_path = Some("/run/seccomp.sock".to_string());
+        assert!(Filter::compile(&listener).is_err());
+    }
+}
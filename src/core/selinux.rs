@@ -0,0 +1,204 @@
+use log::debug;
+use nix::{errno::Errno, libc};
+use std::{collections::HashMap, ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+use crate::core::common::{disabled, Error, Result};
+use crate::oci::spec::{Mount, Spec};
+
+/// Annotation of the bind mounts to relabel, `DEST:z` shared with other
+/// containers or `DEST:Z` private to this one, comma separated
+pub const RELABEL_ANNOTATION: &str = "pura.selinux.relabel";
+/// Present when SELinux is enabled, mounted by the policy load
+const SELINUXFS_ENFORCE: &str = "/sys/fs/selinux/enforce";
+const XATTR_NAME: &str = "security.selinux";
+/// Filesystems without labels of their own, labeled by the mount option
+const CONTEXT_FILESYSTEMS: [&str; 4] = ["tmpfs", "devpts", "mqueue", "shm"];
+/// Mount options setting the label of a filesystem
+const CONTEXT_OPTIONS: [&str; 4] = ["context=", "fscontext=", "defcontext=", "rootcontext="];
+/// Host directories never relabeled, the host would lose access to them,
+/// like the root
+const PROTECTED_PATHS: [&str; 11] = [
+    "/bin", "/boot", "/dev", "/etc", "/home", "/lib", "/proc", "/root", "/sys", "/usr", "/var",
+];
+
+pub fn enabled() -> bool {
+    Path::new(SELINUXFS_ENFORCE).exists()
+}
+
+/// Labels of the mounts of the container, from `linux.mountLabel`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountLabels {
+    label: String,
+    /// Destinations of the bind mounts to relabel, and whether shared
+    relabel: HashMap<String, bool>,
+}
+
+impl MountLabels {
+    /// Labels of the spec, None without a mount label or SELinux
+    pub fn from_spec(spec: &Spec) -> Result<Option<MountLabels>> {
+        let label = spec
+            .linux
+            .as_ref()
+            .and_then(|linux| linux.mount_label.as_ref())
+            .filter(|label| !label.is_empty());
+        let label = match label {
+            Some(label) if enabled() => label.clone(),
+            _ => return Ok(None),
+        };
+        // Labels left out would only show up as denials in the container
+        if !cfg!(feature = "selinux") {
+            return Err(disabled("selinux"));
+        }
+        let relabel = relabel_requests(spec.annotations.as_ref())?;
+        Ok(Some(MountLabels { label, relabel }))
+    }
+
+    /// `context=` option of the mount, for the filesystems without labels
+    /// and unless the options label it already
+    pub fn context_option(&self, mount_type: &str, options: &[String]) -> Option<String> {
+        let labeled = options.iter().any(|option| {
+            CONTEXT_OPTIONS
+                .iter()
+                .any(|prefix| option.starts_with(prefix))
+        });
+        if labeled || !CONTEXT_FILESYSTEMS.contains(&mount_type) {
+            return None;
+        }
+        Some(format!("context=\"{}\"", self.label))
+    }
+
+    /// Relabels the source of the bind mount when the annotation asks for it
+    pub fn relabel(&self, mount: &Mount) -> Result<()> {
+        let shared = match self.relabel.get(&mount.destination) {
+            Some(shared) => *shared,
+            None => return Ok(()),
+        };
+        let source = mount.source.as_deref().unwrap_or_default();
+        let protected = match source.trim_end_matches('/') {
+            "" => true,
+            source => PROTECTED_PATHS.contains(&source),
+        };
+        if protected {
+            return Err(Error::Bundle(format!(
+                "refusing to relabel {}, a directory of the host",
+                source
+            )));
+        }
+        let label = match shared {
+            true => shared_label(&self.label),
+            false => self.label.clone(),
+        };
+        debug!("relabeling {} as {}", source, label);
+        relabel(Path::new(source), &label)
+    }
+}
+
+/// Bind mounts the annotation asks to relabel, by destination, and whether
+/// their label is shared
+pub fn relabel_requests(
+    annotations: Option<&HashMap<String, String>>,
+) -> Result<HashMap<String, bool>> {
+    let value = match annotations.and_then(|annotations| annotations.get(RELABEL_ANNOTATION)) {
+        Some(value) => value,
+        None => return Ok(HashMap::new()),
+    };
+    let mut requests = HashMap::new();
+    for request in value.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let shared = match request.rsplit_once(':') {
+            Some((destination, "z")) if destination.starts_with('/') => (destination, true),
+            Some((destination, "Z")) if destination.starts_with('/') => (destination, false),
+            _ => {
+                return Err(Error::Bundle(format!(
+                    "invalid relabel {:?} in {}, DEST:z or DEST:Z",
+                    request, RELABEL_ANNOTATION
+                )))
+            }
+        };
+        requests.insert(shared.0.to_string(), shared.1);
+    }
+    Ok(requests)
+}
+
+/// Label readable by every container: the categories of the level dropped
+fn shared_label(label: &str) -> String {
+    let mut parts: Vec<&str> = label.splitn(4, ':').collect();
+    if parts.len() == 4 {
+        parts[3] = "s0";
+    }
+    parts.join(":")
+}
+
+/// Sets the label of the path and everything below it, symlinks included
+/// but never followed
+fn relabel(path: &Path, label: &str) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::Bundle(format!("invalid path {:?}", path)))?;
+    let name = CString::new(XATTR_NAME).unwrap();
+    let res = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            label.as_ptr() as *const libc::c_void,
+            label.len(),
+            0,
+        )
+    };
+    Errno::result(res).map_err(Error::sys(format!("unable to relabel {:?}", path)))?;
+
+    let metadata = path
+        .symlink_metadata()
+        .map_err(Error::io(format!("unable to stat {:?}", path)))?;
+    if metadata.is_dir() {
+        let entries =
+            std::fs::read_dir(path).map_err(Error::io(format!("unable to list {:?}", path)))?;
+        for entry in entries.flatten() {
+            relabel(&entry.path(), label)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{relabel_requests, shared_label, MountLabels, RELABEL_ANNOTATION};
+    use crate::oci::spec::Mount;
+
+    #[test]
+    fn mount_labels() {
+        let label = "system_u:object_r:container_file_t:s0:c1,c2";
+        assert_eq!(shared_label(label), "system_u:object_r:container_file_t:s0");
+
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            RELABEL_ANNOTATION.to_string(),
+            "/data:z, /cache:Z".to_string(),
+        );
+        let relabel = relabel_requests(Some(&annotations)).unwrap();
+        assert_eq!(relabel.get("/data"), Some(&true));
+        assert_eq!(relabel.get("/cache"), Some(&false));
+        for invalid in ["/data", "/data:x", "data:z"] {
+            annotations.insert(RELABEL_ANNOTATION.to_string(), invalid.to_string());
+            assert!(relabel_requests(Some(&annotations)).is_err(), "{}", invalid);
+        }
+        assert!(relabel_requests(None).unwrap().is_empty());
+
+        let labels = MountLabels {
+            label: label.to_string(),
+            relabel,
+        };
+        assert_eq!(
+            labels.context_option("tmpfs", &[String::from("nosuid")]),
+            Some(format!("context=\"{}\"", label))
+        );
+        assert_eq!(labels.context_option("bind", &[]), None);
+        let labeled = [String::from("context=\"system_u:object_r:tmp_t:s0\"")];
+        assert_eq!(labels.context_option("tmpfs", &labeled), None);
+
+        let mount = |source: &str, destination: &str| Mount::new(destination, "bind", source, &[]);
+        assert!(labels.relabel(&mount("/usr", "/data")).is_err());
+        // Not requested
+        assert!(labels.relabel(&mount("/usr", "/other")).is_ok());
+    }
+}
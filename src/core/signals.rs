@@ -0,0 +1,190 @@
+use log::debug;
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc,
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::{close, pipe2, read, write},
+};
+use std::{
+    convert::TryFrom,
+    os::unix::io::RawFd,
+    path::Path,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use crate::core::{
+    cgroups::procs,
+    common::{Error, Result},
+    fork::{self, Process, EXIT_POLL_INTERVAL},
+};
+
+/// Signals of the terminal and the service managers, forwarded to the container
+pub const FORWARDED: [Signal; 7] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+];
+
+/// Write end of the pipe of the signal handler, -1 without a forwarder
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn queue_signal(signal: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // A full pipe drops the signal, like a pending one would be
+        let _ = write(fd, &[signal as u8]);
+    }
+}
+
+/// Catches the forwarded signals sent to the runtime in the foreground, like
+/// Ctrl-C or `systemctl stop`, instead of them killing the runtime.
+///
+/// The handlers only queue the signals in a pipe. Unlike blocked signals, caught
+/// ones are reset on exec, so hooks and helpers run with the default handling.
+/// There's a single forwarder at once, the previous handlers are restored on drop.
+#[derive(Debug)]
+pub struct Forwarder {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    previous: Vec<(Signal, SigAction)>,
+}
+
+impl Forwarder {
+    pub fn new() -> Result<Forwarder> {
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+            .map_err(Error::sys("unable to create the signal pipe"))?;
+        if SIGNAL_PIPE
+            .compare_exchange(-1, write_fd, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            let _ = close(read_fd);
+            let _ = close(write_fd);
+            return Err(Error::Runtime("signals are already forwarded".to_string()));
+        }
+
+        let mut forwarder = Forwarder {
+            read_fd,
+            write_fd,
+            previous: Vec::new(),
+        };
+        let action = SigAction::new(
+            SigHandler::Handler(queue_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        for signal in FORWARDED.iter() {
+            let previous = unsafe { sigaction(*signal, &action) }
+                .map_err(Error::sys(format!("unable to catch {}", signal)))?;
+            forwarder.previous.push((*signal, previous));
+        }
+        Ok(forwarder)
+    }
+
+    /// Forwards the signals to the process, or to every process of the cgroup
+    /// when given, until the process exits
+    pub fn forward(&mut self, process: &Process, cgroup: Option<&Path>) -> Result<()> {
+        loop {
+            let mut fds = vec![PollFd::new(self.read_fd, PollFlags::POLLIN)];
+            let timeout = match process.pidfd() {
+                // The pidfd becomes readable once the process exits
+                Some(pidfd) => {
+                    fds.push(PollFd::new(pidfd, PollFlags::POLLIN));
+                    -1
+                }
+                None => EXIT_POLL_INTERVAL.as_millis() as i32,
+            };
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(err) => return Err(Error::sys("error polling the signal pipe")(err)),
+            }
+
+            self.forward_pending(process, cgroup)?;
+            if fork::exited(process.pid()) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn forward_pending(&mut self, process: &Process, cgroup: Option<&Path>) -> Result<()> {
+        let mut signals = [0u8; 32];
+        loop {
+            let num = match read(self.read_fd, &mut signals) {
+                Ok(num) => num,
+                Err(nix::Error::Sys(Errno::EAGAIN)) => return Ok(()),
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => return Err(Error::sys("unable to read the signal pipe")(err)),
+            };
+            for signal in signals[..num].iter() {
+                let signal = match Signal::try_from(*signal as i32) {
+                    Ok(signal) => signal,
+                    Err(_) => continue,
+                };
+                debug!("forwarding {} to the container", signal);
+                match cgroup {
+                    Some(cgroup) => {
+                        for pid in procs(cgroup) {
+                            // Processes exiting meanwhile are fine
+                            let _ = fork::signal(pid, signal as i32);
+                        }
+                    }
+                    None => match process.signal(signal as i32) {
+                        Err(Error::Sys { source, .. })
+                            if source.as_errno() == Some(Errno::ESRCH) => {}
+                        result => result?,
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        for (signal, previous) in self.previous.iter() {
+            let _ = unsafe { sigaction(*signal, previous) };
+        }
+        SIGNAL_PIPE.store(-1, Ordering::SeqCst);
+        let _ = close(self.read_fd);
+        let _ = close(self.write_fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        sys::signal::{raise, Signal},
+        unistd::Pid,
+    };
+    use std::{
+        os::unix::process::ExitStatusExt,
+        process::{Command, Stdio},
+    };
+
+    use super::Forwarder;
+    use crate::core::fork::Process;
+
+    #[test]
+    fn forward_signals() {
+        let mut forwarder = Forwarder::new().unwrap();
+        // A single forwarder catches the signals
+        assert!(Forwarder::new().is_err());
+
+        let mut child = Command::new("sleep")
+            .arg("60")
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let process = Process::open(Pid::from_raw(child.id() as i32), None).unwrap();
+
+        // Caught by the runtime, forwarded to the process
+        raise(Signal::SIGTERM).unwrap();
+        forwarder.forward(&process, None).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(nix::libc::SIGTERM));
+    }
+}
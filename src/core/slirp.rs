@@ -0,0 +1,220 @@
+use log::debug;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc,
+    unistd::{close, pipe2, read, setsid, Pid},
+};
+use std::{
+    fs::File,
+    os::unix::{io::RawFd, process::CommandExt},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::core::{
+    common::{Error, Result},
+    fork::{self, Process},
+};
+
+/// Pid and start time of the network helper of the container
+pub const HELPER_FILE: &str = "network.pid";
+/// Output of the network helper, to debug a failed setup
+pub const HELPER_LOG: &str = "network.log";
+
+/// Program giving a rootless container outbound networking from user mode,
+/// like podman and rootless docker do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Helper {
+    Slirp4netns,
+    Pasta,
+}
+
+impl Helper {
+    fn binary(&self) -> &'static str {
+        match self {
+            Helper::Slirp4netns => "slirp4netns",
+            Helper::Pasta => "pasta",
+        }
+    }
+}
+
+/// Connects the network namespace of the process (and its user namespace) to
+/// the host through the helper, returning once the namespace is configured.
+/// The helper runs detached from the runtime until `stop`.
+pub fn start(helper: Helper, pid: Pid, container_path: &Path) -> Result<()> {
+    let log = File::create(container_path.join(HELPER_LOG))
+        .map_err(Error::io("unable to create the network helper log"))?;
+    debug!("starting {} for container process {}", helper.binary(), pid);
+
+    let helper_pid = match helper {
+        Helper::Slirp4netns => start_slirp4netns(pid, log)?,
+        Helper::Pasta => start_pasta(pid, container_path, log)?,
+    };
+
+    // Like the container process, stop tells the helper apart from a process
+    // reusing its pid by the start time
+    let start_time = fork::start_time(helper_pid).ok_or_else(|| exited(helper, container_path))?;
+    std::fs::write(
+        container_path.join(HELPER_FILE),
+        format!("{} {}", helper_pid, start_time),
+    )
+    .map_err(Error::io("unable to save the network helper pid"))
+}
+
+fn exited(helper: Helper, container_path: &Path) -> Error {
+    Error::Runtime(format!(
+        "{} exited, see {}",
+        helper.binary(),
+        container_path.join(HELPER_LOG).display()
+    ))
+}
+
+fn slirp4netns_args(pid: Pid, ready_fd: RawFd) -> Vec<String> {
+    vec![
+        String::from("--configure"),
+        String::from("--mtu=65520"),
+        // The loopback of the host isn't reachable from the container as 10.0.2.2
+        String::from("--disable-host-loopback"),
+        format!("--ready-fd={}", ready_fd),
+        pid.to_string(),
+        String::from("tap0"),
+    ]
+}
+
+fn start_slirp4netns(pid: Pid, log: File) -> Result<Pid> {
+    let helper = Helper::Slirp4netns;
+    let (ready_read, ready_write) =
+        pipe2(OFlag::O_CLOEXEC).map_err(Error::sys("unable to create the ready pipe"))?;
+
+    let mut command = Command::new(helper.binary());
+    command
+        .args(slirp4netns_args(pid, ready_write))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone().map_err(Error::io("unable to dup log"))?)
+        .stderr(log);
+    // Only async-signal-safe calls run between the fork and the exec
+    unsafe {
+        command.pre_exec(move || {
+            let _ = setsid();
+            fcntl(ready_write, FcntlArg::F_SETFD(FdFlag::empty()))
+                .map(drop)
+                .map_err(|_| std::io::Error::last_os_error())
+        });
+    }
+    let spawned = command.spawn();
+    let _ = close(ready_write);
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(err) => {
+            let _ = close(ready_read);
+            return Err(Error::io(format!("unable to run {}", helper.binary()))(err));
+        }
+    };
+
+    // slirp4netns writes 1 once configured, the end of the pipe means it exited
+    let mut ready = [0u8; 1];
+    let num = read(ready_read, &mut ready);
+    let _ = close(ready_read);
+    if num != Ok(1) {
+        let _ = child.wait();
+        return Err(Error::Runtime(format!(
+            "{} failed to configure the network namespace",
+            helper.binary()
+        )));
+    }
+    Ok(Pid::from_raw(child.id() as i32))
+}
+
+fn start_pasta(pid: Pid, container_path: &Path, log: File) -> Result<Pid> {
+    let helper = Helper::Pasta;
+    let pid_file = container_path.join(HELPER_FILE);
+
+    // pasta returns once the namespace is configured, leaving its daemon behind
+    let status = Command::new(helper.binary())
+        .arg("--config-net")
+        .arg("--quiet")
+        .arg("--pid")
+        .arg(&pid_file)
+        .arg(pid.to_string())
+        .stdin(Stdio::null())
+        .stdout(log.try_clone().map_err(Error::io("unable to dup log"))?)
+        .stderr(log)
+        .status()
+        .map_err(Error::io(format!("unable to run {}", helper.binary())))?;
+    if !status.success() {
+        return Err(exited(helper, container_path));
+    }
+
+    std::fs::read_to_string(&pid_file)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok())
+        .map(Pid::from_raw)
+        .ok_or_else(|| exited(helper, container_path))
+}
+
+fn parse_helper_file(content: &str) -> Option<(Pid, u64)> {
+    let (pid, start_time) = content.trim().split_once(' ')?;
+    Some((Pid::from_raw(pid.parse().ok()?), start_time.parse().ok()?))
+}
+
+/// Stops the network helper of the container, if any
+pub fn stop(container_path: &Path) -> Result<()> {
+    let helper_file = container_path.join(HELPER_FILE);
+    let content = match std::fs::read_to_string(&helper_file) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(Error::io("unable to read the network helper pid")(err)),
+    };
+    let (pid, start_time) = parse_helper_file(&content)
+        .ok_or_else(|| Error::State(format!("invalid {}: {:?}", HELPER_FILE, content)))?;
+
+    match Process::open(pid, Some(start_time)) {
+        Ok(process) => process.signal(libc::SIGTERM)?,
+        Err(err) => debug!("network helper already gone: {}", err),
+    }
+    std::fs::remove_file(&helper_file).map_err(Error::io("unable to remove the network helper pid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::Pid;
+    use std::{os::unix::process::ExitStatusExt, path::Path, process::Command};
+
+    use super::{parse_helper_file, slirp4netns_args, stop, HELPER_FILE};
+    use crate::core::fork::start_time;
+
+    #[test]
+    fn helper_args() {
+        assert_eq!(
+            slirp4netns_args(Pid::from_raw(42), 5).join(" "),
+            "--configure --mtu=65520 --disable-host-loopback --ready-fd=5 42 tap0"
+        );
+        assert_eq!(
+            parse_helper_file("42 1234\n"),
+            Some((Pid::from_raw(42), 1234))
+        );
+        assert_eq!(parse_helper_file("42"), None);
+    }
+
+    #[test]
+    fn stop_helper() {
+        let path = Path::new("./slirp_test");
+        std::fs::create_dir_all(path).unwrap();
+        // Nothing to stop without a helper
+        stop(path).unwrap();
+
+        let mut helper = Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = Pid::from_raw(helper.id() as i32);
+        std::fs::write(
+            path.join(HELPER_FILE),
+            format!("{} {}", pid, start_time(pid).unwrap()),
+        )
+        .unwrap();
+
+        stop(path).unwrap();
+        assert_eq!(helper.wait().unwrap().signal(), Some(nix::libc::SIGTERM));
+        assert!(!path.join(HELPER_FILE).exists());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{path::Path, time::Duration};
+
+use crate::core::{
+    common::{Error, Result},
+    state::{JsonStore, State, Store, STATE_DB},
+};
+
+/// Wait of a runtime for another one writing the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const SCHEMA: &str = concat!(
+    "PRAGMA journal_mode = WAL;",
+    "CREATE TABLE IF NOT EXISTS states (id TEXT PRIMARY KEY, state TEXT NOT NULL);"
+);
+
+fn sqlite<S: Into<String>>(context: S) -> impl FnOnce(rusqlite::Error) -> Error {
+    let context = context.into();
+    move |err| Error::Runtime(format!("{}: {}", context, err))
+}
+
+/// The states of the root in a single SQLite database, `state.db`, so listing
+/// thousands of containers is one query and sees them all at the same time
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database of the root, created with the states of the state
+    /// files the root already has
+    pub fn open(root: &Path) -> Result<SqliteStore> {
+        std::fs::create_dir_all(root)
+            .map_err(Error::io(format!("unable to create root {:?}", root)))?;
+        let path = root.join(STATE_DB);
+        let new = !path.exists();
+        let connection =
+            Connection::open(&path).map_err(sqlite(format!("unable to open {:?}", path)))?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(sqlite("unable to set the busy timeout"))?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(sqlite(format!("unable to create the tables of {:?}", path)))?;
+
+        let store = SqliteStore { connection };
+        if new {
+            for state in JsonStore::new(root).list()? {
+                store.save(&state)?;
+            }
+        }
+        Ok(store)
+    }
+}
+
+impl Store for SqliteStore {
+    fn save(&self, state: &State) -> Result<()> {
+        let json = serde_json::to_string(state).map_err(Error::json("unable to serialize state"))?;
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO states (id, state) VALUES (?1, ?2)",
+                params![state.id, json],
+            )
+            .map_err(sqlite(format!("save state failed for {}", state.id)))?;
+        Ok(())
+    }
+
+    fn load(&self, id: &str) -> Result<Option<State>> {
+        let json: Option<String> = self
+            .connection
+            .query_row("SELECT state FROM states WHERE id = ?1", params![id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sqlite(format!("unable to load the state of {}", id)))?;
+        json.map(|json| {
+            serde_json::from_str(&json).map_err(Error::json("unable to deserialize state"))
+        })
+        .transpose()
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        self.connection
+            .execute("DELETE FROM states WHERE id = ?1", params![id])
+            .map_err(sqlite(format!("unable to remove the state of {}", id)))?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<State>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT state FROM states ORDER BY id")
+            .map_err(sqlite("unable to list the states"))?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(sqlite("unable to list the states"))?;
+        let mut states = Vec::new();
+        for json in rows {
+            let json = json.map_err(sqlite("unable to list the states"))?;
+            states.push(
+                serde_json::from_str(&json).map_err(Error::json("unable to deserialize state"))?,
+            );
+        }
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::SqliteStore;
+    use crate::core::state::{JsonStore, State, Status, Store};
+
+    #[test]
+    fn sqlite_store() {
+        let root = Path::new("./sqlite_store_test");
+        // Imported from the state files on creation
+        JsonStore::new(root)
+            .save(&State::new("a", 24, "/containers"))
+            .unwrap();
+        let store = SqliteStore::open(root).unwrap();
+
+        let mut state = State::new("b", 23, "/containers");
+        store.save(&state).unwrap();
+        state.status = Status::Running;
+        store.save(&state).unwrap();
+
+        assert_eq!(store.load("b").unwrap(), Some(state));
+        assert_eq!(store.load("c").unwrap(), None);
+        let ids: Vec<String> = store.list().unwrap().into_iter().map(|s| s.id).collect();
+        assert_eq!(ids, ["a", "b"]);
+
+        store.remove("a").unwrap();
+        assert_eq!(store.load("a").unwrap(), None);
+        // Shared by the runtimes
+        let other = SqliteStore::open(root).unwrap();
+        assert_eq!(other.list().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,7 +1,20 @@
+use log::debug;
+use nix::unistd::Pid;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, convert::TryFrom, io::Write, path::{Path, PathBuf}};
+use std::{collections::HashMap, convert::TryFrom, ffi::OsStr, io::Write, path::{Path, PathBuf}};
 
-use crate::core::common::{Result, Error, ErrorType};
+use crate::core::{
+    audit::AUDIT_FILE,
+    common::{Error, Result},
+    fork::exited,
+    lock::LOCKS_DIR,
+    pod::PODS_DIR,
+    recording::RECORDING_EXTENSION,
+    seccomp::CACHE_DIR,
+    volume::VOLUMES_DIR,
+};
+#[cfg(feature = "sqlite")]
+use crate::core::sqlite::SqliteStore;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -24,37 +37,98 @@ pub struct State {
 }
 
 const OCI_VERSION: &str = "1.0.2";
+/// File of the state in the directory of the container
+const STATE_FILE: &str = "state.json";
+/// Database of the states, the root uses the SQLite store once it exists
+pub const STATE_DB: &str = "state.db";
+/// Store of a root without containers yet, `json` (the default) or `sqlite`
+pub const STATE_BACKEND_ENV: &str = "PURA_STATE_BACKEND";
+
+/// Entries of the runtime root next to the state dirs, the SQLite database
+/// with its WAL files included
+const RESERVED_IDS: [&str; 9] = [
+    VOLUMES_DIR,
+    PODS_DIR,
+    CACHE_DIR,
+    LOCKS_DIR,
+    AUDIT_FILE,
+    STATE_DB,
+    "state.db-wal",
+    "state.db-shm",
+    "state.db-journal",
+];
+
+/// Checks the id is a single plain name, since it names the directory of the
+/// container in the runtime root. Letters, digits and `_+-.` only, like runc,
+/// so an id can never be a path out of the root. The names of the other
+/// entries of the root, `<id>.cast` recordings included, are refused too.
+pub fn validate_id(id: &str) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(Error::Runtime(format!("invalid container id {:?}: {}", id, reason)))
+    };
+    if id.is_empty() {
+        return invalid("empty");
+    }
+    if id == "." || id == ".." {
+        return invalid("not a name");
+    }
+    if let Some(c) = id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "_+-.".contains(*c)))
+    {
+        return invalid(&format!("{:?} isn't allowed, only letters, digits and _+-.", c));
+    }
+    if RESERVED_IDS.contains(&id)
+        || Path::new(id).extension() == Some(OsStr::new(RECORDING_EXTENSION))
+    {
+        return invalid("reserved for the runtime root");
+    }
+    Ok(())
+}
 
 impl State {
-    pub fn new(id: &String, pid: u64, bundle: &String) -> State {
+    pub fn new(id: &str, pid: u64, bundle: &str) -> State {
         State {
             oci_version: String::from(OCI_VERSION),
-            id: id.clone(),
-            pid: pid,
+            id: id.to_string(),
+            pid,
             status: Status::Creating,
-            bundle: Path::new(bundle).canonicalize().unwrap(),
+            bundle: Path::new(bundle)
+                .canonicalize()
+                .unwrap_or_else(|_| PathBuf::from(bundle)),
             annotations: Some(HashMap::<String, String>::new()),
         }
     }
 
+    /// The state with a container whose process is gone reported as stopped
+    pub fn refreshed(mut self) -> State {
+        if (self.status == Status::Created || self.status == Status::Running)
+            && exited(Pid::from_raw(self.pid as i32))
+        {
+            self.status = Status::Stopped;
+        }
+        self
+    }
+
     pub fn save(&self, root_path: &Path) -> Result<()> {
-        std::fs::create_dir_all(root_path).unwrap();
+        std::fs::create_dir_all(root_path)
+            .map_err(Error::io(format!("unable to create state dir {:?}", root_path)))?;
 
+        // Renamed over the state, readers never see it half written
+        let tmp_path = root_path.join(format!("{}.tmp", STATE_FILE));
         let mut state_file = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(root_path.join("state.json"))
-            .map_err(|err| Error {
-                msg: format!("save state failed {} for {:?}", err, root_path),
-                err_type: ErrorType::Container,
-            })?;
+            .truncate(true)
+            .open(&tmp_path)
+            .map_err(Error::io(format!("save state failed for {:?}", root_path)))?;
+        let state_json =
+            serde_json::to_string(self).map_err(Error::json("unable to serialize state"))?;
         state_file
-            .write_all(serde_json::to_string(self).unwrap().as_bytes())
-            .map_err(|_| Error {
-                msg: "cannot write to state.json file".to_string(),
-                err_type: ErrorType::Container,
-            })?;
-        Ok(())
+            .write_all(state_json.as_bytes())
+            .map_err(Error::io("cannot write to state.json file"))?;
+        std::fs::rename(&tmp_path, root_path.join(STATE_FILE))
+            .map_err(Error::io(format!("save state failed for {:?}", root_path)))
     }
 }
 
@@ -62,25 +136,124 @@ impl TryFrom<&Path> for State {
     type Error = Error;
 
     fn try_from(path: &Path) -> core::result::Result<Self, Self::Error> {
-        let state_json = std::fs::read_to_string(path.join("state.json")).map_err(|err| {
-            Error {
-                msg: format!("state file not found {} for {:?}", err, path),
-                err_type: ErrorType::Runtime,
-            }
-        })?;
-        let state: State = serde_json::from_str(&state_json).map_err(|_| Error {
-            msg: "unable to deserialize state file".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        let state_json = std::fs::read_to_string(path.join(STATE_FILE))
+            .map_err(Error::io(format!("state file not found for {:?}", path)))?;
+        let state: State = serde_json::from_str(&state_json)
+            .map_err(Error::json("unable to deserialize state file"))?;
         Ok(state)
     }
 }
 
+/// Storage of the states of the containers of a runtime root
+pub trait Store {
+    fn save(&self, state: &State) -> Result<()>;
+    /// State of the container, None without a container of the id
+    fn load(&self, id: &str) -> Result<Option<State>>;
+    fn remove(&self, id: &str) -> Result<()>;
+    /// States of all the containers, sorted by id
+    fn list(&self) -> Result<Vec<State>>;
+}
+
+/// The `state.json` file in the directory of each container
+pub struct JsonStore {
+    root: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new(root: &Path) -> JsonStore {
+        JsonStore {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+impl Store for JsonStore {
+    fn save(&self, state: &State) -> Result<()> {
+        state.save(&self.root.join(&state.id))
+    }
+
+    fn load(&self, id: &str) -> Result<Option<State>> {
+        // Read right away rather than looked up first, every command loads a state
+        match State::try_from(self.root.join(id).as_path()) {
+            Err(Error::Io { source, .. })
+                if source.kind() == std::io::ErrorKind::NotFound
+                    || source.raw_os_error() == Some(nix::libc::ENOTDIR) =>
+            {
+                Ok(None)
+            }
+            result => result.map(Some),
+        }
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        match std::fs::remove_file(self.root.join(id).join(STATE_FILE)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::io(format!("unable to remove the state of {}", id))(err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<State>> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            // No container created yet
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::io(format!("unable to list {:?}", self.root))(err)),
+        };
+        let mut ids: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        ids.sort();
+
+        let mut states = Vec::new();
+        for id in ids {
+            match self.load(&id) {
+                Ok(Some(state)) => states.push(state),
+                Ok(None) => (),
+                // Deleted meanwhile
+                Err(err) => debug!("skipping container {}: {}", id, err),
+            }
+        }
+        Ok(states)
+    }
+}
+
+/// Store of the runtime root: the SQLite database once the root has one, or
+/// when `PURA_STATE_BACKEND=sqlite` asks for it, the state files otherwise
+pub fn open(root: &Path) -> Result<Box<dyn Store>> {
+    if root.join(STATE_DB).exists() {
+        return open_sqlite(root);
+    }
+    match std::env::var(STATE_BACKEND_ENV).as_deref() {
+        Ok("sqlite") => open_sqlite(root),
+        Ok("json") | Ok("") | Err(_) => Ok(Box::new(JsonStore::new(root))),
+        Ok(backend) => Err(Error::Runtime(format!(
+            "unknown state backend {:?} in {}, json or sqlite",
+            backend, STATE_BACKEND_ENV
+        ))),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(root: &Path) -> Result<Box<dyn Store>> {
+    Ok(Box::new(SqliteStore::open(root)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(root: &Path) -> Result<Box<dyn Store>> {
+    Err(Error::Runtime(format!(
+        "{:?} uses the sqlite state backend, pura is built without the sqlite feature",
+        root
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use std::{io::Read, path::Path};
 
-    use crate::core::state::State;
+    use crate::core::state::{validate_id, JsonStore, State, Status, Store};
 
     #[test]
     fn serialize() {
@@ -110,4 +283,56 @@ mod tests {
 
         std::fs::remove_file("./state.json").unwrap();
     }
+
+    #[test]
+    fn json_store() {
+        let root = Path::new("./json_store_test");
+        let store = JsonStore::new(root);
+        assert!(store.list().unwrap().is_empty());
+
+        let mut state = State::new("b", 23, "/containers");
+        store.save(&state).unwrap();
+        store.save(&State::new("a", 24, "/containers")).unwrap();
+        state.status = Status::Running;
+        store.save(&state).unwrap();
+        // Not a container
+        std::fs::create_dir_all(root.join("c")).unwrap();
+
+        assert_eq!(store.load("b").unwrap(), Some(state));
+        assert_eq!(store.load("c").unwrap(), None);
+        let ids: Vec<String> = store.list().unwrap().into_iter().map(|s| s.id).collect();
+        assert_eq!(ids, ["a", "b"]);
+
+        store.remove("a").unwrap();
+        store.remove("a").unwrap();
+        assert_eq!(store.load("a").unwrap(), None);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn container_ids() {
+        for id in ["123456", "web-1", "app_2.v+3", ".hidden"] {
+            assert!(validate_id(id).is_ok(), "{}", id);
+        }
+        for id in ["", ".", "..", "../escape", "/tmp/abs", "a/b", "tab\tid", "naïve"] {
+            assert!(validate_id(id).is_err(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn reserved_ids() {
+        assert!(validate_id("volumes").is_err());
+        assert!(validate_id("pods").is_err());
+        assert!(validate_id("seccomp").is_err());
+        assert!(validate_id("locks").is_err());
+        assert!(validate_id("audit.log").is_err());
+        assert!(validate_id("state.db").is_err());
+        assert!(validate_id("state.db-wal").is_err());
+        // The recording of the container web
+        assert!(validate_id("web.cast").is_err());
+        for id in ["volumes-1", "pods.v2", "audit", "web.cast.1", "cast"] {
+            assert!(validate_id(id).is_ok(), "{}", id);
+        }
+    }
 }
@@ -1,23 +1,47 @@
 use std::{
-    os::unix::prelude::{AsRawFd, RawFd},
+    os::unix::prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     path::Path,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Once, OnceLock,
+    },
 };
 
 use nix::{
-    fcntl::{open, OFlag},
+    fcntl::{self, open, OFlag, SpliceFFlags},
     pty::{grantpt, posix_openpt, ptsname_r, unlockpt},
     sys::{
+        stat::{fstat, major, minor},
         socket::{
             self, connect, sendmsg, AddressFamily, ControlMessage, MsgFlags, SockAddr, SockFlag,
             SockType, UnixAddr,
         },
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
         stat::Mode,
+        termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios},
         uio::IoVec,
     },
-    unistd::{close, dup2, setsid},
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    libc,
+    unistd::{close, dup2, fchown, pipe2, read, setsid, write, Gid, Uid},
 };
 
-use crate::core::common::{Error, ErrorType, Result};
+#[cfg(feature = "uring")]
+use crate::core::uring::{self, Ring};
+use crate::core::common::{Error, Result};
+
+const BUF_SIZE: usize = 64 * 1024;
+/// Bytes moved by a single splice(2) call
+const SPLICE_LEN: usize = 64 * 1024;
+/// Signals ending the runtime, the terminal is restored before they do
+const FATAL: [Signal; 4] = [Signal::SIGHUP, Signal::SIGINT, Signal::SIGQUIT, Signal::SIGTERM];
+
+/// Terminal in raw mode, -1 without one, and its attributes before. For the
+/// signal handlers, the panic hook and `exit`, where `RawMode` isn't dropped.
+static RAW_FD: AtomicI32 = AtomicI32::new(-1);
+static COOKED: OnceLock<libc::termios> = OnceLock::new();
+static PANIC_HOOK: Once = Once::new();
 
 pub struct Pty {
     master: nix::pty::PtyMaster,
@@ -26,87 +50,98 @@ pub struct Pty {
 
 impl Pty {
     pub fn new() -> Result<Pty> {
-        let master = posix_openpt(OFlag::O_RDWR).map_err(|_| Error {
-            msg: "failed to open new terminal".to_string(),
-            err_type: ErrorType::Container,
-        })?;
+        let master =
+            posix_openpt(OFlag::O_RDWR).map_err(Error::sys("failed to open new terminal"))?;
 
-        grantpt(&master).map_err(|_| Error {
-            msg: "failed to grantpt".to_string(),
-            err_type: ErrorType::Container,
-        })?;
+        grantpt(&master).map_err(Error::sys("failed to grantpt"))?;
 
-        unlockpt(&master).map_err(|_| Error {
-            msg: "failed to unlock".to_string(),
-            err_type: ErrorType::Container,
-        })?;
+        unlockpt(&master).map_err(Error::sys("failed to unlock"))?;
 
         // Get the name of the slave
-        let slave_name = ptsname_r(&master).map_err(|_| Error {
-            msg: "failed to get slave pty".to_string(),
-            err_type: ErrorType::Container,
-        })?;
+        let slave_name = ptsname_r(&master).map_err(Error::sys("failed to get slave pty"))?;
 
         Ok(Pty {
-            master: master,
+            master,
             slave_name,
         })
     }
 
+    /// Path of the slave on the devpts of the runtime
+    pub fn slave_name(&self) -> &str {
+        &self.slave_name
+    }
+
     pub fn connect(&self) -> Result<()> {
-        setsid().map_err(|_| Error {
-            msg: "failed to set session".to_string(),
-            err_type: ErrorType::Container,
-        })?;
-
-        let slave_fd =
-            open(Path::new(&self.slave_name), OFlag::O_RDWR, Mode::empty()).map_err(|_| Error {
-                msg: "failed to open slave pty".to_string(),
-                err_type: ErrorType::Container,
-            })?;
-
-        dup2(slave_fd.as_raw_fd(), 0).map_err(|_| Error {
-            msg: "error dup2 stdin".to_string(),
-            err_type: ErrorType::Container,
-        })?;
-        dup2(slave_fd.as_raw_fd(), 1).map_err(|_| Error {
-            msg: "error dup2 stdout".to_string(),
-            err_type: ErrorType::Container,
-        })?;
-        dup2(slave_fd.as_raw_fd(), 2).map_err(|_| Error {
-            msg: "error dup2 stderr".to_string(),
-            err_type: ErrorType::Container,
-        })?;
+        setsid().map_err(Error::sys("failed to set session"))?;
+
+        let slave_fd = open(Path::new(&self.slave_name), OFlag::O_RDWR, Mode::empty())
+            .map_err(Error::sys("failed to open slave pty"))?;
+
+        dup2(slave_fd.as_raw_fd(), 0).map_err(Error::sys("error dup2 stdin"))?;
+        dup2(slave_fd.as_raw_fd(), 1).map_err(Error::sys("error dup2 stdout"))?;
+        dup2(slave_fd.as_raw_fd(), 2).map_err(Error::sys("error dup2 stderr"))?;
 
         Ok(())
     }
 }
 
+/// Gives the stdio of the process to the user of the container, before the
+/// privileges are dropped, so a process not running as root can still open
+/// its pipes or terminal through /proc/self/fd like runc. Only pipes and
+/// sockets are chowned, and the terminal when the runtime allocated it: the
+/// terminal or the files of the caller stay theirs. The fds the runtime
+/// can't chown, like in a user namespace, are left alone.
+pub fn chown_stdio(uid: Uid, gid: Gid, terminal: bool) -> Result<()> {
+    chown_fds(&[0, 1, 2], uid, gid, terminal)
+}
+
+fn chown_fds(fds: &[RawFd], uid: Uid, gid: Gid, terminal: bool) -> Result<()> {
+    for fd in fds.iter().copied() {
+        let stat = match fstat(fd) {
+            Ok(stat) => stat,
+            // Closed stdio
+            Err(nix::Error::Sys(Errno::EBADF)) => continue,
+            Err(err) => return Err(Error::sys(format!("unable to stat fd {}", fd))(err)),
+        };
+        let allocated = match stat.st_mode & libc::S_IFMT {
+            libc::S_IFIFO | libc::S_IFSOCK => true,
+            // The pty slave, not /dev/null
+            libc::S_IFCHR => terminal && !(major(stat.st_rdev) == 1 && minor(stat.st_rdev) == 3),
+            _ => false,
+        };
+        if !allocated || stat.st_uid == uid.as_raw() {
+            continue;
+        }
+        match fchown(fd, Some(uid), Some(gid)) {
+            Ok(_)
+            | Err(nix::Error::Sys(Errno::EPERM))
+            | Err(nix::Error::Sys(Errno::EINVAL))
+            | Err(nix::Error::Sys(Errno::EROFS)) => (),
+            Err(err) => return Err(Error::sys(format!("unable to chown fd {}", fd))(err)),
+        }
+    }
+    Ok(())
+}
+
 pub struct PtySocket {
     pub socket_fd: RawFd,
 }
 
 impl PtySocket {
-    pub fn new(console_socket_path: &String) -> Result<PtySocket> {
+    pub fn new(console_socket_path: &str) -> Result<PtySocket> {
         let socket_fd = socket::socket(
             AddressFamily::Unix,
             SockType::Stream,
             SockFlag::empty(),
             None,
         )
-        .map_err(|_| Error {
-            msg: "".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        .map_err(Error::sys("unable to create console socket"))?;
 
         connect(
             socket_fd,
-            &SockAddr::Unix(UnixAddr::new(console_socket_path.as_str()).unwrap()),
+            &SockAddr::Unix(UnixAddr::new(console_socket_path).unwrap()),
         )
-        .map_err(|err| Error {
-            msg: format!("error connecting pty {}", err),
-            err_type: ErrorType::Runtime,
-        })?;
+        .map_err(Error::sys("error connecting pty"))?;
 
         Ok(PtySocket {
             socket_fd: socket_fd.as_raw_fd(),
@@ -114,10 +149,7 @@ impl PtySocket {
     }
 
     pub fn close(&self) -> Result<()> {
-        close(self.socket_fd).map_err(|_| Error {
-            msg: "error closing console-socket".to_string(),
-            err_type: ErrorType::Runtime,
-        })?;
+        close(self.socket_fd).map_err(Error::sys("error closing console-socket"))?;
 
         Ok(())
     }
@@ -129,11 +161,348 @@ impl PtySocket {
         let iov = [IoVec::from_slice(&master_fd_msg)];
         let cmsg = [ControlMessage::ScmRights(&master_fds)];
 
-        sendmsg(self.socket_fd, &iov, &cmsg, MsgFlags::empty(), None).map_err(|_| Error {
-            msg: "failed sending pty fd to socket".to_string(),
-            err_type: ErrorType::Container,
-        })?;
+        sendmsg(self.socket_fd, &iov, &cmsg, MsgFlags::empty(), None)
+            .map_err(Error::sys("failed sending pty fd to socket"))?;
+
+        Ok(())
+    }
+}
+
+/// Raw mode of a terminal, restored on drop.
+/// Keys like Ctrl-C go through to the process behind the terminal.
+///
+/// The terminal is also restored when the runtime panics, exits or is killed
+/// by SIGHUP, SIGINT, SIGQUIT or SIGTERM meanwhile, so it's never left raw.
+pub struct RawMode {
+    fd: RawFd,
+    previous: Termios,
+    handlers: Vec<(Signal, SigAction)>,
+}
+
+impl RawMode {
+    pub fn enable(fd: RawFd) -> Result<RawMode> {
+        let previous = tcgetattr(fd).map_err(Error::sys("unable to get terminal attributes"))?;
+        let mut mode = RawMode {
+            fd,
+            previous: previous.clone(),
+            handlers: Vec::new(),
+        };
+        // The attributes of the user, a nested raw mode doesn't replace them
+        COOKED.get_or_init(|| previous.clone().into());
+        RAW_FD.store(fd, Ordering::SeqCst);
+        PANIC_HOOK.call_once(|| {
+            let default_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore();
+                default_hook(info);
+            }));
+        });
+        let action = SigAction::new(
+            SigHandler::Handler(restore_and_raise),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        for signal in FATAL.iter() {
+            let previous = unsafe { sigaction(*signal, &action) }
+                .map_err(Error::sys(format!("unable to catch {}", signal)))?;
+            mode.handlers.push((*signal, previous));
+        }
+
+        let mut raw = previous;
+        cfmakeraw(&mut raw);
+        tcsetattr(fd, SetArg::TCSANOW, &raw).map_err(Error::sys("unable to set raw mode"))?;
+        Ok(mode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, SetArg::TCSANOW, &self.previous);
+        RAW_FD.store(-1, Ordering::SeqCst);
+        for (signal, previous) in self.handlers.iter() {
+            let _ = unsafe { sigaction(*signal, previous) };
+        }
+    }
+}
+
+/// Restores the terminal left in raw mode, if any. Async-signal-safe.
+pub fn restore() {
+    let fd = RAW_FD.swap(-1, Ordering::SeqCst);
+    if let (true, Some(cooked)) = (fd >= 0, COOKED.get()) {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, cooked) };
+    }
+}
+
+/// Restores the terminal, then dies of the signal as without the handler
+extern "C" fn restore_and_raise(signal: libc::c_int) {
+    restore();
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        // Delivered once the handler returns, the signal being blocked in it
+        libc::raise(signal);
+    }
+}
+
+/// Rows and columns of the terminal
+pub fn window_size(fd: RawFd) -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) };
+    if res < 0 {
+        return None;
+    }
+    Some((size.ws_row, size.ws_col))
+}
+
+pub fn set_window_size(fd: RawFd, rows: u16, cols: u16) -> Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let res = unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &size) };
+    Errno::result(res)
+        .map(drop)
+        .map_err(Error::sys("unable to set the window size"))
+}
+
+/// Copies the console data from `from` to `to` until the end of `from` or the
+/// hangup of the pty slave, returning the number of bytes copied.
+///
+/// The data moves through a pipe with splice(2), it's never copied into
+/// userspace buffers. Kernels that can't splice from a tty fall back to reads
+/// and writes. Built with the `uring` feature, the copy goes through io_uring
+/// instead when the kernel supports it.
+pub fn proxy(from: RawFd, to: RawFd) -> Result<u64> {
+    #[cfg(feature = "uring")]
+    if uring::supported() {
+        return Ring::new()?.copy(from, to);
+    }
+
+    let splice = Splice::new()?;
+    let mut total = 0;
+    loop {
+        let num = match splice.fill(from, true) {
+            Ok(num) => num,
+            Err(nix::Error::Sys(Errno::EINVAL)) if total == 0 => return copy(from, to),
+            Err(err) => return Err(Error::sys("unable to splice console data")(err)),
+        };
+        if num == 0 {
+            return Ok(total);
+        }
+        splice.drain(to, num, true)?;
+        total += num as u64;
+    }
+}
+
+/// Copy through a userspace buffer, for the fds that can't be spliced from
+fn copy(from: RawFd, to: RawFd) -> Result<u64> {
+    let mut buf = vec![0; BUF_SIZE];
+    let mut total = 0;
+    loop {
+        let num = read_ready(from, &mut buf)?;
+        if num == 0 {
+            return Ok(total);
+        }
+        write_all_ready(to, &buf[..num])?;
+        total += num as u64;
+    }
+}
+
+/// Pipe moving console data from one fd to another with splice(2)
+pub(crate) struct Splice {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Splice {
+    pub(crate) fn new() -> Result<Splice> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+            .map_err(Error::sys("unable to create splice pipe"))?;
+        Ok(unsafe {
+            Splice {
+                read: OwnedFd::from_raw_fd(read),
+                write: OwnedFd::from_raw_fd(write),
+            }
+        })
+    }
 
+    /// Moves the data of `from` into the pipe, returning 0 at the end of
+    /// `from` or the hangup of the pty slave. Without waiting, a nonblocking
+    /// `from` without data fails with EAGAIN. Fails with EINVAL when `from`
+    /// can't be spliced from.
+    pub(crate) fn fill(&self, from: RawFd, wait: bool) -> nix::Result<usize> {
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+        loop {
+            match fcntl::splice(from, None, self.write.as_raw_fd(), None, SPLICE_LEN, flags) {
+                Err(nix::Error::Sys(Errno::EIO)) => return Ok(0),
+                Err(nix::Error::Sys(Errno::EAGAIN)) if wait => {
+                    poll(&mut [PollFd::new(from, PollFlags::POLLIN)], -1)?;
+                }
+                Err(nix::Error::Sys(Errno::EINTR)) => (),
+                result => return result,
+            }
+        }
+    }
+
+    /// Moves `len` bytes of the pipe to `to`. Without waiting, a nonblocking
+    /// `to` that is full fails with EAGAIN, the rest of the data is left in
+    /// the pipe. The fds that can't be spliced to, like O_APPEND files, are
+    /// written instead.
+    pub(crate) fn drain(&self, to: RawFd, mut len: usize, wait: bool) -> Result<()> {
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+        while len > 0 {
+            match fcntl::splice(self.read.as_raw_fd(), None, to, None, len, flags) {
+                Ok(num) => len -= num,
+                Err(nix::Error::Sys(Errno::EAGAIN)) if wait => wait_ready(to, PollFlags::POLLOUT)?,
+                Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(nix::Error::Sys(Errno::EINVAL)) => {
+                    let mut buf = vec![0; len];
+                    let num = read_ready(self.read.as_raw_fd(), &mut buf)?;
+                    write_all_ready(to, &buf[..num])?;
+                    len -= num;
+                }
+                Err(err) => return Err(Error::sys("unable to splice console data")(err)),
+            }
+        }
         Ok(())
     }
+
+    /// Drops the data left in the pipe
+    pub(crate) fn discard(&self) {
+        let mut buf = vec![0; BUF_SIZE];
+        while let Ok(1..) = read(self.read.as_raw_fd(), &mut buf) {}
+    }
+}
+
+/// Blocking read, also of nonblocking fds like the pty master.
+/// The hangup of the pty slave reads as the end of the data.
+pub(crate) fn read_ready(fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+    loop {
+        match read(fd, buf) {
+            Ok(num) => return Ok(num),
+            Err(nix::Error::Sys(Errno::EIO)) => return Ok(0),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => wait_ready(fd, PollFlags::POLLIN)?,
+            Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(err) => return Err(Error::sys("unable to read console data")(err)),
+        }
+    }
+}
+
+fn write_ready(fd: RawFd, buf: &[u8]) -> Result<usize> {
+    loop {
+        match write(fd, buf) {
+            Ok(num) => return Ok(num),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => wait_ready(fd, PollFlags::POLLOUT)?,
+            Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(err) => return Err(Error::sys("unable to write console data")(err)),
+        }
+    }
+}
+
+fn write_all_ready(fd: RawFd, buf: &[u8]) -> Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        written += write_ready(fd, &buf[written..])?;
+    }
+    Ok(())
+}
+
+fn wait_ready(fd: RawFd, events: PollFlags) -> Result<()> {
+    poll(&mut [PollFd::new(fd, events)], -1).map_err(Error::sys("unable to poll console"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use nix::{
+        fcntl::{open, OFlag},
+        sys::{
+            signal::{raise, Signal},
+            stat::{fstat, Mode},
+            termios::{tcgetattr, LocalFlags},
+            wait::{waitpid, WaitStatus},
+        },
+        unistd::{close, fork, geteuid, pipe, write, ForkResult, Gid, Uid},
+    };
+
+    use super::{chown_fds, proxy, Pty, RawMode};
+
+    #[test]
+    fn chown_pipes() {
+        if !geteuid().is_root() {
+            return;
+        }
+        let (read_fd, write_fd) = pipe().unwrap();
+        let dev_null = open("/dev/null", OFlag::O_RDWR, Mode::empty()).unwrap();
+        let file = open("/proc/self/stat", OFlag::O_RDONLY, Mode::empty()).unwrap();
+        let fds = [read_fd, write_fd, dev_null.as_raw_fd(), file];
+        chown_fds(&fds, Uid::from_raw(1000), Gid::from_raw(1000), true).unwrap();
+
+        assert_eq!(fstat(write_fd).unwrap().st_uid, 1000);
+        assert_eq!(fstat(dev_null).unwrap().st_uid, 0);
+        assert_eq!(fstat(file).unwrap().st_uid, 0);
+        for fd in fds {
+            close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn raw_mode_restored() {
+        let pty = Pty::new().unwrap();
+        let slave = open(pty.slave_name(), OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty()).unwrap();
+        let canonical = || tcgetattr(slave).unwrap().local_flags.contains(LocalFlags::ICANON);
+        assert!(canonical());
+
+        let raw_mode = RawMode::enable(slave).unwrap();
+        assert!(!canonical());
+        drop(raw_mode);
+        assert!(canonical());
+
+        // Killed in raw mode, the terminal is restored before dying of the signal
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let _raw_mode = RawMode::enable(slave);
+                let _ = raise(Signal::SIGTERM);
+                unsafe { nix::libc::_exit(0) }
+            }
+            ForkResult::Parent { child } => {
+                let status = waitpid(child, None).unwrap();
+                assert_eq!(status, WaitStatus::Signaled(child, Signal::SIGTERM, false));
+            }
+        }
+        assert!(canonical());
+        close(slave).unwrap();
+    }
+
+    #[test]
+    fn proxy_to_files() {
+        let data = vec![b'x'; 100_000];
+        // Spliced to the file, and written to the O_APPEND one
+        let files = [("./terminal_proxy.log", false), ("./terminal_proxy_append.log", true)];
+        for (path, append) in files {
+            let _ = std::fs::remove_file(path);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .open(path)
+                .unwrap();
+            let (pipe_out, pipe_in) = pipe().unwrap();
+            let writer_data = data.clone();
+            let writer = std::thread::spawn(move || {
+                for chunk in writer_data.chunks(30_000) {
+                    write(pipe_in, chunk).unwrap();
+                }
+                close(pipe_in).unwrap();
+            });
+            assert_eq!(proxy(pipe_out, file.as_raw_fd()).unwrap(), 100_000);
+            writer.join().unwrap();
+            close(pipe_out).unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), data);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
 }
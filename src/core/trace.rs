@@ -0,0 +1,369 @@
+use log::debug;
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{
+        ptrace::{self, Options},
+        signal::Signal,
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{LineWriter, Write},
+    mem,
+    os::unix::fs::FileExt,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use crate::core::common::{Error, Result};
+
+/// Longest string argument written to the trace
+const MAX_STRING: usize = 128;
+
+/// Name and number of arguments of the syscall, for the names of libc
+macro_rules! syscalls {
+    ($nr:expr, $($syscall:ident / $args:literal),* $(,)?) => {
+        $(
+            if $nr == libc::$syscall {
+                return Some((&stringify!($syscall)[4..], $args));
+            }
+        )*
+    };
+}
+
+fn syscall(nr: libc::c_long) -> Option<(&'static str, usize)> {
+    syscalls!(
+        nr,
+        SYS_read / 3, SYS_write / 3, SYS_openat / 4, SYS_close / 1, SYS_lseek / 3,
+        SYS_pread64 / 4, SYS_pwrite64 / 4, SYS_readv / 3, SYS_writev / 3,
+        SYS_execve / 3, SYS_execveat / 5, SYS_exit / 1, SYS_exit_group / 1,
+        SYS_mmap / 6, SYS_mprotect / 3, SYS_munmap / 2, SYS_brk / 1,
+        SYS_clone / 5, SYS_clone3 / 2, SYS_wait4 / 4, SYS_kill / 2,
+        SYS_getpid / 0, SYS_getppid / 0, SYS_gettid / 0,
+        SYS_getuid / 0, SYS_geteuid / 0, SYS_getgid / 0, SYS_getegid / 0,
+        SYS_setuid / 1, SYS_setgid / 1, SYS_setgroups / 2, SYS_capget / 2, SYS_capset / 2,
+        SYS_chdir / 1, SYS_fchdir / 1, SYS_getcwd / 2, SYS_umask / 1,
+        SYS_faccessat / 3, SYS_faccessat2 / 4, SYS_newfstatat / 4, SYS_statx / 5,
+        SYS_readlinkat / 4, SYS_mkdirat / 3, SYS_unlinkat / 3, SYS_getdents64 / 3,
+        SYS_ioctl / 3, SYS_fcntl / 3, SYS_dup / 1, SYS_dup3 / 3, SYS_pipe2 / 2,
+        SYS_socket / 3, SYS_connect / 3, SYS_bind / 3, SYS_listen / 2, SYS_accept / 3,
+        SYS_accept4 / 4, SYS_close_range / 3,
+        SYS_sendto / 6, SYS_recvfrom / 6, SYS_sendmsg / 3, SYS_recvmsg / 3,
+        SYS_rt_sigaction / 4, SYS_rt_sigprocmask / 4, SYS_rt_sigreturn / 0,
+        SYS_set_tid_address / 1, SYS_set_robust_list / 2, SYS_rseq / 4,
+        SYS_prlimit64 / 4, SYS_getrandom / 3, SYS_futex / 6, SYS_uname / 1,
+        SYS_nanosleep / 2, SYS_clock_nanosleep / 4, SYS_ppoll / 5, SYS_pselect6 / 6,
+        SYS_epoll_pwait / 6, SYS_prctl / 5, SYS_setns / 2, SYS_unshare / 1,
+        SYS_mount / 5, SYS_umount2 / 2, SYS_pivot_root / 2, SYS_sethostname / 2,
+    );
+    #[cfg(target_arch = "x86_64")]
+    syscalls!(
+        nr,
+        SYS_open / 3, SYS_stat / 2, SYS_lstat / 2, SYS_fstat / 2, SYS_access / 2,
+        SYS_readlink / 3, SYS_arch_prctl / 2, SYS_fork / 0, SYS_vfork / 0,
+        SYS_dup2 / 2, SYS_pipe / 1, SYS_poll / 3, SYS_mkdir / 2, SYS_unlink / 1,
+    );
+    None
+}
+
+/// Arguments of the syscall which are paths
+fn string_args(name: &str) -> &'static [usize] {
+    match name {
+        "open" | "stat" | "lstat" | "access" | "readlink" | "chdir" | "mkdir" | "unlink"
+        | "execve" | "umount2" => &[0],
+        "openat" | "execveat" | "faccessat" | "faccessat2" | "newfstatat" | "statx"
+        | "readlinkat" | "mkdirat" | "unlinkat" => &[1],
+        "pivot_root" => &[0, 1],
+        "mount" => &[0, 1, 2],
+        _ => &[],
+    }
+}
+
+/// Syscall tracer of the container process, recording the syscalls it makes
+/// and their results until it exits, for `run --trace-syscalls`
+pub struct Tracer {
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Tracer {
+    /// Seizes the process and traces it from a thread of its own, ptrace(2)
+    /// requests being accepted from the tracer thread only. The process keeps
+    /// being reaped by its parent.
+    pub fn attach(pid: Pid, output: &File) -> Result<Tracer> {
+        let output = output
+            .try_clone()
+            .map_err(Error::io("unable to open the syscall trace"))?;
+        let (seized_tx, seized_rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("tracer".to_string())
+            .spawn(move || {
+                let seized = seize(pid);
+                let failed = seized.is_err();
+                let _ = seized_tx.send(seized);
+                if failed {
+                    return Ok(());
+                }
+                trace(pid, output)
+            })
+            .map_err(Error::io("unable to start the tracer"))?;
+        seized_rx
+            .recv()
+            .map_err(|_| Error::Runtime("the tracer exited".to_string()))??;
+        debug!("tracing the syscalls of {}", pid);
+        Ok(Tracer { thread })
+    }
+
+    /// Waits for the end of the trace, once the process exited
+    pub fn finish(self) -> Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(Error::Runtime("the tracer panicked".to_string())))
+    }
+}
+
+fn seize(pid: Pid) -> Result<()> {
+    let options =
+        Options::PTRACE_O_TRACESYSGOOD | Options::PTRACE_O_TRACEEXEC | Options::PTRACE_O_TRACEEXIT;
+    ptrace::seize(pid, options).map_err(Error::sys(format!("unable to trace {}", pid)))?;
+    // Stops the process to start tracing its syscalls
+    let res = unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, pid.as_raw(), 0, 0) };
+    Errno::result(res)
+        .map(drop)
+        .map_err(Error::sys(format!("unable to interrupt {}", pid)))
+}
+
+/// Syscall stop of the process
+enum Stop {
+    Entry(libc::c_long, [u64; 6]),
+    Exit(i64, bool),
+    Other,
+}
+
+fn syscall_stop(pid: Pid) -> Result<Stop> {
+    let mut info: libc::ptrace_syscall_info = unsafe { mem::zeroed() };
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GET_SYSCALL_INFO,
+            pid.as_raw(),
+            mem::size_of::<libc::ptrace_syscall_info>(),
+            &mut info as *mut libc::ptrace_syscall_info,
+        )
+    };
+    Errno::result(res).map_err(Error::sys("unable to get the syscall info, it needs Linux 5.3"))?;
+    Ok(match info.op {
+        libc::PTRACE_SYSCALL_INFO_ENTRY => {
+            let entry = unsafe { info.u.entry };
+            Stop::Entry(entry.nr as libc::c_long, entry.args)
+        }
+        libc::PTRACE_SYSCALL_INFO_EXIT => {
+            let exit = unsafe { info.u.exit };
+            Stop::Exit(exit.sval, exit.is_error != 0)
+        }
+        _ => Stop::Other,
+    })
+}
+
+/// Exit of the process, without reaping it for its parent
+fn exited(pid: Pid) -> Result<Option<String>> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let res = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid.as_raw() as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WSTOPPED | libc::WNOWAIT | libc::__WALL,
+        )
+    };
+    match Errno::result(res) {
+        Ok(_) => (),
+        Err(nix::Error::Sys(Errno::EINTR)) => return Ok(None),
+        // Reaped by its parent already
+        Err(nix::Error::Sys(Errno::ECHILD)) => return Ok(Some("+++ exited +++".to_string())),
+        Err(err) => return Err(Error::sys("error waiting for the traced process")(err)),
+    }
+    let status = unsafe { info.si_status() };
+    Ok(match info.si_code {
+        libc::CLD_EXITED => Some(format!("+++ exited with {} +++", status)),
+        libc::CLD_KILLED | libc::CLD_DUMPED => Some(killed(status)),
+        _ => None,
+    })
+}
+
+fn killed(signal: i32) -> String {
+    match Signal::try_from(signal) {
+        Ok(signal) => format!("+++ killed by {} +++", signal),
+        Err(_) => format!("+++ killed by signal {} +++", signal),
+    }
+}
+
+fn trace(pid: Pid, output: File) -> Result<()> {
+    let mut output = LineWriter::new(output);
+    let mut write = |line: String| {
+        writeln!(output, "{}", line).map_err(Error::io("unable to write the syscall trace"))
+    };
+    let open_memory = || File::open(format!("/proc/{}/mem", pid)).ok();
+    let mut memory = open_memory();
+    let start = Instant::now();
+    // Syscall entered, written with its result
+    let mut entered: Option<(String, f64)> = None;
+    loop {
+        // Gone without an exit stop, like when killed by SIGKILL
+        if let Some(exit) = exited(pid)? {
+            return write(exit);
+        }
+        let status = match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+            Ok(status) => status,
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(err) => return Err(Error::sys("error waiting for the traced process")(err)),
+        };
+        let resume = match status {
+            WaitStatus::PtraceSyscall(_) => {
+                match syscall_stop(pid)? {
+                    Stop::Entry(nr, args) => {
+                        let call = format_call(memory.as_ref(), nr, &args);
+                        entered = Some((call, start.elapsed().as_secs_f64()));
+                    }
+                    Stop::Exit(value, is_error) => {
+                        if let Some((call, time)) = entered.take() {
+                            write(format!("{:10.6} {} = {}", time, call, format_result(value, is_error)))?;
+                        }
+                    }
+                    Stop::Other => (),
+                }
+                None
+            }
+            WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXIT) => {
+                if let Some((call, time)) = entered.take() {
+                    write(format!("{:10.6} {} = ?", time, call))?;
+                }
+                let status = ptrace::getevent(pid)
+                    .map_err(Error::sys("unable to get the exit status"))? as i32;
+                write(if libc::WIFSIGNALED(status) {
+                    killed(libc::WTERMSIG(status))
+                } else {
+                    format!("+++ exited with {} +++", libc::WEXITSTATUS(status))
+                })?;
+                // Exits once detached, for its parent to reap
+                return ptrace::detach(pid, None).map_err(Error::sys("unable to detach the tracer"));
+            }
+            // The memory of the new program
+            WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXEC) => {
+                memory = open_memory();
+                None
+            }
+            // Interrupt and group stops
+            WaitStatus::PtraceEvent(..) => None,
+            WaitStatus::Stopped(_, signal) => {
+                write(format!("{:10.6} --- {} ---", start.elapsed().as_secs_f64(), signal))?;
+                Some(signal)
+            }
+            // Reported by exited
+            _ => continue,
+        };
+        match ptrace::syscall(pid, resume) {
+            // Killed meanwhile
+            Ok(()) | Err(nix::Error::Sys(Errno::ESRCH)) => (),
+            Err(err) => return Err(Error::sys("unable to resume the traced process")(err)),
+        }
+    }
+}
+
+/// `name(args)`, the paths read from the memory of the process
+fn format_call(memory: Option<&File>, nr: libc::c_long, args: &[u64; 6]) -> String {
+    let (name, count) = match syscall(nr) {
+        Some((name, count)) => (name.to_string(), count),
+        None => (format!("syscall_{}", nr), args.len()),
+    };
+    let strings = string_args(&name);
+    let args: Vec<String> = args[..count]
+        .iter()
+        .enumerate()
+        .map(|(index, &arg)| match memory {
+            Some(memory) if strings.contains(&index) && arg != 0 => read_string(memory, arg),
+            _ => format_value(arg_value(arg)),
+        })
+        .collect();
+    format!("{}({})", name, args.join(", "))
+}
+
+fn format_result(value: i64, is_error: bool) -> String {
+    if !is_error {
+        return format_value(value);
+    }
+    let errno = Errno::from_i32(-value as i32);
+    format!("-1 {:?} ({})", errno, errno.desc())
+}
+
+/// The argument, the negative int arguments like AT_FDCWD sign extended
+fn arg_value(arg: u64) -> i64 {
+    match u32::try_from(arg) {
+        Ok(int) if (int as i32) < 0 && (int as i32) >= -4096 => int as i32 as i64,
+        _ => arg as i64,
+    }
+}
+
+/// Small values in decimal, addresses and flags in hexadecimal
+fn format_value(value: i64) -> String {
+    if (-4096..=0xffff).contains(&value) {
+        value.to_string()
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+/// Quoted string of the process at the address, cut after `MAX_STRING`
+fn read_string(memory: &File, address: u64) -> String {
+    let mut buffer = vec![0u8; MAX_STRING + 1];
+    let read = match memory.read_at(&mut buffer, address) {
+        Ok(read) if read > 0 => read,
+        _ => return format!("{:#x}", address),
+    };
+    buffer.truncate(read);
+    let cut = match buffer.iter().position(|&byte| byte == 0) {
+        Some(end) => {
+            buffer.truncate(end);
+            false
+        }
+        None => {
+            buffer.truncate(MAX_STRING);
+            true
+        }
+    };
+    let string = format!("{:?}", String::from_utf8_lossy(&buffer));
+    match cut {
+        true => format!("{}...", string),
+        false => string,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::libc;
+
+    use super::{format_call, format_result, syscall};
+
+    #[test]
+    fn format() {
+        assert_eq!(syscall(libc::SYS_openat), Some(("openat", 4)));
+        assert_eq!(syscall(-1), None);
+        assert_eq!(format_call(None, libc::SYS_close, &[3, 0, 0, 0, 0, 0]), "close(3)");
+        assert_eq!(
+            format_call(None, libc::SYS_mmap, &[0, 4096, 3, 0x22, u64::MAX, 0]),
+            "mmap(0, 4096, 3, 34, -1, 0)"
+        );
+        assert_eq!(
+            format_call(None, libc::SYS_openat, &[0xffff_ff9c, 0, 0x80000, 0, 0, 0]),
+            "openat(-100, 0, 0x80000, 0)"
+        );
+        assert_eq!(format_result(0x7f00_0000_0000, false), "0x7f0000000000");
+        assert_eq!(
+            format_result(-(libc::ENOENT as i64), true),
+            "-1 ENOENT (No such file or directory)"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use lazy_static::lazy_static;
+use nix::{
+    errno::Errno,
+    libc,
+    poll::{poll, PollFd, PollFlags},
+};
+use std::os::unix::io::RawFd;
+
+use crate::core::common::{Error, Result};
+
+const ENTRIES: u32 = 8;
+const BUF_SIZE: usize = 64 * 1024;
+/// Offset of the reads and writes using (and advancing) the file position
+const FILE_POSITION: u64 = u64::MAX;
+
+lazy_static! {
+    /// io_uring may be missing (before 5.6 for plain reads and writes)
+    /// or disabled with the kernel.io_uring_disabled sysctl
+    static ref SUPPORTED: bool = probe().unwrap_or(false);
+}
+
+fn probe() -> std::io::Result<bool> {
+    let ring = IoUring::new(ENTRIES)?;
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe)?;
+    Ok([opcode::Read::CODE, opcode::Write::CODE, opcode::PollAdd::CODE]
+        .iter()
+        .all(|code| probe.is_supported(*code)))
+}
+
+/// Whether the io_uring backend can be used on the running kernel
+pub fn supported() -> bool {
+    *SUPPORTED
+}
+
+/// Ring copying the console and log data.
+///
+/// Every read and write is linked behind a poll of its fd, so nonblocking
+/// fds like the pty master wait for readiness in the kernel instead of failing
+/// with EAGAIN. A copy submits the write of a chunk with the read of the next
+/// one, a single io_uring_enter(2) per chunk.
+pub struct Ring {
+    ring: IoUring,
+    bufs: [Vec<u8>; 2],
+}
+
+impl Ring {
+    pub fn new() -> Result<Ring> {
+        let ring = IoUring::new(ENTRIES).map_err(Error::io("unable to set up io_uring"))?;
+        Ok(Ring {
+            ring,
+            bufs: [vec![0; BUF_SIZE], vec![0; BUF_SIZE]],
+        })
+    }
+
+    /// Submits the entries and waits for all of them, returning their results
+    /// in submission order
+    fn run(&mut self, entries: &[squeue::Entry]) -> Result<Vec<i32>> {
+        for entry in entries {
+            // The buffers of the entries live in self and the caller, both
+            // outliving the wait below
+            unsafe { self.ring.submission().push(entry) }
+                .map_err(|_| Error::Runtime("io_uring submission queue full".to_string()))?;
+        }
+
+        let mut results = vec![0; entries.len()];
+        let mut completed = 0;
+        while completed < entries.len() {
+            match self.ring.submit_and_wait(entries.len() - completed) {
+                Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+                result => result.map_err(Error::io("io_uring_enter failed"))?,
+            };
+            for cqe in self.ring.completion() {
+                results[cqe.user_data() as usize] = cqe.result();
+                completed += 1;
+            }
+        }
+        Ok(results)
+    }
+
+    fn read_entries(fd: RawFd, buf: &mut [u8], index: u64) -> [squeue::Entry; 2] {
+        [
+            opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as u32)
+                .build()
+                .flags(squeue::Flags::IO_LINK)
+                .user_data(index),
+            opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32)
+                .offset(FILE_POSITION)
+                .build()
+                .user_data(index + 1),
+        ]
+    }
+
+    fn write_entries(fd: RawFd, buf: &[u8], index: u64) -> [squeue::Entry; 2] {
+        [
+            opcode::PollAdd::new(types::Fd(fd), libc::POLLOUT as u32)
+                .build()
+                .flags(squeue::Flags::IO_LINK)
+                .user_data(index),
+            opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+                .offset(FILE_POSITION)
+                .build()
+                .user_data(index + 1),
+        ]
+    }
+
+    /// Reads into the buffer once the fd is readable, returning 0 at the end
+    /// of the fd or the hangup of a pty slave
+    pub fn read(&mut self, fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+        match self.run(&Ring::read_entries(fd, buf, 0))?[1] {
+            num if num == -libc::EIO => Ok(0),
+            num => check(num, "read"),
+        }
+    }
+
+    /// Reads the data available in the fd without waiting for it, a fd
+    /// without data fails with EAGAIN like a nonblocking read(2)
+    pub fn try_read(&mut self, fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+        // io_uring waits for the data of nonblocking fds too
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        if poll(&mut fds, 0).map_err(Error::sys("unable to poll"))? == 0 {
+            return Err(Error::sys("io_uring read failed")(nix::Error::Sys(Errno::EAGAIN)));
+        }
+        self.read(fd, buf)
+    }
+
+    /// Writes the whole buffer to the fd
+    pub fn write_all(&mut self, fd: RawFd, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let written = self.run(&Ring::write_entries(fd, buf, 0))?[1];
+            buf = &buf[check(written, "write")?..];
+        }
+        Ok(())
+    }
+
+    /// Copies `from` to `to` until the end of `from`, returning the number of
+    /// bytes copied. The hangup of a pty slave ends the copy like an EOF.
+    pub fn copy(&mut self, from: RawFd, to: RawFd) -> Result<u64> {
+        let mut bufs = std::mem::take(&mut self.bufs);
+        let result = self.copy_with(from, to, &mut bufs);
+        self.bufs = bufs;
+        result
+    }
+
+    fn copy_with(&mut self, from: RawFd, to: RawFd, bufs: &mut [Vec<u8>; 2]) -> Result<u64> {
+        let [first, second] = bufs;
+        let (mut reading, mut writing) = (first, second);
+
+        let mut read = self.run(&Ring::read_entries(from, reading, 0))?[1];
+        let mut total = 0;
+        loop {
+            let num = match read {
+                num if num == -libc::EIO => return Ok(total),
+                num => check(num, "read")?,
+            };
+            if num == 0 {
+                return Ok(total);
+            }
+            std::mem::swap(&mut reading, &mut writing);
+
+            let [poll_out, write] = Ring::write_entries(to, &writing[..num], 0);
+            let [poll_in, next_read] = Ring::read_entries(from, reading, 2);
+            let results = self.run(&[poll_out, write, poll_in, next_read])?;
+            // The polls are the even entries
+            let written = check(results[1], "write")?;
+            // The rest of a short write goes out on its own
+            self.write_all(to, &writing[written..num])?;
+
+            total += num as u64;
+            read = results[3];
+        }
+    }
+}
+
+fn check(result: i32, op: &str) -> Result<usize> {
+    if result < 0 {
+        return Err(Error::sys(format!("io_uring {} failed", op))(nix::Error::Sys(
+            Errno::from_i32(-result),
+        )));
+    }
+    Ok(result as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        errno::Errno,
+        fcntl::OFlag,
+        unistd::{close, pipe, pipe2, write},
+    };
+    use std::{fs::File, os::unix::io::AsRawFd};
+
+    use super::{supported, Ring};
+    use crate::core::common::Error;
+
+    #[test]
+    fn copy_pipe_to_file() {
+        if !supported() {
+            return;
+        }
+        let (pipe_out, pipe_in) = pipe().unwrap();
+        let data = vec![b'x'; 100_000];
+        let writer_data = data.clone();
+        let writer = std::thread::spawn(move || {
+            for chunk in writer_data.chunks(30_000) {
+                write(pipe_in, chunk).unwrap();
+            }
+            close(pipe_in).unwrap();
+        });
+
+        let path = "./uring_copy.log";
+        let file = File::create(path).unwrap();
+        let mut ring = Ring::new().unwrap();
+        assert_eq!(ring.copy(pipe_out, file.as_raw_fd()).unwrap(), 100_000);
+        writer.join().unwrap();
+        close(pipe_out).unwrap();
+
+        ring.write_all(file.as_raw_fd(), b"end").unwrap();
+        let copied = std::fs::read(path).unwrap();
+        assert_eq!(copied[..100_000], data[..]);
+        assert_eq!(&copied[100_000..], b"end");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn try_read_nonblocking() {
+        if !supported() {
+            return;
+        }
+        let (pipe_out, pipe_in) = pipe2(OFlag::O_NONBLOCK).unwrap();
+        let mut ring = Ring::new().unwrap();
+        let mut buf = [0; 16];
+        match ring.try_read(pipe_out, &mut buf) {
+            Err(Error::Sys { source, .. }) => assert_eq!(source.as_errno(), Some(Errno::EAGAIN)),
+            result => panic!("unexpected read {:?}", result),
+        }
+        write(pipe_in, b"data").unwrap();
+        assert_eq!(ring.try_read(pipe_out, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"data");
+        close(pipe_in).unwrap();
+        assert_eq!(ring.try_read(pipe_out, &mut buf).unwrap(), 0);
+        close(pipe_out).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+use nix::{
+    errno::Errno,
+    unistd::{setgroups, Gid},
+};
+use std::path::Path;
+
+use crate::core::common::{Error, Result};
+use crate::oci::spec::User;
+
+/// Entry of /etc/passwd
+#[derive(Debug, PartialEq)]
+struct Passwd {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+/// Entry of /etc/group
+#[derive(Debug, PartialEq)]
+struct Group {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+/// Entries of `etc/passwd` of the rootfs, none when it's missing
+fn passwd(rootfs: &Path) -> Vec<Passwd> {
+    entries(rootfs, "etc/passwd")
+        .into_iter()
+        .filter_map(|fields| {
+            Some(Passwd {
+                name: fields.first()?.to_string(),
+                uid: fields.get(2)?.parse().ok()?,
+                gid: fields.get(3)?.parse().ok()?,
+                home: fields.get(5)?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Entries of `etc/group` of the rootfs, none when it's missing
+fn groups(rootfs: &Path) -> Vec<Group> {
+    entries(rootfs, "etc/group")
+        .into_iter()
+        .filter_map(|fields| {
+            Some(Group {
+                name: fields.first()?.to_string(),
+                gid: fields.get(2)?.parse().ok()?,
+                members: fields
+                    .get(3)
+                    .map(|members| {
+                        members
+                            .split(',')
+                            .filter(|member| !member.is_empty())
+                            .map(String::from)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn entries(rootfs: &Path, file: &str) -> Vec<Vec<String>> {
+    std::fs::read_to_string(rootfs.join(file))
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(String::from).collect())
+        .collect()
+}
+
+/// Resolves `USER[:GROUP]`, names or ids, against the files of the rootfs
+/// rather than the ones of the host. Without a group, the user gets its
+/// group of /etc/passwd, or 0 when it has no entry. A user of /etc/passwd
+/// also gets the groups of /etc/group it's a member of.
+pub fn lookup(rootfs: &Path, user: &str) -> Result<User> {
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, Some(group)),
+        None => (user, None),
+    };
+    let passwd = passwd(rootfs);
+    let entry = passwd.iter().find(|entry| entry.name == name).or_else(|| {
+        let uid = name.parse::<u32>().ok()?;
+        passwd.iter().find(|entry| entry.uid == uid)
+    });
+    let uid = match entry {
+        Some(entry) => entry.uid,
+        None => name.parse().map_err(|_| {
+            Error::Runtime(format!("no user {} in the /etc/passwd of the rootfs", name))
+        })?,
+    };
+
+    let groups = groups(rootfs);
+    let gid = match group {
+        Some(group) => match groups.iter().find(|entry| entry.name == group) {
+            Some(entry) => entry.gid,
+            None => group.parse().map_err(|_| {
+                Error::Runtime(format!(
+                    "no group {} in the /etc/group of the rootfs",
+                    group
+                ))
+            })?,
+        },
+        None => entry.map(|entry| entry.gid).unwrap_or(0),
+    };
+    let additional_gids: Vec<i64> = match entry {
+        Some(entry) => groups
+            .iter()
+            .filter(|group| group.gid != gid && group.members.contains(&entry.name))
+            .map(|group| group.gid as i64)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(User {
+        uid: uid as i64,
+        gid: gid as i64,
+        additional_gids: match additional_gids.is_empty() {
+            true => None,
+            false => Some(additional_gids),
+        },
+        username: None,
+        ..Default::default()
+    })
+}
+
+/// Home directory of the uid in the /etc/passwd of the rootfs
+pub fn home(rootfs: &Path, uid: u32) -> Option<String> {
+    passwd(rootfs)
+        .into_iter()
+        .find(|entry| entry.uid == uid)
+        .map(|entry| entry.home)
+}
+
+/// Sets the supplementary groups of the current process to the additional
+/// gids, dropping the ones of the runtime. Clearing them is denied in a user
+/// namespace with setgroups disabled, which is fine.
+pub fn set_groups(user: &User) -> Result<()> {
+    let gids: Vec<Gid> = user
+        .additional_gids
+        .iter()
+        .flatten()
+        .map(|gid| Gid::from_raw(*gid as u32))
+        .collect();
+    match setgroups(&gids) {
+        Err(nix::Error::Sys(Errno::EPERM)) if gids.is_empty() => Ok(()),
+        result => result.map_err(Error::sys("unable to set the supplementary groups")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{home, lookup};
+
+    #[test]
+    fn rootfs_users() {
+        let rootfs = Path::new("./users_test");
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::fs::write(
+            rootfs.join("etc/passwd"),
+            concat!(
+                "root:x:0:0:root:/root:/bin/sh\n",
+                "# comment\n",
+                "app:x:1000:1000:App:/home/app:/bin/sh\n",
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            rootfs.join("etc/group"),
+            concat!(
+                "root:x:0:\n",
+                "wheel:x:10:root,app\n",
+                "app:x:1000:\n",
+                "audio:x:29:app\n",
+            ),
+        )
+        .unwrap();
+
+        let app = lookup(rootfs, "app").unwrap();
+        assert_eq!((app.uid, app.gid), (1000, 1000));
+        assert_eq!(app.additional_gids, Some(vec![10, 29]));
+        let app = lookup(rootfs, "1000:wheel").unwrap();
+        assert_eq!((app.uid, app.gid), (1000, 10));
+        assert_eq!(app.additional_gids, Some(vec![29]));
+        let unknown = lookup(rootfs, "2000:42").unwrap();
+        assert_eq!((unknown.uid, unknown.gid), (2000, 42));
+        assert_eq!(unknown.additional_gids, None);
+        assert!(lookup(rootfs, "nobody").is_err());
+        assert!(lookup(rootfs, "app:staff").is_err());
+
+        assert_eq!(home(rootfs, 1000).as_deref(), Some("/home/app"));
+        assert_eq!(home(rootfs, 2000), None);
+        assert!(lookup(Path::new("./missing_rootfs"), "0").is_ok());
+
+        std::fs::remove_dir_all(rootfs).unwrap();
+    }
+}
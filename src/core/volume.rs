@@ -0,0 +1,310 @@
+use log::debug;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::core::{
+    common::{Error, Result},
+    state::{self, State},
+};
+use crate::oci::spec::{Mount, Spec};
+
+/// Directory of the named volumes in the runtime root, bind mounted into the
+/// containers by `--volume NAME:DEST[:OPTIONS]`
+pub const VOLUMES_DIR: &str = "volumes";
+/// Volumes of a container in its state, comma-separated, kept from removal
+pub const VOLUMES_ANNOTATION: &str = "pura.volumes";
+
+/// Named volume of a runtime root
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub name: String,
+    /// Directory bind mounted into the containers
+    pub path: PathBuf,
+}
+
+/// Checks the name of a volume is a plain name, like a container id, starting
+/// with a letter or a digit so it can't be mistaken for a path
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        return Err(Error::Runtime(format!(
+            "invalid volume name {:?}: only letters, digits and _.- starting with a letter or a digit",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl Volume {
+    fn new(root: &Path, name: &str) -> Volume {
+        Volume {
+            name: name.to_string(),
+            path: root.join(VOLUMES_DIR).join(name),
+        }
+    }
+
+    /// Creates the volume, failing if it already exists
+    pub fn create(root: &Path, name: &str) -> Result<Volume> {
+        validate_name(name)?;
+        let volume = Volume::new(root, name);
+        std::fs::create_dir_all(root.join(VOLUMES_DIR))
+            .map_err(Error::io(format!("unable to create the volumes of {:?}", root)))?;
+        match std::fs::create_dir(&volume.path) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                Err(Error::Runtime(format!("volume {} already exists", name)))
+            }
+            Err(err) => Err(Error::io(format!("unable to create volume {}", name))(err)),
+            Ok(_) => Ok(volume),
+        }
+    }
+
+    /// The existing volume, None without a volume of the name
+    pub fn load(root: &Path, name: &str) -> Result<Option<Volume>> {
+        validate_name(name)?;
+        let volume = Volume::new(root, name);
+        Ok(volume.path.is_dir().then_some(volume))
+    }
+
+    /// Volumes of the root, sorted by name
+    pub fn list(root: &Path) -> Result<Vec<Volume>> {
+        let dir = root.join(VOLUMES_DIR);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // No volume created yet
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::io(format!("unable to list {:?}", dir))(err)),
+        };
+        let mut volumes: Vec<Volume> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| Volume::new(root, &entry.file_name().to_string_lossy()))
+            .collect();
+        volumes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(volumes)
+    }
+
+    /// Containers of the root using the volume, until they are deleted
+    pub fn users(&self, root: &Path) -> Result<Vec<String>> {
+        Ok(state::open(root)?
+            .list()?
+            .into_iter()
+            .filter(|state| annotated_volumes(state).any(|name| name == self.name))
+            .map(|state| state.id)
+            .collect())
+    }
+
+    /// Removes the volume and its content, refusing while a container uses
+    /// it unless forced
+    pub fn remove(&self, root: &Path, force: bool) -> Result<()> {
+        let users = self.users(root)?;
+        if !users.is_empty() && !force {
+            return Err(Error::Runtime(format!(
+                "volume {} is used by {}",
+                self.name,
+                users.join(", ")
+            )));
+        }
+        std::fs::remove_dir_all(&self.path)
+            .map_err(Error::io(format!("unable to remove volume {}", self.name)))
+    }
+}
+
+/// Names of the volumes recorded in the state of a container
+fn annotated_volumes(state: &State) -> impl Iterator<Item = &str> {
+    state
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(VOLUMES_ANNOTATION))
+        .map(|names| names.split(','))
+        .into_iter()
+        .flatten()
+        .filter(|name| !name.is_empty())
+}
+
+/// Source of a `--volume`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Named volume of the root, created on first use
+    Named(String),
+    /// Absolute path of the host
+    Host(PathBuf),
+}
+
+/// `--volume SOURCE:DEST[:OPTIONS]`, a named volume or an absolute host path
+/// bind mounted on DEST with the comma-separated mount options, `rw` by default
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeMount {
+    pub source: Source,
+    pub destination: String,
+    pub options: Vec<String>,
+}
+
+impl VolumeMount {
+    pub fn parse(volume: &str) -> Result<VolumeMount> {
+        let invalid = |reason: &str| {
+            Error::Runtime(format!(
+                "invalid volume {:?}: {}, expected SOURCE:DEST[:OPTIONS]",
+                volume, reason
+            ))
+        };
+        let mut parts = volume.splitn(3, ':');
+        let source = parts.next().filter(|source| !source.is_empty());
+        let destination = parts.next().filter(|destination| !destination.is_empty());
+        let (source, destination) = match (source, destination) {
+            (Some(source), Some(destination)) => (source, destination),
+            _ => return Err(invalid("missing source or destination")),
+        };
+        if !destination.starts_with('/') {
+            return Err(invalid("the destination isn't absolute"));
+        }
+
+        let source = if source.starts_with('/') {
+            Source::Host(PathBuf::from(source))
+        } else {
+            validate_name(source)?;
+            Source::Named(source.to_string())
+        };
+        let mut options: Vec<String> = parts
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|option| !option.is_empty())
+            .map(String::from)
+            .collect();
+        if !options.iter().any(|option| option == "ro" || option == "rw") {
+            options.push("rw".to_string());
+        }
+        Ok(VolumeMount {
+            source,
+            destination: destination.to_string(),
+            options,
+        })
+    }
+}
+
+/// Injects the volumes as bind mounts into the spec, creating the named
+/// volumes missing from the root. Returns the names of the named volumes,
+/// to record in the state of the container.
+pub fn inject(mut spec: Spec, root: &Path, volumes: &[VolumeMount]) -> Result<(Spec, Vec<String>)> {
+    let mut names = Vec::new();
+    for volume in volumes {
+        let source = match &volume.source {
+            Source::Host(path) => {
+                if !path.exists() {
+                    return Err(Error::Bundle(format!("volume source {:?} doesn't exist", path)));
+                }
+                path.clone()
+            }
+            Source::Named(name) => {
+                let named = match Volume::load(root, name)? {
+                    Some(named) => named,
+                    None => {
+                        debug!("creating volume {}", name);
+                        Volume::create(root, name)?
+                    }
+                };
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+                named.path
+            }
+        };
+        let mut options = vec!["rbind".to_string()];
+        options.extend(volume.options.iter().cloned());
+        spec = spec.add_mount(Mount {
+            destination: volume.destination.clone(),
+            mount_type: Some("bind".to_string()),
+            source: Some(source.to_string_lossy().to_string()),
+            options: Some(options),
+            ..Default::default()
+        });
+    }
+    Ok((spec, names))
+}
+
+/// Records the named volumes of the container in its state
+pub fn annotate(state: &mut State, names: &[String]) {
+    if !names.is_empty() {
+        state
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(VOLUMES_ANNOTATION.to_string(), names.join(","));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{annotate, inject, Source, Volume, VolumeMount};
+    use crate::core::state::{JsonStore, State, Store};
+    use crate::oci::spec::Spec;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            VolumeMount::parse("data:/var/lib/data").unwrap(),
+            VolumeMount {
+                source: Source::Named(String::from("data")),
+                destination: String::from("/var/lib/data"),
+                options: vec![String::from("rw")],
+            }
+        );
+        let host = VolumeMount::parse("/srv/config:/etc/app:ro,nosuid").unwrap();
+        assert_eq!(host.source, Source::Host(PathBuf::from("/srv/config")));
+        assert_eq!(host.options, vec!["ro", "nosuid"]);
+
+        assert!(VolumeMount::parse("data").is_err());
+        assert!(VolumeMount::parse("data:relative").is_err());
+        assert!(VolumeMount::parse("../data:/data").is_err());
+        assert!(VolumeMount::parse(":/data").is_err());
+    }
+
+    #[test]
+    fn lifecycle() {
+        let root = Path::new("./volume_test");
+        std::fs::create_dir_all(root).unwrap();
+
+        let data = Volume::create(root, "data").unwrap();
+        assert!(data.path.is_dir());
+        assert!(Volume::create(root, "data").is_err());
+        assert!(Volume::create(root, "../data").is_err());
+
+        // Named volumes are created on first use
+        let volumes = [
+            VolumeMount::parse("data:/data").unwrap(),
+            VolumeMount::parse("cache:/cache:ro").unwrap(),
+        ];
+        let (spec, names) = inject(Spec::new(), root, &volumes).unwrap();
+        assert_eq!(names, vec!["data", "cache"]);
+        let mounts = spec.mounts.unwrap();
+        assert_eq!(mounts[1].source.as_deref(), Some("./volume_test/volumes/cache"));
+        assert_eq!(mounts[1].options.as_ref().unwrap(), &["rbind", "ro"]);
+        let listed: Vec<String> = Volume::list(root)
+            .unwrap()
+            .into_iter()
+            .map(|volume| volume.name)
+            .collect();
+        assert_eq!(listed, vec!["cache", "data"]);
+
+        // Kept while a container uses it
+        let mut state = State::new("web", 0, "/bundle");
+        annotate(&mut state, &names);
+        JsonStore::new(root).save(&state).unwrap();
+        assert_eq!(data.users(root).unwrap(), vec!["web"]);
+        assert!(data.remove(root, false).is_err());
+        JsonStore::new(root).remove("web").unwrap();
+        data.remove(root, false).unwrap();
+        assert!(Volume::load(root, "data").unwrap().is_none());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+#[cfg(feature = "wasm")]
+use std::path::Path;
+#[cfg(feature = "wasm")]
+use wasi_common::{
+    sync::{self, ambient_authority, Dir, WasiCtxBuilder},
+    I32Exit, WasiCtx,
+};
+#[cfg(feature = "wasm")]
+use wasmtime::{Engine, Linker, Store};
+
+#[cfg(feature = "wasm")]
+use crate::core::common::Error;
+use crate::core::common::{disabled, Result};
+use crate::oci::spec::{Process, Spec};
+
+/// Annotations of the images of wasm modules, like crun's handlers
+const VARIANT_ANNOTATION: &str = "module.wasm.image/variant";
+const HANDLER_ANNOTATION: &str = "run.oci.handler";
+/// Exit code of a module that trapped, like an abort
+#[cfg(feature = "wasm")]
+const TRAP_EXIT_CODE: i32 = 128 + nix::libc::SIGABRT;
+
+/// Whether the process of the spec is a wasm module, by the annotations of
+/// the image or the extension of the executable
+pub fn is_module(spec: &Spec) -> bool {
+    let annotated = spec.annotations.as_ref().is_some_and(|annotations| {
+        matches!(
+            annotations.get(VARIANT_ANNOTATION).map(String::as_str),
+            Some("compat" | "compat-smart")
+        ) || annotations.get(HANDLER_ANNOTATION).map(String::as_str) == Some("wasm")
+    });
+    let executable = spec
+        .process
+        .as_ref()
+        .and_then(|process| process.args.as_ref())
+        .and_then(|args| args.first());
+    annotated || executable.is_some_and(|executable| executable.ends_with(".wasm"))
+}
+
+/// Refuses the wasm modules when pura is built without the wasmtime backend
+pub fn check(spec: &Spec) -> Result<()> {
+    match is_module(spec) && !cfg!(feature = "wasm") {
+        true => Err(disabled("wasm")),
+        false => Ok(()),
+    }
+}
+
+/// Compiled module of the process of a spec, run by the container process
+/// with wasmtime in place of the exec of an executable
+#[cfg(feature = "wasm")]
+pub struct Module {
+    engine: Engine,
+    module: wasmtime::Module,
+}
+
+#[cfg(feature = "wasm")]
+impl Module {
+    /// Compiles the module of the first arg, a path in the rootfs relative to
+    /// the working directory of the process. Compiled before the container
+    /// is ready, so create fails on a missing or invalid module.
+    pub fn load(process: &Process) -> Result<Module> {
+        let name = process
+            .args
+            .as_ref()
+            .and_then(|args| args.first())
+            .ok_or_else(|| Error::Bundle("no wasm module to run".to_string()))?;
+        let path = Path::new(&process.cwd).join(name);
+        let engine = Engine::default();
+        let module = wasmtime::Module::from_file(&engine, &path).map_err(|err| {
+            Error::Runtime(format!("unable to load the wasm module {:?}: {:#}", path, err))
+        })?;
+        Ok(Module { engine, module })
+    }
+
+    /// Runs the `_start` of the module with WASI, given the args and the
+    /// environment of the process, its stdio, and the root and working
+    /// directory of the container. Returns the exit code of the module.
+    pub fn run(&self, process: &Process) -> Result<i32> {
+        let wasi = self.wasi(process).map_err(|err| {
+            Error::Runtime(format!("unable to set up WASI: {:#}", err))
+        })?;
+        let mut linker: Linker<WasiCtx> = Linker::new(&self.engine);
+        let mut store = Store::new(&self.engine, wasi);
+        let start = sync::add_to_linker(&mut linker, |wasi| wasi)
+            .and_then(|_| linker.instantiate(&mut store, &self.module))
+            .and_then(|instance| instance.get_typed_func::<(), ()>(&mut store, "_start"))
+            .map_err(|err| Error::Runtime(format!("unable to instantiate the module: {:#}", err)))?;
+        match start.call(&mut store, ()) {
+            Ok(()) => Ok(0),
+            Err(err) => match err.downcast_ref::<I32Exit>() {
+                Some(I32Exit(code)) => Ok(*code),
+                None => {
+                    eprintln!("wasm module trapped: {:#}", err);
+                    Ok(TRAP_EXIT_CODE)
+                }
+            },
+        }
+    }
+
+    fn wasi(&self, process: &Process) -> wasmtime::Result<WasiCtx> {
+        let env: Vec<(String, String)> = process
+            .env
+            .iter()
+            .flatten()
+            .filter_map(|env| env.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let root = Dir::open_ambient_dir("/", ambient_authority())?;
+        let cwd = Dir::open_ambient_dir(&process.cwd, ambient_authority())?;
+        Ok(WasiCtxBuilder::new()
+            .inherit_stdio()
+            .args(process.args.as_deref().unwrap_or_default())?
+            .envs(&env)?
+            .preopened_dir(root, "/")?
+            .preopened_dir(cwd, ".")?
+            .build())
+    }
+}
+
+/// Stands for the module when pura is built without the wasmtime backend,
+/// `check` refuses the modules first
+#[cfg(not(feature = "wasm"))]
+pub enum Module {}
+
+#[cfg(not(feature = "wasm"))]
+impl Module {
+    pub fn load(_process: &Process) -> Result<Module> {
+        Err(disabled("wasm"))
+    }
+
+    pub fn run(&self, _process: &Process) -> Result<i32> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_module;
+    use crate::oci::spec::Spec;
+
+    #[test]
+    fn modules() {
+        assert!(!is_module(&Spec::new().with_args(&["/bin/sh"])));
+        assert!(is_module(&Spec::new().with_args(&["/app/main.wasm"])));
+        let annotated = Spec::new()
+            .with_args(&["/app/main"])
+            .add_annotation("module.wasm.image/variant", "compat");
+        assert!(is_module(&annotated));
+        assert!(is_module(&Spec::new().add_annotation("run.oci.handler", "wasm")));
+        let other = Spec::new().add_annotation("run.oci.handler", "krun");
+        assert!(!is_module(&other));
+    }
+}
@@ -0,0 +1,263 @@
+//! Report of the namespaces, cgroup, capabilities, mounts and open fds of a
+//! running container, to find out at once why a container behaves oddly.
+
+use nix::unistd::Pid;
+use std::{fmt::Write as _, path::Path};
+
+use crate::container::Container;
+use crate::core::{
+    capabilities,
+    cgroups::{cgroup_of, CGROUP_ROOT},
+    common::{Error, Result},
+    state::{State, Status},
+};
+
+/// Namespaces listed in /proc/<pid>/ns
+const NAMESPACES: [&str; 10] = [
+    "cgroup",
+    "ipc",
+    "mnt",
+    "net",
+    "pid",
+    "pid_for_children",
+    "time",
+    "time_for_children",
+    "user",
+    "uts",
+];
+/// Limits of the cgroup shown when the controller is enabled
+const CGROUP_FILES: [&str; 10] = [
+    "memory.max",
+    "memory.high",
+    "memory.swap.max",
+    "memory.current",
+    "cpu.max",
+    "cpu.weight",
+    "cpuset.cpus.effective",
+    "pids.max",
+    "pids.current",
+    "io.max",
+];
+/// Capability sets of /proc/<pid>/status
+const CAPABILITY_SETS: [(&str, &str); 5] = [
+    ("CapInh", "inheritable"),
+    ("CapPrm", "permitted"),
+    ("CapEff", "effective"),
+    ("CapBnd", "bounding"),
+    ("CapAmb", "ambient"),
+];
+
+/// Report of the created or running container
+pub fn report(container: &Container) -> Result<String> {
+    let state = container.state()?;
+    if state.status != Status::Created && state.status != Status::Running {
+        return Err(Error::State(format!(
+            "container {} isn't created or running",
+            state.id
+        )));
+    }
+    Ok(render(&state))
+}
+
+fn render(state: &State) -> String {
+    let pid = Pid::from_raw(state.pid as i32);
+    let mut report = String::new();
+    let _ = writeln!(report, "Container {}", state.id);
+    let _ = writeln!(report, "  status  {:?}", state.status);
+    let _ = writeln!(report, "  pid     {}", state.pid);
+    let _ = writeln!(report, "  bundle  {}", state.bundle.display());
+
+    report.push_str("\nNamespaces\n");
+    report.push_str(&namespaces(pid));
+    report.push_str("\nCgroup\n");
+    report.push_str(&cgroup(pid));
+    report.push_str("\nSecurity\n");
+    report.push_str(&security(&proc_file(pid, "status")));
+    report.push_str("\nFile descriptors\n");
+    report.push_str(&fds(pid));
+    report.push_str("\nMounts\n");
+    report.push_str(&mounts(&proc_file(pid, "mountinfo")));
+    report
+}
+
+fn proc_file(pid: Pid, name: &str) -> String {
+    std::fs::read_to_string(format!("/proc/{}/{}", pid, name)).unwrap_or_default()
+}
+
+fn link(path: &str) -> Option<String> {
+    std::fs::read_link(path)
+        .ok()
+        .map(|target| target.to_string_lossy().to_string())
+}
+
+/// Namespaces with their inode, marked when pura itself is in the same one
+fn namespaces(pid: Pid) -> String {
+    let mut lines = String::new();
+    for namespace in NAMESPACES {
+        let target = match link(&format!("/proc/{}/ns/{}", pid, namespace)) {
+            Some(target) => target,
+            // Not supported by the kernel
+            None => continue,
+        };
+        let shared = link(&format!("/proc/self/ns/{}", namespace)).as_ref() == Some(&target);
+        let _ = writeln!(
+            lines,
+            "  {:<18} {}{}",
+            namespace,
+            target,
+            if shared { "  (shared with pura)" } else { "" }
+        );
+    }
+    lines
+}
+
+fn cgroup(pid: Pid) -> String {
+    let path = match cgroup_of(pid) {
+        Some(path) => path,
+        None => return String::from("  no cgroup v2\n"),
+    };
+    let mut lines = format!("  path  {}\n", path);
+    let dir = Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'));
+    for file in CGROUP_FILES {
+        if let Ok(value) = std::fs::read_to_string(dir.join(file)) {
+            let value = value.trim().replace('\n', "; ");
+            let _ = writeln!(lines, "  {:<22} {}", file, value);
+        }
+    }
+    lines
+}
+
+/// Capability sets, no_new_privs and seccomp mode of /proc/<pid>/status
+fn security(status: &str) -> String {
+    let field = |name: &str| {
+        status.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key == name).then(|| value.trim().to_string())
+        })
+    };
+    let mut lines = String::new();
+    for (key, set) in CAPABILITY_SETS {
+        let names = match field(key).and_then(|mask| u64::from_str_radix(&mask, 16).ok()) {
+            Some(0) => String::from("none"),
+            Some(mask) => capabilities::names(mask).join(" "),
+            None => continue,
+        };
+        let _ = writeln!(lines, "  {:<12} {}", set, names);
+    }
+    if let Some(no_new_privs) = field("NoNewPrivs") {
+        let _ = writeln!(lines, "  {:<12} {}", "noNewPrivs", no_new_privs == "1");
+    }
+    if let Some(mode) = field("Seccomp") {
+        let mode = match mode.as_str() {
+            "0" => String::from("disabled"),
+            "1" => String::from("strict"),
+            "2" => match field("Seccomp_filters") {
+                Some(filters) => format!("filter ({} filters)", filters),
+                None => String::from("filter"),
+            },
+            mode => mode.to_string(),
+        };
+        let _ = writeln!(lines, "  {:<12} {}", "seccomp", mode);
+    }
+    lines
+}
+
+/// Open fds of the process, the terminals marked as console
+fn fds(pid: Pid) -> String {
+    let mut fds: Vec<(i32, String)> = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let fd = entry.file_name().to_string_lossy().parse().ok()?;
+                let target = std::fs::read_link(entry.path()).ok()?;
+                Some((fd, target.to_string_lossy().to_string()))
+            })
+            .collect(),
+        Err(err) => return format!("  unavailable: {}\n", err),
+    };
+    fds.sort();
+
+    let mut lines = String::new();
+    for (fd, target) in fds {
+        let console = target.starts_with("/dev/pts/")
+            || target == "/dev/ptmx"
+            || target == "/dev/console";
+        let _ = writeln!(
+            lines,
+            "  {:<4} {}{}",
+            fd,
+            target,
+            if console { "  (console)" } else { "" }
+        );
+    }
+    lines
+}
+
+/// Mount points with their filesystem, source and options, from mountinfo
+fn mounts(mountinfo: &str) -> String {
+    let mut lines = String::new();
+    for line in mountinfo.lines() {
+        // ID PARENT MAJOR:MINOR ROOT POINT OPTIONS [OPTIONAL...] - TYPE SOURCE SUPER
+        let (mount, filesystem) = match line.split_once(" - ") {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let mount: Vec<&str> = mount.split(' ').collect();
+        let filesystem: Vec<&str> = filesystem.split(' ').collect();
+        if mount.len() < 6 || filesystem.len() < 3 {
+            continue;
+        }
+        let _ = writeln!(
+            lines,
+            "  {} {} {} {},{}",
+            mount[4], filesystem[0], filesystem[1], mount[5], filesystem[2]
+        );
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::getpid;
+
+    use super::{mounts, render, security};
+    use crate::core::state::{State, Status};
+
+    #[test]
+    fn status_security() {
+        let status = "Name:\tsh\nCapInh:\t0000000000000000\nCapPrm:\t00000000a80425fb\n\
+                      CapEff:\t00000000a80425fb\nCapBnd:\t00000000a80425fb\n\
+                      CapAmb:\t0000000000000000\nNoNewPrivs:\t1\nSeccomp:\t2\n\
+                      Seccomp_filters:\t1\n";
+        let lines = security(status);
+        assert!(lines.contains("inheritable  none\n"));
+        assert!(lines.contains("effective    CAP_CHOWN CAP_DAC_OVERRIDE CAP_FOWNER"));
+        assert!(lines.contains("CAP_NET_BIND_SERVICE CAP_NET_RAW CAP_SYS_CHROOT CAP_MKNOD"));
+        assert!(lines.contains("noNewPrivs   true\n"));
+        assert!(lines.contains("seccomp      filter (1 filters)\n"));
+    }
+
+    #[test]
+    fn mountinfo() {
+        let mountinfo = "\
+22 1 0:21 / / rw,relatime master:1 - overlay overlay rw,lowerdir=/l
+23 22 0:22 / /proc rw,nosuid,nodev,noexec - proc proc rw
+";
+        assert_eq!(
+            mounts(mountinfo),
+            "  / overlay overlay rw,relatime,rw,lowerdir=/l\n  /proc proc proc rw,nosuid,nodev,noexec,rw\n"
+        );
+    }
+
+    #[test]
+    fn own_process() {
+        // The test process as the container process
+        let mut state = State::new("debug", getpid().as_raw() as u64, "/tmp");
+        state.status = Status::Running;
+        let report = render(&state);
+        assert!(report.starts_with("Container debug\n"));
+        assert!(report.contains("  net                net:["));
+        assert!(report.contains("(shared with pura)"));
+        assert!(report.contains(" /proc proc proc "));
+    }
+}
@@ -0,0 +1,173 @@
+//! Output formats of `state` and `list`, so scripts can extract fields like
+//! `{{.pid}}` without piping the JSON through jq.
+
+use serde_json::Value;
+use std::{fmt::Write as _, str::FromStr};
+
+use crate::core::{
+    common::{Error, Result},
+    state::State,
+};
+
+/// Output format of container states
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    Json,
+    Table,
+    /// Go-template-like text, `{{.field}}` is replaced by the field of the
+    /// state, like `{{.pid}}` or `{{.annotations.key}}`, and `{{.}}` by the
+    /// whole state
+    Template(String),
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(Format::Json),
+            "table" => Ok(Format::Table),
+            template if template.contains("{{") => Ok(Format::Template(template.to_string())),
+            _ => Err(Error::Runtime(format!(
+                "unknown format {}, expected json, table or a template like {{{{.pid}}}}",
+                format
+            ))),
+        }
+    }
+}
+
+impl Format {
+    /// Formats the state of a container
+    pub fn state(&self, state: &State) -> Result<String> {
+        match self {
+            // Single line without a newline, like runc
+            Format::Json => {
+                serde_json::to_string(state).map_err(Error::json("unable to serialize state"))
+            }
+            Format::Table => Ok(table(std::slice::from_ref(state))),
+            Format::Template(template) => Ok(format!("{}\n", render(template, state)?)),
+        }
+    }
+
+    /// Formats the states of the containers
+    pub fn list(&self, states: &[State]) -> Result<String> {
+        match self {
+            Format::Json => serde_json::to_string(states)
+                .map(|states| format!("{}\n", states))
+                .map_err(Error::json("unable to serialize states")),
+            Format::Table => Ok(table(states)),
+            Format::Template(template) => {
+                let mut lines = String::new();
+                for state in states {
+                    let _ = writeln!(lines, "{}", render(template, state)?);
+                }
+                Ok(lines)
+            }
+        }
+    }
+}
+
+fn table(states: &[State]) -> String {
+    let id_width = states
+        .iter()
+        .map(|state| state.id.len())
+        .max()
+        .unwrap_or(0)
+        .max(2);
+    let mut table = format!(
+        "{:<id_width$} {:>8} {:<8} {}\n",
+        "ID",
+        "PID",
+        "STATUS",
+        "BUNDLE",
+        id_width = id_width
+    );
+    for state in states {
+        let _ = writeln!(
+            table,
+            "{:<id_width$} {:>8} {:<8} {}",
+            state.id,
+            state.pid,
+            format!("{:?}", state.status).to_lowercase(),
+            state.bundle.display(),
+            id_width = id_width
+        );
+    }
+    table
+}
+
+fn render(template: &str, state: &State) -> Result<String> {
+    let value = serde_json::to_value(state).map_err(Error::json("unable to serialize state"))?;
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| Error::Runtime(format!("unclosed {{{{ in template {:?}", template)))?;
+        output.push_str(&field(&value, rest[start + 2..start + end].trim())?);
+        rest = &rest[start + end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Text of the field of the state, strings without their quotes
+fn field(state: &Value, key: &str) -> Result<String> {
+    let path = key
+        .strip_prefix('.')
+        .ok_or_else(|| Error::Runtime(format!("unsupported template action {:?}", key)))?;
+    let mut value = state;
+    for name in path.split('.').filter(|name| !name.is_empty()) {
+        value = value
+            .get(name)
+            .ok_or_else(|| Error::Runtime(format!("no field {:?} in the state", name)))?;
+    }
+    Ok(match value {
+        Value::String(value) => value.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::Format;
+    use crate::core::state::{State, Status};
+
+    #[test]
+    fn formats() {
+        let mut state = State::new("abc", 42, "/tmp");
+        state.status = Status::Running;
+        state
+            .annotations
+            .as_mut()
+            .unwrap()
+            .insert("owner".to_string(), "ci".to_string());
+
+        let template = Format::from_str("{{.id}} {{ .pid }} {{.status}} {{.annotations.owner}}");
+        assert_eq!(
+            template.unwrap().state(&state).unwrap(),
+            "abc 42 running ci\n"
+        );
+        assert!(Format::from_str("{{.missing}}")
+            .unwrap()
+            .state(&state)
+            .is_err());
+        assert!(Format::from_str("{{.id").unwrap().state(&state).is_err());
+        assert!(Format::from_str("yaml").is_err());
+
+        let json = Format::Json.state(&state).unwrap();
+        assert!(json.starts_with(r#"{"ociVersion""#));
+        assert_eq!(
+            Format::Table.list(&[state]).unwrap(),
+            concat!(
+                "ID       PID STATUS   BUNDLE\n",
+                "abc       42 running  /tmp\n",
+            )
+        );
+        assert_eq!(Format::Json.list(&[]).unwrap(), "[]\n");
+    }
+}
@@ -0,0 +1,209 @@
+//! Bundle of an image: its layers unpacked as the rootfs and a config.json
+//! running the entrypoint of the image with the defaults of `runc spec`.
+
+use nix::unistd::{getegid, geteuid};
+use std::path::Path;
+
+use crate::core::common::{Error, Result};
+use crate::image::{
+    layout::{Descriptor, ImageConfig, Layout},
+    unpack::unpack,
+};
+use crate::oci::spec::{Capabilities, Mount, Process, Spec, UidMapping, User};
+
+/// Capabilities docker gives its containers
+const DEFAULT_CAPABILITIES: [&str; 14] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FSETID",
+    "CAP_FOWNER",
+    "CAP_MKNOD",
+    "CAP_NET_RAW",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETFCAP",
+    "CAP_SETPCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SYS_CHROOT",
+    "CAP_KILL",
+    "CAP_AUDIT_WRITE",
+];
+const MASKED_PATHS: [&str; 9] = [
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/sys/firmware",
+];
+const READONLY_PATHS: [&str; 6] = [
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+    "/sys/fs/cgroup",
+];
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Unpacks the image into `<bundle>/rootfs` and writes its config.json
+pub fn create(layout: &Layout, manifest: &Descriptor, bundle: &Path) -> Result<()> {
+    if bundle.join("config.json").exists() {
+        return Err(Error::Bundle(format!("{:?} already holds a bundle", bundle)));
+    }
+    let image = layout.image(manifest)?;
+    unpack(layout, &image, &bundle.join("rootfs"))?;
+    spec(&image.config, !geteuid().is_root())?.save(bundle)
+}
+
+/// Spec running the process of the image config. A rootless spec maps the
+/// user to root in a user namespace and doesn't create devices.
+pub fn spec(image: &ImageConfig, rootless: bool) -> Result<Spec> {
+    let config = image.config.clone().unwrap_or_default();
+    let args: Vec<String> = config
+        .entrypoint
+        .unwrap_or_default()
+        .into_iter()
+        .chain(config.cmd.unwrap_or_default())
+        .collect();
+    if args.is_empty() {
+        return Err(Error::Bundle(
+            "the image has neither an entrypoint nor a cmd".to_string(),
+        ));
+    }
+    let mut env = config.env.unwrap_or_default();
+    if !env.iter().any(|entry| entry.starts_with("PATH=")) {
+        env.insert(0, format!("PATH={}", DEFAULT_PATH));
+    }
+    let capabilities: Vec<String> = DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    let process = Process {
+        terminal: Some(false),
+        user: Some(User {
+            username: config.user.filter(|user| !user.is_empty()),
+            ..Default::default()
+        }),
+        args: Some(args),
+        env: Some(env),
+        cwd: config
+            .working_dir
+            .filter(|cwd| !cwd.is_empty())
+            .unwrap_or_else(|| String::from("/")),
+        capabilities: Some(Capabilities {
+            bounding: Some(capabilities.clone()),
+            permitted: Some(capabilities.clone()),
+            effective: Some(capabilities),
+            ..Default::default()
+        }),
+        no_new_privileges: Some(true),
+        ..Default::default()
+    };
+
+    let mut spec = Spec::new()
+        .with_rootfs("rootfs", false)
+        .with_hostname("pura")
+        .with_process(process)
+        .add_mount(Mount::new("/proc", "proc", "proc", &[]))
+        .add_mount(Mount::new(
+            "/dev",
+            "tmpfs",
+            "tmpfs",
+            &["nosuid", "strictatime", "mode=755", "size=65536k"],
+        ))
+        .add_mount(Mount::new(
+            "/dev/pts",
+            "devpts",
+            "devpts",
+            &["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620"],
+        ))
+        .add_mount(Mount::new(
+            "/dev/shm",
+            "tmpfs",
+            "shm",
+            &["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"],
+        ))
+        .add_mount(Mount::new(
+            "/dev/mqueue",
+            "mqueue",
+            "mqueue",
+            &["nosuid", "noexec", "nodev"],
+        ))
+        .add_mount(Mount::new(
+            "/sys",
+            "sysfs",
+            "sysfs",
+            &["nosuid", "noexec", "nodev", "ro"],
+        ))
+        .add_namespace("pid", None)
+        .add_namespace("network", None)
+        .add_namespace("ipc", None)
+        .add_namespace("uts", None)
+        .add_namespace("mount", None);
+    for path in MASKED_PATHS {
+        spec = spec.add_masked_path(path);
+    }
+    for path in READONLY_PATHS {
+        spec = spec.add_readonly_path(path);
+    }
+    if rootless {
+        spec = spec.add_namespace("user", None);
+        let linux = spec.linux.get_or_insert_with(Default::default);
+        linux.uid_mappings = Some(vec![UidMapping {
+            container_id: 0,
+            host_id: geteuid().as_raw() as i64,
+            size: 1,
+            ..Default::default()
+        }]);
+        linux.gid_mappings = Some(vec![UidMapping {
+            container_id: 0,
+            host_id: getegid().as_raw() as i64,
+            size: 1,
+            ..Default::default()
+        }]);
+    }
+    Ok(spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spec;
+    use crate::image::layout::{ExecConfig, ImageConfig};
+
+    #[test]
+    fn image_spec() {
+        let image = ImageConfig {
+            config: Some(ExecConfig {
+                user: Some(String::from("nginx")),
+                env: Some(vec![String::from("NGINX_VERSION=1.25")]),
+                entrypoint: Some(vec![String::from("/docker-entrypoint.sh")]),
+                cmd: Some(vec![String::from("nginx"), String::from("-g")]),
+                working_dir: Some(String::new()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let image_spec = spec(&image, false).unwrap();
+        let process = image_spec.process.as_ref().unwrap();
+        assert_eq!(
+            process.args.as_ref().unwrap(),
+            &["/docker-entrypoint.sh", "nginx", "-g"]
+        );
+        assert_eq!(process.cwd, "/");
+        assert_eq!(process.user.as_ref().unwrap().username.as_deref(), Some("nginx"));
+        let env = process.env.as_ref().unwrap();
+        assert!(env[0].starts_with("PATH=/usr/local/sbin"));
+        assert_eq!(env[1], "NGINX_VERSION=1.25");
+        let linux = image_spec.linux.as_ref().unwrap();
+        assert!(linux.uid_mappings.is_none());
+        assert_eq!(linux.namespaces.as_ref().unwrap().len(), 5);
+
+        let rootless = spec(&image, true).unwrap();
+        let linux = rootless.linux.unwrap();
+        assert_eq!(linux.namespaces.unwrap().len(), 6);
+        assert_eq!(linux.uid_mappings.unwrap()[0].container_id, 0);
+
+        assert!(spec(&ImageConfig::default(), false).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+//! OCI image layout: the `blobs/sha256` content store and the `index.json` of
+//! the images, named by their `org.opencontainers.image.ref.name` annotation.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::core::common::{Error, Result};
+
+pub const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+pub const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const DOCKER_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const DOCKER_MANIFEST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.v2+json";
+/// Annotation naming an image of the index
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+const LAYOUT_VERSION: &str = "1.0.0";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// Index of the layout, or of a multi-platform image
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Index {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+/// Configuration of an image, the part of it a bundle is made of
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(default)]
+    pub os: String,
+    pub config: Option<ExecConfig>,
+}
+
+/// Defaults of the process of the containers of the image
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ExecConfig {
+    pub user: Option<String>,
+    pub env: Option<Vec<String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    pub labels: Option<HashMap<String, String>>,
+}
+
+/// Manifest and configuration of an image of a layout
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub manifest: Manifest,
+    pub config: ImageConfig,
+}
+
+/// Platform of the runtime, in the terms of the image specs
+pub fn host_platform() -> Platform {
+    let architecture = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "powerpc64" => "ppc64",
+        "mips64" if cfg!(target_endian = "little") => "mips64le",
+        "loongarch64" => "loong64",
+        // arm, riscv64 and s390x are named the same
+        arch => arch,
+    };
+    Platform {
+        architecture: architecture.to_string(),
+        os: String::from("linux"),
+        variant: None,
+    }
+}
+
+/// Manifest of the index for the host platform
+pub fn select_platform(index: &Index) -> Result<&Descriptor> {
+    let host = host_platform();
+    index
+        .manifests
+        .iter()
+        .find(|manifest| {
+            manifest.platform.as_ref().is_some_and(|platform| {
+                platform.os == host.os && platform.architecture == host.architecture
+            })
+        })
+        .ok_or_else(|| {
+            Error::Runtime(format!(
+                "no image for {}/{} in the index",
+                host.os, host.architecture
+            ))
+        })
+}
+
+/// Checks the digest is a sha256 one, the only algorithm of the layouts
+pub fn validate_digest(digest: &str) -> Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(hex),
+        _ => Err(Error::Runtime(format!("unsupported digest {}", digest))),
+    }
+}
+
+pub struct Layout {
+    path: PathBuf,
+}
+
+impl Layout {
+    /// Opens the layout, created if missing
+    pub fn create(path: &Path) -> Result<Layout> {
+        let blobs = path.join("blobs").join("sha256");
+        std::fs::create_dir_all(&blobs)
+            .map_err(Error::io(format!("unable to create {:?}", blobs)))?;
+        let layout = Layout {
+            path: path.to_path_buf(),
+        };
+        if !path.join("oci-layout").exists() {
+            std::fs::write(
+                path.join("oci-layout"),
+                format!("{{\"imageLayoutVersion\":\"{}\"}}", LAYOUT_VERSION),
+            )
+            .map_err(Error::io(format!("unable to write the oci-layout of {:?}", path)))?;
+        }
+        if !path.join("index.json").exists() {
+            layout.save_index(&Index {
+                schema_version: 2,
+                media_type: Some(INDEX_MEDIA_TYPE.to_string()),
+                manifests: Vec::new(),
+            })?;
+        }
+        Ok(layout)
+    }
+
+    /// Opens an existing layout
+    pub fn open(path: &Path) -> Result<Layout> {
+        if !path.join("oci-layout").exists() {
+            return Err(Error::Runtime(format!("{:?} isn't an OCI image layout", path)));
+        }
+        Ok(Layout {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn index(&self) -> Result<Index> {
+        let path = self.path.join("index.json");
+        let index = std::fs::read_to_string(&path)
+            .map_err(Error::io(format!("unable to read {:?}", path)))?;
+        serde_json::from_str(&index).map_err(Error::json(format!("invalid index {:?}", path)))
+    }
+
+    fn save_index(&self, index: &Index) -> Result<()> {
+        let json = serde_json::to_string_pretty(index)
+            .map_err(Error::json("unable to serialize the index"))?;
+        let path = self.path.join("index.json");
+        let tmp = self.path.join("index.json.tmp");
+        std::fs::write(&tmp, json)
+            .and_then(|_| std::fs::rename(&tmp, &path))
+            .map_err(Error::io(format!("unable to write {:?}", path)))
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let hex = validate_digest(digest)?;
+        Ok(self.path.join("blobs").join("sha256").join(hex))
+    }
+
+    pub fn has_blob(&self, digest: &str) -> bool {
+        self.blob_path(digest).is_ok_and(|path| path.exists())
+    }
+
+    pub fn read_blob(&self, digest: &str) -> Result<Vec<u8>> {
+        let path = self.blob_path(digest)?;
+        std::fs::read(&path).map_err(Error::io(format!("unable to read blob {}", digest)))
+    }
+
+    /// Stores the content, returns its digest
+    pub fn write_blob(&self, content: &[u8]) -> Result<String> {
+        let digest = format!("sha256:{:x}", Sha256::digest(content));
+        let path = self.blob_path(&digest)?;
+        if !path.exists() {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, content)
+                .and_then(|_| std::fs::rename(&tmp, &path))
+                .map_err(Error::io(format!("unable to write blob {}", digest)))?;
+        }
+        Ok(digest)
+    }
+
+    /// Moves the file into the store once its content matches the digest
+    pub fn import_blob(&self, file: &Path, digest: &str) -> Result<()> {
+        let actual = file_digest(file)?;
+        if actual != digest {
+            let _ = std::fs::remove_file(file);
+            return Err(Error::Runtime(format!(
+                "digest mismatch, expected {} and got {}",
+                digest, actual
+            )));
+        }
+        let path = self.blob_path(digest)?;
+        std::fs::rename(file, &path)
+            .or_else(|_| std::fs::copy(file, &path).map(|_| ()))
+            .map_err(Error::io(format!("unable to store blob {}", digest)))
+    }
+
+    /// Stores the serialized value, returns its descriptor
+    pub fn write_json<T: Serialize>(&self, value: &T, media_type: &str) -> Result<Descriptor> {
+        let json = serde_json::to_vec(value).map_err(Error::json("unable to serialize blob"))?;
+        Ok(Descriptor {
+            media_type: media_type.to_string(),
+            digest: self.write_blob(&json)?,
+            size: json.len() as u64,
+            ..Default::default()
+        })
+    }
+
+    pub fn read_json<T: for<'de> Deserialize<'de>>(&self, digest: &str) -> Result<T> {
+        let blob = self.read_blob(digest)?;
+        serde_json::from_slice(&blob).map_err(Error::json(format!("invalid blob {}", digest)))
+    }
+
+    /// Names the manifest in the index, replacing the image of the same name
+    pub fn tag(&self, name: &str, manifest: &Descriptor) -> Result<()> {
+        let mut index = self.index()?;
+        index.manifests.retain(|descriptor| ref_name(descriptor) != Some(name));
+        let mut manifest = manifest.clone();
+        manifest
+            .annotations
+            .get_or_insert_with(HashMap::new)
+            .insert(REF_NAME_ANNOTATION.to_string(), name.to_string());
+        index.manifests.push(manifest);
+        self.save_index(&index)
+    }
+
+    /// Manifest of the image of the name, or of the only image without one.
+    /// A multi-platform image resolves to the manifest of the host platform.
+    pub fn resolve(&self, name: Option<&str>) -> Result<Descriptor> {
+        let index = self.index()?;
+        let descriptor = match name {
+            Some(name) => index
+                .manifests
+                .iter()
+                .find(|descriptor| ref_name(descriptor) == Some(name))
+                .ok_or_else(|| Error::Runtime(format!("no image {} in {:?}", name, self.path)))?,
+            None => match index.manifests.as_slice() {
+                [descriptor] => descriptor,
+                _ => {
+                    return Err(Error::Runtime(format!(
+                        "{:?} holds {} images, name one",
+                        self.path,
+                        index.manifests.len()
+                    )))
+                }
+            },
+        };
+        self.platform_manifest(descriptor.clone())
+    }
+
+    fn platform_manifest(&self, descriptor: Descriptor) -> Result<Descriptor> {
+        match descriptor.media_type.as_str() {
+            INDEX_MEDIA_TYPE | DOCKER_LIST_MEDIA_TYPE => {
+                let index: Index = self.read_json(&descriptor.digest)?;
+                self.platform_manifest(select_platform(&index)?.clone())
+            }
+            _ => Ok(descriptor),
+        }
+    }
+
+    pub fn image(&self, manifest: &Descriptor) -> Result<Image> {
+        let manifest: Manifest = self.read_json(&manifest.digest)?;
+        let config = self.read_json(&manifest.config.digest)?;
+        Ok(Image { manifest, config })
+    }
+
+    /// Copies the image into the layout, the blobs already there are kept
+    pub fn copy_from(&self, source: &Layout, manifest: &Descriptor) -> Result<()> {
+        let image: Manifest = source.read_json(&manifest.digest)?;
+        let digests = image
+            .layers
+            .iter()
+            .map(|layer| &layer.digest)
+            .chain([&image.config.digest, &manifest.digest]);
+        for digest in digests {
+            if !self.has_blob(digest) {
+                std::fs::copy(source.blob_path(digest)?, self.blob_path(digest)?)
+                    .map_err(Error::io(format!("unable to copy blob {}", digest)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ref_name(descriptor: &Descriptor) -> Option<&str> {
+    descriptor
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+        .map(String::as_str)
+}
+
+pub fn file_digest(path: &Path) -> Result<String> {
+    let mut file = File::open(path).map_err(Error::io(format!("unable to open {:?}", path)))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .map_err(Error::io(format!("unable to read {:?}", path)))?;
+        if read == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..read]).unwrap();
+    }
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{
+        Descriptor, ImageConfig, Layout, Manifest, CONFIG_MEDIA_TYPE, LAYER_MEDIA_TYPE,
+        MANIFEST_MEDIA_TYPE,
+    };
+
+    #[test]
+    fn tag_and_resolve() {
+        let path = Path::new("./layout_test");
+        let layout = Layout::create(path).unwrap();
+
+        let layer = layout.write_blob(b"layer").unwrap();
+        assert_eq!(
+            layer,
+            "sha256:dac1d7cfa95021764849fd102524e141488c5e3a90f861dbb5a12d9ac8584f85"
+        );
+        assert_eq!(layout.read_blob(&layer).unwrap(), b"layer");
+        let config = layout
+            .write_json(&ImageConfig::default(), CONFIG_MEDIA_TYPE)
+            .unwrap();
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MANIFEST_MEDIA_TYPE.to_string()),
+            config,
+            layers: vec![Descriptor {
+                media_type: LAYER_MEDIA_TYPE.to_string(),
+                digest: layer,
+                size: 5,
+                ..Default::default()
+            }],
+        };
+        let descriptor = layout.write_json(&manifest, MANIFEST_MEDIA_TYPE).unwrap();
+
+        // The only image
+        assert!(layout.resolve(Some("alpine")).is_err());
+        layout.tag("alpine", &descriptor).unwrap();
+        layout.tag("alpine", &descriptor).unwrap();
+        assert_eq!(layout.index().unwrap().manifests.len(), 1);
+        let resolved = layout.resolve(None).unwrap();
+        assert_eq!(resolved.digest, descriptor.digest);
+        assert_eq!(layout.image(&resolved).unwrap().manifest, manifest);
+
+        layout.tag("busybox", &descriptor).unwrap();
+        assert!(layout.resolve(None).is_err());
+        assert_eq!(layout.resolve(Some("busybox")).unwrap().digest, descriptor.digest);
+
+        assert!(layout.blob_path("sha256:../../etc").is_err());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
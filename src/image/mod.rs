@@ -0,0 +1,320 @@
+//! Images pulled from registries, OCI layouts and docker archives into an
+//! image store, itself an OCI layout, and turned into bundles.
+
+pub mod bundle;
+pub mod layout;
+pub mod registry;
+pub mod unpack;
+
+use log::info;
+use nix::unistd::geteuid;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tar::Archive;
+
+use crate::core::common::{Error, Result};
+use layout::{
+    file_digest, Descriptor, Layout, Manifest, CONFIG_MEDIA_TYPE, LAYER_MEDIA_TYPE,
+    MANIFEST_MEDIA_TYPE,
+};
+use registry::Reference;
+
+/// Image store of root
+pub const IMAGES_PATH: &str = "/var/lib/pura/images";
+const OCI_PREFIX: &str = "oci:";
+const DOCKER_ARCHIVE_PREFIX: &str = "docker-archive:";
+
+/// Default image store: `/var/lib/pura/images` as root,
+/// `$XDG_DATA_HOME/pura/images` otherwise
+pub fn default_store() -> Result<PathBuf> {
+    if geteuid().is_root() {
+        return Ok(PathBuf::from(IMAGES_PATH));
+    }
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .filter(|data| !data.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+        })
+        .ok_or_else(|| {
+            Error::Runtime("neither XDG_DATA_HOME nor HOME is set, pura needs --images".to_string())
+        })?;
+    Ok(data.join("pura").join("images"))
+}
+
+/// Where an image comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Registry(Reference),
+    /// `oci:PATH[:NAME]`, an OCI layout and the name of the image in it
+    Layout(PathBuf, Option<String>),
+    /// `docker-archive:PATH`, the output of `docker save`
+    DockerArchive(PathBuf),
+}
+
+impl Source {
+    pub fn parse(source: &str) -> Result<Source> {
+        if let Some(layout) = source.strip_prefix(OCI_PREFIX) {
+            let (path, name) = parse_image(layout);
+            return Ok(Source::Layout(path, name));
+        }
+        if let Some(archive) = source.strip_prefix(DOCKER_ARCHIVE_PREFIX) {
+            return Ok(Source::DockerArchive(PathBuf::from(archive)));
+        }
+        Reference::parse(source).map(Source::Registry)
+    }
+}
+
+/// Layout and image name of `PATH[:NAME]`, the name being a reference or
+/// the name of an image of the layout
+pub fn parse_image(image: &str) -> (PathBuf, Option<String>) {
+    match image.split_once(':') {
+        Some((path, name)) if !Path::new(image).exists() => {
+            (PathBuf::from(path), Some(name.to_string()))
+        }
+        _ => (PathBuf::from(image), None),
+    }
+}
+
+/// Image pulled into the store
+#[derive(Debug, Clone)]
+pub struct Pulled {
+    pub name: String,
+    pub manifest: Descriptor,
+}
+
+/// Pulls the image into the store. An image of a layout keeps its name, or
+/// is named after the directory of the layout.
+pub fn pull(store: &Path, source: &Source) -> Result<Pulled> {
+    let layout = Layout::create(store)?;
+    let (name, manifest) = match source {
+        Source::Registry(reference) => {
+            info!("pulling {}", reference.name());
+            (reference.name(), registry::pull(&layout, reference)?)
+        }
+        Source::Layout(path, name) => {
+            let source = Layout::open(path)?;
+            let manifest = source.resolve(name.as_deref())?;
+            layout.copy_from(&source, &manifest)?;
+            let name = match name {
+                Some(name) => name.clone(),
+                None => path
+                    .canonicalize()
+                    .map_err(Error::io(format!("unable to resolve {:?}", path)))?
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| String::from("image")),
+            };
+            layout.tag(&name, &manifest)?;
+            (name, manifest)
+        }
+        Source::DockerArchive(path) => import_docker_archive(&layout, path)?,
+    };
+    Ok(Pulled { name, manifest })
+}
+
+/// Manifest of the image of the store, by its name or its reference
+pub fn find(store: &Path, name: &str) -> Result<Descriptor> {
+    let layout = Layout::open(store)?;
+    layout.resolve(Some(name)).or_else(|err| {
+        match Reference::parse(name) {
+            Ok(reference) if reference.name() != name => layout.resolve(Some(&reference.name())),
+            _ => Err(err),
+        }
+    })
+}
+
+/// Creates the bundle from the image `PATH[:NAME]` of an OCI layout, the
+/// only image of the layout without a name
+pub fn create_bundle(image: &str, bundle: &Path) -> Result<()> {
+    let (path, name) = parse_image(image);
+    let manifest = match &name {
+        Some(name) => find(&path, name)?,
+        None => Layout::open(&path)?.resolve(None)?,
+    };
+    bundle::create(&Layout::open(&path)?, &manifest, bundle)
+}
+
+/// Entry of the manifest.json of `docker save`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// Imports the first image of the archive, named after its first tag
+fn import_docker_archive(layout: &Layout, path: &Path) -> Result<(String, Descriptor)> {
+    let file = File::open(path).map_err(Error::io(format!("unable to open {:?}", path)))?;
+    let extracted = layout.path().join("archive.partial");
+    let _ = std::fs::remove_dir_all(&extracted);
+    std::fs::create_dir_all(&extracted)
+        .map_err(Error::io(format!("unable to create {:?}", extracted)))?;
+    let result = Archive::new(file)
+        .unpack(&extracted)
+        .map_err(Error::io(format!("unable to extract {:?}", path)))
+        .and_then(|_| import_extracted(layout, &extracted, path));
+    let _ = std::fs::remove_dir_all(&extracted);
+    result
+}
+
+fn import_extracted(layout: &Layout, extracted: &Path, path: &Path) -> Result<(String, Descriptor)> {
+    let manifest_path = extracted.join("manifest.json");
+    let content = std::fs::read(&manifest_path)
+        .map_err(Error::io(format!("{:?} isn't a docker archive", path)))?;
+    let manifests: Vec<ArchiveManifest> = serde_json::from_slice(&content)
+        .map_err(Error::json(format!("invalid manifest.json in {:?}", path)))?;
+    let archived = manifests
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Runtime(format!("no image in {:?}", path)))?;
+
+    // Relative paths of the archive, checked not to leave it
+    let import = |file: &str, media_type: &str| -> Result<Descriptor> {
+        if file.split('/').any(|part| part == "..") {
+            return Err(Error::Runtime(format!("invalid path {} in {:?}", file, path)));
+        }
+        let file = extracted.join(file);
+        let digest = file_digest(&file)?;
+        let size = std::fs::metadata(&file)
+            .map_err(Error::io(format!("unable to stat {:?}", file)))?
+            .len();
+        let media_type = match media_type == LAYER_MEDIA_TYPE && gzipped(&file) {
+            true => format!("{}+gzip", LAYER_MEDIA_TYPE),
+            false => media_type.to_string(),
+        };
+        if !layout.has_blob(&digest) {
+            layout.import_blob(&file, &digest)?;
+        }
+        Ok(Descriptor {
+            media_type,
+            digest,
+            size,
+            ..Default::default()
+        })
+    };
+    let manifest = Manifest {
+        schema_version: 2,
+        media_type: Some(MANIFEST_MEDIA_TYPE.to_string()),
+        config: import(&archived.config, CONFIG_MEDIA_TYPE)?,
+        layers: archived
+            .layers
+            .iter()
+            .map(|layer| import(layer, LAYER_MEDIA_TYPE))
+            .collect::<Result<_>>()?,
+    };
+    let descriptor = layout.write_json(&manifest, MANIFEST_MEDIA_TYPE)?;
+
+    let name = match archived.repo_tags.unwrap_or_default().first() {
+        Some(tag) => Reference::parse(tag)?.name(),
+        None => path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("image")),
+    };
+    layout.tag(&name, &descriptor)?;
+    Ok((name, descriptor))
+}
+
+fn gzipped(path: &Path) -> bool {
+    let mut magic = [0; 2];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && magic == [0x1f, 0x8b]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use tar::{Builder, Header};
+
+    use super::{create_bundle, find, pull, Source};
+
+    fn append(builder: &mut Builder<Vec<u8>>, name: &str, content: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content).unwrap();
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(
+            Source::parse("oci:/srv/images:alpine").unwrap(),
+            Source::Layout(PathBuf::from("/srv/images"), Some(String::from("alpine")))
+        );
+        assert_eq!(
+            Source::parse("oci:./layout").unwrap(),
+            Source::Layout(PathBuf::from("./layout"), None)
+        );
+        assert_eq!(
+            Source::parse("docker-archive:alpine.tar").unwrap(),
+            Source::DockerArchive(PathBuf::from("alpine.tar"))
+        );
+        assert_eq!(
+            Source::parse("oci:/srv/images:docker.io/library/alpine:3.19").unwrap(),
+            Source::Layout(
+                PathBuf::from("/srv/images"),
+                Some(String::from("docker.io/library/alpine:3.19"))
+            )
+        );
+        assert!(matches!(
+            Source::parse("alpine:3.19").unwrap(),
+            Source::Registry(_)
+        ));
+    }
+
+    #[test]
+    fn docker_archive_to_bundle() {
+        let dir = Path::new("./image_test");
+        std::fs::create_dir_all(dir).unwrap();
+
+        let mut layer = Builder::new(Vec::new());
+        append(&mut layer, "bin/hello", b"#!/bin/sh\necho hello\n");
+        let layer = layer.into_inner().unwrap();
+        let config = br#"{"architecture":"amd64","os":"linux","config":{"Cmd":["/bin/hello"],"WorkingDir":"/bin"}}"#;
+        let mut archive = Builder::new(Vec::new());
+        append(&mut archive, "abc/layer.tar", &layer);
+        append(&mut archive, "abc.json", config);
+        append(
+            &mut archive,
+            "manifest.json",
+            br#"[{"Config":"abc.json","RepoTags":["hello:1.0"],"Layers":["abc/layer.tar"]}]"#,
+        );
+        std::fs::write(dir.join("hello.tar"), archive.into_inner().unwrap()).unwrap();
+
+        let store = dir.join("images");
+        let source = Source::parse("docker-archive:./image_test/hello.tar").unwrap();
+        let pulled = pull(&store, &source).unwrap();
+        assert_eq!(pulled.name, "docker.io/library/hello:1.0");
+        assert_eq!(find(&store, "hello:1.0").unwrap().digest, pulled.manifest.digest);
+        assert!(find(&store, "hello:2.0").is_err());
+
+        // Copied from the store as another layout
+        let copy = dir.join("copy");
+        let source = Source::Layout(store.clone(), Some(pulled.name.clone()));
+        let copied = pull(&copy, &source).unwrap();
+        assert_eq!(copied.manifest.digest, pulled.manifest.digest);
+
+        let bundle_path = dir.join("bundle");
+        let image = format!("{}:hello:1.0", copy.display());
+        create_bundle(&image, &bundle_path).unwrap();
+        assert!(bundle_path.join("rootfs/bin/hello").exists());
+        let config = std::fs::read_to_string(bundle_path.join("config.json")).unwrap();
+        assert!(config.contains("\"/bin/hello\""));
+        assert!(config.contains("\"cwd\": \"/bin\""));
+        assert!(create_bundle(&image, &bundle_path).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,438 @@
+//! Anonymous pulls from the distribution API of OCI registries, over the
+//! curl of the host.
+
+use log::debug;
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+use crate::core::common::{Error, Result};
+use crate::image::layout::{
+    file_digest, select_platform, Descriptor, Index, Layout, Manifest, DOCKER_LIST_MEDIA_TYPE,
+    DOCKER_MANIFEST_MEDIA_TYPE, INDEX_MEDIA_TYPE, MANIFEST_MEDIA_TYPE,
+};
+
+const CURL_BINARY: &str = "curl";
+const DEFAULT_REGISTRY: &str = "docker.io";
+/// Host serving the API of the default registry
+const DEFAULT_REGISTRY_HOST: &str = "registry-1.docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+/// Image of a registry, `[REGISTRY/]REPOSITORY[:TAG][@DIGEST]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    /// Parses the reference like docker does: without a registry it's an
+    /// image of Docker Hub, official images being under `library/`
+    pub fn parse(reference: &str) -> Result<Reference> {
+        let invalid = || Error::Runtime(format!("invalid image reference {:?}", reference));
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.to_string())),
+            None => (reference, None),
+        };
+        // The tag follows the last `:` unless it's the port of the registry
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+            _ => (name, DEFAULT_TAG.to_string()),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((registry, repository))
+                if registry.contains('.') || registry.contains(':') || registry == "localhost" =>
+            {
+                (registry.to_string(), repository.to_string())
+            }
+            _ => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+        };
+        if repository.is_empty() {
+            return Err(invalid());
+        }
+        let repository = match registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            true => format!("library/{}", repository),
+            false => repository,
+        };
+        let valid = |part: &str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-/".contains(c))
+        };
+        if !valid(&repository) || !valid(&tag) || repository.contains("..") {
+            return Err(invalid());
+        }
+        Ok(Reference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// Full name of the image, its name in the image store
+    pub fn name(&self) -> String {
+        match &self.digest {
+            Some(digest) => format!("{}/{}@{}", self.registry, self.repository, digest),
+            None => format!("{}/{}:{}", self.registry, self.repository, self.tag),
+        }
+    }
+
+    fn base_url(&self) -> String {
+        let host = match self.registry.as_str() {
+            DEFAULT_REGISTRY => DEFAULT_REGISTRY_HOST,
+            registry => registry,
+        };
+        // Local registries rarely have a certificate
+        let scheme = match host.starts_with("localhost") || host.starts_with("127.0.0.1") {
+            true => "http",
+            false => "https",
+        };
+        format!("{}://{}/v2/{}", scheme, host, self.repository)
+    }
+}
+
+struct Response {
+    status: u32,
+    headers: HashMap<String, String>,
+}
+
+/// Session with the registry, holding the bearer token once authenticated
+struct Registry<'a> {
+    reference: &'a Reference,
+    token: Option<String>,
+}
+
+impl Registry<'_> {
+    /// Downloads the url into the file, authenticating on the first 401
+    fn fetch(&mut self, url: &str, accept: &[&str], output: &Path) -> Result<Response> {
+        let response = self.get(url, accept, output)?;
+        if response.status != 401 || self.token.is_some() {
+            return Ok(response);
+        }
+        let challenge = response
+            .headers
+            .get("www-authenticate")
+            .ok_or_else(|| Error::Runtime(format!("{} requires authentication", url)))?;
+        self.token = Some(self.authenticate(challenge)?);
+        self.get(url, accept, output)
+    }
+
+    fn get(&self, url: &str, accept: &[&str], output: &Path) -> Result<Response> {
+        let headers_file = output.with_extension("headers");
+        let mut command = Command::new(CURL_BINARY);
+        command
+            .args(["-sS", "-L", "-w", "%{http_code}", "-o"])
+            .arg(output)
+            .arg("-D")
+            .arg(&headers_file);
+        for media_type in accept {
+            command.arg("-H").arg(format!("Accept: {}", media_type));
+        }
+        // Through stdin, the command line of a process is readable by every user
+        let input = match &self.token {
+            Some(token) => {
+                command.args(["-H", "@-"]);
+                format!("Authorization: Bearer {}\n", token)
+            }
+            None => String::new(),
+        };
+        debug!("fetching {}", url);
+        let out = run(command.arg("--").arg(url), &input)?;
+        let headers = std::fs::read_to_string(&headers_file).unwrap_or_default();
+        let _ = std::fs::remove_file(&headers_file);
+        if !out.status.success() {
+            return Err(Error::Runtime(format!(
+                "unable to fetch {}: {}",
+                url,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )));
+        }
+        let status = String::from_utf8_lossy(&out.stdout)
+            .trim()
+            .parse()
+            .map_err(|_| Error::Runtime(format!("invalid response of {}", url)))?;
+        Ok(Response {
+            status,
+            headers: parse_headers(&headers),
+        })
+    }
+
+    /// Gets an anonymous pull token from the realm of the bearer challenge
+    fn authenticate(&self, challenge: &str) -> Result<String> {
+        let params = parse_challenge(challenge)
+            .ok_or_else(|| Error::Runtime(format!("unsupported authentication {}", challenge)))?;
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| Error::Runtime(format!("no realm in {}", challenge)))?;
+        check_realm(realm)?;
+        let mut command = Command::new(CURL_BINARY);
+        command.args(["-sS", "-f", "-G"]);
+        if let Some(service) = params.get("service") {
+            command
+                .arg("--data-urlencode")
+                .arg(format!("service={}", service));
+        }
+        let scope = params
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| format!("repository:{}:pull", self.reference.repository));
+        command.arg("--data-urlencode").arg(format!("scope={}", scope));
+        // The realm comes from the registry, it must never be taken for an option
+        let out = run(command.arg("--").arg(realm), "")?;
+        if !out.status.success() {
+            return Err(Error::Runtime(format!(
+                "unable to get a token from {}: {}",
+                realm,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )));
+        }
+        let body: serde_json::Value = serde_json::from_slice(&out.stdout)
+            .map_err(Error::json(format!("invalid token of {}", realm)))?;
+        body.get("token")
+            .or_else(|| body.get("access_token"))
+            .and_then(|token| token.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| Error::Runtime(format!("no token from {}", realm)))
+    }
+
+    fn manifest(&mut self, reference: &str, output: &Path) -> Result<Response> {
+        let url = format!("{}/manifests/{}", self.reference.base_url(), reference);
+        let accept = [
+            INDEX_MEDIA_TYPE,
+            MANIFEST_MEDIA_TYPE,
+            DOCKER_LIST_MEDIA_TYPE,
+            DOCKER_MANIFEST_MEDIA_TYPE,
+        ];
+        let response = self.fetch(&url, &accept, output)?;
+        if response.status != 200 {
+            return Err(Error::Runtime(format!(
+                "unable to get manifest {} of {}: HTTP {}",
+                reference,
+                self.reference.name(),
+                response.status
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Downloads the blob into the layout unless it's already there
+    fn blob(&mut self, layout: &Layout, digest: &str) -> Result<()> {
+        if layout.has_blob(digest) {
+            return Ok(());
+        }
+        let partial = layout.blob_path(digest)?.with_extension("partial");
+        let url = format!("{}/blobs/{}", self.reference.base_url(), digest);
+        let response = self.fetch(&url, &[], &partial)?;
+        if response.status != 200 {
+            let _ = std::fs::remove_file(&partial);
+            return Err(Error::Runtime(format!(
+                "unable to get blob {}: HTTP {}",
+                digest, response.status
+            )));
+        }
+        layout.import_blob(&partial, digest)
+    }
+}
+
+/// Pulls the image of the host platform into the layout, named by the full
+/// reference. Returns its manifest.
+pub fn pull(layout: &Layout, reference: &Reference) -> Result<Descriptor> {
+    let mut registry = Registry {
+        reference,
+        token: None,
+    };
+    let partial = layout.path().join("manifest.partial");
+    let tag = reference.digest.as_ref().unwrap_or(&reference.tag).clone();
+    let mut response = registry.manifest(&tag, &partial)?;
+    let mut digest = file_digest(&partial)?;
+    if let Some(expected) = &reference.digest {
+        if *expected != digest {
+            let _ = std::fs::remove_file(&partial);
+            return Err(Error::Runtime(format!(
+                "digest mismatch, expected {} and got {}",
+                expected, digest
+            )));
+        }
+    }
+
+    let mut media_type = content_type(&response);
+    if media_type == INDEX_MEDIA_TYPE || media_type == DOCKER_LIST_MEDIA_TYPE {
+        let index: Index = read_json(&partial)?;
+        let manifest = select_platform(&index)?.clone();
+        response = registry.manifest(&manifest.digest, &partial)?;
+        digest = file_digest(&partial)?;
+        if digest != manifest.digest {
+            let _ = std::fs::remove_file(&partial);
+            return Err(Error::Runtime(format!(
+                "digest mismatch, expected {} and got {}",
+                manifest.digest, digest
+            )));
+        }
+        media_type = content_type(&response);
+    }
+
+    let manifest: Manifest = read_json(&partial)?;
+    let size = std::fs::metadata(&partial)
+        .map_err(Error::io(format!("unable to stat {:?}", partial)))?
+        .len();
+    for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+        registry.blob(layout, &descriptor.digest)?;
+    }
+    layout.import_blob(&partial, &digest)?;
+
+    let descriptor = Descriptor {
+        media_type,
+        digest,
+        size,
+        ..Default::default()
+    };
+    layout.tag(&reference.name(), &descriptor)?;
+    Ok(descriptor)
+}
+
+/// Runs curl with the input on its stdin
+fn run(command: &mut Command, input: &str) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::io(format!("unable to run {}", CURL_BINARY)))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin
+        .write_all(input.as_bytes())
+        .map_err(Error::io(format!("unable to write to {}", CURL_BINARY)))?;
+    drop(stdin);
+    child
+        .wait_with_output()
+        .map_err(Error::io(format!("unable to run {}", CURL_BINARY)))
+}
+
+/// Checks the realm of a challenge is an https URL, or an http one of a local
+/// registry like the ones pulled from over http
+fn check_realm(realm: &str) -> Result<()> {
+    let invalid = || Error::Runtime(format!("invalid authentication realm {:?}", realm));
+    let (scheme, rest) = realm.split_once("://").ok_or_else(invalid)?;
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let local = host.starts_with("localhost") || host.starts_with("127.0.0.1");
+    let valid_host = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c));
+    match scheme {
+        "https" if valid_host => (),
+        "http" if valid_host && local => (),
+        _ => return Err(invalid()),
+    }
+    if realm.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+fn content_type(response: &Response) -> String {
+    response
+        .headers
+        .get("content-type")
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim().to_string())
+        .unwrap_or_else(|| MANIFEST_MEDIA_TYPE.to_string())
+}
+
+fn read_json<T: for<'de> serde::Deserialize<'de>>(path: &Path) -> Result<T> {
+    let content = std::fs::read(path).map_err(Error::io(format!("unable to read {:?}", path)))?;
+    serde_json::from_slice(&content).map_err(Error::json(format!("invalid manifest {:?}", path)))
+}
+
+/// Headers of the last response, the redirects followed
+fn parse_headers(headers: &str) -> HashMap<String, String> {
+    let last = headers
+        .rsplit("HTTP/")
+        .next()
+        .unwrap_or_default();
+    last.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+/// Parameters of a `Bearer realm="...",service="...",scope="..."` challenge
+fn parse_challenge(challenge: &str) -> Option<HashMap<String, String>> {
+    let params = challenge.strip_prefix("Bearer ")?;
+    let mut parsed = HashMap::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.strip_prefix('"')?;
+        let end = value.find('"')?;
+        parsed.insert(key.trim().to_string(), value[..end].to_string());
+        rest = value[end + 1..].trim_start_matches(',').trim();
+    }
+    Some(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_realm, parse_challenge, parse_headers, Reference};
+
+    #[test]
+    fn references() {
+        let alpine = Reference::parse("alpine").unwrap();
+        assert_eq!(alpine.name(), "docker.io/library/alpine:latest");
+        assert_eq!(
+            alpine.base_url(),
+            "https://registry-1.docker.io/v2/library/alpine"
+        );
+
+        let reference = Reference::parse("localhost:5000/team/app:1.2").unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "team/app");
+        assert_eq!(reference.tag, "1.2");
+        assert_eq!(reference.base_url(), "http://localhost:5000/v2/team/app");
+
+        let reference = Reference::parse("ghcr.io/org/tool@sha256:abc").unwrap();
+        assert_eq!(reference.digest.as_deref(), Some("sha256:abc"));
+        assert_eq!(reference.name(), "ghcr.io/org/tool@sha256:abc");
+        assert_eq!(
+            Reference::parse("user/app:v1").unwrap().name(),
+            "docker.io/user/app:v1"
+        );
+
+        assert!(Reference::parse("").is_err());
+        assert!(Reference::parse("UPPER CASE").is_err());
+        assert!(Reference::parse("quay.io/../etc").is_err());
+    }
+
+    #[test]
+    fn authentication() {
+        let headers = "HTTP/1.1 307 Temporary Redirect\r\nLocation: /x\r\n\r\n\
+                       HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\n\
+                       Www-Authenticate: Bearer realm=\"https://auth.docker.io/token\",\
+                       service=\"registry.docker.io\",scope=\"repository:library/alpine:pull\"\r\n\r\n";
+        let headers = parse_headers(headers);
+        assert_eq!(headers["content-type"], "application/json");
+        assert!(!headers.contains_key("location"));
+
+        let params = parse_challenge(&headers["www-authenticate"]).unwrap();
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+        assert!(parse_challenge("Basic realm=\"registry\"").is_none());
+
+        assert!(check_realm("https://auth.docker.io/token").is_ok());
+        assert!(check_realm("http://localhost:5000/token").is_ok());
+        assert!(check_realm("http://auth.example.com/token").is_err());
+        assert!(check_realm("-o/etc/passwd").is_err());
+        assert!(check_realm("--config=/tmp/curlrc").is_err());
+        assert!(check_realm("file:///etc/shadow").is_err());
+        assert!(check_realm("https://evil@-K/x").is_err());
+        assert!(check_realm("https://auth.example.com/token -o /x").is_err());
+    }
+}
@@ -0,0 +1,388 @@
+//! Extraction of the layers of an image into a rootfs, applying the whiteouts
+//! of each layer to the layers below it.
+
+use flate2::read::GzDecoder;
+use log::debug;
+use nix::{
+    libc,
+    unistd::{fchownat, geteuid, FchownatFlags, Gid, Uid},
+};
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{BufReader, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, EntryType};
+
+use crate::core::common::{Error, Result};
+use crate::image::layout::{Image, Layout};
+use crate::oci::spec::UidMapping;
+
+/// Prefix of the entries deleting the file of the same name from the layers below
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Entry hiding the whole content of its directory in the layers below
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// File capabilities, cleared by chown
+const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Extracts the layers of the image in order into the rootfs
+pub fn unpack(layout: &Layout, image: &Image, rootfs: &Path) -> Result<()> {
+    std::fs::create_dir_all(rootfs)
+        .map_err(Error::io(format!("unable to create rootfs {:?}", rootfs)))?;
+    for layer in &image.manifest.layers {
+        debug!("unpacking layer {}", layer.digest);
+        apply_layer(&layout.blob_path(&layer.digest)?, rootfs)?;
+    }
+    Ok(())
+}
+
+/// Extracts the layer, plain or gzip compressed tar, on top of the rootfs
+pub fn apply_layer(layer: &Path, rootfs: &Path) -> Result<()> {
+    // The whiteouts only hide the layers below, so they go first
+    let mut archive = open_layer(layer)?;
+    for entry in archive
+        .entries()
+        .map_err(Error::io(format!("unable to read layer {:?}", layer)))?
+    {
+        let entry = entry.map_err(Error::io(format!("unable to read layer {:?}", layer)))?;
+        let path = entry
+            .path()
+            .map_err(Error::io(format!("invalid entry in layer {:?}", layer)))?
+            .to_path_buf();
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if name == OPAQUE_WHITEOUT {
+            clear_dir(&resolve(rootfs, dir)?)?;
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            remove(&resolve(rootfs, &dir.join(hidden))?)?;
+        }
+    }
+
+    unpack_entries(layer, rootfs, None)
+}
+
+/// Id mappings of the user namespace of a container
+pub struct IdShift<'a> {
+    pub uids: &'a [UidMapping],
+    pub gids: &'a [UidMapping],
+}
+
+/// Extracts the tarball, plain or gzip compressed, into the rootfs. Shifted,
+/// the files are owned by the host ids their ids map to, so the container
+/// sees the owners of the tarball; rootless, only the ids mapping to the user
+/// can be given and the other files are the user's too.
+pub fn extract(tarball: &Path, rootfs: &Path, shift: Option<&IdShift>) -> Result<()> {
+    std::fs::create_dir_all(rootfs)
+        .map_err(Error::io(format!("unable to create rootfs {:?}", rootfs)))?;
+    unpack_entries(tarball, rootfs, shift)
+}
+
+fn unpack_entries(layer: &Path, rootfs: &Path, shift: Option<&IdShift>) -> Result<()> {
+    let root = geteuid().is_root();
+    let mut archive = open_layer(layer)?;
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(root && shift.is_none());
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(root);
+    archive.set_overwrite(true);
+    for entry in archive
+        .entries()
+        .map_err(Error::io(format!("unable to read layer {:?}", layer)))?
+    {
+        let mut entry = entry.map_err(Error::io(format!("unable to read layer {:?}", layer)))?;
+        let path = entry
+            .path()
+            .map_err(Error::io(format!("invalid entry in layer {:?}", layer)))?
+            .to_path_buf();
+        let whiteout = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(WHITEOUT_PREFIX));
+        let kind = entry.header().entry_type();
+        // Only root creates device nodes
+        if whiteout || (!root && (kind == EntryType::Char || kind == EntryType::Block)) {
+            continue;
+        }
+        // A file replacing a directory of a layer below, or the reverse
+        let target = resolve(rootfs, &path)?;
+        if let Ok(metadata) = std::fs::symlink_metadata(&target) {
+            if metadata.is_dir() != (kind == EntryType::Directory) {
+                remove(&target)?;
+            }
+        }
+        let unpacked = entry
+            .unpack_in(rootfs)
+            .map_err(Error::io(format!("unable to unpack {:?} of layer {:?}", path, layer)))?;
+        if let (true, Some(shift)) = (unpacked, shift) {
+            let header = entry.header();
+            let uid = header.uid().ok().and_then(|uid| shift_id(shift.uids, uid));
+            let gid = header.gid().ok().and_then(|gid| shift_id(shift.gids, gid));
+            match chown(&target, uid, gid) {
+                Err(_) if !root => (),
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Host id of the id in the mappings
+fn shift_id(mappings: &[UidMapping], id: u64) -> Option<u32> {
+    mappings
+        .iter()
+        .find(|mapping| {
+            id >= mapping.container_id as u64
+                && id < (mapping.container_id + mapping.size) as u64
+        })
+        .map(|mapping| (mapping.host_id as u64 + id - mapping.container_id as u64) as u32)
+}
+
+/// Changes the owner without following symlinks, keeping the file
+/// capabilities chown clears
+fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::Runtime(format!("invalid path {:?}", path)))?;
+    let name = CString::new(CAPABILITY_XATTR).unwrap();
+    let mut capabilities = vec![0u8; 256];
+    let size = unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            capabilities.as_mut_ptr() as *mut libc::c_void,
+            capabilities.len(),
+        )
+    };
+    fchownat(
+        None,
+        path,
+        uid.map(Uid::from_raw),
+        gid.map(Gid::from_raw),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .map_err(Error::sys(format!("unable to chown {:?}", path)))?;
+    if size > 0 {
+        let set = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                capabilities.as_ptr() as *const libc::c_void,
+                size as usize,
+                0,
+            )
+        };
+        if set != 0 {
+            return Err(Error::sys(format!("unable to restore the capabilities of {:?}", path))(
+                nix::Error::last(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn open_layer(layer: &Path) -> Result<Archive<Box<dyn Read>>> {
+    let mut file = File::open(layer).map_err(Error::io(format!("unable to open {:?}", layer)))?;
+    let mut magic = [0; 4];
+    let read = file
+        .read(&mut magic)
+        .map_err(Error::io(format!("unable to read {:?}", layer)))?;
+    let file = File::open(layer).map_err(Error::io(format!("unable to open {:?}", layer)))?;
+    let reader: Box<dyn Read> = if read >= 2 && magic[..2] == GZIP_MAGIC {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else if read == 4 && magic == ZSTD_MAGIC {
+        return Err(Error::Runtime(format!(
+            "zstd compressed layer {:?} isn't supported",
+            layer
+        )));
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    Ok(Archive::new(reader))
+}
+
+/// Path of the entry in the rootfs. Its parents must stay in the rootfs once
+/// their symlinks are followed.
+fn resolve(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let mut target = rootfs.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            Component::RootDir | Component::CurDir => (),
+            _ => return Err(Error::Runtime(format!("invalid layer entry {:?}", path))),
+        }
+    }
+    if let Some(parent) = target.parent().and_then(|parent| parent.canonicalize().ok()) {
+        let rootfs = rootfs
+            .canonicalize()
+            .map_err(Error::io(format!("unable to resolve {:?}", rootfs)))?;
+        if !parent.starts_with(&rootfs) {
+            return Err(Error::Runtime(format!(
+                "layer entry {:?} escapes the rootfs",
+                path
+            )));
+        }
+    }
+    Ok(target)
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    result.map_err(Error::io(format!("unable to remove {:?}", path)))
+}
+
+fn clear_dir(dir: &Path) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        remove(&entry.path())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use nix::unistd::geteuid;
+    use std::{io::Write, os::unix::fs::MetadataExt, path::Path};
+    use tar::{Builder, EntryType, Header};
+
+    use super::{apply_layer, extract, IdShift};
+    use crate::oci::spec::UidMapping;
+
+    fn layer(path: &Path, entries: &[(&str, Option<&str>)], gzip: bool) {
+        let mut builder = Builder::new(Vec::new());
+        for (name, content) in entries {
+            let mut header = Header::new_gnu();
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            match content {
+                Some(content) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(content.len() as u64);
+                    header.set_mode(0o644);
+                    header.set_cksum();
+                    builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+                }
+                None => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    header.set_cksum();
+                    builder.append_data(&mut header, name, &[][..]).unwrap();
+                }
+            }
+        }
+        let tar = builder.into_inner().unwrap();
+        if gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(&tar).unwrap();
+            std::fs::write(path, encoder.finish().unwrap()).unwrap();
+        } else {
+            std::fs::write(path, tar).unwrap();
+        }
+    }
+
+    #[test]
+    fn whiteouts() {
+        let dir = Path::new("./unpack_test");
+        let rootfs = dir.join("rootfs");
+        std::fs::create_dir_all(&rootfs).unwrap();
+
+        layer(
+            &dir.join("base.tar"),
+            &[
+                ("etc/", None),
+                ("etc/hostname", Some("base")),
+                ("etc/passwd", Some("root:x:0:0::/root:/bin/sh")),
+                ("var/", None),
+                ("var/cache/", None),
+                ("var/cache/apk", Some("index")),
+                ("opt", Some("a file")),
+            ],
+            true,
+        );
+        layer(
+            &dir.join("top.tar"),
+            &[
+                ("etc/.wh.hostname", Some("")),
+                ("etc/motd", Some("welcome")),
+                ("var/cache/.wh..wh..opq", Some("")),
+                ("var/cache/new", Some("new")),
+                ("opt/", None),
+                ("opt/app", Some("app")),
+            ],
+            false,
+        );
+        apply_layer(&dir.join("base.tar"), &rootfs).unwrap();
+        assert_eq!(std::fs::read_to_string(rootfs.join("etc/hostname")).unwrap(), "base");
+        apply_layer(&dir.join("top.tar"), &rootfs).unwrap();
+
+        assert!(!rootfs.join("etc/hostname").exists());
+        assert!(!rootfs.join("etc/.wh.hostname").exists());
+        assert!(rootfs.join("etc/passwd").exists());
+        assert_eq!(std::fs::read_to_string(rootfs.join("etc/motd")).unwrap(), "welcome");
+        assert!(!rootfs.join("var/cache/apk").exists());
+        assert!(rootfs.join("var/cache/new").exists());
+        assert!(!rootfs.join("var/cache/.wh..wh..opq").exists());
+        assert_eq!(std::fs::read_to_string(rootfs.join("opt/app")).unwrap(), "app");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shifted_owners() {
+        if !geteuid().is_root() {
+            return;
+        }
+        let dir = Path::new("./extract_test");
+        std::fs::create_dir_all(dir).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        for (name, uid) in [("root", 0), ("user", 1000), ("nobody", 70000)] {
+            let mut header = Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_uid(uid);
+            header.set_gid(uid);
+            header.set_mtime(0);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &[][..]).unwrap();
+        }
+        std::fs::write(dir.join("rootfs.tar"), builder.into_inner().unwrap()).unwrap();
+
+        let mappings = [UidMapping {
+            container_id: 0,
+            host_id: 100000,
+            size: 65536,
+            ..Default::default()
+        }];
+        let shift = IdShift {
+            uids: &mappings,
+            gids: &mappings,
+        };
+        let rootfs = dir.join("rootfs");
+        extract(&dir.join("rootfs.tar"), &rootfs, Some(&shift)).unwrap();
+        let owner = |name: &str| std::fs::metadata(rootfs.join(name)).unwrap().uid();
+        assert_eq!(owner("root"), 100000);
+        assert_eq!(owner("user"), 101000);
+        // Unmapped, left to the extracting user
+        assert_eq!(owner("nobody"), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,22 @@
+//! Lightweight, Rust-based and OCI-compliant container runtime.
+//!
+//! The [`Container`] type drives the OCI lifecycle (create, start, kill, delete
+//! and state) so the runtime can be embedded without shelling out to the `pura` binary.
+//! With the `async` feature, [`aio`] provides the same API on top of tokio.
+
+#[cfg(feature = "async")]
+pub mod aio;
+pub mod container;
+pub mod core;
+pub mod debug;
+pub mod format;
+#[cfg(feature = "image")]
+pub mod image;
+pub mod metrics;
+pub mod oci;
+pub mod top;
+pub mod watch;
+
+pub use crate::container::Container;
+pub use crate::core::state::{State, Status};
+pub use crate::oci::spec::Spec;
@@ -1,311 +1,795 @@
-mod core;
-mod oci;
-
-use std::convert::TryInto;
-use std::{convert::TryFrom, io::Write, path::Path};
-
-use crate::core::container::fork_container;
-use crate::core::hooks::exec_hook;
-use crate::core::ipc::IpcChannel;
-use crate::core::logger::ContainerLogger;
-use crate::core::state::State as ContainerState;
-
-use crate::core::{
-    common::{exit, exit_msg},
-    fork::signal,
-    ipc::IpcParent,
-    state::Status,
-    terminal::PtySocket,
+use std::{io::Write, str::FromStr, sync::OnceLock};
+
+use pura::core::{
+    audit,
+    cgroups::{parse_bytes, parse_cpus},
+    common::{
+        exit, exit_code, exit_msg, exit_process, fail, fail_run, report_error, set_error_format, Error, ErrorFormat,
+        Result,
+    },
+    config::Config,
+    events::parse_interval,
+    jsonfile::{Rotation, Stream},
+    logger::{ContainerLogger, LogFormat},
+    monitor::{ConsoleBuffer, Overflow},
+    network::Network,
+    otlp,
+    pod,
+    recording,
+    restart::RestartPolicy,
+    root::{default_root, open_root},
+    sealed,
+    state::{validate_id, Status},
+    volume,
 };
-use crate::oci::spec::Namespace;
-
-use clap::{App, Arg, SubCommand, AppSettings};
-use log::{error, warn, Level};
-use nix::errno::Errno;
-use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::Pid;
-use oci::{
-    ops::{Create, Delete, Kill, Start, State},
-    spec::Spec,
+use pura::oci::{
+    ops::{
+        Attach, Checkpoint, Create, DebugReport, Delete, Events, Exec, Kill, List, Logs, Metrics, Pod, PodCommand, Pull, Replay, Restore, Run,
+        Start, State, Top, Update, Validate, Volume, VolumeCommand, Wait,
+    },
+    spec::{BlockIo, Cpu, Memory, Pids, Resources},
+    validate::{validate_bundle, Severity},
 };
+#[cfg(feature = "image")]
+use pura::image;
+use pura::{debug, format::Format, metrics, top, watch::Watcher, Container};
 
-const PURA_ROOT_PATH: &str = "/tmp/pura";
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{warn, Level};
 
-pub fn create(create: Create) {
-    let container_id = create.id;
-    let root = create.root;
-    let bundle = create.bundle;
-    let console_socket = create.console_socket;
+pub fn create(create: Create) -> Result<()> {
+    Container::create(&create)?;
+    Ok(())
+}
 
-    let spec = match Spec::try_from(Path::new(&bundle).join("config.json").as_path()) {
-        Ok(spec) => spec,
-        Err(err) => {
-            error!("{}", err);
-            exit(1);
-        }
-    };
+pub fn start(start: Start) -> Result<()> {
+    Container::load(&start.root, &start.id)?.start()
+}
 
-    let has_terminal = if let Some(process) = &spec.process {
-        if let Some(terminal) = process.terminal {
-            terminal == true
-        } else {
-            false
-        }
-    } else {
-        false
-    };
+pub fn run(run: Run) -> Result<()> {
+    match Container::run(&run)? {
+        Some(exit_code) => exit_process(exit_code),
+        None if run.create.detach => Ok(()),
+        None => Err(Error::Runtime(format!(
+            "exit code of container {} is unknown",
+            run.create.id
+        ))),
+    }
+}
 
-    let state = ContainerState::new(&container_id.to_string(), 0, &bundle.to_string());
-    let container_path_str = format!("{}/{}", &root, container_id);
-    let container_path = Path::new(&container_path_str);
-    state.save(container_path).unwrap();
+/// Containers of the root whose status passes the filter, for the bulk
+/// operations
+fn root_ids(root: &str, filter: impl Fn(&Status) -> bool) -> Result<Vec<String>> {
+    Ok(metrics::containers(std::path::Path::new(root))?
+        .into_iter()
+        .filter(|state| filter(&state.status))
+        .map(|state| state.id)
+        .collect())
+}
 
-    let pty_socket = if has_terminal {
-        match PtySocket::new(&console_socket.expect("no console-socket arg")) {
-            Ok(socket_fd) => Some(socket_fd),
+/// Applies the operation of the subcommand to each container. A single
+/// container fails the command with its error; several are reported one by
+/// one, the ids of the successful ones on stdout, and the command fails once
+/// any did. Each container gets its own audit record.
+fn bulk(
+    subcommand: &str,
+    root: &str,
+    ids: &[String],
+    mut operation: impl FnMut(&str) -> Result<()>,
+) -> Result<()> {
+    if let [id] = ids {
+        return operation(id);
+    }
+    let mut failed = 0;
+    for id in ids {
+        ContainerLogger::set_context(subcommand, Some(id));
+        if config().audit() {
+            audit::begin(root, subcommand, id);
+        }
+        match operation(id) {
+            Ok(()) => {
+                audit::finish(0);
+                println!("{}", id);
+            }
             Err(err) => {
-                exit_msg(1, format!("error setting up socket for console_fd {}", err));
+                audit::finish(err.exit_code());
+                report_error(&err);
+                failed += 1;
             }
         }
+    }
+    ContainerLogger::set_context(subcommand, None);
+    match failed {
+        0 => Ok(()),
+        _ => Err(Error::Runtime(format!(
+            "{} of {} containers failed",
+            failed,
+            ids.len()
+        ))),
+    }
+}
+
+pub fn delete(delete: Delete) -> Result<()> {
+    let container = Container::load(&delete.root, &delete.id)?;
+    if delete.force {
+        container.force_delete()
     } else {
-        None
-    };
+        container.delete()
+    }
+}
 
-    // IPC lock that waits the setup of the container IPC channel
-    let init_lock_path = format!("{}/init.sock", container_path_str);
-    let init_lock = IpcParent::new(&init_lock_path).unwrap();
+pub fn kill(kill: Kill) -> Result<()> {
+    let container = Container::load(&kill.root, &kill.id)?;
+    if kill.all {
+        container.kill_all(kill.signal)
+    } else {
+        container.kill(kill.signal)
+    }
+}
 
-    let sock_path = format!("{}/container.sock", container_path.display());
+pub fn exec(exec: Exec) -> Result<()> {
+    match Container::load(&exec.root, &exec.id)?.exec(&exec)? {
+        Some(exit_code) => exit_process(exit_code),
+        None if exec.detach => Ok(()),
+        None => Err(Error::Runtime(format!(
+            "exit code of {:?} in container {} is unknown",
+            exec.args, exec.id
+        ))),
+    }
+}
 
-    let namespaces: Vec<Namespace> = match &spec.linux {
-        Some(linux) => linux.namespaces.clone().unwrap_or(Vec::new()),
-        None => Vec::new(),
-    };
+pub fn attach(attach: Attach) -> Result<()> {
+    Container::load(&attach.root, &attach.id)?.attach()
+}
 
-    let pid = fork_container(
-        &spec,
-        &state,
-        &namespaces,
-        &init_lock_path,
-        &sock_path,
-        &pty_socket,
-    )
-    .expect("error forking container");
-
-    // Wait until child sets up IPC channel
-    match init_lock.wait() {
-        Ok(str) => {
-            if !str.eq("ok") {
-                error!("child process error {}", str);
-                exit(2);
-            }
-        }
-        Err(err) => {
-            error!("error with init_lock {}", err);
-            exit(2);
-        }
+pub fn logs(logs: Logs) -> Result<()> {
+    let container = Container::load(&logs.root, &logs.id)?;
+
+    let mut stdout = std::io::stdout();
+    let mut stderr = std::io::stderr();
+    for entry in container.logs(&logs)? {
+        let entry = entry?;
+        let output: &mut dyn Write = match entry.stream {
+            Stream::Stdout => &mut stdout,
+            Stream::Stderr => &mut stderr,
+        };
+        output.write_all(entry.log.as_bytes()).unwrap();
+        output.flush().unwrap();
     }
-    init_lock.close().unwrap();
+    Ok(())
+}
 
-    let ipc_channel = IpcChannel::connect(&sock_path).unwrap();
+pub fn checkpoint(checkpoint: Checkpoint) -> Result<()> {
+    Container::load(&checkpoint.root, &checkpoint.id)?.checkpoint(&checkpoint)
+}
 
-    loop {
-        match ipc_channel.recv() {
-            Ok(msg) => {
-                if msg.starts_with("error") {
-                    error!("{}", msg);
-                    exit(1);
-                } else if msg.eq("ready") {
-                    break;
-                } else if msg.eq("before_pivot") {
-                    if let Some(hooks) = &spec.hooks {
-                        if let Some(create_runtime) = &hooks.create_runtime {
-                            for cr_hook in create_runtime {
-                                if exec_hook(cr_hook, &state).is_err() {
-                                    error!("createRuntime hook failed");
-                                    signal(pid, 9).unwrap();
-                                }
-                            }
-                        }
-                    }
-                    ipc_channel.send("ok").unwrap();
-                }
-            }
-            Err(err) => {
-                error!("{}", err);
-                signal(pid, 9).unwrap();
-            }
-        }
+pub fn update(update: Update) -> Result<()> {
+    let container = Container::load(&update.root, &update.id)?;
+    if update.resources != Resources::default() {
+        container.update(&update.resources)?;
+    }
+    if !update.devices.is_empty() {
+        container.add_devices(&update.devices)?;
     }
+    Ok(())
+}
 
-    if let Some(pid_file_path) = create.pid_file {
-        let mut pid_file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(pid_file_path)
-            .unwrap();
-        // Write process pid to pid_file
-        pid_file.write_all(format!("{}", pid).as_bytes()).unwrap();
+pub fn restore(restore: Restore) -> Result<()> {
+    Container::restore(&restore)?;
+    Ok(())
+}
+
+pub fn wait(wait: Wait) -> Result<()> {
+    match Container::load(&wait.root, &wait.id)?.wait()? {
+        Some(exit_code) => exit_process(exit_code),
+        None => Err(Error::Runtime(format!(
+            "exit code of container {} is unknown",
+            wait.id
+        ))),
+    }
+}
+
+pub fn state(state: State) -> Result<()> {
+    if state.quiet {
+        // Running exits 0, so `if pura state -q ID` reads naturally
+        let code = match Container::load(&state.root, &state.id).and_then(|c| c.state()) {
+            Ok(container_state) => match container_state.status {
+                Status::Creating | Status::Created => exit_code::CREATED,
+                Status::Running => 0,
+                Status::Stopped => exit_code::STOPPED,
+            },
+            Err(Error::NotFound(_)) => exit_code::NOT_FOUND,
+            Err(err) => return Err(err),
+        };
+        exit(code);
     }
 
-    // Update state
-    let mut state = ContainerState::try_from(container_path).unwrap();
-    state.status = Status::Created;
-    state.pid = i32::from(pid) as u64;
-    state.save(container_path).unwrap();
+    let container = Container::load(&state.root, &state.id)?;
+    let mut stdout = std::io::stdout();
+    if !state.watch {
+        stdout
+            .write_all(
+                state
+                    .format
+                    .state(&container.exec_state(container.state()?))?
+                    .as_bytes(),
+            )
+            .unwrap();
+        stdout.flush().unwrap();
+        return Ok(());
+    }
 
-    // Parent cleanup
-    if has_terminal {
-        match pty_socket.unwrap().close() {
-            Ok(_) => (),
-            Err(err) => error!("error closing console-socket: {}", err),
+    let mut watcher = Watcher::new(std::path::Path::new(&state.root))?;
+    let mut current = watcher.states().iter().find(|s| s.id == state.id).cloned();
+    while let Some(container_state) = current {
+        let mut output = state
+            .format
+            .state(&container.exec_state(container_state.clone()))?;
+        if !output.ends_with('\n') {
+            output.push('\n');
         }
+        stdout.write_all(output.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+
+        // Until the state changes or the container is deleted
+        current = loop {
+            let next = watcher.wait()?.iter().find(|s| s.id == state.id).cloned();
+            if next.as_ref() != Some(&container_state) {
+                break next;
+            }
+        };
+    }
+    Ok(())
+}
+
+pub fn list(list: List) -> Result<()> {
+    let root = std::path::Path::new(&list.root);
+    let mut stdout = std::io::stdout();
+    if !list.watch {
+        stdout
+            .write_all(list.format.list(&metrics::containers(root)?)?.as_bytes())
+            .unwrap();
+        stdout.flush().unwrap();
+        return Ok(());
+    }
+
+    let mut watcher = Watcher::new(root)?;
+    let mut states = watcher.states();
+    loop {
+        stdout.write_all(list.format.list(states)?.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+        states = watcher.wait()?;
     }
 }
 
-pub fn start(start: Start) {
-    let container_path = Path::new(&start.root).join(&start.id);
+pub fn validate(validate: Validate) -> Result<()> {
+    let diagnostics = validate_bundle(std::path::Path::new(&validate.bundle));
 
-    let mut state = ContainerState::try_from(container_path.as_path()).unwrap();
+    let mut stdout = std::io::stdout();
+    for diagnostic in &diagnostics {
+        let line = format!("{}\n", serde_json::to_string(diagnostic).unwrap());
+        stdout.write_all(line.as_bytes()).unwrap();
+    }
+    stdout.flush().unwrap();
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(Error::Bundle(format!(
+            "{} has {} error(s)",
+            validate.bundle, errors
+        )));
+    }
+    Ok(())
+}
 
-    let bundle = &state.bundle;
-    let spec = match Spec::try_from(Path::new(&bundle).join("config.json").as_path()) {
-        Ok(spec) => spec,
-        Err(err) => {
-            error!("{}", err);
-            exit(1);
+pub fn metrics(metrics: Metrics) -> Result<()> {
+    let root = std::path::Path::new(&metrics.root);
+    match metrics.listen {
+        Some(listen) => metrics::serve(root, &listen),
+        None => {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(metrics::render(root)?.as_bytes()).unwrap();
+            stdout.flush().unwrap();
+            Ok(())
         }
-    };
+    }
+}
 
-    let pid = Pid::from_raw(state.pid.try_into().unwrap());
+pub fn debug(report: DebugReport) -> Result<()> {
+    let container = Container::load(&report.root, &report.id)?;
+    std::io::stdout()
+        .write_all(debug::report(&container)?.as_bytes())
+        .map_err(Error::io("unable to write the report"))
+}
 
-    if state.status != Status::Created {
-        error!("container isn't created");
-        signal(pid, 9).unwrap();
-        exit(1);
+#[cfg(feature = "image")]
+pub fn pull(pull: Pull) -> Result<()> {
+    let store = match pull.images {
+        Some(images) => std::path::PathBuf::from(images),
+        None => image::default_store()?,
+    };
+    let pulled = image::pull(&store, &image::Source::parse(&pull.source)?)?;
+    println!("{} {}", pulled.name, pulled.manifest.digest);
+    if let Some(bundle) = pull.bundle {
+        let layout = image::layout::Layout::open(&store)?;
+        image::bundle::create(&layout, &pulled.manifest, std::path::Path::new(&bundle))?;
     }
+    Ok(())
+}
+
+#[cfg(not(feature = "image"))]
+pub fn pull(_pull: Pull) -> Result<()> {
+    Err(pura::core::common::disabled("image"))
+}
 
-    if let Some(hooks) = &spec.hooks {
-        if let Some(prestart) = &hooks.prestart {
-            for pre_hook in prestart {
-                if exec_hook(pre_hook, &state).is_err() {
-                    error!("prestart hook failed");
-                    signal(pid, 9).unwrap();
-                    exit(1);
-                }
+pub fn volume(volume: Volume) -> Result<()> {
+    let root = std::path::Path::new(&volume.root);
+    match volume.command {
+        VolumeCommand::Create { name } => {
+            volume::Volume::create(root, &name)?;
+            println!("{}", name);
+        }
+        VolumeCommand::List => {
+            let volumes = volume::Volume::list(root)?;
+            let width = volumes
+                .iter()
+                .map(|volume| volume.name.len())
+                .max()
+                .unwrap_or(0)
+                .max(4);
+            println!("{:<width$} PATH", "NAME");
+            for volume in volumes {
+                println!("{:<width$} {}", volume.name, volume.path.display());
             }
         }
-
-        if let Some(start_container) = &hooks.start_container {
-            for hook in start_container {
-                if exec_hook(hook, &state).is_err() {
-                    error!("startContainer hook failed");
-                    signal(pid, 9).unwrap();
-                    exit(1);
-                }
+        VolumeCommand::Remove { names, force } => {
+            for name in names {
+                volume::Volume::load(root, &name)?
+                    .ok_or_else(|| Error::Runtime(format!("volume {} does not exist", name)))?
+                    .remove(root, force)?;
             }
         }
     }
+    Ok(())
+}
 
-    let sock_path = format!("{}/container.sock", container_path.display());
-    let ipc_channel = IpcChannel::connect(&sock_path).unwrap();
-    ipc_channel.send(&"start".to_string()).unwrap();
-    ipc_channel.close().unwrap();
-
-    state.status = Status::Running;
-    state.save(container_path.as_path()).unwrap();
-
-    if let Some(hooks) = &spec.hooks {
-        if let Some(poststart) = &hooks.poststart {
-            for hook in poststart {
-                if let Err(err) = exec_hook(hook, &state) {
-                    warn!("poststart hook error: {}", err);
-                }
+pub fn pod(pod: Pod) -> Result<()> {
+    let root = std::path::Path::new(&pod.root);
+    match pod.command {
+        PodCommand::Create {
+            name,
+            share_pid,
+            network,
+        } => {
+            let network = network.as_deref().map(Network::parse).transpose()?;
+            pod::Pod::create(root, &name, share_pid, network.as_ref())?;
+            println!("{}", name);
+        }
+        PodCommand::Add(create) => {
+            Container::create(&create)?;
+        }
+        PodCommand::List => {
+            let pods = pod::Pod::list(root)?;
+            let width = pods
+                .iter()
+                .map(|pod| pod.name.len())
+                .max()
+                .unwrap_or(0)
+                .max(4);
+            println!("{:<width$} {:<8} {:<8} CONTAINERS", "NAME", "PID", "STATUS");
+            for pod in pods {
+                let status = if pod.running() { "running" } else { "stopped" };
+                println!(
+                    "{:<width$} {:<8} {:<8} {}",
+                    pod.name,
+                    pod.pid,
+                    status,
+                    pod.members(root)?.join(",")
+                );
+            }
+        }
+        PodCommand::Remove { names, force } => {
+            for name in names {
+                pod::Pod::load(root, &name)?
+                    .ok_or_else(|| Error::Runtime(format!("pod {} does not exist", name)))?
+                    .remove(root, force)?;
             }
         }
     }
+    Ok(())
 }
 
-pub fn delete(delete: Delete) {
-    let state_path = Path::new(&delete.root).join(&delete.id);
+pub fn replay(replay: Replay) -> Result<()> {
+    validate_id(&replay.id)?;
+    let path = recording::recording_path(&std::path::Path::new(&replay.root).join(&replay.id));
+    recording::replay(&path, &mut std::io::stdout(), replay.speed)
+}
 
-    let state = match ContainerState::try_from(state_path.as_path()) {
-        Ok(state) => state,
-        Err(err) => {
-            error!("error reading state {}", err);
-            exit(1);
-        }
-    };
+pub fn top(top: Top) -> Result<()> {
+    top::run(
+        std::path::Path::new(&top.root),
+        top.id.as_deref(),
+        top.interval,
+        top.iterations,
+    )
+}
 
-    let bundle = &state.bundle;
-    let spec = match Spec::try_from(Path::new(&bundle).join("config.json").as_path()) {
-        Ok(spec) => spec,
-        Err(err) => {
-            error!("{}", err);
-            exit(1);
-        }
-    };
+pub fn events(events: Events) -> Result<()> {
+    let container = Container::load(&events.root, &events.id)?;
 
-    // Just log the error
-    if state.status != Status::Stopped {
-        error!("[DELETE] container isn't created in {:?} for {:?}", delete.root, delete.id);
+    let mut stdout = std::io::stdout();
+    let stats_event =
+        |stats| serde_json::json!({ "type": "stats", "id": events.id, "data": stats });
+    if events.stats {
+        // Like runc, a single stats event of the cgroup, PSI included
+        let stats = metrics::Stats::of(&container.state()?)
+            .ok_or_else(|| Error::State(format!("container {} isn't running", events.id)))?;
+        writeln!(stdout, "{}", stats_event(stats)).unwrap();
+        return Ok(());
     }
-
-    if let Some(hooks) = &spec.hooks {
-        if let Some(poststop) = &hooks.poststop {
-            for hook in poststop {
-                exec_hook(hook, &state).expect("error executing poststop hook");
-            }
+    if let Some(interval) = events.interval {
+        // Stats events until the container stops or is deleted, like runc
+        while let Some(stats) = container.state().ok().as_ref().and_then(metrics::Stats::of) {
+            writeln!(stdout, "{}", stats_event(stats)).unwrap();
+            stdout.flush().unwrap();
+            std::thread::sleep(interval);
         }
+        return Ok(());
+    }
+
+    let mut stream = container.events(events.follow);
+    if let Some(threshold) = events.pressure_threshold {
+        stream = stream.with_pressure_threshold(threshold);
+    }
+    for event in stream {
+        let line = format!("{}\n", serde_json::to_string(&event?).unwrap());
+        stdout.write_all(line.as_bytes()).unwrap();
+        stdout.flush().unwrap();
     }
+    Ok(())
+}
+
+fn values(args: &ArgMatches, name: &str) -> Vec<String> {
+    args.values_of(name)
+        .map(|values| values.map(|value| value.to_string()).collect())
+        .unwrap_or_default()
+}
 
-    if std::fs::remove_dir_all(Path::new(&delete.root).join(&delete.id)).is_err() {
-        warn!("failed to delete container root");
+fn format_arg(args: &ArgMatches) -> Format {
+    let format = args.value_of("format").expect("format has a default");
+    match Format::from_str(format) {
+        Ok(format) => format,
+        Err(err) => exit_msg(1, err),
     }
 }
 
-pub fn kill(kill: Kill) {
-    let state_path = Path::new(&kill.root).join(&kill.id);
-    let mut state = ContainerState::try_from(state_path.as_path()).unwrap();
+/// Host configuration, loaded before the subcommand runs
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
 
-    if state.status != Status::Created && state.status != Status::Running {
-        error!(
-            "[KILL] error can't kill container that isn't created or running: {:?}",
-            &state
-        );
+/// Runtime root of the command: the flag, the one of the host configuration
+/// or the default one, private to the user in every case
+fn root(args: &ArgMatches) -> String {
+    let root = match args.value_of("root").or(config().root.as_deref()) {
+        Some(root) => open_root(std::path::Path::new(root)).map(|_| std::path::PathBuf::from(root)),
+        None => default_root(),
+    };
+    match root {
+        Ok(root) => root.to_string_lossy().to_string(),
+        Err(err) => exit_msg(1, err),
     }
+}
 
-    if let Err(err) = signal(Pid::from_raw(state.pid as i32), kill.signal) {
-        error!("error killing container: {}", err);
-        exit(1);
+fn create_args(args: &ArgMatches) -> Create {
+    Create {
+        id: args
+            .value_of("id")
+            .expect("id is required")
+            .to_string(),
+        bundle: args.value_of("bundle").unwrap_or_default().to_string(),
+        console_socket: args
+            .value_of("console-socket")
+            .map(|s| Some(s.to_string()))
+            .unwrap_or(None),
+        root: root(args),
+        pid_file: args.value_of("pid-file").map(|p| p.to_string()),
+        network: args.value_of("network").map(|n| n.to_string()),
+        detach: args.is_present("detach"),
+        init: args.is_present("init"),
+        devices: args
+            .values_of("device")
+            .map(|devices| devices.map(String::from).collect())
+            .unwrap_or_default(),
+        gpus: args.value_of("gpus").map(|g| g.to_string()),
+        image: args.value_of("image").map(|i| i.to_string()),
+        rootfs_tar: args.value_of("rootfs-tar").map(|r| r.to_string()),
+        overlay: args.is_present("overlay"),
+        volumes: values(args, "volume"),
+        record: args.is_present("record"),
+        log_rotation: log_rotation(args),
+        console_buffer: console_buffer(args),
+        pod: args.value_of("pod").map(|p| p.to_string()),
+        restart: args
+            .value_of("restart")
+            .map(RestartPolicy::from_str)
+            .transpose()
+            .unwrap_or_else(|err| exit_msg(1, err))
+            .unwrap_or_default(),
     }
+}
 
-    match waitpid(Pid::from_raw(state.pid as i32), Some(WaitPidFlag::WNOHANG)) {
-        Ok(res) => match res {
-            WaitStatus::Exited(_, _) | WaitStatus::Signaled(_, _, _) => {
-                state.status = Status::Stopped;
-                state.save(state_path.as_path()).unwrap();
-            }
-            _ => (),
+/// Rotation of the log of the log flags, None without --log-max-size
+fn log_rotation(args: &ArgMatches) -> Option<Rotation> {
+    let max_size = match parse_bytes(args.value_of("log-max-size")?) {
+        Ok(max_size) if max_size > 0 => max_size as u64,
+        Ok(_) => exit_msg(1, "log-max-size expected as a positive size"),
+        Err(err) => exit_msg(1, err),
+    };
+    let max_files = args
+        .value_of("log-max-files")
+        .expect("log-max-files has a default")
+        .parse()
+        .ok()
+        .filter(|max_files| *max_files > 0)
+        .unwrap_or_else(|| exit_msg(1, "log-max-files expected as a positive number"));
+    Some(Rotation {
+        max_size,
+        max_files,
+    })
+}
+
+/// Console buffer of the console flags, None without any
+fn console_buffer(args: &ArgMatches) -> Option<ConsoleBuffer> {
+    let size = args.value_of("console-buffer");
+    let overflow = args.value_of("console-overflow");
+    if size.is_none() && overflow.is_none() {
+        return None;
+    }
+    let mut buffer = ConsoleBuffer::default();
+    if let Some(size) = size {
+        buffer.size = match parse_bytes(size) {
+            Ok(size) if size > 0 => size as usize,
+            Ok(_) => exit_msg(1, "console-buffer expected as a positive size"),
+            Err(err) => exit_msg(1, err),
+        };
+    }
+    if let Some(overflow) = overflow {
+        buffer.overflow = Overflow::from_str(overflow).unwrap_or_else(|err| exit_msg(1, err));
+    }
+    Some(buffer)
+}
+
+/// Resource flags of update and run, like `--memory 512m`
+fn resource_flags<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    let flag = |name: &'a str, help: &'a str| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .help(help)
+    };
+    app.arg(flag("memory", "memory limit, like 512m or 1g"))
+        .arg(flag("memory-reservation", "memory reclaimed last, like 256m"))
+        .arg(flag("memory-swap", "memory plus swap limit, like 1g, -1 for unlimited"))
+        .arg(flag("cpus", "number of CPUs, like 1.5"))
+        .arg(flag("cpu-shares", "relative CPU weight, 1024 by default"))
+        .arg(flag("cpuset-cpus", "CPUs to run on, like 0-3"))
+        .arg(flag("cpuset-mems", "memory nodes to allocate on, like 0"))
+        .arg(flag("pids-limit", "maximum number of processes, 0 or -1 for unlimited"))
+        .arg(flag("blkio-weight", "relative block IO weight, 10 to 1000"))
+}
+
+/// Resources of the resource flags, None without any
+fn resource_args(args: &ArgMatches) -> Option<Resources> {
+    let bytes = |name: &str| {
+        args.value_of(name)
+            .map(|size| parse_bytes(size).unwrap_or_else(|err| exit_msg(1, err)))
+    };
+    let integer = |name: &str| {
+        args.value_of(name)
+            .map(|value| value.parse::<i64>().expect("resource expected as integer"))
+    };
+    let string = |name: &str| args.value_of(name).map(|value| value.to_string());
+
+    let mut resources = Resources::default();
+    let memory = Memory {
+        limit: bytes("memory"),
+        reservation: bytes("memory-reservation"),
+        swap: bytes("memory-swap"),
+        ..Default::default()
+    };
+    if memory != Memory::default() {
+        resources.memory = Some(memory);
+    }
+    let (quota, period) = match args.value_of("cpus") {
+        Some(cpus) => match parse_cpus(cpus) {
+            Ok((quota, period)) => (Some(quota), Some(period)),
+            Err(err) => exit_msg(1, err),
         },
-        Err(err) => {
-            if err.as_errno() != Some(Errno::ECHILD) {
-                error!("error polling pid status: {}", err);
-                exit(1);
-            }
-        }
+        None => (None, None),
+    };
+    let cpu = Cpu {
+        shares: integer("cpu-shares"),
+        quota,
+        period,
+        cpus: string("cpuset-cpus"),
+        mems: string("cpuset-mems"),
+        ..Default::default()
+    };
+    if cpu != Cpu::default() {
+        resources.cpu = Some(cpu);
+    }
+    if let Some(limit) = integer("pids-limit") {
+        resources.pids = Some(Pids {
+            limit: Some(limit),
+            ..Default::default()
+        });
+    }
+    if let Some(weight) = integer("blkio-weight") {
+        resources.block_io = Some(BlockIo {
+            weight: Some(weight),
+            ..Default::default()
+        });
+    }
+
+    if resources == Resources::default() {
+        None
+    } else {
+        Some(resources)
     }
 }
 
-pub fn state(state: State) {
-    let state_path = Path::new(&state.root).join(&state.id);
+/// Arguments of the commands creating a container
+fn create_subcommand<'a, 'b>(name: &str) -> App<'a, 'b> {
+    create_options(SubCommand::with_name(name))
+        .arg(
+            Arg::with_name("pod")
+                .long("pod")
+                .takes_value(true)
+                .conflicts_with("network")
+                .help("joins the namespaces of the pod created by pura pod create"),
+        )
+        .arg(
+            Arg::with_name("id")
+                .required(true)
+                .help("ID of the container"),
+        )
+}
 
-    let state = ContainerState::try_from(state_path.as_path()).unwrap();
+/// Options of the commands creating a container, before their positional arguments
+fn create_options<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("bundle")
+                .long("bundle")
+                .short("b")
+                .takes_value(true)
+                .required_unless("image")
+                .conflicts_with("image")
+                .help("bundle directory containing container configuration"),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .takes_value(true)
+                .help("OCI layout PATH[:NAME] of an image to synthesize the bundle from"),
+        )
+        .arg(
+            Arg::with_name("rootfs-tar")
+                .long("rootfs-tar")
+                .takes_value(true)
+                .conflicts_with("image")
+                .help("tarball extracted as the rootfs of the container instead of the one of the bundle"),
+        )
+        .arg(
+            Arg::with_name("overlay")
+                .long("overlay")
+                .help("shares the rootfs read-only, the writes of the container going to an overlay"),
+        )
+        .arg(
+            Arg::with_name("volume")
+                .long("volume")
+                .short("v")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("bind mounts a named volume or an absolute host path: SOURCE:DEST[:OPTIONS]"),
+        )
+        .arg(
+            Arg::with_name("pid-file")
+                .long("pid-file")
+                .takes_value(true)
+                .help("file to write the container process PID"),
+        )
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .takes_value(true)
+                .help("connects the container to a bridge: bridge=NAME,address=CIDR[,gateway=IP]"),
+        )
+        .arg(
+            Arg::with_name("device")
+                .long("device")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("injects the CDI device, like vendor.com/gpu=0, from the specs of /etc/cdi and /var/run/cdi"),
+        )
+        .arg(
+            Arg::with_name("gpus")
+                .long("gpus")
+                .takes_value(true)
+                .help("gives the NVIDIA GPUs to the container: all, or indices like 0,1"),
+        )
+        .arg(
+            Arg::with_name("init")
+                .long("init")
+                .help("runs the process under an init reaping zombies and forwarding signals"),
+        )
+        .arg(
+            Arg::with_name("console-socket")
+                .long("console-socket")
+                .takes_value(true)
+                .help("UNIX socket to send the pty master fd, if terminal: true"),
+        )
+}
 
-    std::io::stdout()
-        .write_all(&serde_json::to_string(&state).unwrap().as_bytes())
-        .unwrap();
-    std::io::stdout().flush().unwrap();
+/// Options of the commands leaving the container to a monitor
+fn detach_flags<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(
+        Arg::with_name("detach")
+            .long("detach")
+            .short("d")
+            .conflicts_with("console-socket")
+            .help("leaves the container to a monitor holding its stdio and recording its exit"),
+    )
+    .arg(
+        Arg::with_name("record")
+            .long("record")
+            .requires("detach")
+            .help("records the console to <root>/<id>.cast, in the asciicast v2 format"),
+    )
+    .arg(
+        Arg::with_name("log-max-size")
+            .long("log-max-size")
+            .takes_value(true)
+            .requires("detach")
+            .help("rotates the log of the monitor once it grows past the size, like 10m"),
+    )
+    .arg(
+        Arg::with_name("log-max-files")
+            .long("log-max-files")
+            .takes_value(true)
+            .default_value("1")
+            .help("number of log files kept on rotation, the current one included"),
+    )
+    .arg(
+        Arg::with_name("console-buffer")
+            .long("console-buffer")
+            .takes_value(true)
+            .requires("detach")
+            .help("output buffered for each attached client not keeping up, 1m by default"),
+    )
+    .arg(
+        Arg::with_name("restart")
+            .long("restart")
+            .takes_value(true)
+            .requires("detach")
+            .help("has the monitor create the container again from its spec once it failed: no or on-failure[:MAX]"),
+    )
+    .arg(
+        Arg::with_name("console-overflow")
+            .long("console-overflow")
+            .takes_value(true)
+            .requires("detach")
+            .possible_values(&["drop-oldest", "block"])
+            .help("once the buffer of a client is full, drops its oldest output or blocks the container until it catches up"),
+    )
 }
 
 pub fn main() {
@@ -317,48 +801,77 @@ pub fn main() {
         .arg(
             Arg::with_name("root")
                 .long("root")
+                .global(true)
+                .takes_value(true)
+                .help("runtime root for the container state, /run/pura as root and $XDG_RUNTIME_DIR/pura otherwise"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .global(true)
                 .takes_value(true)
-                .help("runtime root for the container state"),
+                .help("host configuration file, /etc/pura/config.toml by default"),
         )
         .arg(
             Arg::with_name("log")
                 .long("log")
+                .global(true)
                 .takes_value(true)
                 .help("location of the log file"),
         )
         .arg(
             Arg::with_name("log-format")
                 .long("log-format")
+                .global(true)
+                .takes_value(true)
+                .help("log format (text, json or journald)"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .help("minimum level of the logged records"),
+        )
+        .arg(
+            Arg::with_name("error-format")
+                .long("error-format")
+                .global(true)
                 .takes_value(true)
-                .help("log format (e.q. json, txt)"),
+                .possible_values(&["text", "json"])
+                .help("format of the error the command fails on, json prints one object with its code, stage, message and errno on stderr"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .global(true)
+                .help("enable debug logging (same as --log-level debug)"),
         )
         // Subcommands
+        .subcommand(detach_flags(create_subcommand("create")))
         .subcommand(
-            SubCommand::with_name("create")
+            detach_flags(resource_flags(create_subcommand("run")))
+                .about("creates and starts the container in the foreground, forwarding signals to it, or leaves it to its monitor with --detach")
                 .arg(
-                    Arg::with_name("bundle")
-                        .long("bundle")
-                        .short("b")
-                        .takes_value(true)
-                        .required(true)
-                        .help("bundle directory containing container configuration"),
+                    Arg::with_name("all")
+                        .long("all")
+                        .short("a")
+                        .conflicts_with("detach")
+                        .help("forwards the signals to every process of the container cgroup"),
                 )
                 .arg(
-                    Arg::with_name("pid-file")
-                        .long("pid-file")
-                        .takes_value(true)
-                        .help("file to write the container process PID"),
+                    Arg::with_name("rm")
+                        .long("rm")
+                        .conflicts_with("detach")
+                        .help("deletes the container once it stopped"),
                 )
                 .arg(
-                    Arg::with_name("console-socket")
-                        .long("console-socket")
+                    Arg::with_name("trace-syscalls")
+                        .long("trace-syscalls")
                         .takes_value(true)
-                        .help("UNIX socket to send the pty master fd, if terminal: true"),
-                )
-                .arg(
-                    Arg::with_name("id")
-                        .required(true)
-                        .help("ID of the container"),
+                        .conflicts_with("detach")
+                        .help("writes the syscalls of the container process and their results to the file, from its start"),
                 ),
         )
         .subcommand(
@@ -371,100 +884,958 @@ pub fn main() {
         )
         .subcommand(
             SubCommand::with_name("kill")
+                .arg(
+                    // The signal comes last, clap can't have a positional after several ids
+                    Arg::with_name("id")
+                        .required(true)
+                        .multiple(true)
+                        .value_name("ID... SIGNAL")
+                        .help("IDs of the containers, then the signal to send to their process (e.q. 15, 9, ...)"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .short("a")
+                        .help("sends the signal to every process of the container, exec'd ones included"),
+                )
+                .arg(
+                    Arg::with_name("all-containers")
+                        .long("all-containers")
+                        .help("signals every created or running container of the root, only the signal is given"),
+                ),
+        )
+        .subcommand(
+            resource_flags(SubCommand::with_name("exec"))
+                .about("executes a command in the running container")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("detach")
+                        .long("detach")
+                        .short("d")
+                        .help("returns once the command is executed, without waiting for it"),
+                )
+                .arg(
+                    Arg::with_name("pid-file")
+                        .long("pid-file")
+                        .takes_value(true)
+                        .help("file to write the pid of the executed process to"),
+                )
+                .arg(
+                    Arg::with_name("env")
+                        .long("env")
+                        .short("e")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("KEY=VALUE variable added to the environment"),
+                )
+                .arg(
+                    Arg::with_name("user")
+                        .long("user")
+                        .short("u")
+                        .takes_value(true)
+                        .help("USER[:GROUP] to run the command as, names or ids of the container"),
+                )
+                .arg(
+                    Arg::with_name("cwd")
+                        .long("cwd")
+                        .short("w")
+                        .takes_value(true)
+                        .help("working directory of the command"),
+                )
+                .arg(
+                    Arg::with_name("cap-add")
+                        .long("cap-add")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("capability added to the command, ALL for every one"),
+                )
+                .arg(
+                    Arg::with_name("cap-drop")
+                        .long("cap-drop")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("capability dropped from the command, ALL for every one"),
+                )
+                .arg(
+                    Arg::with_name("cgroup")
+                        .long("cgroup")
+                        .takes_value(true)
+                        .help("child cgroup of the container for the command, limited by the resource flags"),
+                )
                 .arg(
                     Arg::with_name("id")
                         .required(true)
                         .help("ID of the container"),
                 )
                 .arg(
-                    Arg::with_name("signal")
+                    Arg::with_name("command")
                         .required(true)
-                        .help("signal to send to the process (e.q. SIGTERM, SIGKILL, ...)"),
+                        .multiple(true)
+                        .help("command and its arguments"),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("delete").arg(
-                Arg::with_name("id")
-                    .required(true)
-                    .help("ID of the container"),
-            ),
+            SubCommand::with_name("delete")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .short("f")
+                        .help("kill the container first if it's running"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .conflicts_with_all(&["id", "all-stopped"])
+                        .help("deletes every container of the root"),
+                )
+                .arg(
+                    Arg::with_name("all-stopped")
+                        .long("all-stopped")
+                        .conflicts_with("id")
+                        .help("deletes every stopped container of the root"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .multiple(true)
+                        .required_unless_one(&["all", "all-stopped"])
+                        .help("IDs of the containers"),
+                ),
         )
         .subcommand(
-            SubCommand::with_name("state").arg(
-                Arg::with_name("id")
-                    .required(true)
-                    .help("ID of the container"),
-            ),
-        )
-        .get_matches();
-
-    let mut log_path = matches.value_of("log").map(|s| s.to_string());
-
-    if log_path.is_none() {
-        if let Some(id) = matches.value_of("id") {
-            log_path = Some(format!("/tmp/pura/{}.log", id));
-        } else {
-            // This isn't likely to happen since each OCI runtime command has the id arg
-            log_path = Some(String::from("/tmp/pura/unknown.log"));
-        }
+            SubCommand::with_name("state")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .takes_value(true)
+                        .default_value("json")
+                        .help("json, table or a template like {{.pid}}"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .short("w")
+                        .help("prints the state again each time it changes, until the container is deleted"),
+                )
+                .arg(
+                    Arg::with_name("quiet")
+                        .long("quiet")
+                        .short("q")
+                        .conflicts_with("watch")
+                        .help("prints nothing and exits 0 if running, 10 if created, 11 if stopped or 12 if the container doesn't exist"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("lists the containers of the root")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .takes_value(true)
+                        .default_value("table")
+                        .help("json, table or a template like {{.id}} {{.status}}"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .short("w")
+                        .help("prints the list again each time a container changes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("wait")
+                .about("waits for the container to stop and exits with its exit code")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("attach")
+                .about("forwards stdin to the detached container and prints its output")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("prints the output of the detached container")
+                .arg(
+                    Arg::with_name("follow")
+                        .long("follow")
+                        .short("f")
+                        .help("wait for new output until the container stops"),
+                )
+                .arg(
+                    Arg::with_name("tail")
+                        .long("tail")
+                        .takes_value(true)
+                        .help("number of last lines to print"),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .help("print the output since an RFC 3339 timestamp, or a duration ago like 10m"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("checkpoint")
+                .about("checkpoints the running container with criu")
+                .arg(
+                    Arg::with_name("image-path")
+                        .long("image-path")
+                        .takes_value(true)
+                        .default_value("checkpoint")
+                        .help("directory receiving the criu images"),
+                )
+                .arg(
+                    Arg::with_name("parent-path")
+                        .long("parent-path")
+                        .takes_value(true)
+                        .help("images of a previous pre-dump, relative to the image path"),
+                )
+                .arg(
+                    Arg::with_name("work-path")
+                        .long("work-path")
+                        .takes_value(true)
+                        .help("directory of the criu log, the image path by default"),
+                )
+                .arg(
+                    Arg::with_name("pre-dump")
+                        .long("pre-dump")
+                        .help("dump the memory only and leave the container running"),
+                )
+                .arg(
+                    Arg::with_name("leave-running")
+                        .long("leave-running")
+                        .help("leave the container running after the dump"),
+                )
+                .arg(
+                    Arg::with_name("tcp-established")
+                        .long("tcp-established")
+                        .help("checkpoint the established TCP connections"),
+                )
+                .arg(
+                    Arg::with_name("lazy-pages")
+                        .long("lazy-pages")
+                        .help("let the memory pages be fetched lazily on restore"),
+                )
+                .arg(
+                    Arg::with_name("page-server")
+                        .long("page-server")
+                        .takes_value(true)
+                        .help("ADDRESS:PORT of the page server, or to serve lazy pages on"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            resource_flags(SubCommand::with_name("update"))
+                .about("updates the cgroup resources of a created or running container")
+                .arg(
+                    Arg::with_name("device")
+                        .long("device")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("adds the device of the host: HOST[:CONTAINER[:PERMISSIONS]]"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("restores a checkpoint as a new running container")
+                .arg(
+                    Arg::with_name("bundle")
+                        .long("bundle")
+                        .short("b")
+                        .takes_value(true)
+                        .required(true)
+                        .help("bundle directory containing container configuration"),
+                )
+                .arg(
+                    Arg::with_name("image-path")
+                        .long("image-path")
+                        .takes_value(true)
+                        .default_value("checkpoint")
+                        .help("directory holding the criu images"),
+                )
+                .arg(
+                    Arg::with_name("work-path")
+                        .long("work-path")
+                        .takes_value(true)
+                        .help("directory of the criu log, the image path by default"),
+                )
+                .arg(
+                    Arg::with_name("pid-file")
+                        .long("pid-file")
+                        .takes_value(true)
+                        .help("file to write the container process PID"),
+                )
+                .arg(
+                    Arg::with_name("network")
+                        .long("network")
+                        .takes_value(true)
+                        .help("connects the container to a bridge: bridge=NAME,address=CIDR[,gateway=IP]"),
+                )
+                .arg(
+                    Arg::with_name("tcp-established")
+                        .long("tcp-established")
+                        .help("restore the established TCP connections"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the new container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("events")
+                .about("prints the lifecycle events of the container")
+                .arg(
+                    Arg::with_name("follow")
+                        .long("follow")
+                        .short("f")
+                        .help("wait for new events until the container stops"),
+                )
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .conflicts_with("follow")
+                        .help("print the resource usage and pressure of the container"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .conflicts_with_all(&["follow", "stats"])
+                        .help("print the stats every interval, like 5s, until the container stops"),
+                )
+                .arg(
+                    Arg::with_name("pressure-threshold")
+                        .long("pressure-threshold")
+                        .takes_value(true)
+                        .requires("follow")
+                        .help("report a pressure event when tasks stall over PERCENT of 10s"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("checks the bundle configuration without creating a container")
+                .arg(
+                    Arg::with_name("bundle")
+                        .long("bundle")
+                        .short("b")
+                        .takes_value(true)
+                        .default_value(".")
+                        .help("bundle directory containing container configuration"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("metrics")
+                .about("exports the container metrics in the Prometheus text format")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .help("ADDRESS:PORT serving the metrics on /metrics"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("top")
+                .about("refreshes the resource usage of the containers")
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .short("d")
+                        .takes_value(true)
+                        .default_value("2")
+                        .help("seconds between refreshes"),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .short("n")
+                        .takes_value(true)
+                        .help("number of refreshes before exiting"),
+                )
+                .arg(Arg::with_name("id").help("ID of the container, all the containers without one")),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("reports the namespaces, cgroup, capabilities, fds and mounts of the container")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pull")
+                .about("pulls an image into the image store, optionally creating a bundle from it")
+                .arg(
+                    Arg::with_name("images")
+                        .long("images")
+                        .takes_value(true)
+                        .help("image store, an OCI layout"),
+                )
+                .arg(
+                    Arg::with_name("bundle")
+                        .long("bundle")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path of a bundle to create from the image"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .help("image reference, oci:PATH[:NAME] or docker-archive:PATH"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("plays the console recorded by create --detach --record back")
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("factor the recording is sped up by"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .help("ID of the container"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("volume")
+                .about("manages the named volumes of the root, bind mounted by --volume")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("creates an empty volume")
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .help("name of the volume"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .alias("list")
+                        .about("lists the volumes of the root"),
+                )
+                .subcommand(
+                    SubCommand::with_name("rm")
+                        .alias("remove")
+                        .about("removes volumes and their content")
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .short("f")
+                                .help("removes the volumes even if containers use them"),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .multiple(true)
+                                .help("names of the volumes"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pod")
+                .about("manages pods, sandboxes whose containers share their network, IPC and UTS namespaces")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("starts a pause process holding the namespaces of the pod")
+                        .arg(
+                            Arg::with_name("share-pid")
+                                .long("share-pid")
+                                .help("shares a PID namespace too, the pause process being its PID 1"),
+                        )
+                        .arg(
+                            Arg::with_name("network")
+                                .long("network")
+                                .takes_value(true)
+                                .help("connects the pod to a bridge: bridge=NAME,address=CIDR[,gateway=IP]"),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .help("name of the pod"),
+                        ),
+                )
+                .subcommand(
+                    detach_flags(create_options(SubCommand::with_name("add")))
+                        .about("creates a container in the pod, like create --pod")
+                        .arg(
+                            Arg::with_name("pod")
+                                .required(true)
+                                .help("name of the pod"),
+                        )
+                        .arg(
+                            Arg::with_name("id")
+                                .required(true)
+                                .help("ID of the container"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .alias("list")
+                        .about("lists the pods of the root and their containers"),
+                )
+                .subcommand(
+                    SubCommand::with_name("rm")
+                        .alias("remove")
+                        .about("stops the pause process of pods and removes them")
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .short("f")
+                                .help("removes the pods even if they have containers"),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .multiple(true)
+                                .help("names of the pods"),
+                        ),
+                ),
+        )
+        .get_matches();
+
+    // The global flags are only given to the innermost command
+    let mut innermost = &matches;
+    while let (_, Some(args)) = innermost.subcommand() {
+        innermost = args;
+    }
+    if let Some(format) = innermost.value_of("error-format") {
+        set_error_format(ErrorFormat::from_str(format).unwrap_or_default());
     }
 
-    let _ = ContainerLogger::init(&log_path.unwrap(), Level::Info).unwrap();
+    // Joining the namespaces of a container exposes the binary to it
+    let creating = match matches.subcommand() {
+        ("create" | "run" | "exec", Some(_)) => true,
+        ("pod", Some(pod)) => pod.subcommand_name() == Some("add"),
+        _ => false,
+    };
+    if creating {
+        if let Err(err) = sealed::reexec() {
+            exit_msg(1, err);
+        }
+    }
 
-    match matches.subcommand() {
-        ("create", create_cmd) => {
-            let args = create_cmd.unwrap();
-            create(Create {
-                id: args
-                    .value_of("id")
-                    .expect("id is required")
-                    .to_string(),
-                bundle: args
-                    .value_of("bundle")
-                    .expect("bundle is required")
-                    .to_string(),
-                console_socket: args
-                    .value_of("console-socket")
-                    .map(|s| Some(s.to_string()))
-                    .unwrap_or(None),
-                root: args.value_of("root").unwrap_or(PURA_ROOT_PATH).to_string(),
-                pid_file: args.value_of("pid-file").map(|p| p.to_string()),
+    let config = match Config::load(matches.value_of("config")) {
+        Ok(config) => CONFIG.get_or_init(|| config),
+        Err(err) => exit_msg(1, err),
+    };
+
+    let log = matches.value_of("log").or(config.log.as_deref());
+    let log_format = match matches.value_of("log-format").or(config.log_format.as_deref()) {
+        Some(format) => match LogFormat::from_str(format) {
+            Ok(format) => format,
+            Err(err) => exit_msg(1, err),
+        },
+        None => LogFormat::detect(log),
+    };
+
+    let log_level = match matches.value_of("log-level") {
+        Some(level) => Level::from_str(level).unwrap_or(Level::Info),
+        None if matches.is_present("debug") => Level::Debug,
+        None => match config.log_level.as_deref().map(Level::from_str) {
+            Some(Ok(level)) => level,
+            Some(Err(_)) => exit_msg(1, "invalid log_level in the host configuration"),
+            None => Level::Info,
+        },
+    };
+
+    if let Err(err) = ContainerLogger::init(log, log_format, log_level) {
+        exit_msg(1, err);
+    }
+
+    if let Err(err) = config.apply() {
+        exit_msg(1, err);
+    }
+
+    if let Err(err) = otlp::init() {
+        warn!("{}", err);
+    }
+
+    if let (subcommand, Some(args)) = matches.subcommand() {
+        // The only value of kill --all-containers is the signal
+        let id = match subcommand {
+            "kill" if args.is_present("all-containers") => None,
+            _ => args.value_of("id"),
+        };
+        ContainerLogger::set_context(subcommand, id);
+
+        // Record every state-changing operation in the audit file of the root,
+        // per container for the ones of bulk
+        if let ("create" | "run" | "start" | "kill" | "delete" | "update" | "checkpoint" | "restore", true) = (subcommand, config.audit()) {
+            if let Some(id) = id {
+                audit::begin(&root(args), subcommand, id);
+            }
+
+            let default_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                audit::finish(101);
+                default_hook(info);
+            }));
+        }
+    }
+
+    let result = match matches.subcommand() {
+        ("create", create_cmd) => create(create_args(create_cmd.unwrap())),
+        ("run", run_cmd) => {
+            let args = run_cmd.unwrap();
+            run(Run {
+                create: create_args(args),
+                all: args.is_present("all"),
+                rm: args.is_present("rm"),
+                resources: resource_args(args),
+                trace_syscalls: args.value_of("trace-syscalls").map(|t| t.to_string()),
             })
         }
         ("start", start_cmd) => {
             let args = start_cmd.unwrap();
             start(Start {
                 id: args.value_of("id").expect("id is required").to_string(),
-                root: args.value_of("root").unwrap_or(PURA_ROOT_PATH).to_string(),
+                root: root(args),
             })
         }
         ("delete", delete_cmd) => {
             let args = delete_cmd.unwrap();
-            delete(Delete {
-                id: args.value_of("id").expect("id is required").to_string(),
-                root: args.value_of("root").unwrap_or(PURA_ROOT_PATH).to_string(),
+            let root = root(args);
+            let ids = if args.is_present("all") {
+                root_ids(&root, |_| true)
+            } else if args.is_present("all-stopped") {
+                root_ids(&root, |status| *status == Status::Stopped)
+            } else {
+                Ok(values(args, "id"))
+            };
+            ids.and_then(|ids| {
+                bulk("delete", &root, &ids, |id| {
+                    delete(Delete {
+                        id: id.to_string(),
+                        root: root.clone(),
+                        force: args.is_present("force"),
+                    })
+                })
             })
         }
         ("kill", kill_cmd) => {
             let args = kill_cmd.unwrap();
-            kill(Kill {
+            let root = root(args);
+            let mut ids = values(args, "id");
+            let signal = ids
+                .pop()
+                .expect("signal is required")
+                .parse()
+                .unwrap_or_else(|_| exit_msg(1, "signal expected as integer"));
+            let ids = match (args.is_present("all-containers"), ids.is_empty()) {
+                (true, true) => root_ids(&root, |status| {
+                    *status == Status::Created || *status == Status::Running
+                }),
+                (true, false) => exit_msg(1, "no container id is given with --all-containers"),
+                (false, true) => exit_msg(1, "container id and signal are required"),
+                (false, false) => Ok(ids),
+            };
+            ids.and_then(|ids| {
+                bulk("kill", &root, &ids, |id| {
+                    kill(Kill {
+                        id: id.to_string(),
+                        root: root.clone(),
+                        signal,
+                        all: args.is_present("all"),
+                    })
+                })
+            })
+        }
+        ("exec", exec_cmd) => {
+            let args = exec_cmd.unwrap();
+            exec(Exec {
                 id: args.value_of("id").expect("id is required").to_string(),
-                root: args.value_of("root").unwrap_or(PURA_ROOT_PATH).to_string(),
-                signal: args
-                    .value_of("signal")
-                    .expect("signal is required")
-                    .parse()
-                    .expect("signal expected as integer"),
+                root: root(args),
+                args: args
+                    .values_of("command")
+                    .expect("command is required")
+                    .map(|arg| arg.to_string())
+                    .collect(),
+                detach: args.is_present("detach"),
+                pid_file: args.value_of("pid-file").map(|p| p.to_string()),
+                env: values(args, "env"),
+                user: args.value_of("user").map(|user| user.to_string()),
+                cwd: args.value_of("cwd").map(|cwd| cwd.to_string()),
+                cap_add: values(args, "cap-add"),
+                cap_drop: values(args, "cap-drop"),
+                cgroup: args.value_of("cgroup").map(|cgroup| cgroup.to_string()),
+                resources: resource_args(args),
             })
         }
         ("state", state_cmd) => {
             let args = state_cmd.unwrap();
             state(State {
                 id: args.value_of("id").expect("id is required").to_string(),
-                root: args.value_of("root").unwrap_or(PURA_ROOT_PATH).to_string(),
+                root: root(args),
+                format: format_arg(args),
+                watch: args.is_present("watch"),
+                quiet: args.is_present("quiet"),
+            })
+        }
+        ("list", list_cmd) => {
+            let args = list_cmd.unwrap();
+            list(List {
+                root: root(args),
+                format: format_arg(args),
+                watch: args.is_present("watch"),
+            })
+        }
+        ("wait", wait_cmd) => {
+            let args = wait_cmd.unwrap();
+            wait(Wait {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+            })
+        }
+        ("attach", attach_cmd) => {
+            let args = attach_cmd.unwrap();
+            attach(Attach {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+            })
+        }
+        ("logs", logs_cmd) => {
+            let args = logs_cmd.unwrap();
+            logs(Logs {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+                follow: args.is_present("follow"),
+                tail: args
+                    .value_of("tail")
+                    .map(|n| n.parse().expect("tail expected as integer")),
+                since: args.value_of("since").map(|since| since.to_string()),
+            })
+        }
+        ("checkpoint", checkpoint_cmd) => {
+            let args = checkpoint_cmd.unwrap();
+            checkpoint(Checkpoint {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+                image_path: args
+                    .value_of("image-path")
+                    .expect("image-path has a default")
+                    .to_string(),
+                parent_path: args.value_of("parent-path").map(|p| p.to_string()),
+                work_path: args.value_of("work-path").map(|p| p.to_string()),
+                pre_dump: args.is_present("pre-dump"),
+                leave_running: args.is_present("leave-running"),
+                tcp_established: args.is_present("tcp-established"),
+                lazy_pages: args.is_present("lazy-pages"),
+                page_server: args.value_of("page-server").map(|p| p.to_string()),
+            })
+        }
+        ("update", update_cmd) => {
+            let args = update_cmd.unwrap();
+            let resources = resource_args(args);
+            if resources.is_none() && !args.is_present("device") {
+                exit_msg(1, "update requires a resource flag like --memory 512m, or --device")
+            }
+            update(Update {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+                resources: resources.unwrap_or_default(),
+                devices: args
+                    .values_of("device")
+                    .map(|devices| devices.map(String::from).collect())
+                    .unwrap_or_default(),
+            })
+        }
+        ("restore", restore_cmd) => {
+            let args = restore_cmd.unwrap();
+            restore(Restore {
+                id: args.value_of("id").expect("id is required").to_string(),
+                bundle: args.value_of("bundle").expect("bundle is required").to_string(),
+                root: root(args),
+                image_path: args
+                    .value_of("image-path")
+                    .expect("image-path has a default")
+                    .to_string(),
+                work_path: args.value_of("work-path").map(|p| p.to_string()),
+                pid_file: args.value_of("pid-file").map(|p| p.to_string()),
+                network: args.value_of("network").map(|n| n.to_string()),
+                tcp_established: args.is_present("tcp-established"),
+            })
+        }
+        ("events", events_cmd) => {
+            let args = events_cmd.unwrap();
+            events(Events {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+                follow: args.is_present("follow"),
+                stats: args.is_present("stats"),
+                interval: args.value_of("interval").map(|interval| {
+                    parse_interval(interval).expect("interval expected like 5s or 500ms")
+                }),
+                pressure_threshold: args.value_of("pressure-threshold").map(|threshold| {
+                    threshold
+                        .parse()
+                        .expect("pressure threshold expected as a percentage")
+                }),
+            })
+        }
+        ("validate", validate_cmd) => {
+            let args = validate_cmd.unwrap();
+            validate(Validate {
+                bundle: args
+                    .value_of("bundle")
+                    .expect("bundle has a default")
+                    .to_string(),
+            })
+        }
+        ("metrics", metrics_cmd) => {
+            let args = metrics_cmd.unwrap();
+            metrics(Metrics {
+                root: root(args),
+                listen: args.value_of("listen").map(|l| l.to_string()),
+            })
+        }
+        ("top", top_cmd) => {
+            let args = top_cmd.unwrap();
+            top(Top {
+                id: args.value_of("id").map(|id| id.to_string()),
+                root: root(args),
+                interval: args
+                    .value_of("interval")
+                    .expect("interval has a default")
+                    .parse()
+                    .map(std::time::Duration::from_secs_f64)
+                    .expect("interval expected as seconds"),
+                iterations: args
+                    .value_of("iterations")
+                    .map(|n| n.parse().expect("iterations expected as integer")),
+            })
+        }
+        ("debug", debug_cmd) => {
+            let args = debug_cmd.unwrap();
+            debug(DebugReport {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+            })
+        }
+        ("pull", pull_cmd) => {
+            let args = pull_cmd.unwrap();
+            pull(Pull {
+                source: args.value_of("source").expect("source is required").to_string(),
+                images: args.value_of("images").map(|images| images.to_string()),
+                bundle: args.value_of("bundle").map(|bundle| bundle.to_string()),
+            })
+        }
+        ("replay", replay_cmd) => {
+            let args = replay_cmd.unwrap();
+            replay(Replay {
+                id: args.value_of("id").expect("id is required").to_string(),
+                root: root(args),
+                speed: args
+                    .value_of("speed")
+                    .expect("speed has a default")
+                    .parse()
+                    .ok()
+                    .filter(|speed: &f64| *speed > 0.0)
+                    .unwrap_or_else(|| exit_msg(1, "speed expected as a positive number")),
+            })
+        }
+        ("volume", volume_cmd) => {
+            // The global flags like --root are given to the innermost command
+            let (command, args) = match volume_cmd.unwrap().subcommand() {
+                ("create", Some(args)) => (
+                    VolumeCommand::Create {
+                        name: args.value_of("name").expect("name is required").to_string(),
+                    },
+                    args,
+                ),
+                ("ls", Some(args)) => (VolumeCommand::List, args),
+                ("rm", Some(args)) => (
+                    VolumeCommand::Remove {
+                        names: values(args, "name"),
+                        force: args.is_present("force"),
+                    },
+                    args,
+                ),
+                (_, _) => exit_msg(1, "unknown volume command"),
+            };
+            volume(Volume {
+                root: root(args),
+                command,
+            })
+        }
+        ("pod", pod_cmd) => {
+            // The global flags like --root are given to the innermost command
+            let (command, args) = match pod_cmd.unwrap().subcommand() {
+                ("create", Some(args)) => (
+                    PodCommand::Create {
+                        name: args.value_of("name").expect("name is required").to_string(),
+                        share_pid: args.is_present("share-pid"),
+                        network: args.value_of("network").map(|n| n.to_string()),
+                    },
+                    args,
+                ),
+                ("add", Some(args)) => (PodCommand::Add(Box::new(create_args(args))), args),
+                ("ls", Some(args)) => (PodCommand::List, args),
+                ("rm", Some(args)) => (
+                    PodCommand::Remove {
+                        names: values(args, "name"),
+                        force: args.is_present("force"),
+                    },
+                    args,
+                ),
+                (_, _) => exit_msg(1, "unknown pod command"),
+            };
+            pod(Pod {
+                root: root(args),
+                command,
             })
         }
         (_, _) => exit_msg(1, "unknown container command"),
+    };
+
+    if let Err(err) = result {
+        match matches.subcommand_name() {
+            Some("run" | "wait" | "exec") => fail_run(&err),
+            _ => fail(&err),
+        }
     }
 
     exit(0);
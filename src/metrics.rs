@@ -0,0 +1,509 @@
+//! Prometheus metrics of the containers of a runtime root: the status and
+//! cgroup (v2) stats of every container, and the latencies of the runtime
+//! operations recorded in the audit log.
+
+use log::{debug, warn};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    time::Duration,
+};
+
+use crate::core::{
+    audit::AUDIT_FILE,
+    cgroups::{cgroup_of, pressure, Pressure, CGROUP_ROOT},
+    common::{Error, Result},
+    state::{self, State, Status},
+};
+
+/// Upper bounds of the operation latency buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const MAX_REQUEST: u64 = 8 * 1024;
+
+/// Samples of a metric, written in the Prometheus text format
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Family {
+        Family {
+            name,
+            help,
+            kind,
+            samples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, labels: &[(&str, &str)], value: f64) {
+        self.add_suffixed("", labels, value);
+    }
+
+    fn add_suffixed(&mut self, suffix: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        self.samples.push((
+            format!("{}{}{{{}}}", self.name, suffix, labels.join(",")),
+            value,
+        ));
+    }
+
+    fn write(&self, out: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (sample, value) in &self.samples {
+            let _ = writeln!(out, "{} {}", sample, value);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Metrics of the containers under the root, in the Prometheus text format
+pub fn render(root: &Path) -> Result<String> {
+    let mut status = Family::new(
+        "pura_container_status",
+        "gauge",
+        "Status of the container, 1 for its current status",
+    );
+    let mut memory = Family::new(
+        "pura_container_memory_usage_bytes",
+        "gauge",
+        "Memory used by the cgroup of the container",
+    );
+    let mut memory_limit = Family::new(
+        "pura_container_memory_limit_bytes",
+        "gauge",
+        "Memory limit of the cgroup of the container",
+    );
+    let mut cpu = Family::new(
+        "pura_container_cpu_usage_seconds_total",
+        "counter",
+        "CPU time used by the cgroup of the container",
+    );
+    let mut pids = Family::new(
+        "pura_container_pids",
+        "gauge",
+        "Processes in the cgroup of the container",
+    );
+    let mut oom_kills = Family::new(
+        "pura_container_oom_kills_total",
+        "counter",
+        "Processes of the container killed by the OOM killer",
+    );
+    let mut io_read = Family::new(
+        "pura_container_io_read_bytes_total",
+        "counter",
+        "Bytes read from block devices by the container",
+    );
+    let mut io_write = Family::new(
+        "pura_container_io_write_bytes_total",
+        "counter",
+        "Bytes written to block devices by the container",
+    );
+    let mut stalled = Family::new(
+        "pura_container_pressure_stalled_seconds_total",
+        "counter",
+        "Time some or all the tasks of the container stalled on a resource (PSI)",
+    );
+
+    for state in containers(root)? {
+        let id = state.id.as_str();
+        for current in [
+            Status::Creating,
+            Status::Created,
+            Status::Running,
+            Status::Stopped,
+        ] {
+            let value = if current == state.status { 1.0 } else { 0.0 };
+            status.add(&[("id", id), ("status", status_name(&current))], value);
+        }
+
+        let stats = match Stats::of(&state) {
+            Some(stats) => stats,
+            None => continue,
+        };
+        let labels = [("id", id)];
+        for (family, value) in [
+            (&mut memory, stats.memory_usage),
+            (&mut memory_limit, stats.memory_limit),
+            (&mut cpu, stats.cpu_seconds),
+            (&mut pids, stats.pids),
+            (&mut oom_kills, stats.oom_kills),
+            (&mut io_read, stats.io_read),
+            (&mut io_write, stats.io_write),
+        ] {
+            if let Some(value) = value {
+                family.add(&labels, value);
+            }
+        }
+        for (resource, pressure) in [
+            ("cpu", stats.cpu_pressure),
+            ("memory", stats.memory_pressure),
+            ("io", stats.io_pressure),
+        ] {
+            let pressure = match pressure {
+                Some(pressure) => pressure,
+                None => continue,
+            };
+            for (kind, line) in [("some", Some(pressure.some)), ("full", pressure.full)] {
+                if let Some(line) = line {
+                    let labels = [("id", id), ("resource", resource), ("kind", kind)];
+                    stalled.add(&labels, line.total as f64 / 1e6);
+                }
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for family in [
+        &status,
+        &memory,
+        &memory_limit,
+        &cpu,
+        &pids,
+        &oom_kills,
+        &io_read,
+        &io_write,
+        &stalled,
+    ] {
+        family.write(&mut out);
+    }
+    operation_durations(root).write(&mut out);
+    Ok(out)
+}
+
+fn status_name(status: &Status) -> &'static str {
+    match status {
+        Status::Creating => "creating",
+        Status::Created => "created",
+        Status::Running => "running",
+        Status::Stopped => "stopped",
+    }
+}
+
+/// Stats of the cgroup (v2) of a container, None for the missing files
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub memory_usage: Option<f64>,
+    pub memory_limit: Option<f64>,
+    pub cpu_seconds: Option<f64>,
+    pub pids: Option<f64>,
+    pub oom_kills: Option<f64>,
+    pub io_read: Option<f64>,
+    pub io_write: Option<f64>,
+    pub cpu_pressure: Option<Pressure>,
+    pub memory_pressure: Option<Pressure>,
+    pub io_pressure: Option<Pressure>,
+}
+
+impl Stats {
+    /// Stats of the cgroup of the container process, if it's created or running
+    pub fn of(state: &State) -> Option<Stats> {
+        if state.status != Status::Created && state.status != Status::Running {
+            return None;
+        }
+        let cgroup = cgroup_of(Pid::from_raw(state.pid as i32))?;
+        Some(Stats::read(
+            &Path::new(CGROUP_ROOT).join(cgroup.trim_start_matches('/')),
+        ))
+    }
+
+    /// Reads the stats of the cgroup directory
+    pub fn read(cgroup: &Path) -> Stats {
+        let read = |file: &str| std::fs::read_to_string(cgroup.join(file)).ok();
+        let value = |file: &str| read(file).and_then(|value| value.trim().parse().ok());
+        let io = read("io.stat").map(|stat| io_bytes(&stat));
+
+        Stats {
+            memory_usage: value("memory.current"),
+            // "max" without a limit
+            memory_limit: value("memory.max"),
+            cpu_seconds: read("cpu.stat")
+                .and_then(|stat| key_value(&stat, "usage_usec"))
+                .map(|usage| usage / 1e6),
+            pids: value("pids.current"),
+            oom_kills: read("memory.events").and_then(|events| key_value(&events, "oom_kill")),
+            io_read: io.map(|io| io.0),
+            io_write: io.map(|io| io.1),
+            cpu_pressure: pressure(cgroup, "cpu"),
+            memory_pressure: pressure(cgroup, "memory"),
+            io_pressure: pressure(cgroup, "io"),
+        }
+    }
+}
+
+/// States of the containers under the root, sorted by id.
+/// A container whose process is gone is reported as stopped.
+pub fn containers(root: &Path) -> Result<Vec<State>> {
+    Ok(state::open(root)?
+        .list()?
+        .into_iter()
+        .map(State::refreshed)
+        .collect())
+}
+
+/// Value of a `key value` line of a cgroup file
+fn key_value(content: &str, key: &str) -> Option<f64> {
+    content.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        if name == key {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Bytes read and written summed over the devices of io.stat
+fn io_bytes(stat: &str) -> (f64, f64) {
+    let mut bytes = (0.0, 0.0);
+    for field in stat.split_whitespace() {
+        match field.split_once('=') {
+            Some(("rbytes", value)) => bytes.0 += value.parse().unwrap_or(0.0),
+            Some(("wbytes", value)) => bytes.1 += value.parse().unwrap_or(0.0),
+            _ => (),
+        }
+    }
+    bytes
+}
+
+#[derive(Deserialize)]
+struct AuditRecord {
+    subcommand: String,
+    result: String,
+    duration: Option<f64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Histograms of the durations in the audit log, by operation and result
+fn operation_durations(root: &Path) -> Family {
+    let mut histograms: BTreeMap<(String, String), Histogram> = BTreeMap::new();
+    if let Ok(audit) = std::fs::File::open(root.join(AUDIT_FILE)) {
+        for line in BufReader::new(audit).lines().map_while(|line| line.ok()) {
+            let record: AuditRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            // Records written before the durations were audited
+            let duration = match record.duration {
+                Some(duration) => duration,
+                None => continue,
+            };
+            let histogram = histograms
+                .entry((record.subcommand, record.result))
+                .or_default();
+            for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS.iter()) {
+                if duration <= *bound {
+                    *bucket += 1;
+                }
+            }
+            histogram.count += 1;
+            histogram.sum += duration;
+        }
+    }
+
+    let mut family = Family::new(
+        "pura_operation_duration_seconds",
+        "histogram",
+        "Duration of the runtime operations",
+    );
+    for ((operation, result), histogram) in &histograms {
+        let labels = [
+            ("operation", operation.as_str()),
+            ("result", result.as_str()),
+        ];
+        for (bucket, bound) in histogram.buckets.iter().zip(BUCKETS.iter()) {
+            let le = bound.to_string();
+            family.add_suffixed(
+                "_bucket",
+                &[labels[0], labels[1], ("le", &le)],
+                *bucket as f64,
+            );
+        }
+        family.add_suffixed(
+            "_bucket",
+            &[labels[0], labels[1], ("le", "+Inf")],
+            histogram.count as f64,
+        );
+        family.add_suffixed("_sum", &labels, histogram.sum);
+        family.add_suffixed("_count", &labels, histogram.count as f64);
+    }
+    family
+}
+
+/// Serves the metrics of the root on `GET /metrics` until the process is killed
+pub fn serve(root: &Path, listen: &str) -> Result<()> {
+    let listener =
+        TcpListener::bind(listen).map_err(Error::io(format!("unable to listen on {}", listen)))?;
+    debug!("serving metrics on {}", listen);
+
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(Error::io("unable to accept metrics connection"))
+            .and_then(|stream| respond(root, stream));
+        if let Err(err) = result {
+            warn!("{}", err);
+        }
+    }
+    Ok(())
+}
+
+fn respond(root: &Path, mut stream: TcpStream) -> Result<()> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .map_err(Error::io("unable to set metrics read timeout"))?;
+
+    let mut request_line = String::new();
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+    reader
+        .read_line(&mut request_line)
+        .map_err(Error::io("unable to read metrics request"))?;
+    // Drain the headers, the request has no body
+    let mut header = String::new();
+    while reader
+        .read_line(&mut header)
+        .map_err(Error::io("unable to read metrics request"))?
+        > 2
+    {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", CONTENT_TYPE, render(root)?),
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", String::from("not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("method not allowed\n"),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(response.as_bytes())
+        .map_err(Error::io("unable to write metrics response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{io_bytes, key_value, render, Stats};
+    use crate::core::state::{State, Status};
+
+    #[test]
+    fn prometheus_text() {
+        let root = Path::new("./metrics_test");
+        let mut state = State::new("abc", 0, "/tmp");
+        state.status = Status::Stopped;
+        state.save(&root.join("abc")).unwrap();
+        std::fs::write(
+            root.join("audit.log"),
+            concat!(
+                "{\"subcommand\":\"create\",\"id\":\"abc\",\"result\":\"success\",\"code\":0,\"duration\":0.03}\n",
+                "{\"subcommand\":\"create\",\"id\":\"abc\",\"result\":\"success\",\"code\":0,\"duration\":2}\n",
+                "{\"subcommand\":\"kill\",\"id\":\"abc\",\"result\":\"failure\",\"code\":5}\n",
+            ),
+        )
+        .unwrap();
+
+        let metrics = render(root).unwrap();
+        assert!(metrics.contains("# TYPE pura_container_status gauge\n"));
+        assert!(metrics.contains("pura_container_status{id=\"abc\",status=\"stopped\"} 1\n"));
+        assert!(metrics.contains("pura_container_status{id=\"abc\",status=\"running\"} 0\n"));
+        let create = "operation=\"create\",result=\"success\"";
+        assert!(metrics.contains(&format!(
+            "pura_operation_duration_seconds_bucket{{{},le=\"0.05\"}} 1\n",
+            create
+        )));
+        assert!(metrics.contains(&format!(
+            "pura_operation_duration_seconds_bucket{{{},le=\"+Inf\"}} 2\n",
+            create
+        )));
+        assert!(metrics.contains(&format!(
+            "pura_operation_duration_seconds_count{{{}}} 2\n",
+            create
+        )));
+        // Records without a duration are skipped
+        assert!(!metrics.contains("operation=\"kill\""));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn cgroup_stats() {
+        let cgroup = Path::new("./metrics_cgroup_test");
+        std::fs::create_dir_all(cgroup).unwrap();
+        std::fs::write(cgroup.join("memory.current"), "4096\n").unwrap();
+        std::fs::write(cgroup.join("memory.max"), "max\n").unwrap();
+        std::fs::write(cgroup.join("cpu.stat"), "usage_usec 2500000\n").unwrap();
+        std::fs::write(cgroup.join("io.stat"), "8:0 rbytes=10 wbytes=20\n").unwrap();
+        std::fs::write(
+            cgroup.join("memory.pressure"),
+            concat!(
+                "some avg10=2.00 avg60=1.00 avg300=0.50 total=1000\n",
+                "full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"
+            ),
+        )
+        .unwrap();
+
+        let stats = Stats::read(cgroup);
+        assert_eq!(stats.memory_usage, Some(4096.0));
+        assert_eq!(stats.memory_limit, None);
+        assert_eq!(stats.cpu_seconds, Some(2.5));
+        assert_eq!(stats.pids, None);
+        assert_eq!((stats.io_read, stats.io_write), (Some(10.0), Some(20.0)));
+        assert_eq!(stats.memory_pressure.unwrap().some.avg10, 2.0);
+        assert_eq!(stats.cpu_pressure, None);
+
+        std::fs::remove_dir_all(cgroup).unwrap();
+    }
+
+    #[test]
+    fn cgroup_files() {
+        assert_eq!(
+            key_value("usage_usec 1500000\nuser_usec 10\n", "usage_usec"),
+            Some(1500000.0)
+        );
+        assert_eq!(
+            io_bytes("8:0 rbytes=10 wbytes=20 rios=1\n8:16 rbytes=5 wbytes=0\n"),
+            (15.0, 20.0)
+        );
+    }
+}
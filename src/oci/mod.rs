@@ -1,2 +1,3 @@
 pub mod spec;
-pub mod ops;
\ No newline at end of file
+pub mod ops;
+pub mod validate;
\ No newline at end of file
@@ -1,28 +1,288 @@
+#[derive(Debug, Clone)]
 pub struct Create {
     pub id: String,
     pub bundle: String,
     pub console_socket: Option<String>,
     pub pid_file: Option<String>,
     pub root: String,
+    /// `bridge=NAME,address=CIDR[,gateway=IP]`, see `core::network::Network`
+    pub network: Option<String>,
+    /// Leave the container process to a monitor holding its stdio, see `core::monitor`
+    pub detach: bool,
+    /// Run the process under the init shim of pura, see `core::init`
+    pub init: bool,
+    /// CDI devices like `vendor.com/gpu=0`, see `core::cdi`
+    pub devices: Vec<String>,
+    /// `all` or indices of NVIDIA GPUs, see `core::nvidia`
+    pub gpus: Option<String>,
+    /// Image `PATH[:NAME]` of an OCI layout the bundle is synthesized from
+    /// instead, see `image::create_bundle`
+    pub image: Option<String>,
+    /// Tarball extracted as the rootfs of the container in its state dir,
+    /// replacing the root of the spec
+    pub rootfs_tar: Option<String>,
+    /// Mount the rootfs as the lower layer of an overlay, see `core::overlay`
+    pub overlay: bool,
+    /// `SOURCE:DEST[:OPTIONS]` volumes bind mounted into the container, see
+    /// `core::volume::VolumeMount`
+    pub volumes: Vec<String>,
+    /// Record the console of the detached container, see `core::recording`
+    pub record: bool,
+    /// Rotation of the log of the detached container, see `core::jsonfile::Rotation`
+    pub log_rotation: Option<crate::core::jsonfile::Rotation>,
+    /// Output buffered for each client attached to the detached container, see
+    /// `core::monitor::ConsoleBuffer`
+    pub console_buffer: Option<crate::core::monitor::ConsoleBuffer>,
+    /// Pod whose namespaces the container joins, see `core::pod`
+    pub pod: Option<String>,
+    /// When the monitor of the detached container creates it again after it
+    /// stopped, see `core::restart`
+    pub restart: crate::core::restart::RestartPolicy,
 }
 
+#[derive(Debug, Clone)]
 pub struct Start {
     pub id: String,
     pub root: String,
 }
 
+#[derive(Debug, Clone)]
 pub struct Delete {
     pub id: String,
     pub root: String,
+    /// Kill the container first if it's running
+    pub force: bool,
 }
 
+#[derive(Debug, Clone)]
 pub struct State {
     pub id: String,
     pub root: String,
+    pub format: crate::format::Format,
+    /// Prints the state again on each change, until the container is deleted
+    pub watch: bool,
+    /// Prints nothing and only reports the status through the exit code
+    pub quiet: bool,
 }
 
+#[derive(Debug, Clone)]
+pub struct List {
+    pub root: String,
+    pub format: crate::format::Format,
+    /// Prints the list again on each change, until interrupted
+    pub watch: bool,
+}
+
+#[derive(Debug, Clone)]
 pub struct Kill {
     pub id: String,
     pub root: String,
     pub signal: i32,
-}
\ No newline at end of file
+    /// Signal the processes of the cgroup and the exec'd processes too
+    pub all: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Exec {
+    pub id: String,
+    pub root: String,
+    /// Command and its arguments
+    pub args: Vec<String>,
+    /// Return once the command is executed, without waiting for it
+    pub detach: bool,
+    pub pid_file: Option<String>,
+    /// KEY=VALUE variables added to the environment of the process of the spec
+    pub env: Vec<String>,
+    /// USER[:GROUP], names or ids of the container, instead of the user of the process of the spec
+    pub user: Option<String>,
+    /// Working directory instead of the one of the process of the spec
+    pub cwd: Option<String>,
+    pub cap_add: Vec<String>,
+    pub cap_drop: Vec<String>,
+    /// Child cgroup of the container to exec the command in, created if missing
+    pub cgroup: Option<String>,
+    /// Resources of the child cgroup
+    pub resources: Option<crate::oci::spec::Resources>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Events {
+    pub id: String,
+    pub root: String,
+    pub follow: bool,
+    /// Prints the stats of the cgroup once instead of the events
+    pub stats: bool,
+    /// Prints the stats of the cgroup at the interval until the container stops
+    pub interval: Option<std::time::Duration>,
+    /// Percentage of stalled time over 10s reported as a pressure event
+    pub pressure_threshold: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Wait {
+    pub id: String,
+    pub root: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub create: Create,
+    /// Forward the signals to every process of the cgroup, not only the container process
+    pub all: bool,
+    /// Delete the container once it stopped
+    pub rm: bool,
+    /// Resources overriding those of the spec
+    pub resources: Option<crate::oci::spec::Resources>,
+    /// File the syscalls of the container process are written to, see
+    /// `core::trace`
+    pub trace_syscalls: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Update {
+    pub id: String,
+    pub root: String,
+    /// Resources replacing those of the spec, the unset ones are kept
+    pub resources: crate::oci::spec::Resources,
+    /// Devices added to the container, `HOST[:CONTAINER[:PERMISSIONS]]`
+    pub devices: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attach {
+    pub id: String,
+    pub root: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Logs {
+    pub id: String,
+    pub root: String,
+    /// Wait for new output until the container stops
+    pub follow: bool,
+    /// Number of last entries to print
+    pub tail: Option<usize>,
+    /// RFC 3339 timestamp, or duration ago like `10m`
+    pub since: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub id: String,
+    pub root: String,
+    /// Directory receiving the criu images
+    pub image_path: String,
+    /// Images of a previous pre-dump, relative to the image path
+    pub parent_path: Option<String>,
+    /// Directory of the criu log and temporary files, the image path by default
+    pub work_path: Option<String>,
+    /// Dump the memory only, leaving the container running
+    pub pre_dump: bool,
+    /// Keep the container running after the dump
+    pub leave_running: bool,
+    /// Checkpoint the established TCP connections
+    pub tcp_established: bool,
+    /// Leave the memory pages to be fetched lazily on restore
+    pub lazy_pages: bool,
+    /// ADDRESS:PORT of the page server receiving the memory, or to serve lazy pages on
+    pub page_server: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Restore {
+    /// ID of the new container, not necessarily the checkpointed one
+    pub id: String,
+    pub bundle: String,
+    pub root: String,
+    /// Directory holding the criu images of the checkpoint
+    pub image_path: String,
+    /// Directory of the criu log and temporary files, the image path by default
+    pub work_path: Option<String>,
+    pub pid_file: Option<String>,
+    /// `bridge=NAME,address=CIDR[,gateway=IP]`, set up in the restored network namespace
+    pub network: Option<String>,
+    /// Restore the established TCP connections of the checkpoint
+    pub tcp_established: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Validate {
+    pub bundle: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub root: String,
+    /// ADDRESS:PORT to serve the metrics on, printed once without it
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Top {
+    /// Container to show, all the containers of the root without one
+    pub id: Option<String>,
+    pub root: String,
+    pub interval: std::time::Duration,
+    /// Refreshes before exiting, until interrupted without one
+    pub iterations: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugReport {
+    pub id: String,
+    pub root: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pull {
+    /// Image reference, `oci:PATH[:NAME]` or `docker-archive:PATH`, see `image::Source`
+    pub source: String,
+    /// Image store, the default one without it
+    pub images: Option<String>,
+    /// Bundle to create from the image
+    pub bundle: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum VolumeCommand {
+    Create { name: String },
+    List,
+    /// Removes the volumes, even those used by a container if forced
+    Remove { names: Vec<String>, force: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub root: String,
+    pub command: VolumeCommand,
+}
+
+#[derive(Debug, Clone)]
+pub enum PodCommand {
+    /// Starts a pod, sharing its PID namespace too if `share_pid`, with the
+    /// network of `core::network::Network::parse` if any
+    Create {
+        name: String,
+        share_pid: bool,
+        network: Option<String>,
+    },
+    /// Creates a container in the pod
+    Add(Box<Create>),
+    List,
+    /// Removes the pods, even those with containers if forced
+    Remove { names: Vec<String>, force: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct Pod {
+    pub root: String,
+    pub command: PodCommand,
+}
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub id: String,
+    pub root: String,
+    /// Factor the recording is sped up by
+    pub speed: f64,
+}
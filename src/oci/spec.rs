@@ -1,142 +1,649 @@
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, convert::TryFrom, path::Path};
+use nix::{
+    fcntl::{open, OFlag},
+    libc::c_void,
+    sys::{
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+        stat::{fstat, Mode},
+    },
+    unistd::close,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    borrow::{Borrow, Cow},
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    hash::Hash,
+    ops::Deref,
+    path::Path,
+    ptr::{self, NonNull},
+    slice,
+};
 
-use crate::core::common::{ErrorType, Error};
+use crate::core::common::{Error, Result};
 
+const OCI_VERSION: &str = "1.0.2";
+
+/// Fields of an object of the spec that pura doesn't know, from a newer
+/// runtime-spec or an extension, written back as they were read
+pub type Extensions = serde_json::Map<String, serde_json::Value>;
+
+/// OCI runtime spec of a container, the config.json of its bundle.
+///
+/// The strings of the spec are `String`s, or `Str`s borrowing them from the
+/// config.json `SpecFile` maps in memory.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Spec {
-    pub oci_version: String,
-    pub root: Root,
-    pub mounts: Option<Vec<Mount>>,
-    pub process: Option<Process>,
-    pub hostname: Option<String>,
-    pub hooks: Option<Hooks>,
-    pub linux: Option<Linux>,
-    pub annotations: Option<HashMap<String, String>>,
+pub struct Spec<S = String>
+where
+    S: Eq + Hash,
+{
+    pub oci_version: S,
+    pub root: Root<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mounts: Option<Vec<Mount<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process: Option<Process<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domainname: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<Hooks<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub linux: Option<Linux<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<S, S>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 impl TryFrom<&Path> for Spec {
     type Error = Error;
 
-    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+    fn try_from(path: &Path) -> std::result::Result<Self, Self::Error> {
         let spec_json = std::fs::read_to_string(path)
-            .map_err(|_| Self::Error { msg: "spec file not found".to_string(), err_type: ErrorType::Runtime })?;
-        let state: Spec = serde_json::from_str(&spec_json)
-            .map_err(|err| Self::Error { msg: format!("unable to deserialize spec file {}", err.to_string()), err_type: ErrorType::Runtime })?;
+            .map_err(|err| Error::Bundle(format!("spec file {:?} not found: {}", path, err)))?;
+        let state: Spec = serde_json::from_str(&spec_json).map_err(Error::Spec)?;
         Ok(state)
     }
 }
 
+/// Builder-style construction of a spec, e.g. to generate bundles from code:
+///
+/// ```
+/// use pura::oci::spec::{Mount, Spec};
+///
+/// let spec = Spec::new()
+///     .with_rootfs("rootfs", true)
+///     .with_args(&["sh", "-c", "echo hello"])
+///     .add_mount(Mount::new("/proc", "proc", "proc", &[]))
+///     .add_namespace("pid", None)
+///     .add_namespace("mount", None);
+/// assert_eq!(spec.root.path, "rootfs");
+/// ```
+impl Spec {
+    /// Empty spec of the supported runtime-spec version
+    pub fn new() -> Spec {
+        Spec {
+            oci_version: String::from(OCI_VERSION),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rootfs(mut self, path: &str, readonly: bool) -> Spec {
+        self.root = Root {
+            path: path.to_string(),
+            readonly: Some(readonly),
+            ..Default::default()
+        };
+        self
+    }
+
+    pub fn with_hostname(mut self, hostname: &str) -> Spec {
+        self.hostname = Some(hostname.to_string());
+        self
+    }
+
+    pub fn with_domainname(mut self, domainname: &str) -> Spec {
+        self.domainname = Some(domainname.to_string());
+        self
+    }
+
+    pub fn with_process(mut self, process: Process) -> Spec {
+        self.process = Some(process);
+        self
+    }
+
+    /// Sets the args of the process, running in `/` unless a cwd was set
+    pub fn with_args(mut self, args: &[&str]) -> Spec {
+        let process = self.process.get_or_insert_with(|| Process {
+            cwd: String::from("/"),
+            ..Default::default()
+        });
+        process.args = Some(args.iter().map(|arg| arg.to_string()).collect());
+        self
+    }
+
+    /// Sets the variable in the env of the process, replacing a previous value
+    pub fn add_env(mut self, key: &str, value: &str) -> Spec {
+        let process = self.process.get_or_insert_with(|| Process {
+            cwd: String::from("/"),
+            ..Default::default()
+        });
+        let env = process.env.get_or_insert_with(Vec::new);
+        env.retain(|entry| entry.split_once('=').map(|(k, _)| k) != Some(key));
+        env.push(format!("{}={}", key, value));
+        self
+    }
+
+    pub fn add_mount(mut self, mount: Mount) -> Spec {
+        self.mounts.get_or_insert_with(Vec::new).push(mount);
+        self
+    }
+
+    pub fn add_masked_path(mut self, path: &str) -> Spec {
+        self.linux
+            .get_or_insert_with(Default::default)
+            .masked_paths
+            .get_or_insert_with(Vec::new)
+            .push(path.to_string());
+        self
+    }
+
+    pub fn add_readonly_path(mut self, path: &str) -> Spec {
+        self.linux
+            .get_or_insert_with(Default::default)
+            .readonly_paths
+            .get_or_insert_with(Vec::new)
+            .push(path.to_string());
+        self
+    }
+
+    /// Adds a new namespace of the type, or joins the one at path
+    pub fn add_net_device(mut self, host_name: &str, name: Option<&str>) -> Spec {
+        self.linux
+            .get_or_insert_with(Default::default)
+            .net_devices
+            .get_or_insert_with(HashMap::new)
+            .insert(
+                host_name.to_string(),
+                NetDevice {
+                    name: name.map(str::to_string),
+                    ..Default::default()
+                },
+            );
+        self
+    }
+
+    pub fn add_namespace(mut self, namespace: &str, path: Option<&str>) -> Spec {
+        self.linux
+            .get_or_insert_with(Default::default)
+            .namespaces
+            .get_or_insert_with(Vec::new)
+            .push(Namespace {
+                namespace: namespace.to_string(),
+                path: path.map(|p| p.to_string()),
+                ..Default::default()
+            });
+        self
+    }
+
+    pub fn with_resources(mut self, resources: Resources) -> Spec {
+        self.linux.get_or_insert_with(Default::default).resources = Some(resources);
+        self
+    }
+
+    pub fn add_annotation(mut self, key: &str, value: &str) -> Spec {
+        self.annotations
+            .get_or_insert_with(HashMap::new)
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Writes the spec as the config.json of the bundle
+    pub fn save(&self, bundle: &Path) -> Result<()> {
+        let spec_json =
+            serde_json::to_string_pretty(self).map_err(Error::json("unable to serialize spec"))?;
+        std::fs::write(bundle.join("config.json"), spec_json)
+            .map_err(Error::io(format!("unable to write config.json to {:?}", bundle)))
+    }
+}
+
+/// String of a spec parsed by `SpecFile`, borrowed from the file unless it
+/// had escapes to unescape
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct Str<'a>(pub Cow<'a, str>);
+
+impl Str<'_> {
+    pub fn into_owned(self) -> String {
+        self.0.into_owned()
+    }
+}
+
+impl Deref for Str<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Str<'_> {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Looks up maps keyed by `Str`, like the annotations, with a `&str`
+impl Borrow<str> for Str<'_> {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Str<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl PartialEq<str> for Str<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Str<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Str<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Str<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, value: &'de str) -> std::result::Result<Str<'de>, E> {
+                Ok(Str(Cow::Borrowed(value)))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<Str<'de>, E> {
+                Ok(Str(Cow::Owned(value.to_string())))
+            }
+
+            fn visit_string<E: de::Error>(self, value: String) -> std::result::Result<Str<'de>, E> {
+                Ok(Str(Cow::Owned(value)))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+/// config.json mapped in memory, parsed into a spec borrowing its strings
+/// from the mapping instead of allocating them.
+///
+/// The bundle must not change while the file is mapped, a truncated file
+/// faults on access like any mapped file.
+///
+/// ```no_run
+/// use pura::oci::spec::SpecFile;
+///
+/// let file = SpecFile::open("/path/to/bundle/config.json".as_ref())?;
+/// let spec = file.spec()?;
+/// let image = spec
+///     .annotations
+///     .as_ref()
+///     .and_then(|annotations| annotations.get("org.opencontainers.image.ref.name"));
+/// # Ok::<(), pura::core::common::Error>(())
+/// ```
+pub struct SpecFile {
+    /// None for an empty file, which can't be mapped
+    addr: Option<NonNull<u8>>,
+    len: usize,
+}
+
+// The mapping is private and read-only
+unsafe impl Send for SpecFile {}
+unsafe impl Sync for SpecFile {}
+
+impl SpecFile {
+    pub fn open(path: &Path) -> Result<SpecFile> {
+        let fd = open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+            .map_err(|err| Error::Bundle(format!("spec file {:?} not found: {}", path, err)))?;
+        let mapped = fstat(fd)
+            .map_err(Error::sys(format!("unable to stat {:?}", path)))
+            .and_then(|stat| {
+                let len = stat.st_size as usize;
+                if len == 0 {
+                    return Ok(SpecFile { addr: None, len });
+                }
+                let addr = unsafe {
+                    mmap(ptr::null_mut(), len, ProtFlags::PROT_READ, MapFlags::MAP_PRIVATE, fd, 0)
+                }
+                .map_err(Error::sys(format!("unable to map {:?}", path)))?;
+                Ok(SpecFile {
+                    addr: NonNull::new(addr as *mut u8),
+                    len,
+                })
+            });
+        // The mapping stays valid once the fd is closed
+        let _ = close(fd);
+        mapped
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.addr {
+            Some(addr) => unsafe { slice::from_raw_parts(addr.as_ptr(), self.len) },
+            None => &[],
+        }
+    }
+
+    /// Parses the spec, its strings borrowed from the file
+    pub fn spec(&self) -> Result<Spec<Str<'_>>> {
+        serde_json::from_slice(self.as_bytes()).map_err(Error::Spec)
+    }
+}
+
+impl Drop for SpecFile {
+    fn drop(&mut self) {
+        if let Some(addr) = self.addr {
+            let _ = unsafe { munmap(addr.as_ptr() as *mut c_void, self.len) };
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Process {
+pub struct Process<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub terminal: Option<bool>,
-    pub user: Option<User>,
-    pub args: Option<Vec<String>>,
-    pub env: Option<Vec<String>>,
-    pub cwd: String,
-    pub capabilities: Option<Capabilities>,
-    pub rlimits: Option<Vec<Rlimit>>,
-    pub apparmor_profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<S>>,
+    pub cwd: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rlimits: Option<Vec<Rlimit<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apparmor_profile: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub oom_score_adj: Option<i64>,
-    pub selinux_label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selinux_label: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub no_new_privileges: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduler: Option<Scheduler<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_priority: Option<IoPriority<S>>,
+    #[serde(rename = "execCPUAffinity", skip_serializing_if = "Option::is_none")]
+    pub exec_cpu_affinity: Option<ExecCpuAffinity<S>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scheduler<S = String> {
+    pub policy: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IoPriority<S = String> {
+    pub class: S,
+    pub priority: i64,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct User {
+pub struct ExecCpuAffinity<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial: Option<S>,
+    #[serde(rename = "final", skip_serializing_if = "Option::is_none")]
+    pub final_: Option<S>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User<S = String> {
     pub uid: i64,
     pub gid: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub additional_gids: Option<Vec<i64>>,
+    /// `USER[:GROUP]` resolved in the /etc/passwd and /etc/group of the
+    /// rootfs, taking precedence over the ids
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<S>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Capabilities {
-    pub bounding: Option<Vec<String>>,
-    pub permitted: Option<Vec<String>>,
-    pub inheritable: Option<Vec<String>>,
-    pub effective: Option<Vec<String>>,
-    pub ambient: Option<Vec<String>>,
+pub struct Capabilities<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bounding: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permitted: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inheritable: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ambient: Option<Vec<S>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Rlimit {
+pub struct Rlimit<S = String> {
     #[serde(rename = "type")]
-    pub type_field: String,
+    pub type_field: S,
     pub hard: i64,
     pub soft: i64,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Root {
-    pub path: String,
+pub struct Root<S = String> {
+    pub path: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub readonly: Option<bool>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Mount {
-    pub destination: String,
-    #[serde(rename = "type")]
-    pub mount_type: Option<String>,
-    pub source: Option<String>,
-    #[serde(default)]
-    pub options: Option<Vec<String>>,
+pub struct Mount<S = String> {
+    pub destination: S,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub mount_type: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Vec<S>>,
+    /// Mappings of an idmapped mount
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid_mappings: Option<Vec<UidMapping>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid_mappings: Option<Vec<UidMapping>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+impl Mount {
+    pub fn new(destination: &str, mount_type: &str, source: &str, options: &[&str]) -> Mount {
+        Mount {
+            destination: destination.to_string(),
+            mount_type: Some(mount_type.to_string()),
+            source: Some(source.to_string()),
+            options: Some(options.iter().map(|opt| opt.to_string()).collect()),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Hooks {
-    pub prestart: Option<Vec<Hook>>,
-    pub create_runtime: Option<Vec<Hook>>,
-    pub create_container: Option<Vec<Hook>>,
-    pub start_container: Option<Vec<Hook>>,
-    pub poststart: Option<Vec<Hook>>,
-    pub poststop: Option<Vec<Hook>>,
+pub struct Hooks<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prestart: Option<Vec<Hook<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub create_runtime: Option<Vec<Hook<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub create_container: Option<Vec<Hook<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_container: Option<Vec<Hook<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poststart: Option<Vec<Hook<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poststop: Option<Vec<Hook<S>>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Hook {
-    pub path: String,
-    pub args: Option<Vec<String>>,
-    pub env: Option<Vec<String>>,
+pub struct Hook<S = String> {
+    pub path: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub timeout: Option<i64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Linux {
-    pub devices: Option<Vec<Device>>,
+pub struct Linux<S = String>
+where
+    S: Eq + Hash,
+{
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<Device<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub uid_mappings: Option<Vec<UidMapping>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub gid_mappings: Option<Vec<UidMapping>>,
-    pub sysctl: Option<HashMap<String, String>>,
-    pub cgroups_path: Option<String>,
-    pub resources: Option<Resources>,
-    pub rootfs_propagation: Option<String>,
-    pub seccomp: Option<Seccomp>,
-    pub namespaces: Option<Vec<Namespace>>,
-    pub masked_paths: Option<Vec<String>>,
-    pub readonly_paths: Option<Vec<String>>,
-    pub mount_label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sysctl: Option<HashMap<S, S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cgroups_path: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<Resources<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rootfs_propagation: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<Seccomp<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<Vec<Namespace<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub masked_paths: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub readonly_paths: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount_label: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_offsets: Option<HashMap<S, TimeOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_devices: Option<HashMap<S, NetDevice<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_policy: Option<MemoryPolicy<S>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+/// Offset of a clock of the time namespace
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeOffset {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secs: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nanosecs: Option<u32>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
+/// NUMA memory policy of the container process, see set_mempolicy(2)
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Device {
-    pub path: String,
+pub struct MemoryPolicy<S = String> {
+    pub mode: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<S>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+/// Host network device moved into the network namespace of the container
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetDevice<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<S>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Device<S = String> {
+    pub path: S,
     #[serde(rename = "type")]
-    pub device_type: String,
+    pub device_type: S,
     pub major: u64,
     pub minor: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub file_mode: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub gid: Option<u32>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -147,95 +654,148 @@ pub struct UidMapping {
     #[serde(rename = "hostID")]
     pub host_id: i64,
     pub size: i64,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Resources {
-    pub network: Option<Network>,
+pub struct Resources<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub pids: Option<Pids>,
-    pub hugepage_limits: Option<Vec<HugepageLimit>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugepage_limits: Option<Vec<HugepageLimit<S>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub memory: Option<Memory>,
-    pub cpu: Option<Cpu>,
-    pub devices: Option<Vec<DeviceResource>>,
-    #[serde(rename = "blockIO")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<Cpu<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<DeviceResource<S>>>,
+    #[serde(rename = "blockIO", skip_serializing_if = "Option::is_none")]
     pub block_io: Option<BlockIo>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Network {
-    #[serde(rename = "classID")]
+pub struct Network<S = String> {
+    #[serde(rename = "classID", skip_serializing_if = "Option::is_none")]
     pub class_id: Option<i64>,
-    pub priorities: Option<Vec<Priority>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priorities: Option<Vec<Priority<S>>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Priority {
-    pub name: String,
+pub struct Priority<S = String> {
+    pub name: S,
     pub priority: i64,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Pids {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<i64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct HugepageLimit {
-    pub page_size: String,
+pub struct HugepageLimit<S = String> {
+    pub page_size: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<i64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Memory {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub reservation: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub swap: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub kernel: Option<i64>,
-    #[serde(rename = "kernelTCP")]
+    #[serde(rename = "kernelTCP", skip_serializing_if = "Option::is_none")]
     pub kernel_tcp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub swappiness: Option<i64>,
-    #[serde(rename = "disableOOMKiller")]
+    #[serde(rename = "disableOOMKiller", skip_serializing_if = "Option::is_none")]
     pub disable_oomkiller: Option<bool>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Cpu {
+pub struct Cpu<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub shares: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub quota: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub period: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub realtime_runtime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub realtime_period: Option<i64>,
-    pub cpus: Option<String>,
-    pub mems: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mems: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle: Option<i64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct DeviceResource {
+pub struct DeviceResource<S = String> {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub allow: Option<bool>,
-    pub access: Option<String>,
-    #[serde(rename = "type")]
-    pub device_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access: Option<S>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub major: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub minor: Option<i64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BlockIo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub weight: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub leaf_weight: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub weight_device: Option<Vec<WeightDevice>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub throttle_read_bps_device: Option<Vec<ThrottleReadBpsDevice>>,
-    #[serde(rename = "throttleWriteIOPSDevice")]
+    #[serde(rename = "throttleWriteIOPSDevice", skip_serializing_if = "Option::is_none")]
     pub throttle_write_iopsdevice: Option<Vec<ThrottleWriteIopsdevice>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -243,8 +803,12 @@ pub struct BlockIo {
 pub struct WeightDevice {
     pub major: i64,
     pub minor: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub weight: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub leaf_weight: Option<i64>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -253,6 +817,8 @@ pub struct ThrottleReadBpsDevice {
     pub major: i64,
     pub minor: i64,
     pub rate: i64,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -261,27 +827,231 @@ pub struct ThrottleWriteIopsdevice {
     pub major: i64,
     pub minor: i64,
     pub rate: i64,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Seccomp {
-    pub default_action: String,
-    pub architectures: Option<Vec<String>>,
-    pub syscalls: Option<Vec<Syscall>>,
+pub struct Seccomp<S = String> {
+    pub default_action: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_errno_ret: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architectures: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<S>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener_path: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener_metadata: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syscalls: Option<Vec<Syscall<S>>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Syscall {
-    pub names: Vec<String>,
-    pub action: String,
+pub struct Syscall<S = String> {
+    pub names: Vec<S>,
+    pub action: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errno_ret: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<SyscallArg<S>>>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Namespace {
+pub struct SyscallArg<S = String> {
+    pub index: u32,
+    pub value: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_two: Option<u64>,
+    pub op: S,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Namespace<S = String> {
     #[serde(rename = "type")]
-    pub namespace: String,
-    pub path: Option<String>,
+    pub namespace: S,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<S>,
+    #[serde(flatten)]
+    pub extensions: Extensions,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, convert::TryFrom, path::Path};
+
+    use super::{Memory, Mount, Resources, Spec, SpecFile};
+
+    #[test]
+    fn build_and_save() {
+        let bundle = Path::new("./spec_test");
+        std::fs::create_dir_all(bundle).unwrap();
+
+        let spec = Spec::new()
+            .with_rootfs("rootfs", false)
+            .with_hostname("builder")
+            .with_args(&["/bin/true"])
+            .add_env("PATH", "/usr/bin")
+            .add_env("PATH", "/bin")
+            .add_mount(Mount::new("/tmp", "tmpfs", "tmpfs", &["nosuid"]))
+            .add_namespace("uts", None)
+            .add_namespace("network", Some("/proc/1/ns/net"))
+            .with_resources(Resources {
+                memory: Some(Memory {
+                    limit: Some(1 << 20),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .add_annotation("org.example", "test");
+        spec.save(bundle).unwrap();
+
+        let saved = Spec::try_from(bundle.join("config.json").as_path()).unwrap();
+        assert_eq!(saved, spec);
+        assert_eq!(saved.oci_version, "1.0.2");
+        let process = saved.process.unwrap();
+        assert_eq!(process.cwd, "/");
+        assert_eq!(process.env.unwrap(), vec!["PATH=/bin"]);
+        assert_eq!(saved.linux.unwrap().namespaces.unwrap().len(), 2);
+
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn runtime_spec_1_2() {
+        let spec_json = r#"{
+            "ociVersion": "1.2.0",
+            "root": { "path": "rootfs" },
+            "domainname": "example.com",
+            "process": {
+                "cwd": "/",
+                "args": ["sh"],
+                "scheduler": { "policy": "SCHED_FIFO", "priority": 10, "flags": ["SCHED_FLAG_RESET_ON_FORK"] },
+                "ioPriority": { "class": "IOPRIO_CLASS_RT", "priority": 4 },
+                "execCPUAffinity": { "initial": "0", "final": "0-3" }
+            },
+            "mounts": [{
+                "destination": "/data",
+                "type": "bind",
+                "source": "/srv/data",
+                "options": ["rbind"],
+                "uidMappings": [{ "containerID": 0, "hostID": 1000, "size": 1 }],
+                "gidMappings": [{ "containerID": 0, "hostID": 1000, "size": 1 }]
+            }],
+            "linux": {
+                "timeOffsets": { "monotonic": { "secs": 172800, "nanosecs": 0 } },
+                "netDevices": { "eth1": { "name": "ctr1" } }
+            }
+        }"#;
+
+        let spec: Spec = serde_json::from_str(spec_json).unwrap();
+        assert_eq!(spec.domainname.as_deref(), Some("example.com"));
+
+        let process = spec.process.as_ref().unwrap();
+        assert_eq!(process.scheduler.as_ref().unwrap().priority, Some(10));
+        assert_eq!(process.io_priority.as_ref().unwrap().class, "IOPRIO_CLASS_RT");
+        let affinity = process.exec_cpu_affinity.as_ref().unwrap();
+        assert_eq!(affinity.final_.as_deref(), Some("0-3"));
+
+        let mount = &spec.mounts.as_ref().unwrap()[0];
+        assert_eq!(mount.uid_mappings.as_ref().unwrap()[0].host_id, 1000);
+
+        let linux = spec.linux.as_ref().unwrap();
+        assert_eq!(linux.time_offsets.as_ref().unwrap()["monotonic"].secs, Some(172800));
+        assert_eq!(
+            linux.net_devices.as_ref().unwrap()["eth1"].name.as_deref(),
+            Some("ctr1")
+        );
+
+        // Nothing is dropped when writing the spec back
+        let value = serde_json::to_value(&spec).unwrap();
+        assert_eq!(value["process"]["execCPUAffinity"]["final"], "0-3");
+        assert_eq!(value["mounts"][0]["gidMappings"][0]["containerID"], 0);
+        assert_eq!(value["linux"]["netDevices"]["eth1"]["name"], "ctr1");
+    }
+
+    #[test]
+    fn borrowed_from_file() {
+        let bundle = Path::new("./spec_file_test");
+        std::fs::create_dir_all(bundle).unwrap();
+        let spec = Spec::new()
+            .with_rootfs("rootfs", false)
+            .with_args(&["/bin/echo", "quoted \"arg\""])
+            .add_mount(Mount::new("/tmp", "tmpfs", "tmpfs", &["nosuid"]))
+            .add_annotation("org.example", "test");
+        spec.save(bundle).unwrap();
+
+        let file = SpecFile::open(&bundle.join("config.json")).unwrap();
+        let borrowed = file.spec().unwrap();
+        assert_eq!(borrowed.root.path, "rootfs");
+        assert_eq!(borrowed.annotations.as_ref().unwrap()["org.example"], "test");
+        let args = borrowed.process.as_ref().unwrap().args.as_ref().unwrap();
+        // Strings are borrowed from the file, unless they had escapes
+        assert!(matches!(args[0].0, Cow::Borrowed("/bin/echo")));
+        assert!(matches!(&args[1].0, Cow::Owned(arg) if arg == "quoted \"arg\""));
+        // Same spec as the owned parse
+        assert_eq!(
+            serde_json::to_value(&borrowed).unwrap(),
+            serde_json::to_value(&spec).unwrap()
+        );
+
+        std::fs::write(bundle.join("config.json"), "").unwrap();
+        assert!(SpecFile::open(&bundle.join("config.json")).unwrap().spec().is_err());
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let spec_json = r#"{
+            "ociVersion": "1.3.0",
+            "root": { "path": "rootfs", "readonly": true },
+            "process": {
+                "cwd": "/",
+                "args": ["sh"],
+                "user": { "uid": 0, "gid": 0 },
+                "personality": { "domain": "LINUX32" }
+            },
+            "hostname": "example",
+            "mounts": [{
+                "destination": "/tmp",
+                "type": "tmpfs",
+                "source": "tmpfs",
+                "options": ["nosuid"],
+                "recursive": true
+            }],
+            "linux": {
+                "namespaces": [{ "type": "pid" }, { "type": "mount" }],
+                "intelRdt": { "closID": "guaranteed" },
+                "resources": { "memory": { "limit": 1048576, "checkBeforeUpdate": true } }
+            },
+            "vm": { "hypervisor": { "path": "/usr/bin/qemu" } }
+        }"#;
+
+        let spec: Spec = serde_json::from_str(spec_json).unwrap();
+        assert_eq!(spec.extensions["vm"]["hypervisor"]["path"], "/usr/bin/qemu");
+        let process = spec.process.as_ref().unwrap();
+        assert_eq!(process.extensions["personality"]["domain"], "LINUX32");
+
+        let bundle = Path::new("./spec_test_unknown_fields");
+        std::fs::create_dir_all(bundle).unwrap();
+        spec.save(bundle).unwrap();
+        let saved = std::fs::read_to_string(bundle.join("config.json")).unwrap();
+        std::fs::remove_dir_all(bundle).unwrap();
+
+        // The same document, without nulls for the fields left out
+        let input: serde_json::Value = serde_json::from_str(spec_json).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        assert_eq!(saved, input);
+    }
 }
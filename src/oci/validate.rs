@@ -0,0 +1,965 @@
+use nix::sys::statfs::{statfs, FsType};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
+
+use crate::core::{
+    cdi,
+    cgroups::{delegated_controllers, DELEGATE_ANNOTATION},
+    common::Error,
+    filesystem::check_proc_options,
+    network::valid_interface_name,
+    numa,
+    nvidia::{self, GPUS_ANNOTATION},
+    selinux::{relabel_requests, RELABEL_ANNOTATION},
+};
+use crate::oci::spec::{Spec, UidMapping};
+
+const NAMESPACES: [&str; 9] = [
+    "pid", "network", "net", "mount", "mnt", "ipc", "uts", "user", "cgroup",
+];
+const DEVICE_TYPES: [&str; 4] = ["c", "b", "u", "p"];
+const NSFS_MAGIC: FsType = FsType(0x6e73_6673);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The container can't be created from the bundle
+    Error,
+    /// The container can be created, but likely not as intended
+    Warning,
+}
+
+/// Problem found in a bundle, at the field of config.json it's about
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn error(&mut self, field: &str, message: String) {
+        self.push(Severity::Error, field, message);
+    }
+
+    fn warning(&mut self, field: &str, message: String) {
+        self.push(Severity::Warning, field, message);
+    }
+
+    fn push(&mut self, severity: Severity, field: &str, message: String) {
+        self.0.push(Diagnostic {
+            severity,
+            field: field.to_string(),
+            message,
+        });
+    }
+}
+
+/// Checks the config.json of the bundle without creating anything
+pub fn validate_bundle(bundle: &Path) -> Vec<Diagnostic> {
+    match Spec::try_from(bundle.join("config.json").as_path()) {
+        Ok(spec) => validate(&spec, bundle),
+        Err(Error::Spec(err)) => vec![Diagnostic {
+            severity: Severity::Error,
+            field: String::new(),
+            message: format!("config.json isn't a valid OCI spec: {}", err),
+        }],
+        Err(err) => vec![Diagnostic {
+            severity: Severity::Error,
+            field: String::new(),
+            message: err.to_string(),
+        }],
+    }
+}
+
+/// Checks the spec on top of its schema, with the paths relative to the bundle
+pub fn validate(spec: &Spec, bundle: &Path) -> Vec<Diagnostic> {
+    let mut diagnostics = Diagnostics::default();
+
+    if !spec.oci_version.starts_with("1.") {
+        diagnostics.error(
+            "ociVersion",
+            format!("unsupported version {:?}", spec.oci_version),
+        );
+    }
+    validate_root(spec, bundle, &mut diagnostics);
+    validate_process(spec, &mut diagnostics);
+    validate_mounts(spec, bundle, &mut diagnostics);
+    validate_namespaces(spec, &mut diagnostics);
+    validate_id_mappings(spec, &mut diagnostics);
+    validate_devices(spec, &mut diagnostics);
+    validate_net_devices(spec, &mut diagnostics);
+    validate_memory_policy(spec, &mut diagnostics);
+    validate_resources(spec, &mut diagnostics);
+    validate_hooks(spec, &mut diagnostics);
+    validate_delegation(spec, &mut diagnostics);
+    validate_device_annotations(spec, &mut diagnostics);
+    validate_relabel(spec, &mut diagnostics);
+
+    diagnostics.0
+}
+
+fn resolve(bundle: &Path, path: &str) -> PathBuf {
+    bundle.join(path)
+}
+
+fn validate_root(spec: &Spec, bundle: &Path, diagnostics: &mut Diagnostics) {
+    if spec.root.path.is_empty() {
+        diagnostics.error("root.path", "missing rootfs".to_string());
+    } else if !resolve(bundle, &spec.root.path).is_dir() {
+        diagnostics.error(
+            "root.path",
+            format!("rootfs {} isn't a directory", spec.root.path),
+        );
+    }
+}
+
+fn validate_process(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let process = match &spec.process {
+        Some(process) => process,
+        None => {
+            diagnostics.error("process", "no process to run".to_string());
+            return;
+        }
+    };
+
+    if process.args.as_ref().is_none_or(|args| args.is_empty()) {
+        diagnostics.error("process.args", "no program to run".to_string());
+    }
+    if !Path::new(&process.cwd).is_absolute() {
+        diagnostics.error(
+            "process.cwd",
+            format!("{:?} isn't an absolute path", process.cwd),
+        );
+    }
+    for (i, var) in process.env.iter().flatten().enumerate() {
+        if !var.contains('=') {
+            diagnostics.error(
+                &format!("process.env[{}]", i),
+                format!("{:?} isn't KEY=VALUE", var),
+            );
+        }
+    }
+}
+
+fn validate_mounts(spec: &Spec, bundle: &Path, diagnostics: &mut Diagnostics) {
+    for (i, mount) in spec.mounts.iter().flatten().enumerate() {
+        if !Path::new(&mount.destination).is_absolute() {
+            diagnostics.error(
+                &format!("mounts[{}].destination", i),
+                format!("{:?} isn't an absolute path", mount.destination),
+            );
+        }
+
+        if mount.mount_type.is_none() {
+            diagnostics.error(&format!("mounts[{}].type", i), "missing type".to_string());
+        }
+        if mount.source.is_none() {
+            diagnostics.error(
+                &format!("mounts[{}].source", i),
+                "missing source".to_string(),
+            );
+        }
+
+        if mount.mount_type.as_deref() == Some("proc") {
+            let options = mount.options.as_deref().unwrap_or_default();
+            if let Err(Error::Bundle(message)) = check_proc_options(options) {
+                diagnostics.error(&format!("mounts[{}].options", i), message);
+            }
+        }
+
+        let bind = mount.mount_type.as_deref() == Some("bind")
+            || mount
+                .options
+                .iter()
+                .flatten()
+                .any(|option| option == "bind" || option == "rbind");
+        if let (true, Some(source)) = (bind, &mount.source) {
+            if !resolve(bundle, source).exists() {
+                diagnostics.warning(
+                    &format!("mounts[{}].source", i),
+                    format!("bind mount source {} doesn't exist", source),
+                );
+            }
+        }
+    }
+}
+
+/// Whether the container gets a new namespace of the type, not a joined one
+fn creates_namespace(spec: &Spec, namespace: &str) -> bool {
+    spec.linux
+        .as_ref()
+        .and_then(|linux| linux.namespaces.as_ref())
+        .is_some_and(|namespaces| {
+            namespaces
+                .iter()
+                .any(|ns| ns.namespace == namespace && ns.path.is_none())
+        })
+}
+
+fn validate_namespaces(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let namespaces = spec
+        .linux
+        .as_ref()
+        .and_then(|linux| linux.namespaces.as_ref());
+    let mut seen = HashSet::new();
+    for (i, namespace) in namespaces.iter().copied().flatten().enumerate() {
+        let field = format!("linux.namespaces[{}]", i);
+        if !NAMESPACES.contains(&namespace.namespace.as_str()) {
+            diagnostics.error(
+                &format!("{}.type", field),
+                format!("unknown namespace {:?}", namespace.namespace),
+            );
+        }
+        if !seen.insert(namespace.namespace.as_str()) {
+            diagnostics.error(
+                &format!("{}.type", field),
+                format!("duplicate {} namespace", namespace.namespace),
+            );
+        }
+        if let Some(path) = &namespace.path {
+            match statfs(path.as_str()) {
+                Ok(stat) if stat.filesystem_type() == NSFS_MAGIC => (),
+                Ok(_) => diagnostics.error(
+                    &format!("{}.path", field),
+                    format!("{} isn't a namespace file", path),
+                ),
+                Err(err) => {
+                    diagnostics.error(&format!("{}.path", field), format!("{}: {}", path, err))
+                }
+            }
+        }
+    }
+
+    let new_uts = creates_namespace(spec, "uts");
+    if spec.hostname.is_some() && !new_uts {
+        diagnostics.error(
+            "hostname",
+            "setting the hostname needs a new uts namespace".to_string(),
+        );
+    }
+    if spec.domainname.is_some() && !new_uts {
+        diagnostics.error(
+            "domainname",
+            "setting the domainname needs a new uts namespace".to_string(),
+        );
+    }
+}
+
+fn validate_id_mappings(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let linux = match &spec.linux {
+        Some(linux) => linux,
+        None => return,
+    };
+    let user_namespace = linux
+        .namespaces
+        .iter()
+        .flatten()
+        .find(|ns| ns.namespace == "user");
+
+    for (field, mappings) in [
+        ("linux.uidMappings", &linux.uid_mappings),
+        ("linux.gidMappings", &linux.gid_mappings),
+    ] {
+        let mappings = mappings.as_deref().unwrap_or_default();
+        match user_namespace {
+            None if !mappings.is_empty() => {
+                diagnostics.error(field, "id mappings need a user namespace".to_string())
+            }
+            // Joined user namespaces come with their mappings
+            Some(ns) if ns.path.is_none() && mappings.is_empty() => {
+                diagnostics.error(field, "a new user namespace needs id mappings".to_string())
+            }
+            _ => (),
+        }
+        validate_mappings(field, mappings, diagnostics);
+    }
+
+    if let Some(user) = spec
+        .process
+        .as_ref()
+        .and_then(|process| process.user.as_ref())
+    {
+        let unmapped = |mappings: &Option<Vec<UidMapping>>, id: i64| match mappings {
+            Some(mappings) if !mappings.is_empty() => !mappings
+                .iter()
+                .any(|m| id >= m.container_id && id < m.container_id + m.size),
+            _ => false,
+        };
+        if unmapped(&linux.uid_mappings, user.uid) {
+            diagnostics.error("process.user.uid", format!("uid {} isn't mapped", user.uid));
+        }
+        if unmapped(&linux.gid_mappings, user.gid) {
+            diagnostics.error("process.user.gid", format!("gid {} isn't mapped", user.gid));
+        }
+    }
+}
+
+/// Ranges must be valid, and overlap neither in the container nor on the host
+fn validate_mappings(field: &str, mappings: &[UidMapping], diagnostics: &mut Diagnostics) {
+    for (i, mapping) in mappings.iter().enumerate() {
+        let field = format!("{}[{}]", field, i);
+        if mapping.size <= 0 || mapping.container_id < 0 || mapping.host_id < 0 {
+            diagnostics.error(
+                &field,
+                format!(
+                    "invalid range of {} ids from {} to {}",
+                    mapping.size, mapping.container_id, mapping.host_id
+                ),
+            );
+            continue;
+        }
+
+        for other in &mappings[..i] {
+            if ranges_overlap(
+                mapping.container_id,
+                mapping.size,
+                other.container_id,
+                other.size,
+            ) {
+                diagnostics.error(&field, "container ids overlap another mapping".to_string());
+            }
+            if ranges_overlap(mapping.host_id, mapping.size, other.host_id, other.size) {
+                diagnostics.error(&field, "host ids overlap another mapping".to_string());
+            }
+        }
+    }
+}
+
+fn ranges_overlap(start: i64, size: i64, other_start: i64, other_size: i64) -> bool {
+    start < other_start + other_size && other_start < start + size
+}
+
+fn validate_devices(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let devices = spec.linux.as_ref().and_then(|linux| linux.devices.as_ref());
+    let mut seen = HashSet::new();
+    for (i, device) in devices.iter().copied().flatten().enumerate() {
+        let field = format!("linux.devices[{}]", i);
+        let path = Path::new(&device.path);
+        if !path.starts_with("/dev")
+            || path
+                .components()
+                .any(|component| component == std::path::Component::ParentDir)
+        {
+            diagnostics.error(
+                &format!("{}.path", field),
+                format!("{} isn't a path under /dev", device.path),
+            );
+        }
+        if !seen.insert(device.path.as_str()) {
+            diagnostics.error(
+                &format!("{}.path", field),
+                format!("duplicate device {}", device.path),
+            );
+        }
+        if !DEVICE_TYPES.contains(&device.device_type.as_str()) {
+            diagnostics.error(
+                &format!("{}.type", field),
+                format!("unknown device type {:?}", device.device_type),
+            );
+        }
+    }
+}
+
+/// Host interfaces to move into the container, which needs its own network
+/// namespace to receive them
+fn validate_net_devices(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let devices = match spec.linux.as_ref().and_then(|linux| linux.net_devices.as_ref()) {
+        Some(devices) if !devices.is_empty() => devices,
+        _ => return,
+    };
+    if !creates_namespace(spec, "network") {
+        diagnostics.error(
+            "linux.netDevices",
+            "moving net devices needs a new network namespace".to_string(),
+        );
+    }
+    let mut names: Vec<_> = devices.iter().collect();
+    names.sort_by_key(|(host_name, _)| *host_name);
+    let mut seen = HashSet::new();
+    for (host_name, device) in names {
+        let field = format!("linux.netDevices.{}", host_name);
+        if !valid_interface_name(host_name) {
+            diagnostics.error(&field, format!("invalid interface name {:?}", host_name));
+        } else if !Path::new("/sys/class/net").join(host_name).exists() {
+            diagnostics.error(&field, format!("no interface {} on the host", host_name));
+        }
+        let name = device.name.as_deref().unwrap_or(host_name);
+        if !valid_interface_name(name) {
+            diagnostics.error(
+                &format!("{}.name", field),
+                format!("invalid interface name {:?}", name),
+            );
+        }
+        if !seen.insert(name) {
+            diagnostics.error(
+                &format!("{}.name", field),
+                format!("duplicate interface {} in the container", name),
+            );
+        }
+    }
+}
+
+fn validate_memory_policy(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let policy = match spec.linux.as_ref().and_then(|linux| linux.memory_policy.as_ref()) {
+        Some(policy) => policy,
+        None => return,
+    };
+    if numa::parse_mode(&policy.mode).is_none() {
+        diagnostics.error(
+            "linux.memoryPolicy.mode",
+            format!("unknown memory policy mode {:?}", policy.mode),
+        );
+    }
+    let flags = policy.flags.as_deref().unwrap_or_default();
+    for (i, flag) in flags.iter().enumerate() {
+        if numa::parse_flag(flag).is_none() {
+            diagnostics.error(
+                &format!("linux.memoryPolicy.flags[{}]", i),
+                format!("unknown memory policy flag {:?}", flag),
+            );
+        }
+    }
+    if flags.iter().any(|flag| flag == "MPOL_F_STATIC_NODES")
+        && flags.iter().any(|flag| flag == "MPOL_F_RELATIVE_NODES")
+    {
+        diagnostics.error(
+            "linux.memoryPolicy.flags",
+            "static and relative nodes exclude each other".to_string(),
+        );
+    }
+
+    let nodes = policy.nodes.as_deref().filter(|nodes| !nodes.is_empty());
+    if let Some(nodes) = nodes {
+        if numa::parse_nodes(nodes).is_none() {
+            diagnostics.error(
+                "linux.memoryPolicy.nodes",
+                format!("invalid node list {:?}", nodes),
+            );
+        }
+    }
+    match (numa::needs_nodes(&policy.mode), nodes) {
+        (Some(true), None) => diagnostics.error(
+            "linux.memoryPolicy.nodes",
+            format!("{} needs nodes", policy.mode),
+        ),
+        (Some(false), Some(_)) => diagnostics.error(
+            "linux.memoryPolicy.nodes",
+            format!("{} takes no nodes", policy.mode),
+        ),
+        _ => (),
+    }
+}
+
+/// Values the cgroup would reject or silently clamp, and limits contradicting
+/// each other
+fn validate_resources(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let resources = match spec
+        .linux
+        .as_ref()
+        .and_then(|linux| linux.resources.as_ref())
+    {
+        Some(resources) => resources,
+        None => return,
+    };
+    // A limit is positive, or -1 for none
+    let invalid_limit =
+        |limit: Option<i64>| matches!(limit, Some(limit) if limit == 0 || limit < -1);
+
+    if let Some(memory) = &resources.memory {
+        let field = "linux.resources.memory";
+        for (name, value) in [
+            ("limit", memory.limit),
+            ("reservation", memory.reservation),
+            ("swap", memory.swap),
+        ] {
+            if invalid_limit(value) {
+                diagnostics.error(
+                    &format!("{}.{}", field, name),
+                    format!("{} isn't a positive number of bytes or -1", value.unwrap()),
+                );
+            }
+        }
+        if let (Some(reservation), Some(limit)) = (memory.reservation, memory.limit) {
+            if reservation > 0 && limit > 0 && reservation > limit {
+                diagnostics.error(
+                    &format!("{}.reservation", field),
+                    format!("reservation {} is above the limit {}", reservation, limit),
+                );
+            }
+        }
+        // The swap limit counts the memory too
+        match (memory.swap, memory.limit) {
+            (Some(swap), None) | (Some(swap), Some(-1)) if swap > 0 => diagnostics.error(
+                &format!("{}.swap", field),
+                "a swap limit needs a memory limit".to_string(),
+            ),
+            (Some(swap), Some(limit)) if swap > 0 && swap < limit => diagnostics.error(
+                &format!("{}.swap", field),
+                format!("memory+swap {} is below the memory limit {}", swap, limit),
+            ),
+            _ => (),
+        }
+        if let Some(swappiness) = memory.swappiness {
+            if !(0..=100).contains(&swappiness) {
+                diagnostics.error(
+                    &format!("{}.swappiness", field),
+                    format!("{} isn't between 0 and 100", swappiness),
+                );
+            }
+        }
+    }
+
+    if let Some(cpu) = &resources.cpu {
+        let field = "linux.resources.cpu";
+        if let Some(shares) = cpu.shares {
+            if !(2..=262144).contains(&shares) {
+                diagnostics.error(
+                    &format!("{}.shares", field),
+                    format!("{} isn't between 2 and 262144", shares),
+                );
+            }
+        }
+        if invalid_limit(cpu.quota) {
+            diagnostics.error(
+                &format!("{}.quota", field),
+                format!("{} isn't a positive quota or -1", cpu.quota.unwrap()),
+            );
+        }
+        if let Some(period) = cpu.period {
+            if !(1000..=1_000_000).contains(&period) {
+                diagnostics.error(
+                    &format!("{}.period", field),
+                    format!("{} isn't between 1000 and 1000000 microseconds", period),
+                );
+            }
+        }
+        if let Some(burst) = cpu.burst.filter(|burst| *burst > 0) {
+            // The kernel rejects a burst over the quota, and without one
+            match cpu.quota {
+                Some(quota) if quota > 0 => {
+                    if burst > quota as u64 {
+                        diagnostics.error(
+                            &format!("{}.burst", field),
+                            format!("{} exceeds the quota {}", burst, quota),
+                        );
+                    }
+                }
+                _ => diagnostics.error(
+                    &format!("{}.burst", field),
+                    "a burst needs a quota".to_string(),
+                ),
+            }
+        }
+        if let Some(idle) = cpu.idle {
+            if idle != 0 && idle != 1 {
+                diagnostics.error(
+                    &format!("{}.idle", field),
+                    format!("{} isn't 0 or 1", idle),
+                );
+            }
+        }
+        for (name, list) in [("cpus", &cpu.cpus), ("mems", &cpu.mems)] {
+            if let Some(list) = list {
+                if !valid_cpu_list(list) {
+                    diagnostics.error(
+                        &format!("{}.{}", field, name),
+                        format!("{:?} isn't a list like 0-3,5", list),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(weight) = resources
+        .block_io
+        .as_ref()
+        .and_then(|block_io| block_io.weight)
+    {
+        if !(10..=1000).contains(&weight) {
+            diagnostics.error(
+                "linux.resources.blockIO.weight",
+                format!("{} isn't between 10 and 1000", weight),
+            );
+        }
+    }
+}
+
+/// Whether the list is made of comma separated numbers and ranges
+fn valid_cpu_list(list: &str) -> bool {
+    let number = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+    list.split(',').all(|item| match item.split_once('-') {
+        Some((start, end)) => {
+            number(start) && number(end) && start.parse::<u64>().ok() <= end.parse::<u64>().ok()
+        }
+        None => number(item),
+    })
+}
+
+fn validate_delegation(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let field = format!("annotations.{}", DELEGATE_ANNOTATION);
+    match delegated_controllers(spec.annotations.as_ref()) {
+        Ok(Some(_)) if !creates_namespace(spec, "cgroup") => diagnostics.warning(
+            &field,
+            "without a cgroup namespace, the container sees the cgroup paths of the host"
+                .to_string(),
+        ),
+        Err(Error::Bundle(message)) => diagnostics.error(&field, message),
+        _ => (),
+    }
+}
+
+/// The devices are resolved on create only, the host may differ
+fn validate_device_annotations(spec: &Spec, diagnostics: &mut Diagnostics) {
+    if let Err(Error::Bundle(message)) = nvidia::annotated_gpus(spec) {
+        diagnostics.error(&format!("annotations.{}", GPUS_ANNOTATION), message);
+    }
+    let annotations = spec.annotations.iter().flatten();
+    for (key, value) in annotations.filter(|(key, _)| key.starts_with(cdi::ANNOTATION_PREFIX)) {
+        for device in value.split(',').map(str::trim) {
+            if cdi::parse_name(device).is_none() {
+                diagnostics.error(
+                    &format!("annotations.{}", key),
+                    format!(
+                        "{:?} isn't a qualified CDI device name, vendor.com/class=name",
+                        device
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn validate_relabel(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let field = format!("annotations.{}", RELABEL_ANNOTATION);
+    let requests = match relabel_requests(spec.annotations.as_ref()) {
+        Ok(requests) => requests,
+        Err(Error::Bundle(message)) => return diagnostics.error(&field, message),
+        Err(_) => return,
+    };
+    let mounts = spec.mounts.as_deref().unwrap_or_default();
+    let mut destinations: Vec<&String> = requests.keys().collect();
+    destinations.sort();
+    for destination in destinations {
+        let bind = mounts.iter().any(|mount| {
+            &mount.destination == destination && mount.mount_type.as_deref() == Some("bind")
+        });
+        if !bind {
+            diagnostics.error(&field, format!("{} isn't a bind mount", destination));
+        }
+    }
+    let label = spec.linux.as_ref().and_then(|linux| linux.mount_label.as_ref());
+    if !requests.is_empty() && label.is_none() {
+        diagnostics.warning(
+            &field,
+            String::from("without linux.mountLabel, nothing is relabeled"),
+        );
+    }
+}
+
+fn validate_hooks(spec: &Spec, diagnostics: &mut Diagnostics) {
+    let hooks = match &spec.hooks {
+        Some(hooks) => hooks,
+        None => return,
+    };
+    for (stage, hooks) in [
+        ("prestart", &hooks.prestart),
+        ("createRuntime", &hooks.create_runtime),
+        ("createContainer", &hooks.create_container),
+        ("startContainer", &hooks.start_container),
+        ("poststart", &hooks.poststart),
+        ("poststop", &hooks.poststop),
+    ] {
+        // createContainer and startContainer hooks run in the container
+        let on_host = stage != "createContainer" && stage != "startContainer";
+        for (i, hook) in hooks.iter().flatten().enumerate() {
+            let field = format!("hooks.{}[{}].path", stage, i);
+            if !Path::new(&hook.path).is_absolute() {
+                diagnostics.error(&field, format!("{:?} isn't an absolute path", hook.path));
+            } else if on_host && !Path::new(&hook.path).exists() {
+                diagnostics.warning(&field, format!("{} doesn't exist", hook.path));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{validate, validate_bundle, Severity};
+    use crate::oci::spec::{
+        Cpu, Device, Linux, Memory, MemoryPolicy, Mount, Resources, Spec, UidMapping,
+    };
+
+    fn errors(spec: &Spec, bundle: &Path) -> Vec<String> {
+        validate(spec, bundle)
+            .into_iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .map(|diagnostic| diagnostic.field)
+            .collect()
+    }
+
+    #[test]
+    fn semantic_rules() {
+        let bundle = Path::new("./validate_test");
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+
+        let spec = Spec::new()
+            .with_rootfs("rootfs", false)
+            .with_args(&["sh"])
+            .with_hostname("valid")
+            .with_domainname("example.com")
+            .add_namespace("uts", None);
+        assert!(validate(&spec, bundle).is_empty());
+
+        let mut spec = Spec::new()
+            .with_rootfs("missing", false)
+            .with_args(&["sh"])
+            .with_hostname("no-uts")
+            .with_domainname("no-uts")
+            .add_mount(Mount::new("relative", "tmpfs", "tmpfs", &[]))
+            .add_mount(Mount::new("/proc", "proc", "proc", &["hidepid=3"]))
+            .add_namespace("pid", None)
+            .add_namespace("pid", Some("/tmp"))
+            .add_namespace("user", None);
+        let linux = spec.linux.get_or_insert_with(Linux::default);
+        linux.uid_mappings = Some(vec![
+            UidMapping {
+                container_id: 0,
+                host_id: 1000,
+                size: 10,
+                ..Default::default()
+            },
+            UidMapping {
+                container_id: 5,
+                host_id: 2000,
+                size: 10,
+                ..Default::default()
+            },
+        ]);
+        linux.devices = Some(vec![Device {
+            path: String::from("/dev/../etc/passwd"),
+            device_type: String::from("x"),
+            ..Default::default()
+        }]);
+        spec.process.as_mut().unwrap().user.get_or_insert_with(Default::default).uid = 100;
+
+        let fields = errors(&spec, bundle);
+        for field in [
+            "root.path",
+            "mounts[0].destination",
+            "mounts[1].options",
+            "linux.namespaces[1].type",
+            "linux.namespaces[1].path",
+            "hostname",
+            "domainname",
+            "linux.uidMappings[1]",
+            "linux.gidMappings",
+            "process.user.uid",
+            "linux.devices[0].path",
+            "linux.devices[0].type",
+        ] {
+            assert!(fields.iter().any(|f| f == field), "no error for {}", field);
+        }
+
+        std::fs::write(bundle.join("config.json"), "{ \"ociVersion\": \"1.0.2\" }").unwrap();
+        let diagnostics = validate_bundle(bundle);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("isn't a valid OCI spec"));
+
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn resource_rules() {
+        let bundle = Path::new("./validate_resources_test");
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+
+        let resources = |memory, cpu| {
+            let mut spec = Spec::new().with_rootfs("rootfs", false).with_args(&["sh"]);
+            spec.linux.get_or_insert_with(Linux::default).resources = Some(Resources {
+                memory: Some(memory),
+                cpu: Some(cpu),
+                ..Default::default()
+            });
+            errors(&spec, bundle)
+        };
+        let valid = resources(
+            Memory {
+                limit: Some(512 << 20),
+                reservation: Some(256 << 20),
+                swap: Some(1 << 30),
+                ..Default::default()
+            },
+            Cpu {
+                shares: Some(1024),
+                quota: Some(50000),
+                period: Some(100000),
+                cpus: Some(String::from("0-3,5")),
+                burst: Some(10000),
+                idle: Some(0),
+                ..Default::default()
+            },
+        );
+        assert!(valid.is_empty(), "{:?}", valid);
+
+        let fields = resources(
+            Memory {
+                limit: Some(256 << 20),
+                reservation: Some(512 << 20),
+                swap: Some(128 << 20),
+                swappiness: Some(200),
+                ..Default::default()
+            },
+            Cpu {
+                shares: Some(1),
+                quota: Some(0),
+                period: Some(10),
+                cpus: Some(String::from("3-1")),
+                mems: Some(String::from("0,")),
+                burst: Some(1000),
+                idle: Some(2),
+                ..Default::default()
+            },
+        );
+        for field in [
+            "linux.resources.memory.reservation",
+            "linux.resources.memory.swap",
+            "linux.resources.memory.swappiness",
+            "linux.resources.cpu.shares",
+            "linux.resources.cpu.quota",
+            "linux.resources.cpu.period",
+            "linux.resources.cpu.cpus",
+            "linux.resources.cpu.mems",
+            "linux.resources.cpu.burst",
+            "linux.resources.cpu.idle",
+        ] {
+            assert!(fields.iter().any(|f| f == field), "no error for {}", field);
+        }
+        let swap_only = resources(
+            Memory {
+                swap: Some(1 << 30),
+                ..Default::default()
+            },
+            Cpu::default(),
+        );
+        assert_eq!(swap_only, vec!["linux.resources.memory.swap"]);
+
+        let mut spec = Spec::new().with_rootfs("rootfs", false);
+        spec.mounts = Some(vec![Default::default()]);
+        let fields = errors(&spec, bundle);
+        for field in ["process", "mounts[0].type", "mounts[0].source"] {
+            assert!(fields.iter().any(|f| f == field), "no error for {}", field);
+        }
+
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn net_device_rules() {
+        let bundle = Path::new("./validate_net_devices_test");
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+
+        let spec = Spec::new()
+            .with_rootfs("rootfs", false)
+            .with_args(&["sh"])
+            .add_namespace("network", None)
+            .add_net_device("lo", Some("lo1"));
+        assert!(errors(&spec, bundle).is_empty());
+
+        let spec = Spec::new()
+            .with_rootfs("rootfs", false)
+            .with_args(&["sh"])
+            .add_net_device("lo", Some("eth0"))
+            .add_net_device("pura-missing0", Some("eth0"))
+            .add_net_device("eth/0", Some("a-very-long-name0"));
+        let fields = errors(&spec, bundle);
+        for field in [
+            "linux.netDevices",
+            "linux.netDevices.pura-missing0",
+            "linux.netDevices.pura-missing0.name",
+            "linux.netDevices.eth/0",
+            "linux.netDevices.eth/0.name",
+        ] {
+            assert!(fields.iter().any(|f| f == field), "no error for {}", field);
+        }
+
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn memory_policy_rules() {
+        let bundle = Path::new("./validate_memory_policy_test");
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+
+        let policy = |mode: &str, nodes: Option<&str>, flags: &[&str]| {
+            let mut spec = Spec::new().with_rootfs("rootfs", false).with_args(&["sh"]);
+            spec.linux.get_or_insert_with(Linux::default).memory_policy = Some(MemoryPolicy {
+                mode: mode.to_string(),
+                nodes: nodes.map(str::to_string),
+                flags: Some(flags.iter().map(|flag| flag.to_string()).collect()),
+                ..Default::default()
+            });
+            errors(&spec, bundle)
+        };
+        assert!(policy("MPOL_BIND", Some("0-1,3"), &["MPOL_F_STATIC_NODES"]).is_empty());
+        assert!(policy("MPOL_PREFERRED", None, &[]).is_empty());
+        assert!(policy("MPOL_LOCAL", None, &[]).is_empty());
+
+        assert_eq!(policy("MPOL_RANDOM", None, &[]), vec!["linux.memoryPolicy.mode"]);
+        assert_eq!(policy("MPOL_INTERLEAVE", None, &[]), vec!["linux.memoryPolicy.nodes"]);
+        assert_eq!(policy("MPOL_DEFAULT", Some("0"), &[]), vec!["linux.memoryPolicy.nodes"]);
+        assert_eq!(policy("MPOL_BIND", Some("1-0"), &[]), vec!["linux.memoryPolicy.nodes"]);
+        assert_eq!(
+            policy(
+                "MPOL_BIND",
+                Some("0"),
+                &["MPOL_F_STATIC_NODES", "MPOL_F_RELATIVE_NODES", "MPOL_F_UNKNOWN"]
+            ),
+            vec!["linux.memoryPolicy.flags[2]", "linux.memoryPolicy.flags"]
+        );
+
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn annotation_rules() {
+        let bundle = Path::new("./validate_annotations_test");
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+
+        let annotated = |key: &str, value: &str| {
+            let spec = Spec::new()
+                .with_rootfs("rootfs", false)
+                .with_args(&["sh"])
+                .add_namespace("cgroup", None)
+                .add_annotation(key, value);
+            errors(&spec, bundle)
+        };
+        assert!(annotated("cdi.k8s.io/gpus", "nvidia.com/gpu=0,nvidia.com/gpu=1").is_empty());
+        assert_eq!(
+            annotated("cdi.k8s.io/gpus", "nvidia.com/gpu=0,gpu1"),
+            vec!["annotations.cdi.k8s.io/gpus"]
+        );
+        assert_eq!(
+            annotated("pura.selinux.relabel", "/data:z"),
+            vec!["annotations.pura.selinux.relabel"]
+        );
+        assert!(annotated("pura.gpus", "0,1").is_empty());
+        assert_eq!(annotated("pura.gpus", "first"), vec!["annotations.pura.gpus"]);
+        assert!(annotated("pura.cgroup.delegate", "cpu,memory").is_empty());
+        assert_eq!(
+            annotated("pura.cgroup.delegate", "cpu,blkio"),
+            vec!["annotations.pura.cgroup.delegate"]
+        );
+
+        std::fs::remove_dir_all(bundle).unwrap();
+    }
+}
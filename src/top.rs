@@ -0,0 +1,215 @@
+//! Live view of the resources of the containers of a runtime root, like top(1),
+//! for hosts without a monitoring stack.
+
+use nix::unistd::isatty;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::container::Container;
+use crate::core::{
+    common::{Error, Result},
+    state::State,
+};
+use crate::metrics::{containers, Stats};
+
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// Line of a container in the view
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    id: String,
+    status: String,
+    pid: u64,
+    /// Percentage of a CPU used since the previous refresh
+    cpu: Option<f64>,
+    stats: Stats,
+}
+
+/// Refreshes the view of the container, or of all the containers without one,
+/// every interval. Stops after the number of iterations if any.
+pub fn run(
+    root: &Path,
+    id: Option<&str>,
+    interval: Duration,
+    iterations: Option<u64>,
+) -> Result<()> {
+    let tty = isatty(1).unwrap_or(false);
+    let mut stdout = std::io::stdout();
+    // CPU time of each container at the previous refresh
+    let mut previous: HashMap<String, (Instant, f64)> = HashMap::new();
+
+    let mut iteration = 0;
+    loop {
+        let states = match id {
+            Some(id) => vec![Container::load(&root.to_string_lossy(), id)?.state()?],
+            None => containers(root)?,
+        };
+        let rows = sample(states, &mut previous, Instant::now());
+
+        let mut view = String::new();
+        if tty {
+            view.push_str(CLEAR_SCREEN);
+        } else if iteration > 0 {
+            view.push('\n');
+        }
+        view.push_str(&table(&rows));
+        stdout
+            .write_all(view.as_bytes())
+            .and_then(|_| stdout.flush())
+            .map_err(Error::io("unable to write the view"))?;
+
+        iteration += 1;
+        if iterations.is_some_and(|iterations| iteration >= iterations) {
+            return Ok(());
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn sample(
+    states: Vec<State>,
+    previous: &mut HashMap<String, (Instant, f64)>,
+    now: Instant,
+) -> Vec<Row> {
+    states
+        .into_iter()
+        .map(|state| {
+            let stats = Stats::of(&state).unwrap_or_default();
+            let cpu = stats
+                .cpu_seconds
+                .and_then(|cpu_seconds| cpu_usage(previous, &state.id, now, cpu_seconds));
+            Row {
+                id: state.id,
+                status: format!("{:?}", state.status).to_lowercase(),
+                pid: state.pid,
+                cpu,
+                stats,
+            }
+        })
+        .collect()
+}
+
+/// Percentage of a CPU used by the container since the previous refresh
+fn cpu_usage(
+    previous: &mut HashMap<String, (Instant, f64)>,
+    id: &str,
+    now: Instant,
+    cpu_seconds: f64,
+) -> Option<f64> {
+    let (then, before) = previous.insert(id.to_string(), (now, cpu_seconds))?;
+    let elapsed = now.duration_since(then).as_secs_f64();
+    if elapsed > 0.0 {
+        Some((cpu_seconds - before) / elapsed * 100.0)
+    } else {
+        None
+    }
+}
+
+/// Bytes in binary units, like 1.5M
+fn bytes(value: Option<f64>) -> String {
+    let mut value = match value {
+        Some(value) => value,
+        None => return String::from("-"),
+    };
+    for unit in ["B", "K", "M", "G", "T"] {
+        if value < 1024.0 || unit == "T" {
+            return if unit == "B" {
+                format!("{}{}", value, unit)
+            } else {
+                format!("{:.1}{}", value, unit)
+            };
+        }
+        value /= 1024.0;
+    }
+    unreachable!()
+}
+
+fn table(rows: &[Row]) -> String {
+    let id_width = rows
+        .iter()
+        .map(|row| row.id.len())
+        .max()
+        .unwrap_or(0)
+        .max(2);
+    let mut table = format!(
+        "{:<id_width$} {:<8} {:>8} {:>6} {:>8} {:>8} {:>5} {:>8} {:>8}\n",
+        "ID",
+        "STATUS",
+        "PID",
+        "CPU%",
+        "MEM",
+        "LIMIT",
+        "PIDS",
+        "READ",
+        "WRITE",
+        id_width = id_width
+    );
+    for row in rows {
+        let _ = writeln!(
+            table,
+            "{:<id_width$} {:<8} {:>8} {:>6} {:>8} {:>8} {:>5} {:>8} {:>8}",
+            row.id,
+            row.status,
+            row.pid,
+            row.cpu
+                .map_or_else(|| String::from("-"), |cpu| format!("{:.1}", cpu)),
+            bytes(row.stats.memory_usage),
+            bytes(row.stats.memory_limit),
+            row.stats
+                .pids
+                .map_or_else(|| String::from("-"), |pids| pids.to_string()),
+            bytes(row.stats.io_read),
+            bytes(row.stats.io_write),
+            id_width = id_width
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use super::{bytes, cpu_usage, sample, table};
+    use crate::core::state::{State, Status};
+
+    #[test]
+    fn view() {
+        assert_eq!(bytes(None), "-");
+        assert_eq!(bytes(Some(512.0)), "512B");
+        assert_eq!(bytes(Some(1536.0)), "1.5K");
+        assert_eq!(bytes(Some(3.0 * 1024.0 * 1024.0 * 1024.0)), "3.0G");
+
+        let mut state = State::new("abc", 42, "/tmp");
+        state.status = Status::Stopped;
+        let mut previous = HashMap::new();
+        let rows = sample(vec![state], &mut previous, Instant::now());
+        assert_eq!(rows[0].cpu, None);
+        assert_eq!(
+            table(&rows),
+            concat!(
+                "ID  STATUS        PID   CPU%      MEM    LIMIT  PIDS     READ    WRITE\n",
+                "abc stopped        42      -        -        -     -        -        -\n",
+            )
+        );
+    }
+
+    #[test]
+    fn cpu_percentage() {
+        let mut previous = HashMap::new();
+        let start = Instant::now();
+        assert_eq!(cpu_usage(&mut previous, "abc", start, 1.0), None);
+        // Half a CPU over two seconds
+        let now = start + Duration::from_secs(2);
+        assert_eq!(cpu_usage(&mut previous, "abc", now, 2.0), Some(50.0));
+        assert_eq!(cpu_usage(&mut previous, "abc", now, 2.0), None);
+    }
+}
@@ -0,0 +1,196 @@
+//! Streams the states of the containers of a runtime root as they change, so
+//! supervisors react to created, started, stopped and deleted containers
+//! without polling `state`.
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+    unistd::close,
+};
+use std::{
+    collections::HashMap,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::container::Container;
+use crate::core::{
+    common::{Error, Result},
+    fork::Process,
+    state::{State, Status},
+};
+use crate::metrics::containers;
+
+/// Checks of the containers whose exit can't be waited for on a pidfd
+/// (kernels before 5.3)
+const FALLBACK_INTERVAL_MS: i32 = 1000;
+
+/// Watches the runtime root with inotify, for the state files and the state
+/// database being written and containers being created or deleted, and the
+/// pidfds of the created and running containers, for their processes exiting
+/// without the runtime recording it.
+pub struct Watcher {
+    root: PathBuf,
+    inotify: Inotify,
+    /// Watches of the directories of the containers
+    dirs: HashMap<String, WatchDescriptor>,
+    /// Processes of the created and running containers
+    processes: HashMap<String, Process>,
+    states: Vec<State>,
+}
+
+impl Watcher {
+    pub fn new(root: &Path) -> Result<Watcher> {
+        std::fs::create_dir_all(root)
+            .map_err(Error::io(format!("unable to create root {:?}", root)))?;
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+            .map_err(Error::sys("unable to init inotify"))?;
+        let mut watcher = Watcher {
+            root: root.to_path_buf(),
+            inotify,
+            dirs: HashMap::new(),
+            processes: HashMap::new(),
+            states: Vec::new(),
+        };
+        watcher
+            .inotify
+            .add_watch(
+                root,
+                AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_DELETE
+                    | AddWatchFlags::IN_MOVED_TO
+                    | AddWatchFlags::IN_MODIFY,
+            )
+            .map_err(Error::sys(format!("unable to watch {:?}", root)))?;
+        watcher.states = watcher.scan()?;
+        Ok(watcher)
+    }
+
+    /// States of the containers, sorted by id
+    pub fn states(&self) -> &[State] {
+        &self.states
+    }
+
+    /// Blocks until a container is created or deleted or its state changes,
+    /// returns the new states
+    pub fn wait(&mut self) -> Result<&[State]> {
+        loop {
+            let mut fds = vec![PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+            fds.extend(
+                self.processes
+                    .values()
+                    .filter_map(Process::pidfd)
+                    .map(|pidfd| PollFd::new(pidfd, PollFlags::POLLIN)),
+            );
+            let timeout = match self.processes.len() + 1 == fds.len() {
+                true => -1,
+                false => FALLBACK_INTERVAL_MS,
+            };
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(err) => return Err(Error::sys("unable to poll the root")(err)),
+            }
+            // Drained, the root is scanned anyway
+            while let Ok(events) = self.inotify.read_events() {
+                if events.is_empty() {
+                    break;
+                }
+            }
+
+            let states = self.scan()?;
+            if states != self.states {
+                self.states = states;
+                return Ok(&self.states);
+            }
+        }
+    }
+
+    /// Reads the states and follows the containers created and deleted.
+    /// The directories are watched before their state is read, so no write
+    /// goes unnoticed.
+    fn scan(&mut self) -> Result<Vec<State>> {
+        let entries = std::fs::read_dir(&self.root)
+            .map_err(Error::io(format!("unable to list {:?}", self.root)))?;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let id = entry.file_name().to_string_lossy().to_string();
+            if self.dirs.contains_key(&id) || !entry.path().is_dir() {
+                continue;
+            }
+            // Deleted meanwhile, seen by the next scan
+            if let Ok(wd) = self.inotify.add_watch(
+                &entry.path(),
+                AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+            ) {
+                self.dirs.insert(id, wd);
+            }
+        }
+        let root = self.root.clone();
+        self.dirs.retain(|id, _| root.join(id).is_dir());
+
+        let states = containers(&self.root)?;
+        self.processes.retain(|id, _| {
+            states
+                .iter()
+                .any(|state| state.id == *id && alive(&state.status))
+        });
+        let root = self.root.to_string_lossy().to_string();
+        for state in &states {
+            if alive(&state.status) && !self.processes.contains_key(&state.id) {
+                let process =
+                    Container::load(&root, &state.id).and_then(|container| container.process(state));
+                if let Ok(process) = process {
+                    self.processes.insert(state.id.clone(), process);
+                }
+            }
+        }
+        Ok(states)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = close(self.inotify.as_raw_fd());
+    }
+}
+
+fn alive(status: &Status) -> bool {
+    *status == Status::Created || *status == Status::Running
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use super::Watcher;
+    use crate::core::state::{JsonStore, State, Status, Store};
+
+    #[test]
+    fn watch_root() {
+        let root = Path::new("./watch_test");
+        let store = JsonStore::new(root);
+        store.save(&State::new("a", 0, "/containers")).unwrap();
+
+        let mut watcher = Watcher::new(root).unwrap();
+        assert_eq!(watcher.states().len(), 1);
+
+        // A running container whose process exits
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let mut state = State::new("b", child.id() as u64, "/containers");
+        state.status = Status::Running;
+        store.save(&state).unwrap();
+        let states = watcher.wait().unwrap();
+        assert_eq!(states[1], state);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let states = watcher.wait().unwrap();
+        assert_eq!(states[1].status, Status::Stopped);
+
+        std::fs::remove_dir_all(root.join("a")).unwrap();
+        let states = watcher.wait().unwrap();
+        assert_eq!(states.len(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
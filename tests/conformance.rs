@@ -0,0 +1,1774 @@
+//! Runtime conformance cases, after the opencontainers runtime-tools validation
+//! suite, run against the built binary under a temporary root. Cases creating
+//! containers need root and /bin/sh on the host, they're skipped otherwise.
+
+use std::{
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::{geteuid, Pid},
+};
+use pura::oci::spec::{
+    Device, Memory, MemoryPolicy, Mount, Pids, Resources, Seccomp, Spec, Syscall, SyscallArg,
+    UidMapping, User,
+};
+use serde_json::Value;
+use tempfile::TempDir;
+
+const PURA: &str = env!("CARGO_BIN_EXE_pura");
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// WASI module writing `hello wasm` to /wasm and exiting with 3, assembled from
+///
+/// ```text
+/// (module
+///   (import "wasi_snapshot_preview1" "path_open"
+///     (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+///   (import "wasi_snapshot_preview1" "fd_write"
+///     (func $fd_write (param i32 i32 i32 i32) (result i32)))
+///   (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+///   (memory (export "memory") 1)
+///   (data (i32.const 16) "hello wasm\n")
+///   (data (i32.const 32) "wasm")
+///   (func (export "_start")
+///     ;; Created in the preopened root, fd 3, with the right to write
+///     (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 32) (i32.const 4)
+///       (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 40)))
+///     (i32.store (i32.const 0) (i32.const 16))
+///     (i32.store (i32.const 4) (i32.const 11))
+///     (drop (call $fd_write (i32.load (i32.const 40)) (i32.const 0) (i32.const 1) (i32.const 8)))
+///     (call $proc_exit (i32.const 3))))
+/// ```
+const HELLO_WASM: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x1d, 0x04, 0x60, 0x09, 0x7f,
+    0x7f, 0x7f, 0x7f, 0x7f, 0x7e, 0x7e, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x04, 0x7f, 0x7f,
+    0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x00, 0x00, 0x02, 0x69, 0x03,
+    0x16, 0x77, 0x61, 0x73, 0x69, 0x5f, 0x73, 0x6e, 0x61, 0x70, 0x73, 0x68, 0x6f, 0x74,
+    0x5f, 0x70, 0x72, 0x65, 0x76, 0x69, 0x65, 0x77, 0x31, 0x09, 0x70, 0x61, 0x74, 0x68,
+    0x5f, 0x6f, 0x70, 0x65, 0x6e, 0x00, 0x00, 0x16, 0x77, 0x61, 0x73, 0x69, 0x5f, 0x73,
+    0x6e, 0x61, 0x70, 0x73, 0x68, 0x6f, 0x74, 0x5f, 0x70, 0x72, 0x65, 0x76, 0x69, 0x65,
+    0x77, 0x31, 0x08, 0x66, 0x64, 0x5f, 0x77, 0x72, 0x69, 0x74, 0x65, 0x00, 0x01, 0x16,
+    0x77, 0x61, 0x73, 0x69, 0x5f, 0x73, 0x6e, 0x61, 0x70, 0x73, 0x68, 0x6f, 0x74, 0x5f,
+    0x70, 0x72, 0x65, 0x76, 0x69, 0x65, 0x77, 0x31, 0x09, 0x70, 0x72, 0x6f, 0x63, 0x5f,
+    0x65, 0x78, 0x69, 0x74, 0x00, 0x02, 0x03, 0x02, 0x01, 0x03, 0x05, 0x03, 0x01, 0x00,
+    0x01, 0x07, 0x13, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06,
+    0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x03, 0x0a, 0x3a, 0x01, 0x38, 0x00, 0x41,
+    0x03, 0x41, 0x00, 0x41, 0x20, 0x41, 0x04, 0x41, 0x01, 0x42, 0xc0, 0x00, 0x42, 0x00,
+    0x41, 0x00, 0x41, 0x28, 0x10, 0x00, 0x1a, 0x41, 0x00, 0x41, 0x10, 0x36, 0x02, 0x00,
+    0x41, 0x04, 0x41, 0x0b, 0x36, 0x02, 0x00, 0x41, 0x28, 0x28, 0x02, 0x00, 0x41, 0x00,
+    0x41, 0x01, 0x41, 0x08, 0x10, 0x01, 0x1a, 0x41, 0x03, 0x10, 0x02, 0x0b, 0x0b, 0x1a,
+    0x02, 0x00, 0x41, 0x10, 0x0b, 0x0b, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x77, 0x61,
+    0x73, 0x6d, 0x0a, 0x00, 0x41, 0x20, 0x0b, 0x04, 0x77, 0x61, 0x73, 0x6d,
+];
+
+static ROOT: OnceLock<TempDir> = OnceLock::new();
+
+/// Runtime root of the suite, removed when the test binary exits
+fn root() -> &'static str {
+    extern "C" fn remove_root() {
+        if let Some(root) = ROOT.get() {
+            let _ = std::fs::remove_dir_all(root.path());
+        }
+    }
+    ROOT.get_or_init(|| {
+        // Statics are never dropped
+        unsafe { nix::libc::atexit(remove_root) };
+        tempfile::Builder::new().prefix("pura-conformance-").tempdir().unwrap()
+    })
+    .path()
+    .to_str()
+    .unwrap()
+}
+
+/// pura on the root of the suite, unless the args give another one
+fn command(args: &[&str]) -> Command {
+    let mut command = Command::new(PURA);
+    if !args.contains(&"--root") {
+        command.args(["--root", root()]);
+    }
+    command.args(args);
+    command
+}
+
+/// Runs pura, returning its exit code. The container process inherits the
+/// stdio, so it's never piped: the pipe would stay open as long as the container.
+fn pura(args: &[&str]) -> i32 {
+    command(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .code()
+        .unwrap_or(-1)
+}
+
+fn state(id: &str) -> Option<Value> {
+    let output = command(&["state", id])
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        return None;
+    }
+    Some(serde_json::from_slice(&output.stdout).unwrap())
+}
+
+fn status(id: &str) -> Option<String> {
+    state(id).and_then(|state| state["status"].as_str().map(String::from))
+}
+
+fn wait_for(mut cond: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// Whether the process runs, zombies excluded
+fn alive(pid: Pid) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .map(|stat| !stat.rsplit(')').next().unwrap_or("").trim_start().starts_with('Z'))
+        .unwrap_or(false)
+}
+
+fn supported(case: &str) -> bool {
+    if !geteuid().is_root() || !Path::new("/bin/sh").exists() {
+        eprintln!("skipping {}: needs root and /bin/sh", case);
+        return false;
+    }
+    true
+}
+
+/// Spec running the script with the binaries of the host bind mounted
+fn base_spec(script: &str) -> Spec {
+    let mut spec = Spec::new()
+        .with_rootfs("rootfs", false)
+        .with_args(&["/bin/sh", "-c", script])
+        .add_env("PATH", "/usr/local/bin:/usr/bin:/bin")
+        .add_mount(Mount::new("/dev", "tmpfs", "tmpfs", &["nosuid"]))
+        .add_mount(Mount::new("/proc", "proc", "proc", &[]));
+    for dir in ["/bin", "/sbin", "/lib", "/lib32", "/lib64", "/usr"] {
+        if Path::new(dir).exists() {
+            spec = spec.add_mount(Mount::new(dir, "bind", dir, &["bind"]));
+        }
+    }
+    ["pid", "mount", "uts", "ipc"]
+        .iter()
+        .fold(spec, |spec, namespace| spec.add_namespace(namespace, None))
+}
+
+/// Bundle and container of a case, both removed on drop
+struct Container {
+    id: String,
+    bundle: PathBuf,
+}
+
+impl Container {
+    fn new(case: &str, spec: &Spec) -> Container {
+        let id = format!("conformance-{}-{}", case, std::process::id());
+        let bundle = std::env::temp_dir().join(&id);
+        std::fs::create_dir_all(bundle.join("rootfs")).unwrap();
+        spec.save(&bundle).unwrap();
+        Container { id, bundle }
+    }
+
+    fn create(&self) -> i32 {
+        pura(&[
+            "create",
+            "--bundle",
+            self.bundle.to_str().unwrap(),
+            &self.id,
+        ])
+    }
+
+    /// File written by the container process into its rootfs
+    fn output(&self, file: &str) -> Option<String> {
+        let path = self.bundle.join("rootfs").join(file);
+        if wait_for(|| path.exists()) {
+            // Let the process finish writing
+            thread::sleep(Duration::from_millis(100));
+            std::fs::read_to_string(path).ok()
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if status(&self.id).as_deref() != Some("stopped") {
+            pura(&["kill", &self.id, "9"]);
+            wait_for(|| status(&self.id).as_deref() != Some("running"));
+        }
+        pura(&["delete", &self.id]);
+        let _ = std::fs::remove_dir_all(&self.bundle);
+    }
+}
+
+#[test]
+fn lifecycle() {
+    if !supported("lifecycle") {
+        return;
+    }
+    let container = Container::new("lifecycle", &base_spec("echo started > /started; sleep 60"));
+
+    assert_eq!(container.create(), 0);
+    let created = state(&container.id).unwrap();
+    assert_eq!(created["ociVersion"], "1.0.2");
+    assert_eq!(created["id"], container.id.as_str());
+    assert_eq!(created["status"], "created");
+    assert!(created["pid"].as_u64().unwrap() > 0);
+    assert_eq!(
+        Path::new(created["bundle"].as_str().unwrap()),
+        container.bundle.canonicalize().unwrap()
+    );
+    // The user process doesn't run before start
+    thread::sleep(Duration::from_millis(200));
+    assert!(!container.bundle.join("rootfs/started").exists());
+
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(status(&container.id).as_deref(), Some("running"));
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+    let pid = command(&["state", "--format", "{{.pid}}", &container.id])
+        .output()
+        .unwrap()
+        .stdout;
+    assert_eq!(String::from_utf8_lossy(&pid), format!("{}\n", created["pid"]));
+    let list = command(&["list", "--format", "{{.id}} {{.status}}"])
+        .output()
+        .unwrap()
+        .stdout;
+    assert!(String::from_utf8_lossy(&list)
+        .lines()
+        .any(|line| line == format!("{} running", container.id)));
+
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    assert!(wait_for(
+        || status(&container.id).as_deref() == Some("stopped")
+    ));
+
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    assert!(state(&container.id).is_none());
+}
+
+#[test]
+fn sealed_runtime_binary() {
+    if !supported("sealed_runtime_binary") {
+        return;
+    }
+    let container = Container::new("sealed", &base_spec("true"));
+
+    assert_eq!(container.create(), 0);
+    // Until start, the init process of the container is still the runtime
+    let pid = state(&container.id).unwrap()["pid"].as_u64().unwrap();
+    let exe = std::fs::read_link(format!("/proc/{}/exe", pid)).unwrap();
+    assert!(exe.to_string_lossy().starts_with("/memfd:pura"), "{:?}", exe);
+}
+
+#[test]
+fn wait_exit_code() {
+    if !supported("wait") {
+        return;
+    }
+    let container = Container::new("wait", &base_spec("sleep 0.5; exit 3"));
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    // Blocks until the container stops, with its exit code
+    assert_eq!(pura(&["wait", &container.id]), 3);
+    assert_eq!(status(&container.id).as_deref(), Some("stopped"));
+    // Stopped already, from the recorded exit code
+    assert_eq!(pura(&["wait", &container.id]), 3);
+}
+
+#[test]
+fn wait_signal_exit_code() {
+    if !supported("wait") {
+        return;
+    }
+    let container = Container::new("wait-signal", &base_spec("echo started > /started; exec sleep 60"));
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+
+    let mut wait = command(&["wait", &container.id])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    // 128 + SIGKILL, like a shell reports the killed process
+    assert_eq!(wait.wait().unwrap().code(), Some(137));
+    assert_eq!(pura(&["wait", &container.id]), 137);
+}
+
+#[test]
+fn quiet_state_exit_codes() {
+    if !supported("quiet-state") {
+        return;
+    }
+    let container = Container::new("quiet-state", &base_spec("echo started > /started; exec sleep 60"));
+    let quiet = |id: &str| {
+        let output = command(&["state", "--quiet", id])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.stdout.is_empty() && output.stderr.is_empty());
+        output.status.code()
+    };
+
+    assert_eq!(quiet(&container.id), Some(12));
+    assert_eq!(container.create(), 0);
+    assert_eq!(quiet(&container.id), Some(10));
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+    assert_eq!(quiet(&container.id), Some(0));
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    assert_eq!(pura(&["wait", &container.id]), 137);
+    assert_eq!(quiet(&container.id), Some(11));
+}
+
+#[test]
+fn run_forwards_signals() {
+    if !supported("run") {
+        return;
+    }
+    let container = Container::new(
+        "run",
+        &base_spec("trap 'exit 4' TERM; echo started > /started; while true; do sleep 0.1; done"),
+    );
+
+    let mut run = command(&[
+        "run",
+        "--rm",
+        "--bundle",
+        container.bundle.to_str().unwrap(),
+        &container.id,
+    ])
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+
+    // Like systemctl stop, the container handles the signal and exits with its code
+    kill(Pid::from_raw(run.id() as i32), Signal::SIGTERM).unwrap();
+    assert_eq!(run.wait().unwrap().code(), Some(4));
+    // Deleted once stopped, even by a signal
+    assert!(state(&container.id).is_none());
+}
+
+#[test]
+fn failed_run_leaves_no_state() {
+    // Fails on the bundle, before the process is forked
+    let id = format!("conformance-failed-run-{}", std::process::id());
+    let bundle = std::env::temp_dir().join(&id);
+    std::fs::create_dir_all(&bundle).unwrap();
+    assert_eq!(pura(&["run", "--rm", "--bundle", bundle.to_str().unwrap(), &id]), 125);
+    assert!(!Path::new(root()).join(&id).exists());
+    std::fs::remove_dir_all(&bundle).unwrap();
+
+    if !supported("failed_run") {
+        return;
+    }
+    // Fails on the pid file, once the process is in its cgroup
+    let container = Container::new("failed-run", &base_spec("sleep 60"));
+    let pid_file = container.bundle.join("missing").join("pid");
+    assert_ne!(
+        pura(&[
+            "run",
+            "--rm",
+            "--pid-file",
+            pid_file.to_str().unwrap(),
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            &container.id,
+        ]),
+        0
+    );
+    assert!(state(&container.id).is_none());
+    assert!(!Path::new(root()).join(&container.id).exists());
+    assert!(!Path::new("/sys/fs/cgroup/pura").join(&container.id).exists());
+}
+
+#[test]
+#[cfg(feature = "image")]
+fn rootfs_tarball() {
+    if !supported("rootfs_tar") {
+        return;
+    }
+    let container = Container::new("rootfs-tar", &base_spec("test \"$(cat /marker)\" = extracted"));
+    let tarball = container.bundle.join("rootfs.tar");
+    let mut builder = tar::Builder::new(std::fs::File::create(&tarball).unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(9);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_cksum();
+    builder.append_data(&mut header, "marker", &b"extracted"[..]).unwrap();
+    builder.finish().unwrap();
+
+    assert_eq!(
+        pura(&[
+            "run",
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            "--rootfs-tar",
+            tarball.to_str().unwrap(),
+            &container.id,
+        ]),
+        0
+    );
+    // Extracted in the state dir, the bundle is left alone
+    assert!(!container.bundle.join("rootfs/marker").exists());
+}
+
+#[test]
+fn overlay_rootfs() {
+    if !supported("overlay") {
+        return;
+    }
+    let container = Container::new(
+        "overlay",
+        &base_spec("test ! -e /written && echo overlay > /written"),
+    );
+    for id in [format!("{}-1", container.id), format!("{}-2", container.id)] {
+        assert_eq!(
+            pura(&[
+                "run",
+                "--bundle",
+                container.bundle.to_str().unwrap(),
+                "--overlay",
+                &id,
+            ]),
+            0
+        );
+        pura(&["delete", "--force", &id]);
+    }
+    // Written to the upper dir of each container, the shared rootfs is left alone
+    assert!(!container.bundle.join("rootfs/written").exists());
+}
+
+#[test]
+fn named_volume() {
+    if !supported("volume") {
+        return;
+    }
+    let container = Container::new("volume", &base_spec("echo kept > /data/file"));
+    let volume = format!("{}:/data", container.id);
+    assert_eq!(
+        pura(&[
+            "run",
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            "--volume",
+            &volume,
+            &container.id,
+        ]),
+        0
+    );
+    // Created on first use, kept from removal until the container is deleted
+    let path = Path::new(root()).join("volumes").join(&container.id);
+    assert_eq!(std::fs::read_to_string(path.join("file")).unwrap(), "kept\n");
+    assert_ne!(pura(&["volume", "rm", &container.id]), 0);
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    assert_eq!(pura(&["volume", "rm", &container.id]), 0);
+    assert!(!path.exists());
+}
+
+#[test]
+fn pod_namespaces() {
+    if !supported("pod") {
+        return;
+    }
+    let pod = format!("conformance-pod-{}", std::process::id());
+    assert_eq!(pura(&["pod", "create", "--share-pid", &pod]), 0);
+    let spec = base_spec("readlink /proc/self/ns/net > /net; hostname > /hostname; echo $$ > /pid");
+    let containers = [Container::new("pod-a", &spec), Container::new("pod-b", &spec)];
+    for container in &containers {
+        let bundle = container.bundle.to_str().unwrap();
+        assert_eq!(pura(&["pod", "add", "--bundle", bundle, &pod, &container.id]), 0);
+        assert_eq!(pura(&["start", &container.id]), 0);
+    }
+
+    // Both in the namespaces of the pause process, PID 1 of the pod
+    let host_net = std::fs::read_link("/proc/self/ns/net").unwrap();
+    let outputs: Vec<_> = containers
+        .iter()
+        .map(|container| (container.output("net").unwrap(), container.output("hostname").unwrap()))
+        .collect();
+    assert_eq!(outputs[0], outputs[1]);
+    assert_ne!(outputs[0].0.trim_end(), host_net.to_str().unwrap());
+    assert_eq!(outputs[0].1, format!("{}\n", pod));
+    assert_ne!(containers[0].output("pid").unwrap(), "1\n");
+
+    // Kept while it has containers
+    assert_ne!(pura(&["pod", "rm", &pod]), 0);
+    drop(containers);
+    assert_eq!(pura(&["pod", "rm", &pod]), 0);
+    assert!(!Path::new(root()).join("pods").join(&pod).exists());
+}
+
+#[test]
+fn restart_on_failure() {
+    if !supported("restart") {
+        return;
+    }
+    // Fails until its third run
+    let container = Container::new(
+        "restart",
+        &base_spec("echo run >> /runs; test $(wc -l < /runs) -ge 3"),
+    );
+    let run = |policy: &str| {
+        pura(&[
+            "run",
+            "--detach",
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            "--restart",
+            policy,
+            &container.id,
+        ])
+    };
+    // Created again from the spec cached in the state dir, not from the bundle
+    let config = container.bundle.join("config.json");
+    let spec = std::fs::read(&config).unwrap();
+    assert_eq!(run("on-failure:5"), 0);
+    std::fs::remove_file(&config).unwrap();
+    // Waits for the monitor, until it stops restarting the container
+    assert_eq!(pura(&["wait", &container.id]), 0);
+    assert_eq!(container.output("runs").unwrap().lines().count(), 3);
+    let state = state(&container.id).unwrap();
+    assert_eq!(state["status"], "stopped");
+    assert_eq!(state["annotations"]["pura.restart.count"], "2");
+
+    // Gives up after the maximum number of restarts
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    std::fs::write(&config, spec).unwrap();
+    std::fs::remove_file(container.bundle.join("rootfs/runs")).unwrap();
+    assert_eq!(run("on-failure:1"), 0);
+    assert_eq!(pura(&["wait", &container.id]), 1);
+    assert_eq!(container.output("runs").unwrap().lines().count(), 2);
+
+    // Only the monitor of a detached container restarts it
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    let bundle = container.bundle.to_str().unwrap();
+    let foreground = ["run", "--bundle", bundle, "--restart", "on-failure", &container.id];
+    assert_ne!(pura(&foreground), 0);
+
+    // Killed by the runtime, it isn't restarted
+    let failing = Container::new("restart-stop", &base_spec("echo run >> /runs; sleep 60"));
+    let detached = |id: &str| {
+        let bundle = failing.bundle.to_str().unwrap();
+        pura(&["run", "--detach", "--bundle", bundle, "--restart", "on-failure", id])
+    };
+    assert_eq!(detached(&failing.id), 0);
+    assert!(wait_for(|| failing.output("runs").is_some()));
+    assert_eq!(pura(&["kill", &failing.id, "9"]), 0);
+    assert_eq!(pura(&["wait", &failing.id]), 137);
+    assert_eq!(failing.output("runs").unwrap().lines().count(), 1);
+}
+
+#[test]
+fn syscall_trace() {
+    if !supported("trace") {
+        return;
+    }
+    let container = Container::new("trace", &base_spec("exec /missing"));
+    let trace = container.bundle.join("trace");
+    assert_ne!(
+        pura(&[
+            "run",
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            "--trace-syscalls",
+            trace.to_str().unwrap(),
+            &container.id,
+        ]),
+        0
+    );
+    // The failed exec of the missing binary, then the exit of the shell
+    let trace = std::fs::read_to_string(trace).unwrap();
+    assert!(trace.contains("execve(\"/missing\""), "{}", trace);
+    assert!(trace.contains("= -1 ENOENT"), "{}", trace);
+    assert!(trace.trim_end().ends_with("+++ exited with 127 +++"), "{}", trace);
+}
+
+#[test]
+fn init_shim() {
+    if !supported("init") {
+        return;
+    }
+    // Without a handler, the signal would be dropped for PID 1 of the namespace
+    let container = Container::new(
+        "init",
+        &base_spec("(sleep 60 &); echo started > /started; exec sleep 60"),
+    );
+
+    let mut run = command(&[
+        "run",
+        "--init",
+        "--bundle",
+        container.bundle.to_str().unwrap(),
+        &container.id,
+    ])
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+
+    kill(Pid::from_raw(run.id() as i32), Signal::SIGTERM).unwrap();
+    // 128 + SIGTERM, from the workload killed by the forwarded signal
+    assert_eq!(run.wait().unwrap().code(), Some(143));
+    // Kept without --rm
+    assert_eq!(status(&container.id).as_deref(), Some("stopped"));
+}
+
+#[test]
+fn exec_detach() {
+    if !supported("exec") {
+        return;
+    }
+    let container = Container::new("exec", &base_spec("echo started > /started; sleep 60"));
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+
+    // In the mount namespace of the container, exiting with the code of the command
+    let script = "test -f /started && exit 6";
+    assert_eq!(pura(&["exec", &container.id, "sh", "-c", script]), 6);
+    let script = "test \"$GREETING $(pwd) $(id -u)\" = \"hello /usr 1000\"";
+    assert_eq!(
+        pura(&[
+            "exec",
+            "--env",
+            "GREETING=hello",
+            "--cwd",
+            "/usr",
+            "--user",
+            "1000:1000",
+            &container.id,
+            "sh",
+            "-c",
+            script,
+        ]),
+        0
+    );
+
+    let pid_file = container.bundle.join("exec.pid");
+    assert_eq!(
+        pura(&[
+            "exec",
+            "--detach",
+            "--pid-file",
+            pid_file.to_str().unwrap(),
+            &container.id,
+            "sleep",
+            "60",
+        ]),
+        0
+    );
+    let pid = Pid::from_raw(std::fs::read_to_string(&pid_file).unwrap().parse().unwrap());
+    assert!(alive(pid));
+    let exec_count = || state(&container.id).unwrap()["annotations"]["pura.exec.count"].clone();
+    assert_eq!(exec_count(), "1");
+
+    // PID 1 of the container ignores SIGTERM, the exec'd process doesn't
+    assert_eq!(pura(&["kill", "--all", &container.id, "15"]), 0);
+    assert!(wait_for(|| !alive(pid)));
+    assert_eq!(exec_count(), "0");
+    assert_eq!(status(&container.id).as_deref(), Some("running"));
+}
+
+#[test]
+fn exec_cgroup() {
+    if !supported("exec_cgroup") {
+        return;
+    }
+    let container = Container::new("exec-cgroup", &base_spec("sleep 60"));
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+
+    // Limits without a cgroup of their own would apply to the whole container
+    assert_ne!(pura(&["exec", "--memory", "50m", &container.id, "true"]), 0);
+
+    if !Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        eprintln!("skipping the rest of exec_cgroup: needs cgroup v2");
+        return;
+    }
+    let cgroup = Path::new("/sys/fs/cgroup/pura").join(&container.id).join("debug");
+    let script = "grep -q '/debug$' /proc/self/cgroup";
+    assert_eq!(
+        pura(&["exec", "--cgroup", "debug", "--pids-limit", "10", &container.id, "sh", "-c", script]),
+        0
+    );
+    assert_eq!(std::fs::read_to_string(cgroup.join("pids.max")).unwrap(), "10\n");
+
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    assert!(wait_for(
+        || status(&container.id).as_deref() == Some("stopped")
+    ));
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    assert!(!cgroup.exists());
+}
+
+#[test]
+fn rootless_limits() {
+    // The other way around: a user of a systemd session, like `cargo test` without sudo
+    let session = Command::new("busctl")
+        .args(["--user", "status"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    let unified = Path::new("/sys/fs/cgroup/cgroup.controllers").exists();
+    if geteuid().is_root() || !session || !unified {
+        eprintln!("skipping rootless_limits: needs a user of a systemd session and cgroup v2");
+        return;
+    }
+    let mut spec = base_spec("sleep 60").add_namespace("user", None);
+    let linux = spec.linux.as_mut().unwrap();
+    let mapping = |id: u32| {
+        Some(vec![UidMapping {
+            container_id: 0,
+            host_id: id as i64,
+            size: 1,
+            ..Default::default()
+        }])
+    };
+    linux.uid_mappings = mapping(geteuid().as_raw());
+    linux.gid_mappings = mapping(nix::unistd::getegid().as_raw());
+    linux.resources = Some(Resources {
+        memory: Some(Memory {
+            limit: Some(64 << 20),
+            ..Default::default()
+        }),
+        pids: Some(Pids {
+            limit: Some(32),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let container = Container::new("rootless-limits", &spec);
+
+    // In a leaf of the scope, the only cgroup whose limits the user may write
+    assert_eq!(container.create(), 0);
+    let pid = state(&container.id).unwrap()["pid"].as_i64().unwrap();
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap();
+    let cgroup = cgroup.trim_end().strip_prefix("0::").unwrap();
+    assert!(cgroup.ends_with(&format!("/pura-{}.scope/container", container.id)));
+    let cgroup = Path::new("/sys/fs/cgroup").join(cgroup.trim_start_matches('/'));
+    assert_eq!(std::fs::read_to_string(cgroup.join("pids.max")).unwrap(), "32\n");
+    if cgroup.join("memory.max").exists() {
+        assert_eq!(std::fs::read_to_string(cgroup.join("memory.max")).unwrap(), "67108864\n");
+    }
+
+    assert_eq!(pura(&["update", "--pids-limit", "16", &container.id]), 0);
+    assert_eq!(std::fs::read_to_string(cgroup.join("pids.max")).unwrap(), "16\n");
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(pura(&["kill", "--all", &container.id, "9"]), 0);
+    assert!(wait_for(
+        || status(&container.id).as_deref() == Some("stopped")
+    ));
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    assert!(!cgroup.exists());
+}
+
+#[test]
+fn session_recording() {
+    if !supported("recording") {
+        return;
+    }
+    let mut spec = base_spec("echo recorded");
+    spec.process.as_mut().unwrap().terminal = Some(true);
+    let container = Container::new("recording", &spec);
+    assert_eq!(
+        pura(&[
+            "create",
+            "--detach",
+            "--record",
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            &container.id,
+        ]),
+        0
+    );
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(pura(&["wait", &container.id]), 0);
+    assert_eq!(pura(&["delete", &container.id]), 0);
+
+    // Next to the state dir, the recording outlives the container
+    let recording = Path::new(root()).join(format!("{}.cast", container.id));
+    let replay = command(&["replay", "--speed", "100", &container.id])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(replay.status.success());
+    assert_eq!(String::from_utf8_lossy(&replay.stdout), "recorded\r\n");
+    std::fs::remove_file(recording).unwrap();
+}
+
+#[test]
+fn detached_log_rotation() {
+    if !supported("log_rotation") {
+        return;
+    }
+    let container = Container::new(
+        "log-rotation",
+        &base_spec("i=0; while [ $i -lt 100 ]; do echo line $i; i=$((i+1)); done; sleep 1; echo done"),
+    );
+
+    let bundle = container.bundle.to_str().unwrap();
+    let args = ["create", "--detach", "--log-max-size", "2k", "--log-max-files", "2"];
+    assert_eq!(pura(&[&args[..], &["--bundle", bundle, &container.id]].concat()), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+
+    let root = Path::new(root()).join(&container.id);
+    let log = root.join("container-json.log");
+    assert!(wait_for(|| root.join("container-json.log.1").exists()));
+    // No rotation is left in flight once the last line is written
+    assert!(wait_for(|| std::fs::read_to_string(&log)
+        .unwrap_or_default()
+        .contains(r#""log":"line 99\n""#)));
+    // The monitor reopens the log on SIGHUP instead of dying
+    let monitor = std::fs::read_to_string(root.join("monitor.pid")).unwrap();
+    let monitor = Pid::from_raw(monitor.split(' ').next().unwrap().parse().unwrap());
+    std::fs::rename(&log, root.join("container-json.log.old")).unwrap();
+    kill(monitor, Signal::SIGHUP).unwrap();
+
+    assert_eq!(pura(&["wait", &container.id]), 0);
+    assert!(std::fs::metadata(root.join("container-json.log.1")).unwrap().len() <= 2048);
+    assert!(!root.join("container-json.log.2").exists());
+    assert!(std::fs::read_to_string(&log).unwrap().contains(r#""log":"done\n""#));
+}
+
+#[test]
+fn detached_monitor() {
+    if !supported("detach") {
+        return;
+    }
+    let container = Container::new(
+        "detach",
+        &base_spec("echo started; read line; echo \"got $line\" >&2; exit 5"),
+    );
+
+    assert_eq!(
+        pura(&[
+            "create",
+            "--detach",
+            "--bundle",
+            container.bundle.to_str().unwrap(),
+            &container.id,
+        ]),
+        0
+    );
+    assert_eq!(pura(&["start", &container.id]), 0);
+
+    let mut attach = command(&["attach", &container.id])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    attach.stdin.take().unwrap().write_all(b"hello\n").unwrap();
+    let output = attach.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("got hello"));
+
+    // The monitor reaped the process, the runtime isn't its parent
+    assert_eq!(pura(&["wait", &container.id]), 5);
+    assert_eq!(status(&container.id).as_deref(), Some("stopped"));
+    let root = Path::new(root()).join(&container.id);
+    let exit: Value =
+        serde_json::from_str(&std::fs::read_to_string(root.join("exit.json")).unwrap()).unwrap();
+    assert_eq!(exit["exitCode"], 5);
+    assert!(std::fs::read_to_string(root.join("container-json.log"))
+        .unwrap()
+        .contains(r#""log":"started\n","stream":"stdout""#));
+
+    // The logs keep the streams apart
+    let logs = command(&["logs", "--tail", "1", &container.id])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(logs.status.success());
+    assert!(logs.stdout.is_empty());
+    assert_eq!(String::from_utf8_lossy(&logs.stderr), "got hello\n");
+}
+
+#[test]
+fn stdio_owned_by_user() {
+    if !supported("stdio_owner") {
+        return;
+    }
+    // Reopening the pipe of the monitor needs to own it
+    let mut spec = base_spec("echo reopened > /proc/self/fd/1");
+    spec.process.as_mut().unwrap().user = Some(User {
+        uid: 1000,
+        gid: 1000,
+        ..Default::default()
+    });
+    let container = Container::new("stdio-owner", &spec);
+
+    let bundle = container.bundle.to_str().unwrap();
+    assert_eq!(pura(&["create", "--detach", "--bundle", bundle, &container.id]), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(pura(&["wait", &container.id]), 0);
+    let logs = command(&["logs", &container.id])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&logs.stdout), "reopened\n");
+}
+
+#[test]
+fn process_env_and_cwd() {
+    if !supported("process") {
+        return;
+    }
+    let mut spec = base_spec("echo \"$GREETING $(pwd)\" > /process").add_env("GREETING", "hello");
+    spec.process.as_mut().unwrap().cwd = String::from("/usr");
+    let container = Container::new("process", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("process").as_deref(), Some("hello /usr\n"));
+}
+
+#[test]
+fn default_env() {
+    if !supported("default_env") {
+        return;
+    }
+    let mut spec = base_spec("echo \"$PATH $HOME $HOSTNAME ${CARGO:-none}\" > /env")
+        .with_hostname("defaults");
+    spec.process.as_mut().unwrap().env = None;
+    let container = Container::new("default-env", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    // Nothing leaks from the environment of the runtime, CARGO of the test
+    assert_eq!(
+        container.output("env").as_deref(),
+        Some("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin / defaults none\n")
+    );
+}
+
+#[test]
+fn username_from_rootfs() {
+    if !supported("username") {
+        return;
+    }
+    let mut spec = base_spec("echo \"$(id -u) $(id -g) $(id -G) $HOME\" > /out/user");
+    spec.process.as_mut().unwrap().user = Some(User {
+        username: Some(String::from("app")),
+        ..Default::default()
+    });
+    let container = Container::new("username", &spec);
+    let rootfs = container.bundle.join("rootfs");
+    std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+    std::fs::write(
+        rootfs.join("etc/passwd"),
+        "root:x:0:0:root:/root:/bin/sh\napp:x:1000:1000::/home/app:/bin/sh\n",
+    )
+    .unwrap();
+    std::fs::write(rootfs.join("etc/group"), "app:x:1000:\naudio:x:29:app\n").unwrap();
+    std::fs::create_dir_all(rootfs.join("out")).unwrap();
+    std::fs::set_permissions(rootfs.join("out"), std::fs::Permissions::from_mode(0o777)).unwrap();
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(
+        container.output("out/user").as_deref(),
+        Some("1000 1000 1000 29 /home/app\n")
+    );
+}
+
+#[test]
+fn no_leaked_fds() {
+    if !supported("fds") {
+        return;
+    }
+    // ls opens the directory as fd 3, the runtime passes the stdio only
+    let script = "ls /proc/self/fd | tr '\\n' ' ' > /fds; sleep 60";
+    let container = Container::new("fds", &base_spec(script));
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("fds").as_deref(), Some("0 1 2 3 "));
+
+    let output = command(&["exec", &container.id, "ls", "/proc/self/fd"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n1\n2\n3\n");
+}
+
+#[test]
+fn mounts_and_hostname() {
+    if !supported("mounts") {
+        return;
+    }
+    let spec = base_spec(concat!(
+        "cat /proc/self/mounts > /mounts; ",
+        "cat /proc/sys/kernel/hostname /proc/sys/kernel/domainname > /hostname"
+    ))
+    .with_hostname("conformance")
+    .with_domainname("example.com")
+    .add_mount(Mount::new("/data", "tmpfs", "tmpfs", &[]));
+    let container = Container::new("mounts", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(
+        container.output("hostname").as_deref(),
+        Some("conformance\nexample.com\n")
+    );
+    let mounts = container.output("mounts").unwrap();
+    assert!(mounts.lines().any(|line| line.contains(" /data tmpfs ")));
+    assert!(mounts.lines().any(|line| line.contains(" /proc proc ")));
+}
+
+#[test]
+fn masked_and_readonly_paths() {
+    if !supported("masked_paths") {
+        return;
+    }
+    let spec = base_spec(concat!(
+        "wc -c < /proc/kallsyms > /kallsyms; ls /proc/irq | wc -l > /irq; ",
+        "echo masked > /proc/sys/kernel/hostname 2> /dev/null; echo $? > /hostname"
+    ))
+    .add_masked_path("/proc/kallsyms")
+    .add_masked_path("/proc/irq")
+    .add_masked_path("/proc/missing")
+    .add_readonly_path("/proc/sys");
+    let container = Container::new("masked", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("kallsyms").as_deref(), Some("0\n"));
+    assert_eq!(container.output("irq").as_deref(), Some("0\n"));
+    assert_ne!(container.output("hostname").as_deref(), Some("0\n"));
+}
+
+#[test]
+fn proc_options() {
+    if !supported("proc_options") {
+        return;
+    }
+    let mut spec = base_spec(concat!(
+        "test -e /proc/sys; echo $? > /sys; ",
+        "grep -o 'hidepid=[a-z0-9]*' /proc/self/mounts > /hidepid"
+    ))
+    .add_masked_path("/proc/kcore")
+    .add_readonly_path("/proc/sys");
+    spec.mounts.as_mut().unwrap().retain(|mount| mount.destination != "/proc");
+    let spec = spec.add_mount(Mount::new(
+        "/proc",
+        "proc",
+        "proc",
+        &["nosuid", "hidepid=2", "subset=pid"],
+    ));
+    let container = Container::new("proc-options", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    // subset=pid hides all but the processes, unless the kernel is too old
+    assert!(matches!(container.output("sys").as_deref(), Some("1\n") | Some("0\n")));
+    assert_eq!(container.output("hidepid").as_deref(), Some("hidepid=invisible\n"));
+}
+
+#[test]
+fn masked_path_on_fake_proc() {
+    if !supported("masked_path_on_fake_proc") {
+        return;
+    }
+    // A rootfs faking /proc with a directory of its own
+    let fake = std::env::temp_dir().join(format!("conformance-fake-proc-{}", std::process::id()));
+    std::fs::create_dir_all(&fake).unwrap();
+    std::fs::write(fake.join("kallsyms"), "secret").unwrap();
+    let mut spec = base_spec("true").add_masked_path("/proc/kallsyms");
+    spec.mounts.as_mut().unwrap().retain(|mount| mount.destination != "/proc");
+    let spec = spec.add_mount(Mount::new("/proc", "bind", fake.to_str().unwrap(), &["bind"]));
+    let container = Container::new("fake-proc", &spec);
+
+    assert_ne!(container.create(), 0);
+    std::fs::remove_dir_all(&fake).unwrap();
+}
+
+#[test]
+fn default_devices() {
+    if !supported("default_devices") {
+        return;
+    }
+    let mut spec = base_spec(concat!(
+        "for dev in null zero full random urandom tty; do ",
+        "stat -c '%n %F %t:%T %a' /dev/$dev; done > /devices; ",
+        "readlink /dev/ptmx /dev/fd /dev/stdin /dev/stdout /dev/stderr >> /devices"
+    ));
+    // A device of the spec replaces the default one
+    spec.linux.as_mut().unwrap().devices = Some(vec![Device {
+        path: String::from("/dev/zero"),
+        device_type: String::from("c"),
+        major: 1,
+        minor: 5,
+        file_mode: Some(0o600),
+        ..Default::default()
+    }]);
+    let container = Container::new("default-devices", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(
+        container.output("devices").as_deref(),
+        Some(concat!(
+            "/dev/null character special file 1:3 666\n",
+            "/dev/zero character special file 1:5 600\n",
+            "/dev/full character special file 1:7 666\n",
+            "/dev/random character special file 1:8 666\n",
+            "/dev/urandom character special file 1:9 666\n",
+            "/dev/tty character special file 5:0 666\n",
+            "pts/ptmx\n",
+            "/proc/self/fd\n",
+            "/proc/self/fd/0\n",
+            "/proc/self/fd/1\n",
+            "/proc/self/fd/2\n",
+        ))
+    );
+}
+
+#[test]
+fn mount_options() {
+    if !supported("mount_options") {
+        return;
+    }
+    let data = std::env::temp_dir().join(format!("conformance-mount-data-{}", std::process::id()));
+    std::fs::create_dir_all(&data).unwrap();
+    let spec = base_spec(concat!(
+        "cat /proc/self/mounts > /mounts; ",
+        "touch /data/file 2> /dev/null; echo $? > /touch"
+    ))
+        .add_mount(Mount::new("/tmp", "tmpfs", "tmpfs", &["nosuid", "size=1m", "mode=700"]))
+        .add_mount(Mount::new("/data", "bind", data.to_str().unwrap(), &["rbind", "ro"]));
+    let container = Container::new("mount-options", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    let mounts = container.output("mounts").unwrap();
+    let tmp = mounts.lines().find(|line| line.contains(" /tmp tmpfs ")).unwrap();
+    assert!(
+        tmp.contains("nosuid") && tmp.contains("size=1024k") && tmp.contains("mode=700"),
+        "{}",
+        tmp
+    );
+    let bind = mounts.lines().find(|line| line.contains(" /data ")).unwrap();
+    assert!(bind.contains(" ro,"), "{}", bind);
+    assert_ne!(container.output("touch").as_deref(), Some("0\n"));
+    assert!(!data.join("file").exists());
+    std::fs::remove_dir_all(&data).unwrap();
+}
+
+#[test]
+fn net_devices() {
+    if !supported("net_devices") {
+        return;
+    }
+    // A veth pair stands in for a physical interface, one end moves into the container
+    let host = format!("puranet{}", std::process::id() % 100000);
+    let peer = format!("{}p", host);
+    let added = Command::new("ip")
+        .args(["link", "add", &host, "type", "veth", "peer", "name", &peer])
+        .status();
+    if !added.is_ok_and(|status| status.success()) {
+        eprintln!("skipping net_devices: unable to add a veth pair");
+        return;
+    }
+    let spec = base_spec("cat /proc/net/dev > /netdev; sleep 60")
+        .add_namespace("network", None)
+        .add_net_device(&host, Some("eth7"));
+    let container = Container::new("net-devices", &spec);
+    let on_host = || Path::new("/sys/class/net").join(&host).exists();
+
+    assert_eq!(container.create(), 0);
+    assert!(!on_host());
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert!(container.output("netdev").unwrap().contains("eth7:"));
+
+    // Still returned under its name once the container process is gone
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    assert!(wait_for(
+        || status(&container.id).as_deref() == Some("stopped")
+    ));
+    assert_eq!(pura(&["delete", &container.id]), 0);
+    assert!(on_host());
+    let _ = Command::new("ip").args(["link", "del", &host]).status();
+}
+
+#[test]
+fn memory_policy() {
+    if !supported("memory_policy") || !Path::new("/proc/self/numa_maps").exists() {
+        return;
+    }
+    let mut spec = base_spec("head -1 /proc/self/numa_maps > /numa_maps");
+    spec.linux.as_mut().unwrap().memory_policy = Some(MemoryPolicy {
+        mode: String::from("MPOL_PREFERRED"),
+        nodes: Some(String::from("0")),
+        flags: None,
+        ..Default::default()
+    });
+    let container = Container::new("memory-policy", &spec);
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert!(container.output("numa_maps").unwrap().contains(" prefer:0 "));
+}
+
+#[test]
+fn seccomp_filter() {
+    if !supported("seccomp") {
+        return;
+    }
+    let script = "mkdir /denied 2>/dev/null; m=$?; kill -USR1 $$ 2>/dev/null; u=$?; \
+                  kill -0 $$; echo \"$m $u $?\" > /seccomp; sleep 60";
+    for no_new_privileges in [false, true] {
+        let mut spec = base_spec(script);
+        spec.process.as_mut().unwrap().no_new_privileges = Some(no_new_privileges);
+        spec.linux.as_mut().unwrap().seccomp = Some(Seccomp {
+            default_action: String::from("SCMP_ACT_ALLOW"),
+            syscalls: Some(vec![
+                Syscall {
+                    names: vec![String::from("mkdir"), String::from("mkdirat")],
+                    action: String::from("SCMP_ACT_ERRNO"),
+                    ..Default::default()
+                },
+                // SIGUSR1 only
+                Syscall {
+                    names: vec![String::from("kill")],
+                    action: String::from("SCMP_ACT_ERRNO"),
+                    args: Some(vec![SyscallArg {
+                        index: 1,
+                        value: 10,
+                        op: String::from("SCMP_CMP_EQ"),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        });
+        let container = Container::new("seccomp", &spec);
+
+        assert_eq!(container.create(), 0);
+        assert_eq!(pura(&["start", &container.id]), 0);
+        assert_eq!(container.output("seccomp").as_deref(), Some("1 1 0\n"));
+        // The exec'd processes get the filter of the container
+        assert_eq!(pura(&["exec", &container.id, "mkdir", "/exec"]), 1);
+        assert_eq!(pura(&["exec", &container.id, "touch", "/exec"]), 0);
+        drop(container);
+    }
+
+    let mut spec = base_spec("true");
+    spec.linux.as_mut().unwrap().seccomp = Some(Seccomp {
+        default_action: String::from("SCMP_ACT_DENY"),
+        ..Default::default()
+    });
+    let container = Container::new("seccomp-invalid", &spec);
+    assert_eq!(container.create(), 2);
+}
+
+#[test]
+fn cdi_devices() {
+    if !supported("cdi_devices") {
+        return;
+    }
+    let container = Container::new(
+        "cdi-devices",
+        &base_spec("test -c /dev/vendor0 && echo $VENDOR_DEVICES > /cdi"),
+    );
+    let spec_dir = container.bundle.join("cdi");
+    std::fs::create_dir_all(&spec_dir).unwrap();
+    std::fs::write(
+        spec_dir.join("vendor.json"),
+        r#"{
+            "cdiVersion": "0.6.0",
+            "kind": "vendor.com/device",
+            "devices": [{
+                "name": "null",
+                "containerEdits": {
+                    "env": ["VENDOR_DEVICES=null"],
+                    "deviceNodes": [{"path": "/dev/vendor0", "hostPath": "/dev/null"}]
+                }
+            }]
+        }"#,
+    )
+    .unwrap();
+
+    let create = |device: &str| {
+        command(&["create", "--device", device, "--bundle"])
+            .arg(&container.bundle)
+            .arg(&container.id)
+            .env("PURA_CDI_SPEC_DIRS", &spec_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+    };
+    assert!(!create("vendor.com/device=missing").success());
+    assert!(create("vendor.com/device=null").success());
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("cdi").as_deref(), Some("null\n"));
+}
+
+#[test]
+fn hot_add_device() {
+    if !supported("hot_add_device") {
+        return;
+    }
+    let script = "while ! test -c /dev/hot/null0; do sleep 0.1; done; echo added > /hot; sleep 60";
+    let container = Container::new("hot-add-device", &base_spec(script));
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+
+    let device = "/dev/null:/dev/hot/null0:rw";
+    assert_eq!(pura(&["update", "--device", device, &container.id]), 0);
+    assert_eq!(container.output("hot").as_deref(), Some("added\n"));
+    // Taken already
+    assert_ne!(pura(&["update", "--device", device, &container.id]), 0);
+}
+
+#[test]
+fn events_stats() {
+    if !supported("events_stats") {
+        return;
+    }
+    let container = Container::new("events-stats", &base_spec("sleep 60"));
+    assert_eq!(container.create(), 0);
+
+    let output = command(&["events", "--stats", &container.id])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["type"], "stats");
+    assert_eq!(stats["id"], container.id.as_str());
+    for field in ["memoryUsage", "cpuPressure", "memoryPressure", "ioPressure"] {
+        assert!(stats["data"].get(field).is_some(), "no {}", field);
+    }
+
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    assert!(wait_for(
+        || status(&container.id).as_deref() == Some("stopped")
+    ));
+    assert_ne!(pura(&["events", "--stats", &container.id]), 0);
+}
+
+#[test]
+fn events_interval() {
+    if !supported("events_interval") {
+        return;
+    }
+    let container = Container::new("events-interval", &base_spec("sleep 1"));
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+
+    // Ends by itself once the container stopped
+    let output = command(&["events", "--interval", "200ms", &container.id])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let lines: Vec<Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(lines.len() >= 2, "{:?}", lines);
+    assert!(lines.iter().all(|line| line["type"] == "stats"));
+}
+
+#[test]
+fn hostname_without_uts_namespace() {
+    if !supported("hostname_without_uts_namespace") {
+        return;
+    }
+    let mut spec = base_spec("true").with_domainname("example.com");
+    spec.linux
+        .as_mut()
+        .unwrap()
+        .namespaces
+        .as_mut()
+        .unwrap()
+        .retain(|ns| ns.namespace != "uts");
+    let container = Container::new("no-uts", &spec);
+
+    assert_ne!(container.create(), 0);
+    assert!(state(&container.id).is_none());
+}
+
+#[test]
+fn bundle_changed_after_create() {
+    if !supported("bundle") {
+        return;
+    }
+    let container = Container::new("bundle", &base_spec("echo started > /started; sleep 60"));
+
+    assert_eq!(container.create(), 0);
+    // The runtime keeps the spec it created the container with
+    std::fs::write(container.bundle.join("config.json"), "{}").unwrap();
+
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(container.output("started").as_deref(), Some("started\n"));
+    assert_eq!(pura(&["kill", &container.id, "9"]), 0);
+    assert!(wait_for(
+        || status(&container.id).as_deref() == Some("stopped")
+    ));
+    assert_eq!(pura(&["delete", &container.id]), 0);
+}
+
+#[test]
+fn concurrent_create_and_delete() {
+    if !supported("concurrent_create_and_delete") {
+        return;
+    }
+    let container = Container::new("concurrent", &base_spec("sleep 60"));
+    let bundle = container.bundle.to_str().unwrap();
+    for _ in 0..10 {
+        assert_eq!(container.create(), 0);
+        let mut delete = command(&["delete", &container.id])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        // Waiting for the lock of the delete, a create must not take over
+        // the container from the other one once it's gone
+        let creates: Vec<_> = (0..2)
+            .map(|i| {
+                // Not piped, the container process would hold the pipe open
+                let log = container.bundle.join(format!("create-{}.log", i));
+                let output = std::fs::File::create(&log).unwrap();
+                let create = command(&["create", "--bundle", bundle, &container.id])
+                    .stdin(Stdio::null())
+                    .stdout(output.try_clone().unwrap())
+                    .stderr(output)
+                    .spawn()
+                    .unwrap();
+                (create, log)
+            })
+            .collect();
+        assert!(delete.wait().unwrap().success());
+
+        let mut created = 0;
+        for (mut create, log) in creates {
+            if create.wait().unwrap().success() {
+                created += 1;
+            } else {
+                let output = std::fs::read_to_string(&log).unwrap();
+                assert!(output.contains("already exists"), "{}", output);
+            }
+        }
+        assert!(created <= 1);
+        if created == 1 {
+            assert_eq!(status(&container.id).as_deref(), Some("created"));
+            assert_eq!(pura(&["delete", &container.id]), 0);
+        }
+        assert!(state(&container.id).is_none());
+        assert!(!Path::new(root()).join(&container.id).exists());
+    }
+}
+
+#[test]
+fn force_delete() {
+    if !supported("force_delete") {
+        return;
+    }
+    let container = Container::new("force-delete", &base_spec("sleep 60"));
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    let pid = Pid::from_raw(state(&container.id).unwrap()["pid"].as_i64().unwrap() as i32);
+    assert!(alive(pid));
+
+    assert_eq!(pura(&["delete", "--force", &container.id]), 0);
+    assert!(state(&container.id).is_none());
+    assert!(!alive(pid));
+}
+
+#[test]
+fn kill_processes_left_in_cgroup() {
+    if !supported("leftover") {
+        return;
+    }
+    if !Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        eprintln!("skipping kill_processes_left_in_cgroup: needs cgroup v2");
+        return;
+    }
+    // Without a PID namespace, the children outlive the container process
+    let mut spec = base_spec("sleep 60 & echo $! > /child");
+    let linux = spec.linux.as_mut().unwrap();
+    linux.namespaces.as_mut().unwrap().retain(|namespace| namespace.namespace != "pid");
+    let container = Container::new("leftover", &spec);
+
+    for kill in [&["kill", "--all", &container.id, "9"][..], &["delete", "--force", &container.id]] {
+        let _ = std::fs::remove_file(container.bundle.join("rootfs/child"));
+        assert_eq!(container.create(), 0);
+        assert_eq!(pura(&["start", &container.id]), 0);
+        let child = Pid::from_raw(container.output("child").unwrap().trim().parse().unwrap());
+        assert!(wait_for(
+            || status(&container.id).as_deref() == Some("stopped")
+        ));
+        assert!(alive(child));
+
+        assert_eq!(pura(kill), 0);
+        assert!(wait_for(|| !alive(child)));
+        pura(&["delete", &container.id]);
+    }
+}
+
+#[test]
+fn global_flags() {
+    if !supported("global_flags") {
+        return;
+    }
+    let container = Container::new("global-flags", &base_spec("sleep 60"));
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().join("root");
+    let root = root.to_str().unwrap();
+    let log = format!("{}.log", root);
+
+    // Before or after the subcommand
+    let bundle = container.bundle.to_str().unwrap();
+    assert_eq!(pura(&["--root", root, "create", "-b", bundle, &container.id]), 0);
+    assert!(Path::new(root).join(&container.id).join("state.json").exists());
+    assert!(state(&container.id).is_none());
+    assert_eq!(pura(&["start", &container.id, "--root", root]), 0);
+    assert_eq!(
+        pura(&["kill", "--root", root, "--log", &log, "--debug", &container.id, "9"]),
+        0
+    );
+    assert!(std::fs::read_to_string(&log)
+        .unwrap()
+        .contains("sending signal 9"));
+    assert_eq!(pura(&["--root", root, "delete", "--force", &container.id]), 0);
+    assert!(!Path::new(root).join(&container.id).exists());
+}
+
+#[test]
+fn bulk_operations() {
+    if !supported("bulk") {
+        return;
+    }
+    let container = Container::new("bulk", &base_spec("sleep 60"));
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_str().unwrap();
+    let bundle = container.bundle.to_str().unwrap();
+    let ids: Vec<String> = (0..3).map(|index| format!("{}-{}", container.id, index)).collect();
+    let exists = |id: &str| Path::new(root).join(id).exists();
+    for id in ids.iter() {
+        assert_eq!(pura(&["create", "--root", root, "-b", bundle, id]), 0);
+    }
+    assert_eq!(pura(&["start", "--root", root, &ids[0]]), 0);
+    assert_eq!(pura(&["start", "--root", root, &ids[1]]), 0);
+
+    // The created container too, stopped ones would be skipped
+    assert_eq!(pura(&["kill", "--root", root, "--all-containers", "9"]), 0);
+    assert_eq!(pura(&["wait", "--root", root, &ids[0]]), 137);
+    assert_eq!(pura(&["wait", "--root", root, &ids[1]]), 137);
+    assert_eq!(pura(&["wait", "--root", root, &ids[2]]), 137);
+    assert_eq!(pura(&["delete", "--root", root, "--all-stopped"]), 0);
+    assert!(ids.iter().all(|id| !exists(id)));
+
+    // Every container is tried, the command fails if any did
+    assert_eq!(pura(&["create", "--root", root, "-b", bundle, &ids[0]]), 0);
+    let missing = format!("{}-missing", container.id);
+    assert_eq!(pura(&["kill", "--root", root, &missing, &ids[0], "9"]), 1);
+    assert_eq!(pura(&["wait", "--root", root, &ids[0]]), 137);
+    assert_eq!(pura(&["delete", "--root", root, &missing, &ids[0]]), 1);
+    assert!(!exists(&ids[0]));
+    assert_eq!(pura(&["delete", "--root", root, "--all"]), 0);
+
+    // One audit record per container
+    let audit = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+    let records = |subcommand: &str| -> Vec<(String, i64)> {
+        audit
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|record| record["subcommand"] == subcommand)
+            .map(|record| (record["id"].as_str().unwrap().to_string(), record["code"].as_i64().unwrap()))
+            .collect()
+    };
+    let expected = |codes: &[(&String, i64)]| -> Vec<(String, i64)> {
+        codes.iter().map(|(id, code)| (id.to_string(), *code)).collect()
+    };
+    let mut killed = records("kill");
+    killed[..3].sort();
+    assert_eq!(
+        killed,
+        expected(&[(&ids[0], 0), (&ids[1], 0), (&ids[2], 0), (&missing, 5), (&ids[0], 0)])
+    );
+    let mut deleted = records("delete");
+    deleted[..3].sort();
+    assert_eq!(
+        deleted,
+        expected(&[(&ids[0], 0), (&ids[1], 0), (&ids[2], 0), (&missing, 5), (&ids[0], 0)])
+    );
+}
+
+#[test]
+fn operations_on_missing_container() {
+    let id = format!("conformance-missing-{}", std::process::id());
+    assert!(state(&id).is_none());
+    // Exit code of an invalid container state
+    assert_eq!(pura(&["state", &id]), 5);
+    assert_eq!(pura(&["start", &id]), 5);
+    assert_eq!(pura(&["kill", &id, "15"]), 5);
+    assert_eq!(pura(&["update", "--memory", "512m", &id]), 5);
+    assert_eq!(pura(&["delete", &id]), 5);
+}
+
+#[test]
+fn invalid_ids() {
+    let escape = format!("conformance-escape-{}", std::process::id());
+    let bundle = std::env::temp_dir().join(&escape);
+    for id in [format!("../{}", escape), "/tmp".to_string(), ".".to_string()] {
+        assert_eq!(pura(&["state", &id]), 1);
+        assert_eq!(pura(&["delete", &id]), 1);
+        assert_eq!(pura(&["create", "--bundle", bundle.to_str().unwrap(), &id]), 1);
+    }
+    assert!(!bundle.exists());
+}
+
+#[test]
+fn reserved_ids() {
+    let case = format!("conformance-reserved-{}", std::process::id());
+    let bundle = std::env::temp_dir().join(&case);
+    let recording = format!("{}.cast", case);
+    for id in ["volumes", "pods", "seccomp", "locks", "audit.log", "state.db", &recording] {
+        assert_eq!(pura(&["create", "--bundle", bundle.to_str().unwrap(), id]), 1, "{}", id);
+        assert_eq!(pura(&["state", id]), 1, "{}", id);
+        assert_eq!(pura(&["delete", "--force", id]), 1, "{}", id);
+    }
+}
+
+#[test]
+fn invalid_bundle() {
+    let id = format!("conformance-invalid-{}", std::process::id());
+    let bundle = std::env::temp_dir().join(&id);
+    std::fs::create_dir_all(&bundle).unwrap();
+
+    // No config.json
+    assert_eq!(
+        pura(&["create", "--bundle", bundle.to_str().unwrap(), &id]),
+        2
+    );
+
+    std::fs::write(bundle.join("config.json"), "{ \"ociVersion\": ").unwrap();
+    assert_eq!(
+        pura(&["create", "--bundle", bundle.to_str().unwrap(), &id]),
+        2
+    );
+    assert!(state(&id).is_none());
+
+    // Every semantic error is reported before anything is set up
+    let spec = Spec::new()
+        .with_rootfs("missing", false)
+        .with_args(&["sh"])
+        .with_hostname("no-uts");
+    spec.save(&bundle).unwrap();
+    let output = command(&["create", "--bundle", bundle.to_str().unwrap(), &id])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for field in ["root.path", "hostname"] {
+        assert!(
+            stderr.contains(field) || stdout.contains(field),
+            "{} missing from {}{}",
+            field,
+            stdout,
+            stderr
+        );
+    }
+    assert!(state(&id).is_none());
+
+    std::fs::remove_dir_all(&bundle).unwrap();
+}
+
+#[test]
+fn missing_executable() {
+    if !supported("missing_executable") {
+        return;
+    }
+    let spec = base_spec("true").with_args(&["no-such-executable"]);
+    let container = Container::new("missing-executable", &spec);
+
+    let output = command(&["create", "--bundle", container.bundle.to_str().unwrap(), &container.id])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("no-such-executable: executable file not found in $PATH"));
+}
+
+#[test]
+fn validate_bundle() {
+    let spec = base_spec("true");
+    let container = Container::new("validate", &spec);
+    let bundle = container.bundle.to_str().unwrap();
+    assert_eq!(pura(&["validate", "--bundle", bundle]), 0);
+
+    spec.with_rootfs("missing", false).save(&container.bundle).unwrap();
+    assert_eq!(pura(&["validate", "--bundle", bundle]), 2);
+    // Nothing is created
+    assert!(state(&container.id).is_none());
+}
+
+#[test]
+fn wasm_module() {
+    if !supported("wasm") {
+        return;
+    }
+    let mut spec = base_spec("");
+    spec.process.as_mut().unwrap().args = Some(vec![String::from("/hello.wasm")]);
+    let container = Container::new("wasm", &spec);
+    std::fs::write(container.bundle.join("rootfs/hello.wasm"), HELLO_WASM).unwrap();
+    // Without the wasmtime backend, the module is refused
+    if !cfg!(feature = "wasm") {
+        assert_eq!(container.create(), 1);
+        return;
+    }
+
+    assert_eq!(container.create(), 0);
+    assert_eq!(pura(&["start", &container.id]), 0);
+    assert_eq!(pura(&["wait", &container.id]), 3);
+    assert_eq!(container.output("wasm").as_deref(), Some("hello wasm\n"));
+
+    // Compiled by create, like an executable is resolved
+    spec.process.as_mut().unwrap().args = Some(vec![String::from("/missing.wasm")]);
+    let missing = Container::new("wasm-missing", &spec);
+    assert_ne!(missing.create(), 0);
+}